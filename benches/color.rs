@@ -0,0 +1,43 @@
+//! Throughput of the frame color math against the budget of a frame at 60
+//! frames a second, e.g. on a Raspberry Pi 3: `cargo bench --bench color`.
+
+use blinkie::core::color::{self, Gamma, PIXEL};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const FRAME_BUDGET: Duration = Duration::from_micros(16_667);
+const RUN: Duration = Duration::from_millis(500);
+
+fn measure<F: FnMut()>(name: &str, pixels: usize, mut f: F) {
+    let mut runs = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < RUN {
+        f();
+        runs += 1;
+    }
+    let per_frame = start.elapsed() / runs;
+    println!(
+        "{:<12} {:>6} px {:>10.2?}/frame {:>7.3}% of a 60 fps frame, {:>8.1} Mpx/s",
+        name,
+        pixels,
+        per_frame,
+        per_frame.as_secs_f64() * 100.0 / FRAME_BUDGET.as_secs_f64(),
+        pixels as f64 / per_frame.as_secs_f64() / 1e6,
+    );
+}
+
+fn main() {
+    let gamma = Gamma::default();
+    // A strip, a 32x32 matrix and a large 64x64 one
+    for pixels in [300, 1024, 4096] {
+        let src: Vec<u8> = (0..pixels * PIXEL).map(|i| (i * 7) as u8).collect();
+        let mut dst: Vec<u8> = (0..pixels * PIXEL).map(|i| (i * 13) as u8).collect();
+        measure("gamma", pixels, || gamma.apply(black_box(&mut dst)));
+        measure("blend", pixels, || {
+            color::blend(black_box(&mut dst), black_box(&src), 96)
+        });
+        measure("hsv_to_rgb", pixels, || {
+            color::hsv_to_rgb(black_box(&src), black_box(&mut dst))
+        });
+    }
+}
@@ -0,0 +1,27 @@
+//! Output of state commands, parsed with arbitrary formats and patterns.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use blinkie::handlers::exec::StateFormat;
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    state_format: Option<String>,
+    state_pattern: Option<String>,
+    output: String,
+}
+
+fuzz_target!(|input: Input| {
+    let mut details = HashMap::new();
+    if let Some(format) = input.state_format {
+        details.insert("state_format".to_string(), format);
+    }
+    if let Some(pattern) = input.state_pattern {
+        details.insert("state_pattern".to_string(), pattern);
+    }
+    if let Ok(format) = StateFormat::from_details(&details, "fuzz") {
+        let _ = format.parse(&input.output);
+    }
+});
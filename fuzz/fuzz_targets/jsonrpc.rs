@@ -0,0 +1,20 @@
+//! Lines written by subprocess handlers on stdout.
+#![no_main]
+
+use blinkie::core::replay::Decoder;
+use blinkie::core::sniffer::{Direction, Frame};
+use blinkie::handlers::subprocess::SubprocessDecoder;
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+fuzz_target!(|line: &str| {
+    let frame = Frame {
+        seq: 1,
+        timestamp: chrono::Utc::now(),
+        handler: "subprocess".to_string(),
+        device_id: Some("fuzz".to_string()),
+        direction: Direction::In,
+        data: line.to_string(),
+    };
+    let _ = SubprocessDecoder.decode(&frame, &HashMap::new());
+});
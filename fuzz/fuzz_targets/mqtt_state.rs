@@ -0,0 +1,42 @@
+//! MQTT device mappings and the state messages received on their topics.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use blinkie::core::device::{Config, Type};
+use blinkie::core::replay::Decoder;
+use blinkie::core::sniffer::{Direction, Frame};
+use blinkie::handlers::mqtt::{MqttDecoder, MqttSpec};
+use libfuzzer_sys::fuzz_target;
+use rumqttc::v5::mqttbytes::QoS;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    details: Vec<(String, String)>,
+    topic: String,
+    payload: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let config = Config {
+        id: "fuzz".to_string(),
+        name: "fuzz".to_string(),
+        device_type: Type::Sensor,
+        connection_details: input.details.into_iter().collect(),
+        supported_protocols: vec!["mqtt".to_string()],
+        preferred_handler: None,
+    };
+    let _ = MqttSpec::from_config(&config, QoS::AtLeastOnce);
+
+    let mut data = input.topic.replace(' ', "/");
+    data.push(' ');
+    data.push_str(&String::from_utf8_lossy(&input.payload));
+    let frame = Frame {
+        seq: 1,
+        timestamp: chrono::Utc::now(),
+        handler: "mqtt".to_string(),
+        device_id: Some(config.id.clone()),
+        direction: Direction::In,
+        data,
+    };
+    let _ = MqttDecoder.decode(&frame, &config.connection_details);
+});
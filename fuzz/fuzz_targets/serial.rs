@@ -0,0 +1,34 @@
+//! Bytes read from a serial port in arbitrary chunks, split into frames
+//! with arbitrary framing settings and parsed into state.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use blinkie::handlers::serial::{parse_frame, Deframer, Framing};
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    framing: Option<String>,
+    length_bytes: Option<String>,
+    reads: Vec<Vec<u8>>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut details = HashMap::new();
+    if let Some(framing) = input.framing {
+        details.insert("framing".to_string(), framing);
+    }
+    if let Some(bytes) = input.length_bytes {
+        details.insert("length_bytes".to_string(), bytes);
+    }
+    let Ok(framing) = Framing::from_details(&details) else {
+        return;
+    };
+    let mut deframer = Deframer::new(framing);
+    for read in &input.reads {
+        for frame in deframer.push(read) {
+            let _ = parse_frame(framing, &frame);
+        }
+    }
+});
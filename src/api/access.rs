@@ -0,0 +1,90 @@
+use super::endpoints::error;
+use crate::core::access::Scope;
+use crate::core::app::App;
+use crate::core::doorbell::SNAPSHOT_PATH;
+use crate::core::push::TELEGRAM_UPDATE_PATH;
+use crate::core::tts::CLIP_PATH;
+use crate::core::webhook::WEBHOOK_PATH;
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+/**
+ * Account
+ * Name of the API token a request presented, found in the request
+ * extensions of requests that presented a known one.
+ */
+#[derive(Clone, Debug)]
+pub struct Account(pub String);
+
+/// Endpoints taking a body that only read, allowed in read-only scope.
+const READING_POSTS: [&str; 2] = ["/api/history/query", "/api/template"];
+
+/// Whether a request calls a webhook. Outside services can't send tokens;
+/// webhooks are guarded by their secret ids instead.
+fn is_webhook_call(request: &Request) -> bool {
+    let path = request.uri().path();
+    request.method() == Method::POST
+        && ((path.starts_with(WEBHOOK_PATH) && path.len() > WEBHOOK_PATH.len())
+            || path == TELEGRAM_UPDATE_PATH)
+}
+
+/// Whether a media player fetches an announcement or a push service a
+/// doorbell snapshot. Neither can send tokens; the files are guarded by
+/// their secret ids instead.
+fn is_media_fetch(request: &Request) -> bool {
+    let path = request.uri().path();
+    request.method() == Method::GET
+        && [CLIP_PATH, SNAPSHOT_PATH]
+            .iter()
+            .any(|prefix| path.starts_with(prefix) && path.len() > prefix.len())
+}
+
+/// Works out the scope of a request from its bearer token, or the `token`
+/// query parameter browsers have to use for event streams and sockets,
+/// and turns away changes made in read-only scope. Handlers find the scope
+/// in the request extensions.
+pub async fn layer(State(app): State<Arc<App>>, mut request: Request, next: Next) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .or_else(|| {
+            request.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("token="))
+                    .map(str::to_string)
+            })
+        });
+    if is_webhook_call(&request) || is_media_fetch(&request) {
+        request.extensions_mut().insert(Scope::ReadOnly);
+        return next.run(request).await;
+    }
+    let scope = match app.access.scope(token.as_deref()) {
+        Ok(scope) => scope,
+        Err(e) => return error(StatusCode::UNAUTHORIZED, e).into_response(),
+    };
+    let reading = matches!(*request.method(), Method::GET | Method::HEAD)
+        || (request.method() == Method::POST && READING_POSTS.contains(&request.uri().path()));
+    if scope == Scope::ReadOnly && !reading {
+        return error(
+            StatusCode::FORBIDDEN,
+            "Read-only access: commands and changes are not allowed",
+        )
+        .into_response();
+    }
+    request.extensions_mut().insert(scope);
+    if let Some(account) = token.as_deref().and_then(|token| app.access.account(token)) {
+        request
+            .extensions_mut()
+            .insert(Account(account.to_string()));
+    }
+    next.run(request).await
+}
@@ -0,0 +1,171 @@
+use crate::core::app::App;
+use std::sync::Arc;
+
+/// Well-known name the hub owns on the bus.
+pub const BUS_NAME: &str = "org.blinkie";
+/// Object path of the object manager; devices live below it.
+pub const ROOT_PATH: &str = "/org/blinkie";
+
+/**
+ * Buses
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bus {
+    Session,
+    System,
+}
+
+/// Object path of a device. Characters not allowed in paths are escaped as
+/// `_xx` hex, so `kitchen-lamp` becomes `/org/blinkie/devices/kitchen_2dlamp`.
+pub fn device_path(device_id: &str) -> String {
+    let mut path = format!("{}/devices/", ROOT_PATH);
+    for byte in device_id.bytes() {
+        if byte.is_ascii_alphanumeric() {
+            path.push(byte as char);
+        } else {
+            path.push_str(&format!("_{:02x}", byte));
+        }
+    }
+    path
+}
+
+#[cfg(not(feature = "dbus"))]
+pub async fn serve(_app: Arc<App>, _bus: Bus) -> Result<(), String> {
+    Err("D-Bus support requires blinkie to be built with the `dbus` feature".to_string())
+}
+
+/// Exports every device as an `org.blinkie.Device1` object, discoverable
+/// through `org.freedesktop.DBus.ObjectManager` at [`ROOT_PATH`]. Devices
+/// registered later are added, and state changes are signalled as
+/// `PropertiesChanged` of the `State` property.
+#[cfg(feature = "dbus")]
+pub async fn serve(app: Arc<App>, bus: Bus) -> Result<(), String> {
+    use crate::core::event::EventKind;
+    use tokio::sync::broadcast::error::RecvError;
+
+    let builder = match bus {
+        Bus::Session => zbus::connection::Builder::session(),
+        Bus::System => zbus::connection::Builder::system(),
+    };
+    let connection = builder
+        .and_then(|builder| builder.name(BUS_NAME))
+        .and_then(|builder| builder.serve_at(ROOT_PATH, zbus::fdo::ObjectManager))
+        .map_err(|e| format!("Failed to connect to D-Bus: {}", e))?
+        .build()
+        .await
+        .map_err(|e| format!("Failed to connect to D-Bus: {}", e))?;
+
+    let mut events = app.events.subscribe();
+    for device_id in app.device_ids() {
+        export(&connection, &app, &device_id).await?;
+    }
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        match event.kind {
+            EventKind::DeviceRegistered { device_id } => {
+                export(&connection, &app, &device_id).await?
+            }
+            EventKind::StateChanged { device_id, .. } => {
+                let Ok(object) = connection
+                    .object_server()
+                    .interface::<_, DeviceObject>(device_path(&device_id))
+                    .await
+                else {
+                    continue;
+                };
+                let result = object
+                    .get()
+                    .await
+                    .state_changed(object.signal_emitter())
+                    .await;
+                if let Err(e) = result {
+                    log::warn!("Failed to signal state of '{}': {}", device_id, e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(feature = "dbus")]
+async fn export(
+    connection: &zbus::Connection,
+    app: &Arc<App>,
+    device_id: &str,
+) -> Result<(), String> {
+    let object = DeviceObject {
+        app: app.clone(),
+        id: device_id.to_string(),
+    };
+    connection
+        .object_server()
+        .at(device_path(device_id), object)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to export '{}' on D-Bus: {}", device_id, e))
+}
+
+/**
+ * DeviceObject
+ * A device as seen over D-Bus.
+ */
+#[cfg(feature = "dbus")]
+struct DeviceObject {
+    app: Arc<App>,
+    id: String,
+}
+
+#[cfg(feature = "dbus")]
+#[zbus::interface(name = "org.blinkie.Device1")]
+impl DeviceObject {
+    #[zbus(property)]
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> String {
+        self.app
+            .devices
+            .read()
+            .unwrap()
+            .iter()
+            .find(|device| device.get_id() == self.id)
+            .map(|device| device.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    fn state(&self) -> std::collections::HashMap<String, String> {
+        self.app
+            .devices
+            .read()
+            .unwrap()
+            .iter()
+            .find(|device| device.get_id() == self.id)
+            .map(|device| crate::core::value::format_state(&device.get_state()))
+            .unwrap_or_default()
+    }
+
+    /// Sends a command to the device and returns the sequence number of the
+    /// resulting event.
+    fn send_command(
+        &self,
+        command: String,
+        parameters: std::collections::HashMap<String, String>,
+    ) -> zbus::fdo::Result<u64> {
+        self.app
+            .send_command(
+                &self.id,
+                &command,
+                (!parameters.is_empty()).then_some(parameters),
+                None,
+            )
+            .map(|result| result.event.seq)
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+    }
+}
@@ -1,8 +1,2536 @@
+use super::access::Account;
+use super::{access, sse, websocket};
+use crate::automation::graph::DependencyGraph;
+use crate::automation::reload::RuleFileStatus;
+use crate::automation::rule::{Rule, StalePolicy};
+use crate::automation::stats::RuleStats;
+use crate::core::access::Scope;
+use crate::core::aggregate::{BucketView, Resolution};
+use crate::core::app::{App, AppState, CommandResult};
+use crate::core::chaos::{ChaosProfile, ChaosStatus};
+use crate::core::command_queue::{QueueConfig, QueueStatus};
+use crate::core::config::{AppConfig, ConfigChanges, ConfigPreview};
+use crate::core::dashboard::Dashboard;
+use crate::core::degraded::StorageStatus;
+use crate::core::device::{ApiVersion, Capability, Config, Type};
+use crate::core::doorbell::{DoorbellConfig, DoorbellStatus};
+use crate::core::drift::{DriftStatus, Fingerprint};
+use crate::core::effects::{EffectDefinition, EffectStatus};
+use crate::core::energy::{EnergyConfig, EnergyStatus, Mode};
+use crate::core::error::BlinkieError;
+use crate::core::event::Event;
+use crate::core::federation::{SatelliteStatus, States, SyncAck, SyncMetrics, SYNC_PATH};
+use crate::core::freshness::{ClockOffset, StateAge, MAX_CLOCK_SKEW_SECS};
+use crate::core::group::{DeviceGroup, MemberResult};
+use crate::core::guest::{CodeRequest, GuestCodeStatus, GuestConfig};
+use crate::core::health::DeviceHealth;
+use crate::core::history::{HistoryBucket, Sample};
+use crate::core::hot_keys::HotKeyStats;
+use crate::core::job::Job;
+use crate::core::latency::CommandLatency;
+use crate::core::lease::Lease;
+use crate::core::lock::{LockRecord, LockStatus};
+use crate::core::maintenance::JobMetrics;
+use crate::core::maintenance_mode::{MaintenanceFlag, MaintenanceFlags};
+use crate::core::memory::MemoryStatus;
+use crate::core::notification::{Delivery, Notification, NotificationPolicy, PendingNotifications};
+use crate::core::occupancy::{AreaOccupancy, OccupancyConfig};
+use crate::core::pacing::OutputTiming;
+use crate::core::prediction::{FeatureWindow, Prediction, PredictionConfig};
+use crate::core::price::{PriceConfig, PriceStatus};
+use crate::core::pump::{PumpConfig, PumpStatus};
+use crate::core::push::{telegram_callback, TELEGRAM_UPDATE_PATH};
+use crate::core::query::{QueryRequest, QueryResult};
+use crate::core::retention::PurgeTarget;
+use crate::core::scene::{DeviceSelector, Scene};
+use crate::core::scheduler::{JobStatus, SchedulerConfig};
+use crate::core::sniffer::{Frame, SnifferStatus};
+use crate::core::snippet::DeviceSnippet;
+use crate::core::tts::{Announcement, TtsConfig};
+use crate::core::ui::UiManifest;
+use crate::core::usage::{DeviceUsage, Usage, UsagePeriod};
+use crate::core::user::{Preferences, User, UserStatus};
+use crate::core::value::StateMap;
+use crate::core::webhook::{Webhook, WEBHOOK_PATH};
+use crate::handlers::plugin::PluginStatus;
 use axum::{
-Json,
-http::StatusCode,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
 };
-use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tower_http::services::{ServeDir, ServeFile};
 
+/// How long requests in flight may take to finish once the server stops.
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
 
+/**
+ * ApiError
+ * Body of every error response.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiError {
+    pub error: String,
+}
 
+pub type ApiResult<T> = Result<Json<T>, (StatusCode, Json<ApiError>)>;
+
+pub fn error(status: StatusCode, message: impl Into<String>) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: message.into(),
+        }),
+    )
+}
+
+/// Builds the HTTP API.
+pub fn router(app: Arc<App>) -> Router {
+    Router::new()
+        .route("/api/history/query", post(query_history))
+        .route("/api/history/purge", post(purge_history))
+        .route("/api/devices/:id/aggregates", get(device_aggregates))
+        .route("/api/devices/:id/history", get(device_history))
+        .route("/api/devices/:id/history/keys", get(history_keys))
+        .route("/api/devices/:id/history/buckets", get(history_buckets))
+        .route("/api/devices/:id/usage", get(device_usage))
+        .route("/api/usage", get(all_usage))
+        .route("/api/websocket", get(websocket::handler))
+        .route("/api/events/stream", get(sse::handler))
+        .route("/api/ui/manifest", get(ui_manifest))
+        .route("/api/devices/:id/icon", put(set_icon))
+        .route(
+            "/api/devices/:id/picture",
+            get(get_picture).put(set_picture).delete(delete_picture),
+        )
+        .route("/api/states", get(device_states))
+        .route("/api/devices", get(list_devices).post(register_device))
+        .route("/api/devices/discovered", get(discovered_devices))
+        .route("/api/devices/import", post(import_device))
+        .route("/api/devices/:id/snippet", get(device_snippet))
+        .route(
+            "/api/devices/:id",
+            get(get_device).delete(unregister_device),
+        )
+        .route("/api/devices/:id/commands", post(send_command))
+        .route("/api/devices/:id/frame", put(write_frame))
+        .route("/api/frames/pacing", get(frame_pacing))
+        .route("/api/effects", get(effects))
+        .route(
+            "/api/effects/:name",
+            get(get_effect).put(start_effect).delete(stop_effect),
+        )
+        .route(
+            "/api/devices/:id/lease",
+            get(get_lease).post(claim_lease).delete(release_lease),
+        )
+        .route("/api/template", post(render_template))
+        .route("/api/handlers", get(handlers))
+        .route("/api/maintenance", get(maintenance_flags))
+        .route("/api/maintenance/jobs", get(maintenance_jobs))
+        .route("/api/maintenance/jobs/:name/run", post(run_maintenance_job))
+        .route(
+            "/api/devices/:id/maintenance",
+            put(set_device_maintenance).delete(clear_device_maintenance),
+        )
+        .route(
+            "/api/handlers/:name/maintenance",
+            put(set_handler_maintenance).delete(clear_handler_maintenance),
+        )
+        .route("/api/commands/latency", get(command_latency))
+        .route("/api/jobs", get(list_jobs))
+        .route("/api/jobs/:id", get(get_job).delete(cancel_job))
+        .route("/api/dashboards", get(list_dashboards))
+        .route(
+            "/api/dashboards/:id",
+            get(get_dashboard)
+                .put(save_dashboard)
+                .delete(delete_dashboard),
+        )
+        .route("/api/groups", get(list_groups))
+        .route(
+            "/api/groups/:id",
+            get(get_group).put(save_group).delete(delete_group),
+        )
+        .route("/api/groups/:id/commands", post(send_group_command))
+        .route("/api/scenes", get(list_scenes))
+        .route("/api/scenes/capture", post(capture_new_scene))
+        .route(
+            "/api/scenes/:id",
+            get(get_scene).put(save_scene).delete(delete_scene),
+        )
+        .route("/api/scenes/:id/capture", post(capture_scene))
+        .route("/api/scenes/:id/apply", post(apply_scene))
+        .route("/api/users", get(list_users))
+        .route("/api/users/me", get(current_user))
+        .route("/api/users/me/preferences", put(set_current_preferences))
+        .route(
+            "/api/users/:id",
+            get(get_user).put(save_user).delete(delete_user),
+        )
+        .route("/api/users/:id/preferences", put(set_user_preferences))
+        .route(
+            "/api/sniffer",
+            get(sniffer_status).put(set_sniffer).delete(clear_sniffer),
+        )
+        .route("/api/sniffer/frames", get(sniffer_frames))
+        .route("/api/devices/:id/ages", get(device_ages))
+        .route("/api/clocks", get(clocks))
+        .route("/api/notifications", post(send_notification))
+        .route(
+            "/api/notifications/policy",
+            get(notification_policy).put(set_notification_policy),
+        )
+        .route("/api/notifications/pending", get(pending_notifications))
+        .route("/api/doorbells", get(doorbells))
+        .route(
+            "/api/doorbells/config",
+            get(doorbell_config).put(set_doorbell_config),
+        )
+        .route("/api/doorbells/:id/ring", post(ring_doorbell))
+        .route(
+            "/api/doorbells/:id/ignore",
+            post(ignore_doorbell).delete(unignore_doorbell),
+        )
+        .route("/api/doorbells/snapshots/:id", get(doorbell_snapshot))
+        .route("/api/occupancy", get(occupancy))
+        .route(
+            "/api/occupancy/config",
+            get(occupancy_config).put(set_occupancy_config),
+        )
+        .route("/api/predictions", get(predictions))
+        .route(
+            "/api/predictions/config",
+            get(prediction_config).put(set_prediction_config),
+        )
+        .route("/api/predictions/:model", post(accept_prediction))
+        .route("/api/predictions/:model/window", get(prediction_window))
+        .route("/api/predictions/:model/run", post(run_prediction))
+        .route("/api/energy", get(energy))
+        .route(
+            "/api/energy/config",
+            get(energy_config).put(set_energy_config),
+        )
+        .route("/api/energy/loads/:id/override", put(set_energy_override))
+        .route("/api/prices", get(prices))
+        .route(
+            "/api/prices/config",
+            get(price_config).put(set_price_config),
+        )
+        .route("/api/prices/refresh", post(refresh_prices))
+        .route("/api/schedules", get(schedules))
+        .route(
+            "/api/schedules/config",
+            get(scheduler_config).put(set_scheduler_config),
+        )
+        .route("/api/schedules/:name/run", post(run_schedule))
+        .route("/api/guest-codes", get(guest_codes).post(create_guest_code))
+        .route(
+            "/api/guest-codes/config",
+            get(guest_config).put(set_guest_config),
+        )
+        .route(
+            "/api/guest-codes/:id",
+            get(guest_code).delete(revoke_guest_code),
+        )
+        .route("/api/locks", get(locks))
+        .route("/api/locks/audit", get(lock_audit))
+        .route("/api/pumps", get(pumps))
+        .route("/api/pumps/config", get(pump_config).put(set_pump_config))
+        .route("/api/pumps/:id/trip", delete(reset_pump))
+        .route(
+            "/api/chaos",
+            get(chaos_status).put(set_chaos).delete(clear_chaos),
+        )
+        .route("/api/queue", get(queue_status))
+        .route("/api/queue/config", get(queue_config).put(set_queue_config))
+        .route("/api/queue/dead-letters", delete(clear_dead_letters))
+        .route("/api/storage", get(storage_status))
+        .route("/api/memory", get(memory_status))
+        .route("/api/hot-keys", get(hot_keys))
+        .route("/api/plugins", get(plugins))
+        .route(
+            "/api/availability",
+            get(availability).post(check_availability),
+        )
+        .route("/api/availability/:id", get(device_availability))
+        .route("/api/drift", get(drift_status).post(check_drift))
+        .route("/api/drift/expectations", get(drift_expectations))
+        .route("/api/drift/:id/accept", post(accept_drift))
+        .route("/api/announcements", post(announce))
+        .route("/api/announcements/clips/:id", get(announcement_clip))
+        .route("/api/tts", get(tts_config).put(set_tts_config))
+        .route(TELEGRAM_UPDATE_PATH, post(telegram_update))
+        .route("/api/webhooks", get(list_webhooks).post(create_webhook))
+        .route(
+            "/api/webhooks/:id",
+            post(receive_webhook).delete(delete_webhook),
+        )
+        .route("/api/rules", get(list_rules))
+        .route("/api/rules/stats", get(rule_stats))
+        .route("/api/rules/graph", get(rule_graph))
+        .route("/api/rules/files", get(rule_files))
+        .route("/api/rules/reload", post(reload_rules))
+        .route("/api/config", get(get_config).put(apply_config))
+        .route("/api/config/preview", post(preview_config))
+        .route("/api/config/rules", get(config_rules).put(save_rules))
+        .route("/api/config/rules/preview", post(preview_rules))
+        .route(
+            "/api/rules/stale-policy",
+            get(stale_policy).put(set_stale_policy),
+        )
+        .route("/api/federation", get(federation_status))
+        .route(SYNC_PATH, post(federation_sync))
+        .route(
+            "/api/federation/satellites/:id/states",
+            get(satellite_states),
+        )
+        .layer(middleware::from_fn_with_state(app.clone(), access::layer))
+        .with_state(app)
+}
+
+/// Serves the HTTP API until the process is interrupted or the hub shuts
+/// down. Requests in flight get `SHUTDOWN_GRACE` to finish, after which
+/// connections still open, e.g. event streams, are dropped. With a `ui`
+/// directory, its files are served as well, with unknown paths falling
+/// back to its `index.html` so single page dashboards can do their own
+/// routing.
+pub async fn serve(app: Arc<App>, addr: SocketAddr, ui: Option<PathBuf>) -> Result<(), String> {
+    let stopping = Arc::new(Notify::new());
+    {
+        let stopping = stopping.clone();
+        app.on_lifecycle(Box::new(move |state| {
+            if state == AppState::STOPPING {
+                stopping.notify_one();
+            }
+        }));
+    }
+    let mut router = router(app);
+    if let Some(dir) = ui {
+        let index = ServeFile::new(dir.join("index.html"));
+        router = router.fallback_service(ServeDir::new(dir).fallback(index));
+    }
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    let drain = Arc::new(Notify::new());
+    let server = {
+        let drain = drain.clone();
+        axum::serve(listener, router).with_graceful_shutdown(async move { drain.notified().await })
+    };
+    let mut server = tokio::spawn(async move { server.await });
+    tokio::select! {
+        served = &mut server => return served_result(served),
+        _ = tokio::signal::ctrl_c() => {}
+        _ = stopping.notified() => {}
+    }
+    drain.notify_one();
+    match tokio::time::timeout(SHUTDOWN_GRACE, &mut server).await {
+        Ok(served) => served_result(served),
+        Err(_) => {
+            log::warn!("Dropping connections still open after shutting down");
+            server.abort();
+            Ok(())
+        }
+    }
+}
+
+fn served_result(
+    served: Result<std::io::Result<()>, tokio::task::JoinError>,
+) -> Result<(), String> {
+    served
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()))
+        .map_err(|e| format!("HTTP server failed: {}", e))
+}
+
+/// Runs a read-only SQL query over the event history.
+async fn query_history(
+    State(app): State<Arc<App>>,
+    Json(request): Json<QueryRequest>,
+) -> ApiResult<QueryResult> {
+    let result = tokio::task::spawn_blocking(move || app.history.query(&request))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    result
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+/**
+ * PurgeResult
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PurgeResult {
+    /// Journaled events deleted.
+    pub removed: usize,
+}
+
+/// Purging can't be undone, so only full-scope clients may do it.
+async fn purge_history(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+    Json(target): Json<PurgeTarget>,
+) -> ApiResult<PurgeResult> {
+    if scope != Scope::Full {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "Purging history needs a full-scope token",
+        ));
+    }
+    let result = tokio::task::spawn_blocking(move || app.purge(&target))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    result
+        .map(|removed| Json(PurgeResult { removed }))
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/**
+ * AggregateParams
+ * Query parameters of the aggregates endpoint. The range defaults to the last day.
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct AggregateParams {
+    pub key: String,
+    pub resolution: Resolution,
+    #[serde(default)]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Returns pre-computed min/max/mean buckets of a numeric device state.
+async fn device_aggregates(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Query(params): Query<AggregateParams>,
+) -> ApiResult<Vec<BucketView>> {
+    let to = params.to.unwrap_or_else(Utc::now);
+    let from = params.from.unwrap_or(to - Duration::days(1));
+    Ok(Json(app.aggregates.query(
+        &id,
+        &params.key,
+        params.resolution,
+        from,
+        to,
+    )))
+}
+
+/**
+ * HistoryParams
+ * Query parameters of the history endpoints: the latest `last` samples,
+ * or else those within a range that defaults to the last day. Buckets are
+ * `step_secs` long.
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct HistoryParams {
+    pub key: String,
+    #[serde(default)]
+    pub last: Option<usize>,
+    #[serde(default)]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub step_secs: Option<u64>,
+}
+
+impl HistoryParams {
+    fn range(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        let to = self.to.unwrap_or_else(Utc::now);
+        (self.from.unwrap_or(to - Duration::days(1)), to)
+    }
+}
+
+/// Returns recorded values of a device state, oldest first.
+async fn device_history(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Query(params): Query<HistoryParams>,
+) -> ApiResult<Vec<Sample>> {
+    if let Some(last) = params.last {
+        return Ok(Json(app.state_history.last_n(&id, &params.key, last)));
+    }
+    let (from, to) = params.range();
+    Ok(Json(app.state_history.range(&id, &params.key, from, to)))
+}
+
+/// Returns the state keys of a device that have recorded values.
+async fn history_keys(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<Vec<String>> {
+    Ok(Json(app.state_history.keys(&id)))
+}
+
+/// Returns recorded values of a device state downsampled to buckets of
+/// `step_secs`, by default an hour.
+async fn history_buckets(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Query(params): Query<HistoryParams>,
+) -> ApiResult<Vec<HistoryBucket>> {
+    let (from, to) = params.range();
+    let step = i64::try_from(params.step_secs.unwrap_or(3600))
+        .ok()
+        .and_then(Duration::try_seconds)
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "step_secs is too large"))?;
+    app.state_history
+        .downsample(&id, &params.key, from, to, step)
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+/**
+ * UsageSummary
+ * Usage of a device today and since tracking started.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsageSummary {
+    pub today: Usage,
+    pub total: Usage,
+    pub on: bool,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+/// Returns how often and how long a device was used.
+async fn device_usage(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<UsageSummary> {
+    let now = Utc::now();
+    let device = app.usage.all().remove(&id).unwrap_or_default();
+    Ok(Json(UsageSummary {
+        today: app.usage.usage(&id, UsagePeriod::Today, now),
+        total: app.usage.usage(&id, UsagePeriod::Total, now),
+        on: device.on_since.is_some(),
+        last_used: device.last_used,
+    }))
+}
+
+/// Returns the raw usage counters of every tracked device.
+async fn all_usage(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, DeviceUsage>> {
+    Ok(Json(app.usage.all()))
+}
+
+/// Returns areas, devices and scenes for dashboards to lay out their views.
+async fn ui_manifest(State(app): State<Arc<App>>) -> ApiResult<UiManifest> {
+    Ok(Json(
+        app.ui.manifest(&app.devices.read().unwrap(), &app.icons),
+    ))
+}
+
+async fn list_dashboards(State(app): State<Arc<App>>) -> ApiResult<Vec<Dashboard>> {
+    Ok(Json(app.dashboards.list()))
+}
+
+async fn get_dashboard(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<Dashboard> {
+    app.dashboards.get(&id).map(Json).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!("Dashboard '{}' not found", id),
+        )
+    })
+}
+
+/// Validates and stores a dashboard under the id in the path.
+async fn save_dashboard(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(dashboard): Json<Dashboard>,
+) -> ApiResult<Dashboard> {
+    if dashboard.id != id {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("Dashboard id '{}' does not match '{}'", dashboard.id, id),
+        ));
+    }
+    app.dashboards
+        .save(dashboard.clone(), &app.device_ids(), &app.ui)
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(Json(dashboard))
+}
+
+async fn delete_dashboard(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<Dashboard> {
+    app.dashboards
+        .remove(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| {
+            error(
+                StatusCode::NOT_FOUND,
+                format!("Dashboard '{}' not found", id),
+            )
+        })
+}
+
+async fn list_groups(State(app): State<Arc<App>>) -> ApiResult<Vec<DeviceGroup>> {
+    Ok(Json(app.groups.list()))
+}
+
+async fn get_group(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<DeviceGroup> {
+    app.groups
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Group '{}' not found", id)))
+}
+
+/// Validates and stores a group under the id in the path.
+async fn save_group(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(group): Json<DeviceGroup>,
+) -> ApiResult<DeviceGroup> {
+    if group.id != id {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("Group id '{}' does not match '{}'", group.id, id),
+        ));
+    }
+    app.groups
+        .save(group.clone(), &app.device_ids())
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(Json(group))
+}
+
+async fn delete_group(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<DeviceGroup> {
+    app.groups
+        .remove(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Group '{}' not found", id)))
+}
+
+/// Sends a command to every member of a group and returns how it went on
+/// each of them.
+async fn send_group_command(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(body): Json<CommandBody>,
+) -> ApiResult<Vec<MemberResult>> {
+    if app.groups.get(&id).is_none() {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("Group '{}' not found", id),
+        ));
+    }
+    app.send_group_command(&id, &body.command, body.parameters, body.lease.as_deref())
+        .await
+        .map(Json)
+        .map_err(device_error)
+}
+
+async fn list_scenes(State(app): State<Arc<App>>) -> ApiResult<Vec<Scene>> {
+    Ok(Json(app.scenes.list()))
+}
+
+async fn get_scene(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<Scene> {
+    app.scenes
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Scene '{}' not found", id)))
+}
+
+/// Validates and stores a scene written by hand under the id in the path.
+async fn save_scene(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(scene): Json<Scene>,
+) -> ApiResult<Scene> {
+    if scene.id != id {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("Scene id '{}' does not match '{}'", scene.id, id),
+        ));
+    }
+    app.scenes
+        .save(scene.clone(), &app.device_ids())
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(Json(scene))
+}
+
+async fn delete_scene(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<Scene> {
+    app.scenes
+        .remove(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Scene '{}' not found", id)))
+}
+
+/**
+ * SceneCapture
+ * What to capture as a scene: the devices to capture, see
+ * `DeviceSelector`, and the state keys to keep, all of them if unset.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SceneCapture {
+    pub name: String,
+    #[serde(flatten)]
+    pub selector: DeviceSelector,
+    #[serde(default)]
+    pub keys: Option<Vec<String>>,
+}
+
+/// Captures the current states of the requested devices as a scene.
+async fn capture_scene(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(capture): Json<SceneCapture>,
+) -> ApiResult<Scene> {
+    app.select_devices(&capture.selector)
+        .and_then(|devices| {
+            app.capture_scene(&id, &capture.name, &devices, capture.keys.as_deref())
+        })
+        .map(Json)
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))
+}
+
+/// Captures the current states of the requested devices as a new scene,
+/// e.g. from a dashboard button saving the current lighting.
+async fn capture_new_scene(
+    State(app): State<Arc<App>>,
+    Json(capture): Json<SceneCapture>,
+) -> Result<(StatusCode, Json<Scene>), (StatusCode, Json<ApiError>)> {
+    app.select_devices(&capture.selector)
+        .and_then(|devices| app.capture_new_scene(&capture.name, &devices, capture.keys.as_deref()))
+        .map(|scene| (StatusCode::CREATED, Json(scene)))
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))
+}
+
+/// Applies a scene and returns how it went on each of its devices.
+async fn apply_scene(
+    State(app): State<Arc<App>>,
+    account: Option<Extension<Account>>,
+    Path(id): Path<String>,
+) -> ApiResult<Vec<MemberResult>> {
+    if app.scenes.get(&id).is_none() {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("Scene '{}' not found", id),
+        ));
+    }
+    let actor = account.map(|Extension(Account(account))| account);
+    app.apply_scene(&id, actor.as_deref())
+        .await
+        .map(Json)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn list_users(State(app): State<Arc<App>>) -> ApiResult<Vec<UserStatus>> {
+    Ok(Json(app.users.list()))
+}
+
+async fn get_user(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<UserStatus> {
+    app.users
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("User '{}' not found", id)))
+}
+
+/// Validates and stores a user under the id in the path.
+async fn save_user(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(user): Json<User>,
+) -> ApiResult<UserStatus> {
+    if user.id != id {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("User id '{}' does not match '{}'", user.id, id),
+        ));
+    }
+    app.users
+        .save(user, &app.access.accounts(), &app.notifications.channels())
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    get_user(State(app), Path(id)).await
+}
+
+async fn delete_user(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<User> {
+    app.users
+        .remove(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("User '{}' not found", id)))
+}
+
+async fn set_user_preferences(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(preferences): Json<Preferences>,
+) -> ApiResult<UserStatus> {
+    if app.users.get(&id).is_none() {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("User '{}' not found", id),
+        ));
+    }
+    app.users
+        .set_preferences(
+            &id,
+            preferences,
+            &app.access.accounts(),
+            &app.notifications.channels(),
+        )
+        .map(Json)
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))
+}
+
+/// User linked to the account of the request's token.
+fn signed_in(
+    app: &App,
+    account: Option<Extension<Account>>,
+) -> Result<UserStatus, (StatusCode, Json<ApiError>)> {
+    let Some(Extension(Account(account))) = account else {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            "Requests without a token have no user",
+        ));
+    };
+    app.users.for_account(&account).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!("No user is linked to account '{}'", account),
+        )
+    })
+}
+
+async fn current_user(
+    State(app): State<Arc<App>>,
+    account: Option<Extension<Account>>,
+) -> ApiResult<UserStatus> {
+    signed_in(&app, account).map(Json)
+}
+
+/// Lets users change their own preferences.
+async fn set_current_preferences(
+    State(app): State<Arc<App>>,
+    account: Option<Extension<Account>>,
+    Json(preferences): Json<Preferences>,
+) -> ApiResult<UserStatus> {
+    let user = signed_in(&app, account)?;
+    set_user_preferences(State(app), Path(user.user.id), Json(preferences)).await
+}
+
+/**
+ * IconRequest
+ * Body of the icon endpoint; `null` goes back to the default icon.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IconRequest {
+    pub icon: Option<String>,
+}
+
+fn known_device(app: &App, id: &str) -> Result<(), (StatusCode, Json<ApiError>)> {
+    if app.device_ids().iter().any(|known| known == id) {
+        Ok(())
+    } else {
+        Err(error(
+            StatusCode::NOT_FOUND,
+            format!("Device '{}' not found", id),
+        ))
+    }
+}
+
+async fn set_icon(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(request): Json<IconRequest>,
+) -> ApiResult<IconRequest> {
+    known_device(&app, &id)?;
+    app.icons
+        .set_icon(&id, request.icon.clone())
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(request))
+}
+
+async fn get_picture(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiError>)> {
+    let (content_type, bytes) = app
+        .icons
+        .picture(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("No picture for '{}'", id)))?;
+    Ok(([(header::CONTENT_TYPE, content_type)], bytes))
+}
+
+/// Stores the request body as the device's picture, typed by its `Content-Type`.
+async fn set_picture(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    known_device(&app, &id)?;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    app.icons
+        .set_picture(&id, content_type, &body)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_picture(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    match app.icons.remove_picture(&id) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(error(
+            StatusCode::NOT_FOUND,
+            format!("No picture for '{}'", id),
+        )),
+        Err(e) => Err(error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+/**
+ * DeviceSummary
+ * A device with its type, the commands it accepts, what it can do and its
+ * current state.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceSummary {
+    pub id: String,
+    pub name: String,
+    pub device_type: Option<Type>,
+    /// Commands the device accepts, `None` if it doesn't tell.
+    pub commands: Option<Vec<String>>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    pub state: StateMap,
+}
+
+/// Summaries of all devices, sync and async, by id.
+fn device_summaries(app: &App) -> Vec<DeviceSummary> {
+    let mut devices: Vec<DeviceSummary> = app
+        .devices
+        .read()
+        .unwrap()
+        .iter()
+        .map(|device| DeviceSummary {
+            id: device.get_id().to_string(),
+            name: device.get_name().to_string(),
+            device_type: device.get_type(),
+            commands: device.commands(),
+            capabilities: device.capabilities(),
+            state: device.get_state(),
+        })
+        .chain(
+            app.async_registry
+                .devices()
+                .iter()
+                .map(|device| DeviceSummary {
+                    id: device.get_id().to_string(),
+                    name: device.get_name().to_string(),
+                    device_type: device.get_type(),
+                    commands: device.commands(),
+                    capabilities: device.capabilities(),
+                    state: device.get_state(),
+                }),
+        )
+        .collect();
+    devices.sort_by(|a, b| a.id.cmp(&b.id));
+    devices
+}
+
+async fn list_devices(State(app): State<Arc<App>>) -> ApiResult<Vec<DeviceSummary>> {
+    Ok(Json(device_summaries(&app)))
+}
+
+async fn get_device(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<DeviceSummary> {
+    device_summaries(&app)
+        .into_iter()
+        .find(|device| device.id == id)
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Device '{}' not found", id)))
+}
+
+async fn device_states(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, StateMap>> {
+    Ok(Json(app.device_states()))
+}
+
+/// Devices discovery on start found and that aren't set up yet, as
+/// configs to review and register.
+async fn discovered_devices(State(app): State<Arc<App>>) -> ApiResult<Vec<Config>> {
+    Ok(Json(app.discovered.read().unwrap().clone()))
+}
+
+/// Creates a device from its config and stores it, so it is created again
+/// after a restart.
+async fn register_device(
+    State(app): State<Arc<App>>,
+    Json(config): Json<Config>,
+) -> ApiResult<Config> {
+    app.register_device(config.clone()).map_err(device_error)?;
+    Ok(Json(config))
+}
+
+/// The device's config and customizations to share, without its secrets.
+async fn device_snippet(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<DeviceSnippet> {
+    app.export_device(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Device '{}' not found", id)))
+}
+
+/**
+ * ImportRequest
+ * Body of the import endpoint: a device snippet, the id to register it
+ * under instead of its own and connection details to set, which must
+ * include its secrets.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImportRequest {
+    pub snippet: DeviceSnippet,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub connection_details: HashMap<String, String>,
+}
+
+/// Registers the device of a snippet and answers with the snippet of the
+/// registered device.
+async fn import_device(
+    State(app): State<Arc<App>>,
+    Json(request): Json<ImportRequest>,
+) -> ApiResult<DeviceSnippet> {
+    let config = app
+        .import_device(
+            &request.snippet,
+            request.id.as_deref(),
+            &request.connection_details,
+        )
+        .map_err(device_error)?;
+    let icon = app.icons.icon(&config.id);
+    Ok(Json(DeviceSnippet::new(config, icon)))
+}
+
+/**
+ * UnregisterParams
+ * Query parameters of removing a device. Devices rules or scenes still
+ * use are only removed with `force`.
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct UnregisterParams {
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Removes a device registered through the API. Devices from a config file
+/// can't be removed this way.
+async fn unregister_device(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Query(params): Query<UnregisterParams>,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    let rules = app.rules.rules_using_device(&id);
+    let scenes: Vec<String> = app
+        .scenes
+        .list()
+        .into_iter()
+        .filter(|scene| scene.states.contains_key(&id))
+        .map(|scene| scene.id)
+        .collect();
+    if !rules.is_empty() || !scenes.is_empty() {
+        let mut users = Vec::new();
+        if !rules.is_empty() {
+            users.push(format!("rules {}", rules.join(", ")));
+        }
+        if !scenes.is_empty() {
+            users.push(format!("scenes {}", scenes.join(", ")));
+        }
+        let users = users.join(" and ");
+        if !params.force {
+            return Err(error(
+                StatusCode::CONFLICT,
+                format!(
+                    "Device '{}' is used by {}; remove it with force=true anyway",
+                    id, users
+                ),
+            ));
+        }
+        log::warn!("Removing device '{}' still used by {}", id, users);
+    }
+    match app.unregister_device(&id) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(error(StatusCode::NOT_FOUND, "Unknown registered device")),
+        Err(e) => Err(error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+/**
+ * HandlerStatus
+ * A registered protocol handler and whether it is healthy.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HandlerStatus {
+    pub name: String,
+    pub protocols: Vec<String>,
+    pub priority: u8,
+    #[serde(default)]
+    pub api_version: Option<ApiVersion>,
+    pub healthy: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Set while the handler is in maintenance, when being unhealthy is
+    /// expected and shouldn't be alerted on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceFlag>,
+}
+
+async fn handlers(State(app): State<Arc<App>>) -> ApiResult<Vec<HandlerStatus>> {
+    let mut handlers: Vec<HandlerStatus> = {
+        let registry = app.protocol_registry.read().unwrap();
+        registry
+            .all_handlers()
+            .iter()
+            .map(|handler| {
+                let handler = handler.read().unwrap();
+                let health = handler.health();
+                HandlerStatus {
+                    name: handler.name(),
+                    protocols: handler.supported_protocols(),
+                    priority: handler.priority(),
+                    api_version: registry.api_version(&handler.name()),
+                    healthy: health.is_ok(),
+                    error: health.err().map(String::from),
+                    maintenance: app.maintenance.handler(&handler.name()),
+                }
+            })
+            .collect()
+    };
+    for handler in app.async_registry.all_handlers() {
+        let health = handler.health().await;
+        handlers.push(HandlerStatus {
+            name: handler.name(),
+            protocols: handler.supported_protocols(),
+            priority: handler.priority(),
+            api_version: app.async_registry.api_version(&handler.name()),
+            healthy: health.is_ok(),
+            error: health.err().map(String::from),
+            maintenance: app.maintenance.handler(&handler.name()),
+        });
+    }
+    handlers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(handlers))
+}
+
+/**
+ * MaintenanceRequest
+ * Body putting a device or handler in maintenance.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MaintenanceRequest {
+    pub duration_secs: i64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+async fn maintenance_flags(State(app): State<Arc<App>>) -> ApiResult<MaintenanceFlags> {
+    Ok(Json(app.maintenance.flags()))
+}
+
+async fn maintenance_jobs(State(app): State<Arc<App>>) -> ApiResult<BTreeMap<String, JobMetrics>> {
+    Ok(Json(app.maintenance_jobs.metrics()))
+}
+
+/// Runs a housekeeping job now, answering with its metrics once it is done.
+async fn run_maintenance_job(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+) -> ApiResult<JobMetrics> {
+    if !app.maintenance_jobs.metrics().contains_key(&name) {
+        return Err(error(StatusCode::NOT_FOUND, "Unknown maintenance job"));
+    }
+    app.maintenance_jobs
+        .run_job(&name)
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(
+        app.maintenance_jobs
+            .metrics()
+            .remove(&name)
+            .unwrap_or_default(),
+    ))
+}
+
+async fn set_device_maintenance(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(request): Json<MaintenanceRequest>,
+) -> ApiResult<MaintenanceFlag> {
+    known_device(&app, &id)?;
+    app.maintenance
+        .set_device(&id, request.duration_secs, request.reason)
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+async fn clear_device_maintenance(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<MaintenanceFlag> {
+    app.maintenance
+        .clear_device(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| {
+            error(
+                StatusCode::NOT_FOUND,
+                format!("Device '{}' is not in maintenance", id),
+            )
+        })
+}
+
+async fn set_handler_maintenance(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+    Json(request): Json<MaintenanceRequest>,
+) -> ApiResult<MaintenanceFlag> {
+    let registered = app
+        .protocol_registry
+        .read()
+        .unwrap()
+        .api_version(&name)
+        .or_else(|| app.async_registry.api_version(&name));
+    if registered.is_none() {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("Handler '{}' not found", name),
+        ));
+    }
+    app.maintenance
+        .set_handler(&name, request.duration_secs, request.reason)
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+async fn clear_handler_maintenance(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+) -> ApiResult<MaintenanceFlag> {
+    app.maintenance
+        .clear_handler(&name)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| {
+            error(
+                StatusCode::NOT_FOUND,
+                format!("Handler '{}' is not in maintenance", name),
+            )
+        })
+}
+
+async fn command_latency(
+    State(app): State<Arc<App>>,
+) -> ApiResult<HashMap<String, CommandLatency>> {
+    Ok(Json(app.latency.all()))
+}
+
+/**
+ * CommandBody
+ * Body of the command endpoint.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandBody {
+    pub command: String,
+    #[serde(default)]
+    pub parameters: Option<HashMap<String, String>>,
+    /// Token of the lease the sender holds on the device, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease: Option<String>,
+}
+
+/// Sends a command to a device and returns the published event with the
+/// device's response.
+async fn send_command(
+    State(app): State<Arc<App>>,
+    account: Option<Extension<Account>>,
+    Path(id): Path<String>,
+    Json(body): Json<CommandBody>,
+) -> ApiResult<CommandResult> {
+    app.leases
+        .check(&id, body.lease.as_deref())
+        .map_err(|e| error(StatusCode::CONFLICT, e))?;
+    let actor = account.map(|Extension(Account(account))| account);
+    app.send_command_async_as(
+        actor.as_deref(),
+        &id,
+        &body.command,
+        body.parameters,
+        body.lease.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(device_error)
+}
+
+/**
+ * OutputFrameParams
+ * Query parameters of an output frame: the lease held on the device, if
+ * it is leased, and when the frame is to show, if not right away.
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct OutputFrameParams {
+    #[serde(default)]
+    pub lease: Option<String>,
+    #[serde(default)]
+    pub at: Option<DateTime<Utc>>,
+}
+
+/// Writes the request body to the device as an output frame, e.g. the RGB
+/// bytes of every LED of a strip.
+async fn write_frame(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Query(params): Query<OutputFrameParams>,
+    body: Bytes,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    app.leases
+        .check(&id, params.lease.as_deref())
+        .map_err(|e| error(StatusCode::CONFLICT, e))?;
+    app.write_frame(&id, body, params.lease.as_deref(), params.at)
+        .await
+        .map_err(device_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn frame_pacing(State(app): State<Arc<App>>) -> ApiResult<BTreeMap<String, OutputTiming>> {
+    Ok(Json(app.pacing.status()))
+}
+
+async fn effects(State(app): State<Arc<App>>) -> ApiResult<Vec<EffectStatus>> {
+    Ok(Json(app.effects.status()))
+}
+
+async fn get_effect(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+) -> ApiResult<EffectStatus> {
+    app.effects.get(&name).map(Json).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!("Effect '{}' not running", name),
+        )
+    })
+}
+
+/// Starts an effect, or changes the one running under the name.
+async fn start_effect(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+    Json(definition): Json<EffectDefinition>,
+) -> ApiResult<EffectStatus> {
+    app.start_effect(&name, definition)
+        .map(Json)
+        .map_err(device_error)
+}
+
+async fn stop_effect(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+) -> ApiResult<EffectStatus> {
+    app.effects.stop(&name).map(Json).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!("Effect '{}' not running", name),
+        )
+    })
+}
+
+/// Answers a failed device operation with a status telling what went wrong.
+fn device_error(e: BlinkieError) -> (StatusCode, Json<ApiError>) {
+    let status = match &e {
+        BlinkieError::Connection(_) => StatusCode::BAD_GATEWAY,
+        BlinkieError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        BlinkieError::LockPoisoned(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    error(status, e)
+}
+
+/**
+ * LeaseRequest
+ * Body of a claim on a device. Carrying the token of the current lease
+ * renews it.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeaseRequest {
+    pub holder: String,
+    pub duration_secs: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/**
+ * LeaseGrant
+ * A granted claim with the token commands to the device must carry.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeaseGrant {
+    #[serde(flatten)]
+    pub lease: Lease,
+    pub token: String,
+}
+
+/**
+ * LeaseRelease
+ * Body releasing a lease early.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeaseRelease {
+    pub token: String,
+}
+
+async fn get_lease(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<Lease> {
+    known_device(&app, &id)?;
+    app.leases.get(&id).map(Json).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!("Device '{}' is not claimed", id),
+        )
+    })
+}
+
+/// Claims a device for exclusive control, or renews a claim.
+async fn claim_lease(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(request): Json<LeaseRequest>,
+) -> ApiResult<LeaseGrant> {
+    known_device(&app, &id)?;
+    let (lease, token) = app
+        .leases
+        .claim(
+            &id,
+            &request.holder,
+            request.duration_secs,
+            request.token.as_deref(),
+        )
+        .map_err(|e| error(StatusCode::CONFLICT, e))?;
+    Ok(Json(LeaseGrant { lease, token }))
+}
+
+async fn release_lease(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(request): Json<LeaseRelease>,
+) -> ApiResult<Lease> {
+    known_device(&app, &id)?;
+    app.leases
+        .release(&id, &request.token)
+        .map(Json)
+        .map_err(|e| error(StatusCode::CONFLICT, e))
+}
+
+async fn list_jobs(State(app): State<Arc<App>>) -> ApiResult<Vec<Job>> {
+    Ok(Json(app.jobs.list()))
+}
+
+async fn get_job(State(app): State<Arc<App>>, Path(id): Path<u64>) -> ApiResult<Job> {
+    app.jobs
+        .get(id)
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Job {} not found", id)))
+}
+
+/// Cancels a running job.
+async fn cancel_job(State(app): State<Arc<App>>, Path(id): Path<u64>) -> ApiResult<Job> {
+    if app.jobs.get(id).is_none() {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("Job {} not found", id),
+        ));
+    }
+    app.jobs
+        .cancel(id)
+        .map(Json)
+        .map_err(|e| error(StatusCode::CONFLICT, e))
+}
+
+/**
+ * TemplateBody
+ * Body of the template endpoint.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TemplateBody {
+    pub template: String,
+}
+
+/**
+ * TemplateResult
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TemplateResult {
+    pub output: String,
+}
+
+/// Renders a template against current device states and global variables.
+async fn render_template(
+    State(app): State<Arc<App>>,
+    Json(body): Json<TemplateBody>,
+) -> ApiResult<TemplateResult> {
+    Ok(Json(TemplateResult {
+        output: app.rules.render(&body.template),
+    }))
+}
+
+fn default_sniff_secs() -> i64 {
+    600
+}
+
+/**
+ * SnifferRequest
+ * Body of the sniffer endpoint. Capturing stops by itself after
+ * `duration_secs`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnifferRequest {
+    pub enabled: bool,
+    #[serde(default = "default_sniff_secs")]
+    pub duration_secs: i64,
+}
+
+async fn sniffer_status(State(app): State<Arc<App>>) -> ApiResult<SnifferStatus> {
+    Ok(Json(app.sniffer.status()))
+}
+
+/// Starts or stops capturing protocol frames.
+async fn set_sniffer(
+    State(app): State<Arc<App>>,
+    Json(request): Json<SnifferRequest>,
+) -> ApiResult<SnifferStatus> {
+    if request.enabled {
+        if request.duration_secs <= 0 {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                "duration_secs must be positive",
+            ));
+        }
+        app.sniffer.enable(Duration::seconds(request.duration_secs));
+    } else {
+        app.sniffer.disable();
+    }
+    Ok(Json(app.sniffer.status()))
+}
+
+async fn clear_sniffer(State(app): State<Arc<App>>) -> ApiResult<SnifferStatus> {
+    app.sniffer.clear();
+    Ok(Json(app.sniffer.status()))
+}
+
+fn default_frame_limit() -> usize {
+    200
+}
+
+/**
+ * FrameParams
+ * Query parameters of the sniffer frames endpoint. Clients following the
+ * capture pass the last sequence number they saw as `after`.
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct FrameParams {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub after: u64,
+    #[serde(default = "default_frame_limit")]
+    pub limit: usize,
+}
+
+async fn sniffer_frames(
+    State(app): State<Arc<App>>,
+    Query(params): Query<FrameParams>,
+) -> ApiResult<Vec<Frame>> {
+    Ok(Json(app.sniffer.frames(
+        params.device_id.as_deref(),
+        params.after,
+        params.limit,
+    )))
+}
+
+/**
+ * UplinkStatus
+ * The link of a satellite to its hub.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UplinkStatus {
+    pub satellite_id: String,
+    pub url: String,
+    pub acked_seq: u64,
+    pub metrics: SyncMetrics,
+}
+
+/**
+ * FederationStatus
+ * Satellites syncing to this hub, and its own uplink when it is one.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FederationStatus {
+    pub satellites: Vec<SatelliteStatus>,
+    #[serde(default)]
+    pub uplink: Option<UplinkStatus>,
+}
+
+async fn federation_status(State(app): State<Arc<App>>) -> ApiResult<FederationStatus> {
+    Ok(Json(FederationStatus {
+        satellites: app.federation.satellites(),
+        uplink: app.uplink.as_ref().map(|uplink| UplinkStatus {
+            satellite_id: uplink.satellite_id.clone(),
+            url: uplink.url.clone(),
+            acked_seq: uplink.acked_seq(),
+            metrics: uplink.metrics(),
+        }),
+    }))
+}
+
+/// Applies a gzipped batch of state changes from a satellite.
+async fn federation_sync(State(app): State<Arc<App>>, body: Bytes) -> ApiResult<SyncAck> {
+    app.federation
+        .receive(&body)
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+async fn satellite_states(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<States> {
+    app.federation.states(&id).map(Json).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!("Satellite '{}' not found", id),
+        )
+    })
+}
+
+/**
+ * ClockStatus
+ * Clock offsets of devices reporting timestamps and of satellites, and
+ * which of them are skewed.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClockStatus {
+    pub max_skew_secs: f64,
+    pub devices: HashMap<String, ClockOffset>,
+    pub satellites: HashMap<String, ClockOffset>,
+    /// Ids of skewed devices and satellites.
+    pub skewed: Vec<String>,
+}
+
+async fn device_ages(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<HashMap<String, StateAge>> {
+    // Devices of satellites are only known by their state changes
+    let ages = app.clock.ages(&id, Utc::now());
+    if ages.is_empty() && !app.device_ids().contains(&id) {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("Device '{}' not found", id),
+        ));
+    }
+    Ok(Json(ages))
+}
+
+/**
+ * NotificationStatus
+ * Registered channels and the policy routing notifications to them.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct NotificationStatus {
+    pub channels: Vec<String>,
+    pub policy: NotificationPolicy,
+}
+
+async fn send_notification(
+    State(app): State<Arc<App>>,
+    Json(notification): Json<Notification>,
+) -> ApiResult<HashMap<String, Delivery>> {
+    // Channels send through blocking clients
+    tokio::task::spawn_blocking(move || app.notifications.notify(notification, Utc::now()))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+/// Answers to inline buttons of Telegram notifications. Other updates are
+/// acknowledged and ignored, so Telegram doesn't retry them.
+async fn telegram_update(State(app): State<Arc<App>>, body: Bytes) -> StatusCode {
+    let update: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+    if let Some((webhook, payload)) = telegram_callback(&update) {
+        if let Err(e) = app.webhooks.receive(&webhook, payload) {
+            log::warn!("Ignoring Telegram answer: {}", e);
+        }
+    }
+    StatusCode::OK
+}
+
+/**
+ * WebhookRequest
+ * A webhook to create, expiring after `expires_secs` if set.
+ */
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    pub event: String,
+    #[serde(default)]
+    pub data: HashMap<String, String>,
+    #[serde(default)]
+    pub expires_secs: Option<i64>,
+}
+
+/**
+ * CreatedWebhook
+ * Id of a new webhook and the path it is called on.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct CreatedWebhook {
+    pub id: String,
+    pub path: String,
+}
+
+/// Webhook ids are secrets, so only full-scope clients may list them.
+async fn list_webhooks(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+) -> ApiResult<HashMap<String, Webhook>> {
+    if scope != Scope::Full {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "Listing webhooks needs a full-scope token",
+        ));
+    }
+    Ok(Json(app.webhooks.list()))
+}
+
+async fn create_webhook(
+    State(app): State<Arc<App>>,
+    Json(request): Json<WebhookRequest>,
+) -> ApiResult<CreatedWebhook> {
+    let webhook = Webhook {
+        event: request.event,
+        data: request.data,
+        expires: None,
+        once_group: None,
+    };
+    let id = match request.expires_secs {
+        Some(secs) => app.webhooks.create_for(webhook, secs),
+        None => app.webhooks.create(webhook),
+    }
+    .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    Ok(Json(CreatedWebhook {
+        path: format!("{}{}", WEBHOOK_PATH, id),
+        id,
+    }))
+}
+
+async fn delete_webhook(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<Webhook> {
+    app.webhooks
+        .remove(&id)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Unknown webhook"))
+}
+
+/// Publishes a webhook's event with the fields of a JSON object body, or
+/// the raw body as `body`.
+async fn receive_webhook(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> ApiResult<Event> {
+    let payload: HashMap<String, String> = match serde_json::from_slice(&body) {
+        Ok(serde_json::Value::Object(fields)) => fields
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            })
+            .collect(),
+        _ if body.is_empty() => HashMap::new(),
+        _ => HashMap::from([(
+            "body".to_string(),
+            String::from_utf8_lossy(&body).into_owned(),
+        )]),
+    };
+    app.webhooks
+        .receive(&id, payload)
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+async fn notification_policy(State(app): State<Arc<App>>) -> ApiResult<NotificationStatus> {
+    Ok(Json(NotificationStatus {
+        channels: app.notifications.channels(),
+        policy: app.notifications.policy(),
+    }))
+}
+
+async fn set_notification_policy(
+    State(app): State<Arc<App>>,
+    Json(policy): Json<NotificationPolicy>,
+) -> ApiResult<NotificationStatus> {
+    app.notifications
+        .set_policy(policy)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    notification_policy(State(app)).await
+}
+
+async fn pending_notifications(
+    State(app): State<Arc<App>>,
+) -> ApiResult<HashMap<String, PendingNotifications>> {
+    Ok(Json(app.notifications.pending()))
+}
+
+async fn doorbells(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, DoorbellStatus>> {
+    Ok(Json(app.doorbells.status()))
+}
+
+async fn doorbell_config(State(app): State<Arc<App>>) -> ApiResult<DoorbellConfig> {
+    Ok(Json(app.doorbells.config()))
+}
+
+async fn set_doorbell_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<DoorbellConfig>,
+) -> ApiResult<DoorbellConfig> {
+    app.doorbells
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    doorbell_config(State(app)).await
+}
+
+/// Rings a doorbell as if it was pressed, e.g. to try its notification.
+async fn ring_doorbell(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<Option<DoorbellStatus>> {
+    // The camera and the channels are blocking
+    tokio::task::spawn_blocking(move || app.doorbells.ring(&id, Utc::now()))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+/**
+ * IgnoreRequest
+ * How long to ignore a doorbell; its configured time if unset.
+ */
+#[derive(Default, Deserialize)]
+pub struct IgnoreRequest {
+    #[serde(default)]
+    pub secs: Option<u64>,
+}
+
+async fn ignore_doorbell(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    body: Option<Json<IgnoreRequest>>,
+) -> ApiResult<DoorbellStatus> {
+    let request = body.map(|Json(request)| request).unwrap_or_default();
+    app.doorbells
+        .ignore(&id, request.secs, Utc::now())
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+async fn unignore_doorbell(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<DoorbellStatus> {
+    app.doorbells
+        .unignore(&id)
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+/// Doorbell snapshots, fetched by push services without a token.
+async fn doorbell_snapshot(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiError>)> {
+    let snapshot = app
+        .doorbells
+        .snapshot(&id)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Unknown or expired snapshot"))?;
+    Ok((
+        [(header::CONTENT_TYPE, snapshot.content_type)],
+        snapshot.data,
+    ))
+}
+
+async fn occupancy(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, AreaOccupancy>> {
+    Ok(Json(app.occupancy.status(Utc::now())))
+}
+
+async fn occupancy_config(State(app): State<Arc<App>>) -> ApiResult<OccupancyConfig> {
+    Ok(Json(app.occupancy.config()))
+}
+
+async fn set_occupancy_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<OccupancyConfig>,
+) -> ApiResult<OccupancyConfig> {
+    app.occupancy
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    occupancy_config(State(app)).await
+}
+
+async fn predictions(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, Prediction>> {
+    Ok(Json(app.predictions.latest()))
+}
+
+async fn prediction_config(State(app): State<Arc<App>>) -> ApiResult<PredictionConfig> {
+    Ok(Json(app.predictions.config()))
+}
+
+async fn set_prediction_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<PredictionConfig>,
+) -> ApiResult<PredictionConfig> {
+    app.predictions
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    prediction_config(State(app)).await
+}
+
+/// Exports the feature window a model currently sees, e.g. for an outside
+/// service to predict from or to collect training data.
+async fn prediction_window(
+    State(app): State<Arc<App>>,
+    Path(model): Path<String>,
+) -> ApiResult<FeatureWindow> {
+    // Reading the journal blocks
+    tokio::task::spawn_blocking(move || app.predictions.window(&model, Utc::now()))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+/// Takes a prediction posted back by an outside service.
+async fn accept_prediction(
+    State(app): State<Arc<App>>,
+    Path(model): Path<String>,
+    Json(outputs): Json<HashMap<String, f64>>,
+) -> ApiResult<Prediction> {
+    app.predictions
+        .accept(&model, outputs, Utc::now())
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+/// Runs a model now instead of waiting for its interval.
+async fn run_prediction(
+    State(app): State<Arc<App>>,
+    Path(model): Path<String>,
+) -> ApiResult<Prediction> {
+    // Backends block on their service or command
+    tokio::task::spawn_blocking(move || app.predictions.predict(&model, Utc::now()))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_GATEWAY, e))
+}
+
+async fn energy(State(app): State<Arc<App>>) -> ApiResult<EnergyStatus> {
+    Ok(Json(app.energy.status()))
+}
+
+async fn energy_config(State(app): State<Arc<App>>) -> ApiResult<EnergyConfig> {
+    Ok(Json(app.energy.config()))
+}
+
+async fn set_energy_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<EnergyConfig>,
+) -> ApiResult<EnergyConfig> {
+    app.energy
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    energy_config(State(app)).await
+}
+
+/**
+ * OverrideRequest
+ * Holds a load on or off, for `duration_secs` if given, or hands it back
+ * to the energy manager with `auto`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OverrideRequest {
+    pub mode: Mode,
+    #[serde(default)]
+    pub duration_secs: Option<i64>,
+}
+
+async fn set_energy_override(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    Json(request): Json<OverrideRequest>,
+) -> ApiResult<EnergyStatus> {
+    app.energy
+        .set_override(
+            &id,
+            request.mode,
+            request.duration_secs.map(Duration::seconds),
+        )
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))?;
+    energy(State(app)).await
+}
+
+async fn prices(State(app): State<Arc<App>>) -> ApiResult<PriceStatus> {
+    Ok(Json(app.prices.status()))
+}
+
+async fn price_config(State(app): State<Arc<App>>) -> ApiResult<PriceConfig> {
+    Ok(Json(app.prices.config()))
+}
+
+async fn set_price_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<PriceConfig>,
+) -> ApiResult<PriceConfig> {
+    app.prices
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    price_config(State(app)).await
+}
+
+/// Fetches prices right away rather than at the next refresh.
+async fn refresh_prices(State(app): State<Arc<App>>) -> StatusCode {
+    app.prices.refresh();
+    StatusCode::ACCEPTED
+}
+
+async fn schedules(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, JobStatus>> {
+    Ok(Json(app.scheduler.status()))
+}
+
+async fn scheduler_config(State(app): State<Arc<App>>) -> ApiResult<SchedulerConfig> {
+    Ok(Json(app.scheduler.config()))
+}
+
+async fn set_scheduler_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<SchedulerConfig>,
+) -> ApiResult<SchedulerConfig> {
+    config
+        .validate()
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    app.scheduler
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    scheduler_config(State(app)).await
+}
+
+/// Runs a scheduled job now, leaving its next run where it was.
+async fn run_schedule(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+) -> ApiResult<JobStatus> {
+    app.scheduler
+        .run_now(&name)
+        .await
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Unknown schedule"))
+}
+
+/// Guest codes open doors, so only full-scope clients may see or manage
+/// them.
+fn full_scope(scope: Scope) -> Result<(), (StatusCode, Json<ApiError>)> {
+    if scope != Scope::Full {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "Guest codes need a full-scope token",
+        ));
+    }
+    Ok(())
+}
+
+async fn guest_codes(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+) -> ApiResult<Vec<GuestCodeStatus>> {
+    full_scope(scope)?;
+    Ok(Json(app.guests.list(Utc::now())))
+}
+
+async fn guest_code(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+    Path(id): Path<String>,
+) -> ApiResult<GuestCodeStatus> {
+    full_scope(scope)?;
+    app.guests.get(&id, Utc::now()).map(Json).ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            format!("Guest code '{}' not found", id),
+        )
+    })
+}
+
+/// Adds a guest code, e.g. for a booking, and returns it with the code
+/// generated if none was given.
+async fn create_guest_code(
+    State(app): State<Arc<App>>,
+    Json(request): Json<CodeRequest>,
+) -> Result<(StatusCode, Json<GuestCodeStatus>), (StatusCode, Json<ApiError>)> {
+    app.guests
+        .create(request, Utc::now())
+        .map(|code| (StatusCode::CREATED, Json(code)))
+        .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, e))
+}
+
+/// Revokes a guest code, clearing it from its devices.
+async fn revoke_guest_code(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<GuestCodeStatus> {
+    app.guests
+        .revoke(&id, Utc::now())
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or_else(|| {
+            error(
+                StatusCode::NOT_FOUND,
+                format!("Guest code '{}' not found", id),
+            )
+        })
+}
+
+async fn guest_config(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+) -> ApiResult<GuestConfig> {
+    full_scope(scope)?;
+    Ok(Json(app.guests.config()))
+}
+
+async fn set_guest_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<GuestConfig>,
+) -> ApiResult<GuestConfig> {
+    config
+        .validate(&app.device_ids())
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    app.guests
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(app.guests.config()))
+}
+
+async fn locks(State(app): State<Arc<App>>) -> ApiResult<Vec<LockStatus>> {
+    Ok(Json(app.locks.locks()))
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+/**
+ * AuditParams
+ * Query of the lock audit: the newest `limit` records, of one lock if
+ * `device_id` is given.
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuditParams {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default = "default_audit_limit")]
+    pub limit: usize,
+}
+
+/// Who operated which lock when. Needs a full-access token, as it tells
+/// when people come and go.
+async fn lock_audit(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+    Query(params): Query<AuditParams>,
+) -> ApiResult<Vec<LockRecord>> {
+    full_scope(scope)?;
+    app.locks
+        .records(params.device_id.as_deref(), params.limit)
+        .map(Json)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn pumps(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, PumpStatus>> {
+    Ok(Json(app.pumps.status()))
+}
+
+async fn pump_config(State(app): State<Arc<App>>) -> ApiResult<PumpConfig> {
+    Ok(Json(app.pumps.config()))
+}
+
+async fn set_pump_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<PumpConfig>,
+) -> ApiResult<PumpConfig> {
+    app.pumps
+        .set_config(config)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    pump_config(State(app)).await
+}
+
+/// Clears the trip of a pump stopped for lack of flow, so it may be
+/// started again.
+async fn reset_pump(State(app): State<Arc<App>>, Path(id): Path<String>) -> ApiResult<PumpStatus> {
+    app.pumps
+        .reset(&id)
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+async fn chaos_status(State(app): State<Arc<App>>) -> ApiResult<ChaosStatus> {
+    Ok(Json(app.chaos.status()))
+}
+
+/// Turns chaos mode on, or restarts it, with the given profile.
+async fn set_chaos(
+    State(app): State<Arc<App>>,
+    Json(profile): Json<ChaosProfile>,
+) -> ApiResult<ChaosStatus> {
+    app.chaos
+        .set_profile(Some(profile))
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    chaos_status(State(app)).await
+}
+
+async fn clear_chaos(State(app): State<Arc<App>>) -> ApiResult<ChaosStatus> {
+    app.chaos
+        .set_profile(None)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    chaos_status(State(app)).await
+}
+
+async fn queue_status(State(app): State<Arc<App>>) -> ApiResult<QueueStatus> {
+    Ok(Json(app.queue.status()))
+}
+
+async fn queue_config(State(app): State<Arc<App>>) -> ApiResult<QueueConfig> {
+    Ok(Json(app.queue.config()))
+}
+
+async fn set_queue_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<QueueConfig>,
+) -> ApiResult<QueueConfig> {
+    app.queue
+        .set_config(config)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    queue_config(State(app)).await
+}
+
+async fn clear_dead_letters(State(app): State<Arc<App>>) -> ApiResult<QueueStatus> {
+    app.queue.clear_dead_letters();
+    queue_status(State(app)).await
+}
+
+/// Stores failing at runtime, while the hub runs in memory.
+async fn storage_status(State(app): State<Arc<App>>) -> ApiResult<StorageStatus> {
+    Ok(Json(app.storage.status()))
+}
+
+async fn memory_status(State(app): State<Arc<App>>) -> ApiResult<MemoryStatus> {
+    Ok(Json(app.memory()))
+}
+
+async fn availability(State(app): State<Arc<App>>) -> ApiResult<BTreeMap<String, DeviceHealth>> {
+    Ok(Json(app.health.status()))
+}
+
+/// Checks every device now rather than with the next periodic check.
+async fn check_availability(
+    State(app): State<Arc<App>>,
+) -> ApiResult<BTreeMap<String, DeviceHealth>> {
+    let checked = app.clone();
+    tokio::task::spawn_blocking(move || checked.health.check(|| checked.check_health()))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    availability(State(app)).await
+}
+
+async fn device_availability(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<DeviceHealth> {
+    known_device(&app, &id)?;
+    Ok(Json(app.health.device(&id).unwrap_or_default()))
+}
+
+async fn hot_keys(State(app): State<Arc<App>>) -> ApiResult<Vec<HotKeyStats>> {
+    Ok(Json(app.events.hot_keys()))
+}
+
+async fn plugins(State(app): State<Arc<App>>) -> ApiResult<Vec<PluginStatus>> {
+    Ok(Json(app.plugins.clone()))
+}
+
+async fn drift_status(State(app): State<Arc<App>>) -> ApiResult<DriftStatus> {
+    Ok(Json(app.drift.status()))
+}
+
+/// Runs the self-test now, e.g. after replacing a device.
+async fn check_drift(State(app): State<Arc<App>>) -> ApiResult<DriftStatus> {
+    app.drift.check(&app.fingerprints());
+    drift_status(State(app)).await
+}
+
+async fn drift_expectations(
+    State(app): State<Arc<App>>,
+) -> ApiResult<HashMap<String, Fingerprint>> {
+    Ok(Json(app.drift.expectations()))
+}
+
+/// Accepts what a device says about itself now as what is expected of it,
+/// e.g. after updating its firmware on purpose.
+async fn accept_drift(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> ApiResult<DriftStatus> {
+    let fingerprint = app
+        .fingerprints()
+        .remove(&id)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("Device '{}' not found", id)))?;
+    app.drift
+        .accept(&id, fingerprint)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    drift_status(State(app)).await
+}
+
+async fn announce(
+    State(app): State<Arc<App>>,
+    Json(announcement): Json<Announcement>,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    let player = app.command_sender();
+    // Rendering and local playback block until the announcement is spoken
+    tokio::task::spawn_blocking(move || app.announcer.announce(&announcement, &player))
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Rendered announcements, fetched by media players without a token.
+async fn announcement_clip(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiError>)> {
+    let audio = app
+        .announcer
+        .clip(&id)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "Unknown or expired clip"))?;
+    Ok(([(header::CONTENT_TYPE, audio.content_type)], audio.data))
+}
+
+async fn tts_config(State(app): State<Arc<App>>) -> ApiResult<TtsConfig> {
+    Ok(Json(app.announcer.config()))
+}
+
+async fn set_tts_config(
+    State(app): State<Arc<App>>,
+    Json(config): Json<TtsConfig>,
+) -> ApiResult<TtsConfig> {
+    app.announcer
+        .set_config(config)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    tts_config(State(app)).await
+}
+
+/**
+ * RuleSummary
+ * A rule with how often it ran and how its last runs went.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RuleSummary {
+    #[serde(flatten)]
+    pub rule: Rule,
+    pub stats: RuleStats,
+}
+
+async fn list_rules(State(app): State<Arc<App>>) -> ApiResult<Vec<RuleSummary>> {
+    let mut rules: Vec<RuleSummary> = app
+        .rules
+        .rules()
+        .into_iter()
+        .map(|rule| RuleSummary {
+            stats: app.rules.stats(&rule.id),
+            rule,
+        })
+        .collect();
+    rules.sort_by(|a, b| a.rule.id.cmp(&b.rule.id));
+    Ok(Json(rules))
+}
+
+/// Which rules refer to which devices, events and variables, and which
+/// devices scenes set.
+async fn rule_graph(State(app): State<Arc<App>>) -> ApiResult<DependencyGraph> {
+    Ok(Json(app.rules.dependency_graph(&app.scenes.list())))
+}
+
+/// Statistics of every rule that ever triggered, by id.
+async fn rule_stats(State(app): State<Arc<App>>) -> ApiResult<HashMap<String, RuleStats>> {
+    Ok(Json(app.rules.all_stats()))
+}
+
+async fn rule_files(State(app): State<Arc<App>>) -> ApiResult<Vec<RuleFileStatus>> {
+    Ok(Json(app.reloader.status()))
+}
+
+/// Reloads changed rule files right away instead of with the next check.
+async fn reload_rules(State(app): State<Arc<App>>) -> ApiResult<Vec<RuleFileStatus>> {
+    tokio::task::spawn_blocking(move || app.reloader.check())
+        .await
+        .map(Json)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Connection details in the config may hold passwords, so only
+/// full-scope clients may read it.
+async fn get_config(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+) -> ApiResult<AppConfig> {
+    if scope != Scope::Full {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "Reading the config needs a full-scope token",
+        ));
+    }
+    app.config()
+        .map(Json)
+        .map_err(|e| error(StatusCode::NOT_FOUND, e))
+}
+
+fn config_body(
+    app: &App,
+    body: serde_json::Value,
+) -> Result<AppConfig, (StatusCode, Json<ApiError>)> {
+    let path = app.config_path.as_deref().ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            "The hub was not set up from a config file",
+        )
+    })?;
+    AppConfig::from_value(path, body).map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+async fn preview_config(
+    State(app): State<Arc<App>>,
+    Json(body): Json<serde_json::Value>,
+) -> ApiResult<ConfigPreview> {
+    let config = config_body(&app, body)?;
+    app.preview_config(&config)
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+/// Writes the config file and applies it to the running hub, or changes
+/// nothing if it has problems.
+async fn apply_config(
+    State(app): State<Arc<App>>,
+    Json(body): Json<serde_json::Value>,
+) -> ApiResult<ConfigChanges> {
+    let config = config_body(&app, body)?;
+    app.apply_config(config)
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+async fn config_rules(State(app): State<Arc<App>>) -> ApiResult<Vec<Rule>> {
+    app.reloader
+        .rules()
+        .map(Json)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn preview_rules(
+    State(app): State<Arc<App>>,
+    Json(rules): Json<Vec<Rule>>,
+) -> ApiResult<ConfigPreview> {
+    app.reloader
+        .preview_rules(&rules)
+        .map(Json)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// Writes `rules.json` and swaps its rules in, or changes nothing if they
+/// clash with other rules.
+async fn save_rules(
+    State(app): State<Arc<App>>,
+    Json(rules): Json<Vec<Rule>>,
+) -> ApiResult<ConfigChanges> {
+    app.reloader
+        .save_rules(rules)
+        .map(Json)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+async fn stale_policy(State(app): State<Arc<App>>) -> ApiResult<StalePolicy> {
+    Ok(Json(app.rules.stale_policy()))
+}
+
+async fn set_stale_policy(
+    State(app): State<Arc<App>>,
+    Json(policy): Json<StalePolicy>,
+) -> ApiResult<StalePolicy> {
+    app.rules.set_stale_policy(policy);
+    Ok(Json(policy))
+}
+
+async fn clocks(State(app): State<Arc<App>>) -> ApiResult<ClockStatus> {
+    let devices = app.clock.offsets();
+    let satellites: HashMap<String, ClockOffset> = app
+        .federation
+        .satellites()
+        .into_iter()
+        .filter(|satellite| satellite.clock.samples > 0)
+        .map(|satellite| (satellite.id, satellite.clock))
+        .collect();
+    let mut skewed: Vec<String> = devices
+        .iter()
+        .chain(&satellites)
+        .filter(|(_, offset)| offset.is_skewed())
+        .map(|(id, _)| id.clone())
+        .collect();
+    skewed.sort();
+    Ok(Json(ClockStatus {
+        max_skew_secs: MAX_CLOCK_SKEW_SECS,
+        devices,
+        satellites,
+        skewed,
+    }))
+}
@@ -0,0 +1,5 @@
+pub mod access;
+pub mod dbus;
+pub mod endpoints;
+pub mod sse;
+pub mod websocket;
@@ -0,0 +1,79 @@
+use crate::core::app::App;
+use crate::core::event::{Event, EventFilter};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::sse::{self, KeepAlive, Sse},
+};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+/**
+ * StreamParams
+ * Query parameters of the event stream, matching the filter of WebSocket
+ * subscriptions. `types` is a comma separated list of event types.
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StreamParams {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub types: Option<String>,
+}
+
+impl From<StreamParams> for EventFilter {
+    fn from(params: StreamParams) -> Self {
+        EventFilter {
+            device_id: params.device_id,
+            types: params
+                .types
+                .map(|types| {
+                    types
+                        .split(',')
+                        .map(|t| t.trim().to_string())
+                        .filter(|t| !t.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Streams events as server-sent events. Each event carries its sequence
+/// number as id, so clients reconnecting with `Last-Event-ID` get the events
+/// they missed from the journal first.
+pub async fn handler(
+    State(app): State<Arc<App>>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let filter = EventFilter::from(params);
+    let last_seen = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+
+    let (missed, receiver) = match last_seen.map(|seq| app.events.resume(seq)) {
+        Some(Ok((missed, receiver))) => (missed, receiver),
+        _ => (Vec::new(), app.events.subscribe()),
+    };
+    // Lagged receivers skip ahead; the gap shows in the ids
+    let live = BroadcastStream::new(receiver).filter_map(|event| event.ok());
+    let stream = tokio_stream::iter(missed)
+        .chain(live)
+        .filter(move |event| filter.matches(event))
+        .map(|event| Ok(to_sse(&event)));
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+fn to_sse(event: &Event) -> sse::Event {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    sse::Event::default()
+        .id(event.seq.to_string())
+        .event(event.kind.name())
+        .data(data)
+}
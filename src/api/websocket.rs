@@ -0,0 +1,369 @@
+use super::access::Account;
+use crate::automation::engine::ManualRun;
+use crate::core::access::Scope;
+use crate::core::app::App;
+use crate::core::device::CommandResponse;
+use crate::core::event::{Event, EventFilter};
+use crate::core::query::QueryRequest;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+/**
+ * CommandRequest
+ * A single device command, on its own or as part of a batch.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandRequest {
+    pub device_id: String,
+    pub command: String,
+    #[serde(default)]
+    pub parameters: Option<HashMap<String, String>>,
+    /// Token of the lease the sender holds on the device, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease: Option<String>,
+}
+
+/**
+ * Socket Requests
+ * Everything a client can ask for over the socket.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Ping,
+    /// States of one device, or of all devices.
+    GetStates {
+        #[serde(default)]
+        device_id: Option<String>,
+    },
+    Command(CommandRequest),
+    /// Sends several commands in order and reports the outcome of each.
+    Batch {
+        commands: Vec<CommandRequest>,
+        /// Skip the remaining commands after the first failure.
+        #[serde(default)]
+        stop_on_error: bool,
+    },
+    /// Streams events matching the filter. The request id becomes the
+    /// subscription id.
+    Subscribe {
+        #[serde(flatten)]
+        filter: EventFilter,
+    },
+    Unsubscribe {
+        subscription: u64,
+    },
+    QueryHistory(QueryRequest),
+    TriggerRule {
+        rule_id: String,
+        #[serde(default)]
+        run: ManualRun,
+    },
+}
+
+impl Request {
+    /// Whether the request only reads, and is allowed in read-only scope.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Request::Ping
+            | Request::GetStates { .. }
+            | Request::Subscribe { .. }
+            | Request::Unsubscribe { .. }
+            | Request::QueryHistory(_) => true,
+            Request::TriggerRule { run, .. } => run.dry_run,
+            Request::Command(_) | Request::Batch { .. } => false,
+        }
+    }
+}
+
+/**
+ * ClientMessage
+ * A request tagged with an id chosen by the client. The response carries the
+ * same id, so several requests can be in flight at once.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientMessage {
+    pub id: u64,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/**
+ * Server Messages
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Response to the request with the same id. The id is missing when the
+    /// request could not be parsed.
+    Result {
+        id: Option<u64>,
+        success: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Event {
+        subscription: u64,
+        event: Event,
+    },
+}
+
+impl ServerMessage {
+    fn result(id: u64, result: Result<Value, String>) -> Self {
+        match result {
+            Ok(result) => ServerMessage::Result {
+                id: Some(id),
+                success: true,
+                result: Some(result),
+                error: None,
+            },
+            Err(error) => ServerMessage::Result {
+                id: Some(id),
+                success: false,
+                result: None,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/**
+ * CommandOutcome
+ * Outcome of one command of a batch.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CommandOutcome {
+    pub success: bool,
+    /// Set for commands skipped after an earlier failure.
+    #[serde(default)]
+    pub skipped: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What the device answered, for commands that succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<CommandResponse>,
+}
+
+/// Upgrades the connection to the multiplexed WebSocket API.
+pub async fn handler(
+    State(app): State<Arc<App>>,
+    Extension(scope): Extension<Scope>,
+    account: Option<Extension<Account>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let account = account.map(|Extension(Account(account))| account);
+    upgrade.on_upgrade(move |socket| connection(app, scope, account, socket))
+}
+
+/// Handles requests concurrently, writing responses and subscribed events
+/// back as they become ready. Commands are sent on behalf of `account`.
+async fn connection(app: Arc<App>, scope: Scope, account: Option<String>, mut socket: WebSocket) {
+    let (sender, mut outgoing) = mpsc::unbounded_channel::<ServerMessage>();
+    let mut subscriptions: HashMap<u64, AbortHandle> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let message = match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        let _ = sender.send(ServerMessage::Result {
+                            id: serde_json::from_str::<Value>(&text)
+                                .ok()
+                                .and_then(|value| value["id"].as_u64()),
+                            success: false,
+                            result: None,
+                            error: Some(format!("Invalid request: {}", e)),
+                        });
+                        continue;
+                    }
+                };
+                if scope == Scope::ReadOnly && !message.request.is_read_only() {
+                    let _ = sender.send(ServerMessage::result(
+                        message.id,
+                        Err("Read-only access: commands are not allowed".to_string()),
+                    ));
+                    continue;
+                }
+                match message.request {
+                    Request::Subscribe { filter } => {
+                        if subscriptions.contains_key(&message.id) {
+                            let _ = sender.send(ServerMessage::result(
+                                message.id,
+                                Err(format!("Subscription {} already exists", message.id)),
+                            ));
+                            continue;
+                        }
+                        let task = tokio::spawn(follow(
+                            app.clone(),
+                            message.id,
+                            filter,
+                            sender.clone(),
+                        ));
+                        subscriptions.insert(message.id, task.abort_handle());
+                    }
+                    Request::Unsubscribe { subscription } => {
+                        let result = match subscriptions.remove(&subscription) {
+                            Some(task) => {
+                                task.abort();
+                                Ok(Value::Null)
+                            }
+                            None => Err(format!("Subscription {} not found", subscription)),
+                        };
+                        let _ = sender.send(ServerMessage::result(message.id, result));
+                    }
+                    request => {
+                        let app = app.clone();
+                        let sender = sender.clone();
+                        let account = account.clone();
+                        tokio::spawn(async move {
+                            let result = handle(app, account.as_deref(), request).await;
+                            let _ = sender.send(ServerMessage::result(message.id, result));
+                        });
+                    }
+                }
+            }
+            Some(message) = outgoing.recv() => {
+                let Ok(text) = serde_json::to_string(&message) else {
+                    continue;
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    for task in subscriptions.values() {
+        task.abort();
+    }
+}
+
+/// Confirms a subscription and forwards matching events until the bus closes.
+async fn follow(
+    app: Arc<App>,
+    id: u64,
+    filter: EventFilter,
+    sender: mpsc::UnboundedSender<ServerMessage>,
+) {
+    let mut receiver = app.events.subscribe();
+    let _ = sender.send(ServerMessage::result(id, Ok(Value::Null)));
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if !filter.matches(&event) {
+                    continue;
+                }
+                let message = ServerMessage::Event {
+                    subscription: id,
+                    event,
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn handle(app: Arc<App>, account: Option<&str>, request: Request) -> Result<Value, String> {
+    let value = match request {
+        Request::Ping => Value::from("pong"),
+        Request::GetStates { device_id } => {
+            let mut states = app.device_states();
+            match device_id {
+                Some(device_id) => {
+                    let state = states
+                        .remove(&device_id)
+                        .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+                    to_value(state)?
+                }
+                None => to_value(states)?,
+            }
+        }
+        Request::Command(command) => to_value(
+            app.send_command_async_as(
+                account,
+                &command.device_id,
+                &command.command,
+                command.parameters,
+                command.lease.as_deref(),
+            )
+            .await?,
+        )?,
+        Request::Batch {
+            commands,
+            stop_on_error,
+        } => {
+            let mut outcomes = Vec::with_capacity(commands.len());
+            let mut failed = false;
+            for command in commands {
+                if failed && stop_on_error {
+                    outcomes.push(CommandOutcome {
+                        skipped: true,
+                        ..CommandOutcome::default()
+                    });
+                    continue;
+                }
+                let result = app
+                    .send_command_async_as(
+                        account,
+                        &command.device_id,
+                        &command.command,
+                        command.parameters,
+                        command.lease.as_deref(),
+                    )
+                    .await;
+                failed |= result.is_err();
+                let (response, error) = match result {
+                    Ok(result) => (Some(result.response), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                outcomes.push(CommandOutcome {
+                    success: error.is_none(),
+                    skipped: false,
+                    error,
+                    response,
+                });
+            }
+            to_value(outcomes)?
+        }
+        Request::QueryHistory(query) => {
+            let result = tokio::task::spawn_blocking(move || app.history.query(&query))
+                .await
+                .map_err(|e| e.to_string())??;
+            to_value(result)?
+        }
+        Request::TriggerRule { rule_id, run } => to_value(app.rules.trigger(&rule_id, run).await?)?,
+        Request::Subscribe { .. } | Request::Unsubscribe { .. } => {
+            return Err("Subscriptions are handled by the connection".to_string())
+        }
+    };
+    Ok(value)
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, String> {
+    serde_json::to_value(value).map_err(|e| format!("Failed to serialize response: {}", e))
+}
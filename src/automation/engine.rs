@@ -0,0 +1,817 @@
+use super::graph::{device_node_id, DependencyGraph};
+use super::rule::{Rule, RunMode, StalePolicy, Step, Trigger};
+use super::stats::{RuleStats, RuleStatsStore};
+use super::template::Scope;
+use super::variables::{VariableScope, VariableStore};
+use crate::core::async_device::{AsyncDevice, AsyncExecutor, AsyncRegistry};
+use crate::core::device::{CommandResponse, Device, DeviceList, Executor};
+use crate::core::error::BlinkieError;
+use crate::core::event::{Event, EventBus, EventKind};
+use crate::core::freshness::StateClock;
+use crate::core::history::StateHistory;
+use crate::core::lease::LeaseManager;
+use crate::core::maintenance_mode::MaintenanceMode;
+use crate::core::scene::Scene;
+use crate::core::usage::UsageTracker;
+use crate::core::user::UserStore;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::AbortHandle;
+
+/**
+ * RunContext
+ * Everything a single run of a rule knows about why it was started.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct RunContext {
+    pub rule_id: String,
+    pub trigger: Option<Event>,
+    /// Variables scoped to this run.
+    pub variables: HashMap<String, String>,
+    /// Outcome of the most recent wait-for-trigger step of this run.
+    pub wait: Option<WaitOutcome>,
+    /// What the device answered to the most recent command step of this run.
+    pub response: Option<CommandResponse>,
+    /// Only describe side effects in `planned` instead of performing them.
+    pub dry_run: bool,
+    pub planned: Vec<String>,
+}
+
+/**
+ * ManualRun
+ * Options for running a rule on demand, e.g. to test a notification rule
+ * with a made-up temperature reading.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ManualRun {
+    /// Run-scoped variables to start the run with.
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// Event to present as the trigger. It is not published on the bus.
+    #[serde(default)]
+    pub trigger: Option<EventKind>,
+    #[serde(default)]
+    pub skip_conditions: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/**
+ * RunReport
+ * Result of a manual run.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RunReport {
+    pub conditions_passed: bool,
+    /// Whether a run was started; false if conditions failed or the run mode refused it.
+    pub started: bool,
+    /// For dry runs, the side effects the run would have had, in order.
+    pub planned: Vec<String>,
+    pub error: Option<String>,
+}
+
+/**
+ * WaitOutcome
+ * How a wait-for-trigger step ended: with the awaited event, or by timing out.
+ */
+#[derive(Clone, Debug)]
+pub struct WaitOutcome {
+    pub completed: bool,
+    pub event: Option<Event>,
+}
+
+/// Whether a step sequence should go on after a step.
+enum Flow {
+    Continue,
+    Stop,
+}
+
+type StepsFuture<'a> = Pin<Box<dyn Future<Output = Result<Flow, BlinkieError>> + Send + 'a>>;
+
+pub type CommandFuture =
+    Pin<Box<dyn Future<Output = Result<CommandResponse, BlinkieError>> + Send>>;
+
+/// Sends a command of a rule the way every command of the hub is sent:
+/// the device id, the command, its parameters and the id of the rule.
+pub type RuleCommandSender = Arc<
+    dyn Fn(String, String, Option<HashMap<String, String>>, String) -> CommandFuture + Send + Sync,
+>;
+
+#[derive(Default)]
+struct RunTracker {
+    next_id: u64,
+    active: Vec<(u64, AbortHandle)>,
+}
+
+struct RuleRuntime {
+    rule: Rule,
+    tracker: Mutex<RunTracker>,
+    // Serializes runs of rules in queued mode
+    queue: tokio::sync::Mutex<()>,
+}
+
+/**
+ * RuleEngine
+ * Evaluates rules against the event bus and runs their action sequences,
+ * honouring each rule's run mode.
+ */
+pub struct RuleEngine {
+    devices: DeviceList,
+    events: Arc<EventBus>,
+    variables: Arc<VariableStore>,
+    stats: Arc<RuleStatsStore>,
+    usage: Option<Arc<UsageTracker>>,
+    history: Option<Arc<StateHistory>>,
+    async_devices: Option<Arc<AsyncRegistry>>,
+    leases: Option<Arc<LeaseManager>>,
+    maintenance: Option<Arc<MaintenanceMode>>,
+    clock: Option<Arc<StateClock>>,
+    users: Option<Arc<UserStore>>,
+    sender: RwLock<Option<RuleCommandSender>>,
+    stale: RwLock<StalePolicy>,
+    rules: RwLock<HashMap<String, Arc<RuleRuntime>>>,
+}
+
+impl RuleEngine {
+    pub fn new(
+        devices: DeviceList,
+        events: Arc<EventBus>,
+        variables: Arc<VariableStore>,
+        stats: Arc<RuleStatsStore>,
+    ) -> Self {
+        RuleEngine {
+            devices,
+            events,
+            variables,
+            stats,
+            usage: None,
+            history: None,
+            async_devices: None,
+            leases: None,
+            maintenance: None,
+            clock: None,
+            users: None,
+            sender: RwLock::new(None),
+            stale: RwLock::new(StalePolicy::default()),
+            rules: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Lets steps address devices of async handlers, which are awaited
+    /// instead of being locked.
+    pub fn with_async_devices(mut self, registry: Arc<AsyncRegistry>) -> Self {
+        self.async_devices = Some(registry);
+        self
+    }
+
+    /// Keeps rules away from devices claimed for exclusive control.
+    pub fn with_leases(mut self, leases: Arc<LeaseManager>) -> Self {
+        self.leases = Some(leases);
+        self
+    }
+
+    /// Pauses automations for devices in maintenance: their events trigger
+    /// no rules and steps addressing them are skipped.
+    pub fn with_maintenance(mut self, maintenance: Arc<MaintenanceMode>) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    fn in_maintenance(&self, device_id: &str) -> bool {
+        self.maintenance
+            .as_ref()
+            .is_some_and(|maintenance| maintenance.device(device_id).is_some())
+    }
+
+    /// Makes device usage available to usage conditions.
+    pub fn with_usage(mut self, usage: Arc<UsageTracker>) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// Makes recorded state history available to recent conditions.
+    pub fn with_history(mut self, history: Arc<StateHistory>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Makes the age of state values available to fresh conditions and the
+    /// stale policy. The engine keeps the clock up to date from the events
+    /// it handles, so conditions see the change that triggered them.
+    pub fn with_clock(mut self, clock: Arc<StateClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Makes users, their preferences and who is home available to
+    /// templates.
+    pub fn with_users(mut self, users: Arc<UserStore>) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    /// Sends the commands of rules through `sender` from now on. Until a
+    /// sender is set, command steps fail.
+    pub fn set_sender(&self, sender: RuleCommandSender) {
+        *self.sender.write().unwrap() = Some(sender);
+    }
+
+    /// Stale policy for state conditions without a limit of their own.
+    pub fn stale_policy(&self) -> StalePolicy {
+        *self.stale.read().unwrap()
+    }
+
+    pub fn set_stale_policy(&self, policy: StalePolicy) {
+        *self.stale.write().unwrap() = policy;
+    }
+
+    /// Execution statistics of a rule.
+    pub fn stats(&self, id: &str) -> RuleStats {
+        self.stats.get(id)
+    }
+
+    /// Execution statistics of all rules, keyed by rule id.
+    pub fn all_stats(&self) -> HashMap<String, RuleStats> {
+        self.stats.all()
+    }
+
+    /// Rule-scoped and global variables shared by all rules.
+    pub fn variables(&self) -> &Arc<VariableStore> {
+        &self.variables
+    }
+
+    fn scope<'a>(&'a self, context: &'a RunContext) -> Scope<'a> {
+        Scope {
+            devices: &self.devices,
+            variables: &self.variables,
+            context,
+            usage: self.usage.as_deref(),
+            history: self.history.as_deref(),
+            clock: self.clock.as_deref(),
+            users: self.users.as_deref(),
+            stale: self.stale_policy(),
+        }
+    }
+
+    /// Renders a template outside of any rule run, e.g. to try it out.
+    /// Only device states, global variables and users are available.
+    pub fn render(&self, template: &str) -> String {
+        let context = RunContext::default();
+        self.scope(&context).render(template)
+    }
+
+    /// Adds a rule. Fails if a rule with the same id already exists.
+    pub fn add_rule(&self, rule: Rule) -> Result<(), BlinkieError> {
+        let mut rules = self.rules.write().unwrap();
+        if rules.contains_key(&rule.id) {
+            return Err(format!("Rule '{}' already exists", rule.id).into());
+        }
+        rules.insert(
+            rule.id.clone(),
+            Arc::new(RuleRuntime {
+                rule,
+                tracker: Mutex::new(RunTracker::default()),
+                queue: tokio::sync::Mutex::new(()),
+            }),
+        );
+        Ok(())
+    }
+
+    /// Adds the rules declared in the file at `path`, a JSON list of rules,
+    /// and returns how many there were. A missing file means there are none.
+    pub fn load_rules(&self, path: &Path) -> Result<usize, BlinkieError> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let rules = read_rules(path)?;
+        let count = rules.len();
+        for rule in rules {
+            self.add_rule(rule)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(count)
+    }
+
+    /// Replaces the rules with ids `old` by `rules` at once, e.g. those of a
+    /// file that changed. Fails without changing anything if two of `rules`
+    /// share an id or one clashes with a rule not in `old`. Runs of replaced
+    /// and removed rules go on until they finish; unchanged rules keep their
+    /// runs and run mode.
+    pub fn replace_rules(&self, old: &[String], rules: Vec<Rule>) -> Result<(), BlinkieError> {
+        let mut current = self.rules.write().unwrap();
+        Self::check_ids(&current, old, &rules)?;
+        let ids: Vec<&str> = rules.iter().map(|rule| rule.id.as_str()).collect();
+        let removed: Vec<String> = old
+            .iter()
+            .filter(|id| !ids.contains(&id.as_str()))
+            .cloned()
+            .collect();
+        for id in &removed {
+            current.remove(id);
+        }
+        for rule in rules {
+            let unchanged = current.get(&rule.id).is_some_and(|runtime| {
+                serde_json::to_value(&runtime.rule).ok() == serde_json::to_value(&rule).ok()
+            });
+            if !unchanged {
+                current.insert(
+                    rule.id.clone(),
+                    Arc::new(RuleRuntime {
+                        rule,
+                        tracker: Mutex::new(RunTracker::default()),
+                        queue: tokio::sync::Mutex::new(()),
+                    }),
+                );
+            }
+        }
+        drop(current);
+        for id in removed {
+            if let Err(e) = self.stats.remove(&id) {
+                log::warn!("Failed to drop statistics of rule '{}': {}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `rules` could replace the rules with ids `old`, see
+    /// `replace_rules`.
+    pub fn check_rules(&self, old: &[String], rules: &[Rule]) -> Result<(), BlinkieError> {
+        Self::check_ids(&self.rules.read().unwrap(), old, rules)
+    }
+
+    fn check_ids(
+        current: &HashMap<String, Arc<RuleRuntime>>,
+        old: &[String],
+        rules: &[Rule],
+    ) -> Result<(), BlinkieError> {
+        let mut ids: Vec<&str> = Vec::new();
+        for rule in rules {
+            if rule.id.is_empty() {
+                return Err(format!("Rule '{}' has an empty id", rule.name).into());
+            }
+            if ids.contains(&rule.id.as_str()) {
+                return Err(format!("Rule '{}' is declared twice", rule.id).into());
+            }
+            if current.contains_key(&rule.id) && !old.contains(&rule.id) {
+                return Err(format!("Rule '{}' already exists", rule.id).into());
+            }
+            ids.push(&rule.id);
+        }
+        Ok(())
+    }
+
+    /// Removes a rule and cancels its running sequences.
+    pub fn remove_rule(&self, id: &str) -> Option<Rule> {
+        let runtime = self.rules.write().unwrap().remove(id)?;
+        for (_, handle) in runtime.tracker.lock().unwrap().active.drain(..) {
+            handle.abort();
+        }
+        if let Err(e) = self.stats.remove(id) {
+            log::warn!("Failed to drop statistics of rule '{}': {}", id, e);
+        }
+        Some(runtime.rule.clone())
+    }
+
+    /// Returns all rules.
+    pub fn rules(&self) -> Vec<Rule> {
+        let rules = self.rules.read().unwrap();
+        rules.values().map(|runtime| runtime.rule.clone()).collect()
+    }
+
+    /// Graph of what every rule refers to, and of the devices `scenes` set.
+    pub fn dependency_graph(&self, scenes: &[Scene]) -> DependencyGraph {
+        let devices: Vec<(String, String)> = self
+            .devices
+            .read()
+            .unwrap()
+            .iter()
+            .map(|device| (device.get_id().to_string(), device.get_name().to_string()))
+            .collect();
+        DependencyGraph::build(&self.rules(), &devices, scenes)
+    }
+
+    /// Ids of the rules that would break if the device were deleted.
+    pub fn rules_using_device(&self, device_id: &str) -> Vec<String> {
+        self.dependency_graph(&[])
+            .dependents(&device_node_id(device_id))
+    }
+
+    /// Number of runs of a rule currently executing or waiting in its queue.
+    pub fn active_runs(&self, id: &str) -> usize {
+        let rules = self.rules.read().unwrap();
+        rules
+            .get(id)
+            .map_or(0, |runtime| runtime.tracker.lock().unwrap().active.len())
+    }
+
+    /// Listens on the event bus and fires matching rules until the bus closes.
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = self.events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.handle_event(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Starts every enabled rule that is triggered by `event` and whose conditions hold.
+    pub fn handle_event(self: &Arc<Self>, event: &Event) {
+        if let Some(clock) = &self.clock {
+            clock.record(event);
+        }
+        if let Some(device_id) = event.kind.device_id() {
+            if self.in_maintenance(device_id) {
+                return;
+            }
+        }
+        let triggered: Vec<Arc<RuleRuntime>> = {
+            let rules = self.rules.read().unwrap();
+            rules
+                .values()
+                .filter(|runtime| runtime.rule.enabled)
+                .filter(|runtime| runtime.rule.triggers.iter().any(|t| t.matches(event)))
+                .cloned()
+                .collect()
+        };
+
+        for runtime in triggered {
+            let context = RunContext {
+                rule_id: runtime.rule.id.clone(),
+                trigger: Some(event.clone()),
+                ..RunContext::default()
+            };
+            let scope = self.scope(&context);
+            let conditions_hold = runtime
+                .rule
+                .conditions
+                .iter()
+                .all(|condition| condition.evaluate(&scope));
+            if conditions_hold {
+                self.start(runtime, context);
+            }
+        }
+    }
+
+    /// Runs a rule on demand. Real runs go through the rule's run mode like any
+    /// triggered run and are not awaited; dry runs execute immediately without
+    /// side effects and report what they would have done.
+    pub async fn trigger(
+        self: &Arc<Self>,
+        id: &str,
+        run: ManualRun,
+    ) -> Result<RunReport, BlinkieError> {
+        let runtime = self
+            .rules
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("Rule '{}' not found", id))?;
+
+        let mut context = RunContext {
+            rule_id: runtime.rule.id.clone(),
+            trigger: run.trigger.map(|kind| Event {
+                seq: 0,
+                timestamp: Utc::now(),
+                kind,
+            }),
+            variables: run.variables,
+            dry_run: run.dry_run,
+            ..RunContext::default()
+        };
+
+        let scope = self.scope(&context);
+        let conditions_passed = run.skip_conditions
+            || runtime
+                .rule
+                .conditions
+                .iter()
+                .all(|condition| condition.evaluate(&scope));
+        let mut report = RunReport {
+            conditions_passed,
+            ..RunReport::default()
+        };
+        if !conditions_passed {
+            return Ok(report);
+        }
+
+        if run.dry_run {
+            let result = self.run_steps(&runtime.rule.actions, &mut context).await;
+            report.started = true;
+            report.planned = context.planned;
+            report.error = result.err().map(String::from);
+        } else {
+            report.started = self.start(runtime, context);
+        }
+        Ok(report)
+    }
+
+    /// Starts a run according to the rule's mode. Returns false if the mode
+    /// did not allow another run.
+    fn start(self: &Arc<Self>, runtime: Arc<RuleRuntime>, context: RunContext) -> bool {
+        let mut tracker = runtime.tracker.lock().unwrap();
+
+        let limit = match runtime.rule.mode {
+            RunMode::Single => 1,
+            RunMode::Restart => {
+                for (_, handle) in tracker.active.drain(..) {
+                    handle.abort();
+                }
+                1
+            }
+            RunMode::Queued { max } | RunMode::Parallel { max } => max,
+        };
+        if tracker.active.len() >= limit {
+            self.record(|stats| stats.record_skipped(&runtime.rule.id));
+            return false;
+        }
+        self.record(|stats| stats.record_fired(&runtime.rule.id));
+
+        let run_id = tracker.next_id;
+        tracker.next_id += 1;
+
+        let engine = Arc::clone(self);
+        let task_runtime = Arc::clone(&runtime);
+        // The tracker stays locked until the handle is stored, so a run that
+        // finishes immediately still finds its own entry to remove.
+        let handle = tokio::spawn(async move {
+            let _turn = match task_runtime.rule.mode {
+                RunMode::Queued { .. } => Some(task_runtime.queue.lock().await),
+                _ => None,
+            };
+            let result = engine.execute(&task_runtime.rule, context).await;
+            if let Err(e) = &result {
+                log::warn!("Rule '{}' failed: {}", task_runtime.rule.id, e);
+            }
+            engine.record(|stats| stats.record_result(&task_runtime.rule.id, &result));
+            task_runtime
+                .tracker
+                .lock()
+                .unwrap()
+                .active
+                .retain(|(id, _)| *id != run_id);
+        });
+        tracker.active.push((run_id, handle.abort_handle()));
+        true
+    }
+
+    // Statistics are best effort; failing to persist them must not stop a rule
+    fn record<F>(&self, f: F)
+    where
+        F: FnOnce(&RuleStatsStore) -> Result<(), BlinkieError>,
+    {
+        if let Err(e) = f(&self.stats) {
+            log::warn!("Failed to record rule statistics: {}", e);
+        }
+    }
+
+    /// Runs a rule's action sequence, stopping at the first failing step.
+    async fn execute(&self, rule: &Rule, mut context: RunContext) -> Result<(), BlinkieError> {
+        self.run_steps(&rule.actions, &mut context).await?;
+        Ok(())
+    }
+
+    // Boxed because branches run nested step sequences recursively
+    fn run_steps<'a>(&'a self, steps: &'a [Step], context: &'a mut RunContext) -> StepsFuture<'a> {
+        Box::pin(async move {
+            for step in steps {
+                if let Flow::Stop = self.run_step(step, context).await? {
+                    return Ok(Flow::Stop);
+                }
+            }
+            Ok(Flow::Continue)
+        })
+    }
+
+    async fn run_step(&self, step: &Step, context: &mut RunContext) -> Result<Flow, BlinkieError> {
+        match step {
+            Step::Action { device_id, action } if context.dry_run => {
+                context
+                    .planned
+                    .push(format!("Execute {:?} on '{}'", action, device_id));
+            }
+            Step::Action { device_id, .. } | Step::Command { device_id, .. }
+                if !context.dry_run && self.in_maintenance(device_id) =>
+            {
+                log::info!(
+                    "Rule '{}' skipped a step on '{}', which is in maintenance",
+                    context.rule_id,
+                    device_id
+                );
+            }
+            Step::Action { device_id, action } => match action.command() {
+                Some((command, parameters)) => {
+                    self.command(device_id, command, parameters, &context.rule_id)
+                        .await?;
+                }
+                None => match self.async_device(device_id)? {
+                    Some(device) => action.execute_async(device.as_ref()).await?,
+                    None => self.with_device(device_id, |device| action.execute(device))?,
+                },
+            },
+            Step::Command {
+                device_id,
+                command,
+                parameters,
+            } => {
+                let parameters = parameters.as_ref().map(|parameters| {
+                    let scope = self.scope(context);
+                    parameters
+                        .iter()
+                        .map(|(key, value)| (key.clone(), scope.render(value)))
+                        .collect::<HashMap<_, _>>()
+                });
+                if context.dry_run {
+                    context.planned.push(format!(
+                        "Send '{}' to '{}' with {:?}",
+                        command,
+                        device_id,
+                        parameters.clone().unwrap_or_default()
+                    ));
+                    return Ok(Flow::Continue);
+                }
+                context.response = Some(
+                    self.command(device_id, command, parameters, &context.rule_id)
+                        .await?,
+                );
+            }
+            Step::Delay { millis } if context.dry_run => {
+                context.planned.push(format!("Wait {} ms", millis));
+            }
+            Step::Delay { millis } => {
+                tokio::time::sleep(Duration::from_millis(*millis)).await;
+            }
+            Step::Event { name, data } => {
+                let scope = self.scope(context);
+                let data = data
+                    .iter()
+                    .map(|(key, value)| (key.clone(), scope.render(value)))
+                    .collect();
+                if context.dry_run {
+                    context
+                        .planned
+                        .push(format!("Publish event '{}' with {:?}", name, data));
+                    return Ok(Flow::Continue);
+                }
+                self.events.publish(EventKind::Custom {
+                    name: name.clone(),
+                    data,
+                })?;
+            }
+            Step::SetVariable { name, value, scope } => {
+                let value = self.scope(context).render(value);
+                if context.dry_run && *scope != VariableScope::Run {
+                    context.planned.push(format!(
+                        "Set {:?} variable '{}' to '{}'",
+                        scope, name, value
+                    ));
+                    return Ok(Flow::Continue);
+                }
+                match scope {
+                    VariableScope::Run => {
+                        context.variables.insert(name.clone(), value);
+                    }
+                    VariableScope::Rule => {
+                        self.variables.set_rule(&context.rule_id, name, &value)?
+                    }
+                    VariableScope::Global => self.variables.set_global(name, &value)?,
+                }
+            }
+            Step::WaitForTrigger {
+                triggers,
+                timeout_millis,
+                continue_on_timeout,
+            } => {
+                // Dry runs don't wait for real events and continue as if the wait timed out
+                let outcome = if context.dry_run {
+                    context.planned.push(format!(
+                        "Wait up to {:?} ms for {:?}",
+                        timeout_millis, triggers
+                    ));
+                    None
+                } else {
+                    self.wait_for(triggers, *timeout_millis).await
+                };
+                let completed = outcome.is_some();
+                context.wait = Some(WaitOutcome {
+                    completed,
+                    event: outcome,
+                });
+                if !completed && !continue_on_timeout {
+                    return Ok(Flow::Stop);
+                }
+            }
+            Step::Choose {
+                conditions,
+                then,
+                otherwise,
+            } => {
+                let scope = self.scope(context);
+                let branch = if conditions
+                    .iter()
+                    .all(|condition| condition.evaluate(&scope))
+                {
+                    then
+                } else {
+                    otherwise
+                };
+                return self.run_steps(branch, context).await;
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Suspends the run until an event matching one of `triggers` is published.
+    /// Returns `None` when the timeout expires first.
+    async fn wait_for(&self, triggers: &[Trigger], timeout_millis: Option<u64>) -> Option<Event> {
+        let mut receiver = self.events.subscribe();
+        let matching = async {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if triggers.iter().any(|t| t.matches(&event)) => return Some(event),
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        };
+        match timeout_millis {
+            Some(millis) => tokio::time::timeout(Duration::from_millis(millis), matching)
+                .await
+                .ok()
+                .flatten(),
+            None => matching.await,
+        }
+    }
+
+    /// Sends a command of rule `rule_id` through the sender.
+    async fn command(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        rule_id: &str,
+    ) -> Result<CommandResponse, BlinkieError> {
+        let sender = self
+            .sender
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or("Rules can't send commands before the hub has started")?;
+        sender(
+            device_id.to_string(),
+            command.to_string(),
+            parameters,
+            rule_id.to_string(),
+        )
+        .await
+    }
+
+    /// The async device with the id, if an async handler created it.
+    fn async_device(&self, device_id: &str) -> Result<Option<Arc<dyn AsyncDevice>>, BlinkieError> {
+        let Some(device) = self
+            .async_devices
+            .as_ref()
+            .and_then(|registry| registry.device(device_id))
+        else {
+            return Ok(None);
+        };
+        if let Some(leases) = &self.leases {
+            leases.check(device_id, None)?;
+        }
+        Ok(Some(device))
+    }
+
+    fn with_device<T, F>(&self, device_id: &str, f: F) -> Result<T, BlinkieError>
+    where
+        F: FnOnce(&mut dyn Device) -> Result<T, BlinkieError>,
+    {
+        if let Some(leases) = &self.leases {
+            leases.check(device_id, None)?;
+        }
+        let mut devices = self.devices.write().unwrap();
+        let device = devices
+            .iter_mut()
+            .find(|device| device.get_id() == device_id)
+            .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+        f(device.as_mut())
+    }
+}
+
+/// Reads a JSON list of rules from the file at `path`.
+pub fn read_rules(path: &Path) -> Result<Vec<Rule>, BlinkieError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| {
+        BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+    })
+}
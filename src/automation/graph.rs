@@ -0,0 +1,325 @@
+use super::rule::{Condition, Rule, Step, Trigger};
+use super::variables::VariableScope;
+use crate::core::scene::Scene;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/**
+ * Node Kinds
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Rule,
+    Device,
+    Event,
+    Variable,
+    Scene,
+}
+
+/**
+ * Relations
+ * How a rule refers to another node.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Relation {
+    /// The rule starts when the node changes or fires.
+    TriggeredBy,
+    /// The rule reads the node in a condition or template.
+    Reads,
+    /// The rule sends commands to the node, or the scene sets its state.
+    Controls,
+    /// The rule publishes the event.
+    Emits,
+    /// The rule sets the variable.
+    Writes,
+}
+
+/**
+ * Node
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Node {
+    pub id: String,
+    pub kind: NodeKind,
+    pub name: String,
+    /// Set for devices that rules reference but which are not registered.
+    pub missing: bool,
+}
+
+/**
+ * Edge
+ * Always points from a rule or scene to the node it refers to.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub relation: Relation,
+}
+
+/**
+ * DependencyGraph
+ * Which rules reference which devices, events and variables, and which
+ * devices scenes set, in a shape UIs can render directly.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DependencyGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+pub fn rule_node_id(id: &str) -> String {
+    format!("rule:{}", id)
+}
+
+pub fn device_node_id(id: &str) -> String {
+    format!("device:{}", id)
+}
+
+pub fn scene_node_id(id: &str) -> String {
+    format!("scene:{}", id)
+}
+
+impl DependencyGraph {
+    /// Builds the graph from rules, scenes and the `(id, name)` pairs of
+    /// registered devices.
+    pub fn build(rules: &[Rule], devices: &[(String, String)], scenes: &[Scene]) -> Self {
+        let mut nodes: BTreeMap<String, Node> = BTreeMap::new();
+        let mut edges = Vec::new();
+
+        for (id, name) in devices {
+            nodes.insert(
+                device_node_id(id),
+                Node {
+                    id: device_node_id(id),
+                    kind: NodeKind::Device,
+                    name: name.clone(),
+                    missing: false,
+                },
+            );
+        }
+
+        for rule in rules {
+            let from = rule_node_id(&rule.id);
+            nodes.insert(
+                from.clone(),
+                Node {
+                    id: from.clone(),
+                    kind: NodeKind::Rule,
+                    name: rule.name.clone(),
+                    missing: false,
+                },
+            );
+
+            for (kind, target, relation) in references(rule) {
+                let to = match kind {
+                    NodeKind::Device => device_node_id(&target),
+                    NodeKind::Event => format!("event:{}", target),
+                    NodeKind::Variable => format!("variable:{}", target),
+                    NodeKind::Rule => rule_node_id(&target),
+                    NodeKind::Scene => scene_node_id(&target),
+                };
+                nodes.entry(to.clone()).or_insert_with(|| Node {
+                    id: to.clone(),
+                    kind,
+                    name: target.clone(),
+                    missing: kind == NodeKind::Device,
+                });
+                let edge = Edge {
+                    from: from.clone(),
+                    to,
+                    relation,
+                };
+                if !edges.contains(&edge) {
+                    edges.push(edge);
+                }
+            }
+        }
+
+        for scene in scenes {
+            let from = scene_node_id(&scene.id);
+            nodes.insert(
+                from.clone(),
+                Node {
+                    id: from.clone(),
+                    kind: NodeKind::Scene,
+                    name: scene.name.clone(),
+                    missing: false,
+                },
+            );
+            for device_id in scene.states.keys() {
+                let to = device_node_id(device_id);
+                nodes.entry(to.clone()).or_insert_with(|| Node {
+                    id: to.clone(),
+                    kind: NodeKind::Device,
+                    name: device_id.clone(),
+                    missing: true,
+                });
+                edges.push(Edge {
+                    from: from.clone(),
+                    to,
+                    relation: Relation::Controls,
+                });
+            }
+        }
+
+        DependencyGraph {
+            nodes: nodes.into_values().collect(),
+            edges,
+        }
+    }
+
+    /// Ids of the rules referring to a node, e.g. to warn before deleting a device.
+    pub fn dependents(&self, node_id: &str) -> Vec<String> {
+        let mut rules: Vec<String> = self
+            .edges
+            .iter()
+            .filter(|edge| edge.to == node_id)
+            .filter_map(|edge| edge.from.strip_prefix("rule:").map(str::to_string))
+            .collect();
+        rules.sort();
+        rules.dedup();
+        rules
+    }
+}
+
+type Reference = (NodeKind, String, Relation);
+
+/// Collects everything a rule refers to.
+fn references(rule: &Rule) -> Vec<Reference> {
+    let mut refs = Vec::new();
+    for trigger in &rule.triggers {
+        trigger_references(trigger, Relation::TriggeredBy, &mut refs);
+    }
+    for condition in &rule.conditions {
+        condition_references(condition, &rule.id, &mut refs);
+    }
+    steps_references(&rule.actions, &rule.id, &mut refs);
+    refs
+}
+
+fn trigger_references(trigger: &Trigger, relation: Relation, refs: &mut Vec<Reference>) {
+    match trigger {
+        Trigger::StateChanged { device_id, .. } => {
+            refs.push((NodeKind::Device, device_id.clone(), relation))
+        }
+        Trigger::Event { name, .. } => refs.push((NodeKind::Event, name.clone(), relation)),
+    }
+}
+
+fn variable_id(scope: VariableScope, rule_id: &str, name: &str) -> Option<String> {
+    match scope {
+        // Run variables never leave their run
+        VariableScope::Run => None,
+        VariableScope::Rule => Some(format!("{}.{}", rule_id, name)),
+        VariableScope::Global => Some(name.to_string()),
+    }
+}
+
+fn condition_references(condition: &Condition, rule_id: &str, refs: &mut Vec<Reference>) {
+    match condition {
+        Condition::State { device_id, .. } => {
+            refs.push((NodeKind::Device, device_id.clone(), Relation::Reads))
+        }
+        Condition::Not { condition } => condition_references(condition, rule_id, refs),
+        Condition::Any { conditions } | Condition::All { conditions } => {
+            for condition in conditions {
+                condition_references(condition, rule_id, refs);
+            }
+        }
+        Condition::Wait { .. } => {}
+        Condition::Variable { name, scope, .. } => {
+            if let Some(id) = variable_id(*scope, rule_id, name) {
+                refs.push((NodeKind::Variable, id, Relation::Reads));
+            }
+        }
+        Condition::Template { template, .. } => template_references(template, rule_id, refs),
+        Condition::Usage { device_id, .. }
+        | Condition::Fresh { device_id, .. }
+        | Condition::Recent { device_id, .. } => {
+            refs.push((NodeKind::Device, device_id.clone(), Relation::Reads))
+        }
+    }
+}
+
+fn steps_references(steps: &[Step], rule_id: &str, refs: &mut Vec<Reference>) {
+    for step in steps {
+        match step {
+            Step::Action { device_id, .. } => {
+                refs.push((NodeKind::Device, device_id.clone(), Relation::Controls))
+            }
+            Step::Command {
+                device_id,
+                parameters,
+                ..
+            } => {
+                refs.push((NodeKind::Device, device_id.clone(), Relation::Controls));
+                for value in parameters.iter().flat_map(|p| p.values()) {
+                    template_references(value, rule_id, refs);
+                }
+            }
+            Step::Delay { .. } => {}
+            Step::Event { name, data } => {
+                refs.push((NodeKind::Event, name.clone(), Relation::Emits));
+                for value in data.values() {
+                    template_references(value, rule_id, refs);
+                }
+            }
+            Step::WaitForTrigger { triggers, .. } => {
+                for trigger in triggers {
+                    trigger_references(trigger, Relation::Reads, refs);
+                }
+            }
+            Step::SetVariable { name, value, scope } => {
+                if let Some(id) = variable_id(*scope, rule_id, name) {
+                    refs.push((NodeKind::Variable, id, Relation::Writes));
+                }
+                template_references(value, rule_id, refs);
+            }
+            Step::Choose {
+                conditions,
+                then,
+                otherwise,
+            } => {
+                for condition in conditions {
+                    condition_references(condition, rule_id, refs);
+                }
+                steps_references(then, rule_id, refs);
+                steps_references(otherwise, rule_id, refs);
+            }
+        }
+    }
+}
+
+/// Finds `{{ state.<device>.<key> }}`, `{{ var.<name> }}` and
+/// `{{ global.<name> }}` lookups in a template.
+fn template_references(template: &str, rule_id: &str, refs: &mut Vec<Reference>) {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let path = rest[start + 2..start + end].trim();
+        match path.split_once('.') {
+            Some(("state" | "age", lookup)) => {
+                if let Some((device_id, _)) = lookup.split_once('.') {
+                    refs.push((NodeKind::Device, device_id.to_string(), Relation::Reads));
+                }
+            }
+            Some(("var", name)) => {
+                // Run variables shadow rule variables of the same name, but
+                // the lookup falls back to the rule's
+                if let Some(id) = variable_id(VariableScope::Rule, rule_id, name) {
+                    refs.push((NodeKind::Variable, id, Relation::Reads));
+                }
+            }
+            Some(("global", name)) => {
+                refs.push((NodeKind::Variable, name.to_string(), Relation::Reads));
+            }
+            _ => {}
+        }
+        rest = &rest[start + end + 2..];
+    }
+}
@@ -0,0 +1,7 @@
+pub mod engine;
+pub mod graph;
+pub mod reload;
+pub mod rule;
+pub mod stats;
+pub mod template;
+pub mod variables;
@@ -0,0 +1,295 @@
+use super::engine::{read_rules, RuleEngine};
+use super::rule::Rule;
+use crate::core::config::{line_diff, ConfigChanges, ConfigPreview};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Name of the event published when a rule file was reloaded.
+pub const RELOAD_EVENT: &str = "rules_reloaded";
+/// Name of the event published when a changed rule file was refused.
+pub const RELOAD_FAILED_EVENT: &str = "rules_reload_failed";
+/// Rule file in the data directory.
+pub const RULES_FILE: &str = "rules.json";
+/// Directory below the data directory holding a rule file per blueprint.
+pub const BLUEPRINTS_DIR: &str = "blueprints";
+
+/// How often rule files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/**
+ * RuleFileStatus
+ * A watched rule file: the rules it declares, when it was last changed and
+ * loaded, and why its latest change was refused, if it was.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RuleFileStatus {
+    pub path: PathBuf,
+    pub rules: Vec<String>,
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub loaded_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+struct Watched {
+    modified: Option<SystemTime>,
+    status: RuleFileStatus,
+}
+
+/**
+ * RuleReloader
+ * Keeps the rules of `rules.json` and of every file in `blueprints` in
+ * line with the files. A changed file is read and checked as a whole
+ * before its rules replace the ones it declared before; a file that fails
+ * to parse or clashes with other rules is refused and its previous rules
+ * stay. Removing a file removes its rules.
+ */
+pub struct RuleReloader {
+    data_dir: PathBuf,
+    engine: Arc<RuleEngine>,
+    events: Arc<EventBus>,
+    files: Mutex<BTreeMap<PathBuf, Watched>>,
+}
+
+impl RuleReloader {
+    pub fn new<P: Into<PathBuf>>(
+        data_dir: P,
+        engine: Arc<RuleEngine>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        RuleReloader {
+            data_dir: data_dir.into(),
+            engine,
+            events,
+            files: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Rule files present now, sorted.
+    fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.data_dir.join(RULES_FILE)];
+        if let Ok(entries) = fs::read_dir(self.data_dir.join(BLUEPRINTS_DIR)) {
+            let mut blueprints: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            blueprints.sort();
+            paths.extend(blueprints);
+        }
+        paths.retain(|path| path.is_file());
+        paths
+    }
+
+    /// Loads every rule file, failing on the first one that doesn't load,
+    /// and returns how many rules they declare.
+    pub fn load(&self) -> Result<usize, BlinkieError> {
+        let mut files = self.files.lock().unwrap();
+        for path in self.paths() {
+            let modified = modified(&path);
+            self.reload(&mut files, &path, modified)?;
+        }
+        Ok(files
+            .values()
+            .map(|watched| watched.status.rules.len())
+            .sum())
+    }
+
+    /// Reloads the rule files that changed, appeared or vanished since they
+    /// were last looked at, and returns the status of all of them.
+    pub fn check(&self) -> Vec<RuleFileStatus> {
+        let mut files = self.files.lock().unwrap();
+        let mut paths = self.paths();
+        paths.extend(
+            files
+                .keys()
+                .filter(|path| !path.is_file())
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        for path in paths {
+            let modified = modified(&path);
+            if files
+                .get(&path)
+                .is_some_and(|watched| watched.modified == modified)
+            {
+                continue;
+            }
+            match self.reload(&mut files, &path, modified) {
+                Ok(()) => {
+                    let count = files
+                        .get(&path)
+                        .map_or(0, |watched| watched.status.rules.len());
+                    log::info!("Reloaded {} rules from {}", count, path.display());
+                    self.publish(
+                        RELOAD_EVENT,
+                        &path,
+                        ("rules".to_string(), count.to_string()),
+                    );
+                }
+                Err(e) => {
+                    log::warn!("Keeping previous rules of {}: {}", path.display(), e);
+                    let watched = files.entry(path.clone()).or_insert_with(|| Watched {
+                        modified: None,
+                        status: status(&path, Vec::new(), None),
+                    });
+                    watched.modified = modified;
+                    watched.status.modified = modified.map(DateTime::from);
+                    watched.status.error = Some(e.to_string());
+                    self.publish(
+                        RELOAD_FAILED_EVENT,
+                        &path,
+                        ("error".to_string(), e.to_string()),
+                    );
+                }
+            }
+        }
+        files
+            .values()
+            .map(|watched| watched.status.clone())
+            .collect()
+    }
+
+    pub fn status(&self) -> Vec<RuleFileStatus> {
+        let files = self.files.lock().unwrap();
+        files
+            .values()
+            .map(|watched| watched.status.clone())
+            .collect()
+    }
+
+    /// Rules of `rules.json`, none if there is none.
+    pub fn rules(&self) -> Result<Vec<Rule>, BlinkieError> {
+        let path = self.data_dir.join(RULES_FILE);
+        if !path.is_file() {
+            return Ok(Vec::new());
+        }
+        read_rules(&path)
+    }
+
+    /// What writing `rules` to `rules.json` would change, and what keeps
+    /// them from replacing its rules.
+    pub fn preview_rules(&self, rules: &[Rule]) -> Result<ConfigPreview, BlinkieError> {
+        let files = self.files.lock().unwrap();
+        let path = self.data_dir.join(RULES_FILE);
+        let current = fs::read_to_string(&path).unwrap_or_default();
+        let problems = self
+            .engine
+            .check_rules(&declared(&files, &path), rules)
+            .err()
+            .map(|e| vec![e.to_string()])
+            .unwrap_or_default();
+        Ok(ConfigPreview {
+            problems,
+            changes: ConfigChanges::compare(&self.rules().unwrap_or_default(), rules, |rule| {
+                &rule.id
+            }),
+            diff: line_diff(&current, &render(rules)?),
+        })
+    }
+
+    /// Writes `rules` to `rules.json` and swaps them in for the rules it
+    /// declared. Nothing changes if they can't replace them.
+    pub fn save_rules(&self, rules: Vec<Rule>) -> Result<ConfigChanges, BlinkieError> {
+        let mut files = self.files.lock().unwrap();
+        let path = self.data_dir.join(RULES_FILE);
+        self.engine.check_rules(&declared(&files, &path), &rules)?;
+        let changes =
+            ConfigChanges::compare(&self.rules().unwrap_or_default(), &rules, |rule| &rule.id);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, render(&rules)?)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        self.reload(&mut files, &path, modified(&path))?;
+        self.publish(
+            RELOAD_EVENT,
+            &path,
+            ("rules".to_string(), rules.len().to_string()),
+        );
+        Ok(changes)
+    }
+
+    /// Swaps in the rules of `path`, none if it is gone.
+    fn reload(
+        &self,
+        files: &mut BTreeMap<PathBuf, Watched>,
+        path: &Path,
+        modified: Option<SystemTime>,
+    ) -> Result<(), BlinkieError> {
+        let rules = match modified {
+            Some(_) => read_rules(path)?,
+            None => Vec::new(),
+        };
+        let ids: Vec<String> = rules.iter().map(|rule| rule.id.clone()).collect();
+        self.engine
+            .replace_rules(&declared(files, path), rules)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if modified.is_none() {
+            files.remove(path);
+            return Ok(());
+        }
+        files.insert(
+            path.to_path_buf(),
+            Watched {
+                modified,
+                status: status(path, ids, modified),
+            },
+        );
+        Ok(())
+    }
+
+    fn publish(&self, name: &str, path: &Path, detail: (String, String)) {
+        let _ = self.events.publish(EventKind::Custom {
+            name: name.to_string(),
+            data: HashMap::from([("path".to_string(), path.display().to_string()), detail]),
+        });
+    }
+
+    /// Checks the rule files for changes until the runtime stops.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.check();
+        }
+    }
+}
+
+/// Ids of the rules loaded from `path`.
+fn declared(files: &BTreeMap<PathBuf, Watched>, path: &Path) -> Vec<String> {
+    files
+        .get(path)
+        .map(|watched| watched.status.rules.clone())
+        .unwrap_or_default()
+}
+
+fn render(rules: &[Rule]) -> Result<String, BlinkieError> {
+    serde_json::to_string_pretty(rules)
+        .map(|json| format!("{}\n", json))
+        .map_err(|e| BlinkieError::serialization(format!("Failed to serialize rules: {}", e)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn status(path: &Path, rules: Vec<String>, modified: Option<SystemTime>) -> RuleFileStatus {
+    RuleFileStatus {
+        path: path.to_path_buf(),
+        rules,
+        modified: modified.map(DateTime::from),
+        loaded_at: Some(Utc::now()),
+        error: None,
+    }
+}
@@ -0,0 +1,500 @@
+use super::template::Scope;
+use super::variables::VariableScope;
+use crate::core::device::Action;
+use crate::core::event::{Event, EventKind};
+use crate::core::usage::{UsageMetric, UsagePeriod};
+use crate::core::value::StateValue;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/**
+ * Triggers
+ * Events that start a rule.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    /// A device state key changed, optionally to a specific value. With
+    /// `above` or `below`, fires when a number crosses into the range, e.g.
+    /// a temperature rising above 25, not again while it stays there. Both
+    /// bounds are exclusive.
+    StateChanged {
+        device_id: String,
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default)]
+        to: Option<String>,
+        #[serde(default)]
+        above: Option<f64>,
+        #[serde(default)]
+        below: Option<f64>,
+    },
+    /// A custom event with the given name was published, carrying at least
+    /// `data`, e.g. the answer to one notification.
+    Event {
+        name: String,
+        #[serde(default)]
+        data: HashMap<String, String>,
+    },
+}
+
+impl Trigger {
+    pub fn matches(&self, event: &Event) -> bool {
+        match (self, &event.kind) {
+            (
+                Trigger::StateChanged {
+                    device_id,
+                    key,
+                    to,
+                    above,
+                    below,
+                },
+                EventKind::StateChanged {
+                    device_id: changed_id,
+                    key: changed_key,
+                    old_value,
+                    new_value,
+                    ..
+                },
+            ) => {
+                let bounded = above.is_some() || below.is_some();
+                device_id == changed_id
+                    && key.as_ref().is_none_or(|key| key == changed_key)
+                    && to.as_ref().is_none_or(|to| to == new_value)
+                    && (!bounded
+                        || (in_range(new_value, *above, *below)
+                            && !old_value
+                                .as_ref()
+                                .is_some_and(|old| in_range(old, *above, *below))))
+            }
+            (
+                Trigger::Event { name, data },
+                EventKind::Custom {
+                    name: fired,
+                    data: fired_data,
+                },
+            ) => {
+                name == fired
+                    && data
+                        .iter()
+                        .all(|(key, value)| fired_data.get(key) == Some(value))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether a state value is a number within the exclusive bounds.
+fn in_range(value: &str, above: Option<f64>, below: Option<f64>) -> bool {
+    StateValue::parse(value).as_f64().is_some_and(|value| {
+        above.is_none_or(|above| value > above) && below.is_none_or(|below| value < below)
+    })
+}
+
+/**
+ * Conditions
+ * Checked after a trigger fired; all of a rule's conditions must hold for
+ * its actions to run.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// Whether a state value matches `equals`, is a number within `above`
+    /// and `below` (both exclusive), and is fresh enough to trust: at most
+    /// `max_age_secs` old, or as old as the engine's stale policy allows if
+    /// unset.
+    State {
+        device_id: String,
+        key: String,
+        #[serde(default)]
+        equals: Option<StateValue>,
+        #[serde(default)]
+        above: Option<f64>,
+        #[serde(default)]
+        below: Option<f64>,
+        #[serde(default)]
+        max_age_secs: Option<u64>,
+    },
+    Not {
+        condition: Box<Condition>,
+    },
+    Any {
+        conditions: Vec<Condition>,
+    },
+    All {
+        conditions: Vec<Condition>,
+    },
+    /// Whether the run's last wait-for-trigger step saw its event (`true`)
+    /// or timed out (`false`). False if the run has not waited yet.
+    Wait {
+        completed: bool,
+    },
+    Variable {
+        name: String,
+        #[serde(default)]
+        scope: VariableScope,
+        equals: String,
+    },
+    /// Renders `template` and compares the result, e.g. `{{ trigger.new_value }}`.
+    Template {
+        template: String,
+        equals: String,
+    },
+    /// Compares a device's usage, e.g. whether a compressor ran for more
+    /// than 12 hours today. Both bounds are exclusive.
+    Usage {
+        device_id: String,
+        metric: UsageMetric,
+        #[serde(default)]
+        period: UsagePeriod,
+        #[serde(default)]
+        above: Option<f64>,
+        #[serde(default)]
+        below: Option<f64>,
+    },
+    /// Whether a state value is at most `max_age_secs` old, so rules can
+    /// ignore stale sensor readings. False while the value's age is unknown.
+    Fresh {
+        device_id: String,
+        key: String,
+        max_age_secs: u64,
+    },
+    /// Whether a device reported a state, or with `equals` that value of it,
+    /// within the last `within_secs`. "No motion in 10 minutes" is a `Not`
+    /// of a recent `motion` equal to `true` within 600 seconds. False while
+    /// no state history is recorded.
+    Recent {
+        device_id: String,
+        key: String,
+        #[serde(default)]
+        equals: Option<StateValue>,
+        within_secs: u64,
+    },
+}
+
+impl Condition {
+    pub fn evaluate(&self, scope: &Scope) -> bool {
+        match self {
+            Condition::State {
+                device_id,
+                key,
+                equals,
+                above,
+                below,
+                max_age_secs,
+            } => {
+                let matches = {
+                    let devices = scope.devices.read().unwrap();
+                    devices
+                        .iter()
+                        .find(|device| device.get_id() == device_id)
+                        .and_then(|device| device.get_state().get(key).cloned())
+                        .is_some_and(|value| {
+                            equals.as_ref().is_none_or(|equals| value.matches(equals))
+                                && (above.is_none() && below.is_none()
+                                    || value.as_f64().is_some_and(|value| {
+                                        above.is_none_or(|above| value > above)
+                                            && below.is_none_or(|below| value < below)
+                                    }))
+                        })
+                };
+                matches && scope.is_fresh(device_id, key, *max_age_secs)
+            }
+            Condition::Not { condition } => !condition.evaluate(scope),
+            Condition::Any { conditions } => conditions.iter().any(|c| c.evaluate(scope)),
+            Condition::All { conditions } => conditions.iter().all(|c| c.evaluate(scope)),
+            Condition::Wait { completed } => scope
+                .context
+                .wait
+                .as_ref()
+                .is_some_and(|wait| wait.completed == *completed),
+            Condition::Variable {
+                name,
+                scope: variable_scope,
+                equals,
+            } => {
+                let value = match variable_scope {
+                    VariableScope::Run => scope.context.variables.get(name).cloned(),
+                    VariableScope::Rule => scope.variables.rule(&scope.context.rule_id, name),
+                    VariableScope::Global => scope.variables.global(name),
+                };
+                value.is_some_and(|value| value == *equals)
+            }
+            Condition::Template { template, equals } => scope.render(template) == *equals,
+            Condition::Usage {
+                device_id,
+                metric,
+                period,
+                above,
+                below,
+            } => {
+                let Some(usage) = scope.usage else {
+                    return false;
+                };
+                let value = usage.usage(device_id, *period, Utc::now()).metric(*metric);
+                above.is_none_or(|above| value > above) && below.is_none_or(|below| value < below)
+            }
+            Condition::Fresh {
+                device_id,
+                key,
+                max_age_secs,
+            } => scope
+                .clock
+                .and_then(|clock| clock.age(device_id, key, Utc::now()))
+                .is_some_and(|age| age.num_seconds() <= *max_age_secs as i64),
+            Condition::Recent {
+                device_id,
+                key,
+                equals,
+                within_secs,
+            } => scope.history.is_some_and(|history| {
+                // Windows longer than time can go back reach the beginning
+                let since = i64::try_from(*within_secs)
+                    .ok()
+                    .and_then(chrono::Duration::try_seconds)
+                    .and_then(|within| Utc::now().checked_sub_signed(within))
+                    .unwrap_or(chrono::DateTime::<Utc>::MIN_UTC);
+                history.reported_since(device_id, key, since, equals.as_ref())
+            }),
+        }
+    }
+}
+
+/**
+ * StalePolicy
+ * How old state values read by state conditions may be before rules stop
+ * trusting them, so automations don't act on a sensor that died hours ago.
+ * Conditions with a `max_age_secs` of their own override the limit.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StalePolicy {
+    /// Oldest a value may be, in seconds. No limit if unset.
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+    /// Whether values of unknown age pass, i.e. ones that did not change
+    /// since the hub started.
+    #[serde(default)]
+    pub allow_unknown: bool,
+}
+
+/**
+ * Steps
+ * Single entries of a rule's action sequence, executed in order. Command
+ * parameters, event data and variable values may contain `{{ ... }}` templates.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Step {
+    Action {
+        device_id: String,
+        action: Action,
+    },
+    Command {
+        device_id: String,
+        command: String,
+        #[serde(default)]
+        parameters: Option<HashMap<String, String>>,
+    },
+    Delay {
+        millis: u64,
+    },
+    Event {
+        name: String,
+        #[serde(default)]
+        data: HashMap<String, String>,
+    },
+    /// Pauses the sequence until one of `triggers` fires or the timeout expires.
+    /// On timeout the sequence stops unless `continue_on_timeout` is set.
+    WaitForTrigger {
+        triggers: Vec<Trigger>,
+        #[serde(default)]
+        timeout_millis: Option<u64>,
+        #[serde(default = "default_continue_on_timeout")]
+        continue_on_timeout: bool,
+    },
+    SetVariable {
+        name: String,
+        value: String,
+        #[serde(default)]
+        scope: VariableScope,
+    },
+    /// Runs `then` if all conditions hold, `otherwise` if not.
+    Choose {
+        conditions: Vec<Condition>,
+        then: Vec<Step>,
+        #[serde(default)]
+        otherwise: Vec<Step>,
+    },
+}
+
+fn default_continue_on_timeout() -> bool {
+    true
+}
+
+/**
+ * Run Modes
+ * What happens when a rule triggers again while its actions are still running.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RunMode {
+    /// Ignore the new trigger.
+    #[default]
+    Single,
+    /// Cancel the running sequence and start over.
+    Restart,
+    /// Run after the current sequence finishes; at most `max` runs wait or run.
+    Queued { max: usize },
+    /// Run alongside the current sequence; at most `max` runs at once.
+    Parallel { max: usize },
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/**
+ * Rule
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Step>,
+    #[serde(default)]
+    pub mode: RunMode,
+}
+
+/**
+ * Renames
+ * Id replacements applied to a rule when it is moved into a namespace.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Renames {
+    pub devices: HashMap<String, String>,
+    pub globals: HashMap<String, String>,
+}
+
+impl Renames {
+    fn device(&self, id: &mut String) {
+        if let Some(new_id) = self.devices.get(id.as_str()) {
+            *id = new_id.clone();
+        }
+    }
+
+    fn variable(&self, scope: VariableScope, name: &mut String) {
+        if scope == VariableScope::Global {
+            if let Some(new_name) = self.globals.get(name.as_str()) {
+                *name = new_name.clone();
+            }
+        }
+    }
+
+    /// Rewrites `{{ state.<device>.<key> }}` and `{{ global.<name> }}` lookups.
+    fn template(&self, template: &mut String) {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            let path = rest[start + 2..start + end].trim();
+            let renamed = match path.split_once('.') {
+                Some((root @ ("state" | "age"), lookup)) => {
+                    lookup.split_once('.').and_then(|(device, key)| {
+                        let device = self.devices.get(device)?;
+                        Some(format!("{}.{}.{}", root, device, key))
+                    })
+                }
+                Some(("global", name)) => self
+                    .globals
+                    .get(name)
+                    .map(|name| format!("global.{}", name)),
+                _ => None,
+            };
+            output.push_str(&rest[..start]);
+            match renamed {
+                Some(path) => output.push_str(&format!("{{{{ {} }}}}", path)),
+                None => output.push_str(&rest[start..start + end + 2]),
+            }
+            rest = &rest[start + end + 2..];
+        }
+        output.push_str(rest);
+        *template = output;
+    }
+
+    fn trigger(&self, trigger: &mut Trigger) {
+        if let Trigger::StateChanged { device_id, .. } = trigger {
+            self.device(device_id);
+        }
+    }
+
+    fn condition(&self, condition: &mut Condition) {
+        match condition {
+            Condition::State { device_id, .. } => self.device(device_id),
+            Condition::Not { condition } => self.condition(condition),
+            Condition::Any { conditions } | Condition::All { conditions } => {
+                conditions.iter_mut().for_each(|c| self.condition(c))
+            }
+            Condition::Wait { .. } => {}
+            Condition::Variable { name, scope, .. } => self.variable(*scope, name),
+            Condition::Template { template, .. } => self.template(template),
+            Condition::Usage { device_id, .. }
+            | Condition::Fresh { device_id, .. }
+            | Condition::Recent { device_id, .. } => self.device(device_id),
+        }
+    }
+
+    fn steps(&self, steps: &mut [Step]) {
+        for step in steps {
+            match step {
+                Step::Action { device_id, .. } => self.device(device_id),
+                Step::Command {
+                    device_id,
+                    parameters,
+                    ..
+                } => {
+                    self.device(device_id);
+                    for value in parameters.iter_mut().flat_map(|p| p.values_mut()) {
+                        self.template(value);
+                    }
+                }
+                Step::Delay { .. } => {}
+                Step::Event { data, .. } => data.values_mut().for_each(|v| self.template(v)),
+                Step::WaitForTrigger { triggers, .. } => {
+                    triggers.iter_mut().for_each(|t| self.trigger(t))
+                }
+                Step::SetVariable { name, value, scope } => {
+                    self.variable(*scope, name);
+                    self.template(value);
+                }
+                Step::Choose {
+                    conditions,
+                    then,
+                    otherwise,
+                } => {
+                    conditions.iter_mut().for_each(|c| self.condition(c));
+                    self.steps(then);
+                    self.steps(otherwise);
+                }
+            }
+        }
+    }
+}
+
+impl Rule {
+    /// Replaces every reference to a renamed device or global variable.
+    pub fn apply_renames(&mut self, renames: &Renames) {
+        self.triggers.iter_mut().for_each(|t| renames.trigger(t));
+        self.conditions
+            .iter_mut()
+            .for_each(|c| renames.condition(c));
+        renames.steps(&mut self.actions);
+    }
+}
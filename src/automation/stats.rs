@@ -0,0 +1,190 @@
+use crate::core::error::BlinkieError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/**
+ * RuleStats
+ * Execution counters and last-run metadata of a single rule.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RuleStats {
+    /// Runs started because the rule triggered and its conditions held.
+    pub fired: u64,
+    /// Triggers dropped because the rule's run mode allowed no further run.
+    pub skipped: u64,
+    pub completed: u64,
+    pub failed: u64,
+    pub last_triggered: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/**
+ * RuleStatsStore
+ * Collects statistics for all rules. When opened from a file, every update
+ * is written back so the numbers survive restarts.
+ */
+pub struct RuleStatsStore {
+    path: Option<PathBuf>,
+    stats: RwLock<HashMap<String, RuleStats>>,
+}
+
+impl Default for RuleStatsStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RuleStatsStore {
+    /// Creates a store that only keeps statistics in memory.
+    pub fn new() -> Self {
+        RuleStatsStore {
+            path: None,
+            stats: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Opens a persistent store, loading previously saved statistics if the file exists.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let stats = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            HashMap::new()
+        };
+        Ok(RuleStatsStore {
+            path: Some(path),
+            stats: RwLock::new(stats),
+        })
+    }
+
+    /// Statistics of a single rule.
+    pub fn get(&self, rule_id: &str) -> RuleStats {
+        let stats = self.stats.read().unwrap();
+        stats.get(rule_id).cloned().unwrap_or_default()
+    }
+
+    /// Statistics of all rules that ever triggered.
+    pub fn all(&self) -> HashMap<String, RuleStats> {
+        self.stats.read().unwrap().clone()
+    }
+
+    pub fn record_fired(&self, rule_id: &str) -> Result<(), BlinkieError> {
+        self.update(rule_id, |stats| {
+            stats.fired += 1;
+            stats.last_triggered = Some(Utc::now());
+        })
+    }
+
+    pub fn record_skipped(&self, rule_id: &str) -> Result<(), BlinkieError> {
+        self.update(rule_id, |stats| stats.skipped += 1)
+    }
+
+    /// Records how a run ended.
+    pub fn record_result(
+        &self,
+        rule_id: &str,
+        result: &Result<(), BlinkieError>,
+    ) -> Result<(), BlinkieError> {
+        self.update(rule_id, |stats| match result {
+            Ok(()) => stats.completed += 1,
+            Err(e) => {
+                stats.failed += 1;
+                stats.last_error = Some(e.to_string());
+                stats.last_error_at = Some(Utc::now());
+            }
+        })
+    }
+
+    /// Drops the statistics of a rule, e.g. after it was deleted.
+    pub fn remove(&self, rule_id: &str) -> Result<(), BlinkieError> {
+        let mut stats = self.stats.write().unwrap();
+        stats.remove(rule_id);
+        self.save(&stats)
+    }
+
+    fn update<F>(&self, rule_id: &str, f: F) -> Result<(), BlinkieError>
+    where
+        F: FnOnce(&mut RuleStats),
+    {
+        let mut stats = self.stats.write().unwrap();
+        f(stats.entry(rule_id.to_string()).or_default());
+        self.save(&stats)
+    }
+
+    fn save(&self, stats: &HashMap<String, RuleStats>) -> Result<(), BlinkieError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string_pretty(stats).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize rule statistics: {}", e))
+        })?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn stats_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("blinkie-stats-{}-{}.json", process::id(), name))
+    }
+
+    #[test]
+    fn stats_survive_a_restart() {
+        let path = stats_path("restart");
+        let store = RuleStatsStore::open(&path).unwrap();
+        store.record_fired("porch").unwrap();
+        store.record_result("porch", &Ok(())).unwrap();
+        store.record_fired("porch").unwrap();
+        store
+            .record_result("porch", &Err("Device 'porch' is offline".into()))
+            .unwrap();
+        store.record_skipped("porch").unwrap();
+        let before = store.get("porch");
+        drop(store);
+
+        let reopened = RuleStatsStore::open(&path).unwrap();
+        let after = reopened.get("porch");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(after.fired, 2);
+        assert_eq!(after.completed, 1);
+        assert_eq!(after.failed, 1);
+        assert_eq!(after.skipped, 1);
+        assert_eq!(after.last_triggered, before.last_triggered);
+        assert_eq!(
+            after.last_error.as_deref(),
+            Some("Device 'porch' is offline")
+        );
+        assert_eq!(after.last_error_at, before.last_error_at);
+    }
+
+    #[test]
+    fn removed_stats_stay_removed_after_a_restart() {
+        let path = stats_path("remove");
+        let store = RuleStatsStore::open(&path).unwrap();
+        store.record_fired("porch").unwrap();
+        store.record_fired("hallway").unwrap();
+        store.remove("porch").unwrap();
+        drop(store);
+
+        let reopened = RuleStatsStore::open(&path).unwrap();
+        let all = reopened.all();
+        fs::remove_file(&path).unwrap();
+        assert!(!all.contains_key("porch"));
+        assert_eq!(all["hallway"].fired, 1);
+    }
+}
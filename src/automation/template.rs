@@ -0,0 +1,130 @@
+use super::engine::RunContext;
+use super::rule::StalePolicy;
+use super::variables::VariableStore;
+use crate::core::device::DeviceList;
+use crate::core::event::Event;
+use crate::core::freshness::StateClock;
+use crate::core::history::StateHistory;
+use crate::core::usage::UsageTracker;
+use crate::core::user::UserStore;
+use crate::core::value::StateValue;
+use chrono::Utc;
+use serde_json::Value;
+
+/**
+ * Scope
+ * Everything the templates and conditions of a running rule can look at.
+ */
+pub struct Scope<'a> {
+    pub devices: &'a DeviceList,
+    pub variables: &'a VariableStore,
+    pub context: &'a RunContext,
+    pub usage: Option<&'a UsageTracker>,
+    pub history: Option<&'a StateHistory>,
+    pub clock: Option<&'a StateClock>,
+    pub users: Option<&'a UserStore>,
+    pub stale: StalePolicy,
+}
+
+impl Scope<'_> {
+    /// Whether a state value is at most `max_age_secs` old, or as old as the
+    /// stale policy allows if unset.
+    pub fn is_fresh(&self, device_id: &str, key: &str, max_age_secs: Option<u64>) -> bool {
+        let Some(max_age_secs) = max_age_secs.or(self.stale.max_age_secs) else {
+            return true;
+        };
+        match self
+            .clock
+            .and_then(|clock| clock.age(device_id, key, Utc::now()))
+        {
+            Some(age) => age.num_seconds() <= max_age_secs as i64,
+            None => self.stale.allow_unknown,
+        }
+    }
+
+    /// Resolves a dotted path such as `trigger.new_value`, `var.count`,
+    /// `global.mode`, `state.lamp.brightness`, `age.lamp.brightness`, the
+    /// value's age in seconds, `response.scene`, a field of the device's
+    /// answer to the run's latest command, or `user.alice.temperature` and
+    /// `users.home`, see `UserStore::lookup`.
+    pub fn lookup(&self, path: &str) -> Option<String> {
+        let (root, rest) = path.split_once('.').unwrap_or((path, ""));
+        match root {
+            "trigger" => event_field(self.context.trigger.as_ref()?, rest),
+            "var" => self
+                .context
+                .variables
+                .get(rest)
+                .cloned()
+                .or_else(|| self.variables.rule(&self.context.rule_id, rest)),
+            "global" => self.variables.global(rest),
+            "rule" => match rest {
+                "id" => Some(self.context.rule_id.clone()),
+                _ => None,
+            },
+            "wait" => {
+                let wait = self.context.wait.as_ref()?;
+                match rest.split_once('.') {
+                    Some(("event", field)) => event_field(wait.event.as_ref()?, field),
+                    _ if rest == "completed" => Some(wait.completed.to_string()),
+                    _ => None,
+                }
+            }
+            "response" => self
+                .context
+                .response
+                .as_ref()?
+                .get(rest)
+                .map(str::to_string),
+            "state" => {
+                let (device_id, key) = rest.split_once('.')?;
+                let devices = self.devices.read().unwrap();
+                let device = devices.iter().find(|device| device.get_id() == device_id)?;
+                device.get_state().get(key).map(StateValue::to_string)
+            }
+            "age" => {
+                let (device_id, key) = rest.split_once('.')?;
+                let age = self.clock?.age(device_id, key, Utc::now())?;
+                Some(age.num_seconds().to_string())
+            }
+            "user" | "users" => self.users?.lookup(root, rest),
+            _ => None,
+        }
+    }
+
+    /// Replaces every `{{ path }}` in `template` with the value it refers to.
+    /// Unknown paths render as an empty string.
+    pub fn render(&self, template: &str) -> String {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            output.push_str(&rest[..start]);
+            let path = rest[start + 2..start + end].trim();
+            output.push_str(&self.lookup(path).unwrap_or_default());
+            rest = &rest[start + end + 2..];
+        }
+        output.push_str(rest);
+        output
+    }
+}
+
+/// Reads a field of an event, e.g. `device_id`, `new_value`, `data.source` or `seq`.
+fn event_field(event: &Event, path: &str) -> Option<String> {
+    match path {
+        "seq" => return Some(event.seq.to_string()),
+        "timestamp" => return Some(event.timestamp.to_rfc3339()),
+        _ => {}
+    }
+    let mut value = serde_json::to_value(&event.kind).ok()?;
+    for part in path.split('.') {
+        value = value.get(part)?.clone();
+    }
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        other => Some(other.to_string()),
+    }
+}
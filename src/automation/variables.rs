@@ -0,0 +1,117 @@
+use crate::core::error::BlinkieError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/**
+ * Variable Scopes
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VariableScope {
+    /// Lives only as long as a single run of the rule.
+    #[default]
+    Run,
+    /// Shared by all runs of the same rule.
+    Rule,
+    /// Shared by all rules.
+    Global,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Variables {
+    #[serde(default)]
+    global: HashMap<String, String>,
+    #[serde(default)]
+    rules: HashMap<String, HashMap<String, String>>,
+}
+
+/**
+ * VariableStore
+ * Holds rule-scoped and global variables. When opened from a file, every
+ * change is written back so variables survive restarts.
+ */
+pub struct VariableStore {
+    path: Option<PathBuf>,
+    variables: RwLock<Variables>,
+}
+
+impl Default for VariableStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VariableStore {
+    /// Creates a store that only keeps variables in memory.
+    pub fn new() -> Self {
+        VariableStore {
+            path: None,
+            variables: RwLock::new(Variables::default()),
+        }
+    }
+
+    /// Opens a persistent store, loading previously saved variables if the file exists.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let variables = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            Variables::default()
+        };
+        Ok(VariableStore {
+            path: Some(path),
+            variables: RwLock::new(variables),
+        })
+    }
+
+    pub fn global(&self, name: &str) -> Option<String> {
+        self.variables.read().unwrap().global.get(name).cloned()
+    }
+
+    pub fn rule(&self, rule_id: &str, name: &str) -> Option<String> {
+        let variables = self.variables.read().unwrap();
+        variables.rules.get(rule_id)?.get(name).cloned()
+    }
+
+    pub fn set_global(&self, name: &str, value: &str) -> Result<(), BlinkieError> {
+        let mut variables = self.variables.write().unwrap();
+        variables.global.insert(name.to_string(), value.to_string());
+        self.save(&variables)
+    }
+
+    pub fn set_rule(&self, rule_id: &str, name: &str, value: &str) -> Result<(), BlinkieError> {
+        let mut variables = self.variables.write().unwrap();
+        variables
+            .rules
+            .entry(rule_id.to_string())
+            .or_default()
+            .insert(name.to_string(), value.to_string());
+        self.save(&variables)
+    }
+
+    /// All global variables.
+    pub fn globals(&self) -> HashMap<String, String> {
+        self.variables.read().unwrap().global.clone()
+    }
+
+    fn save(&self, variables: &Variables) -> Result<(), BlinkieError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string_pretty(variables).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize variables: {}", e))
+        })?;
+        // Write to a temporary file first so a crash never leaves half a file behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+    }
+}
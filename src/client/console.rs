@@ -0,0 +1,360 @@
+use super::http::ApiClient;
+use crate::api::endpoints::{CommandBody, HandlerStatus, TemplateBody, TemplateResult};
+use crate::core::app::CommandResult;
+use crate::core::query::{QueryRequest, QueryResult};
+use crate::core::value::{StateMap, StateValue};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEYWORDS: [&str; 10] = [
+    "devices", "state", "send", "render", "tail", "handlers", "query", "help", "exit", "quit",
+];
+/// Commands every device is expected to understand; more are learned from history.
+const BASE_COMMANDS: [&str; 3] = ["turn_on", "turn_off", "reset"];
+/// Completion data older than this is fetched again.
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+const HELP: &str = "\
+devices                          list devices and their state
+state <device> [key]             show the state of a device
+send <device> <command> [k=v..]  send a command to a device
+render <template>                render a template, e.g. {{ state.lamp.state }}
+tail [device]                    print events as they happen
+tail off                         stop printing events
+handlers                         list protocol handlers and their health
+query <sql>                      run a read-only SQL query over the history
+exit                             leave the console";
+
+#[derive(Default)]
+struct Completions {
+    devices: Vec<String>,
+    commands: Vec<String>,
+    fetched: Option<Instant>,
+}
+
+/**
+ * ConsoleHelper
+ * Completes keywords, device ids and commands known to the daemon.
+ */
+struct ConsoleHelper {
+    client: ApiClient,
+    cache: Mutex<Completions>,
+}
+
+impl ConsoleHelper {
+    fn refresh(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .fetched
+            .is_some_and(|fetched| fetched.elapsed() < CACHE_TTL)
+        {
+            return;
+        }
+        if let Ok(states) = self.client.get::<HashMap<String, StateMap>>("/api/states") {
+            cache.devices = states.into_keys().collect();
+            cache.devices.sort();
+        }
+        let mut commands: Vec<String> = BASE_COMMANDS.iter().map(|c| c.to_string()).collect();
+        let request = QueryRequest {
+            sql: "SELECT DISTINCT json_extract(data, '$.command') FROM events WHERE type = 'command_sent'".to_string(),
+            limit: Some(500),
+        };
+        if let Ok(result) = self
+            .client
+            .post::<_, QueryResult>("/api/history/query", &request)
+        {
+            commands.extend(
+                result
+                    .rows
+                    .iter()
+                    .filter_map(|row| row.first()?.as_str().map(str::to_string)),
+            );
+        }
+        commands.sort();
+        commands.dedup();
+        cache.commands = commands;
+        cache.fetched = Some(Instant::now());
+    }
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let options: Vec<String> = match before.as_slice() {
+            [] => KEYWORDS.iter().map(|k| k.to_string()).collect(),
+            ["state" | "send" | "tail"] => {
+                self.refresh();
+                self.cache.lock().unwrap().devices.clone()
+            }
+            ["send", _] => {
+                self.refresh();
+                self.cache.lock().unwrap().commands.clone()
+            }
+            _ => Vec::new(),
+        };
+        let candidates = options
+            .into_iter()
+            .filter(|option| option.starts_with(word))
+            .map(|option| Pair {
+                display: option.clone(),
+                replacement: format!("{} ", option),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+/**
+ * Console
+ * Interactive shell speaking to a running daemon over its API.
+ */
+pub struct Console {
+    client: ApiClient,
+    /// Stops the current event tail, if one is running.
+    tail: Option<Arc<AtomicBool>>,
+}
+
+impl Console {
+    pub fn new(client: ApiClient) -> Self {
+        Console { client, tail: None }
+    }
+
+    /// Reads and runs lines until `exit` or end of input.
+    pub fn run(mut self) -> Result<(), String> {
+        let mut editor: Editor<ConsoleHelper, DefaultHistory> =
+            Editor::new().map_err(|e| format!("Failed to start console: {}", e))?;
+        editor.set_helper(Some(ConsoleHelper {
+            client: self.client.clone(),
+            cache: Mutex::new(Completions::default()),
+        }));
+        println!(
+            "Connected to {}. Type 'help' for commands.",
+            self.client.url()
+        );
+
+        loop {
+            let line = match editor.readline("blinkie> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(format!("Failed to read input: {}", e)),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(line);
+            if matches!(line, "exit" | "quit") {
+                break;
+            }
+            if let Err(e) = self.execute(line, &mut editor) {
+                eprintln!("Error: {}", e);
+            }
+        }
+        self.stop_tail();
+        Ok(())
+    }
+
+    fn execute(
+        &mut self,
+        line: &str,
+        editor: &mut Editor<ConsoleHelper, DefaultHistory>,
+    ) -> Result<(), String> {
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        match (keyword, args.as_slice()) {
+            ("help", _) => println!("{}", HELP),
+            ("devices", []) => {
+                let states: HashMap<String, StateMap> = self.client.get("/api/states")?;
+                let mut ids: Vec<&String> = states.keys().collect();
+                ids.sort();
+                for id in ids {
+                    println!("{:<24} {}", id, format_state(&states[id]));
+                }
+            }
+            ("state", [device_id, key @ ..]) => {
+                let mut states: HashMap<String, StateMap> = self.client.get("/api/states")?;
+                let state = states
+                    .remove(*device_id)
+                    .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+                match key.first() {
+                    Some(key) => match state.get(*key) {
+                        Some(value) => println!("{}", value),
+                        None => println!(),
+                    },
+                    None => println!("{}", format_state(&state)),
+                }
+            }
+            ("send", [device_id, command, parameters @ ..]) => {
+                let parameters = parameters
+                    .iter()
+                    .map(|parameter| {
+                        parameter
+                            .split_once('=')
+                            .map(|(key, value)| (key.to_string(), value.to_string()))
+                            .ok_or_else(|| format!("Expected key=value, got '{}'", parameter))
+                    })
+                    .collect::<Result<HashMap<_, _>, _>>()?;
+                let body = CommandBody {
+                    command: command.to_string(),
+                    parameters: (!parameters.is_empty()).then_some(parameters),
+                    lease: None,
+                };
+                let result: CommandResult = self
+                    .client
+                    .post(&format!("/api/devices/{}/commands", device_id), &body)?;
+                match result.job {
+                    Some(job) => println!("Sent (event #{}, job #{})", result.event.seq, job),
+                    None => println!("Sent (event #{})", result.event.seq),
+                }
+                let mut response: Vec<_> = result.response.data.into_iter().collect();
+                response.sort();
+                for (key, value) in response {
+                    println!("  {} = {}", key, value);
+                }
+            }
+            ("render", _) if !rest.is_empty() => {
+                let result: TemplateResult = self.client.post(
+                    "/api/template",
+                    &TemplateBody {
+                        template: rest.to_string(),
+                    },
+                )?;
+                println!("{}", result.output);
+            }
+            ("tail", ["off"]) => self.stop_tail(),
+            ("tail", device_id) if device_id.len() <= 1 => {
+                self.stop_tail();
+                let printer = editor
+                    .create_external_printer()
+                    .map_err(|e| format!("Failed to tail events: {}", e))?;
+                self.tail =
+                    Some(self.start_tail(device_id.first().map(|id| id.to_string()), printer));
+            }
+            ("handlers", []) => {
+                let handlers: Vec<HandlerStatus> = self.client.get("/api/handlers")?;
+                for handler in handlers {
+                    println!(
+                        "{:<24} {:<24} {:<6} {}",
+                        handler.name,
+                        handler.protocols.join(","),
+                        handler
+                            .api_version
+                            .map_or("-".to_string(), |version| version.to_string()),
+                        match (handler.maintenance, handler.error) {
+                            (Some(flag), _) => format!("maintenance until {}", flag.until),
+                            (None, Some(error)) => error,
+                            (None, None) => "ok".to_string(),
+                        }
+                    );
+                }
+            }
+            ("query", _) if !rest.is_empty() => {
+                let result: QueryResult = self.client.post(
+                    "/api/history/query",
+                    &QueryRequest {
+                        sql: rest.to_string(),
+                        limit: None,
+                    },
+                )?;
+                println!("{}", result.columns.join("\t"));
+                for row in &result.rows {
+                    let cells: Vec<String> = row
+                        .iter()
+                        .map(|value| match value {
+                            serde_json::Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        })
+                        .collect();
+                    println!("{}", cells.join("\t"));
+                }
+                if result.truncated {
+                    println!("(truncated)");
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown or incomplete command '{}', try 'help'",
+                    line
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn start_tail<P: ExternalPrinter + Send + 'static>(
+        &self,
+        device_id: Option<String>,
+        mut printer: P,
+    ) -> Arc<AtomicBool> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let client = self.client.clone();
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            let result = client.follow_events(device_id.as_deref(), |event| {
+                if stopped.load(Ordering::Relaxed) {
+                    return false;
+                }
+                let _ = printer.print(format!(
+                    "{} #{} {}\n",
+                    event.timestamp.format("%H:%M:%S"),
+                    event.seq,
+                    event.kind.summary()
+                ));
+                true
+            });
+            if let Err(e) = result {
+                let _ = printer.print(format!("Event tail stopped: {}\n", e));
+            }
+        });
+        stop
+    }
+
+    fn stop_tail(&mut self) {
+        if let Some(stop) = self.tail.take() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Formats a state as `key=value` pairs sorted by key.
+pub fn format_state(state: &StateMap) -> String {
+    let mut state: Vec<(&String, &StateValue)> = state.iter().collect();
+    state.sort_by_key(|(key, _)| *key);
+    state
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,151 @@
+use crate::core::event::Event;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+/**
+ * ApiClient
+ * Talks to the HTTP API of a running blinkie daemon, presenting the API
+ * token in `BLINKIE_TOKEN` if it is set.
+ */
+#[derive(Clone)]
+pub struct ApiClient {
+    base: String,
+    client: Client,
+}
+
+impl ApiClient {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let mut headers = HeaderMap::new();
+        if let Ok(token) = std::env::var("BLINKIE_TOKEN") {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| "BLINKIE_TOKEN is not a valid token".to_string())?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = Client::builder()
+            .default_headers(headers)
+            .connect_timeout(Duration::from_secs(5))
+            // The event stream stays open indefinitely
+            .timeout(None)
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        Ok(ApiClient {
+            base: url.trim_end_matches('/').to_string(),
+            client,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.base
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, String> {
+        self.get_query(path, &[] as &[(&str, &str)])
+    }
+
+    /// GETs `path` with `query` encoded as its query string.
+    pub fn get_query<Q: Serialize + ?Sized, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &Q,
+    ) -> Result<T, String> {
+        let response = self
+            .client
+            .get(format!("{}{}", self.base, path))
+            .query(query)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .map_err(|e| format!("Failed to reach {}: {}", self.base, e))?;
+        let body = check(response)?
+            .text()
+            .map_err(|e| format!("Failed to read response of {}: {}", path, e))?;
+        serde_json::from_str(&body)
+            .map_err(|e| format!("Failed to parse response of {}: {}", path, e))
+    }
+
+    pub fn post<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<T, String> {
+        self.send(Method::POST, path, body)
+    }
+
+    pub fn put<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<T, String> {
+        self.send(Method::PUT, path, body)
+    }
+
+    fn send<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &B,
+    ) -> Result<T, String> {
+        let body = serde_json::to_string(body)
+            .map_err(|e| format!("Failed to serialize request: {}", e))?;
+        let response = self
+            .client
+            .request(method, format!("{}{}", self.base, path))
+            .header("content-type", "application/json")
+            .body(body)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .map_err(|e| format!("Failed to reach {}: {}", self.base, e))?;
+        let body = check(response)?
+            .text()
+            .map_err(|e| format!("Failed to read response of {}: {}", path, e))?;
+        serde_json::from_str(&body)
+            .map_err(|e| format!("Failed to parse response of {}: {}", path, e))
+    }
+
+    /// Follows the server-sent event stream, optionally of a single device,
+    /// calling `f` for every event until the stream ends or `f` returns false.
+    pub fn follow_events<F: FnMut(Event) -> bool>(
+        &self,
+        device_id: Option<&str>,
+        mut f: F,
+    ) -> Result<(), String> {
+        let mut request = self.client.get(format!("{}/api/events/stream", self.base));
+        if let Some(device_id) = device_id {
+            request = request.query(&[("device_id", device_id)]);
+        }
+        let response = request
+            .send()
+            .map_err(|e| format!("Failed to reach {}: {}", self.base, e))?;
+        let reader = BufReader::new(check(response)?);
+        for line in reader.lines() {
+            let line = line.map_err(|e| format!("Event stream failed: {}", e))?;
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            if let Ok(event) = serde_json::from_str(data.trim()) {
+                if !f(event) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check(response: Response) -> Result<Response, String> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.text().unwrap_or_default();
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|value| value["error"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    Err(format!("Request failed with {}: {}", status, message))
+}
@@ -0,0 +1,3 @@
+pub mod console;
+pub mod http;
+pub mod top;
@@ -0,0 +1,268 @@
+use super::http::ApiClient;
+use crate::api::endpoints::HandlerStatus;
+use crate::core::event::Event;
+use crate::core::latency::CommandLatency;
+use crate::core::value::{StateMap, StateValue};
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, Paragraph, Row, Table};
+use ratatui::Frame;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Events kept for the event pane.
+const EVENT_BACKLOG: usize = 500;
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct Snapshot {
+    states: HashMap<String, StateMap>,
+    handlers: Vec<HandlerStatus>,
+    latency: HashMap<String, CommandLatency>,
+    error: Option<String>,
+    updated: Option<Instant>,
+    events: VecDeque<Event>,
+    stream_error: Option<String>,
+}
+
+/**
+ * Top
+ * Terminal monitor for a running daemon: device states, the live event
+ * stream, handler health and command latency, refreshed over the API.
+ */
+pub struct Top {
+    client: ApiClient,
+    interval: Duration,
+    snapshot: Arc<Mutex<Snapshot>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Top {
+    pub fn new(client: ApiClient, interval: Duration) -> Self {
+        Top {
+            client,
+            interval,
+            snapshot: Arc::new(Mutex::new(Snapshot::default())),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Takes over the terminal until the user quits with `q`, `Esc` or `Ctrl+C`.
+    pub fn run(self) -> Result<(), String> {
+        self.spawn_poller();
+        self.spawn_event_stream();
+
+        let mut terminal = ratatui::init();
+        let result = (|| loop {
+            terminal
+                .draw(|frame| self.draw(frame))
+                .map_err(|e| format!("Failed to draw: {}", e))?;
+            if !event::poll(Duration::from_millis(250))
+                .map_err(|e| format!("Failed to read input: {}", e))?
+            {
+                continue;
+            }
+            if let TermEvent::Key(key) =
+                event::read().map_err(|e| format!("Failed to read input: {}", e))?
+            {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press
+                    && (ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc))
+                {
+                    return Ok(());
+                }
+            }
+        })();
+        ratatui::restore();
+        self.stop.store(true, Ordering::Relaxed);
+        result
+    }
+
+    fn spawn_poller(&self) {
+        let client = self.client.clone();
+        let snapshot = self.snapshot.clone();
+        let stop = self.stop.clone();
+        let interval = self.interval;
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let result = (|| {
+                    Ok::<_, String>((
+                        client.get("/api/states")?,
+                        client.get("/api/handlers")?,
+                        client.get("/api/commands/latency")?,
+                    ))
+                })();
+                {
+                    let mut snapshot = snapshot.lock().unwrap();
+                    match result {
+                        Ok((states, handlers, latency)) => {
+                            snapshot.states = states;
+                            snapshot.handlers = handlers;
+                            snapshot.latency = latency;
+                            snapshot.error = None;
+                            snapshot.updated = Some(Instant::now());
+                        }
+                        Err(e) => snapshot.error = Some(e),
+                    }
+                }
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    fn spawn_event_stream(&self) {
+        let client = self.client.clone();
+        let snapshot = self.snapshot.clone();
+        let stop = self.stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let result = client.follow_events(None, |event| {
+                    let mut snapshot = snapshot.lock().unwrap();
+                    snapshot.stream_error = None;
+                    snapshot.events.push_front(event);
+                    snapshot.events.truncate(EVENT_BACKLOG);
+                    !stop.load(Ordering::Relaxed)
+                });
+                if let Err(e) = result {
+                    snapshot.lock().unwrap().stream_error = Some(e);
+                }
+                thread::sleep(RECONNECT_DELAY);
+            }
+        });
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let snapshot = self.snapshot.lock().unwrap();
+        let [top, bottom, status] = Layout::vertical([
+            Constraint::Percentage(50),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [devices, handlers] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Fill(1)]).areas(top);
+        let [events, latency] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Fill(1)]).areas(bottom);
+
+        draw_devices(frame, devices, &snapshot);
+        draw_handlers(frame, handlers, &snapshot);
+        draw_events(frame, events, &snapshot);
+        draw_latency(frame, latency, &snapshot);
+
+        let (text, style) = match (&snapshot.error, &snapshot.stream_error) {
+            (Some(e), _) | (None, Some(e)) => (e.clone(), Style::default().fg(Color::Red)),
+            (None, None) => {
+                let age = snapshot
+                    .updated
+                    .map(|updated| format!("updated {}s ago", updated.elapsed().as_secs()))
+                    .unwrap_or_else(|| "connecting".to_string());
+                (
+                    format!("{} — {} — q to quit", self.client.url(), age),
+                    Style::default().fg(Color::DarkGray),
+                )
+            }
+        };
+        frame.render_widget(Paragraph::new(text).style(style), status);
+    }
+}
+
+fn header(cells: &[&'static str]) -> Row<'static> {
+    Row::new(cells.to_vec()).style(Style::default().add_modifier(Modifier::BOLD))
+}
+
+fn draw_devices(frame: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let mut ids: Vec<&String> = snapshot.states.keys().collect();
+    ids.sort();
+    let rows = ids.into_iter().map(|id| {
+        let mut state: Vec<(&String, &StateValue)> = snapshot.states[id].iter().collect();
+        state.sort_by_key(|(key, _)| *key);
+        let state = state
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(" ");
+        Row::new(vec![id.clone(), state])
+    });
+    let table = Table::new(rows, [Constraint::Percentage(30), Constraint::Fill(1)])
+        .header(header(&["Device", "State"]))
+        .block(Block::bordered().title(" Devices "));
+    frame.render_widget(table, area);
+}
+
+fn draw_handlers(frame: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let rows = snapshot.handlers.iter().map(|handler| {
+        let (health, color) = match &handler.error {
+            None => ("ok".to_string(), Color::Green),
+            Some(e) => (e.clone(), Color::Red),
+        };
+        Row::new(vec![
+            handler.name.clone(),
+            handler.protocols.join(","),
+            health,
+        ])
+        .style(Style::default().fg(color))
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Percentage(35),
+            Constraint::Percentage(30),
+            Constraint::Fill(1),
+        ],
+    )
+    .header(header(&["Handler", "Protocols", "Health"]))
+    .block(Block::bordered().title(" Handlers "));
+    frame.render_widget(table, area);
+}
+
+fn draw_events(frame: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let items = snapshot
+        .events
+        .iter()
+        .take(area.height as usize)
+        .map(|event| {
+            ListItem::new(Line::from(format!(
+                "{} #{} {}",
+                event.timestamp.format("%H:%M:%S"),
+                event.seq,
+                event.kind.summary()
+            )))
+        });
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(" Events ")),
+        area,
+    );
+}
+
+fn draw_latency(frame: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let mut latency: Vec<(&String, &CommandLatency)> = snapshot.latency.iter().collect();
+    latency.sort_by(|a, b| b.1.mean_ms.total_cmp(&a.1.mean_ms));
+    let rows = latency.into_iter().map(|(id, latency)| {
+        Row::new(vec![
+            id.clone(),
+            latency.count.to_string(),
+            format!("{:.1}", latency.last_ms),
+            format!("{:.1}", latency.mean_ms),
+            format!("{:.1}", latency.max_ms),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Fill(1),
+            Constraint::Length(6),
+            Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(8),
+        ],
+    )
+    .header(header(&["Device", "Count", "Last ms", "Mean ms", "Max ms"]))
+    .block(Block::bordered().title(" Command latency "));
+    frame.render_widget(table, area);
+}
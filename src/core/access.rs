@@ -0,0 +1,92 @@
+use super::error::BlinkieError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/**
+ * Access Scopes
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    #[default]
+    Full,
+    /// Viewing states and streaming events only, e.g. for public dashboards
+    /// and wall tablets in guest areas. Commands and changes are rejected.
+    ReadOnly,
+}
+
+/**
+ * ApiToken
+ * A token API clients present as `Authorization: Bearer <token>`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Who the token was handed to, for logs.
+    pub name: String,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+/**
+ * AccessPolicy
+ * Decides the scope of API requests. Requests without a token get full
+ * access unless the hub runs read-only; requests with one get the token's
+ * scope, so full tokens still work on a read-only hub.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct AccessPolicy {
+    pub read_only: bool,
+    /// Known tokens, by token.
+    pub tokens: HashMap<String, ApiToken>,
+}
+
+impl AccessPolicy {
+    /// Loads the tokens kept at `path`, a JSON object of tokens by token.
+    /// A missing file means there are none.
+    pub fn load(path: &Path) -> Result<Self, BlinkieError> {
+        if !path.exists() {
+            return Ok(AccessPolicy::default());
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let tokens = serde_json::from_str(&contents).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+        })?;
+        Ok(AccessPolicy {
+            read_only: false,
+            tokens,
+        })
+    }
+
+    /// Scope of a request presenting `token`. Unknown tokens are refused.
+    pub fn scope(&self, token: Option<&str>) -> Result<Scope, BlinkieError> {
+        match token {
+            Some(token) => self
+                .tokens
+                .get(token)
+                .map(|token| token.scope)
+                .ok_or_else(|| "Unknown API token".into()),
+            None if self.read_only => Ok(Scope::ReadOnly),
+            None => Ok(Scope::Full),
+        }
+    }
+
+    /// Name of the account `token` was handed to, if it is known.
+    pub fn account(&self, token: &str) -> Option<&str> {
+        self.tokens.get(token).map(|token| token.name.as_str())
+    }
+
+    /// Names of all accounts with a token.
+    pub fn accounts(&self) -> Vec<String> {
+        let mut accounts: Vec<String> = self
+            .tokens
+            .values()
+            .map(|token| token.name.clone())
+            .collect();
+        accounts.sort();
+        accounts.dedup();
+        accounts
+    }
+}
@@ -0,0 +1,289 @@
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::maintenance::MaintenanceTask;
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Hourly buckets older than this are dropped; daily buckets are kept.
+const HOURLY_RETENTION_DAYS: i64 = 30;
+
+/**
+ * Resolutions
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Hour,
+    /// Days as seen in the store's timezone.
+    Day,
+}
+
+/**
+ * Bucket
+ * Aggregate of the numeric values a device reported within one period.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bucket {
+    pub start: DateTime<Utc>,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+}
+
+impl Bucket {
+    fn new(start: DateTime<Utc>, value: f64) -> Self {
+        Bucket {
+            start,
+            count: 1,
+            min: value,
+            max: value,
+            sum: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/**
+ * BucketView
+ * A bucket as served to dashboards.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BucketView {
+    pub start: DateTime<Utc>,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl From<&Bucket> for BucketView {
+    fn from(bucket: &Bucket) -> Self {
+        BucketView {
+            start: bucket.start,
+            count: bucket.count,
+            min: bucket.min,
+            max: bucket.max,
+            mean: bucket.mean(),
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Aggregates {
+    /// Sequence number of the last event included.
+    last_seq: u64,
+    /// Buckets by `device_id/key`, resolution and start timestamp.
+    series: HashMap<String, HashMap<Resolution, BTreeMap<i64, Bucket>>>,
+}
+
+/**
+ * AggregateStore
+ * Keeps hourly and daily min/max/mean of numeric device states up to date
+ * in the background, so graphs do not have to scan the raw history.
+ */
+pub struct AggregateStore {
+    path: Option<PathBuf>,
+    timezone: Tz,
+    aggregates: RwLock<Aggregates>,
+}
+
+impl AggregateStore {
+    /// Creates a store that only keeps aggregates in memory.
+    pub fn new(timezone: Tz) -> Self {
+        AggregateStore {
+            path: None,
+            timezone,
+            aggregates: RwLock::new(Aggregates::default()),
+        }
+    }
+
+    /// Opens a persistent store, loading previously saved aggregates if the file exists.
+    pub fn open<P: Into<PathBuf>>(path: P, timezone: Tz) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let aggregates = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            Aggregates::default()
+        };
+        Ok(AggregateStore {
+            path: Some(path),
+            timezone,
+            aggregates: RwLock::new(aggregates),
+        })
+    }
+
+    /// Folds a numeric state change into its buckets. Other events and
+    /// events already included are ignored.
+    pub fn record(&self, event: &Event) {
+        let mut aggregates = self.aggregates.write().unwrap();
+        if event.seq <= aggregates.last_seq {
+            return;
+        }
+        aggregates.last_seq = event.seq;
+        let EventKind::StateChanged {
+            device_id,
+            key,
+            new_value,
+            ..
+        } = &event.kind
+        else {
+            return;
+        };
+        let Ok(value) = new_value.parse::<f64>() else {
+            return;
+        };
+        if !value.is_finite() {
+            return;
+        }
+
+        let series = aggregates
+            .series
+            .entry(format!("{}/{}", device_id, key))
+            .or_default();
+        for resolution in [Resolution::Hour, Resolution::Day] {
+            let start = self.bucket_start(event.timestamp, resolution);
+            series
+                .entry(resolution)
+                .or_default()
+                .entry(start.timestamp())
+                .and_modify(|bucket| bucket.add(value))
+                .or_insert_with(|| Bucket::new(start, value));
+        }
+    }
+
+    /// Buckets of a device state starting within `from..to`, oldest first.
+    pub fn query(
+        &self,
+        device_id: &str,
+        key: &str,
+        resolution: Resolution,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<BucketView> {
+        let aggregates = self.aggregates.read().unwrap();
+        aggregates
+            .series
+            .get(&format!("{}/{}", device_id, key))
+            .and_then(|series| series.get(&resolution))
+            .map(|buckets| {
+                buckets
+                    .range(from.timestamp()..to.timestamp())
+                    .map(|(_, bucket)| bucket.into())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Catches up on journaled events and then follows the bus until it closes.
+    pub async fn run(self: Arc<Self>, events: Arc<EventBus>) {
+        let last_seq = self.aggregates.read().unwrap().last_seq;
+        let mut receiver = match events.resume(last_seq) {
+            Ok((missed, receiver)) => {
+                for event in &missed {
+                    self.record(event);
+                }
+                receiver
+            }
+            Err(_) => events.subscribe(),
+        };
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Drops expired hourly buckets and writes the aggregates to disk.
+    pub fn flush(&self, now: DateTime<Utc>) -> Result<(), BlinkieError> {
+        let cutoff = (now - Duration::days(HOURLY_RETENTION_DAYS)).timestamp();
+        let mut aggregates = self.aggregates.write().unwrap();
+        for series in aggregates.series.values_mut() {
+            if let Some(hourly) = series.get_mut(&Resolution::Hour) {
+                *hourly = hourly.split_off(&cutoff);
+            }
+        }
+        self.write(&aggregates)
+    }
+
+    /// Drops every series of a device and writes the aggregates to disk.
+    pub fn forget(&self, device_id: &str) -> Result<(), BlinkieError> {
+        let prefix = format!("{}/", device_id);
+        let mut aggregates = self.aggregates.write().unwrap();
+        aggregates
+            .series
+            .retain(|series, _| !series.starts_with(&prefix));
+        self.write(&aggregates)
+    }
+
+    fn write(&self, aggregates: &Aggregates) -> Result<(), BlinkieError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string(aggregates).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize aggregates: {}", e))
+        })?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+    }
+
+    fn bucket_start(&self, timestamp: DateTime<Utc>, resolution: Resolution) -> DateTime<Utc> {
+        match resolution {
+            Resolution::Hour => timestamp
+                .with_minute(0)
+                .and_then(|t| t.with_second(0))
+                .and_then(|t| t.with_nanosecond(0))
+                .unwrap_or(timestamp),
+            Resolution::Day => {
+                let date = timestamp.with_timezone(&self.timezone).date_naive();
+                self.timezone
+                    .from_local_datetime(&date.and_time(NaiveTime::MIN))
+                    .earliest()
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or(timestamp)
+            }
+        }
+    }
+}
+
+/**
+ * AggregateFlushTask
+ * Periodically persists and prunes the aggregates.
+ */
+pub struct AggregateFlushTask {
+    pub store: Arc<AggregateStore>,
+}
+
+impl MaintenanceTask for AggregateFlushTask {
+    fn name(&self) -> &str {
+        "aggregate_flush"
+    }
+
+    fn run(&self) -> Result<(), BlinkieError> {
+        self.store.flush(Utc::now())
+    }
+}
@@ -1,15 +1,1795 @@
-use super::device::{Config, Device, ProtocolRegistry};
-use std::sync::{Arc, RwLock};
+use super::access::AccessPolicy;
+use super::aggregate::{AggregateFlushTask, AggregateStore};
+use super::archive::HistoryArchiveTask;
+use super::async_device::AsyncRegistry;
+use super::backup::{BackupManager, BackupTask};
+use super::chaos::{self, Chaos, Disturbance};
+use super::command_queue::CommandQueue;
+use super::config::{invalid, line_diff, AppConfig, ConfigChanges, ConfigPreview, Settings};
+use super::dashboard::DashboardStore;
+use super::degraded::{StorageHealth, DEVICE_STORE};
+use super::device::{
+    Capability, Command, CommandResponse, CommandSender, Config, DeviceList, HandlerRef,
+    ProtocolRegistry,
+};
+use super::discovery::{discover_all, DiscoveryConfig};
+use super::doorbell::Doorbells;
+use super::drift::{DriftCheck, Fingerprint};
+use super::effects::{EffectDefinition, EffectStatus, Effects};
+use super::emulation::Emulator;
+use super::energy::Energy;
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::export::{ExportConfig, HistoryExportTask};
+use super::federation::{FederationHub, Uplink};
+use super::freshness::StateClock;
+use super::group::{GroupStore, MemberResult};
+use super::guest::GuestCodes;
+use super::health::{self, HealthMonitor};
+use super::history::StateHistory;
+use super::holiday::{self, SharedWorkdays, WorkdaySensor, Workdays};
+use super::icon::IconStore;
+use super::job::JobManager;
+use super::journal::EventJournal;
+use super::latency::LatencyTracker;
+use super::lease::LeaseManager;
+use super::lock::{LockAudit, LockOrigin, LockState};
+use super::maintenance::{
+    JobSettings, LogRotationTask, MaintenanceScheduler, MaintenanceWindow, RetentionTask,
+};
+use super::maintenance_mode::MaintenanceMode;
+use super::memory::{MemoryLimits, MemoryStatus, StateCache};
+use super::notification::{EventChannel, Notifier};
+use super::occupancy::Occupancy;
+use super::pacing::FramePacer;
+use super::prediction::Predictions;
+use super::price::Prices;
+use super::pump::Pumps;
+use super::push::ChannelConfig;
+use super::query::HistoryQuery;
+use super::retention::PurgeTarget;
+use super::scene::{DeviceSelector, Scene, SceneStore};
+use super::scheduler::Scheduler;
+use super::sniffer::Sniffer;
+use super::snippet::DeviceSnippet;
+use super::storage::{DeviceStore, SqliteDeviceStore, DEFAULT_FLUSH_SECS};
+use super::tts::Announcer;
+use super::ui::UiLayout;
+use super::usage::{self, UsageFlushTask, UsageTracker};
+use super::user::UserStore;
+use super::value::StateMap;
+use super::webhook::WebhookStore;
+use crate::automation::engine::RuleEngine;
+use crate::automation::reload::RuleReloader;
+use crate::automation::stats::RuleStatsStore;
+use crate::automation::variables::VariableStore;
+use crate::handlers::exec::ExecHandler;
+use crate::handlers::http::HttpHandler;
+use crate::handlers::plugin::{self, PluginStatus};
+use crate::handlers::simulation::SimulationHandler;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+/// Name of the event published when the hub enters a state, with the
+/// state as `state`.
+pub const STATE_EVENT: &str = "app_state";
+/// Name of the event published for a device found by discovery on start.
+pub const DISCOVERED_EVENT: &str = "device_discovered";
+
+/**
+ * AppState
+ * Where the hub is in its lifecycle: `STARTING` until `start`, `OK` while
+ * running, `STOPPING` and `STOPPED` during and after `shutdown`.
+ */
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AppState {
     STARTING,
     OK,
     ERROR,
     UNKNOWN,
+    STOPPING,
+    STOPPED,
+}
+
+impl AppState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppState::STARTING => "starting",
+            AppState::OK => "ok",
+            AppState::ERROR => "error",
+            AppState::UNKNOWN => "unknown",
+            AppState::STOPPING => "stopping",
+            AppState::STOPPED => "stopped",
+        }
+    }
+}
+
+/// Called with every state the hub enters, e.g. to start and stop a
+/// subsystem with the hub.
+pub type LifecycleHook = Box<dyn Fn(AppState) + Send + Sync>;
+
+/**
+ * CommandResult
+ * A sent command: the event it was published as and what the device
+ * answered.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandResult {
+    #[serde(flatten)]
+    pub event: Event,
+    #[serde(default)]
+    pub response: CommandResponse,
+    /// Id of the job the command started, for long-running commands.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<u64>,
 }
 
 pub struct App {
-    pub devices: Arc<RwLock<Vec<Box<dyn Device>>>>,
+    pub devices: DeviceList,
+    /// Config file the hub was set up from, if any, edited through the API.
+    pub config_path: Option<PathBuf>,
+    /// Held while the config file is rewritten.
+    config_edits: Mutex<()>,
+    /// Devices registered at runtime and the last known state of every device.
+    pub device_store: Arc<dyn DeviceStore>,
+    /// Time between writes of changed device states to `device_store`.
+    pub flush_interval: Duration,
+    /// Stores failing at runtime, while the hub keeps running in memory.
+    pub storage: Arc<StorageHealth>,
+    /// Devices registered while `device_store` failed, not written yet.
+    unsaved: Mutex<Vec<Config>>,
+    /// States as last written to `device_store`.
+    flushed: Mutex<StateCache>,
     pub protocol_registry: Arc<RwLock<ProtocolRegistry>>,
-    pub state: AppState,
+    /// Async handlers and the devices they created, next to the
+    /// synchronous ones in `devices`.
+    pub async_registry: Arc<AsyncRegistry>,
+    pub events: Arc<EventBus>,
+    pub rules: Arc<RuleEngine>,
+    /// Reloads rules when `rules.json` or a blueprint changes.
+    pub reloader: Arc<RuleReloader>,
+    pub history: Arc<HistoryQuery>,
+    /// Recent state changes of every device, to graph and to ask about.
+    pub state_history: Arc<StateHistory>,
+    pub aggregates: Arc<AggregateStore>,
+    pub usage: Arc<UsageTracker>,
+    pub ui: UiLayout,
+    pub dashboards: DashboardStore,
+    /// Devices commanded together.
+    pub groups: GroupStore,
+    /// Captured device states that can be applied again.
+    pub scenes: SceneStore,
+    /// People living in the home with their preferences, and who is home.
+    pub users: Arc<UserStore>,
+    pub icons: IconStore,
+    pub latency: LatencyTracker,
+    /// Write latency of frame outputs, to show frames on time.
+    pub pacing: FramePacer,
+    /// Light effects running on devices.
+    pub effects: Arc<Effects>,
+    /// Age of state values and clock offsets of devices reporting timestamps.
+    pub clock: Arc<StateClock>,
+    pub sniffer: Arc<Sniffer>,
+    /// Handler plugins found in the plugins directory, loaded or not.
+    pub plugins: Vec<PluginStatus>,
+    pub emulator: Emulator,
+    pub jobs: Arc<JobManager>,
+    pub leases: Arc<LeaseManager>,
+    pub maintenance: Arc<MaintenanceMode>,
+    /// Housekeeping jobs run in their maintenance windows.
+    pub maintenance_jobs: Arc<MaintenanceScheduler>,
+    /// Moves old events to remote storage, if set up.
+    archiver: Option<Arc<HistoryArchiveTask>>,
+    /// Exports state changes to CSV or Parquet files, if set up.
+    exporter: Option<Arc<HistoryExportTask>>,
+    /// Backs the hub up, if set up.
+    backups: Option<Arc<BackupTask>>,
+    /// Routing of notifications to channels, honouring quiet hours and digests.
+    pub notifications: Arc<Notifier>,
+    /// Webhooks turning calls from outside services into events.
+    pub webhooks: Arc<WebhookStore>,
+    /// Text-to-speech announcements on speakers and media players.
+    pub announcer: Arc<Announcer>,
+    /// Doorbells notifying with camera snapshots and intercom actions.
+    pub doorbells: Arc<Doorbells>,
+    /// Per-area occupancy inferred from motion, contacts, power and presence.
+    pub occupancy: Arc<Occupancy>,
+    /// Models predicting from feature history, with their outputs as sensors.
+    pub predictions: Arc<Predictions>,
+    /// Flexible loads run on PV surplus, with override switches.
+    pub energy: Arc<Energy>,
+    /// Electricity prices, as a sensor, and runs shifted to cheap hours.
+    pub prices: Arc<Prices>,
+    /// Actions run on devices at cron, interval, time-of-day and sun
+    /// timings, while the app is started.
+    pub scheduler: Arc<Scheduler>,
+    /// Time-limited guest codes set on locks and alarm panels.
+    pub guests: Arc<GuestCodes>,
+    /// Audit of every lock operation, commanded or reported by the locks.
+    pub locks: Arc<LockAudit>,
+    /// Pumps stopped when their flow sensor doesn't confirm flow.
+    pub pumps: Arc<Pumps>,
+    /// Chaos mode, disturbing commands to test automations against.
+    pub chaos: Arc<Chaos>,
+    /// Sends commands to each device in order, retrying transient failures.
+    pub queue: Arc<CommandQueue>,
+    /// Devices that no longer match what they said about themselves before.
+    pub drift: Arc<DriftCheck>,
+    /// Whether devices answer, from health checks and commands.
+    pub health: Arc<HealthMonitor>,
+    /// Scopes of API tokens and whether the API is read-only without one.
+    pub access: AccessPolicy,
+    /// States of satellites syncing to this hub.
+    pub federation: Arc<FederationHub>,
+    /// Link to the hub, when this hub runs as a satellite.
+    pub uplink: Option<Arc<Uplink>>,
+    /// Embedded MQTT broker handlers and bridges can attach to in-process.
+    #[cfg(feature = "mqtt-broker")]
+    pub broker: Option<Arc<super::broker::EmbeddedBroker>>,
+    state: RwLock<AppState>,
+    hooks: Mutex<Vec<LifecycleHook>>,
+    /// Held while the hub starts or shuts down.
+    lifecycle: Mutex<()>,
+    discovery: RwLock<DiscoveryConfig>,
+    /// Devices found by discovery on start that aren't set up yet.
+    pub discovered: RwLock<Vec<Config>>,
+}
+
+impl App {
+    /// Sets up the hub with its journal, rules, variables, statistics,
+    /// aggregates, usage, UI layout, dashboards, groups, scenes, users,
+    /// pictures, maintenance flags, notification policy and channels,
+    /// doorbells, occupancy areas, prediction models, energy setup,
+    /// electricity prices, schedules, guest codes, webhooks, speech outputs,
+    /// API tokens and stored devices with their last known states kept in
+    /// `data_dir`.
+    pub fn open<P: AsRef<Path>>(data_dir: P) -> Result<Self, BlinkieError> {
+        let app = Self::open_with(data_dir.as_ref(), None, &Settings::default())?;
+        app.restore_states()?;
+        Ok(app)
+    }
+
+    /// Sets up the hub like `open`, with its data directory, built-in
+    /// handlers, devices and settings from the TOML or YAML config at
+    /// `path`. Fails listing every problem with the config, e.g. unknown
+    /// protocols, duplicate device ids or missing connection details,
+    /// before any device is created.
+    pub fn from_config<P: AsRef<Path>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.as_ref();
+        let config = AppConfig::load(path)?;
+        let data_dir = config.data_dir.clone().unwrap_or_default();
+        let mut app = Self::open_with(&data_dir, config.handlers.as_deref(), &config.settings)?;
+        {
+            let registry = app.protocol_registry.read().unwrap();
+            let problems = config.validate(&registry);
+            if !problems.is_empty() {
+                return Err(invalid(path, &problems).into());
+            }
+            let mut devices = app.devices.write().unwrap();
+            for device in &config.devices {
+                let handler = registry.handler_for(device)?;
+                // The config file wins over a device stored under the same id
+                devices.retain(|stored| stored.get_id() != device.id);
+                devices.push(registry.create_device(&handler, device)?);
+            }
+        }
+        app.config_path = Some(path.to_path_buf());
+        if let Some(backup) = &config.settings.backup {
+            let manager = BackupManager::new(
+                vec![data_dir.clone(), path.to_path_buf()],
+                backup.to.build(),
+                backup.keep,
+            );
+            let nightly = MaintenanceWindow {
+                start: NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(5, 0, 0).unwrap(),
+            };
+            let backups = Arc::new(BackupTask { manager });
+            app.maintenance_jobs.add_job(
+                backups.clone(),
+                JobSettings::every(Duration::from_secs(24 * 60 * 60)).within(nightly),
+            );
+            app.backups = Some(backups);
+        }
+        app.access.read_only = config.settings.read_only;
+        if let Some(secs) = config.settings.flush_interval_secs {
+            app.flush_interval = Duration::from_secs(secs.max(1));
+        }
+        app.apply_settings(&config.settings)?;
+        app.restore_states()?;
+        Ok(app)
+    }
+
+    /// Applies the settings that can change while the hub runs.
+    fn apply_settings(&self, settings: &Settings) -> Result<(), BlinkieError> {
+        self.rules.set_stale_policy(settings.stale);
+        let memory = &settings.memory;
+        self.flushed.lock().unwrap().set_limit(memory.state_cache);
+        self.events
+            .limit_backlog(memory.journal_backlog, memory.backlog_eviction);
+        self.queue
+            .limit(memory.queue_per_device, memory.dead_letters);
+        self.health.set_config(settings.health.clone())?;
+        self.events.set_hot_keys(settings.hot_keys.clone());
+        self.pacing.set_config(settings.frame_pacing.clone())?;
+        *self.discovery.write().unwrap() = settings.discovery.clone();
+        self.maintenance_jobs.apply(&settings.maintenance.jobs);
+        self.notifications.set_locale(settings.locale.clone());
+        self.announcer.set_locale(settings.locale.clone());
+        Ok(())
+    }
+
+    fn config_file(&self) -> Result<&Path, BlinkieError> {
+        self.config_path
+            .as_deref()
+            .ok_or_else(|| "The hub was not set up from a config file".into())
+    }
+
+    /// The config file the hub was set up from, as written.
+    pub fn config(&self) -> Result<AppConfig, BlinkieError> {
+        AppConfig::read(self.config_file()?)
+    }
+
+    /// What writing `config` to the config file would change, and what
+    /// keeps it from being applied.
+    pub fn preview_config(&self, config: &AppConfig) -> Result<ConfigPreview, BlinkieError> {
+        let path = self.config_file()?;
+        let current = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Ok(ConfigPreview {
+            problems: config.validate(&self.protocol_registry.read().unwrap()),
+            changes: ConfigChanges::between(&AppConfig::read(path)?, config),
+            diff: line_diff(&current, &config.render(path)?),
+        })
+    }
+
+    /// Writes `config` to the config file and applies it: devices added,
+    /// changed or removed in it are created, recreated or dropped, and the
+    /// settings that can change while running take effect. Nothing changes
+    /// if the config has problems or one of its devices can't be created.
+    pub fn apply_config(&self, config: AppConfig) -> Result<ConfigChanges, BlinkieError> {
+        let _edit = self.config_edits.lock().unwrap();
+        let path = self.config_file()?;
+        let changes = ConfigChanges::between(&AppConfig::read(path)?, &config);
+        let created = {
+            let registry = self.protocol_registry.read().unwrap();
+            let problems = config.validate(&registry);
+            if !problems.is_empty() {
+                return Err(invalid(path, &problems).into());
+            }
+            config
+                .devices
+                .iter()
+                .filter(|device| {
+                    changes.added.contains(&device.id) || changes.updated.contains(&device.id)
+                })
+                .map(|device| {
+                    let handler = registry.handler_for(device)?;
+                    registry.create_device(&handler, device)
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        let contents = config.render(path)?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        {
+            let mut devices = self.devices.write().unwrap();
+            devices.retain(|device| {
+                let id = device.get_id();
+                !changes.removed.iter().any(|removed| removed == id)
+                    && !created.iter().any(|created| created.get_id() == id)
+            });
+            devices.extend(created);
+        }
+        for device_id in &changes.removed {
+            self.flushed.lock().unwrap().remove(device_id);
+            self.health.forget(device_id);
+            self.pacing.forget(device_id);
+        }
+        self.apply_settings(&config.settings)?;
+        Ok(changes)
+    }
+
+    /// Names of the built-in handlers of this build.
+    pub fn builtin_handlers() -> Vec<&'static str> {
+        #[allow(unused_mut)]
+        let mut names = vec!["exec", "http", "simulation"];
+        #[cfg(feature = "ble")]
+        names.push("ble");
+        #[cfg(feature = "dbus")]
+        names.push("desktop");
+        #[cfg(feature = "mqtt")]
+        names.push("mqtt");
+        #[cfg(feature = "mqtt")]
+        names.push("zigbee2mqtt");
+        #[cfg(feature = "ocpp")]
+        names.push("ocpp");
+        #[cfg(feature = "serial")]
+        names.push("serial");
+        names
+    }
+
+    /// Sets up the hub in `data_dir` with the built-in handlers named in
+    /// `handlers`, or all of them, and the settings only read on start.
+    fn open_with(
+        data_dir: &Path,
+        handlers: Option<&[String]>,
+        settings: &Settings,
+    ) -> Result<Self, BlinkieError> {
+        let builtin = Self::builtin_handlers();
+        if let Some(unknown) = handlers
+            .into_iter()
+            .flatten()
+            .find(|name| !builtin.contains(&name.as_str()))
+        {
+            return Err(BlinkieError::handler_not_found(format!(
+                "Unknown handler '{}', available handlers: {}",
+                unknown,
+                builtin.join(", ")
+            )));
+        }
+        let enabled = |name: &str| handlers.is_none_or(|names| names.iter().any(|n| n == name));
+        fs::create_dir_all(data_dir)
+            .map_err(|e| format!("Failed to create {}: {}", data_dir.display(), e))?;
+
+        let devices: DeviceList = Arc::new(RwLock::new(Vec::new()));
+        let events = Arc::new(EventBus::with_journal(EventJournal::open(
+            data_dir.join("events.jsonl"),
+        )?));
+        let usage = Arc::new(UsageTracker::open(
+            data_dir.join("usage.json"),
+            settings.timezone(),
+        )?);
+        let protocol_registry = ProtocolRegistry::new();
+        let async_registry = Arc::new(AsyncRegistry::new());
+        let leases = Arc::new(LeaseManager::new());
+        let clock = Arc::new(StateClock::new());
+        let state_history = Arc::new(StateHistory::default());
+        let maintenance = Arc::new(MaintenanceMode::open(
+            data_dir.join("maintenance-mode.json"),
+            protocol_registry.devices.clone(),
+        )?);
+        let users = Arc::new(UserStore::open(
+            data_dir.join("users.json"),
+            devices.clone(),
+            events.clone(),
+        )?);
+        let locks = Arc::new(LockAudit::open(
+            data_dir.join("lock-audit.jsonl"),
+            devices.clone(),
+            events.clone(),
+        )?);
+        let pumps = Arc::new(Pumps::open(data_dir.join("pumps.json"), events.clone())?);
+        let chaos = Arc::new(Chaos::open(
+            data_dir.join("chaos.json"),
+            protocol_registry.devices.clone(),
+        )?);
+        let queue = Arc::new(CommandQueue::open(
+            data_dir.join("command-queue.json"),
+            events.clone(),
+        )?);
+        let drift = Arc::new(DriftCheck::open(
+            data_dir.join("device-expectations.json"),
+            events.clone(),
+        )?);
+        let health = Arc::new(HealthMonitor::new(events.clone()));
+        let rules = Arc::new(
+            RuleEngine::new(
+                devices.clone(),
+                events.clone(),
+                Arc::new(VariableStore::open(data_dir.join("variables.json"))?),
+                Arc::new(RuleStatsStore::open(data_dir.join("rule-stats.json"))?),
+            )
+            .with_usage(usage.clone())
+            .with_history(state_history.clone())
+            .with_async_devices(async_registry.clone())
+            .with_leases(leases.clone())
+            .with_maintenance(maintenance.clone())
+            .with_clock(clock.clone())
+            .with_users(users.clone()),
+        );
+        let reloader = Arc::new(RuleReloader::new(data_dir, rules.clone(), events.clone()));
+        reloader.load()?;
+        let history = Arc::new(HistoryQuery::new(events.clone())?);
+        let aggregates = Arc::new(AggregateStore::open(
+            data_dir.join("aggregates.json"),
+            settings.timezone(),
+        )?);
+
+        // Built-in handlers
+        let sniffer = Arc::new(Sniffer::default());
+        if enabled("exec") {
+            protocol_registry.register(Arc::new(RwLock::new(
+                ExecHandler::new().with_sniffer(sniffer.clone()),
+            )))?;
+        }
+        if enabled("http") {
+            protocol_registry.register(Arc::new(RwLock::new(
+                HttpHandler::new().with_sniffer(sniffer.clone()),
+            )))?;
+        }
+        if enabled("simulation") {
+            protocol_registry.register(Arc::new(RwLock::new(SimulationHandler::new())))?;
+        }
+        #[cfg(feature = "dbus")]
+        if enabled("desktop") {
+            protocol_registry.register(Arc::new(RwLock::new(
+                crate::handlers::desktop::DesktopHandler::new().with_events(events.clone()),
+            )))?;
+        }
+        #[cfg(feature = "mqtt")]
+        if enabled("mqtt") {
+            let mut mqtt = crate::handlers::mqtt::MqttBrokers::new()
+                .with_events(events.clone())
+                .with_sniffer(sniffer.clone());
+            super::device::ProtocolHandler::initialize(&mut mqtt)?;
+            protocol_registry.register(Arc::new(RwLock::new(mqtt)))?;
+        }
+        #[cfg(feature = "mqtt")]
+        if enabled("zigbee2mqtt") {
+            let mut zigbee = crate::handlers::zigbee2mqtt::Zigbee2MqttHandler::new()
+                .with_events(events.clone())
+                .with_sniffer(sniffer.clone());
+            super::device::ProtocolHandler::initialize(&mut zigbee)?;
+            protocol_registry.register(Arc::new(RwLock::new(zigbee)))?;
+        }
+        #[cfg(feature = "ocpp")]
+        if enabled("ocpp") {
+            let mut ocpp = crate::handlers::ocpp::OcppHandler::new()
+                .with_events(events.clone())
+                .with_sniffer(sniffer.clone());
+            super::device::ProtocolHandler::initialize(&mut ocpp)?;
+            protocol_registry.register(Arc::new(RwLock::new(ocpp)))?;
+        }
+        #[cfg(feature = "ble")]
+        if enabled("ble") {
+            let mut ble = crate::handlers::ble::BleHandler::new()
+                .with_events(events.clone())
+                .with_sniffer(sniffer.clone());
+            super::device::ProtocolHandler::initialize(&mut ble)?;
+            protocol_registry.register(Arc::new(RwLock::new(ble)))?;
+        }
+        #[cfg(feature = "serial")]
+        if enabled("serial") {
+            let mut serial = crate::handlers::serial::SerialHandler::new()
+                .with_events(events.clone())
+                .with_sniffer(sniffer.clone());
+            super::device::ProtocolHandler::initialize(&mut serial)?;
+            protocol_registry.register(Arc::new(RwLock::new(serial)))?;
+        }
+        let plugins = plugin::load_plugins(
+            data_dir,
+            &protocol_registry,
+            &builtin,
+            events.clone(),
+            sniffer.clone(),
+        )?;
+
+        // Devices registered at runtime, skipping those whose handler is
+        // missing; they stay stored for when it is back
+        let device_store: Arc<dyn DeviceStore> =
+            Arc::new(SqliteDeviceStore::open(data_dir.join("devices.db"))?);
+        for config in device_store.devices()? {
+            match protocol_registry
+                .handler_for(&config)
+                .and_then(|handler| protocol_registry.create_device(&handler, &config))
+            {
+                Ok(device) => devices.write().unwrap().push(device),
+                Err(e) => log::warn!("Failed to restore device '{}': {}", config.id, e),
+            }
+        }
+
+        let emulator = Emulator::new(devices.clone());
+        let jobs = JobManager::new(events.clone());
+        let federation = Arc::new(FederationHub::new(events.clone()));
+        let webhooks = Arc::new(WebhookStore::open(
+            data_dir.join("webhooks.json"),
+            events.clone(),
+        )?);
+        let notifications = Arc::new(
+            Notifier::open(data_dir.join("notifications.json"))?
+                .with_webhooks(webhooks.clone())
+                .with_users(users.clone()),
+        );
+        notifications.register_channel(Arc::new(EventChannel::new(events.clone())));
+        for (name, channel) in ChannelConfig::load(&data_dir.join("notification-channels.json"))? {
+            notifications.register_channel(channel.build(&name));
+        }
+        let doorbells = Arc::new(Doorbells::open(
+            data_dir.join("doorbells.json"),
+            notifications.clone(),
+            events.clone(),
+        )?);
+        let occupancy = Arc::new(Occupancy::open(
+            data_dir.join("occupancy.json"),
+            devices.clone(),
+            events.clone(),
+        )?);
+        let predictions = Arc::new(Predictions::open(
+            data_dir.join("predictions.json"),
+            devices.clone(),
+            events.clone(),
+        )?);
+        let energy = Arc::new(Energy::open(
+            data_dir.join("energy.json"),
+            devices.clone(),
+            events.clone(),
+        )?);
+        let workdays: SharedWorkdays = Arc::new(RwLock::new(Workdays::default()));
+        devices.write().unwrap().push(Box::new(WorkdaySensor::new(
+            holiday::SENSOR_ID,
+            "Workday",
+            settings.timezone(),
+            workdays.clone(),
+        )));
+        let prices = Arc::new(Prices::open(
+            data_dir.join("prices.json"),
+            settings.timezone(),
+            workdays.clone(),
+            devices.clone(),
+            events.clone(),
+        )?);
+        let scheduler = Arc::new(Scheduler::open(
+            data_dir.join("schedules.json"),
+            settings.timezone(),
+            workdays,
+            devices.clone(),
+            async_registry.clone(),
+            leases.clone(),
+        )?);
+        let guests = Arc::new(GuestCodes::open(
+            data_dir.join("guest-access.json"),
+            data_dir.join("guest-codes.json"),
+            events.clone(),
+        )?);
+        let maintenance_jobs = Arc::new(MaintenanceScheduler::new(settings.timezone()));
+        maintenance_jobs.add_job(
+            Arc::new(AggregateFlushTask {
+                store: aggregates.clone(),
+            }),
+            JobSettings::every(Duration::from_secs(5 * 60)),
+        );
+        maintenance_jobs.add_job(
+            Arc::new(UsageFlushTask {
+                tracker: usage.clone(),
+            }),
+            JobSettings::every(Duration::from_secs(5 * 60)),
+        );
+        if let Some(policy) = &settings.retention {
+            maintenance_jobs.add_job(
+                Arc::new(RetentionTask {
+                    events: events.clone(),
+                    policy: policy.clone(),
+                }),
+                JobSettings::every(Duration::from_secs(24 * 60 * 60)),
+            );
+        }
+        let archiver = settings.archive.as_ref().map(|archive| {
+            Arc::new(HistoryArchiveTask {
+                events: events.clone(),
+                storage: archive.to.build(),
+                spool: data_dir.join("archive-spool"),
+                older_than_days: archive.older_than_days,
+            })
+        });
+        if let Some(archiver) = &archiver {
+            maintenance_jobs.add_job(
+                archiver.clone(),
+                JobSettings::every(Duration::from_secs(24 * 60 * 60)),
+            );
+        }
+        let exporter = settings.export.as_ref().map(|export| {
+            let config = ExportConfig {
+                dir: data_dir.join(&export.dir),
+                ..export.clone()
+            };
+            Arc::new(HistoryExportTask::new(
+                events.clone(),
+                config,
+                devices.clone(),
+            ))
+        });
+        if let Some(exporter) = &exporter {
+            maintenance_jobs.add_job(
+                exporter.clone(),
+                JobSettings::every(Duration::from_secs(60 * 60)),
+            );
+        }
+        if let Some(log) = &settings.maintenance.log {
+            maintenance_jobs.add_job(
+                Arc::new(LogRotationTask {
+                    path: log.path.clone(),
+                    max_bytes: log.max_bytes,
+                    keep: log.keep,
+                }),
+                JobSettings::every(Duration::from_secs(60 * 60)),
+            );
+        }
+        let app = App {
+            devices,
+            device_store,
+            config_path: None,
+            config_edits: Mutex::new(()),
+            flush_interval: Duration::from_secs(DEFAULT_FLUSH_SECS),
+            storage: Arc::new(StorageHealth::new(events.clone())),
+            unsaved: Mutex::new(Vec::new()),
+            flushed: Mutex::new(StateCache::new(MemoryLimits::default().state_cache)),
+            protocol_registry: Arc::new(RwLock::new(protocol_registry)),
+            async_registry,
+            events,
+            rules,
+            reloader,
+            history,
+            state_history,
+            aggregates,
+            usage,
+            ui: UiLayout::load(&data_dir.join("ui.json"))?,
+            dashboards: DashboardStore::open(data_dir.join("dashboards.json"))?,
+            groups: GroupStore::open(data_dir.join("groups.json"))?,
+            scenes: SceneStore::open(data_dir.join("scenes.json"))?,
+            users,
+            icons: IconStore::open(data_dir.join("pictures"))?,
+            latency: LatencyTracker::new(),
+            pacing: FramePacer::new(),
+            effects: Arc::new(Effects::new()),
+            clock,
+            sniffer,
+            plugins,
+            emulator,
+            jobs,
+            leases,
+            maintenance,
+            maintenance_jobs,
+            archiver,
+            exporter,
+            backups: None,
+            notifications,
+            doorbells,
+            occupancy,
+            predictions,
+            energy,
+            prices,
+            scheduler,
+            guests,
+            locks,
+            pumps,
+            chaos,
+            queue,
+            drift,
+            health,
+            webhooks,
+            announcer: Arc::new(Announcer::open(data_dir.join("tts.json"))?),
+            access: AccessPolicy::load(&data_dir.join("api-tokens.json"))?,
+            federation,
+            uplink: None,
+            #[cfg(feature = "mqtt-broker")]
+            broker: None,
+            state: RwLock::new(AppState::STARTING),
+            hooks: Mutex::new(Vec::new()),
+            lifecycle: Mutex::new(()),
+            discovery: RwLock::new(DiscoveryConfig::default()),
+            discovered: RwLock::new(Vec::new()),
+        };
+        let scheduler = app.scheduler.clone();
+        app.on_lifecycle(Box::new(move |state| match state {
+            AppState::OK => scheduler.start(),
+            AppState::STOPPING => scheduler.stop(),
+            _ => {}
+        }));
+        Ok(app)
+    }
+
+    /// Sends a command to a device and publishes it on the bus. Fails while
+    /// the device is claimed, unless `lease` is the claim's token.
+    pub fn send_command(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        self.send_command_as(None, device_id, command, parameters, lease)
+    }
+
+    /// Sends a command like `send_command` on behalf of API account
+    /// `actor`, who lock commands are audited as.
+    pub fn send_command_as(
+        &self,
+        actor: Option<&str>,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        self.pumps.authorize(device_id, command)?;
+        self.locks
+            .authorize(device_id, command, LockOrigin::Command, actor)?;
+        let result = self
+            .queue
+            .send_blocking(device_id, command, parameters.as_ref(), || {
+                self.dispatch(device_id, command, parameters.clone(), lease)
+            });
+        if let Err(e) = &result {
+            self.locks
+                .failed(device_id, command, LockOrigin::Command, actor, e);
+        }
+        if self.maintenance.device(device_id).is_none() {
+            self.health.observe(device_id, &result);
+        }
+        result
+    }
+
+    fn dispatch(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        self.leases.check(device_id, lease)?;
+        let typed = Command::parse(command, parameters.clone())?;
+        let (response, job) = {
+            let mut devices = self.devices.write().unwrap();
+            let device = devices
+                .iter_mut()
+                .find(|device| device.get_id() == device_id)
+                .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+            let started = Instant::now();
+            // Delays hold the device list like a slow device does
+            let disturbance = self.chaos.disturb(device_id);
+            match disturbance {
+                Some(Disturbance::Fail) => return Err(chaos::failure(device_id)),
+                Some(Disturbance::Delay(delay)) => std::thread::sleep(delay),
+                _ => {}
+            }
+            self.emulator.cancel(device_id);
+            let job = self.jobs.handle(device_id, command);
+            let result = if disturbance == Some(Disturbance::Drop)
+                || self
+                    .emulator
+                    .handle(device.as_mut(), command, parameters.as_ref())?
+            {
+                (CommandResponse::default(), None)
+            } else if device.start_job(command, parameters.clone(), job.clone())? {
+                self.jobs.start(&job);
+                (CommandResponse::default(), Some(job.id()))
+            } else {
+                (device.send(&typed)?, None)
+            };
+            self.latency.record(device_id, started.elapsed());
+            result
+        };
+        let event = self.events.publish(EventKind::CommandSent {
+            device_id: device_id.to_string(),
+            command: command.to_string(),
+            parameters,
+        })?;
+        Ok(CommandResult {
+            event,
+            response,
+            job,
+        })
+    }
+
+    /// Starts the hub: reports handlers that can't reach their devices yet,
+    /// looks for new devices if `discovery.on_start` is set and enters
+    /// `OK`, starting what registered for it, e.g. the scheduler. Handlers
+    /// are initialized as they are registered. Must be called on the
+    /// runtime.
+    pub fn start(self: &Arc<Self>) {
+        let _lifecycle = self.lifecycle.lock().unwrap();
+        if self.state() != AppState::STARTING {
+            return;
+        }
+        // Rules send commands like the API does, with the rule as the actor
+        let app = Arc::downgrade(self);
+        self.rules
+            .set_sender(Arc::new(move |device_id, command, parameters, rule_id| {
+                let app = app.clone();
+                Box::pin(async move {
+                    let app = app.upgrade().ok_or("The hub has shut down")?;
+                    app.send_command_async_from(
+                        LockOrigin::Rule,
+                        Some(&rule_id),
+                        &device_id,
+                        &command,
+                        parameters,
+                        None,
+                    )
+                    .await
+                    .map(|result| result.response)
+                })
+            }));
+        for handler in self.protocol_registry.read().unwrap().all_handlers() {
+            let handler = handler.read().unwrap();
+            if let Err(e) = handler.health() {
+                log::warn!("Handler '{}' is not ready: {}", handler.name(), e);
+            }
+        }
+        let discovery = self.discovery.read().unwrap().clone();
+        if discovery.on_start {
+            let app = self.clone();
+            tokio::task::spawn_blocking(move || app.discover(&discovery));
+        }
+        self.enter(AppState::OK);
+    }
+
+    /// Shuts the hub down: stops what registered for `STOPPING`, e.g. the
+    /// scheduler and the HTTP server, and running effects, writes device
+    /// states not written yet and closes the connections of every handler.
+    /// Calls after the first wait for it to finish.
+    pub fn shutdown(&self) {
+        let _lifecycle = self.lifecycle.lock().unwrap();
+        if matches!(self.state(), AppState::STOPPING | AppState::STOPPED) {
+            return;
+        }
+        self.enter(AppState::STOPPING);
+        for effect in self.effects.status() {
+            self.effects.stop(&effect.name);
+        }
+        match self.flush_states() {
+            Ok(count) => log::info!("Wrote {} device states", count),
+            Err(e) => log::error!("Failed to write device states: {}", e),
+        }
+        for handler in self.protocol_registry.read().unwrap().all_handlers() {
+            handler.write().unwrap().shutdown();
+        }
+        self.enter(AppState::STOPPED);
+    }
+
+    pub fn state(&self) -> AppState {
+        *self.state.read().unwrap()
+    }
+
+    /// Calls `hook` with every state the hub enters from now on. Hooks run
+    /// in the order they were added, on the thread changing the state.
+    pub fn on_lifecycle(&self, hook: LifecycleHook) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    fn enter(&self, state: AppState) {
+        *self.state.write().unwrap() = state;
+        log::info!("Hub is {}", state.name());
+        for hook in self.hooks.lock().unwrap().iter() {
+            hook(state);
+        }
+        let _ = self.events.publish(EventKind::Custom {
+            name: STATE_EVENT.to_string(),
+            data: HashMap::from([("state".to_string(), state.name().to_string())]),
+        });
+    }
+
+    /// Looks for devices on the local network and keeps those not set up
+    /// yet in `discovered`, publishing a `device_discovered` event for each.
+    fn discover(&self, config: &DiscoveryConfig) {
+        let known = self.device_ids();
+        let found: Vec<Config> = discover_all(
+            &config.discoverers(),
+            Duration::from_secs(config.timeout_secs),
+        )
+        .into_iter()
+        .filter(|candidate| !known.contains(&candidate.id))
+        .collect();
+        log::info!("Discovered {} new devices", found.len());
+        for candidate in &found {
+            let _ = self.events.publish(EventKind::Custom {
+                name: DISCOVERED_EVENT.to_string(),
+                data: HashMap::from([
+                    ("device_id".to_string(), candidate.id.clone()),
+                    ("name".to_string(), candidate.name.clone()),
+                    (
+                        "protocols".to_string(),
+                        candidate.supported_protocols.join(","),
+                    ),
+                ]),
+            });
+        }
+        *self.discovered.write().unwrap() = found;
+    }
+
+    /// Sends commands like `send_command_async` for parts of the hub that
+    /// run on blocking threads. Must be created on the runtime; sending
+    /// blocks, so call it from blocking threads only.
+    pub fn command_sender(self: &Arc<Self>) -> CommandSender {
+        let app = self.clone();
+        let runtime = tokio::runtime::Handle::current();
+        Arc::new(move |device_id, command, parameters| {
+            runtime
+                .block_on(app.send_command_async(device_id, command, Some(parameters), None))
+                .map(|_| ())
+        })
+    }
+
+    /// Sends a command like `send_command`, awaiting async devices on the
+    /// runtime and running synchronous ones on the blocking pool, so slow
+    /// devices don't hold up other requests.
+    pub async fn send_command_async(
+        self: &Arc<Self>,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        self.send_command_async_as(None, device_id, command, parameters, lease)
+            .await
+    }
+
+    /// Sends a command like `send_command_async` on behalf of API account
+    /// `actor`, who lock commands are audited as.
+    pub async fn send_command_async_as(
+        self: &Arc<Self>,
+        actor: Option<&str>,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        self.send_command_async_from(
+            LockOrigin::Command,
+            actor,
+            device_id,
+            command,
+            parameters,
+            lease,
+        )
+        .await
+    }
+
+    /// Sends a command like `send_command_async` on behalf of `actor`, the
+    /// API account or rule sending it from `origin`.
+    async fn send_command_async_from(
+        self: &Arc<Self>,
+        origin: LockOrigin,
+        actor: Option<&str>,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        self.pumps.authorize(device_id, command)?;
+        self.locks.authorize(device_id, command, origin, actor)?;
+        let result = self
+            .queue
+            .send(device_id, command, parameters.as_ref(), || {
+                self.dispatch_async(device_id, command, parameters.clone(), lease)
+            })
+            .await;
+        if let Err(e) = &result {
+            self.locks.failed(device_id, command, origin, actor, e);
+        }
+        if self.maintenance.device(device_id).is_none() {
+            self.health.observe(device_id, &result);
+        }
+        result
+    }
+
+    /// Sends a typed command like `send_command_async`, refusing commands
+    /// the device lacks the capability for before anything is sent.
+    pub async fn send_typed_command(
+        self: &Arc<Self>,
+        device_id: &str,
+        command: &Command,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        if let Some(capability) = command.capability() {
+            let capabilities = self
+                .device_capabilities(device_id)
+                .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+            if !capabilities.contains(&capability) {
+                return Err(BlinkieError::unsupported_command(device_id, command.name()));
+            }
+        }
+        self.send_command_async(device_id, command.name(), command.parameters(), lease)
+            .await
+    }
+
+    /// What device `device_id` can do, if it is known.
+    pub fn device_capabilities(&self, device_id: &str) -> Option<Vec<Capability>> {
+        if let Some(device) = self.async_registry.device(device_id) {
+            return Some(device.capabilities());
+        }
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .find(|device| device.get_id() == device_id)
+            .map(|device| device.capabilities())
+    }
+
+    async fn dispatch_async(
+        self: &Arc<Self>,
+        device_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<CommandResult, BlinkieError> {
+        let Some(device) = self.async_registry.device(device_id) else {
+            let app = self.clone();
+            let (device_id, command, lease) = (
+                device_id.to_string(),
+                command.to_string(),
+                lease.map(str::to_string),
+            );
+            return tokio::task::spawn_blocking(move || {
+                app.dispatch(&device_id, &command, parameters, lease.as_deref())
+            })
+            .await
+            .map_err(|e| BlinkieError::connection(format!("Failed to send command: {}", e)))?;
+        };
+        self.leases.check(device_id, lease)?;
+        let typed = Command::parse(command, parameters.clone())?;
+        let started = Instant::now();
+        let disturbance = self.chaos.disturb(device_id);
+        if let Some(Disturbance::Delay(delay)) = disturbance {
+            tokio::time::sleep(delay).await;
+        }
+        let response = match disturbance {
+            Some(Disturbance::Fail) => return Err(chaos::failure(device_id)),
+            Some(Disturbance::Drop) => CommandResponse::default(),
+            _ => device.send(&typed).await?,
+        };
+        self.latency.record(device_id, started.elapsed());
+        let event = self.events.publish(EventKind::CommandSent {
+            device_id: device_id.to_string(),
+            command: command.to_string(),
+            parameters,
+        })?;
+        Ok(CommandResult {
+            event,
+            response,
+            job: None,
+        })
+    }
+
+    /// Writes an output frame to a device, see `Device::write_frame`.
+    /// Frames skip the command queue and aren't published as events, so
+    /// effects can stream them at a high rate. A frame meant to show `at`
+    /// a given time is held until then, less the time the device takes to
+    /// show it, so frames to several outputs show together.
+    pub async fn write_frame<F>(
+        self: &Arc<Self>,
+        device_id: &str,
+        frame: F,
+        lease: Option<&str>,
+        at: Option<DateTime<Utc>>,
+    ) -> Result<(), BlinkieError>
+    where
+        F: AsRef<[u8]> + Send + 'static,
+    {
+        self.leases.check(device_id, lease)?;
+        if let Some(at) = at {
+            let delay = self.pacing.delay(device_id, at)?;
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        let started = Instant::now();
+        let written = match self.async_registry.device(device_id) {
+            Some(device) => device.write_frame(frame.as_ref()).await,
+            None => {
+                let app = self.clone();
+                let device_id = device_id.to_string();
+                tokio::task::spawn_blocking(move || {
+                    let mut devices = app.devices.write().unwrap();
+                    devices
+                        .iter_mut()
+                        .find(|device| device.get_id() == device_id)
+                        .ok_or_else(|| format!("Device '{}' not found", device_id))?
+                        .write_frame(frame.as_ref())
+                })
+                .await
+                .map_err(|e| BlinkieError::connection(format!("Failed to write frame: {}", e)))?
+            }
+        };
+        if written.is_ok() {
+            self.pacing.record(device_id, started.elapsed());
+        }
+        written
+    }
+
+    /// Starts light effect `name` on its devices, or changes it if it runs
+    /// already. Every device needs the capability the effect drives.
+    pub fn start_effect(
+        self: &Arc<Self>,
+        name: &str,
+        definition: EffectDefinition,
+    ) -> Result<EffectStatus, BlinkieError> {
+        let capability = definition.effect.capability();
+        let command = definition.effect.frame(0.0, 0, 1);
+        for device_id in &definition.devices {
+            let capabilities = self
+                .device_capabilities(device_id)
+                .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+            if !capabilities.contains(&capability) {
+                return Err(BlinkieError::unsupported_command(device_id, command.name()));
+            }
+        }
+        self.effects.start(name, definition, self.command_sender())
+    }
+
+    /// Sends a command to every member of group `group_id`, one after
+    /// another or all at once as the group says. A member failing doesn't
+    /// keep the others from getting the command.
+    pub async fn send_group_command(
+        self: &Arc<Self>,
+        group_id: &str,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        lease: Option<&str>,
+    ) -> Result<Vec<MemberResult>, BlinkieError> {
+        let group = self
+            .groups
+            .get(group_id)
+            .ok_or_else(|| format!("Unknown group '{}'", group_id))?;
+        if !group.parallel {
+            let mut results = Vec::new();
+            for member in &group.members {
+                let result = self
+                    .send_command_async(member, command, parameters.clone(), lease)
+                    .await
+                    .map(|result| result.response);
+                results.push(MemberResult::new(member, result));
+            }
+            return Ok(results);
+        }
+        let tasks: Vec<_> = group
+            .members
+            .iter()
+            .map(|member| {
+                let app = self.clone();
+                let (member, command, parameters, lease) = (
+                    member.clone(),
+                    command.to_string(),
+                    parameters.clone(),
+                    lease.map(str::to_string),
+                );
+                tokio::spawn(async move {
+                    let result = app
+                        .send_command_async(&member, &command, parameters, lease.as_deref())
+                        .await
+                        .map(|result| result.response);
+                    MemberResult::new(&member, result)
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for (member, task) in group.members.iter().zip(tasks) {
+            results.push(task.await.unwrap_or_else(|e| {
+                MemberResult::new(member, Err(BlinkieError::Other(e.to_string())))
+            }));
+        }
+        Ok(results)
+    }
+
+    /// Ids of the devices `selector` picks, each once, in the order they
+    /// are picked. Fails on unknown groups and areas.
+    pub fn select_devices(&self, selector: &DeviceSelector) -> Result<Vec<String>, BlinkieError> {
+        let mut selected = selector.devices.clone();
+        let mut select = |device_id: &String| {
+            if !selected.contains(device_id) {
+                selected.push(device_id.clone());
+            }
+        };
+        for group_id in &selector.groups {
+            let group = self
+                .groups
+                .get(group_id)
+                .ok_or_else(|| format!("Group '{}' not found", group_id))?;
+            group.members.iter().for_each(&mut select);
+        }
+        for area in &selector.areas {
+            if !self.ui.areas.iter().any(|a| a.id == *area) {
+                return Err(format!("Area '{}' not found", area).into());
+            }
+            let mut members: Vec<&String> = self
+                .ui
+                .devices
+                .iter()
+                .filter(|(_, layout)| layout.area.as_ref() == Some(area))
+                .map(|(device_id, _)| device_id)
+                .collect();
+            members.sort();
+            members.into_iter().for_each(&mut select);
+        }
+        if !selector.types.is_empty() {
+            let devices = self.devices.read().unwrap();
+            let async_devices = self.async_registry.devices();
+            let mut typed: Vec<String> = devices
+                .iter()
+                .filter_map(|device| Some((device.get_id(), device.get_type()?)))
+                .chain(
+                    async_devices
+                        .iter()
+                        .filter_map(|device| Some((device.get_id(), device.get_type()?))),
+                )
+                .filter(|(_, device_type)| selector.types.contains(device_type))
+                .map(|(device_id, _)| device_id.to_string())
+                .collect();
+            typed.sort();
+            typed.iter().for_each(&mut select);
+        }
+        if !selector.capabilities.is_empty() {
+            let able = |capabilities: Vec<Capability>| {
+                selector
+                    .capabilities
+                    .iter()
+                    .all(|capability| capabilities.contains(capability))
+            };
+            let devices = self.devices.read().unwrap();
+            let async_devices = self.async_registry.devices();
+            let mut capable: Vec<String> = devices
+                .iter()
+                .filter(|device| able(device.capabilities()))
+                .map(|device| device.get_id().to_string())
+                .chain(
+                    async_devices
+                        .iter()
+                        .filter(|device| able(device.capabilities()))
+                        .map(|device| device.get_id().to_string()),
+                )
+                .collect();
+            capable.sort();
+            capable.iter().for_each(&mut select);
+        }
+        Ok(selected)
+    }
+
+    /// Captures the current states of `device_ids` as scene `id`, only
+    /// the state keys in `keys` if given, replacing any scene of that id.
+    pub fn capture_scene(
+        &self,
+        id: &str,
+        name: &str,
+        device_ids: &[String],
+        keys: Option<&[String]>,
+    ) -> Result<Scene, BlinkieError> {
+        let scene = self.captured(id, name, device_ids, keys)?;
+        self.scenes.save(scene.clone(), &self.device_ids())?;
+        Ok(scene)
+    }
+
+    /// Captures the current states of `device_ids` as a new scene, with an
+    /// id made from `name`.
+    pub fn capture_new_scene(
+        &self,
+        name: &str,
+        device_ids: &[String],
+        keys: Option<&[String]>,
+    ) -> Result<Scene, BlinkieError> {
+        let scene = self.captured("", name, device_ids, keys)?;
+        self.scenes.add(scene, &self.device_ids())
+    }
+
+    fn captured(
+        &self,
+        id: &str,
+        name: &str,
+        device_ids: &[String],
+        keys: Option<&[String]>,
+    ) -> Result<Scene, BlinkieError> {
+        let mut current = self.device_states();
+        let mut states = BTreeMap::new();
+        for device_id in device_ids {
+            let mut state = current
+                .remove(device_id)
+                .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+            if let Some(keys) = keys {
+                state.retain(|key, _| keys.contains(key));
+            }
+            states.insert(device_id.clone(), state);
+        }
+        Ok(Scene {
+            id: id.to_string(),
+            name: name.to_string(),
+            icon: self.scenes.get(id).and_then(|scene| scene.icon),
+            states,
+            captured_at: Utc::now(),
+        })
+    }
+
+    /// Gives every device of scene `id` its captured state, all at once,
+    /// on behalf of `actor`, the API account applying it. Devices claimed
+    /// by a lease are left alone.
+    pub async fn apply_scene(
+        self: &Arc<Self>,
+        id: &str,
+        actor: Option<&str>,
+    ) -> Result<Vec<MemberResult>, BlinkieError> {
+        let scene = self
+            .scenes
+            .get(id)
+            .ok_or_else(|| format!("Unknown scene '{}'", id))?;
+        let tasks: Vec<_> = scene
+            .states
+            .into_iter()
+            .map(|(device_id, state)| {
+                let app = self.clone();
+                let actor = actor.map(str::to_string);
+                tokio::spawn(async move {
+                    let result = app
+                        .set_scene_state(&device_id, state, actor.as_deref())
+                        .await;
+                    MemberResult::new(&device_id, result.map(|_| Default::default()))
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.map_err(|e| BlinkieError::Other(e.to_string()))?);
+        }
+        Ok(results)
+    }
+
+    /// Sets a state of a scene like `set_state_async`. Locks and pumps are
+    /// guarded as if sent their commands: locking or unlocking is audited
+    /// first and tripped pumps aren't switched on.
+    async fn set_scene_state(
+        &self,
+        device_id: &str,
+        state: StateMap,
+        actor: Option<&str>,
+    ) -> Result<(), BlinkieError> {
+        if state
+            .get("state")
+            .is_some_and(|value| usage::is_on(&value.to_string()))
+        {
+            self.pumps.authorize_start(device_id)?;
+        }
+        let command = LockState::from_state(&state).and_then(LockState::command);
+        if let Some(command) = command {
+            self.locks
+                .authorize(device_id, command, LockOrigin::Scene, actor)?;
+        }
+        let result = self.set_state_async(device_id, state).await;
+        if let (Some(command), Err(e)) = (command, &result) {
+            self.locks
+                .failed(device_id, command, LockOrigin::Scene, actor, e);
+        }
+        result
+    }
+
+    /// Sets the state of a device, awaiting async devices on the runtime
+    /// and running synchronous ones on the blocking pool. Fails while the
+    /// device is claimed.
+    pub async fn set_state_async(
+        &self,
+        device_id: &str,
+        state: StateMap,
+    ) -> Result<(), BlinkieError> {
+        self.leases.check(device_id, None)?;
+        if let Some(device) = self.async_registry.device(device_id) {
+            return device.set_state(state).await;
+        }
+        let devices = self.devices.clone();
+        let device_id = device_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut devices = devices.write().unwrap();
+            let device = devices
+                .iter_mut()
+                .find(|device| device.get_id() == device_id)
+                .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+            device.set_state(state);
+            Ok(())
+        })
+        .await
+        .map_err(|e| BlinkieError::Other(e.to_string()))?
+    }
+
+    /// Ids of all devices known to the hub.
+    pub fn device_ids(&self) -> Vec<String> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .map(|device| device.get_id().to_string())
+            .chain(
+                self.async_registry
+                    .devices()
+                    .iter()
+                    .map(|device| device.get_id().to_string()),
+            )
+            .collect()
+    }
+
+    /// Creates a device from `config` with a matching handler and stores it,
+    /// so it is created again on the next start.
+    pub fn register_device(&self, config: Config) -> Result<(), BlinkieError> {
+        if config.id.is_empty() {
+            return Err("Device id must not be empty".into());
+        }
+        let registry = self.protocol_registry.read().unwrap();
+        let handler = registry.handler_for(&config)?;
+        handler.read()?.validate(&config)?;
+        let mut devices = self.devices.write().unwrap();
+        if devices.iter().any(|device| device.get_id() == config.id)
+            || self.async_registry.device(&config.id).is_some()
+        {
+            return Err(format!("Device '{}' already exists", config.id).into());
+        }
+        let device = registry.create_device(&handler, &config)?;
+        let saved = self.device_store.save_device(&config);
+        if saved.is_err() {
+            // Written with the next flush that works
+            self.unsaved.lock().unwrap().push(config);
+        }
+        self.storage
+            .report(DEVICE_STORE, &saved, self.unsaved.lock().unwrap().len());
+        devices.push(device);
+        Ok(())
+    }
+
+    /// Removes a device registered with `register_device` and its stored
+    /// state. Returns `false` for devices that weren't registered that way.
+    /// Fails while the device store does, unless the device wasn't written
+    /// to it yet.
+    pub fn unregister_device(&self, device_id: &str) -> Result<bool, BlinkieError> {
+        let mut unsaved = self.unsaved.lock().unwrap();
+        let pending = unsaved.len();
+        unsaved.retain(|config| config.id != device_id);
+        let removed = unsaved.len() < pending;
+        drop(unsaved);
+        if !removed && !self.device_store.remove_device(device_id)? {
+            return Ok(false);
+        }
+        self.devices
+            .write()
+            .unwrap()
+            .retain(|device| device.get_id() != device_id);
+        self.flushed.lock().unwrap().remove(device_id);
+        self.health.forget(device_id);
+        self.pacing.forget(device_id);
+        Ok(true)
+    }
+
+    /// Deletes everything the hub recorded about a device or person: its
+    /// journaled and archived events, which the history index is rebuilt
+    /// without, the aggregates, usage, state history, exported rows, hot-key
+    /// samples, captured frames, latency and lock audit kept from them, and
+    /// a person's user, presence, occupancy evidence and guest codes.
+    /// Backups still holding any of it are replaced by a fresh one. Returns
+    /// the number of events removed.
+    pub fn purge(&self, target: &PurgeTarget) -> Result<usize, BlinkieError> {
+        let mut removed = self.events.purge(target)?;
+        if let Some(archiver) = &self.archiver {
+            removed += archiver.purge(target)?;
+        }
+        match target {
+            PurgeTarget::Device(device_id) => {
+                self.aggregates.forget(device_id)?;
+                self.usage.forget(device_id)?;
+                self.state_history.forget(device_id);
+                self.sniffer.forget(device_id);
+                self.latency.forget(device_id);
+                if let Some(exporter) = &self.exporter {
+                    exporter.purge(device_id)?;
+                }
+            }
+            PurgeTarget::Person(person_id) => {
+                self.users.forget(person_id)?;
+                self.guests.forget(person_id, Utc::now())?;
+            }
+        }
+        self.occupancy.forget(target);
+        self.locks.purge(target)?;
+        if let Some(backups) = &self.backups {
+            backups.manager.replace(Utc::now())?;
+        }
+        Ok(removed)
+    }
+
+    /// Config a device was created from, looked up like devices are created:
+    /// the config file wins over the device store.
+    fn device_config(&self, device_id: &str) -> Result<Option<Config>, BlinkieError> {
+        if self.config_path.is_some() {
+            if let Some(config) = self
+                .config()?
+                .devices
+                .into_iter()
+                .find(|config| config.id == device_id)
+            {
+                return Ok(Some(config));
+            }
+        }
+        if let Some(config) = self
+            .unsaved
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|config| config.id == device_id)
+        {
+            return Ok(Some(config.clone()));
+        }
+        Ok(self
+            .device_store
+            .devices()?
+            .into_iter()
+            .find(|config| config.id == device_id))
+    }
+
+    /// A shareable snippet of a device, `None` for devices that weren't
+    /// created from a config, e.g. async devices.
+    pub fn export_device(&self, device_id: &str) -> Result<Option<DeviceSnippet>, BlinkieError> {
+        Ok(self
+            .device_config(device_id)?
+            .map(|config| DeviceSnippet::new(config, self.icons.icon(device_id))))
+    }
+
+    /// Registers the device of `snippet`, under `id` if given and with
+    /// `details` filling in its secrets, and sets its icon.
+    pub fn import_device(
+        &self,
+        snippet: &DeviceSnippet,
+        id: Option<&str>,
+        details: &HashMap<String, String>,
+    ) -> Result<Config, BlinkieError> {
+        let config = snippet.config(id, details)?;
+        self.register_device(config.clone())?;
+        if snippet.icon.is_some() {
+            self.icons.set_icon(&config.id, snippet.icon.clone())?;
+        }
+        Ok(config)
+    }
+
+    /// Writes the devices registered while the device store failed and the
+    /// states that changed since the last flush to the device store, and
+    /// returns how many states it wrote. States that couldn't be written
+    /// are tried again with the next flush.
+    pub fn flush_states(&self) -> Result<usize, BlinkieError> {
+        let mut flushed = self.flushed.lock().unwrap();
+        let changed: HashMap<String, StateMap> = self
+            .device_states()
+            .into_iter()
+            .filter(|(device_id, state)| flushed.get(device_id) != Some(state))
+            .collect();
+        let written = self.save_unsaved().and_then(|_| {
+            if !changed.is_empty() {
+                self.device_store.save_states(&changed)?;
+            }
+            Ok(())
+        });
+        let queued = match written {
+            Ok(_) => 0,
+            Err(_) => self.unsaved.lock().unwrap().len() + changed.len(),
+        };
+        self.storage.report(DEVICE_STORE, &written, queued);
+        written?;
+        let count = changed.len();
+        flushed.extend(changed);
+        Ok(count)
+    }
+
+    /// Writes the devices registered while the device store failed.
+    fn save_unsaved(&self) -> Result<(), BlinkieError> {
+        let mut unsaved = self.unsaved.lock().unwrap();
+        while let Some(config) = unsaved.first() {
+            self.device_store.save_device(config)?;
+            unsaved.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Flushes device states every `flush_interval`, on the blocking pool
+    /// since reading states may run commands, and tries again to journal
+    /// events kept in memory while the journal fails.
+    pub async fn persist_states(self: Arc<Self>) {
+        let mut tick = tokio::time::interval(self.flush_interval);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            self.events.retry_journal();
+            let app = self.clone();
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || app.flush_states()).await {
+                log::error!("Failed to flush device states: {}", e);
+            }
+        }
+    }
+
+    /// Gives every device the state last flushed for it.
+    fn restore_states(&self) -> Result<(), BlinkieError> {
+        let states = self.device_store.states()?;
+        for device in self.devices.write().unwrap().iter_mut() {
+            if let Some(state) = states.get(device.get_id()) {
+                device.set_state(state.clone());
+            }
+        }
+        self.flushed.lock().unwrap().extend(states);
+        Ok(())
+    }
+
+    /// How full the capped in-memory caches are.
+    pub fn memory(&self) -> MemoryStatus {
+        let (command_queue, dead_letters) = self.queue.metrics();
+        MemoryStatus {
+            state_cache: self.flushed.lock().unwrap().metrics(),
+            journal_backlog: self.events.backlog_metrics(),
+            command_queue,
+            dead_letters,
+        }
+    }
+
+    /// Current state of every device, by device id.
+    pub fn device_states(&self) -> HashMap<String, StateMap> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .map(|device| (device.get_id().to_string(), device.get_state()))
+            .chain(
+                self.async_registry
+                    .devices()
+                    .iter()
+                    .map(|device| (device.get_id().to_string(), device.get_state())),
+            )
+            .collect()
+    }
+
+    /// Checks whether each device answers, with the handler that created
+    /// it, skipping devices in maintenance. Async devices are trusted on
+    /// what they report, see `health::reported`.
+    pub fn check_health(&self) -> Vec<(String, Result<(), BlinkieError>)> {
+        let handlers: Vec<HandlerRef> = self.protocol_registry.read().unwrap().all_handlers();
+        let handler_of = |device_id: &str| {
+            let name = self
+                .protocol_registry
+                .read()
+                .unwrap()
+                .handler_of(device_id)?;
+            handlers
+                .iter()
+                .find(|handler| handler.read().unwrap().name() == name)
+                .cloned()
+        };
+        let mut results = Vec::new();
+        for device_id in self.device_ids() {
+            if self.maintenance.device(&device_id).is_some() {
+                continue;
+            }
+            let result = if let Some(device) = self.async_registry.device(&device_id) {
+                health::reported(&device_id, &device.get_state())
+            } else {
+                let handler = handler_of(&device_id);
+                let devices = self.devices.read().unwrap();
+                let Some(device) = devices.iter().find(|device| device.get_id() == device_id)
+                else {
+                    continue;
+                };
+                match handler {
+                    Some(handler) => handler.read().unwrap().check_health(device.as_ref()),
+                    None => health::reported(&device_id, &device.get_state()),
+                }
+            };
+            results.push((device_id, result));
+        }
+        results
+    }
+
+    /// What every device says about itself, by id.
+    pub fn fingerprints(&self) -> HashMap<String, Fingerprint> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .map(|device| {
+                let fingerprint =
+                    Fingerprint::of(device.get_type(), device.commands(), &device.get_state());
+                (device.get_id().to_string(), fingerprint)
+            })
+            .chain(self.async_registry.devices().iter().map(|device| {
+                let fingerprint =
+                    Fingerprint::of(device.get_type(), device.commands(), &device.get_state());
+                (device.get_id().to_string(), fingerprint)
+            }))
+            .collect()
+    }
 }
@@ -0,0 +1,220 @@
+use super::error::BlinkieError;
+use super::event::{Event, EventBus};
+use super::maintenance::MaintenanceTask;
+use super::retention::PurgeTarget;
+use super::storage::{with_retries, RemoteStorage, StorageConfig};
+use chrono::{DateTime, Duration, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Key prefix archives are stored under.
+const ARCHIVE_DIR: &str = "history";
+const ATTEMPTS: u32 = 3;
+
+/**
+ * ArchiveConfig
+ * Where events older than `older_than_days` are moved out of the journal
+ * to, e.g.
+ *
+ * ```toml
+ * [settings.archive]
+ * to = { type = "local", path = "/mnt/nas/blinkie" }
+ * older_than_days = 180
+ * ```
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub to: StorageConfig,
+    #[serde(default = "default_older_than_days")]
+    pub older_than_days: i64,
+}
+
+fn default_older_than_days() -> i64 {
+    90
+}
+
+impl ArchiveConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.older_than_days < 1 {
+            return Err("older_than_days must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/**
+ * HistoryArchiveTask
+ * Moves old events out of the journal into compressed monthly archives in
+ * remote storage. Archives are written to a local spool directory first and
+ * only removed from it once uploaded, so nothing is lost while the remote
+ * side is unreachable.
+ */
+pub struct HistoryArchiveTask {
+    pub events: Arc<EventBus>,
+    pub storage: Box<dyn RemoteStorage>,
+    pub spool: PathBuf,
+    /// Events older than this many days are archived.
+    pub older_than_days: i64,
+}
+
+impl HistoryArchiveTask {
+    /// Writes events older than the cutoff to the spool and drops them from
+    /// the journal. Returns the number of events archived.
+    pub fn spool_events(&self, now: DateTime<Utc>) -> Result<usize, BlinkieError> {
+        let cutoff = now - Duration::days(self.older_than_days);
+        let events = self
+            .events
+            .replay_between(DateTime::<Utc>::MIN_UTC, cutoff)?;
+        let Some(last) = events.last().map(|event| event.seq) else {
+            return Ok(0);
+        };
+
+        let mut months: BTreeMap<String, Vec<&Event>> = BTreeMap::new();
+        for event in &events {
+            let month = event.timestamp.format("%Y-%m").to_string();
+            months.entry(month).or_default().push(event);
+        }
+
+        fs::create_dir_all(&self.spool)
+            .map_err(|e| format!("Failed to create {}: {}", self.spool.display(), e))?;
+        for (month, events) in months {
+            let first = events.first().map(|event| event.seq).unwrap_or_default();
+            let last = events.last().map(|event| event.seq).unwrap_or_default();
+            let name = format!("events-{}-{}-{}.jsonl.gz", month, first, last);
+            let path = self.spool.join(&name);
+            let tmp_path = self.spool.join(format!("{}.tmp", name));
+
+            let file = File::create(&tmp_path)
+                .map_err(|e| format!("Failed to create {}: {}", tmp_path.display(), e))?;
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            for event in events {
+                let line = serde_json::to_string(event).map_err(|e| {
+                    BlinkieError::serialization(format!("Failed to serialize event: {}", e))
+                })?;
+                writeln!(encoder, "{}", line)
+                    .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+            }
+            encoder
+                .finish()
+                .and_then(|_| fs::rename(&tmp_path, &path))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        }
+
+        self.events.remove_until(last)?;
+        Ok(events.len())
+    }
+
+    /// Uploads every spooled archive and removes those that made it.
+    pub fn upload_spooled(&self) -> Result<usize, BlinkieError> {
+        if !self.spool.exists() {
+            return Ok(0);
+        }
+        let entries = fs::read_dir(&self.spool)
+            .map_err(|e| format!("Failed to read {}: {}", self.spool.display(), e))?;
+        let mut uploaded = 0;
+        for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !name.ends_with(".jsonl.gz") {
+                continue;
+            }
+            let key = format!("{}/{}", ARCHIVE_DIR, name);
+            with_retries(ATTEMPTS, || self.storage.upload(&key, &path))?;
+            fs::remove_file(&path)
+                .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            uploaded += 1;
+        }
+        Ok(uploaded)
+    }
+
+    /// Rewrites the spooled and uploaded archives holding events about
+    /// `target` without them. Returns the number of events removed.
+    pub fn purge(&self, target: &PurgeTarget) -> Result<usize, BlinkieError> {
+        let mut removed = 0;
+        if self.spool.exists() {
+            let entries = fs::read_dir(&self.spool)
+                .map_err(|e| format!("Failed to read {}: {}", self.spool.display(), e))?;
+            for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+                if path.to_string_lossy().ends_with(".jsonl.gz") {
+                    removed += purge_archive(&path, target)?;
+                }
+            }
+        }
+        for key in self.storage.list(&format!("{}/", ARCHIVE_DIR))? {
+            let scratch = std::env::temp_dir().join(key.replace('/', "-"));
+            let result = with_retries(ATTEMPTS, || self.storage.download(&key, &scratch))
+                .and_then(|_| purge_archive(&scratch, target))
+                .and_then(|count| {
+                    if count > 0 {
+                        with_retries(ATTEMPTS, || self.storage.upload(&key, &scratch))?;
+                    }
+                    Ok(count)
+                });
+            let _ = fs::remove_file(&scratch);
+            removed += result?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Rewrites the archive at `path` without the events about `target`.
+/// Returns the number of events removed.
+fn purge_archive(path: &Path, target: &PurgeTarget) -> Result<usize, BlinkieError> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut kept = Vec::new();
+    let mut removed = 0;
+    for line in BufReader::new(GzDecoder::new(file)).lines() {
+        let line = line.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let event: Event = serde_json::from_str(&line).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+        })?;
+        if target.matches(&event) {
+            removed += 1;
+        } else {
+            kept.push(line);
+        }
+    }
+    if removed == 0 {
+        return Ok(0);
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path)
+        .map_err(|e| format!("Failed to create {}: {}", tmp_path.display(), e))?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    for line in kept {
+        writeln!(encoder, "{}", line)
+            .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+    }
+    encoder
+        .finish()
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(removed)
+}
+
+impl MaintenanceTask for HistoryArchiveTask {
+    fn name(&self) -> &str {
+        "history_archive"
+    }
+
+    fn run(&self) -> Result<(), BlinkieError> {
+        let archived = self.spool_events(Utc::now())?;
+        let uploaded = self.upload_spooled()?;
+        log::info!(
+            "Archived {} event(s), uploaded {} archive(s)",
+            archived,
+            uploaded
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,361 @@
+use super::device::{
+    Action, ApiVersion, Capability, Command, CommandResponse, Config, Device, HandlerRef, Type,
+    HANDLER_API_VERSION,
+};
+use super::error::BlinkieError;
+use super::value::StateMap;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/**
+ * AsyncDevice Interface
+ * A device driven by tokio, for protocols that talk over the network. All
+ * methods take `&self`, so no lock is held while a command is in flight;
+ * implementations keep their mutable parts behind their own locks.
+ */
+#[async_trait]
+pub trait AsyncDevice: Send + Sync {
+    fn get_id(&self) -> &str;
+    fn get_name(&self) -> &str;
+    /// Last known state. Must not wait on the device; implementations keep
+    /// it up to date from what the device reports.
+    fn get_state(&self) -> StateMap;
+    async fn set_state(&self, state: StateMap) -> Result<(), BlinkieError>;
+    /// Sends a command and returns what the device answered.
+    async fn command(
+        &self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError>;
+    /// Class of the device, if the implementation knows it.
+    fn get_type(&self) -> Option<Type> {
+        None
+    }
+    /// Commands the device handles, if the implementation knows them.
+    fn commands(&self) -> Option<Vec<String>> {
+        None
+    }
+    /// What the device can do, see `Device::capabilities`.
+    fn capabilities(&self) -> Vec<Capability> {
+        Capability::infer(&self.get_state(), self.commands().as_deref())
+    }
+    /// Sends a typed command, see `Device::send`.
+    async fn send(&self, command: &Command) -> Result<CommandResponse, BlinkieError> {
+        self.command(command.name(), command.parameters()).await
+    }
+    /// Writes a frame of output, see `Device::write_frame`.
+    async fn write_frame(&self, _frame: &[u8]) -> Result<(), BlinkieError> {
+        Err(BlinkieError::unsupported_command(self.get_id(), "frame"))
+    }
+}
+
+/**
+ * AsyncProtocolHandler Interface
+ * The async counterpart of `ProtocolHandler`. Handlers are shared as
+ * `Arc`s and called concurrently, not through a lock.
+ */
+#[async_trait]
+pub trait AsyncProtocolHandler: Send + Sync {
+    fn name(&self) -> String;
+    /// Handler API version the handler was written against, see
+    /// `ProtocolHandler::api_version`.
+    fn api_version(&self) -> ApiVersion {
+        HANDLER_API_VERSION
+    }
+    fn priority(&self) -> u8 {
+        0
+    }
+    fn supported_protocols(&self) -> Vec<String>;
+    async fn initialize(&self) -> Result<(), BlinkieError>;
+    async fn create_device(&self, config: &Config) -> Result<Arc<dyn AsyncDevice>, BlinkieError>;
+    /// Reports why the handler can't currently talk to its devices, if it can't.
+    async fn health(&self) -> Result<(), BlinkieError> {
+        Ok(())
+    }
+}
+
+/// Shared handle to a registered async protocol handler.
+pub type AsyncHandlerRef = Arc<dyn AsyncProtocolHandler>;
+
+/**
+ * AsyncExecutor Interface
+ * Executes an action on an async device.
+ */
+#[async_trait]
+pub trait AsyncExecutor {
+    async fn execute_async(&self, device: &dyn AsyncDevice) -> Result<(), BlinkieError>;
+}
+
+#[async_trait]
+impl AsyncExecutor for Action {
+    async fn execute_async(&self, device: &dyn AsyncDevice) -> Result<(), BlinkieError> {
+        match self {
+            Action::TurnOn => device.command("turn_on", None).await.map(|_| ()),
+            Action::TurnOff => device.command("turn_off", None).await.map(|_| ()),
+            Action::Set(state) => device.set_state(state.clone()).await,
+            Action::Reset(target) => device
+                .command(
+                    "reset",
+                    Some(HashMap::from([("target".to_string(), target.clone())])),
+                )
+                .await
+                .map(|_| ()),
+        }
+    }
+}
+
+/**
+ * AsyncRegistry
+ * Registers async handlers by protocol and keeps the devices they created,
+ * so commands to them can be awaited without blocking the runtime.
+ */
+#[derive(Default)]
+pub struct AsyncRegistry {
+    handlers: RwLock<HashMap<String, Vec<AsyncHandlerRef>>>,
+    /// API version agreed with each handler at registration, by name.
+    versions: RwLock<HashMap<String, ApiVersion>>,
+    devices: RwLock<Vec<Arc<dyn AsyncDevice>>>,
+}
+
+impl AsyncRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for its supported protocols. Handlers written
+    /// against an API version this build doesn't implement are refused.
+    pub fn register(&self, handler: AsyncHandlerRef) -> Result<(), BlinkieError> {
+        let name = handler.name();
+        let version = handler
+            .api_version()
+            .negotiate(HANDLER_API_VERSION)
+            .map_err(|e| format!("Failed to register handler '{}': {}", name, e))?;
+        self.versions.write().unwrap().insert(name, version);
+        let mut handlers = self.handlers.write().unwrap();
+        for protocol in handler.supported_protocols() {
+            let list = handlers.entry(protocol).or_default();
+            list.push(handler.clone());
+            list.sort_by_key(|handler| std::cmp::Reverse(handler.priority()));
+        }
+        Ok(())
+    }
+
+    /// API version agreed with the handler named `name`.
+    pub fn api_version(&self, name: &str) -> Option<ApiVersion> {
+        self.versions.read().unwrap().get(name).copied()
+    }
+
+    /// Returns every registered handler once, however many protocols it serves.
+    pub fn all_handlers(&self) -> Vec<AsyncHandlerRef> {
+        let handlers = self.handlers.read().unwrap();
+        let mut all: Vec<AsyncHandlerRef> = Vec::new();
+        for handler in handlers.values().flatten() {
+            if !all.iter().any(|known| Arc::ptr_eq(known, handler)) {
+                all.push(handler.clone());
+            }
+        }
+        all
+    }
+
+    pub fn get_handlers(&self, protocol: &str) -> Option<Vec<AsyncHandlerRef>> {
+        self.handlers.read().unwrap().get(protocol).cloned()
+    }
+
+    /// Creates a device with the preferred or highest priority handler of
+    /// its protocols and keeps it, replacing a device with the same id.
+    pub async fn create_device(
+        &self,
+        config: &Config,
+    ) -> Result<Arc<dyn AsyncDevice>, BlinkieError> {
+        let handler = config
+            .supported_protocols
+            .iter()
+            .filter_map(|protocol| self.get_handlers(protocol))
+            .find_map(|handlers| match &config.preferred_handler {
+                Some(preferred) => handlers
+                    .into_iter()
+                    .find(|handler| handler.name() == *preferred),
+                None => handlers.into_iter().next(),
+            })
+            .ok_or_else(|| {
+                format!(
+                    "No compatible protocol handler found for device: {}",
+                    config.name
+                )
+            })?;
+        let device = handler.create_device(config).await?;
+        let mut devices = self.devices.write().unwrap();
+        devices.retain(|known| known.get_id() != device.get_id());
+        devices.push(device.clone());
+        Ok(device)
+    }
+
+    pub fn device(&self, device_id: &str) -> Option<Arc<dyn AsyncDevice>> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .find(|device| device.get_id() == device_id)
+            .cloned()
+    }
+
+    pub fn devices(&self) -> Vec<Arc<dyn AsyncDevice>> {
+        self.devices.read().unwrap().clone()
+    }
+
+    pub fn remove_device(&self, device_id: &str) -> Option<Arc<dyn AsyncDevice>> {
+        let mut devices = self.devices.write().unwrap();
+        let index = devices
+            .iter()
+            .position(|device| device.get_id() == device_id)?;
+        Some(devices.remove(index))
+    }
+}
+
+/**
+ * BlockingDevice
+ * Drives a synchronous `Device` from async code, running its calls on
+ * tokio's blocking pool.
+ */
+pub struct BlockingDevice {
+    id: String,
+    name: String,
+    device: Arc<Mutex<Box<dyn Device>>>,
+}
+
+impl BlockingDevice {
+    pub fn new(device: Box<dyn Device>) -> Self {
+        BlockingDevice {
+            id: device.get_id().to_string(),
+            name: device.get_name().to_string(),
+            device: Arc::new(Mutex::new(device)),
+        }
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T, BlinkieError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn Device) -> Result<T, BlinkieError> + Send + 'static,
+    {
+        let device = self.device.clone();
+        tokio::task::spawn_blocking(move || f(device.lock().unwrap().as_mut()))
+            .await
+            .map_err(|e| format!("Device '{}' failed: {}", self.id, e))?
+    }
+}
+
+#[async_trait]
+impl AsyncDevice for BlockingDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.device.lock().unwrap().get_state()
+    }
+
+    async fn set_state(&self, state: StateMap) -> Result<(), BlinkieError> {
+        self.blocking(move |device| {
+            device.set_state(state);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn command(
+        &self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        let command = command.to_string();
+        self.blocking(move |device| device.command(&command, parameters))
+            .await
+    }
+
+    async fn send(&self, command: &Command) -> Result<CommandResponse, BlinkieError> {
+        let command = command.clone();
+        self.blocking(move |device| device.send(&command)).await
+    }
+
+    async fn write_frame(&self, frame: &[u8]) -> Result<(), BlinkieError> {
+        let frame = frame.to_vec();
+        self.blocking(move |device| device.write_frame(&frame))
+            .await
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        self.device.lock().unwrap().get_type()
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        self.device.lock().unwrap().commands()
+    }
+
+    fn capabilities(&self) -> Vec<Capability> {
+        self.device.lock().unwrap().capabilities()
+    }
+}
+
+/**
+ * BlockingHandler
+ * Registers a synchronous `ProtocolHandler` with the async registry. Its
+ * calls run on tokio's blocking pool; devices it creates are wrapped in
+ * `BlockingDevice`.
+ */
+pub struct BlockingHandler {
+    handler: HandlerRef,
+}
+
+impl BlockingHandler {
+    pub fn new(handler: HandlerRef) -> Self {
+        BlockingHandler { handler }
+    }
+}
+
+#[async_trait]
+impl AsyncProtocolHandler for BlockingHandler {
+    fn name(&self) -> String {
+        self.handler.read().unwrap().name()
+    }
+
+    fn api_version(&self) -> ApiVersion {
+        self.handler.read().unwrap().api_version()
+    }
+
+    fn priority(&self) -> u8 {
+        self.handler.read().unwrap().priority()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        self.handler.read().unwrap().supported_protocols()
+    }
+
+    async fn initialize(&self) -> Result<(), BlinkieError> {
+        let handler = self.handler.clone();
+        tokio::task::spawn_blocking(move || handler.write().unwrap().initialize())
+            .await
+            .map_err(|e| format!("Handler initialization failed: {}", e))?
+    }
+
+    async fn create_device(&self, config: &Config) -> Result<Arc<dyn AsyncDevice>, BlinkieError> {
+        let handler = self.handler.clone();
+        let config = config.clone();
+        let device =
+            tokio::task::spawn_blocking(move || handler.write().unwrap().create_device(&config))
+                .await
+                .map_err(|e| format!("Device creation failed: {}", e))??;
+        Ok(Arc::new(BlockingDevice::new(device)))
+    }
+
+    async fn health(&self) -> Result<(), BlinkieError> {
+        let handler = self.handler.clone();
+        tokio::task::spawn_blocking(move || handler.read().unwrap().health())
+            .await
+            .map_err(|e| format!("Health check failed: {}", e))?
+    }
+}
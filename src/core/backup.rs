@@ -0,0 +1,208 @@
+use super::error::BlinkieError;
+use super::maintenance::MaintenanceTask;
+use super::storage::{with_retries, RemoteStorage, StorageConfig};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+const PREFIX: &str = "blinkie-";
+const SUFFIX: &str = ".tar.gz";
+/// Attempts per transfer before a backup or restore gives up.
+const ATTEMPTS: u32 = 3;
+
+/**
+ * BackupConfig
+ * Where the hub backs up its data directory and config file, and how many
+ * backups are kept there, e.g.
+ *
+ * ```toml
+ * [settings.backup]
+ * to = { type = "web_dav", url = "https://nas.local/dav/blinkie" }
+ * keep = 14
+ * ```
+ *
+ * Backups run nightly between 02:00 and 05:00 unless
+ * `settings.maintenance.jobs.backup` says otherwise.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+    pub to: StorageConfig,
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+/**
+ * BackupManager
+ * Archives the hub's storage and config directories to remote storage and
+ * keeps only the newest few archives there.
+ */
+pub struct BackupManager {
+    sources: Vec<PathBuf>,
+    storage: Box<dyn RemoteStorage>,
+    keep: usize,
+}
+
+impl BackupManager {
+    /// `sources` are directories or files; each is stored in the archive under its file name.
+    pub fn new(sources: Vec<PathBuf>, storage: Box<dyn RemoteStorage>, keep: usize) -> Self {
+        BackupManager {
+            sources,
+            storage,
+            keep,
+        }
+    }
+
+    /// Names of the backups in storage, oldest first.
+    pub fn list(&self) -> Result<Vec<String>, BlinkieError> {
+        let mut names: Vec<String> = self
+            .storage
+            .list(PREFIX)?
+            .into_iter()
+            .filter(|name| name.ends_with(SUFFIX))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Writes a new backup and rotates old ones out. Returns its name.
+    pub fn create(&self, now: DateTime<Utc>) -> Result<String, BlinkieError> {
+        let name = format!("{}{}{}", PREFIX, now.format("%Y%m%dT%H%M%SZ"), SUFFIX);
+        let archive = scratch_path(&name);
+        let result = self
+            .write_archive(&archive)
+            .and_then(|_| with_retries(ATTEMPTS, || self.storage.upload(&name, &archive)));
+        let _ = fs::remove_file(&archive);
+        result?;
+        self.rotate()?;
+        Ok(name)
+    }
+
+    /// Writes a new backup and deletes every other one, e.g. once data
+    /// older backups still hold was purged. Returns its name.
+    pub fn replace(&self, now: DateTime<Utc>) -> Result<String, BlinkieError> {
+        let name = self.create(now)?;
+        for old in self.list()?.into_iter().filter(|old| *old != name) {
+            self.storage.delete(&old)?;
+        }
+        Ok(name)
+    }
+
+    /// Deletes all but the newest `keep` backups.
+    pub fn rotate(&self) -> Result<(), BlinkieError> {
+        let names = self.list()?;
+        let excess = names.len().saturating_sub(self.keep.max(1));
+        for name in &names[..excess] {
+            self.storage.delete(name)?;
+        }
+        Ok(())
+    }
+
+    /// Restores a backup (the newest one if `name` is not given) into `dir`.
+    /// Directories that are replaced are kept next to it with a
+    /// `.pre-restore` suffix.
+    pub fn restore(&self, name: Option<&str>, dir: &Path) -> Result<String, BlinkieError> {
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => self
+                .list()?
+                .pop()
+                .ok_or_else(|| "No backups found".to_string())?,
+        };
+        let archive = scratch_path(&name);
+        let result = with_retries(ATTEMPTS, || self.storage.download(&name, &archive))
+            .and_then(|_| unpack(&archive, dir));
+        let _ = fs::remove_file(&archive);
+        result.map(|_| name)
+    }
+
+    fn write_archive(&self, path: &Path) -> Result<(), BlinkieError> {
+        let file = File::create(path).map_err(|e| format!("Failed to create backup: {}", e))?;
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        for source in &self.sources {
+            let name = source
+                .file_name()
+                .ok_or_else(|| format!("Invalid backup source {}", source.display()))?;
+            let result = if source.is_dir() {
+                builder.append_dir_all(name, source)
+            } else {
+                builder.append_path_with_name(source, name)
+            };
+            result.map_err(|e| format!("Failed to archive {}: {}", source.display(), e))?;
+        }
+        builder
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .map(|_| ())
+            .map_err(|e| format!("Failed to write backup: {}", e).into())
+    }
+}
+
+/// Unpacks into a staging directory first so a broken archive leaves `dir` untouched.
+fn unpack(archive: &Path, dir: &Path) -> Result<(), BlinkieError> {
+    let staging = dir.join(".restore");
+    let _ = fs::remove_dir_all(&staging);
+    fs::create_dir_all(&staging)
+        .map_err(|e| format!("Failed to create {}: {}", staging.display(), e))?;
+    let file = File::open(archive).map_err(|e| format!("Failed to open backup: {}", e))?;
+    if let Err(e) = tar::Archive::new(GzDecoder::new(file)).unpack(&staging) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(format!("Failed to unpack backup: {}", e).into());
+    }
+
+    let entries =
+        fs::read_dir(&staging).map_err(|e| format!("Failed to read backup contents: {}", e))?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let dest = dir.join(entry.file_name());
+        if dest.exists() {
+            let mut previous = dest.clone().into_os_string();
+            previous.push(".pre-restore");
+            let previous = PathBuf::from(previous);
+            if previous.is_dir() {
+                let _ = fs::remove_dir_all(&previous);
+            } else {
+                let _ = fs::remove_file(&previous);
+            }
+            fs::rename(&dest, &previous)
+                .map_err(|e| format!("Failed to move {} aside: {}", dest.display(), e))?;
+        }
+        fs::rename(entry.path(), &dest)
+            .map_err(|e| format!("Failed to restore {}: {}", dest.display(), e))?;
+    }
+    let _ = fs::remove_dir_all(&staging);
+    Ok(())
+}
+
+/// Backup names are unique, so the same scratch file is used again when a
+/// transfer is retried and the storage backend can resume it.
+fn scratch_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(name)
+}
+
+/**
+ * BackupTask
+ * Runs backups from the maintenance scheduler, e.g. nightly in a window.
+ */
+pub struct BackupTask {
+    pub manager: BackupManager,
+}
+
+impl MaintenanceTask for BackupTask {
+    fn name(&self) -> &str {
+        "backup"
+    }
+
+    fn run(&self) -> Result<(), BlinkieError> {
+        let name = self.manager.create(Utc::now())?;
+        log::info!("Created backup {}", name);
+        Ok(())
+    }
+}
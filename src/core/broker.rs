@@ -0,0 +1,150 @@
+use super::error::BlinkieError;
+use rumqttd::local::{LinkBuilder, LinkRx, LinkTx};
+use rumqttd::protocol::v4::V4;
+use rumqttd::protocol::v5::V5;
+use rumqttd::protocol::Protocol;
+use rumqttd::{ConnectionSettings, LinkType, Router, RouterConfig, Server, ServerSettings};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::thread;
+
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+const DEFAULT_MAX_PAYLOAD_SIZE: usize = 256 * 1024;
+
+/**
+ * BrokerConfig
+ * Settings of the embedded MQTT broker. Without users anyone on the
+ * network may connect.
+ */
+#[derive(Clone, Debug)]
+pub struct BrokerConfig {
+    /// Address of the MQTT 3.1.1 listener.
+    pub listen: SocketAddr,
+    /// Address of the MQTT 5 listener, if any.
+    pub listen_v5: Option<SocketAddr>,
+    /// Passwords by user name.
+    pub users: HashMap<String, String>,
+    pub max_connections: usize,
+    pub max_payload_size: usize,
+}
+
+impl BrokerConfig {
+    pub fn new(listen: SocketAddr) -> Self {
+        BrokerConfig {
+            listen,
+            listen_v5: None,
+            users: HashMap::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+
+/**
+ * EmbeddedBroker
+ * An MQTT 3.1.1 broker running inside the hub, so small installs don't need
+ * a separate Mosquitto. Network clients connect on the configured address;
+ * code in the hub attaches in-process through [`EmbeddedBroker::link`]
+ * without going through a socket.
+ */
+pub struct EmbeddedBroker {
+    config: BrokerConfig,
+    /// Registers a local connection with the router.
+    connect: Connect,
+}
+
+type Connect = Box<dyn Fn(&str) -> Result<(LinkTx, LinkRx), BlinkieError> + Send + Sync>;
+
+impl EmbeddedBroker {
+    /// Starts the router and the network listener on threads of their own.
+    pub fn start(config: BrokerConfig) -> Result<Self, BlinkieError> {
+        let router = Router::new(
+            0,
+            RouterConfig {
+                max_connections: config.max_connections,
+                max_outgoing_packet_count: 200,
+                max_segment_size: 100 * 1024 * 1024,
+                max_segment_count: 10,
+                custom_segment: None,
+                initialized_filters: None,
+                shared_subscriptions_strategy: Default::default(),
+            },
+        )
+        .spawn();
+
+        let settings = |listen: SocketAddr| ServerSettings {
+            name: format!("blinkie-{}", listen),
+            listen,
+            tls: None,
+            next_connection_delay_ms: 1,
+            connections: ConnectionSettings {
+                connection_timeout_ms: 60000,
+                max_payload_size: config.max_payload_size,
+                max_inflight_count: 100,
+                auth: (!config.users.is_empty()).then(|| config.users.clone()),
+                external_auth: None,
+                dynamic_filters: true,
+            },
+        };
+        serve(
+            Server::new(settings(config.listen), router.clone(), V4),
+            config.listen,
+        )?;
+        if let Some(listen_v5) = config.listen_v5 {
+            serve(
+                Server::new(settings(listen_v5), router.clone(), V5),
+                listen_v5,
+            )?;
+        }
+
+        Ok(EmbeddedBroker {
+            config,
+            connect: Box::new(move |client_id| {
+                LinkBuilder::new(client_id, router.clone())
+                    .build()
+                    .map(|(tx, rx, _ack)| (tx, rx))
+                    .map_err(|e| {
+                        BlinkieError::connection(format!(
+                            "Failed to attach '{}' to the MQTT broker: {}",
+                            client_id, e
+                        ))
+                    })
+            }),
+        })
+    }
+
+    pub fn listen(&self) -> SocketAddr {
+        self.config.listen
+    }
+
+    /// Connects an in-process client, e.g. a handler or bridge, as `client_id`.
+    pub fn link(&self, client_id: &str) -> Result<(LinkTx, LinkRx), BlinkieError> {
+        (self.connect)(client_id)
+    }
+}
+
+/// Runs a listener of the broker on a thread of its own.
+fn serve<P: Protocol + Clone + Send + 'static>(
+    mut server: Server<P>,
+    listen: SocketAddr,
+) -> Result<(), BlinkieError> {
+    // Bind here so a taken port fails startup instead of a background thread
+    std::net::TcpListener::bind(listen)
+        .map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+    thread::Builder::new()
+        .name(format!("mqtt-{}", listen))
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => return log::error!("Failed to start MQTT broker: {}", e),
+            };
+            if let Err(e) = runtime.block_on(server.start(LinkType::Remote)) {
+                log::error!("MQTT broker on {} stopped: {}", listen, e);
+            }
+        })
+        .map(|_| ())
+        .map_err(|e| format!("Failed to start MQTT broker: {}", e).into())
+}
@@ -0,0 +1,241 @@
+use super::error::BlinkieError;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+fn default_max_delay_ms() -> u64 {
+    1000
+}
+
+/**
+ * ChaosProfile
+ * How commands to devices are disturbed in chaos mode, to see how
+ * automations cope with a flaky network. Every command is failed with
+ * `fail_probability`, otherwise lost on the way with `drop_probability`,
+ * otherwise delayed by `min_delay_ms` to `max_delay_ms` with
+ * `delay_probability`. Only commands to `devices` and to devices of
+ * `handlers` are disturbed, all of them if both are empty. A `seed` makes
+ * the sequence of disturbances repeatable.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChaosProfile {
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub handlers: Vec<String>,
+    #[serde(default)]
+    pub fail_probability: f64,
+    #[serde(default)]
+    pub drop_probability: f64,
+    #[serde(default)]
+    pub delay_probability: f64,
+    #[serde(default)]
+    pub min_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl ChaosProfile {
+    fn validate(&self) -> Result<(), BlinkieError> {
+        for (name, probability) in [
+            ("fail_probability", self.fail_probability),
+            ("drop_probability", self.drop_probability),
+            ("delay_probability", self.delay_probability),
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(format!("{} must be between 0 and 1", name).into());
+            }
+        }
+        if self.min_delay_ms > self.max_delay_ms {
+            return Err("min_delay_ms is above max_delay_ms".into());
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Disturbance
+ * What chaos mode does to a command: fail it with a connection error,
+ * drop it as if it got lost while reporting it sent, or delay it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disturbance {
+    Fail,
+    Drop,
+    Delay(Duration),
+}
+
+/**
+ * ChaosStatus
+ * The active profile, if any, and how many commands it disturbed so far.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChaosStatus {
+    pub profile: Option<ChaosProfile>,
+    pub passed: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub delayed: u64,
+}
+
+/**
+ * Chaos
+ * Chaos mode: disturbs commands to devices as its profile says, until the
+ * profile is removed. The profile is kept on disk, so the hub starts with
+ * it again after a restart; never leave one on a hub that runs the house.
+ */
+pub struct Chaos {
+    path: PathBuf,
+    profile: RwLock<Option<ChaosProfile>>,
+    /// State of the random number generator, seeded from the profile.
+    rng: Mutex<u64>,
+    /// Handler that created each device, shared with the protocol registry.
+    device_handlers: Arc<RwLock<HashMap<String, String>>>,
+    passed: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    delayed: AtomicU64,
+}
+
+impl Chaos {
+    /// Opens the profile kept at `path`, if there is one, resolving device
+    /// handlers through `device_handlers`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        device_handlers: Arc<RwLock<HashMap<String, String>>>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let profile: Option<ChaosProfile> = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let profile: ChaosProfile = serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?;
+            profile.validate()?;
+            log::warn!(
+                "Chaos mode is on: commands to devices are disturbed as {} says",
+                path.display()
+            );
+            Some(profile)
+        } else {
+            None
+        };
+        let chaos = Chaos {
+            path,
+            profile: RwLock::new(None),
+            rng: Mutex::new(0),
+            device_handlers,
+            passed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            delayed: AtomicU64::new(0),
+        };
+        chaos.apply(profile);
+        Ok(chaos)
+    }
+
+    pub fn status(&self) -> ChaosStatus {
+        ChaosStatus {
+            profile: self.profile.read().unwrap().clone(),
+            passed: self.passed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Turns chaos mode on with `profile`, or off without one, starting
+    /// the counts and the profile's sequence of disturbances over.
+    pub fn set_profile(&self, profile: Option<ChaosProfile>) -> Result<(), BlinkieError> {
+        match &profile {
+            Some(profile) => {
+                profile.validate()?;
+                let contents = serde_json::to_string_pretty(profile).map_err(|e| {
+                    BlinkieError::serialization(format!("Failed to serialize chaos profile: {}", e))
+                })?;
+                let tmp_path = self.path.with_extension("tmp");
+                fs::write(&tmp_path, contents)
+                    .and_then(|_| fs::rename(&tmp_path, &self.path))
+                    .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+            }
+            None if self.path.exists() => fs::remove_file(&self.path)
+                .map_err(|e| format!("Failed to remove {}: {}", self.path.display(), e))?,
+            None => {}
+        }
+        self.apply(profile);
+        Ok(())
+    }
+
+    fn apply(&self, profile: Option<ChaosProfile>) {
+        let seed = profile
+            .as_ref()
+            .and_then(|profile| profile.seed)
+            .unwrap_or_else(|| RandomState::new().hash_one(0u64));
+        *self.rng.lock().unwrap() = seed;
+        for count in [&self.passed, &self.failed, &self.dropped, &self.delayed] {
+            count.store(0, Ordering::Relaxed);
+        }
+        *self.profile.write().unwrap() = profile;
+    }
+
+    /// How to disturb a command to `device_id`, `None` to let it through.
+    pub fn disturb(&self, device_id: &str) -> Option<Disturbance> {
+        let profile = self.profile.read().unwrap();
+        let profile = profile.as_ref()?;
+        let targeted = (profile.devices.is_empty() && profile.handlers.is_empty())
+            || profile.devices.iter().any(|id| id == device_id)
+            || self
+                .device_handlers
+                .read()
+                .unwrap()
+                .get(device_id)
+                .is_some_and(|handler| profile.handlers.contains(handler));
+        if !targeted {
+            return None;
+        }
+        let mut rng = self.rng.lock().unwrap();
+        let (disturbance, count) = if next_f64(&mut rng) < profile.fail_probability {
+            (Some(Disturbance::Fail), &self.failed)
+        } else if next_f64(&mut rng) < profile.drop_probability {
+            (Some(Disturbance::Drop), &self.dropped)
+        } else if next_f64(&mut rng) < profile.delay_probability {
+            let spread = profile.max_delay_ms - profile.min_delay_ms;
+            let millis = profile.min_delay_ms + next_u64(&mut rng) % (spread + 1);
+            (
+                Some(Disturbance::Delay(Duration::from_millis(millis))),
+                &self.delayed,
+            )
+        } else {
+            (None, &self.passed)
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        disturbance
+    }
+}
+
+/// Error of a command failed by chaos mode.
+pub fn failure(device_id: &str) -> BlinkieError {
+    BlinkieError::connection(format!("Command to '{}' failed by chaos mode", device_id))
+}
+
+/// SplitMix64, small and good enough to pick disturbances.
+pub(crate) fn next_u64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Uniform in [0, 1).
+pub(crate) fn next_f64(state: &mut u64) -> f64 {
+    (next_u64(state) >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,489 @@
+use super::device::Command;
+use super::error::BlinkieError;
+use super::value::Color;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Bytes handled per chunk: one 128 bit register of `u8`, two of `u16`.
+/// Color math over frames, as written to LED strips and matrices with
+/// `Device::write_frame`, runs over fixed chunks in 16 bit arithmetic,
+/// which the compiler turns into SIMD on stable Rust: NEON on ARM boards,
+/// SSE2 or AVX2 on x86.
+pub const LANES: usize = 16;
+
+/// Bytes per pixel of a frame: packed 8 bit RGB.
+pub const PIXEL: usize = 3;
+
+/// `x / 255` rounded, for `x` up to `255 * 255`.
+#[inline(always)]
+fn div255(x: u16) -> u16 {
+    let x = x + 128;
+    (x + (x >> 8)) >> 8
+}
+
+#[inline(always)]
+fn mix(dst: u8, src: u8, alpha: u16) -> u8 {
+    div255(u16::from(src) * alpha + u16::from(dst) * (255 - alpha)) as u8
+}
+
+/**
+ * Gamma
+ * Gamma correction, so brightness steps look even on LEDs whose light
+ * output is linear in their duty cycle. A table lookup per byte beats
+ * computing powers in vector registers.
+ */
+#[derive(Clone, Debug)]
+pub struct Gamma {
+    table: [u8; 256],
+}
+
+impl Gamma {
+    pub fn new(gamma: f32) -> Self {
+        let mut table = [0; 256];
+        for (i, out) in table.iter_mut().enumerate() {
+            *out = ((i as f32 / 255.0).powf(gamma) * 255.0).round() as u8;
+        }
+        Gamma { table }
+    }
+
+    pub fn apply(&self, frame: &mut [u8]) {
+        for byte in frame {
+            *byte = self.table[usize::from(*byte)];
+        }
+    }
+
+    pub fn correct(&self, color: Rgb) -> Rgb {
+        let channel = |value: u8| self.table[usize::from(value)];
+        Rgb::new(
+            channel(color.red),
+            channel(color.green),
+            channel(color.blue),
+        )
+    }
+}
+
+impl Default for Gamma {
+    /// The usual gamma of 2.2.
+    fn default() -> Self {
+        Gamma::new(2.2)
+    }
+}
+
+/// Blends `src` over `dst` with `alpha`, 255 being `src` alone, over the
+/// bytes both frames have.
+pub fn blend(dst: &mut [u8], src: &[u8], alpha: u8) {
+    let alpha = u16::from(alpha);
+    let len = dst.len().min(src.len());
+    let (dst, src) = (&mut dst[..len], &src[..len]);
+    let mut dst_chunks = dst.chunks_exact_mut(LANES);
+    let mut src_chunks = src.chunks_exact(LANES);
+    for (d, s) in (&mut dst_chunks).zip(&mut src_chunks) {
+        let d: &mut [u8; LANES] = d.try_into().unwrap();
+        let s: &[u8; LANES] = s.try_into().unwrap();
+        for i in 0..LANES {
+            d[i] = mix(d[i], s[i], alpha);
+        }
+    }
+    for (d, s) in dst_chunks
+        .into_remainder()
+        .iter_mut()
+        .zip(src_chunks.remainder())
+    {
+        *d = mix(*d, *s, alpha);
+    }
+}
+
+/// Converts a frame of HSV pixels, hue going round the color circle from 0
+/// to 255, into `rgb`, over the pixels both frames have.
+pub fn hsv_to_rgb(hsv: &[u8], rgb: &mut [u8]) {
+    for (hsv, rgb) in hsv.chunks_exact(PIXEL).zip(rgb.chunks_exact_mut(PIXEL)) {
+        let (h, s, v) = (u16::from(hsv[0]), u16::from(hsv[1]), u16::from(hsv[2]));
+        let sextant = h * 6;
+        let f = sextant & 0xff;
+        let p = div255(v * (255 - s));
+        let q = div255(v * (255 - div255(s * f)));
+        let t = div255(v * (255 - div255(s * (255 - f))));
+        let (r, g, b) = match sextant >> 8 {
+            0 => (v, t, p),
+            1 => (q, v, p),
+            2 => (p, v, t),
+            3 => (p, q, v),
+            4 => (t, p, v),
+            _ => (v, p, q),
+        };
+        rgb[0] = r as u8;
+        rgb[1] = g as u8;
+        rgb[2] = b as u8;
+    }
+}
+
+/**
+ * Rgb
+ * A color, written `#rrggbb`, black by default.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Rgb {
+    pub fn new(red: u8, green: u8, blue: u8) -> Self {
+        Rgb { red, green, blue }
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl FromStr for Rgb {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim().trim_start_matches('#');
+        let channel = |at: usize| {
+            hex.get(at..at + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+        };
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(red), Some(green), Some(blue)) => Ok(Rgb::new(red, green, blue)),
+            _ => Err(format!("Invalid color '{}', expected #rrggbb", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Rgb {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Rgb> for String {
+    fn from(color: Rgb) -> Self {
+        color.to_string()
+    }
+}
+
+impl From<Color> for Rgb {
+    fn from(color: Color) -> Self {
+        Rgb::new(color.r, color.g, color.b)
+    }
+}
+
+impl From<Rgb> for Color {
+    fn from(color: Rgb) -> Self {
+        Color {
+            r: color.red,
+            g: color.green,
+            b: color.blue,
+        }
+    }
+}
+
+/// sRGB channel from 0 to 1 to linear light.
+fn linear(channel: f64) -> f64 {
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Linear light from 0 to 1 to an sRGB channel.
+fn companded(channel: f64) -> f64 {
+    if channel <= 0.003_130_8 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn channel(value: f64) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/**
+ * Hsv
+ * A color as hue in degrees from 0 to 360, and saturation and value from
+ * 0 to 1.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hsv {
+    pub hue: f64,
+    pub saturation: f64,
+    pub value: f64,
+}
+
+impl From<Rgb> for Hsv {
+    fn from(color: Rgb) -> Self {
+        let [r, g, b] = [color.red, color.green, color.blue].map(|c| f64::from(c) / 255.0);
+        let max = r.max(g).max(b);
+        let delta = max - r.min(g).min(b);
+        let hue = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        Hsv {
+            hue,
+            saturation: if max == 0.0 { 0.0 } else { delta / max },
+            value: max,
+        }
+    }
+}
+
+impl From<Hsv> for Rgb {
+    fn from(color: Hsv) -> Self {
+        let (s, v) = (
+            color.saturation.clamp(0.0, 1.0),
+            color.value.clamp(0.0, 1.0),
+        );
+        let hue = color.hue.rem_euclid(360.0) / 60.0;
+        let chroma = v * s;
+        let x = chroma * (1.0 - (hue.rem_euclid(2.0) - 1.0).abs());
+        let (r, g, b) = match hue as u8 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = v - chroma;
+        Rgb::new(channel(r + m), channel(g + m), channel(b + m))
+    }
+}
+
+/**
+ * Xy
+ * A color as its CIE 1931 chromaticity, as Zigbee and Hue lights take it.
+ * It says nothing about brightness.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Xy {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Xy {
+    /// Chromaticity of daylight white, D65, which sRGB white is.
+    pub const WHITE: Xy = Xy {
+        x: 0.3127,
+        y: 0.3290,
+    };
+}
+
+impl From<Rgb> for Xy {
+    fn from(color: Rgb) -> Self {
+        let [r, g, b] = [color.red, color.green, color.blue].map(|c| linear(f64::from(c) / 255.0));
+        let x = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let z = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+        let sum = x + y + z;
+        if sum == 0.0 {
+            return Xy::WHITE;
+        }
+        Xy {
+            x: x / sum,
+            y: y / sum,
+        }
+    }
+}
+
+impl From<Xy> for Rgb {
+    /// The brightest sRGB color of the chromaticity, clipped to the sRGB
+    /// gamut.
+    fn from(color: Xy) -> Self {
+        if color.y <= 0.0 {
+            return Rgb::default();
+        }
+        let (x, y, z) = (color.x / color.y, 1.0, (1.0 - color.x - color.y) / color.y);
+        let rgb = [
+            3.2406 * x - 1.5372 * y - 0.4986 * z,
+            -0.9689 * x + 1.8758 * y + 0.0415 * z,
+            0.0557 * x - 0.2040 * y + 1.0570 * z,
+        ]
+        .map(|c| c.max(0.0));
+        let max = rgb.iter().copied().fold(0.0, f64::max);
+        if max == 0.0 {
+            return Rgb::default();
+        }
+        let [r, g, b] = rgb.map(|c| channel(companded(c / max)));
+        Rgb::new(r, g, b)
+    }
+}
+
+/**
+ * Mired
+ * A color temperature in mired, a million divided by kelvin, as lights
+ * take it: 153 is a cold 6500 K, 500 a warm 2000 K.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Mired(pub u16);
+
+impl Mired {
+    pub fn from_kelvin(kelvin: f64) -> Self {
+        Mired(
+            (1_000_000.0 / kelvin.max(1.0))
+                .round()
+                .min(f64::from(u16::MAX)) as u16,
+        )
+    }
+
+    pub fn kelvin(self) -> f64 {
+        1_000_000.0 / f64::from(self.0.max(1))
+    }
+
+    /// Color temperature nearest to `color`, by McCamy's approximation.
+    pub fn from_xy(color: Xy) -> Self {
+        let n = (color.x - 0.3320) / (0.1858 - color.y);
+        Mired::from_kelvin(449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33)
+    }
+}
+
+impl From<Mired> for Xy {
+    /// Chromaticity of a black body at the temperature, by the cubic
+    /// approximation of Kim et al., good from 1667 to 25000 K.
+    fn from(mired: Mired) -> Self {
+        let t = mired.kelvin().clamp(1667.0, 25_000.0);
+        let (t2, t3) = (t * t, t * t * t);
+        let x = if t <= 4000.0 {
+            -0.266_123_9e9 / t3 - 0.234_358_9e6 / t2 + 0.877_695_6e3 / t + 0.179_910
+        } else {
+            -3.025_846_9e9 / t3 + 2.107_037_9e6 / t2 + 0.222_634_7e3 / t + 0.240_390
+        };
+        let (x2, x3) = (x * x, x * x * x);
+        let y = if t <= 2222.0 {
+            -1.106_381_4 * x3 - 1.348_110_20 * x2 + 2.185_558_32 * x - 0.202_196_83
+        } else if t <= 4000.0 {
+            -0.954_947_6 * x3 - 1.374_185_93 * x2 + 2.091_370_15 * x - 0.167_488_67
+        } else {
+            3.081_758_0 * x3 - 5.873_386_70 * x2 + 3.751_129_97 * x - 0.370_014_83
+        };
+        Xy { x, y }
+    }
+}
+
+/**
+ * ColorMode
+ * A way a light takes colors.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    Rgb,
+    Hsv,
+    Xy,
+    ColorTemperature,
+}
+
+/**
+ * LightColor
+ * A color to set a light to, in any of the color modes.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", content = "color", rename_all = "snake_case")]
+pub enum LightColor {
+    Rgb(Rgb),
+    Hsv(Hsv),
+    Xy(Xy),
+    ColorTemperature(Mired),
+}
+
+impl LightColor {
+    /// The color a command sets, if it sets one.
+    pub fn from_command(command: &Command) -> Option<Self> {
+        match command {
+            Command::SetColor(color) => Some(LightColor::Rgb(*color)),
+            Command::SetColorTemperature(mired) => {
+                Some(LightColor::ColorTemperature(Mired(*mired)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn mode(&self) -> ColorMode {
+        match self {
+            LightColor::Rgb(_) => ColorMode::Rgb,
+            LightColor::Hsv(_) => ColorMode::Hsv,
+            LightColor::Xy(_) => ColorMode::Xy,
+            LightColor::ColorTemperature(_) => ColorMode::ColorTemperature,
+        }
+    }
+
+    pub fn to_xy(&self) -> Xy {
+        match self {
+            LightColor::Rgb(color) => Xy::from(*color),
+            LightColor::Hsv(color) => Xy::from(Rgb::from(*color)),
+            LightColor::Xy(color) => *color,
+            LightColor::ColorTemperature(mired) => Xy::from(*mired),
+        }
+    }
+
+    pub fn to_rgb(&self) -> Rgb {
+        match self {
+            LightColor::Rgb(color) => *color,
+            LightColor::Hsv(color) => Rgb::from(*color),
+            color => Rgb::from(color.to_xy()),
+        }
+    }
+
+    /// The color in `mode`. Going through chromaticity drops brightness,
+    /// and a color in `ColorTemperature` is the nearest white.
+    pub fn convert(&self, mode: ColorMode) -> LightColor {
+        if self.mode() == mode {
+            return *self;
+        }
+        match mode {
+            ColorMode::Rgb => LightColor::Rgb(self.to_rgb()),
+            ColorMode::Hsv => LightColor::Hsv(Hsv::from(self.to_rgb())),
+            ColorMode::Xy => LightColor::Xy(self.to_xy()),
+            ColorMode::ColorTemperature => {
+                LightColor::ColorTemperature(Mired::from_xy(self.to_xy()))
+            }
+        }
+    }
+}
+
+/**
+ * ColorLight
+ * A light taking colors, implemented by devices of handlers that drive
+ * lights so color math lives here rather than in every handler: the
+ * light lists the color modes it takes natively, and blinkie converts
+ * colors into one of them before handing them over.
+ */
+pub trait ColorLight {
+    /// Color modes the light takes, the one it prefers first.
+    fn color_modes(&self) -> Vec<ColorMode>;
+    /// Sets the light to `color`, in one of its color modes.
+    fn apply_color(&mut self, color: LightColor) -> Result<(), BlinkieError>;
+}
+
+/// Sets `light` to `color`, in the mode of the color if the light takes it,
+/// else in the first mode it takes that shows colors. Lights taking color
+/// temperatures only refuse colors.
+pub fn set_color(light: &mut dyn ColorLight, color: LightColor) -> Result<(), BlinkieError> {
+    let modes = light.color_modes();
+    let mode = if modes.contains(&color.mode()) {
+        color.mode()
+    } else {
+        *modes
+            .iter()
+            .find(|mode| **mode != ColorMode::ColorTemperature)
+            .ok_or_else(|| format!("Light takes no color mode for {:?}", color.mode()))?
+    };
+    light.apply_color(color.convert(mode))
+}
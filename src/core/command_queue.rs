@@ -0,0 +1,426 @@
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::memory::{CacheMetrics, MemoryLimits};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Name of the custom event published when a command is given up on,
+/// carrying `device_id`, `command`, `attempts` and `error`.
+pub const DEAD_LETTER_EVENT: &str = "command_dead_letter";
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+/**
+ * RetryPolicy
+ * How often a command failing for a transient reason, a connection that
+ * dropped or a device that didn't answer in time, is tried again. The
+ * wait starts at `initial_backoff_ms` and grows by `multiplier` after
+ * every attempt, up to `max_backoff_ms`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: default_retries(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            multiplier: default_multiplier(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `retry`, counted from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let millis = self.initial_backoff_ms as f64 * self.multiplier.powi(retry as i32);
+        Duration::from_millis(millis.min(self.max_backoff_ms as f64) as u64)
+    }
+
+    fn validate(&self, name: &str) -> Result<(), BlinkieError> {
+        if self.multiplier < 1.0 {
+            return Err(format!("Retry policy of {} needs a multiplier of 1 or more", name).into());
+        }
+        if self.initial_backoff_ms > self.max_backoff_ms {
+            return Err(format!(
+                "Retry policy of {} has initial_backoff_ms above max_backoff_ms",
+                name
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/**
+ * QueueConfig
+ * The retry policy of every device, unless it has one of its own.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueueConfig {
+    #[serde(default)]
+    pub default: RetryPolicy,
+    #[serde(default)]
+    pub devices: HashMap<String, RetryPolicy>,
+}
+
+impl QueueConfig {
+    fn validate(&self) -> Result<(), BlinkieError> {
+        self.default.validate("all devices")?;
+        for (device_id, policy) in &self.devices {
+            policy.validate(&format!("'{}'", device_id))?;
+        }
+        Ok(())
+    }
+
+    pub fn policy(&self, device_id: &str) -> &RetryPolicy {
+        self.devices.get(device_id).unwrap_or(&self.default)
+    }
+}
+
+/**
+ * DeadLetter
+ * A command given up on after its last attempt failed.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub timestamp: DateTime<Utc>,
+    pub device_id: String,
+    pub command: String,
+    #[serde(default)]
+    pub parameters: Option<HashMap<String, String>>,
+    pub attempts: u32,
+    pub error: String,
+}
+
+/**
+ * QueueStatus
+ * Commands waiting for or being sent to each device, and the newest
+ * commands given up on.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueueStatus {
+    pub pending: HashMap<String, usize>,
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+/**
+ * CommandQueue
+ * Sends the commands to each device one after the other, trying those
+ * failing for a transient reason again with exponential backoff, so a
+ * short network blip doesn't lose them. Commands still failing after
+ * their last retry are reported on the bus as dead letters.
+ */
+pub struct CommandQueue {
+    path: PathBuf,
+    config: RwLock<QueueConfig>,
+    /// Turn of each device, held while one of its commands is sent.
+    turns: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    pending: Mutex<HashMap<String, usize>>,
+    /// Commands that may wait for a device at once; more are refused.
+    per_device: AtomicUsize,
+    rejected: AtomicU64,
+    /// Dead letters kept for the API, oldest dropped first.
+    dead_letters: Mutex<VecDeque<DeadLetter>>,
+    dead_letter_limit: AtomicUsize,
+    dropped: AtomicU64,
+    events: Arc<EventBus>,
+}
+
+impl CommandQueue {
+    /// Opens the retry policies configured at `path`.
+    pub fn open<P: Into<PathBuf>>(path: P, events: Arc<EventBus>) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            QueueConfig::default()
+        };
+        Ok(CommandQueue {
+            path,
+            config: RwLock::new(config),
+            turns: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            per_device: AtomicUsize::new(MemoryLimits::default().queue_per_device),
+            rejected: AtomicU64::new(0),
+            dead_letters: Mutex::new(VecDeque::new()),
+            dead_letter_limit: AtomicUsize::new(MemoryLimits::default().dead_letters),
+            dropped: AtomicU64::new(0),
+            events,
+        })
+    }
+
+    pub fn config(&self) -> QueueConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: QueueConfig) -> Result<(), BlinkieError> {
+        config.validate()?;
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize retry policies: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        QueueStatus {
+            pending: self.pending.lock().unwrap().clone(),
+            dead_letters: self.dead_letters.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+
+    /// Caps the commands waiting for each device and the dead letters kept.
+    pub fn limit(&self, per_device: usize, dead_letters: usize) {
+        self.per_device.store(per_device, Ordering::Relaxed);
+        self.dead_letter_limit
+            .store(dead_letters, Ordering::Relaxed);
+        self.trim(&mut self.dead_letters.lock().unwrap());
+    }
+
+    /// Metrics of the commands waiting, all devices together, and of the
+    /// dead letters kept.
+    pub fn metrics(&self) -> (CacheMetrics, CacheMetrics) {
+        let queue = CacheMetrics {
+            entries: self.pending.lock().unwrap().values().sum(),
+            limit: self.per_device.load(Ordering::Relaxed),
+            evicted: self.rejected.load(Ordering::Relaxed),
+            coalesced: 0,
+        };
+        let dead_letters = CacheMetrics {
+            entries: self.dead_letters.lock().unwrap().len(),
+            limit: self.dead_letter_limit.load(Ordering::Relaxed),
+            evicted: self.dropped.load(Ordering::Relaxed),
+            coalesced: 0,
+        };
+        (queue, dead_letters)
+    }
+
+    fn trim(&self, dead_letters: &mut VecDeque<DeadLetter>) {
+        while dead_letters.len() > self.dead_letter_limit.load(Ordering::Relaxed) {
+            dead_letters.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Forgets the dead letters kept so far.
+    pub fn clear_dead_letters(&self) {
+        self.dead_letters.lock().unwrap().clear();
+    }
+
+    /// Sends `command` to `device_id` with `send` once the device's
+    /// earlier commands are done, trying again as its retry policy says.
+    /// Refused while too many commands wait for the device.
+    pub async fn send<T, F, Fut>(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+        mut send: F,
+    ) -> Result<T, BlinkieError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, BlinkieError>>,
+    {
+        let turn = self
+            .turns
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_default()
+            .clone();
+        let _pending = self.queued(device_id)?;
+        let _turn = turn.lock().await;
+        let mut attempts = 0;
+        let result = loop {
+            let result = send().await;
+            attempts += 1;
+            match self.retry_after(device_id, attempts, &result) {
+                Some(backoff) => tokio::time::sleep(backoff).await,
+                None => break result,
+            }
+        };
+        self.settle(device_id, command, parameters, attempts, result)
+    }
+
+    /// Sends like `send`, blocking the calling thread while backing off.
+    /// Doesn't wait for the device's earlier commands, for callers that
+    /// can't await.
+    pub fn send_blocking<T, F>(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+        mut send: F,
+    ) -> Result<T, BlinkieError>
+    where
+        F: FnMut() -> Result<T, BlinkieError>,
+    {
+        let _pending = self.queued(device_id)?;
+        let mut attempts = 0;
+        let result = loop {
+            let result = send();
+            attempts += 1;
+            match self.retry_after(device_id, attempts, &result) {
+                Some(backoff) => std::thread::sleep(backoff),
+                None => break result,
+            }
+        };
+        self.settle(device_id, command, parameters, attempts, result)
+    }
+
+    /// Wait before trying again after `attempts` attempts ended in
+    /// `result`, `None` if it is final.
+    fn retry_after<T>(
+        &self,
+        device_id: &str,
+        attempts: u32,
+        result: &Result<T, BlinkieError>,
+    ) -> Option<Duration> {
+        let Err(e) = result else {
+            return None;
+        };
+        if !transient(e) {
+            return None;
+        }
+        let config = self.config.read().unwrap();
+        let policy = config.policy(device_id);
+        if attempts > policy.retries {
+            return None;
+        }
+        let backoff = policy.backoff(attempts - 1);
+        log::warn!(
+            "Command to '{}' failed, trying again in {} ms: {}",
+            device_id,
+            backoff.as_millis(),
+            e
+        );
+        Some(backoff)
+    }
+
+    /// Counts a command to `device_id` as pending until the returned
+    /// guard is dropped, which also covers callers giving up waiting.
+    fn queued<'a>(&'a self, device_id: &'a str) -> Result<Pending<'a>, BlinkieError> {
+        let mut pending = self.pending.lock().unwrap();
+        let count = pending.entry(device_id.to_string()).or_default();
+        if *count >= self.per_device.load(Ordering::Relaxed) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(format!(
+                "Too many commands wait for '{}' already, try again later",
+                device_id
+            )
+            .into());
+        }
+        *count += 1;
+        Ok(Pending {
+            queue: self,
+            device_id,
+        })
+    }
+
+    /// Reports a command still failing for a transient reason after its
+    /// retries as a dead letter.
+    fn settle<T>(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+        attempts: u32,
+        result: Result<T, BlinkieError>,
+    ) -> Result<T, BlinkieError> {
+        let Err(e) = &result else {
+            return result;
+        };
+        if !transient(e) {
+            return result;
+        }
+        log::error!(
+            "Giving up on '{}' to '{}' after {} attempts: {}",
+            command,
+            device_id,
+            attempts,
+            e
+        );
+        let _ = self.events.publish(EventKind::Custom {
+            name: DEAD_LETTER_EVENT.to_string(),
+            data: HashMap::from([
+                ("device_id".to_string(), device_id.to_string()),
+                ("command".to_string(), command.to_string()),
+                ("attempts".to_string(), attempts.to_string()),
+                ("error".to_string(), e.to_string()),
+            ]),
+        });
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        dead_letters.push_back(DeadLetter {
+            timestamp: Utc::now(),
+            device_id: device_id.to_string(),
+            command: command.to_string(),
+            parameters: parameters.cloned(),
+            attempts,
+            error: e.to_string(),
+        });
+        self.trim(&mut dead_letters);
+        result
+    }
+}
+
+struct Pending<'a> {
+    queue: &'a CommandQueue,
+    device_id: &'a str,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.queue.pending.lock().unwrap();
+        if let Some(count) = pending.get_mut(self.device_id) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(self.device_id);
+            }
+        }
+    }
+}
+
+/// Whether a command failing with `e` may go through when tried again.
+fn transient(e: &BlinkieError) -> bool {
+    matches!(e, BlinkieError::Connection(_) | BlinkieError::Timeout(_))
+}
@@ -0,0 +1,541 @@
+use super::archive::ArchiveConfig;
+use super::backup::BackupConfig;
+use super::device::{Config, ProtocolRegistry};
+use super::discovery::DiscoveryConfig;
+use super::error::BlinkieError;
+use super::export::ExportConfig;
+use super::health::HealthConfig;
+use super::hot_keys::HotKeyConfig;
+use super::i18n::LocaleSettings;
+use super::maintenance::MaintenanceConfig;
+use super::memory::MemoryLimits;
+use super::pacing::FramePacingConfig;
+use super::retention::RetentionPolicy;
+use crate::automation::rule::StalePolicy;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use yaml_rust2::yaml::Hash;
+use yaml_rust2::{Yaml, YamlEmitter, YamlLoader};
+
+/// Data directory of a config file that doesn't name one, next to the file.
+const DEFAULT_DATA_DIR: &str = "data";
+
+/**
+ * Settings
+ * App-level settings of a config file, the ones `serve` also takes as flags.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Only allow viewing states and streaming events without a full-scope
+    /// API token.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How old state values may be before rules stop trusting them.
+    #[serde(default)]
+    pub stale: StalePolicy,
+    /// Seconds between writes of changed device states to the device
+    /// store, 30 if unset.
+    #[serde(default)]
+    pub flush_interval_secs: Option<u64>,
+    /// Caps of the caches the hub keeps in memory.
+    #[serde(default)]
+    pub memory: MemoryLimits,
+    /// How often devices are checked for being online.
+    #[serde(default)]
+    pub health: HealthConfig,
+    /// State keys changing too often to journal every change, e.g. power
+    /// readings once a second.
+    #[serde(default)]
+    pub hot_keys: HotKeyConfig,
+    /// How frames to LED outputs are timed to show together.
+    #[serde(default)]
+    pub frame_pacing: FramePacingConfig,
+    /// Whether to look for new devices on start.
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    /// Time zone days, schedules and the hours of aggregates and usage are
+    /// counted in, UTC if unset.
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+    /// When the built-in housekeeping jobs run.
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// How many days of history to keep per category. History is kept
+    /// until purged if unset.
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    /// Where nightly backups go, none if unset.
+    #[serde(default)]
+    pub backup: Option<BackupConfig>,
+    /// Where old events are archived to, none if unset.
+    #[serde(default)]
+    pub archive: Option<ArchiveConfig>,
+    /// Where state changes are exported to as CSV or Parquet, none if
+    /// unset.
+    #[serde(default)]
+    pub export: Option<ExportConfig>,
+    /// Language and units of notifications and announcements, unless a
+    /// user's preferences say otherwise.
+    #[serde(default)]
+    pub locale: LocaleSettings,
+}
+
+impl Settings {
+    pub fn timezone(&self) -> Tz {
+        self.timezone.unwrap_or(chrono_tz::UTC)
+    }
+}
+
+/**
+ * AppConfig
+ * A hub described by a TOML or YAML file: where it keeps its data, the
+ * built-in handlers to enable, its devices and app-level settings, e.g.
+ *
+ * ```toml
+ * data_dir = "/var/lib/blinkie"
+ * handlers = ["exec", "mqtt"]
+ *
+ * [settings]
+ * read_only = true
+ *
+ * [[devices]]
+ * id = "lamp"
+ * name = "Desk lamp"
+ * device_type = "Switch"
+ * supported_protocols = ["exec"]
+ * connection_details = { state_command = "lamp status", "command.on" = "lamp on" }
+ * ```
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppConfig {
+    /// Directory holding the journal, rules and other stores, relative to
+    /// the config file. `data` next to it if unset.
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    /// Built-in handlers to enable, by name. All of them if unset.
+    #[serde(default)]
+    pub handlers: Option<Vec<String>>,
+    #[serde(default)]
+    pub settings: Settings,
+    #[serde(default)]
+    pub devices: Vec<Config>,
+}
+
+impl AppConfig {
+    /// Reads the config at `path`, TOML or YAML by its extension, with its
+    /// data directory resolved against the file's directory. Reports every
+    /// device it can't read, not just the first.
+    pub fn load(path: &Path) -> Result<Self, BlinkieError> {
+        let mut config = Self::read(path)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        config.data_dir = Some(
+            base.join(
+                config
+                    .data_dir
+                    .take()
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR)),
+            ),
+        );
+        Ok(config)
+    }
+
+    /// Reads the config at `path` as written, its data directory left
+    /// relative.
+    pub fn read(path: &Path) -> Result<Self, BlinkieError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let value = match format(path)? {
+            "toml" => toml::from_str::<Value>(&contents).map_err(|e| e.to_string()),
+            "yaml" => parse_yaml(&contents),
+            _ => serde_json::from_str::<Value>(&contents).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+        })?;
+        Self::from_value(path, value)
+    }
+
+    /// Reads a config meant for `path` from its JSON form, e.g. the body
+    /// of an API request.
+    pub fn from_value(path: &Path, value: Value) -> Result<Self, BlinkieError> {
+        let Value::Object(mut fields) = value else {
+            return Err(format!("Failed to parse {}: not a table", path.display()).into());
+        };
+
+        let mut problems = Vec::new();
+        let entries = match fields.remove("devices") {
+            Some(Value::Array(entries)) => entries,
+            Some(Value::Null) | None => Vec::new(),
+            Some(_) => {
+                problems.push("devices: expected a list of devices".to_string());
+                Vec::new()
+            }
+        };
+        let mut config = serde_json::from_value::<AppConfig>(Value::Object(fields))
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        for (index, entry) in entries.into_iter().enumerate() {
+            let id = entry
+                .get("id")
+                .and_then(Value::as_str)
+                .map(|id| format!(" ('{}')", id))
+                .unwrap_or_default();
+            match serde_json::from_value::<Config>(stringify_details(entry)) {
+                Ok(device) => config.devices.push(device),
+                Err(e) => problems.push(format!("devices[{}]{}: {}", index, id, e)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(invalid(path, &problems).into());
+        }
+        Ok(config)
+    }
+
+    /// The config as a file at `path` in the format of its extension,
+    /// leaving out settings at their defaults. Comments of the file it was
+    /// read from are not kept.
+    pub fn render(&self, path: &Path) -> Result<String, BlinkieError> {
+        let mut value = serde_json::to_value(self)
+            .map(strip_nulls)
+            .map_err(|e| BlinkieError::serialization(e.to_string()))?;
+        let defaults = serde_json::to_value(Settings::default()).map(strip_nulls);
+        if let (Some(Value::Object(settings)), Ok(Value::Object(defaults))) =
+            (value.get_mut("settings"), defaults)
+        {
+            settings.retain(|key, setting| defaults.get(key) != Some(setting));
+        }
+        if let Value::Object(fields) = &mut value {
+            fields.retain(|key, field| {
+                key != "settings"
+                    || field
+                        .as_object()
+                        .is_some_and(|settings| !settings.is_empty())
+            });
+        }
+        match format(path)? {
+            "toml" => toml::to_string_pretty(&value)
+                .map_err(|e| BlinkieError::serialization(e.to_string())),
+            "yaml" => {
+                let mut output = String::new();
+                YamlEmitter::new(&mut output)
+                    .dump(&json_to_yaml(&value))
+                    .map_err(|e| BlinkieError::serialization(e.to_string()))?;
+                let output = output.strip_prefix("---\n").unwrap_or(&output);
+                Ok(format!("{}\n", output))
+            }
+            _ => serde_json::to_string_pretty(&value)
+                .map(|json| format!("{}\n", json))
+                .map_err(|e| BlinkieError::serialization(e.to_string())),
+        }
+    }
+
+    /// Problems keeping the devices from being created with the handlers
+    /// in `registry`: duplicate or empty ids, protocols nothing handles,
+    /// missing preferred handlers and connection details their handler
+    /// refuses.
+    pub fn validate(&self, registry: &ProtocolRegistry) -> Vec<String> {
+        let mut known: Vec<String> = registry.handlers.read().unwrap().keys().cloned().collect();
+        known.sort();
+        let mut problems = Vec::new();
+        let mut ids = HashSet::new();
+        for (index, device) in self.devices.iter().enumerate() {
+            let at = format!("devices[{}] ('{}')", index, device.id);
+            if device.id.is_empty() {
+                problems.push(format!("devices[{}]: empty device id", index));
+            } else if !ids.insert(device.id.as_str()) {
+                problems.push(format!("{}: duplicate device id", at));
+            }
+            if device.supported_protocols.is_empty() {
+                problems.push(format!("{}: no supported_protocols", at));
+                continue;
+            }
+            let unknown: Vec<&str> = device
+                .supported_protocols
+                .iter()
+                .filter(|protocol| !known.contains(protocol))
+                .map(String::as_str)
+                .collect();
+            if unknown.len() == device.supported_protocols.len() {
+                problems.push(format!(
+                    "{}: unknown protocol '{}', known protocols: {}",
+                    at,
+                    unknown.join("', '"),
+                    known.join(", ")
+                ));
+                continue;
+            }
+            let checked = registry
+                .handler_for(device)
+                .and_then(|handler| handler.read().unwrap().validate(device));
+            if let Err(e) = checked {
+                problems.push(format!("{}: {}", at, e));
+            }
+        }
+        if let Err(e) = self.settings.memory.validate() {
+            problems.push(format!("settings.memory: {}", e));
+        }
+        if let Err(e) = self.settings.health.validate() {
+            problems.push(format!("settings.health: {}", e));
+        }
+        if let Err(e) = self.settings.hot_keys.validate() {
+            problems.push(format!("settings.hot_keys: {}", e));
+        }
+        if let Err(e) = self.settings.maintenance.validate() {
+            problems.push(format!("settings.maintenance: {}", e));
+        }
+        if let Some(Err(e)) = self
+            .settings
+            .retention
+            .as_ref()
+            .map(RetentionPolicy::validate)
+        {
+            problems.push(format!("settings.retention: {}", e));
+        }
+        if let Some(Err(e)) = self.settings.archive.as_ref().map(ArchiveConfig::validate) {
+            problems.push(format!("settings.archive: {}", e));
+        }
+        if let Err(e) = self.settings.frame_pacing.validate() {
+            problems.push(format!("settings.frame_pacing: {}", e));
+        }
+        if let Err(e) = self.settings.discovery.validate() {
+            problems.push(format!("settings.discovery: {}", e));
+        }
+        problems
+    }
+}
+
+/**
+ * ConfigChanges
+ * What applying a config or rule file changes: the devices or rules it
+ * adds, updates and removes, by id, and the changed settings that only
+ * take effect on the next start.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConfigChanges {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+impl ConfigChanges {
+    /// Compares the items of `old` and `new` with the same `id`.
+    pub fn compare<T, F>(old: &[T], new: &[T], id: F) -> Self
+    where
+        T: Serialize,
+        F: Fn(&T) -> &str,
+    {
+        let mut changes = ConfigChanges::default();
+        for item in new {
+            match old.iter().find(|old| id(old) == id(item)) {
+                None => changes.added.push(id(item).to_string()),
+                Some(old) if serde_json::to_value(old).ok() != serde_json::to_value(item).ok() => {
+                    changes.updated.push(id(item).to_string())
+                }
+                Some(_) => {}
+            }
+        }
+        changes.removed = old
+            .iter()
+            .filter(|old| !new.iter().any(|item| id(item) == id(old)))
+            .map(|old| id(old).to_string())
+            .collect();
+        changes
+    }
+
+    pub fn between(old: &AppConfig, new: &AppConfig) -> Self {
+        let mut changes = Self::compare(&old.devices, &new.devices, |device| &device.id);
+        let restart = [
+            ("data_dir", old.data_dir != new.data_dir),
+            ("handlers", old.handlers != new.handlers),
+            (
+                "settings.read_only",
+                old.settings.read_only != new.settings.read_only,
+            ),
+            (
+                "settings.flush_interval_secs",
+                old.settings.flush_interval_secs != new.settings.flush_interval_secs,
+            ),
+            (
+                "settings.timezone",
+                old.settings.timezone != new.settings.timezone,
+            ),
+            (
+                "settings.maintenance.log",
+                old.settings.maintenance.log != new.settings.maintenance.log,
+            ),
+            (
+                "settings.backup",
+                old.settings.backup != new.settings.backup,
+            ),
+        ];
+        changes.restart_required = restart
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(field, _)| field.to_string())
+            .collect();
+        changes
+    }
+}
+
+/**
+ * ConfigPreview
+ * What writing a config would do: the problems keeping it from being
+ * applied, the changes it makes and how the file would change, see
+ * `line_diff`.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConfigPreview {
+    pub problems: Vec<String>,
+    pub changes: ConfigChanges,
+    pub diff: Vec<String>,
+}
+
+/// Format of the config file at `path` by its extension: toml, yaml or
+/// json.
+fn format(path: &Path) -> Result<&'static str, BlinkieError> {
+    match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+        "toml" => Ok("toml"),
+        "yaml" | "yml" => Ok("yaml"),
+        "json" => Ok("json"),
+        other => Err(format!(
+            "Unknown config format '{}', expected toml, yaml, yml or json",
+            other
+        )
+        .into()),
+    }
+}
+
+/// Error listing every problem found in the config at `path`.
+pub fn invalid(path: &Path, problems: &[String]) -> String {
+    let mut message = format!("Invalid config {}:", path.display());
+    for problem in problems {
+        message.push_str("\n  - ");
+        message.push_str(problem);
+    }
+    message
+}
+
+/// Turns numbers and booleans in a device's connection details into the
+/// strings handlers read, so `port = 1883` works as well as `port = "1883"`.
+fn stringify_details(mut entry: Value) -> Value {
+    if let Some(Value::Object(details)) = entry.get_mut("connection_details") {
+        for value in details.values_mut() {
+            match value {
+                Value::Number(number) => *value = Value::String(number.to_string()),
+                Value::Bool(flag) => *value = Value::String(flag.to_string()),
+                _ => {}
+            }
+        }
+    }
+    entry
+}
+
+/// Drops unset fields, which TOML has no way to write.
+fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, strip_nulls(value)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(strip_nulls).collect()),
+        value => value,
+    }
+}
+
+/// Lines of `old` and `new`, each prefixed with `-` if only `old` has
+/// it, `+` if only `new` has it and a space if both have it.
+pub fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // Length of the longest common subsequence of the lines from i and j on
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(format!(" {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            lines.push(format!("+{}", new[j]));
+            j += 1;
+        } else {
+            lines.push(format!("-{}", old[i]));
+            i += 1;
+        }
+    }
+    lines
+}
+
+/// Parses the first document of a YAML file.
+fn parse_yaml(contents: &str) -> Result<Value, String> {
+    let documents = YamlLoader::load_from_str(contents).map_err(|e| e.to_string())?;
+    Ok(documents.first().map(yaml_to_json).unwrap_or(Value::Null))
+}
+
+fn yaml_to_json(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Real(real) => real
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or_else(|| Value::String(real.clone())),
+        Yaml::Integer(integer) => Value::from(*integer),
+        Yaml::String(string) => Value::String(string.clone()),
+        Yaml::Boolean(flag) => Value::Bool(*flag),
+        Yaml::Array(items) => Value::Array(items.iter().map(yaml_to_json).collect()),
+        Yaml::Hash(hash) => Value::Object(
+            hash.iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Yaml::String(key) => key.clone(),
+                        other => match yaml_to_json(other) {
+                            Value::String(key) => key,
+                            key => key.to_string(),
+                        },
+                    };
+                    (key, yaml_to_json(value))
+                })
+                .collect::<Map<String, Value>>(),
+        ),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => Value::Null,
+    }
+}
+
+fn json_to_yaml(value: &Value) -> Yaml {
+    match value {
+        Value::Null => Yaml::Null,
+        Value::Bool(flag) => Yaml::Boolean(*flag),
+        Value::Number(number) => match number.as_i64() {
+            Some(integer) => Yaml::Integer(integer),
+            None => Yaml::Real(number.to_string()),
+        },
+        Value::String(string) => Yaml::String(string.clone()),
+        Value::Array(items) => Yaml::Array(items.iter().map(json_to_yaml).collect()),
+        Value::Object(fields) => Yaml::Hash(
+            fields
+                .iter()
+                .map(|(key, value)| (Yaml::String(key.clone()), json_to_yaml(value)))
+                .collect::<Hash>(),
+        ),
+    }
+}
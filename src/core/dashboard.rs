@@ -0,0 +1,262 @@
+use super::aggregate::Resolution;
+use super::error::BlinkieError;
+use super::ui::UiLayout;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/**
+ * Stack Directions
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StackDirection {
+    #[default]
+    Vertical,
+    Horizontal,
+}
+
+fn default_hours() -> u32 {
+    24
+}
+
+/**
+ * Cards
+ * Building blocks of a dashboard view. Cards only reference devices, areas
+ * and scenes by id; UIs look up everything else in the UI manifest.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Card {
+    /// A single device with controls for its state.
+    Device {
+        device_id: String,
+        #[serde(default)]
+        title: Option<String>,
+        /// State keys to show; all keys if empty.
+        #[serde(default)]
+        keys: Vec<String>,
+    },
+    /// A list of devices.
+    Entities {
+        #[serde(default)]
+        title: Option<String>,
+        devices: Vec<String>,
+    },
+    /// Every device of an area.
+    Area {
+        area_id: String,
+        #[serde(default)]
+        title: Option<String>,
+    },
+    /// A numeric device state over the last `hours`, drawn from aggregates.
+    Graph {
+        device_id: String,
+        key: String,
+        #[serde(default)]
+        title: Option<String>,
+        resolution: Resolution,
+        #[serde(default = "default_hours")]
+        hours: u32,
+    },
+    Scene {
+        scene_id: String,
+    },
+    Markdown {
+        content: String,
+    },
+    /// Cards laid out next to or below each other.
+    Stack {
+        #[serde(default)]
+        direction: StackDirection,
+        cards: Vec<Card>,
+    },
+}
+
+impl Card {
+    fn validate(&self, device_ids: &[String], layout: &UiLayout) -> Result<(), BlinkieError> {
+        let device = |id: &String| -> Result<(), BlinkieError> {
+            if device_ids.contains(id) {
+                Ok(())
+            } else {
+                Err(format!("Unknown device '{}'", id).into())
+            }
+        };
+        match self {
+            Card::Device { device_id, .. } => device(device_id),
+            Card::Entities { devices, .. } => {
+                if devices.is_empty() {
+                    return Err("Entities card lists no devices".into());
+                }
+                devices.iter().try_for_each(device)
+            }
+            Card::Area { area_id, .. } => {
+                if layout.areas.iter().any(|area| &area.id == area_id) {
+                    Ok(())
+                } else {
+                    Err(format!("Unknown area '{}'", area_id).into())
+                }
+            }
+            Card::Graph {
+                device_id, hours, ..
+            } => {
+                if *hours == 0 {
+                    return Err("Graph card must cover at least one hour".into());
+                }
+                device(device_id)
+            }
+            Card::Scene { scene_id } => {
+                if layout.scenes.iter().any(|scene| &scene.id == scene_id) {
+                    Ok(())
+                } else {
+                    Err(format!("Unknown scene '{}'", scene_id).into())
+                }
+            }
+            Card::Markdown { .. } => Ok(()),
+            Card::Stack { cards, .. } => cards
+                .iter()
+                .try_for_each(|card| card.validate(device_ids, layout)),
+        }
+    }
+}
+
+/**
+ * View
+ * A tab of a dashboard.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct View {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub icon: Option<String>,
+    #[serde(default)]
+    pub cards: Vec<Card>,
+}
+
+/**
+ * Dashboard
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Dashboard {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub icon: Option<String>,
+    pub views: Vec<View>,
+}
+
+impl Dashboard {
+    /// Checks ids and that every card references a known device, area or scene.
+    pub fn validate(&self, device_ids: &[String], layout: &UiLayout) -> Result<(), BlinkieError> {
+        if self.id.is_empty() {
+            return Err("Dashboard id must not be empty".into());
+        }
+        let mut seen = Vec::new();
+        for view in &self.views {
+            if view.id.is_empty() || seen.contains(&&view.id) {
+                return Err(format!(
+                    "Invalid view id '{}' in dashboard '{}': must be non-empty and unique",
+                    view.id, self.id
+                )
+                .into());
+            }
+            seen.push(&view.id);
+            for (index, card) in view.cards.iter().enumerate() {
+                card.validate(device_ids, layout).map_err(|e| {
+                    format!("Card {} of view '{}' is invalid: {}", index + 1, view.id, e)
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * DashboardStore
+ * Holds dashboard configurations. When opened from a file, every change is
+ * written back.
+ */
+pub struct DashboardStore {
+    path: Option<PathBuf>,
+    dashboards: RwLock<BTreeMap<String, Dashboard>>,
+}
+
+impl Default for DashboardStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DashboardStore {
+    /// Creates a store that only keeps dashboards in memory.
+    pub fn new() -> Self {
+        DashboardStore {
+            path: None,
+            dashboards: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Opens a persistent store, loading previously saved dashboards if the file exists.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let dashboards = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(DashboardStore {
+            path: Some(path),
+            dashboards: RwLock::new(dashboards),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Dashboard> {
+        self.dashboards.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Dashboard> {
+        self.dashboards.read().unwrap().get(id).cloned()
+    }
+
+    /// Validates a dashboard and adds or replaces it.
+    pub fn save(
+        &self,
+        dashboard: Dashboard,
+        device_ids: &[String],
+        layout: &UiLayout,
+    ) -> Result<(), BlinkieError> {
+        dashboard.validate(device_ids, layout)?;
+        let mut dashboards = self.dashboards.write().unwrap();
+        dashboards.insert(dashboard.id.clone(), dashboard);
+        self.persist(&dashboards)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<Dashboard>, BlinkieError> {
+        let mut dashboards = self.dashboards.write().unwrap();
+        let removed = dashboards.remove(id);
+        if removed.is_some() {
+            self.persist(&dashboards)?;
+        }
+        Ok(removed)
+    }
+
+    fn persist(&self, dashboards: &BTreeMap<String, Dashboard>) -> Result<(), BlinkieError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string_pretty(dashboards).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize dashboards: {}", e))
+        })?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+    }
+}
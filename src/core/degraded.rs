@@ -0,0 +1,129 @@
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Name of the custom event published when writes to a store start
+/// failing, carrying `store` and `error`.
+pub const DEGRADED_EVENT: &str = "storage_degraded";
+/// Name of the custom event published when a failing store takes writes
+/// again, carrying `store`.
+pub const RECOVERED_EVENT: &str = "storage_recovered";
+/// Store name of the event journal.
+pub const JOURNAL_STORE: &str = "journal";
+/// Store name of the device store, holding registered devices and states.
+pub const DEVICE_STORE: &str = "devices";
+
+/**
+ * StoreFailure
+ * Since when writes to a store fail, the last error, and how many writes
+ * wait in memory for it to come back.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoreFailure {
+    pub since: DateTime<Utc>,
+    pub error: String,
+    pub queued: usize,
+}
+
+impl StoreFailure {
+    pub fn new(error: &BlinkieError, queued: usize) -> Self {
+        StoreFailure {
+            since: Utc::now(),
+            error: error.to_string(),
+            queued,
+        }
+    }
+}
+
+/**
+ * StorageStatus
+ * Whether the hub runs degraded, and the stores failing it.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StorageStatus {
+    pub degraded: bool,
+    pub stores: BTreeMap<String, StoreFailure>,
+}
+
+/// Custom event telling `store` failed with `error`, or recovered without
+/// one.
+pub fn notice(store: &str, error: Option<&str>) -> EventKind {
+    let mut data = HashMap::from([("store".to_string(), store.to_string())]);
+    let name = match error {
+        Some(error) => {
+            data.insert("error".to_string(), error.to_string());
+            DEGRADED_EVENT
+        }
+        None => RECOVERED_EVENT,
+    };
+    EventKind::Custom {
+        name: name.to_string(),
+        data,
+    }
+}
+
+/**
+ * StorageHealth
+ * Keeps track of stores whose writes fail at runtime, while the hub keeps
+ * running in memory with their writes queued, and reports them failing
+ * and recovering on the bus. The event journal reports its own failures,
+ * see `EventBus::publish`.
+ */
+pub struct StorageHealth {
+    failures: Mutex<BTreeMap<String, StoreFailure>>,
+    events: Arc<EventBus>,
+}
+
+impl StorageHealth {
+    pub fn new(events: Arc<EventBus>) -> Self {
+        StorageHealth {
+            failures: Mutex::new(BTreeMap::new()),
+            events,
+        }
+    }
+
+    /// Reports how a write to `store` went, with `queued` writes left
+    /// waiting for it.
+    pub fn report<T>(&self, store: &str, result: &Result<T, BlinkieError>, queued: usize) {
+        let mut failures = self.failures.lock().unwrap();
+        match result {
+            Ok(_) if queued == 0 => {
+                if failures.remove(store).is_some() {
+                    log::info!("Writes to {} work again", store);
+                    let _ = self.events.publish(notice(store, None));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => match failures.get_mut(store) {
+                Some(failure) => {
+                    failure.error = e.to_string();
+                    failure.queued = queued;
+                }
+                None => {
+                    log::error!(
+                        "Writes to {} fail, keeping them in memory until it works again: {}",
+                        store,
+                        e
+                    );
+                    failures.insert(store.to_string(), StoreFailure::new(e, queued));
+                    let _ = self.events.publish(notice(store, Some(&e.to_string())));
+                }
+            },
+        }
+    }
+
+    /// The stores failing, the event journal among them.
+    pub fn status(&self) -> StorageStatus {
+        let mut stores = self.failures.lock().unwrap().clone();
+        if let Some(failure) = self.events.journal_failure() {
+            stores.insert(JOURNAL_STORE.to_string(), failure);
+        }
+        StorageStatus {
+            degraded: !stores.is_empty(),
+            stores,
+        }
+    }
+}
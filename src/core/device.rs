@@ -1,30 +1,130 @@
+use super::color::{self, ColorLight, LightColor, Rgb};
+use super::error::BlinkieError;
+use super::health;
+use super::job::JobHandle;
+use super::value::StateMap;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 
 /**
  * Device Interface
  * Represents a generic Device
  */
-pub trait Device {
+pub trait Device: Send + Sync {
     fn get_id(&self) -> &str;
     fn get_name(&self) -> &str;
-    fn get_state(&self) -> HashMap<String, String>;
-    fn set_state(&mut self, state: HashMap<String, String>);
+    fn get_state(&self) -> StateMap;
+    fn set_state(&mut self, state: StateMap);
     fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>);
+    /// Sends a command and returns what the device answered. Devices whose
+    /// protocol answers commands, e.g. with a measured value, override this;
+    /// the default sends the command and answers nothing.
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        self.send_cmd(command, parameters);
+        Ok(CommandResponse::default())
+    }
+    /// Starts `command` as a job that keeps running in the background,
+    /// reporting progress and its end through `job` and stopping once it is
+    /// cancelled. Returns `false` for commands that aren't jobs, which are
+    /// then sent as usual.
+    fn start_job(
+        &mut self,
+        _command: &str,
+        _parameters: Option<HashMap<String, String>>,
+        _job: JobHandle,
+    ) -> Result<bool, BlinkieError> {
+        Ok(false)
+    }
+    /// Class of the device, if the implementation knows it.
+    fn get_type(&self) -> Option<Type> {
+        None
+    }
+    /// Commands the device handles natively, if the implementation knows
+    /// them. Only devices that list them get emulated commands.
+    fn commands(&self) -> Option<Vec<String>> {
+        None
+    }
+    /// What the device can do. Implementations that know it override this;
+    /// the default infers it from the state keys the device reports and
+    /// the commands it lists.
+    fn capabilities(&self) -> Vec<Capability> {
+        Capability::infer(&self.get_state(), self.commands().as_deref())
+    }
+    /// The device as a light taking colors, if it is one.
+    fn color_light(&mut self) -> Option<&mut dyn ColorLight> {
+        None
+    }
+    /// Sends a typed command. Colors go to the device's `color_light`, if
+    /// it has one, in a color mode it takes. Handlers whose wire format has
+    /// a better translation than its name and parameters override this.
+    fn send(&mut self, command: &Command) -> Result<CommandResponse, BlinkieError> {
+        if let Some(color) = LightColor::from_command(command) {
+            if let Some(light) = self.color_light() {
+                color::set_color(light, color)?;
+                return Ok(CommandResponse::default());
+            }
+        }
+        self.command(command.name(), command.parameters())
+    }
+    /// Writes a frame of output as it is, e.g. the RGB bytes of every LED
+    /// of a strip, without going through commands and their events, for
+    /// effects updating many outputs at a high rate. The default refuses
+    /// frames.
+    fn write_frame(&mut self, _frame: &[u8]) -> Result<(), BlinkieError> {
+        Err(BlinkieError::unsupported_command(self.get_id(), "frame"))
+    }
 }
 
+/**
+ * CommandResponse
+ * What a device answered to a command, e.g. the active scene or a value
+ * measured on request. Empty for most commands.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandResponse {
+    #[serde(default)]
+    pub data: HashMap<String, String>,
+}
+
+impl CommandResponse {
+    pub fn new(data: HashMap<String, String>) -> Self {
+        CommandResponse { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(String::as_str)
+    }
+}
+
+/// Shared list of all devices known to the hub.
+pub type DeviceList = Arc<RwLock<Vec<Box<dyn Device>>>>;
+
 /**
  * Common Device Config
  * Holds metadata and connection information for devices.
  */
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
 pub struct Config {
     pub id: String,
     pub name: String,
     pub device_type: Type,
+    #[serde(default)]
     pub connection_details: HashMap<String, String>,
+    #[serde(default)]
     pub supported_protocols: Vec<String>,
+    #[serde(default)]
     pub preferred_handler: Option<String>,
 }
 
@@ -32,7 +132,8 @@ pub struct Config {
  * Device Types
  *
  */
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
 pub enum Type {
     Sensor,
     Actor,
@@ -41,10 +142,231 @@ pub enum Type {
     Cat,
 }
 
+/**
+ * Capability
+ * Something a device can do or measure, so rules, scenes and dashboards
+ * needn't guess it from state keys.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    OnOff,
+    Brightness,
+    ColorTemperature,
+    RgbColor,
+    Position,
+    Lock,
+    Temperature,
+    Humidity,
+    Pressure,
+    Illuminance,
+    Motion,
+    Contact,
+    Power,
+    Energy,
+    Battery,
+}
+
+impl Capability {
+    /// Capability a state key stands for, e.g. `brightness`, as handlers
+    /// and zigbee2mqtt name them.
+    pub fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "state" => Some(Capability::OnOff),
+            "brightness" => Some(Capability::Brightness),
+            "color_temp" | "color_temperature" => Some(Capability::ColorTemperature),
+            "color" | "rgb" | "color_xy" | "color_hs" | "hue" => Some(Capability::RgbColor),
+            "position" => Some(Capability::Position),
+            "lock_state" => Some(Capability::Lock),
+            "temperature" => Some(Capability::Temperature),
+            "humidity" => Some(Capability::Humidity),
+            "pressure" => Some(Capability::Pressure),
+            "illuminance" | "illuminance_lux" => Some(Capability::Illuminance),
+            "occupancy" | "motion" | "presence" => Some(Capability::Motion),
+            "contact" => Some(Capability::Contact),
+            "power" => Some(Capability::Power),
+            "energy" => Some(Capability::Energy),
+            "battery" => Some(Capability::Battery),
+            _ => None,
+        }
+    }
+
+    /// Capability a command gives a device, e.g. `set_brightness`.
+    fn from_command(command: &str) -> Option<Self> {
+        match command {
+            "turn_on" | "turn_off" | "toggle" => Some(Capability::OnOff),
+            "set_brightness" => Some(Capability::Brightness),
+            "set_color_temp" | "set_color_temperature" => Some(Capability::ColorTemperature),
+            "set_color" | "set_rgb" => Some(Capability::RgbColor),
+            "set_position" | "open" | "close" => Some(Capability::Position),
+            "lock" | "unlock" => Some(Capability::Lock),
+            _ => None,
+        }
+    }
+
+    /// Capabilities of the state `keys` and `commands` of a device, sorted
+    /// and without duplicates.
+    pub fn infer(state: &StateMap, commands: Option<&[String]>) -> Vec<Self> {
+        let mut capabilities: Vec<Capability> = state
+            .keys()
+            .filter_map(|key| Capability::from_key(key))
+            .chain(
+                commands
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|command| Capability::from_command(command)),
+            )
+            .collect();
+        capabilities.sort();
+        capabilities.dedup();
+        capabilities
+    }
+}
+
+/**
+ * Command
+ * A command as blinkie knows it, checked when it is built rather than by
+ * the device. Each one but `Custom` needs a capability of the device;
+ * handlers translate them into their wire format with `Device::send`, the
+ * default being the command name and parameters of `name` and
+ * `parameters`, e.g. `set_brightness` with `brightness=128`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", content = "value", rename_all = "snake_case")]
+pub enum Command {
+    TurnOn,
+    TurnOff,
+    Toggle,
+    /// Brightness from 0 to 255.
+    SetBrightness(u8),
+    /// Color temperature in mired.
+    SetColorTemperature(u16),
+    SetColor(Rgb),
+    /// Position of a cover in percent, 100 being open.
+    SetPosition(u8),
+    Open,
+    Close,
+    Lock,
+    Unlock,
+    /// Any other command, sent as it is.
+    Custom {
+        name: String,
+        parameters: Option<HashMap<String, String>>,
+    },
+}
+
+impl Command {
+    /// Reads a command sent by name, checking the parameters of the ones
+    /// blinkie knows. Known commands with more parameters than they take
+    /// stay `Custom`, e.g. `turn_on` with a `brightness`, as handlers may
+    /// know what to do with them.
+    pub fn parse(
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<Self, BlinkieError> {
+        let custom = |parameters| Command::Custom {
+            name: command.to_string(),
+            parameters,
+        };
+        let plain = match command {
+            "turn_on" => Some(Command::TurnOn),
+            "turn_off" => Some(Command::TurnOff),
+            "toggle" => Some(Command::Toggle),
+            "open" => Some(Command::Open),
+            "close" => Some(Command::Close),
+            "lock" => Some(Command::Lock),
+            "unlock" => Some(Command::Unlock),
+            _ => None,
+        };
+        if let Some(plain) = plain {
+            return Ok(match &parameters {
+                Some(parameters) if !parameters.is_empty() => custom(Some(parameters.clone())),
+                _ => plain,
+            });
+        }
+        let key = match command {
+            "set_brightness" => "brightness",
+            "set_color_temp" | "set_color_temperature" => "color_temp",
+            "set_color" | "set_rgb" => "color",
+            "set_position" => "position",
+            _ => return Ok(custom(parameters)),
+        };
+        let value = parameters
+            .as_ref()
+            .and_then(|parameters| parameters.get(key))
+            .ok_or_else(|| format!("{} needs a {} parameter", command, key))?;
+        if parameters
+            .as_ref()
+            .is_some_and(|parameters| parameters.len() > 1)
+        {
+            return Ok(custom(parameters.clone()));
+        }
+        let invalid =
+            |expected: &str| format!("Invalid {} '{}', expected {}", key, value, expected);
+        Ok(match key {
+            "brightness" => {
+                Command::SetBrightness(value.trim().parse().map_err(|_| invalid("0 to 255"))?)
+            }
+            "color_temp" => {
+                Command::SetColorTemperature(value.trim().parse().map_err(|_| invalid("mired"))?)
+            }
+            "color" => Command::SetColor(value.parse()?),
+            _ => Command::SetPosition(
+                value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|position| *position <= 100)
+                    .ok_or_else(|| invalid("0 to 100"))?,
+            ),
+        })
+    }
+
+    /// Name the command is sent as.
+    pub fn name(&self) -> &str {
+        match self {
+            Command::TurnOn => "turn_on",
+            Command::TurnOff => "turn_off",
+            Command::Toggle => "toggle",
+            Command::SetBrightness(_) => "set_brightness",
+            Command::SetColorTemperature(_) => "set_color_temp",
+            Command::SetColor(_) => "set_color",
+            Command::SetPosition(_) => "set_position",
+            Command::Open => "open",
+            Command::Close => "close",
+            Command::Lock => "lock",
+            Command::Unlock => "unlock",
+            Command::Custom { name, .. } => name,
+        }
+    }
+
+    /// Parameters the command is sent with.
+    pub fn parameters(&self) -> Option<HashMap<String, String>> {
+        let parameter = |key: &str, value: String| Some(HashMap::from([(key.to_string(), value)]));
+        match self {
+            Command::SetBrightness(brightness) => parameter("brightness", brightness.to_string()),
+            Command::SetColorTemperature(mired) => parameter("color_temp", mired.to_string()),
+            Command::SetColor(color) => parameter("color", color.to_string()),
+            Command::SetPosition(position) => parameter("position", position.to_string()),
+            Command::Custom { parameters, .. } => parameters.clone(),
+            _ => None,
+        }
+    }
+
+    /// Capability a device needs for the command, none for `Custom` ones.
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Command::Custom { .. } => None,
+            command => Capability::from_command(command.name()),
+        }
+    }
+}
+
 /**
  * Device States
  */
 #[derive(Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum State {
     ON,
     OFF,
@@ -55,30 +377,126 @@ pub enum State {
     PURRING,
 }
 
+/// Shared handle to a registered protocol handler.
+pub type HandlerRef = Arc<RwLock<dyn ProtocolHandler>>;
+
+/// Version of the handler API this build of blinkie implements. The major
+/// version changes when the `ProtocolHandler` trait or the subprocess
+/// protocol change incompatibly, the minor version when they gain something
+/// older handlers can do without.
+pub const HANDLER_API_VERSION: ApiVersion = ApiVersion { major: 1, minor: 7 };
+
+/**
+ * ApiVersion
+ * A version of the handler API, written `major.minor`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ApiVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ApiVersion {
+    /// The version a handler written against `self` and a host implementing
+    /// `host` talk, if they can talk at all: same major version, and nothing
+    /// newer than the host knows.
+    pub fn negotiate(self, host: ApiVersion) -> Result<ApiVersion, BlinkieError> {
+        if self.major != host.major || self.minor > host.minor {
+            return Err(format!(
+                "handler API {} is not supported, blinkie implements {}",
+                self, host
+            )
+            .into());
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for ApiVersion {
+    type Err = String;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid handler API version '{}', expected major.minor",
+                version
+            )
+        };
+        let (major, minor) = version.split_once('.').ok_or_else(invalid)?;
+        Ok(ApiVersion {
+            major: major.parse().map_err(|_| invalid())?,
+            minor: minor.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for ApiVersion {
+    type Error = String;
+
+    fn try_from(version: String) -> Result<Self, Self::Error> {
+        version.parse()
+    }
+}
+
+impl From<ApiVersion> for String {
+    fn from(version: ApiVersion) -> Self {
+        version.to_string()
+    }
+}
+
 /**
  * ProtocolRegistry
  * Manages the registration and retrieval of protocol handlers.
  */
 pub struct ProtocolRegistry {
-    pub handlers: Arc<RwLock<HashMap<String, Vec<Arc<RwLock<dyn ProtocolHandler>>>>>>,
+    pub handlers: Arc<RwLock<HashMap<String, Vec<HandlerRef>>>>,
+    /// API version agreed with each handler at registration, by name.
+    pub versions: Arc<RwLock<HashMap<String, ApiVersion>>>,
+    /// Name of the handler that created each device, by device id.
+    pub devices: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl Default for ProtocolRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProtocolRegistry {
     pub fn new() -> Self {
         ProtocolRegistry {
             handlers: Arc::new(RwLock::new(HashMap::new())),
+            versions: Arc::new(RwLock::new(HashMap::new())),
+            devices: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    /// Registers a protocol handler, associating it with its supported protocols.
-    pub fn register(&self, handler: Arc<RwLock<dyn ProtocolHandler>>) {
-        let mut handlers = self.handlers.write().unwrap();
+    /// Registers a protocol handler, associating it with its supported
+    /// protocols. Handlers written against an API version this build
+    /// doesn't implement are refused.
+    pub fn register(&self, handler: HandlerRef) -> Result<(), BlinkieError> {
+        let (name, version) = {
+            let handler = handler.read()?;
+            (handler.name(), handler.api_version())
+        };
+        let version = version
+            .negotiate(HANDLER_API_VERSION)
+            .map_err(|e| format!("Failed to register handler '{}': {}", name, e))?;
+        self.versions.write()?.insert(name, version);
+        let mut handlers = self.handlers.write()?;
 
         // Insert handler into all supported protocol entries
-        for protocol in handler.read().unwrap().supported_protocols() {
+        for protocol in handler.read()?.supported_protocols() {
             handlers
                 .entry(protocol.clone())
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(Arc::clone(&handler));
         }
 
@@ -90,10 +508,81 @@ impl ProtocolRegistry {
                 b_priority.cmp(&a_priority)
             });
         }
+        Ok(())
+    }
+
+    /// API version agreed with the handler named `name`.
+    pub fn api_version(&self, name: &str) -> Option<ApiVersion> {
+        self.versions.read().unwrap().get(name).copied()
+    }
+
+    /// Name of the handler that created the device.
+    pub fn handler_of(&self, device_id: &str) -> Option<String> {
+        self.devices.read().unwrap().get(device_id).cloned()
+    }
+
+    /// Creates a device with `handler`, remembering which handler it was.
+    pub fn create_device(
+        &self,
+        handler: &HandlerRef,
+        config: &Config,
+    ) -> Result<Box<dyn Device>, BlinkieError> {
+        let mut handler = handler.write()?;
+        let device = handler.create_device(config)?;
+        self.devices
+            .write()?
+            .insert(config.id.clone(), handler.name());
+        Ok(device)
+    }
+
+    /// Returns every registered handler once, however many protocols it serves.
+    pub fn all_handlers(&self) -> Vec<HandlerRef> {
+        let handlers = self.handlers.read().unwrap();
+        let mut all: Vec<HandlerRef> = Vec::new();
+        for handler in handlers.values().flatten() {
+            if !all.iter().any(|known| Arc::ptr_eq(known, handler)) {
+                all.push(Arc::clone(handler));
+            }
+        }
+        all
+    }
+
+    /// Handler that would create the device: its preferred handler on the
+    /// first of its protocols anything handles, else the one of highest
+    /// priority there.
+    pub fn handler_for(&self, config: &Config) -> Result<HandlerRef, BlinkieError> {
+        for protocol in &config.supported_protocols {
+            // Fetch handlers for each protocol
+            if let Some(handlers) = self.get_handlers(protocol) {
+                // Attempt to find preferred handler, if specified
+                if let Some(preferred) = &config.preferred_handler {
+                    return handlers
+                        .into_iter()
+                        .find(|h| h.read().unwrap().name() == *preferred)
+                        .ok_or_else(|| {
+                            BlinkieError::handler_not_found(format!(
+                                "Preferred handler '{}' not found for protocol: {}",
+                                preferred, protocol
+                            ))
+                        });
+                }
+
+                // Use the highest priority handler if no preferred handler is specified
+                if let Some(handler) = handlers.into_iter().next() {
+                    return Ok(handler);
+                }
+            }
+        }
+
+        // Return an error if no compatible handler was found
+        Err(BlinkieError::handler_not_found(format!(
+            "No compatible protocol handler found for device: {}",
+            config.name
+        )))
     }
 
     /// Retrieves the list of handlers for a given protocol, if any exist.
-    pub fn get_handlers(&self, protocol: &str) -> Option<Vec<Arc<RwLock<dyn ProtocolHandler>>>> {
+    pub fn get_handlers(&self, protocol: &str) -> Option<Vec<HandlerRef>> {
         let handlers = self.handlers.read().unwrap();
         handlers.get(protocol).cloned()
     }
@@ -105,28 +594,53 @@ impl ProtocolRegistry {
  */
 pub trait ProtocolHandler: Send + Sync {
     fn name(&self) -> String;
+    /// Handler API version the handler was written against. The default is
+    /// the version of the blinkie it was compiled with; handlers relaying to
+    /// something outside the process report what that speaks.
+    fn api_version(&self) -> ApiVersion {
+        HANDLER_API_VERSION
+    }
     fn priority(&self) -> u8 {
         0 // Default priority is 0
     }
     fn supported_protocols(&self) -> Vec<String>;
-    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, String>;
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError>;
     fn send_cmd(
         &mut self,
         device: &mut dyn Device,
         cmd: &str,
         params: Option<HashMap<String, String>>,
-    ) -> Result<(), String>;
-    fn initialize(&mut self) -> Result<(), String>;
+    ) -> Result<(), BlinkieError>;
+    fn initialize(&mut self) -> Result<(), BlinkieError>;
+    /// Closes the handler's connections when the hub shuts down.
+    fn shutdown(&mut self) {}
+    /// Checks that the handler could create a device from `config`, e.g.
+    /// that it has the connection details the handler needs, without
+    /// creating it.
+    fn validate(&self, _config: &Config) -> Result<(), BlinkieError> {
+        Ok(())
+    }
+    /// Reports why the handler can't currently talk to its devices, if it can't.
+    fn health(&self) -> Result<(), BlinkieError> {
+        Ok(())
+    }
+    /// Pings `device`, a device the handler created, and reports why it
+    /// doesn't answer, if it doesn't. The default trusts the `available`
+    /// state key of devices reporting it.
+    fn check_health(&self, device: &dyn Device) -> Result<(), BlinkieError> {
+        health::reported(device.get_id(), &device.get_state())
+    }
 }
 
 /**
  * Device Actions
  */
-#[derive(Serialize, Deserialize)] // Added Deserialize for this enum
+#[derive(Clone, Debug, Serialize, Deserialize)] // Added Deserialize for this enum
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
 pub enum Action {
     TurnOn,
     TurnOff,
-    Set(HashMap<String, String>),
+    Set(StateMap),
     Reset(String),
 }
 
@@ -135,16 +649,56 @@ pub enum Action {
  * Provides an abstraction for executing an action on a device.
  */
 pub trait Executor {
-    fn execute(&self, device: &mut dyn Device) -> Result<(), String>;
+    fn execute(&self, device: &mut dyn Device) -> Result<(), BlinkieError>;
+}
+
+impl Action {
+    /// The command the action sends and its parameters; `None` for actions
+    /// setting state directly.
+    pub fn command(&self) -> Option<(&'static str, Option<HashMap<String, String>>)> {
+        match self {
+            Action::TurnOn => Some(("turn_on", None)),
+            Action::TurnOff => Some(("turn_off", None)),
+            Action::Set(_) => None,
+            Action::Reset(target) => Some((
+                "reset",
+                Some(HashMap::from([("target".to_string(), target.clone())])),
+            )),
+        }
+    }
+}
+
+impl Executor for Action {
+    fn execute(&self, device: &mut dyn Device) -> Result<(), BlinkieError> {
+        match self {
+            Action::TurnOn => device.send_cmd("turn_on", None),
+            Action::TurnOff => device.send_cmd("turn_off", None),
+            Action::Set(state) => device.set_state(state.clone()),
+            Action::Reset(target) => device.send_cmd(
+                "reset",
+                Some(HashMap::from([("target".to_string(), target.clone())])),
+            ),
+        }
+        Ok(())
+    }
 }
 
+/// Sends a command to a device: its id, the command and its parameters.
+/// Used by parts of the hub that drive devices without holding them, e.g.
+/// to play announcements.
+pub type CommandSender =
+    Arc<dyn Fn(&str, &str, HashMap<String, String>) -> Result<(), BlinkieError> + Send + Sync>;
+
+/// Constructor registered for building a device straight from its config.
+pub type DeviceConstructor = Box<dyn Fn(Config) -> Box<dyn Device> + Send + Sync>;
+
 /**
  * DeviceFactory
  * Responsible for creating devices using registered handlers.
  */
 pub struct DeviceFactory {
     pub protocol_registry: Arc<ProtocolRegistry>,
-    pub registrar: Arc<RwLock<HashMap<String, Box<dyn Fn(Config) -> Box<dyn Device>>>>>,
+    pub registrar: Arc<RwLock<HashMap<String, DeviceConstructor>>>,
 }
 
 impl DeviceFactory {
@@ -157,36 +711,8 @@ impl DeviceFactory {
     }
 
     /// Registers and creates a device using a matching protocol handler.
-    pub fn register(&self, config: &Config) -> Result<Box<dyn Device>, String> {
-        for protocol in &config.supported_protocols {
-            // Fetch handlers for each protocol
-            if let Some(handlers) = self.protocol_registry.get_handlers(protocol) {
-                // Attempt to find preferred handler, if specified
-                if let Some(preferred) = &config.preferred_handler {
-                    if let Some(handler) = handlers
-                        .iter()
-                        .find(|h| h.read().unwrap().name() == *preferred)
-                    {
-                        return handler.write().unwrap().create_device(config);
-                    } else {
-                        return Err(format!(
-                            "Preferred handler '{}' not found for protocol: {}",
-                            preferred, protocol
-                        ));
-                    }
-                }
-
-                // Use the highest priority handler if no preferred handler is specified
-                if let Some(handler) = handlers.get(0) {
-                    return handler.write().unwrap().create_device(config);
-                }
-            }
-        }
-
-        // Return an error if no compatible handler was found
-        Err(format!(
-            "No compatible protocol handler found for device: {}",
-            config.name
-        ))
+    pub fn register(&self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let handler = self.protocol_registry.handler_for(config)?;
+        self.protocol_registry.create_device(&handler, config)
     }
 }
@@ -0,0 +1,622 @@
+use super::device::{Config, Type};
+use super::error::BlinkieError;
+use super::storage::xml_values;
+use reqwest::blocking::Client;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MDNS_ADDR: &str = "224.0.0.251:5353";
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/**
+ * Discoverer Interface
+ * Finds devices on the local network and describes each as a candidate
+ * `Config`, to be reviewed and passed to `DeviceFactory::register`.
+ * Discovery blocks, so async code runs it on the blocking pool.
+ */
+pub trait Discoverer: Send + Sync {
+    fn name(&self) -> String;
+    /// Searches for `timeout` and returns a candidate per device that answered.
+    fn discover(&self, timeout: Duration) -> Result<Vec<Config>, BlinkieError>;
+}
+
+/**
+ * ServiceMapping
+ * Protocols and device type assumed for devices announcing a service, e.g.
+ * an mDNS service type or an SSDP search target.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceMapping {
+    pub service: String,
+    pub protocols: Vec<String>,
+    pub device_type: Type,
+}
+
+impl ServiceMapping {
+    pub fn new(service: &str, protocol: &str, device_type: Type) -> Self {
+        ServiceMapping {
+            service: service.to_string(),
+            protocols: vec![protocol.to_string()],
+            device_type,
+        }
+    }
+}
+
+fn default_timeout_secs() -> u64 {
+    5
+}
+
+/**
+ * DiscoveryConfig
+ * Whether the hub looks for devices on the local network when it starts,
+ * for how long, and the mDNS services to look for besides HTTP devices and
+ * MQTT brokers, by service type, each with the protocol of its devices.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    #[serde(default)]
+    pub on_start: bool,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub services: BTreeMap<String, String>,
+}
+
+impl DiscoveryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_secs == 0 {
+            return Err("timeout_secs must be positive".to_string());
+        }
+        if let Some((service, _)) = self
+            .services
+            .iter()
+            .find(|(service, protocol)| service.is_empty() || protocol.is_empty())
+        {
+            return Err(format!("service '{}' needs a type and a protocol", service));
+        }
+        Ok(())
+    }
+
+    /// Discoverers looking for what the config asks for.
+    pub fn discoverers(&self) -> Vec<Box<dyn Discoverer>> {
+        let mut mdns = MdnsDiscoverer::new();
+        for (service, protocol) in &self.services {
+            mdns = mdns.with_service(ServiceMapping::new(service, protocol, Type::Actor));
+        }
+        vec![Box::new(mdns), Box::new(SsdpDiscoverer::new())]
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            on_start: false,
+            timeout_secs: default_timeout_secs(),
+            services: BTreeMap::new(),
+        }
+    }
+}
+
+/// Runs every discoverer at once and returns their candidates, dropping
+/// ones whose id was found before. Failing discoverers are logged.
+pub fn discover_all(discoverers: &[Box<dyn Discoverer>], timeout: Duration) -> Vec<Config> {
+    let results: Vec<(String, Result<Vec<Config>, BlinkieError>)> = thread::scope(|scope| {
+        let searches: Vec<_> = discoverers
+            .iter()
+            .map(|discoverer| scope.spawn(|| (discoverer.name(), discoverer.discover(timeout))))
+            .collect();
+        searches
+            .into_iter()
+            .filter_map(|search| search.join().ok())
+            .collect()
+    });
+    let mut candidates: Vec<Config> = Vec::new();
+    for (name, result) in results {
+        match result {
+            Ok(found) => {
+                for config in found {
+                    if !candidates.iter().any(|known| known.id == config.id) {
+                        candidates.push(config);
+                    }
+                }
+            }
+            Err(e) => log::warn!("Discovery with {} failed: {}", name, e),
+        }
+    }
+    candidates
+}
+
+/// Turns a name into a device id: lowercase letters, digits and dashes.
+pub(crate) fn slug(name: &str) -> String {
+    let mut id = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            id.push(c.to_ascii_lowercase());
+        } else if !id.is_empty() && !id.ends_with('-') {
+            id.push('-');
+        }
+    }
+    id.trim_end_matches('-').to_string()
+}
+
+/// Receives datagrams on `socket` until `deadline`, passing each to `handle`.
+fn receive_until(socket: &UdpSocket, deadline: Instant, mut handle: impl FnMut(&[u8])) {
+    let mut buf = [0u8; 9000];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if socket
+            .set_read_timeout(Some(left.max(Duration::from_millis(1))))
+            .is_err()
+        {
+            break;
+        }
+        match socket.recv_from(&mut buf) {
+            Ok((len, _)) => handle(&buf[..len]),
+            Err(_) => break,
+        }
+    }
+}
+
+/**
+ * MdnsDiscoverer
+ * Finds devices announcing DNS-SD services over multicast DNS. Queries are
+ * sent from an ephemeral port, so responders answer them directly
+ * ("legacy unicast") and no socket on port 5353 is needed.
+ */
+pub struct MdnsDiscoverer {
+    services: Vec<ServiceMapping>,
+}
+
+impl Default for MdnsDiscoverer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MdnsDiscoverer {
+    /// Looks for HTTP devices and MQTT brokers.
+    pub fn new() -> Self {
+        MdnsDiscoverer {
+            services: vec![
+                ServiceMapping::new("_http._tcp.local", "http", Type::Actor),
+                ServiceMapping::new("_mqtt._tcp.local", "mqtt", Type::Controller),
+            ],
+        }
+    }
+
+    /// Also looks for devices announcing `service`, e.g. `_hue._tcp.local`.
+    pub fn with_service(mut self, service: ServiceMapping) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    fn candidate(&self, mapping: &ServiceMapping, instance: &str, records: &[Record]) -> Config {
+        let name = instance
+            .strip_suffix(&mapping.service)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(instance)
+            .to_string();
+        let mut details = HashMap::from([
+            ("instance".to_string(), instance.to_string()),
+            ("service".to_string(), mapping.service.clone()),
+        ]);
+        for record in records.iter().filter(|record| record.is(instance)) {
+            if let RecordData::Txt(entries) = &record.data {
+                for (key, value) in entries {
+                    details.insert(format!("txt.{}", key), value.clone());
+                }
+            }
+        }
+        let service = records.iter().find_map(|record| match &record.data {
+            RecordData::Srv { port, target } if record.is(instance) => Some((*port, target)),
+            _ => None,
+        });
+        if let Some((port, target)) = service {
+            let mut addresses: Vec<IpAddr> = records
+                .iter()
+                .filter(|record| record.is(target))
+                .filter_map(|record| match record.data {
+                    RecordData::Address(ip) => Some(ip),
+                    _ => None,
+                })
+                .collect();
+            addresses.sort_by_key(|ip| ip.is_ipv6());
+            let host = match addresses.first() {
+                Some(IpAddr::V6(ip)) => format!("[{}]", ip),
+                Some(ip) => ip.to_string(),
+                None => target.clone(),
+            };
+            if mapping.protocols.iter().any(|protocol| protocol == "http") {
+                details.insert("base_url".to_string(), format!("http://{}:{}", host, port));
+            }
+            details.insert("hostname".to_string(), target.clone());
+            details.insert("host".to_string(), host);
+            details.insert("port".to_string(), port.to_string());
+        }
+        Config {
+            id: slug(&name),
+            name,
+            device_type: mapping.device_type,
+            connection_details: details,
+            supported_protocols: mapping.protocols.clone(),
+            preferred_handler: None,
+        }
+    }
+}
+
+impl Discoverer for MdnsDiscoverer {
+    fn name(&self) -> String {
+        "mdns".to_string()
+    }
+
+    /// Asks for instances of every service for half of `timeout`, then for
+    /// the ports, addresses and TXT records responders left out.
+    fn discover(&self, timeout: Duration) -> Result<Vec<Config>, BlinkieError> {
+        let socket =
+            UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Failed to open socket: {}", e))?;
+        let questions: Vec<(&str, u16)> = self
+            .services
+            .iter()
+            .map(|mapping| (mapping.service.as_str(), TYPE_PTR))
+            .collect();
+        socket
+            .send_to(&dns_query(&questions), MDNS_ADDR)
+            .map_err(|e| BlinkieError::connection(format!("Failed to send mDNS query: {}", e)))?;
+        let start = Instant::now();
+        let mut records: Vec<Record> = Vec::new();
+        receive_until(&socket, start + timeout / 2, |packet| {
+            records.extend(parse_message(packet).unwrap_or_default())
+        });
+
+        let instances: Vec<(&ServiceMapping, String)> = self
+            .services
+            .iter()
+            .flat_map(|mapping| {
+                records.iter().filter_map(move |record| match &record.data {
+                    RecordData::Ptr(instance) if record.is(&mapping.service) => {
+                        Some((mapping, instance.clone()))
+                    }
+                    _ => None,
+                })
+            })
+            .collect();
+        let mut missing: Vec<(String, u16)> = Vec::new();
+        for (_, instance) in &instances {
+            let known = |kind| {
+                records
+                    .iter()
+                    .any(|record| record.kind == kind && record.is(instance))
+            };
+            if !known(TYPE_SRV) {
+                missing.push((instance.clone(), TYPE_SRV));
+            }
+            if !known(TYPE_TXT) {
+                missing.push((instance.clone(), TYPE_TXT));
+            }
+        }
+        for record in &records {
+            if let RecordData::Srv { target, .. } = &record.data {
+                let resolved = records.iter().any(|record| {
+                    matches!(record.data, RecordData::Address(_)) && record.is(target)
+                });
+                if !resolved && !missing.iter().any(|(name, _)| name == target) {
+                    missing.push((target.clone(), TYPE_A));
+                }
+            }
+        }
+        if !missing.is_empty() {
+            let questions: Vec<(&str, u16)> = missing
+                .iter()
+                .map(|(name, kind)| (name.as_str(), *kind))
+                .collect();
+            socket
+                .send_to(&dns_query(&questions), MDNS_ADDR)
+                .map_err(|e| {
+                    BlinkieError::connection(format!("Failed to send mDNS query: {}", e))
+                })?;
+            receive_until(&socket, start + timeout, |packet| {
+                records.extend(parse_message(packet).unwrap_or_default())
+            });
+        }
+
+        let mut candidates: Vec<Config> = Vec::new();
+        for (mapping, instance) in &instances {
+            let candidate = self.candidate(mapping, instance, &records);
+            if !candidates.iter().any(|known| known.id == candidate.id) {
+                candidates.push(candidate);
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+/**
+ * SsdpDiscoverer
+ * Finds UPnP devices answering SSDP searches, naming them after their
+ * device description.
+ */
+pub struct SsdpDiscoverer {
+    targets: Vec<ServiceMapping>,
+}
+
+impl Default for SsdpDiscoverer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SsdpDiscoverer {
+    /// Looks for every UPnP root device.
+    pub fn new() -> Self {
+        SsdpDiscoverer {
+            targets: vec![ServiceMapping::new(
+                "upnp:rootdevice",
+                "upnp",
+                Type::Controller,
+            )],
+        }
+    }
+
+    /// Also searches for `target`, e.g. `urn:schemas-upnp-org:device:MediaRenderer:1`.
+    /// Devices answering several searches take the first matching target's mapping.
+    pub fn with_target(mut self, target: ServiceMapping) -> Self {
+        self.targets.push(target);
+        self
+    }
+
+    fn candidate(
+        &self,
+        client: &Client,
+        mapping: &ServiceMapping,
+        headers: &HashMap<String, String>,
+    ) -> Option<Config> {
+        let usn = headers.get("usn")?;
+        let location = Url::parse(headers.get("location")?).ok()?;
+        let uuid = usn.split("::").next().unwrap_or(usn);
+        let uuid = uuid.strip_prefix("uuid:").unwrap_or(uuid);
+
+        let mut details: HashMap<String, String> = ["usn", "st", "server", "location"]
+            .into_iter()
+            .filter_map(|name| Some((name.to_string(), headers.get(name)?.clone())))
+            .collect();
+        if let (Some(host), Some(port)) = (location.host_str(), location.port_or_known_default()) {
+            details.insert("host".to_string(), host.to_string());
+            details.insert("port".to_string(), port.to_string());
+            details.insert(
+                "base_url".to_string(),
+                format!("{}://{}:{}", location.scheme(), host, port),
+            );
+        }
+        // The description is optional; without it the server header names the device
+        let description = client
+            .get(location)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .unwrap_or_default();
+        for (element, key) in [
+            ("friendlyName", "friendly_name"),
+            ("manufacturer", "manufacturer"),
+            ("modelName", "model"),
+        ] {
+            if let Some(value) = xml_values(&description, element).into_iter().next() {
+                details.insert(key.to_string(), value);
+            }
+        }
+        let name = details
+            .get("friendly_name")
+            .or_else(|| details.get("server"))
+            .cloned()
+            .unwrap_or_else(|| uuid.to_string());
+        Some(Config {
+            id: slug(uuid),
+            name,
+            device_type: mapping.device_type,
+            connection_details: details,
+            supported_protocols: mapping.protocols.clone(),
+            preferred_handler: None,
+        })
+    }
+}
+
+impl Discoverer for SsdpDiscoverer {
+    fn name(&self) -> String {
+        "ssdp".to_string()
+    }
+
+    fn discover(&self, timeout: Duration) -> Result<Vec<Config>, BlinkieError> {
+        let socket =
+            UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Failed to open socket: {}", e))?;
+        // Devices wait up to MX seconds before answering, to spread replies
+        let mx = timeout.as_secs().clamp(1, 5);
+        for target in &self.targets {
+            let search = format!(
+                "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+                SSDP_ADDR, mx, target.service
+            );
+            socket.send_to(search.as_bytes(), SSDP_ADDR).map_err(|e| {
+                BlinkieError::connection(format!("Failed to send SSDP search: {}", e))
+            })?;
+        }
+        let mut responses: Vec<HashMap<String, String>> = Vec::new();
+        receive_until(&socket, Instant::now() + timeout, |packet| {
+            let response = String::from_utf8_lossy(packet);
+            let headers: HashMap<String, String> = response
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+                })
+                .collect();
+            responses.push(headers);
+        });
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(2))
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        let mut candidates: Vec<Config> = Vec::new();
+        for headers in &responses {
+            let Some(mapping) = self.targets.iter().find(|target| {
+                headers
+                    .get("st")
+                    .is_some_and(|st| st.eq_ignore_ascii_case(&target.service))
+            }) else {
+                continue;
+            };
+            let Some(candidate) = self.candidate(&client, mapping, headers) else {
+                continue;
+            };
+            if !candidates.iter().any(|known| known.id == candidate.id) {
+                candidates.push(candidate);
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+/**
+ * Record
+ * A resource record of an mDNS answer.
+ */
+#[derive(Clone, Debug)]
+struct Record {
+    name: String,
+    kind: u16,
+    data: RecordData,
+}
+
+#[derive(Clone, Debug)]
+enum RecordData {
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<(String, String)>),
+    Address(IpAddr),
+    Other,
+}
+
+impl Record {
+    /// DNS names compare case-insensitively.
+    fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Builds a DNS query with one question per name and record type.
+fn dns_query(questions: &[(&str, u16)]) -> Vec<u8> {
+    let mut packet = vec![0, 0, 0, 0];
+    packet.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0; 6]);
+    for (name, kind) in questions {
+        for label in name.trim_end_matches('.').split('.') {
+            let label = &label.as_bytes()[..label.len().min(63)];
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label);
+        }
+        packet.push(0);
+        packet.extend_from_slice(&kind.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    packet
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *packet.get(pos)?,
+        *packet.get(pos + 1)?,
+    ]))
+}
+
+/// Reads a possibly compressed name at `pos`, returning it and where the
+/// data after it starts.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, so loops in malformed packets end
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let target = (read_u16(packet, pos)? & 0x3fff) as usize;
+            end.get_or_insert(pos + 2);
+            pos = target;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+/// Parses the answer, authority and additional records of a DNS message.
+fn parse_message(packet: &[u8]) -> Option<Vec<Record>> {
+    let questions = read_u16(packet, 4)?;
+    let records = (6..=10)
+        .step_by(2)
+        .map(|pos| read_u16(packet, pos).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+    let mut parsed = Vec::with_capacity(records);
+    for _ in 0..records {
+        let (name, after) = read_name(packet, pos)?;
+        let kind = read_u16(packet, after)?;
+        let len = read_u16(packet, after + 8)? as usize;
+        let start = after + 10;
+        let rdata = packet.get(start..start + len)?;
+        let data = match kind {
+            TYPE_PTR => RecordData::Ptr(read_name(packet, start)?.0),
+            TYPE_SRV => RecordData::Srv {
+                port: read_u16(packet, start + 4)?,
+                target: read_name(packet, start + 6)?.0,
+            },
+            TYPE_TXT => RecordData::Txt(parse_txt(rdata)),
+            TYPE_A if len == 4 => {
+                RecordData::Address(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).into())
+            }
+            TYPE_AAAA if len == 16 => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                RecordData::Address(Ipv6Addr::from(octets).into())
+            }
+            _ => RecordData::Other,
+        };
+        parsed.push(Record { name, kind, data });
+        pos = start + len;
+    }
+    Some(parsed)
+}
+
+/// Splits TXT data into `key=value` entries; keys without a value map to "".
+fn parse_txt(data: &[u8]) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some(&len) = data.get(pos) {
+        let Some(entry) = data.get(pos + 1..pos + 1 + len as usize) else {
+            break;
+        };
+        let entry = String::from_utf8_lossy(entry);
+        if !entry.is_empty() {
+            let (key, value) = entry.split_once('=').unwrap_or((&entry, ""));
+            entries.push((key.to_ascii_lowercase(), value.to_string()));
+        }
+        pos += 1 + len as usize;
+    }
+    entries
+}
@@ -0,0 +1,548 @@
+use super::device::CommandSender;
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::notification::{
+    Delivery, Notification, NotificationAction, Notifier, Severity, NOTIFICATION_ACTION_EVENT,
+};
+use crate::automation::rule::Trigger;
+use chrono::{DateTime, Duration, Utc};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Name of the custom event published for every ring, carrying `doorbell`,
+/// `ignored` and, unless ignored, `notification_id` and `snapshot_url`.
+pub const RING_EVENT: &str = "doorbell_ring";
+/// Name of the custom event published when a doorbell is ignored, carrying
+/// `doorbell` and `until`.
+pub const IGNORED_EVENT: &str = "doorbell_ignored";
+/// Path snapshots are fetched from by push services, followed by their id.
+pub const SNAPSHOT_PATH: &str = "/api/doorbells/snapshots/";
+/// Snapshots kept in memory, across all doorbells.
+const MAX_SNAPSHOTS: usize = 32;
+/// Prefix of the ids of ring notifications, followed by the doorbell.
+const NOTIFICATION_PREFIX: &str = "doorbell:";
+
+fn default_title() -> String {
+    "Someone is at the door".to_string()
+}
+
+fn default_severity() -> Severity {
+    Severity::High
+}
+
+fn default_ignore_secs() -> u64 {
+    300
+}
+
+fn default_cooldown_secs() -> u64 {
+    10
+}
+
+fn default_talk_command() -> String {
+    "start_talk".to_string()
+}
+
+/**
+ * SnapshotSource
+ * Where the picture of a camera comes from.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SnapshotSource {
+    /// The snapshot URL most IP cameras serve, fetched with basic auth if
+    /// credentials are given.
+    Url {
+        url: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// A command writing a JPEG to stdout, e.g. ffmpeg grabbing a frame of
+    /// an RTSP stream.
+    Command { command: Vec<String> },
+}
+
+impl SnapshotSource {
+    /// Takes a picture; blocks until it is complete.
+    pub fn capture(&self) -> Result<Snapshot, BlinkieError> {
+        match self {
+            SnapshotSource::Url {
+                url,
+                username,
+                password,
+            } => {
+                let client = Client::builder()
+                    .timeout(std::time::Duration::from_secs(10))
+                    .build()
+                    .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+                let mut request = client.get(url);
+                if let Some(username) = username {
+                    request = request.basic_auth(username, password.as_ref());
+                }
+                let response = request
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("Failed to fetch snapshot: {}", e))?;
+                let content_type = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("image/jpeg")
+                    .to_string();
+                let data = response
+                    .bytes()
+                    .map_err(|e| format!("Failed to read snapshot: {}", e))?;
+                Ok(Snapshot {
+                    content_type,
+                    data: data.to_vec(),
+                })
+            }
+            SnapshotSource::Command { command } => {
+                let (program, args) = command.split_first().ok_or("Empty snapshot command")?;
+                let output = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::null())
+                    .output()
+                    .map_err(|e| format!("Failed to start {}: {}", program, e))?;
+                if !output.status.success() || output.stdout.is_empty() {
+                    return Err(format!("{} failed with {}", program, output.status).into());
+                }
+                Ok(Snapshot {
+                    content_type: "image/jpeg".to_string(),
+                    data: output.stdout,
+                })
+            }
+        }
+    }
+}
+
+/**
+ * Snapshot
+ */
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/**
+ * Intercom
+ * Commands of a two-way audio device, offered as actions of the ring
+ * notification.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Intercom {
+    pub device_id: String,
+    #[serde(default = "default_talk_command")]
+    pub talk_command: String,
+    /// Opens the door, e.g. through the intercom's relay.
+    #[serde(default)]
+    pub unlock_command: Option<String>,
+}
+
+/**
+ * Doorbell
+ * What makes a ring and how it is announced. Presses within `cooldown_secs`
+ * of the last ring count as the same ring.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Doorbell {
+    /// The press of the button, e.g. a state change of the bell's sensor.
+    pub press: Trigger,
+    #[serde(default)]
+    pub camera: Option<SnapshotSource>,
+    #[serde(default)]
+    pub intercom: Option<Intercom>,
+    #[serde(default = "default_title")]
+    pub title: String,
+    #[serde(default = "default_severity")]
+    pub severity: Severity,
+    /// Channels to notify; the notification policy's routes if empty.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// How long the "Ignore" action silences the doorbell.
+    #[serde(default = "default_ignore_secs")]
+    pub ignore_secs: u64,
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+/**
+ * DoorbellConfig
+ * Doorbells by id. Push services fetch snapshots from the hub, at
+ * `base_url`; without it notifications go out without a picture.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DoorbellConfig {
+    #[serde(default)]
+    pub doorbells: HashMap<String, Doorbell>,
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+/**
+ * DoorbellStatus
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DoorbellStatus {
+    pub last_ring: Option<DateTime<Utc>>,
+    /// Rings until then are published as ignored and notify nobody.
+    pub ignored_until: Option<DateTime<Utc>>,
+    /// Where the snapshot of the last ring that wasn't ignored is served.
+    pub snapshot_url: Option<String>,
+}
+
+impl DoorbellStatus {
+    pub fn ignored(&self, now: DateTime<Utc>) -> bool {
+        self.ignored_until.is_some_and(|until| until > now)
+    }
+}
+
+/**
+ * Doorbells
+ * Turns presses of doorbells into a notification with a camera snapshot
+ * and actions to ignore the doorbell for a while or to answer through the
+ * intercom, and publishes every ring for rules.
+ */
+pub struct Doorbells {
+    path: PathBuf,
+    config: RwLock<DoorbellConfig>,
+    status: Mutex<HashMap<String, DoorbellStatus>>,
+    snapshots: Mutex<VecDeque<(String, Snapshot)>>,
+    notifier: Arc<Notifier>,
+    events: Arc<EventBus>,
+    taken: AtomicU64,
+}
+
+impl Doorbells {
+    /// Opens the doorbells configured at `path`, notifying through
+    /// `notifier`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        notifier: Arc<Notifier>,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            DoorbellConfig::default()
+        };
+        Ok(Doorbells {
+            path,
+            config: RwLock::new(config),
+            status: Mutex::new(HashMap::new()),
+            snapshots: Mutex::new(VecDeque::new()),
+            notifier,
+            events,
+            taken: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> DoorbellConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: DoorbellConfig) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize doorbells: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// Status of every configured doorbell.
+    pub fn status(&self) -> HashMap<String, DoorbellStatus> {
+        let status = self.status.lock().unwrap();
+        self.config
+            .read()
+            .unwrap()
+            .doorbells
+            .keys()
+            .map(|id| (id.clone(), status.get(id).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    pub fn snapshot(&self, id: &str) -> Option<Snapshot> {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(snapshot_id, _)| snapshot_id == id)
+            .map(|(_, snapshot)| snapshot.clone())
+    }
+
+    /// Silences a doorbell for `secs`, or its configured time if unset.
+    pub fn ignore(
+        &self,
+        id: &str,
+        secs: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<DoorbellStatus, BlinkieError> {
+        let secs = secs.unwrap_or(self.doorbell(id)?.ignore_secs);
+        let until = now + Duration::seconds(secs as i64);
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            let status = status.entry(id.to_string()).or_default();
+            status.ignored_until = Some(until);
+            status.clone()
+        };
+        self.events.publish(EventKind::Custom {
+            name: IGNORED_EVENT.to_string(),
+            data: HashMap::from([
+                ("doorbell".to_string(), id.to_string()),
+                ("until".to_string(), until.to_rfc3339()),
+            ]),
+        })?;
+        Ok(status)
+    }
+
+    /// Lets a doorbell ring again before its ignore time ends.
+    pub fn unignore(&self, id: &str) -> Result<DoorbellStatus, BlinkieError> {
+        self.doorbell(id)?;
+        let mut status = self.status.lock().unwrap();
+        let status = status.entry(id.to_string()).or_default();
+        status.ignored_until = None;
+        Ok(status.clone())
+    }
+
+    /// Handles a press of doorbell `id`: takes a snapshot, notifies and
+    /// publishes the ring. Blocks while the camera and the channels are
+    /// busy. Returns `None` for presses within the cooldown.
+    pub fn ring(
+        &self,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<DoorbellStatus>, BlinkieError> {
+        let doorbell = self.doorbell(id)?;
+        let ignored = {
+            let mut status = self.status.lock().unwrap();
+            let status = status.entry(id.to_string()).or_default();
+            if status
+                .last_ring
+                .is_some_and(|last| now < last + Duration::seconds(doorbell.cooldown_secs as i64))
+            {
+                return Ok(None);
+            }
+            status.last_ring = Some(now);
+            status.ignored(now)
+        };
+        let mut data = HashMap::from([
+            ("doorbell".to_string(), id.to_string()),
+            ("ignored".to_string(), ignored.to_string()),
+        ]);
+        if ignored {
+            self.events.publish(EventKind::Custom {
+                name: RING_EVENT.to_string(),
+                data,
+            })?;
+            return Ok(Some(self.status.lock().unwrap()[id].clone()));
+        }
+
+        let base_url = self.config.read().unwrap().base_url.clone();
+        let snapshot_path = match doorbell.camera.as_ref().map(SnapshotSource::capture) {
+            Some(Ok(snapshot)) => Some(format!("{}{}", SNAPSHOT_PATH, self.keep(snapshot))),
+            Some(Err(e)) => {
+                log::warn!("Doorbell '{}': {}", id, e);
+                None
+            }
+            None => None,
+        };
+        let snapshot_url = snapshot_path.as_ref().map(|path| match &base_url {
+            Some(base_url) => format!("{}{}", base_url.trim_end_matches('/'), path),
+            None => path.clone(),
+        });
+
+        let notification_id = format!("{}{}:{}", NOTIFICATION_PREFIX, id, now.timestamp());
+        let action = |id: &str, label: &str| NotificationAction {
+            id: id.to_string(),
+            label: label.to_string(),
+            callback_url: None,
+        };
+        let mut actions = vec![action("ignore", "Ignore")];
+        if let Some(intercom) = &doorbell.intercom {
+            actions.push(action("talk", "Talk"));
+            if intercom.unlock_command.is_some() {
+                actions.push(action("unlock", "Open door"));
+            }
+        }
+        let notification = Notification {
+            id: Some(notification_id.clone()),
+            title: doorbell.title.clone(),
+            key: None,
+            args: HashMap::new(),
+            message: now
+                .with_timezone(&self.notifier.policy().timezone)
+                .format("%H:%M")
+                .to_string(),
+            severity: doorbell.severity,
+            channels: doorbell.channels.clone(),
+            actions,
+            // Push services can only fetch pictures the hub serves publicly
+            image_url: snapshot_url.clone().filter(|_| base_url.is_some()),
+        };
+        match self.notifier.notify(notification, now) {
+            Ok(deliveries) => {
+                for (channel, delivery) in deliveries {
+                    if let Delivery::Failed { error } = delivery {
+                        log::warn!("Doorbell '{}' on {}: {}", id, channel, error);
+                    }
+                }
+            }
+            Err(e) => log::warn!("Doorbell '{}': {}", id, e),
+        }
+
+        data.insert("notification_id".to_string(), notification_id);
+        if let Some(url) = &snapshot_url {
+            data.insert("snapshot_url".to_string(), url.clone());
+        }
+        self.events.publish(EventKind::Custom {
+            name: RING_EVENT.to_string(),
+            data,
+        })?;
+        let mut status = self.status.lock().unwrap();
+        let status = status.entry(id.to_string()).or_default();
+        status.snapshot_url = snapshot_url;
+        Ok(Some(status.clone()))
+    }
+
+    /// Carries out the action picked on a ring notification.
+    pub fn answer(
+        &self,
+        notification_id: &str,
+        action: &str,
+        sender: &CommandSender,
+    ) -> Result<(), BlinkieError> {
+        let Some((id, _)) = notification_id
+            .strip_prefix(NOTIFICATION_PREFIX)
+            .and_then(|rest| rest.rsplit_once(':'))
+        else {
+            return Ok(());
+        };
+        let doorbell = self.doorbell(id)?;
+        let intercom = || {
+            doorbell
+                .intercom
+                .as_ref()
+                .ok_or_else(|| format!("Doorbell '{}' has no intercom", id))
+        };
+        match action {
+            "ignore" => self.ignore(id, None, Utc::now()).map(|_| ()),
+            "talk" => {
+                let intercom = intercom()?;
+                sender(&intercom.device_id, &intercom.talk_command, HashMap::new())
+            }
+            "unlock" => {
+                let intercom = intercom()?;
+                let command = intercom
+                    .unlock_command
+                    .as_ref()
+                    .ok_or_else(|| format!("Intercom of doorbell '{}' can't unlock", id))?;
+                sender(&intercom.device_id, command, HashMap::new())
+            }
+            _ => Err(format!("Unknown doorbell action '{}'", action).into()),
+        }
+    }
+
+    fn doorbell(&self, id: &str) -> Result<Doorbell, BlinkieError> {
+        self.config
+            .read()
+            .unwrap()
+            .doorbells
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("Doorbell '{}' not found", id).into())
+    }
+
+    /// Keeps a snapshot and returns its id, which nobody can guess, as push
+    /// services fetch it without a token.
+    fn keep(&self, snapshot: Snapshot) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(RandomState::new().hash_one(&snapshot.data).to_le_bytes());
+        hasher.update(self.taken.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(
+            Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+                .to_le_bytes(),
+        );
+        let id: String = hasher.finalize()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.push_back((id.clone(), snapshot));
+        while snapshots.len() > MAX_SNAPSHOTS {
+            snapshots.pop_front();
+        }
+        id
+    }
+
+    /// Rings doorbells whose press shows up on the bus and answers the
+    /// actions picked on their notifications.
+    pub async fn run(self: Arc<Self>, sender: CommandSender) {
+        let mut receiver = self.events.subscribe();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            if let EventKind::Custom { name, data } = &event.kind {
+                if name == NOTIFICATION_ACTION_EVENT {
+                    let (Some(notification_id), Some(action)) =
+                        (data.get("notification_id"), data.get("action"))
+                    else {
+                        continue;
+                    };
+                    let (doorbells, sender) = (self.clone(), sender.clone());
+                    let (notification_id, action) = (notification_id.clone(), action.clone());
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = doorbells.answer(&notification_id, &action, &sender) {
+                            log::warn!("{}", e);
+                        }
+                    });
+                    continue;
+                }
+            }
+            let pressed: Vec<String> = self
+                .config
+                .read()
+                .unwrap()
+                .doorbells
+                .iter()
+                .filter(|(_, doorbell)| doorbell.press.matches(&event))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in pressed {
+                let doorbells = self.clone();
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = doorbells.ring(&id, Utc::now()) {
+                        log::warn!("{}", e);
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use super::device::Type;
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::value::StateMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Name of the custom event published when a device no longer matches
+/// what is expected of it, carrying `device_id`, `field`, `expected` and
+/// `actual`.
+pub const DRIFT_EVENT: &str = "device_drift";
+/// State keys telling what a device is and runs, as handlers report them
+/// after interviewing it.
+pub const IDENTITY_KEYS: [&str; 8] = [
+    "firmware",
+    "firmware_version",
+    "sw_version",
+    "software_build_id",
+    "hardware_version",
+    "model",
+    "vendor",
+    "manufacturer",
+];
+/// Time handlers get to interview their devices before the self-test.
+const SETTLE_SECS: u64 = 10;
+
+/**
+ * Fingerprint
+ * What a device said about itself: its type, the commands it supports and
+ * the identity keys of its state, e.g. model and firmware.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    #[serde(default)]
+    pub device_type: Option<Type>,
+    #[serde(default)]
+    pub commands: Option<Vec<String>>,
+    #[serde(default)]
+    pub identity: BTreeMap<String, String>,
+}
+
+impl Fingerprint {
+    pub fn of(device_type: Option<Type>, commands: Option<Vec<String>>, state: &StateMap) -> Self {
+        let commands = commands.map(|mut commands| {
+            commands.sort();
+            commands
+        });
+        let identity = IDENTITY_KEYS
+            .iter()
+            .filter_map(|key| Some((key.to_string(), state.get(*key)?.to_string())))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        Fingerprint {
+            device_type,
+            commands,
+            identity,
+        }
+    }
+
+    /// How `actual` differs from this expectation. What only one of the
+    /// two knows isn't drift.
+    fn drifts(&self, device_id: &str, actual: &Fingerprint) -> Vec<Drift> {
+        let drift = |field: &str, expected: String, actual: String| Drift {
+            device_id: device_id.to_string(),
+            field: field.to_string(),
+            expected,
+            actual,
+        };
+        let mut drifts = Vec::new();
+        if let (Some(expected), Some(actual)) = (self.device_type, actual.device_type) {
+            if expected != actual {
+                drifts.push(drift(
+                    "type",
+                    format!("{:?}", expected),
+                    format!("{:?}", actual),
+                ));
+            }
+        }
+        if let (Some(expected), Some(actual)) = (&self.commands, &actual.commands) {
+            if expected != actual {
+                drifts.push(drift("commands", expected.join(","), actual.join(",")));
+            }
+        }
+        for (key, expected) in &self.identity {
+            match actual.identity.get(key) {
+                Some(actual) if actual != expected => {
+                    drifts.push(drift(key, expected.clone(), actual.clone()))
+                }
+                _ => {}
+            }
+        }
+        drifts
+    }
+
+    /// Takes over what `actual` knows and this expectation doesn't yet.
+    fn learn(&mut self, actual: &Fingerprint) -> bool {
+        let mut learned = false;
+        if self.device_type.is_none() && actual.device_type.is_some() {
+            self.device_type = actual.device_type;
+            learned = true;
+        }
+        if self.commands.is_none() && actual.commands.is_some() {
+            self.commands = actual.commands.clone();
+            learned = true;
+        }
+        for (key, value) in &actual.identity {
+            if !self.identity.contains_key(key) {
+                self.identity.insert(key.clone(), value.clone());
+                learned = true;
+            }
+        }
+        learned
+    }
+}
+
+/**
+ * Drift
+ * A field of a device that no longer matches what is expected of it.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Drift {
+    pub device_id: String,
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/**
+ * DriftStatus
+ * When devices were last checked and how they drifted.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DriftStatus {
+    pub checked_at: Option<DateTime<Utc>>,
+    pub drifts: Vec<Drift>,
+}
+
+/**
+ * DriftCheck
+ * Self-test comparing what devices say about themselves with what they
+ * said before, kept in `device-expectations.json`, to catch devices that
+ * were silently replaced or re-flashed. Expectations are learned from the
+ * first interview of a device and only change when its drift is
+ * accepted.
+ */
+pub struct DriftCheck {
+    path: PathBuf,
+    expectations: Mutex<HashMap<String, Fingerprint>>,
+    status: Mutex<DriftStatus>,
+    events: Arc<EventBus>,
+}
+
+impl DriftCheck {
+    /// Opens the expectations kept at `path`.
+    pub fn open<P: Into<PathBuf>>(path: P, events: Arc<EventBus>) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let expectations = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            HashMap::new()
+        };
+        Ok(DriftCheck {
+            path,
+            expectations: Mutex::new(expectations),
+            status: Mutex::new(DriftStatus::default()),
+            events,
+        })
+    }
+
+    pub fn status(&self) -> DriftStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn expectations(&self) -> HashMap<String, Fingerprint> {
+        self.expectations.lock().unwrap().clone()
+    }
+
+    fn save(&self, expectations: &HashMap<String, Fingerprint>) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(expectations).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize device expectations: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+
+    /// Compares the `fingerprints` of the devices with their expectations,
+    /// learning those of new devices, and warns about drift not reported
+    /// before.
+    pub fn check(&self, fingerprints: &HashMap<String, Fingerprint>) -> Vec<Drift> {
+        let mut expectations = self.expectations.lock().unwrap();
+        let mut drifts = Vec::new();
+        let mut learned = false;
+        for (device_id, actual) in fingerprints {
+            let expected = expectations.entry(device_id.clone()).or_default();
+            drifts.extend(expected.drifts(device_id, actual));
+            learned |= expected.learn(actual);
+        }
+        if learned {
+            if let Err(e) = self.save(&expectations) {
+                log::error!("{}", e);
+            }
+        }
+        drifts.sort_by(|a, b| (&a.device_id, &a.field).cmp(&(&b.device_id, &b.field)));
+        let mut status = self.status.lock().unwrap();
+        for drift in drifts.iter().filter(|drift| !status.drifts.contains(drift)) {
+            log::warn!(
+                "Device '{}' drifted: {} was '{}', is '{}'",
+                drift.device_id,
+                drift.field,
+                drift.expected,
+                drift.actual
+            );
+            let _ = self.events.publish(EventKind::Custom {
+                name: DRIFT_EVENT.to_string(),
+                data: HashMap::from([
+                    ("device_id".to_string(), drift.device_id.clone()),
+                    ("field".to_string(), drift.field.clone()),
+                    ("expected".to_string(), drift.expected.clone()),
+                    ("actual".to_string(), drift.actual.clone()),
+                ]),
+            });
+        }
+        *status = DriftStatus {
+            checked_at: Some(Utc::now()),
+            drifts: drifts.clone(),
+        };
+        drifts
+    }
+
+    /// Accepts `fingerprint` as what is expected of `device_id` from now
+    /// on, e.g. after a firmware update.
+    pub fn accept(&self, device_id: &str, fingerprint: Fingerprint) -> Result<(), BlinkieError> {
+        let mut expectations = self.expectations.lock().unwrap();
+        expectations.insert(device_id.to_string(), fingerprint);
+        self.save(&expectations)?;
+        self.status
+            .lock()
+            .unwrap()
+            .drifts
+            .retain(|drift| drift.device_id != device_id);
+        Ok(())
+    }
+
+    /// Checks the devices whose fingerprints `read` returns once handlers
+    /// had time to interview them, then again whenever a device registers
+    /// or reports an identity key, until the bus closes.
+    pub async fn run<F>(self: Arc<Self>, read: F)
+    where
+        F: Fn() -> HashMap<String, Fingerprint> + Send + Sync + 'static,
+    {
+        let mut receiver = self.events.subscribe();
+        tokio::time::sleep(Duration::from_secs(SETTLE_SECS)).await;
+        self.check(&read());
+        loop {
+            match receiver.recv().await {
+                Ok(event) => match &event.kind {
+                    EventKind::DeviceRegistered { .. } => {
+                        self.check(&read());
+                    }
+                    EventKind::StateChanged { key, .. }
+                        if IDENTITY_KEYS.contains(&key.as_str()) =>
+                    {
+                        self.check(&read());
+                    }
+                    _ => {}
+                },
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use super::color::{self, Rgb};
+use super::device::{Capability, Command, CommandSender};
+use super::error::BlinkieError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::f64::consts::TAU;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Highest frame rate of an effect; devices take commands slower than
+/// frames anyway.
+const MAX_FPS: f64 = 60.0;
+
+fn default_fps() -> f64 {
+    10.0
+}
+
+fn default_full() -> u8 {
+    255
+}
+
+fn default_fade_secs() -> f64 {
+    2.0
+}
+
+fn default_blink_secs() -> f64 {
+    1.0
+}
+
+fn default_breathe_secs() -> f64 {
+    4.0
+}
+
+fn default_rainbow_secs() -> f64 {
+    10.0
+}
+
+fn default_step_secs() -> f64 {
+    0.5
+}
+
+/**
+ * Effect
+ * An animation of lights, by seconds since it started:
+ *
+ * - `fade`: brightness from `from` to `to` over `duration_secs`, then ends
+ * - `blink`: on and off, once every `period_secs`
+ * - `breathe`: brightness rising from `min` to `max` and back every
+ *   `period_secs`
+ * - `rainbow`: hue going round once every `period_secs`, each device
+ *   `spread` of the circle behind the one before it
+ * - `chase`: `color` moving from device to device every `step_secs`, the
+ *   others showing `background`
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Effect {
+    Fade {
+        #[serde(default)]
+        from: u8,
+        #[serde(default = "default_full")]
+        to: u8,
+        #[serde(default = "default_fade_secs")]
+        duration_secs: f64,
+    },
+    Blink {
+        #[serde(default = "default_blink_secs")]
+        period_secs: f64,
+    },
+    Breathe {
+        #[serde(default)]
+        min: u8,
+        #[serde(default = "default_full")]
+        max: u8,
+        #[serde(default = "default_breathe_secs")]
+        period_secs: f64,
+    },
+    Rainbow {
+        #[serde(default = "default_rainbow_secs")]
+        period_secs: f64,
+        #[serde(default = "default_full")]
+        saturation: u8,
+        #[serde(default = "default_full")]
+        value: u8,
+        #[serde(default)]
+        spread: f64,
+    },
+    Chase {
+        color: Rgb,
+        #[serde(default)]
+        background: Rgb,
+        #[serde(default = "default_step_secs")]
+        step_secs: f64,
+    },
+}
+
+impl Effect {
+    /// Capability every device running the effect needs.
+    pub fn capability(&self) -> Capability {
+        match self {
+            Effect::Fade { .. } | Effect::Breathe { .. } => Capability::Brightness,
+            Effect::Blink { .. } => Capability::OnOff,
+            Effect::Rainbow { .. } | Effect::Chase { .. } => Capability::RgbColor,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let (name, secs) = match self {
+            Effect::Fade { duration_secs, .. } => ("duration_secs", *duration_secs),
+            Effect::Blink { period_secs }
+            | Effect::Breathe { period_secs, .. }
+            | Effect::Rainbow { period_secs, .. } => ("period_secs", *period_secs),
+            Effect::Chase { step_secs, .. } => ("step_secs", *step_secs),
+        };
+        if !(secs > 0.0 && secs.is_finite()) {
+            return Err(format!("{} must be positive", name));
+        }
+        if let Effect::Rainbow { spread, .. } = self {
+            if !spread.is_finite() {
+                return Err("spread must be a number".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Seconds after which the effect is over on its own, if it is.
+    fn duration_secs(&self) -> Option<f64> {
+        match self {
+            Effect::Fade { duration_secs, .. } => Some(*duration_secs),
+            _ => None,
+        }
+    }
+
+    /// Command showing the effect `secs` into it on device `index` of
+    /// `count`.
+    pub fn frame(&self, secs: f64, index: usize, count: usize) -> Command {
+        let phase = |period: f64| (secs / period).fract();
+        let level = |from: u8, to: u8, share: f64| {
+            (f64::from(from) + (f64::from(to) - f64::from(from)) * share).round() as u8
+        };
+        match self {
+            Effect::Fade {
+                from,
+                to,
+                duration_secs,
+            } => Command::SetBrightness(level(*from, *to, (secs / duration_secs).min(1.0))),
+            Effect::Blink { period_secs } if phase(*period_secs) < 0.5 => Command::TurnOn,
+            Effect::Blink { .. } => Command::TurnOff,
+            Effect::Breathe {
+                min,
+                max,
+                period_secs,
+            } => Command::SetBrightness(level(
+                *min,
+                *max,
+                (1.0 - (TAU * phase(*period_secs)).cos()) / 2.0,
+            )),
+            Effect::Rainbow {
+                period_secs,
+                saturation,
+                value,
+                spread,
+            } => {
+                let turn = phase(*period_secs) - spread * index as f64;
+                let hue = (turn.rem_euclid(1.0) * 256.0) as u8;
+                let mut rgb = [0; color::PIXEL];
+                color::hsv_to_rgb(&[hue, *saturation, *value], &mut rgb);
+                Command::SetColor(Rgb {
+                    red: rgb[0],
+                    green: rgb[1],
+                    blue: rgb[2],
+                })
+            }
+            Effect::Chase {
+                color,
+                background,
+                step_secs,
+            } => {
+                let lit = (secs / step_secs) as usize % count.max(1);
+                Command::SetColor(if index == lit { *color } else { *background })
+            }
+        }
+    }
+}
+
+/**
+ * EffectDefinition
+ * An effect run on `devices`, in their order, at `fps` frames a second,
+ * for `duration_secs` or until stopped.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EffectDefinition {
+    pub devices: Vec<String>,
+    #[serde(default = "default_fps")]
+    pub fps: f64,
+    #[serde(default)]
+    pub duration_secs: Option<f64>,
+    pub effect: Effect,
+}
+
+impl EffectDefinition {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.devices.is_empty() {
+            return Err("devices must not be empty".to_string());
+        }
+        if !(self.fps > 0.0 && self.fps <= MAX_FPS) {
+            return Err(format!("fps must be above 0 and at most {}", MAX_FPS));
+        }
+        if self
+            .duration_secs
+            .is_some_and(|secs| !(secs > 0.0 && secs.is_finite()))
+        {
+            return Err("duration_secs must be positive".to_string());
+        }
+        self.effect.validate()
+    }
+
+    fn finished(&self, secs: f64) -> bool {
+        [self.duration_secs, self.effect.duration_secs()]
+            .into_iter()
+            .flatten()
+            .any(|duration| secs >= duration)
+    }
+
+    /// Commands showing the effect `secs` into it, by device.
+    pub fn frame(&self, secs: f64) -> Vec<(String, Command)> {
+        let count = self.devices.len();
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(index, device_id)| (device_id.clone(), self.effect.frame(secs, index, count)))
+            .collect()
+    }
+}
+
+/**
+ * EffectStatus
+ * A running effect: what it runs, since when, the frames shown, commands
+ * sent and commands failed, with the last error.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EffectStatus {
+    pub name: String,
+    pub definition: EffectDefinition,
+    pub started_at: DateTime<Utc>,
+    pub frames: u64,
+    pub commands: u64,
+    pub errors: u64,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+struct Running {
+    status: EffectStatus,
+    task: Option<JoinHandle<()>>,
+}
+
+/**
+ * Effects
+ * Runs light effects by sending the commands of every frame that changed
+ * what a device shows. Changing the definition of a running effect takes
+ * effect with its next frame, without starting it over.
+ */
+#[derive(Default)]
+pub struct Effects {
+    running: Mutex<BTreeMap<String, Running>>,
+}
+
+impl Effects {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(&self) -> Vec<EffectStatus> {
+        let running = self.running.lock().unwrap();
+        running.values().map(|run| run.status.clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<EffectStatus> {
+        let running = self.running.lock().unwrap();
+        running.get(name).map(|run| run.status.clone())
+    }
+
+    /// Starts effect `name`, sending commands with `sender`, or changes its
+    /// definition if it runs already. Must be called on the runtime. A
+    /// device runs one effect at a time.
+    pub fn start(
+        self: &Arc<Self>,
+        name: &str,
+        definition: EffectDefinition,
+        sender: CommandSender,
+    ) -> Result<EffectStatus, BlinkieError> {
+        definition.validate()?;
+        let mut running = self.running.lock().unwrap();
+        if let Some((other, device_id)) = running
+            .iter()
+            .filter(|(other, _)| *other != name)
+            .find_map(|(other, run)| {
+                definition
+                    .devices
+                    .iter()
+                    .find(|device_id| run.status.definition.devices.contains(device_id))
+                    .map(|device_id| (other, device_id))
+            })
+        {
+            return Err(format!("Device '{}' runs effect '{}'", device_id, other).into());
+        }
+        if let Some(run) = running.get_mut(name) {
+            run.status.definition = definition;
+            return Ok(run.status.clone());
+        }
+        let status = EffectStatus {
+            name: name.to_string(),
+            definition,
+            started_at: Utc::now(),
+            frames: 0,
+            commands: 0,
+            errors: 0,
+            last_error: None,
+        };
+        let task = tokio::spawn(self.clone().run(name.to_string(), sender));
+        running.insert(
+            name.to_string(),
+            Running {
+                status: status.clone(),
+                task: Some(task),
+            },
+        );
+        Ok(status)
+    }
+
+    /// Stops effect `name`, leaving its devices as they are. Returns `None`
+    /// for an effect not running.
+    pub fn stop(&self, name: &str) -> Option<EffectStatus> {
+        let run = self.running.lock().unwrap().remove(name)?;
+        if let Some(task) = run.task {
+            task.abort();
+        }
+        Some(run.status)
+    }
+
+    async fn run(self: Arc<Self>, name: String, sender: CommandSender) {
+        let started = Instant::now();
+        let mut next = started;
+        let mut shown: HashMap<String, Command> = HashMap::new();
+        loop {
+            let Some(definition) = self.get(&name).map(|status| status.definition) else {
+                return;
+            };
+            let secs = started.elapsed().as_secs_f64();
+            let changed: Vec<(String, Command)> = definition
+                .frame(secs)
+                .into_iter()
+                .filter(|(device_id, command)| shown.get(device_id) != Some(command))
+                .collect();
+            let sender = sender.clone();
+            let sent = tokio::task::spawn_blocking(move || {
+                changed
+                    .into_iter()
+                    .map(|(device_id, command)| {
+                        let parameters = command.parameters().unwrap_or_default();
+                        let result = sender(&device_id, command.name(), parameters);
+                        (device_id, command, result)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap_or_default();
+            {
+                let mut running = self.running.lock().unwrap();
+                let Some(run) = running.get_mut(&name) else {
+                    return;
+                };
+                run.status.frames += 1;
+                for (device_id, command, result) in sent {
+                    run.status.commands += 1;
+                    match result {
+                        Ok(()) => {
+                            shown.insert(device_id, command);
+                        }
+                        Err(e) => {
+                            run.status.errors += 1;
+                            run.status.last_error = Some(e.to_string());
+                        }
+                    }
+                }
+                if definition.finished(secs) {
+                    running.remove(&name);
+                    return;
+                }
+            }
+            next += Duration::from_secs_f64(1.0 / definition.fps);
+            next = next.max(Instant::now());
+            tokio::time::sleep_until(next.into()).await;
+        }
+    }
+}
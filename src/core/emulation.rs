@@ -0,0 +1,191 @@
+use super::device::{Device, DeviceList};
+use super::error::BlinkieError;
+use super::value::StateValue;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Time between two brightness steps of an emulated transition.
+const STEP_INTERVAL: Duration = Duration::from_millis(200);
+/// Most steps a transition is split into, however long it takes.
+const MAX_STEPS: u64 = 50;
+const DEFAULT_TRANSITION_SECS: f64 = 1.0;
+
+/**
+ * Emulated Commands
+ * Commands blinkie can carry out in software for devices that only offer
+ * simpler ones.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emulation {
+    /// `toggle`: reads `state` and sends `turn_off` when it is `on`,
+    /// `turn_on` otherwise.
+    Toggle,
+    /// `transition {brightness, duration}`: steps `brightness` to the
+    /// target over `duration` seconds with `set_brightness`.
+    Transition,
+}
+
+impl Emulation {
+    pub const ALL: [Emulation; 2] = [Emulation::Toggle, Emulation::Transition];
+
+    pub fn command(self) -> &'static str {
+        match self {
+            Emulation::Toggle => "toggle",
+            Emulation::Transition => "transition",
+        }
+    }
+
+    /// Native commands the emulation is built from.
+    fn requires(self) -> &'static [&'static str] {
+        match self {
+            Emulation::Toggle => &["turn_on", "turn_off"],
+            Emulation::Transition => &["set_brightness"],
+        }
+    }
+
+    /// The emulation standing in for `command` on a device with the `native`
+    /// commands, if the device lacks it and has what emulating it takes.
+    pub fn for_command(command: &str, native: &[String]) -> Option<Emulation> {
+        Emulation::ALL
+            .into_iter()
+            .find(|emulation| emulation.command() == command)
+            .filter(|emulation| emulation.applies(native))
+    }
+
+    fn applies(self, native: &[String]) -> bool {
+        let has = |command: &str| native.iter().any(|c| c == command);
+        !has(self.command()) && self.requires().iter().all(|command| has(command))
+    }
+}
+
+/// Commands emulated for a device with the `native` commands.
+pub fn emulated_commands(native: &[String]) -> Vec<String> {
+    Emulation::ALL
+        .into_iter()
+        .filter(|emulation| emulation.applies(native))
+        .map(|emulation| emulation.command().to_string())
+        .collect()
+}
+
+/**
+ * Emulator
+ * Carries out emulated commands. Transitions run on a thread of their own
+ * and are cancelled by the next command sent to the same device.
+ */
+pub struct Emulator {
+    devices: DeviceList,
+    /// Cancel flags of running transitions, by device id.
+    transitions: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl Emulator {
+    pub fn new(devices: DeviceList) -> Self {
+        Emulator {
+            devices,
+            transitions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stops a running transition of the device, if there is one.
+    pub fn cancel(&self, device_id: &str) {
+        if let Some(cancelled) = self.transitions.lock().unwrap().remove(device_id) {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Carries out `command` in software if the device lacks it natively.
+    /// Returns `false` when the command should go to the device as is.
+    pub fn handle(
+        &self,
+        device: &mut dyn Device,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<bool, BlinkieError> {
+        let Some(native) = device.commands() else {
+            return Ok(false);
+        };
+        match Emulation::for_command(command, &native) {
+            Some(Emulation::Toggle) => {
+                let on = device
+                    .get_state()
+                    .get("state")
+                    .and_then(StateValue::as_bool)
+                    .unwrap_or(false);
+                device.send_cmd(if on { "turn_off" } else { "turn_on" }, None);
+                Ok(true)
+            }
+            Some(Emulation::Transition) => {
+                self.transition(device, parameters)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn transition(
+        &self,
+        device: &dyn Device,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        let parameter = |key: &str| parameters.and_then(|parameters| parameters.get(key));
+        let target: f64 = parameter("brightness")
+            .ok_or("transition needs a brightness")?
+            .parse()
+            .ok()
+            .filter(|brightness: &f64| brightness.is_finite())
+            .ok_or("Invalid transition brightness")?;
+        let duration: f64 = match parameter("duration") {
+            Some(duration) => duration
+                .parse()
+                .ok()
+                .filter(|secs: &f64| secs.is_finite() && *secs >= 0.0)
+                .ok_or("Invalid transition duration")?,
+            None => DEFAULT_TRANSITION_SECS,
+        };
+        let start: f64 = device
+            .get_state()
+            .get("brightness")
+            .and_then(StateValue::as_f64)
+            .unwrap_or(0.0);
+        let steps =
+            ((duration * 1000.0) as u64 / STEP_INTERVAL.as_millis() as u64).clamp(1, MAX_STEPS);
+        let interval = Duration::from_secs_f64(duration / steps as f64);
+
+        let device_id = device.get_id().to_string();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.transitions
+            .lock()
+            .unwrap()
+            .insert(device_id.clone(), cancelled.clone());
+        let devices = self.devices.clone();
+        thread::Builder::new()
+            .name(format!("transition-{}", device_id))
+            .spawn(move || {
+                for step in 1..=steps {
+                    if step > 1 {
+                        thread::sleep(interval);
+                    }
+                    if cancelled.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let brightness = start + (target - start) * step as f64 / steps as f64;
+                    let mut devices = devices.write().unwrap();
+                    let Some(device) = devices.iter_mut().find(|d| d.get_id() == device_id) else {
+                        return;
+                    };
+                    device.send_cmd(
+                        "set_brightness",
+                        Some(HashMap::from([(
+                            "brightness".to_string(),
+                            format!("{}", brightness.round()),
+                        )])),
+                    );
+                }
+            })
+            .map(|_| ())
+            .map_err(|e| format!("Failed to start transition: {}", e).into())
+    }
+}
@@ -0,0 +1,854 @@
+use super::device::{CommandSender, Device, DeviceList, Type};
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::value::{StateMap, StateValue};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+
+/// Prefix of the ids of override switches, followed by the load.
+pub const SWITCH_PREFIX: &str = "energy.";
+
+fn default_power_key() -> String {
+    "power".to_string()
+}
+
+fn default_soc_key() -> String {
+    "soc".to_string()
+}
+
+fn default_full_soc() -> f64 {
+    95.0
+}
+
+fn default_on_command() -> String {
+    "turn_on".to_string()
+}
+
+fn default_off_command() -> String {
+    "turn_off".to_string()
+}
+
+fn default_min_secs() -> u64 {
+    300
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_current_command() -> String {
+    "set_current".to_string()
+}
+
+fn default_current_parameter() -> String {
+    "current".to_string()
+}
+
+fn default_min_a() -> f64 {
+    6.0
+}
+
+fn default_max_a() -> f64 {
+    16.0
+}
+
+fn default_volts() -> f64 {
+    230.0
+}
+
+fn default_phases() -> u8 {
+    1
+}
+
+/**
+ * Reading
+ * A power value in watts in a device's state.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reading {
+    pub device_id: String,
+    #[serde(default = "default_power_key")]
+    pub key: String,
+}
+
+/**
+ * Battery
+ * A home battery: its state of charge in percent and, if it reports it,
+ * the power it charges with in watts, negative while discharging.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Battery {
+    pub device_id: String,
+    #[serde(default = "default_soc_key")]
+    pub soc_key: String,
+    /// Without it, surplus the battery absorbs can't be told apart from
+    /// none, and loads only get what the battery leaves over.
+    #[serde(default)]
+    pub power_key: Option<String>,
+    /// Charge from which the battery counts as full, for `battery_first`.
+    #[serde(default = "default_full_soc")]
+    pub full_soc: f64,
+}
+
+/**
+ * Strategy
+ * How surplus is shared between the battery and flexible loads.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Loads get surplus before the battery charges, keeping as much of the
+    /// production in the house as possible.
+    #[default]
+    SelfConsumption,
+    /// The battery charges until full before loads get surplus.
+    BatteryFirst,
+    /// Loads are only switched by their overrides.
+    Manual,
+}
+
+/**
+ * CurrentControl
+ * How the current of a load is set, e.g. of an EV charger, so it runs on
+ * whatever surplus there is between `min_a` and `max_a` instead of only
+ * at full power.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CurrentControl {
+    #[serde(default = "default_current_command")]
+    pub command: String,
+    /// Parameter of the command carrying the current in A.
+    #[serde(default = "default_current_parameter")]
+    pub parameter: String,
+    #[serde(default = "default_min_a")]
+    pub min_a: f64,
+    #[serde(default = "default_max_a")]
+    pub max_a: f64,
+    #[serde(default = "default_volts")]
+    pub volts: f64,
+    #[serde(default = "default_phases")]
+    pub phases: u8,
+}
+
+impl CurrentControl {
+    /// Watts drawn at `amps`.
+    fn watts(&self, amps: f64) -> f64 {
+        amps * self.volts * self.phases.max(1) as f64
+    }
+
+    /// Whole amps that fit in `watts`, within the limits.
+    fn amps_for(&self, watts: f64) -> f64 {
+        (watts / self.watts(1.0))
+            .floor()
+            .clamp(self.min_a, self.max_a.max(self.min_a))
+    }
+}
+
+/**
+ * FlexibleLoad
+ * A load that can run whenever there is surplus, e.g. an EV charger or a
+ * boiler, switched with commands on its device.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FlexibleLoad {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub device_id: String,
+    /// Watts the load draws while running.
+    pub power_w: f64,
+    #[serde(default = "default_on_command")]
+    pub on_command: String,
+    #[serde(default = "default_off_command")]
+    pub off_command: String,
+    /// Loads of higher priority get surplus first.
+    #[serde(default)]
+    pub priority: u8,
+    /// Seconds the load keeps running once started, so it isn't cycled by
+    /// passing clouds.
+    #[serde(default = "default_min_secs")]
+    pub min_on_secs: u64,
+    /// Seconds the load stays off once stopped.
+    #[serde(default = "default_min_secs")]
+    pub min_off_secs: u64,
+    /// Set for loads whose current can be adjusted; they start once their
+    /// minimum current fits and `power_w` is only used until a current
+    /// was set.
+    #[serde(default)]
+    pub current: Option<CurrentControl>,
+}
+
+impl FlexibleLoad {
+    /// Watts the load needs to start.
+    fn min_power(&self) -> f64 {
+        match &self.current {
+            Some(current) => current.watts(current.min_a),
+            None => self.power_w,
+        }
+    }
+
+    /// Watts the load draws while running at `amps`, if its current is set.
+    fn power(&self, amps: Option<f64>) -> f64 {
+        match (&self.current, amps) {
+            (Some(current), Some(amps)) => current.watts(amps),
+            _ => self.power_w,
+        }
+    }
+}
+
+/**
+ * EnergyConfig
+ * Meters to read, the battery, flexible loads by id and how surplus is
+ * shared. Surplus is read from a grid meter, watts imported positive, or
+ * without one from PV production minus consumption.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnergyConfig {
+    #[serde(default)]
+    pub pv: Option<Reading>,
+    #[serde(default)]
+    pub grid: Option<Reading>,
+    #[serde(default)]
+    pub consumption: Option<Reading>,
+    #[serde(default)]
+    pub battery: Option<Battery>,
+    #[serde(default)]
+    pub loads: HashMap<String, FlexibleLoad>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Watts that may be imported before running loads are stopped, so a
+    /// load isn't stopped the moment production dips below its draw.
+    #[serde(default)]
+    pub max_import_w: f64,
+    /// Seconds between decisions.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl Default for EnergyConfig {
+    fn default() -> Self {
+        EnergyConfig {
+            pv: None,
+            grid: None,
+            consumption: None,
+            battery: None,
+            loads: HashMap::new(),
+            strategy: Strategy::default(),
+            max_import_w: 0.0,
+            interval_secs: default_interval_secs(),
+        }
+    }
+}
+
+/**
+ * Mode
+ * Whether a load follows the surplus or is held on or off.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Auto => "auto",
+            Mode::On => "on",
+            Mode::Off => "off",
+        }
+    }
+}
+
+/**
+ * Override
+ * A load held on or off, until a time or until set back to auto.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Override {
+    pub mode: Mode,
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+}
+
+/**
+ * LoadStatus
+ * Whether a load runs, in which mode and why.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoadStatus {
+    pub device_id: String,
+    pub switch_id: String,
+    pub on: bool,
+    pub mode: Mode,
+    /// Current last set, for loads with current control.
+    #[serde(default)]
+    pub current_a: Option<f64>,
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    /// When the load was last switched, while the hub ran.
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    pub reason: String,
+}
+
+/**
+ * EnergyStatus
+ * Readings of the last decision and the loads it switched.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EnergyStatus {
+    #[serde(default)]
+    pub pv_w: Option<f64>,
+    #[serde(default)]
+    pub grid_w: Option<f64>,
+    #[serde(default)]
+    pub consumption_w: Option<f64>,
+    #[serde(default)]
+    pub battery_soc: Option<f64>,
+    #[serde(default)]
+    pub battery_w: Option<f64>,
+    /// Watts available to flexible loads, including what the running ones
+    /// draw.
+    #[serde(default)]
+    pub available_w: Option<f64>,
+    pub strategy: Strategy,
+    pub loads: HashMap<String, LoadStatus>,
+    #[serde(default)]
+    pub updated: Option<DateTime<Utc>>,
+}
+
+/**
+ * Readings
+ * Values of the configured meters at one moment.
+ */
+#[derive(Clone, Debug, Default)]
+struct Readings {
+    pv: Option<f64>,
+    grid: Option<f64>,
+    consumption: Option<f64>,
+    soc: Option<f64>,
+    battery: Option<f64>,
+}
+
+/**
+ * LoadState
+ * What the manager knows about a load: whether it switched it on, since
+ * when and why, and any override.
+ */
+#[derive(Clone, Debug, Default)]
+struct LoadState {
+    on: bool,
+    /// Current last set, for loads with current control.
+    amps: Option<f64>,
+    since: Option<DateTime<Utc>>,
+    reason: String,
+    held: Option<Override>,
+}
+
+impl LoadState {
+    /// Mode of the load at `now`, auto once an override expired.
+    fn mode(&self, now: DateTime<Utc>) -> Mode {
+        match &self.held {
+            Some(held) if held.until.is_none_or(|until| until > now) => held.mode,
+            _ => Mode::Auto,
+        }
+    }
+}
+
+/**
+ * Manager
+ * State of all loads and the last readings, shared by the manager and its
+ * override switches.
+ */
+#[derive(Default)]
+struct Manager {
+    loads: HashMap<String, LoadState>,
+    status: EnergyStatus,
+}
+
+/// Id of the override switch of the load `id`.
+pub fn switch_id(id: &str) -> String {
+    format!("{}{}", SWITCH_PREFIX, id)
+}
+
+/**
+ * OverrideSwitch
+ * Switch of a load in the device list. `turn_on` and `turn_off` hold the
+ * load on or off, for `duration_secs` if given, and `auto` hands it back
+ * to the manager. Its state tells whether the load runs and its mode.
+ */
+pub struct OverrideSwitch {
+    id: String,
+    name: String,
+    load: String,
+    manager: Arc<Mutex<Manager>>,
+    events: Arc<EventBus>,
+    wake: Arc<Notify>,
+}
+
+impl Device for OverrideSwitch {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        let manager = self.manager.lock().unwrap();
+        let state = manager.loads.get(&self.load).cloned().unwrap_or_default();
+        let on = if state.on { "on" } else { "off" };
+        HashMap::from([
+            ("state".to_string(), StateValue::from(on)),
+            (
+                "mode".to_string(),
+                StateValue::from(state.mode(Utc::now()).name()),
+            ),
+        ])
+    }
+
+    // The state follows the manager's decisions and can't be set from outside
+    fn set_state(&mut self, _state: StateMap) {}
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        let mode = match command {
+            "turn_on" => Mode::On,
+            "turn_off" => Mode::Off,
+            "auto" => Mode::Auto,
+            _ => {
+                log::warn!("Switch '{}' does not accept command '{}'", self.id, command);
+                return;
+            }
+        };
+        let duration = parameters
+            .as_ref()
+            .and_then(|parameters| parameters.get("duration_secs"))
+            .and_then(|secs| secs.parse::<i64>().ok())
+            .map(Duration::seconds);
+        hold(&self.manager, &self.events, &self.load, mode, duration);
+        self.wake.notify_one();
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(Type::Switch)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        Some(vec![
+            "turn_on".to_string(),
+            "turn_off".to_string(),
+            "auto".to_string(),
+        ])
+    }
+}
+
+/// Sets the override of `load` and publishes its switch's new mode.
+fn hold(
+    manager: &Mutex<Manager>,
+    events: &EventBus,
+    load: &str,
+    mode: Mode,
+    duration: Option<Duration>,
+) {
+    let now = Utc::now();
+    let old = {
+        let mut manager = manager.lock().unwrap();
+        let state = manager.loads.entry(load.to_string()).or_default();
+        let old = state.mode(now);
+        state.held = match mode {
+            Mode::Auto => None,
+            mode => Some(Override {
+                mode,
+                until: duration.map(|duration| now + duration),
+            }),
+        };
+        old
+    };
+    if old != mode {
+        publish(events, &switch_id(load), "mode", old.name(), mode.name());
+    }
+}
+
+fn publish(events: &EventBus, device_id: &str, key: &str, old_value: &str, new_value: &str) {
+    let published = events.publish(EventKind::StateChanged {
+        device_id: device_id.to_string(),
+        key: key.to_string(),
+        old_value: Some(old_value.to_string()),
+        new_value: new_value.to_string(),
+        reported_at: None,
+    });
+    if let Err(e) = published {
+        log::warn!("Failed to publish energy state: {}", e);
+    }
+}
+
+/**
+ * Energy
+ * Reads PV production, the grid meter and the home battery, and runs
+ * flexible loads on the surplus to keep as much of the production in the
+ * house as possible. Every load gets an override switch in the device
+ * list, `energy.<load>`, to hold it on or off.
+ */
+pub struct Energy {
+    path: PathBuf,
+    config: RwLock<EnergyConfig>,
+    manager: Arc<Mutex<Manager>>,
+    devices: DeviceList,
+    events: Arc<EventBus>,
+    wake: Arc<Notify>,
+}
+
+impl Energy {
+    /// Opens the setup configured at `path` and adds the override switches
+    /// of its loads to `devices`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        devices: DeviceList,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            EnergyConfig::default()
+        };
+        let energy = Energy {
+            path,
+            config: RwLock::new(EnergyConfig::default()),
+            manager: Arc::new(Mutex::new(Manager::default())),
+            devices,
+            events,
+            wake: Arc::new(Notify::new()),
+        };
+        energy.apply(config);
+        Ok(energy)
+    }
+
+    pub fn config(&self) -> EnergyConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: EnergyConfig) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize energy setup: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        self.apply(config);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Switches to `config`, replacing the override switches of the
+    /// previous loads.
+    fn apply(&self, config: EnergyConfig) {
+        let switches: Vec<Box<dyn Device>> = config
+            .loads
+            .iter()
+            .map(|(id, load)| {
+                Box::new(OverrideSwitch {
+                    id: switch_id(id),
+                    name: load.name.clone().unwrap_or_else(|| id.clone()),
+                    load: id.clone(),
+                    manager: self.manager.clone(),
+                    events: self.events.clone(),
+                    wake: self.wake.clone(),
+                }) as Box<dyn Device>
+            })
+            .collect();
+        let mut devices = self.devices.write().unwrap();
+        let mut current = self.config.write().unwrap();
+        devices.retain(|device| {
+            !device
+                .get_id()
+                .strip_prefix(SWITCH_PREFIX)
+                .is_some_and(|load| current.loads.contains_key(load))
+        });
+        devices.extend(switches);
+        self.manager
+            .lock()
+            .unwrap()
+            .loads
+            .retain(|id, _| config.loads.contains_key(id));
+        *current = config;
+    }
+
+    /// Readings and loads as of the last decision.
+    pub fn status(&self) -> EnergyStatus {
+        let config = self.config.read().unwrap();
+        let manager = self.manager.lock().unwrap();
+        let now = Utc::now();
+        let mut status = manager.status.clone();
+        status.strategy = config.strategy;
+        status.loads = config
+            .loads
+            .iter()
+            .map(|(id, load)| {
+                let state = manager.loads.get(id).cloned().unwrap_or_default();
+                let mode = state.mode(now);
+                let until = match mode {
+                    Mode::Auto => None,
+                    _ => state.held.and_then(|held| held.until),
+                };
+                let status = LoadStatus {
+                    device_id: load.device_id.clone(),
+                    switch_id: switch_id(id),
+                    on: state.on,
+                    mode,
+                    current_a: state.amps,
+                    until,
+                    since: state.since,
+                    reason: state.reason,
+                };
+                (id.clone(), status)
+            })
+            .collect();
+        status
+    }
+
+    /// Holds the load `id` on or off, for `duration` if given, or hands it
+    /// back to the manager with `Mode::Auto`.
+    pub fn set_override(
+        &self,
+        id: &str,
+        mode: Mode,
+        duration: Option<Duration>,
+    ) -> Result<(), BlinkieError> {
+        if !self.config.read().unwrap().loads.contains_key(id) {
+            return Err(format!("Unknown load '{}'", id).into());
+        }
+        hold(&self.manager, &self.events, id, mode, duration);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Reads the meters, decides which loads to run and switches those
+    /// whose decision changed. Blocks while reading devices and sending
+    /// commands, so call it from blocking threads only.
+    pub fn control(&self, now: DateTime<Utc>, sender: &CommandSender) {
+        let config = self.config();
+        let readings = self.read(&config);
+        let available = available_power(&config, &readings, &self.manager.lock().unwrap());
+
+        let mut loads: Vec<(&String, &FlexibleLoad)> = config.loads.iter().collect();
+        loads.sort_by(|(a_id, a), (b_id, b)| b.priority.cmp(&a.priority).then(a_id.cmp(b_id)));
+        let mut left = available;
+        let mut switches = Vec::new();
+        {
+            let mut manager = self.manager.lock().unwrap();
+            for (id, load) in loads {
+                let state = manager.loads.entry(id.clone()).or_default();
+                let mode = state.mode(now);
+                let (on, reason) = decide(&config, load, state, left, now);
+                // Running loads with current control follow the surplus,
+                // or run at full current while held on
+                let amps = match (&load.current, on, mode) {
+                    (Some(current), true, Mode::On) => Some(current.max_a),
+                    (Some(current), true, Mode::Auto) if config.strategy != Strategy::Manual => {
+                        left.map(|left| current.amps_for(left))
+                    }
+                    _ => None,
+                }
+                .filter(|amps| state.amps != Some(*amps));
+                if on {
+                    if let Some(left) = left.as_mut() {
+                        *left -= load.power(amps.or(state.amps));
+                    }
+                }
+                // Held loads are switched once even if they seem to be in
+                // position already, since the manager may not know yet
+                let held = mode != Mode::Auto && state.since.is_none();
+                let switch = on != state.on || held;
+                if switch || amps.is_some() {
+                    switches.push((id.clone(), load.clone(), switch.then_some(on), amps, reason));
+                } else {
+                    state.reason = reason;
+                }
+            }
+            manager.status = EnergyStatus {
+                pv_w: readings.pv,
+                grid_w: readings.grid,
+                consumption_w: readings.consumption,
+                battery_soc: readings.soc,
+                battery_w: readings.battery,
+                available_w: available,
+                strategy: config.strategy,
+                loads: HashMap::new(),
+                updated: Some(now),
+            };
+        }
+
+        for (id, load, on, amps, reason) in switches {
+            // The current is set before starting, so the load doesn't
+            // start at whatever it ran at last
+            if let (Some(current), Some(amps)) = (&load.current, amps) {
+                let parameters = HashMap::from([(current.parameter.clone(), amps.to_string())]);
+                if let Err(e) = sender(&load.device_id, &current.command, parameters) {
+                    log::warn!("Failed to set current of load '{}': {}", id, e);
+                    continue;
+                }
+                let mut manager = self.manager.lock().unwrap();
+                let state = manager.loads.entry(id.clone()).or_default();
+                state.amps = Some(amps);
+                state.reason = reason.clone();
+            }
+            let Some(on) = on else {
+                continue;
+            };
+            let command = if on {
+                &load.on_command
+            } else {
+                &load.off_command
+            };
+            if let Err(e) = sender(&load.device_id, command, HashMap::new()) {
+                log::warn!("Failed to switch load '{}': {}", id, e);
+                continue;
+            }
+            let was_on = {
+                let mut manager = self.manager.lock().unwrap();
+                let state = manager.loads.entry(id.clone()).or_default();
+                let was_on = state.on;
+                state.on = on;
+                state.since = Some(now);
+                state.reason = reason;
+                was_on
+            };
+            if on != was_on {
+                let (old, new) = if on { ("off", "on") } else { ("on", "off") };
+                publish(&self.events, &switch_id(&id), "state", old, new);
+            }
+        }
+    }
+
+    /// Current values of the configured meters. Devices or keys that are
+    /// missing or not numbers read as unknown.
+    fn read(&self, config: &EnergyConfig) -> Readings {
+        let devices = self.devices.read().unwrap();
+        let value = |device_id: &str, key: &str| {
+            devices
+                .iter()
+                .find(|device| device.get_id() == device_id)
+                .and_then(|device| device.get_state().get(key).and_then(StateValue::as_f64))
+        };
+        let reading = |reading: &Option<Reading>| {
+            reading
+                .as_ref()
+                .and_then(|reading| value(&reading.device_id, &reading.key))
+        };
+        let battery = config.battery.as_ref();
+        Readings {
+            pv: reading(&config.pv),
+            grid: reading(&config.grid),
+            consumption: reading(&config.consumption),
+            soc: battery.and_then(|battery| value(&battery.device_id, &battery.soc_key)),
+            battery: battery
+                .and_then(|battery| value(&battery.device_id, battery.power_key.as_deref()?)),
+        }
+    }
+
+    /// Decides every `interval_secs`, and right away when the setup or an
+    /// override changes, until the task is dropped.
+    pub async fn run(self: Arc<Self>, sender: CommandSender) {
+        loop {
+            let energy = self.clone();
+            let sender = sender.clone();
+            let _ = tokio::task::spawn_blocking(move || energy.control(Utc::now(), &sender)).await;
+            let interval = self.config.read().unwrap().interval_secs.max(1);
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(interval)) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+}
+
+/// Watts flexible loads may draw, counting what the running ones already
+/// draw, or `None` without meters to tell. Power the battery discharges
+/// with never counts; power it charges with counts unless the battery
+/// comes first and isn't full yet.
+fn available_power(config: &EnergyConfig, readings: &Readings, manager: &Manager) -> Option<f64> {
+    let surplus = match (readings.grid, readings.pv, readings.consumption) {
+        (Some(grid), _, _) => -grid,
+        (None, Some(pv), Some(consumption)) => pv - consumption,
+        _ => return None,
+    };
+    let running: f64 = config
+        .loads
+        .iter()
+        .filter_map(|(id, load)| {
+            let state = manager.loads.get(id).filter(|state| state.on)?;
+            Some(load.power(state.amps))
+        })
+        .sum();
+    let battery = readings.battery.unwrap_or(0.0);
+    let battery_full = match (&config.battery, readings.soc) {
+        (Some(battery), Some(soc)) => soc >= battery.full_soc,
+        _ => false,
+    };
+    let charging = match config.strategy {
+        Strategy::BatteryFirst if !battery_full => 0.0,
+        _ => battery.max(0.0),
+    };
+    Some(surplus + running + charging + battery.min(0.0))
+}
+
+/// Whether `load` should run with `available` watts left for it, and why.
+fn decide(
+    config: &EnergyConfig,
+    load: &FlexibleLoad,
+    state: &LoadState,
+    available: Option<f64>,
+    now: DateTime<Utc>,
+) -> (bool, String) {
+    match state.mode(now) {
+        Mode::On => return (true, "held on".to_string()),
+        Mode::Off => return (false, "held off".to_string()),
+        Mode::Auto => {}
+    }
+    if config.strategy == Strategy::Manual {
+        return (state.on, "manual strategy".to_string());
+    }
+    let min_secs = if state.on {
+        load.min_on_secs
+    } else {
+        load.min_off_secs
+    };
+    if let Some(since) = state.since {
+        if now - since < Duration::seconds(min_secs as i64) {
+            return (state.on, format!("kept for at least {} seconds", min_secs));
+        }
+    }
+    let Some(available) = available else {
+        return (false, "no surplus reading".to_string());
+    };
+    // Running loads may import a little before stopping, so they don't
+    // stop the moment production dips below their draw
+    let needed = if state.on {
+        load.min_power() - config.max_import_w
+    } else {
+        load.min_power()
+    };
+    if available >= needed {
+        (true, format!("{:.0} W surplus", available))
+    } else {
+        (
+            false,
+            format!("{:.0} W surplus, needs {:.0} W", available, needed),
+        )
+    }
+}
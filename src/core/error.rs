@@ -0,0 +1,84 @@
+use std::sync::PoisonError;
+use thiserror::Error;
+
+/**
+ * BlinkieError
+ * What went wrong in a fallible operation of the hub, so callers can tell
+ * e.g. a device that is unreachable from one that doesn't know a command.
+ * Every variant carries the full message, which is what it displays as.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum BlinkieError {
+    /// No handler serves the protocol, or the preferred one isn't there.
+    #[error("{0}")]
+    HandlerNotFound(String),
+    /// A device, broker or service could not be reached, or dropped.
+    #[error("{0}")]
+    Connection(String),
+    /// A device, script or service did not answer in time.
+    #[error("{0}")]
+    Timeout(String),
+    /// The device or its handler doesn't know the command.
+    #[error("Device '{device}' does not support command '{command}'")]
+    UnsupportedCommand { device: String, command: String },
+    /// Something could not be parsed or serialized.
+    #[error("{0}")]
+    Serialization(String),
+    /// A lock was poisoned by a thread panicking while holding it.
+    #[error("{0}")]
+    LockPoisoned(String),
+    /// Any other failure, e.g. invalid input or a file that can't be written.
+    #[error("{0}")]
+    Other(String),
+}
+
+impl BlinkieError {
+    pub fn handler_not_found(message: impl Into<String>) -> Self {
+        BlinkieError::HandlerNotFound(message.into())
+    }
+
+    pub fn connection(message: impl Into<String>) -> Self {
+        BlinkieError::Connection(message.into())
+    }
+
+    pub fn timeout(message: impl Into<String>) -> Self {
+        BlinkieError::Timeout(message.into())
+    }
+
+    pub fn unsupported_command(device: &str, command: &str) -> Self {
+        BlinkieError::UnsupportedCommand {
+            device: device.to_string(),
+            command: command.to_string(),
+        }
+    }
+
+    pub fn serialization(message: impl Into<String>) -> Self {
+        BlinkieError::Serialization(message.into())
+    }
+}
+
+impl From<String> for BlinkieError {
+    fn from(message: String) -> Self {
+        BlinkieError::Other(message)
+    }
+}
+
+impl From<&str> for BlinkieError {
+    fn from(message: &str) -> Self {
+        BlinkieError::Other(message.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for BlinkieError {
+    fn from(e: PoisonError<T>) -> Self {
+        BlinkieError::LockPoisoned(e.to_string())
+    }
+}
+
+/// Lets callers still reporting errors as plain messages, like the CLI,
+/// use `?` on hub operations.
+impl From<BlinkieError> for String {
+    fn from(e: BlinkieError) -> Self {
+        e.to_string()
+    }
+}
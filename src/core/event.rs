@@ -0,0 +1,535 @@
+use super::degraded::{self, StoreFailure, JOURNAL_STORE};
+use super::error::BlinkieError;
+use super::hot_keys::{HotKeyConfig, HotKeyStats, HotKeys};
+use super::job::JobState;
+use super::journal::EventJournal;
+use super::memory::{CacheMetrics, Eviction, MemoryLimits};
+use super::retention::{DataCategory, PurgeTarget, RetentionPolicy};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+/// Number of events a slow subscriber may fall behind before it starts lagging.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/**
+ * Event Kinds
+ * Everything happening inside the hub that other parts may want to react to.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
+pub enum EventKind {
+    StateChanged {
+        device_id: String,
+        key: String,
+        old_value: Option<String>,
+        new_value: String,
+        /// When the device says it measured the value, if it reports that.
+        /// The event's own timestamp is when the hub received it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reported_at: Option<DateTime<Utc>>,
+    },
+    CommandSent {
+        device_id: String,
+        command: String,
+        parameters: Option<HashMap<String, String>>,
+    },
+    DeviceRegistered {
+        device_id: String,
+    },
+    /// A long-running command started, progressed or finished.
+    JobUpdated {
+        job_id: u64,
+        device_id: String,
+        command: String,
+        state: JobState,
+        percent: Option<u8>,
+        phase: Option<String>,
+    },
+    PresenceChanged {
+        person_id: String,
+        state: String,
+    },
+    LocationUpdated {
+        person_id: String,
+        latitude: f64,
+        longitude: f64,
+    },
+    Custom {
+        name: String,
+        data: HashMap<String, String>,
+    },
+}
+
+impl EventKind {
+    /// Id of the device this event is about, if it concerns a single device.
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            EventKind::StateChanged { device_id, .. }
+            | EventKind::CommandSent { device_id, .. }
+            | EventKind::DeviceRegistered { device_id }
+            | EventKind::JobUpdated { device_id, .. } => Some(device_id),
+            _ => None,
+        }
+    }
+
+    /// Id of the person this event is about, if any.
+    pub fn person_id(&self) -> Option<&str> {
+        match self {
+            EventKind::PresenceChanged { person_id, .. }
+            | EventKind::LocationUpdated { person_id, .. } => Some(person_id),
+            _ => None,
+        }
+    }
+
+    /// Name of the event type, as used in the `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::StateChanged { .. } => "state_changed",
+            EventKind::CommandSent { .. } => "command_sent",
+            EventKind::DeviceRegistered { .. } => "device_registered",
+            EventKind::JobUpdated { .. } => "job_updated",
+            EventKind::PresenceChanged { .. } => "presence_changed",
+            EventKind::LocationUpdated { .. } => "location_updated",
+            EventKind::Custom { .. } => "custom",
+        }
+    }
+
+    /// Short human readable description, e.g. for terminal output.
+    pub fn summary(&self) -> String {
+        match self {
+            EventKind::StateChanged {
+                device_id,
+                key,
+                new_value,
+                ..
+            } => format!("{} {}={}", device_id, key, new_value),
+            EventKind::CommandSent {
+                device_id, command, ..
+            } => format!("{} <- {}", device_id, command),
+            EventKind::DeviceRegistered { device_id } => format!("{} registered", device_id),
+            EventKind::JobUpdated {
+                job_id,
+                device_id,
+                command,
+                state,
+                percent,
+                phase,
+            } => {
+                let mut summary = format!("{} job #{} {} {:?}", device_id, job_id, command, state);
+                if let Some(percent) = percent {
+                    summary.push_str(&format!(" {}%", percent));
+                }
+                if let Some(phase) = phase {
+                    summary.push_str(&format!(" {}", phase));
+                }
+                summary
+            }
+            EventKind::PresenceChanged { person_id, state } => {
+                format!("{} is {}", person_id, state)
+            }
+            EventKind::LocationUpdated {
+                person_id,
+                latitude,
+                longitude,
+            } => format!("{} at {:.4},{:.4}", person_id, latitude, longitude),
+            EventKind::Custom { name, .. } => format!("custom {}", name),
+        }
+    }
+
+    /// Retention category this event's data belongs to.
+    pub fn category(&self) -> DataCategory {
+        match self {
+            EventKind::StateChanged { .. } => DataCategory::Sensor,
+            EventKind::CommandSent { .. } | EventKind::JobUpdated { .. } => DataCategory::Command,
+            EventKind::PresenceChanged { .. } => DataCategory::Presence,
+            EventKind::LocationUpdated { .. } => DataCategory::Location,
+            EventKind::DeviceRegistered { .. } | EventKind::Custom { .. } => DataCategory::System,
+        }
+    }
+}
+
+/**
+ * Event
+ * A published event, stamped with its sequence number and publish time.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
+pub struct Event {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub kind: EventKind,
+}
+
+/**
+ * EventFilter
+ * Narrows an event stream down to a device and/or a set of event types.
+ * Empty fields match everything.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EventFilter {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(device_id) = &self.device_id {
+            if event.kind.device_id() != Some(device_id.as_str()) {
+                return false;
+            }
+        }
+        self.types.is_empty() || self.types.iter().any(|t| t == event.kind.name())
+    }
+}
+
+struct BusState {
+    next_seq: u64,
+    journal: Option<EventJournal>,
+    /// Bumped whenever journaled events are removed.
+    rewrites: u64,
+    /// Events not journaled yet, while the journal fails.
+    backlog: VecDeque<Event>,
+    backlog_limit: usize,
+    eviction: Eviction,
+    evicted: u64,
+    coalesced: u64,
+    journal_failure: Option<StoreFailure>,
+    /// Sampling of state keys changing too often to journal every change.
+    hot: HotKeys,
+}
+
+impl BusState {
+    fn notice(&mut self, error: Option<&str>) -> Event {
+        let event = Event {
+            seq: self.next_seq,
+            timestamp: Utc::now(),
+            kind: degraded::notice(JOURNAL_STORE, error),
+        };
+        self.next_seq += 1;
+        self.backlog.push_back(event.clone());
+        event
+    }
+
+    /// Journals the backlog, oldest first, and returns the events telling
+    /// the journal failed or recovered that came up doing so.
+    fn drain(&mut self) -> Vec<Event> {
+        let mut notices = Vec::new();
+        if self.journal.is_none() {
+            return notices;
+        }
+        // Skipped changes of hot keys get journaled as new events, after
+        // the ones subscribers saw since
+        for mut event in self.hot.due(Instant::now()) {
+            event.seq = self.next_seq;
+            self.next_seq += 1;
+            self.backlog.push_back(event);
+        }
+        loop {
+            while let Some(event) = self.backlog.front() {
+                let journal = self.journal.as_mut().unwrap();
+                let Err(e) = journal.append(event) else {
+                    self.backlog.pop_front();
+                    continue;
+                };
+                self.evict();
+                match self.journal_failure.as_mut() {
+                    Some(failure) => {
+                        failure.error = e.to_string();
+                        failure.queued = self.backlog.len();
+                    }
+                    None => {
+                        log::error!(
+                            "Event journal fails, keeping events in memory until it works again: {}",
+                            e
+                        );
+                        let notice = self.notice(Some(&e.to_string()));
+                        self.journal_failure = Some(StoreFailure::new(&e, self.backlog.len()));
+                        notices.push(notice);
+                    }
+                }
+                return notices;
+            }
+            if self.journal_failure.take().is_none() {
+                return notices;
+            }
+            log::info!("Event journal works again");
+            notices.push(self.notice(None));
+        }
+    }
+
+    /// Lets go of events until the backlog fits its limit.
+    fn evict(&mut self) {
+        while self.backlog.len() > self.backlog_limit {
+            if self.eviction == Eviction::Coalesce {
+                if let Some(index) = self.superseded() {
+                    self.backlog.remove(index);
+                    self.coalesced += 1;
+                    continue;
+                }
+            }
+            self.backlog.pop_front();
+            self.evicted += 1;
+        }
+    }
+
+    /// Index of the oldest state change in the backlog that a later change
+    /// of the same key makes up for.
+    fn superseded(&self) -> Option<usize> {
+        let mut later = HashSet::new();
+        let mut oldest = None;
+        for (index, event) in self.backlog.iter().enumerate().rev() {
+            if let EventKind::StateChanged { device_id, key, .. } = &event.kind {
+                if !later.insert((device_id, key)) {
+                    oldest = Some(index);
+                }
+            }
+        }
+        oldest
+    }
+}
+
+/**
+ * EventBus
+ * Fans events out to live subscribers and, when a journal is attached,
+ * persists them so subscribers can catch up on what they missed.
+ */
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    state: Mutex<BusState>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    /// Creates an in-memory bus without replay support.
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventBus {
+            sender,
+            state: Mutex::new(BusState {
+                next_seq: 1,
+                journal: None,
+                rewrites: 0,
+                backlog: VecDeque::new(),
+                backlog_limit: MemoryLimits::default().journal_backlog,
+                eviction: Eviction::default(),
+                evicted: 0,
+                coalesced: 0,
+                journal_failure: None,
+                hot: HotKeys::default(),
+            }),
+        }
+    }
+
+    /// Creates a bus that persists every event to `journal`, continuing its sequence.
+    pub fn with_journal(journal: EventJournal) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventBus {
+            sender,
+            state: Mutex::new(BusState {
+                next_seq: journal.last_seq().map_or(1, |seq| seq + 1),
+                journal: Some(journal),
+                rewrites: 0,
+                backlog: VecDeque::new(),
+                backlog_limit: MemoryLimits::default().journal_backlog,
+                eviction: Eviction::default(),
+                evicted: 0,
+                coalesced: 0,
+                journal_failure: None,
+                hot: HotKeys::default(),
+            }),
+        }
+    }
+
+    /// Publishes an event, journaling it first so that no subscriber sees an
+    /// event which would be missing from a later replay. While the journal
+    /// fails, events are kept in memory and journaled once it works again,
+    /// so the hub keeps running; replays include them meanwhile.
+    pub fn publish(&self, kind: EventKind) -> Result<Event, BlinkieError> {
+        let mut state = self.state.lock().unwrap();
+
+        let event = Event {
+            seq: state.next_seq,
+            timestamp: Utc::now(),
+            kind,
+        };
+        state.next_seq += 1;
+        let mut notices = Vec::new();
+        if state.journal.is_some() {
+            if state.hot.admit(&event, Instant::now()) {
+                state.backlog.push_back(event.clone());
+            }
+            notices = state.drain();
+        }
+
+        // Having nobody listening is not an error
+        let _ = self.sender.send(event.clone());
+        for notice in notices {
+            let _ = self.sender.send(notice);
+        }
+        Ok(event)
+    }
+
+    /// Tries again to journal the events kept in memory while the journal
+    /// fails.
+    pub fn retry_journal(&self) {
+        let notices = self.state.lock().unwrap().drain();
+        for notice in notices {
+            let _ = self.sender.send(notice);
+        }
+    }
+
+    /// Sets which state keys are hot and how their changes are sampled.
+    pub fn set_hot_keys(&self, config: HotKeyConfig) {
+        self.state.lock().unwrap().hot.set_config(config);
+    }
+
+    pub fn hot_key_config(&self) -> HotKeyConfig {
+        self.state.lock().unwrap().hot.config().clone()
+    }
+
+    /// How often each state key changes and how much of it was journaled.
+    pub fn hot_keys(&self) -> Vec<HotKeyStats> {
+        self.state.lock().unwrap().hot.stats()
+    }
+
+    /// Caps the events kept in memory while the journal fails.
+    pub fn limit_backlog(&self, limit: usize, eviction: Eviction) {
+        let mut state = self.state.lock().unwrap();
+        state.backlog_limit = limit;
+        state.eviction = eviction;
+        state.evict();
+    }
+
+    pub fn backlog_metrics(&self) -> CacheMetrics {
+        let state = self.state.lock().unwrap();
+        CacheMetrics {
+            entries: state.backlog.len(),
+            limit: state.backlog_limit,
+            evicted: state.evicted,
+            coalesced: state.coalesced,
+        }
+    }
+
+    /// Since when and why the journal fails, if it does.
+    pub fn journal_failure(&self) -> Option<StoreFailure> {
+        self.state.lock().unwrap().journal_failure.clone()
+    }
+
+    /// Subscribes to events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes and returns every journaled event after `last_seen`, without
+    /// a gap or duplicate between the replayed events and the live receiver.
+    pub fn resume(
+        &self,
+        last_seen: u64,
+    ) -> Result<(Vec<Event>, broadcast::Receiver<Event>), BlinkieError> {
+        // Holding the lock keeps publishers out while we read the backlog
+        let state = self.state.lock().unwrap();
+        let journal = state
+            .journal
+            .as_ref()
+            .ok_or_else(|| "Event journal is not enabled".to_string())?;
+
+        let mut missed = journal.read_from(last_seen + 1)?;
+        missed.extend(
+            state
+                .backlog
+                .iter()
+                .filter(|event| event.seq > last_seen)
+                .cloned(),
+        );
+        Ok((missed, self.sender.subscribe()))
+    }
+
+    /// Counts how often journaled events were removed, so that copies of the
+    /// journal can tell when they have to be rebuilt.
+    pub fn rewrites(&self) -> u64 {
+        self.state.lock().unwrap().rewrites
+    }
+
+    /// Returns journaled events starting at sequence number `from_seq`.
+    pub fn replay(&self, from_seq: u64) -> Result<Vec<Event>, BlinkieError> {
+        let state = self.state.lock().unwrap();
+        let mut events = match state.journal.as_ref() {
+            Some(journal) => journal.read_from(from_seq)?,
+            None => return Err("Event journal is not enabled".into()),
+        };
+        events.extend(
+            state
+                .backlog
+                .iter()
+                .filter(|event| event.seq >= from_seq)
+                .cloned(),
+        );
+        Ok(events)
+    }
+
+    /// Returns journaled events recorded between `from` and `to`, e.g. to see what happened last night.
+    pub fn replay_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<Event>, BlinkieError> {
+        let state = self.state.lock().unwrap();
+        match state.journal.as_ref() {
+            Some(journal) => journal.read_between(from, to),
+            None => Err("Event journal is not enabled".into()),
+        }
+    }
+
+    /// Drops journaled events that have outlived the retention policy.
+    /// Returns the number of events removed.
+    pub fn apply_retention(
+        &self,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+    ) -> Result<usize, BlinkieError> {
+        let mut state = self.state.lock().unwrap();
+        state.rewrites += 1;
+        match state.journal.as_mut() {
+            Some(journal) => journal.retain(|event| !policy.is_expired(event, now)),
+            None => Ok(0),
+        }
+    }
+
+    /// Drops journaled events up to and including `seq`, e.g. once they were
+    /// archived elsewhere. Returns the number of events removed.
+    pub fn remove_until(&self, seq: u64) -> Result<usize, BlinkieError> {
+        let mut state = self.state.lock().unwrap();
+        state.rewrites += 1;
+        match state.journal.as_mut() {
+            Some(journal) => journal.retain(|event| event.seq > seq),
+            None => Ok(0),
+        }
+    }
+
+    /// Deletes every journaled event holding data about the target, and
+    /// the samples of a device's hot keys not journaled yet. Returns the
+    /// number of events removed.
+    pub fn purge(&self, target: &PurgeTarget) -> Result<usize, BlinkieError> {
+        let mut state = self.state.lock().unwrap();
+        if let PurgeTarget::Device(device_id) = target {
+            state.hot.forget(device_id);
+        }
+        state.rewrites += 1;
+        match state.journal.as_mut() {
+            Some(journal) => journal.retain(|event| !target.matches(event)),
+            None => Ok(0),
+        }
+    }
+}
@@ -0,0 +1,547 @@
+use super::device::{DeviceList, Type};
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::maintenance::MaintenanceTask;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const STATE_FILE: &str = ".export-state.json";
+/// Directory for devices whose class is not known.
+const UNCLASSIFIED: &str = "unclassified";
+
+/**
+ * Export Formats
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// One file per month that new rows are appended to.
+    #[default]
+    Csv,
+    /// One directory per month holding a part file per export run.
+    /// Requires the `parquet` feature.
+    Parquet,
+}
+
+/**
+ * ClassExport
+ * Export settings of a device class.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClassExport {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// State keys to export; all keys if empty.
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+/**
+ * ExportConfig
+ * Which device classes are exported and how. Classes without an entry
+ * fall back to `default`, and are skipped when there is none. A relative
+ * `dir` lies in the data directory, e.g.
+ *
+ * ```toml
+ * [settings.export]
+ * dir = "export"
+ * default = { format = "csv" }
+ * classes.Sensor = { format = "parquet", keys = ["temperature"] }
+ * ```
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExportConfig {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub default: Option<ClassExport>,
+    #[serde(default)]
+    pub classes: HashMap<Type, ClassExport>,
+}
+
+impl ExportConfig {
+    fn for_class(&self, class: Option<Type>) -> Option<&ClassExport> {
+        class
+            .and_then(|class| self.classes.get(&class))
+            .or(self.default.as_ref())
+    }
+}
+
+/**
+ * ExportRow
+ * A single state change as written to the export files.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct ExportRow {
+    pub seq: u64,
+    pub timestamp: String,
+    pub device_id: String,
+    pub key: String,
+    pub old_value: Option<String>,
+    pub new_value: String,
+    /// `new_value` parsed as a number, when it is one.
+    pub numeric_value: Option<f64>,
+}
+
+impl ExportRow {
+    fn from_event(event: &Event) -> Option<Self> {
+        let EventKind::StateChanged {
+            device_id,
+            key,
+            old_value,
+            new_value,
+            ..
+        } = &event.kind
+        else {
+            return None;
+        };
+        Some(ExportRow {
+            seq: event.seq,
+            timestamp: event.timestamp.to_rfc3339(),
+            device_id: device_id.clone(),
+            key: key.clone(),
+            old_value: old_value.clone(),
+            numeric_value: new_value.parse().ok(),
+            new_value: new_value.clone(),
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ExportState {
+    /// Sequence number of the last event looked at.
+    last_seq: u64,
+}
+
+/**
+ * HistoryExportTask
+ * Appends state changes from the event journal to per-class, per-month CSV
+ * or Parquet files for long-term archival and offline analysis.
+ */
+pub struct HistoryExportTask {
+    events: Arc<EventBus>,
+    config: ExportConfig,
+    /// Devices whose class decides how their rows are exported.
+    devices: DeviceList,
+    lock: Mutex<()>,
+}
+
+impl HistoryExportTask {
+    pub fn new(events: Arc<EventBus>, config: ExportConfig, devices: DeviceList) -> Self {
+        HistoryExportTask {
+            events,
+            config,
+            devices,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Exports everything recorded since the previous run. Returns the number of rows written.
+    pub fn export(&self) -> Result<usize, BlinkieError> {
+        let _guard = self.lock.lock().unwrap();
+        let dir = &self.config.dir;
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+        let state_path = dir.join(STATE_FILE);
+        let state: ExportState = match fs::read_to_string(&state_path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!(
+                    "Failed to parse {}: {}",
+                    state_path.display(),
+                    e
+                ))
+            })?,
+            Err(_) => ExportState::default(),
+        };
+
+        let events = self.events.replay(state.last_seq + 1)?;
+        let Some(last_seq) = events.last().map(|event| event.seq) else {
+            return Ok(0);
+        };
+
+        let classes: HashMap<String, Type> = self
+            .devices
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|device| Some((device.get_id().to_string(), device.get_type()?)))
+            .collect();
+
+        // Group rows by class directory, month and format
+        let mut batches: BTreeMap<(String, String, ExportFormat), Vec<ExportRow>> = BTreeMap::new();
+        for event in &events {
+            let Some(row) = ExportRow::from_event(event) else {
+                continue;
+            };
+            let class = classes.get(&row.device_id).copied();
+            let Some(settings) = self.config.for_class(class) else {
+                continue;
+            };
+            if !settings.keys.is_empty() && !settings.keys.contains(&row.key) {
+                continue;
+            }
+            let class_dir = class.map_or(UNCLASSIFIED.to_string(), |class| {
+                format!("{:?}", class).to_lowercase()
+            });
+            let month = event.timestamp.format("%Y-%m").to_string();
+            batches
+                .entry((class_dir, month, settings.format))
+                .or_default()
+                .push(row);
+        }
+
+        let mut written = 0;
+        for ((class_dir, month, format), rows) in batches {
+            let class_dir = dir.join(class_dir);
+            fs::create_dir_all(&class_dir)
+                .map_err(|e| format!("Failed to create {}: {}", class_dir.display(), e))?;
+            match format {
+                ExportFormat::Csv => append_csv(&class_dir.join(format!("{}.csv", month)), &rows)?,
+                ExportFormat::Parquet => write_parquet(&class_dir.join(&month), &rows)?,
+            }
+            written += rows.len();
+        }
+
+        let contents = serde_json::to_string(&ExportState { last_seq }).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize export state: {}", e))
+        })?;
+        let tmp_path = state_path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &state_path))
+            .map_err(|e| format!("Failed to write {}: {}", state_path.display(), e))?;
+        Ok(written)
+    }
+
+    /// Rewrites the export files without the rows of `device_id`. Returns
+    /// the number of rows removed.
+    pub fn purge(&self, device_id: &str) -> Result<usize, BlinkieError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut removed = 0;
+        for class_dir in entries(&self.config.dir)? {
+            if !class_dir.is_dir() {
+                continue;
+            }
+            for path in entries(&class_dir)? {
+                if path.is_dir() {
+                    removed += purge_parquet(&path, device_id)?;
+                } else if path.extension().is_some_and(|extension| extension == "csv") {
+                    removed += purge_csv(&path, device_id)?;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Paths in `dir`, none if it doesn't exist.
+fn entries(dir: &Path) -> Result<Vec<PathBuf>, BlinkieError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    Ok(entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect())
+}
+
+impl MaintenanceTask for HistoryExportTask {
+    fn name(&self) -> &str {
+        "history_export"
+    }
+
+    fn run(&self) -> Result<(), BlinkieError> {
+        let rows = self.export()?;
+        log::info!("Exported {} state change(s)", rows);
+        Ok(())
+    }
+}
+
+const CSV_HEADER: &str = "seq,timestamp,device_id,key,old_value,new_value,numeric_value";
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn append_csv(path: &Path, rows: &[ExportRow]) -> Result<(), BlinkieError> {
+    let new_file = !path.exists();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+    let mut contents = String::new();
+    if new_file {
+        contents.push_str(CSV_HEADER);
+        contents.push('\n');
+    }
+    for row in rows {
+        contents.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            row.seq,
+            row.timestamp,
+            csv_field(&row.device_id),
+            csv_field(&row.key),
+            row.old_value.as_deref().map(csv_field).unwrap_or_default(),
+            csv_field(&row.new_value),
+            row.numeric_value.map(|v| v.to_string()).unwrap_or_default(),
+        ));
+    }
+    file.write_all(contents.as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+}
+
+/// Records of a CSV file with their line ends; quoted fields may span
+/// lines.
+fn csv_records(contents: &str) -> Vec<&str> {
+    let mut records = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in contents.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '\n' if !quoted => {
+                records.push(&contents[start..=i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < contents.len() {
+        records.push(&contents[start..]);
+    }
+    records
+}
+
+fn purge_csv(path: &Path, device_id: &str) -> Result<usize, BlinkieError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let field = csv_field(device_id);
+    let mut kept = String::new();
+    let mut removed = 0;
+    for (index, record) in csv_records(&contents).into_iter().enumerate() {
+        // Sequence numbers and timestamps are never quoted, so the device
+        // id is what follows the second comma
+        let matches = record
+            .splitn(3, ',')
+            .nth(2)
+            .and_then(|rest| rest.strip_prefix(field.as_str()))
+            .is_some_and(|rest| rest.starts_with(','));
+        if index > 0 && matches {
+            removed += 1;
+        } else {
+            kept.push_str(record);
+        }
+    }
+    if removed > 0 {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, kept)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(removed)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn purge_parquet(dir: &Path, _device_id: &str) -> Result<usize, BlinkieError> {
+    if entries(dir)?.is_empty() {
+        return Ok(0);
+    }
+    Err(format!(
+        "Purging the Parquet export in {} requires blinkie to be built with the `parquet` feature",
+        dir.display()
+    )
+    .into())
+}
+
+/// Rewrites the part files in `dir` holding rows of `device_id` without
+/// them.
+#[cfg(feature = "parquet")]
+fn purge_parquet(dir: &Path, device_id: &str) -> Result<usize, BlinkieError> {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    let mut removed = 0;
+    for path in entries(dir)? {
+        if path
+            .extension()
+            .is_none_or(|extension| extension != "parquet")
+        {
+            continue;
+        }
+        let error =
+            |e: parquet::errors::ParquetError| format!("Failed to read {}: {}", path.display(), e);
+        let file = fs::File::open(&path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let reader = SerializedFileReader::new(file).map_err(error)?;
+        let mut rows = Vec::new();
+        for row in reader.get_row_iter(None).map_err(error)? {
+            let row = row.map_err(error)?;
+            rows.push(ExportRow {
+                seq: row.get_long(0).map_err(error)? as u64,
+                timestamp: chrono::DateTime::from_timestamp_micros(
+                    row.get_timestamp_micros(1).map_err(error)?,
+                )
+                .map(|timestamp| timestamp.to_rfc3339())
+                .unwrap_or_default(),
+                device_id: row.get_string(2).map_err(error)?.clone(),
+                key: row.get_string(3).map_err(error)?.clone(),
+                old_value: row.get_string(4).ok().cloned(),
+                new_value: row.get_string(5).map_err(error)?.clone(),
+                numeric_value: row.get_double(6).ok(),
+            });
+        }
+        let count = rows.len();
+        rows.retain(|row| row.device_id != device_id);
+        if rows.len() == count {
+            continue;
+        }
+        removed += count - rows.len();
+        write_parquet(dir, &rows)?;
+        // Parts are named after their first and last row, so the rewritten
+        // part only replaces the old one when those stayed
+        let rewritten = match (rows.first(), rows.last()) {
+            (Some(first), Some(last)) => Some(dir.join(part_name(first, last))),
+            _ => None,
+        };
+        if rewritten.as_ref() != Some(&path) {
+            fs::remove_file(&path)
+                .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(feature = "parquet")]
+fn part_name(first: &ExportRow, last: &ExportRow) -> String {
+    format!("part-{}-{}.parquet", first.seq, last.seq)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(_dir: &Path, _rows: &[ExportRow]) -> Result<(), BlinkieError> {
+    Err(
+        "Parquet export requires blinkie to be built with the `parquet` feature"
+            .to_string()
+            .into(),
+    )
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet(dir: &Path, rows: &[ExportRow]) -> Result<(), BlinkieError> {
+    use parquet::basic::Compression;
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    const SCHEMA: &str = "
+        message state_change {
+            REQUIRED INT64 seq;
+            REQUIRED INT64 timestamp (TIMESTAMP(MICROS, true));
+            REQUIRED BYTE_ARRAY device_id (UTF8);
+            REQUIRED BYTE_ARRAY key (UTF8);
+            OPTIONAL BYTE_ARRAY old_value (UTF8);
+            REQUIRED BYTE_ARRAY new_value (UTF8);
+            OPTIONAL DOUBLE numeric_value;
+        }
+    ";
+
+    let (Some(first), Some(last)) = (rows.first(), rows.last()) else {
+        return Ok(());
+    };
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let name = part_name(first, last);
+    let path = dir.join(&name);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+
+    let error =
+        |e: parquet::errors::ParquetError| format!("Failed to write {}: {}", path.display(), e);
+    let schema = Arc::new(parse_message_type(SCHEMA).map_err(error)?);
+    let properties = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build(),
+    );
+    let file = fs::File::create(&tmp_path)
+        .map_err(|e| format!("Failed to create {}: {}", tmp_path.display(), e))?;
+    let mut writer = SerializedFileWriter::new(file, schema, properties).map_err(error)?;
+    let mut group = writer.next_row_group().map_err(error)?;
+
+    let strings = |f: fn(&ExportRow) -> &str| -> Vec<ByteArray> {
+        rows.iter().map(|row| ByteArray::from(f(row))).collect()
+    };
+    let mut column = 0;
+    while let Some(mut writer) = group.next_column().map_err(error)? {
+        match column {
+            0 => {
+                let seqs: Vec<i64> = rows.iter().map(|row| row.seq as i64).collect();
+                writer.typed::<Int64Type>().write_batch(&seqs, None, None)
+            }
+            1 => {
+                let timestamps: Vec<i64> = rows
+                    .iter()
+                    .map(|row| {
+                        chrono::DateTime::parse_from_rfc3339(&row.timestamp)
+                            .map(|t| t.timestamp_micros())
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                writer
+                    .typed::<Int64Type>()
+                    .write_batch(&timestamps, None, None)
+            }
+            2 => writer.typed::<ByteArrayType>().write_batch(
+                &strings(|row| &row.device_id),
+                None,
+                None,
+            ),
+            3 => writer
+                .typed::<ByteArrayType>()
+                .write_batch(&strings(|row| &row.key), None, None),
+            4 => {
+                let values: Vec<ByteArray> = rows
+                    .iter()
+                    .filter_map(|row| row.old_value.as_deref().map(ByteArray::from))
+                    .collect();
+                let levels: Vec<i16> = rows
+                    .iter()
+                    .map(|row| row.old_value.is_some() as i16)
+                    .collect();
+                writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, Some(&levels), None)
+            }
+            5 => writer.typed::<ByteArrayType>().write_batch(
+                &strings(|row| &row.new_value),
+                None,
+                None,
+            ),
+            _ => {
+                let values: Vec<f64> = rows.iter().filter_map(|row| row.numeric_value).collect();
+                let levels: Vec<i16> = rows
+                    .iter()
+                    .map(|row| row.numeric_value.is_some() as i16)
+                    .collect();
+                writer
+                    .typed::<DoubleType>()
+                    .write_batch(&values, Some(&levels), None)
+            }
+        }
+        .map_err(error)?;
+        writer.close().map_err(error)?;
+        column += 1;
+    }
+    group.close().map_err(error)?;
+    writer.close().map_err(error)?;
+    fs::rename(&tmp_path, &path)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+}
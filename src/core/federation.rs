@@ -0,0 +1,453 @@
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::freshness::ClockOffset;
+use super::value::{StateMap, StateValue};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Path satellites post their batches to on the hub.
+pub const SYNC_PATH: &str = "/api/federation/sync";
+/// Largest decompressed batch the hub accepts.
+const MAX_BATCH_BYTES: u64 = 16 * 1024 * 1024;
+/// Acknowledged snapshots the hub keeps per satellite, so a batch resent
+/// after a lost acknowledgment still applies.
+const KEEP_SNAPSHOTS: usize = 2;
+
+/// Device states by device id, then key.
+pub type States = HashMap<String, StateMap>;
+/// Changed keys by device id; `None` removes a key.
+pub type Changes = BTreeMap<String, BTreeMap<String, Option<StateValue>>>;
+
+/**
+ * SyncBatch
+ * What a satellite ships to its hub: the keys that changed since the last
+ * batch the hub acknowledged. A `base` of 0 carries a full snapshot.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncBatch {
+    pub satellite_id: String,
+    pub seq: u64,
+    /// Sequence number of the acknowledged batch `changes` are relative to.
+    pub base: u64,
+    #[serde(default)]
+    pub changes: Changes,
+    /// When the satellite sent the batch, by its clock.
+    #[serde(default)]
+    pub sent: Option<DateTime<Utc>>,
+}
+
+impl SyncBatch {
+    /// Gzipped JSON, as sent over the wire. Returns the encoded bytes and
+    /// the size before compression.
+    pub fn encode(&self) -> Result<(Vec<u8>, usize), BlinkieError> {
+        let json = serde_json::to_vec(self).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize batch: {}", e))
+        })?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder
+            .write_all(&json)
+            .map_err(|e| format!("Failed to compress batch: {}", e))?;
+        let bytes = encoder
+            .finish()
+            .map_err(|e| format!("Failed to compress batch: {}", e))?;
+        Ok((bytes, json.len()))
+    }
+
+    /// Decodes a batch and returns it with its size before compression.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize), BlinkieError> {
+        let mut json = Vec::new();
+        GzDecoder::new(bytes)
+            .take(MAX_BATCH_BYTES)
+            .read_to_end(&mut json)
+            .map_err(|e| format!("Failed to decompress batch: {}", e))?;
+        let batch = serde_json::from_slice(&json)
+            .map_err(|e| BlinkieError::serialization(format!("Failed to parse batch: {}", e)))?;
+        Ok((batch, json.len()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/**
+ * SyncAck
+ * The hub's answer to a batch. With `resync` the hub couldn't apply it,
+ * e.g. after losing its state, and wants a full snapshot next.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncAck {
+    pub seq: u64,
+    #[serde(default)]
+    pub resync: bool,
+}
+
+/**
+ * SyncMetrics
+ * Traffic of one end of a sync link since it started.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncMetrics {
+    pub since: DateTime<Utc>,
+    pub batches: u64,
+    /// Compressed bytes that went over the wire.
+    pub bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub changed_keys: u64,
+    pub bytes_per_hour: f64,
+    #[serde(default)]
+    pub last_sync: Option<DateTime<Utc>>,
+}
+
+impl Default for SyncMetrics {
+    fn default() -> Self {
+        SyncMetrics {
+            since: Utc::now(),
+            batches: 0,
+            bytes: 0,
+            uncompressed_bytes: 0,
+            changed_keys: 0,
+            bytes_per_hour: 0.0,
+            last_sync: None,
+        }
+    }
+}
+
+impl SyncMetrics {
+    fn record(&mut self, bytes: usize, uncompressed_bytes: usize, changed_keys: usize) {
+        let now = Utc::now();
+        self.batches += 1;
+        self.bytes += bytes as u64;
+        self.uncompressed_bytes += uncompressed_bytes as u64;
+        self.changed_keys += changed_keys as u64;
+        self.last_sync = Some(now);
+        // At least a minute, so the first batch doesn't extrapolate wildly
+        let hours = ((now - self.since).num_seconds().max(60)) as f64 / 3600.0;
+        self.bytes_per_hour = self.bytes as f64 / hours;
+    }
+}
+
+/// Keys whose values differ between `from` and `to`.
+pub fn diff(from: &States, to: &States) -> Changes {
+    let mut changes = Changes::new();
+    let empty = HashMap::new();
+    for (device_id, state) in to {
+        let old = from.get(device_id).unwrap_or(&empty);
+        for (key, value) in state {
+            if old.get(key) != Some(value) {
+                changes
+                    .entry(device_id.clone())
+                    .or_default()
+                    .insert(key.clone(), Some(value.clone()));
+            }
+        }
+        for key in old.keys().filter(|key| !state.contains_key(*key)) {
+            changes
+                .entry(device_id.clone())
+                .or_default()
+                .insert(key.clone(), None);
+        }
+    }
+    for (device_id, old) in from.iter().filter(|(id, _)| !to.contains_key(*id)) {
+        let removed = changes.entry(device_id.clone()).or_default();
+        for key in old.keys() {
+            removed.insert(key.clone(), None);
+        }
+    }
+    changes
+}
+
+/// Applies `changes` to `states`, dropping devices left without keys.
+pub fn apply(states: &mut States, changes: &Changes) {
+    for (device_id, keys) in changes {
+        let state = states.entry(device_id.clone()).or_default();
+        for (key, value) in keys {
+            match value {
+                Some(value) => state.insert(key.clone(), value.clone()),
+                None => state.remove(key),
+            };
+        }
+        if state.is_empty() {
+            states.remove(device_id);
+        }
+    }
+}
+
+fn key_count(changes: &Changes) -> usize {
+    changes.values().map(BTreeMap::len).sum()
+}
+
+/**
+ * Uplink
+ * The satellite end of a sync link. Compares current states with what
+ * the hub last acknowledged and ships only the difference, so an
+ * unacknowledged batch is simply folded into the next one.
+ */
+pub struct Uplink {
+    pub satellite_id: String,
+    pub url: String,
+    token: Option<String>,
+    client: reqwest::Client,
+    /// States the hub acknowledged last and their batch's sequence number.
+    acked: Mutex<(u64, States)>,
+    next_seq: Mutex<u64>,
+    metrics: Mutex<SyncMetrics>,
+}
+
+impl Uplink {
+    /// Links to the hub whose API is at `url`, presenting `token` if the
+    /// hub requires one.
+    pub fn new(satellite_id: &str, url: &str, token: Option<String>) -> Result<Self, BlinkieError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        Ok(Uplink {
+            satellite_id: satellite_id.to_string(),
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            client,
+            acked: Mutex::new((0, States::new())),
+            next_seq: Mutex::new(1),
+            metrics: Mutex::new(SyncMetrics::default()),
+        })
+    }
+
+    pub fn metrics(&self) -> SyncMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Sequence number of the batch the hub acknowledged last.
+    pub fn acked_seq(&self) -> u64 {
+        self.acked.lock().unwrap().0
+    }
+
+    /// The batch bringing the hub from the acknowledged states to `states`.
+    pub fn batch(&self, states: &States) -> SyncBatch {
+        let (base, acked) = &*self.acked.lock().unwrap();
+        SyncBatch {
+            satellite_id: self.satellite_id.clone(),
+            seq: *self.next_seq.lock().unwrap(),
+            base: *base,
+            changes: match base {
+                0 => diff(&States::new(), states),
+                _ => diff(acked, states),
+            },
+            sent: Some(Utc::now()),
+        }
+    }
+
+    /// Ships the changes since the last acknowledged batch, if there are
+    /// any. Returns whether a batch was sent.
+    pub async fn sync(&self, states: States) -> Result<bool, BlinkieError> {
+        let batch = self.batch(&states);
+        if batch.is_empty() && batch.base != 0 {
+            return Ok(false);
+        }
+        *self.next_seq.lock().unwrap() += 1;
+        let (bytes, uncompressed) = batch.encode()?;
+        let size = bytes.len();
+        let mut request = self
+            .client
+            .post(format!("{}{}", self.url, SYNC_PATH))
+            .header("content-type", "application/octet-stream")
+            .body(bytes);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| {
+            BlinkieError::connection(format!("Failed to reach {}: {}", self.url, e))
+        })?;
+        self.metrics
+            .lock()
+            .unwrap()
+            .record(size, uncompressed, key_count(&batch.changes));
+        if !response.status().is_success() {
+            return Err(format!("Hub refused batch {}: {}", batch.seq, response.status()).into());
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("Failed to read acknowledgment: {}", e))?;
+        let ack: SyncAck = serde_json::from_slice(&body).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse acknowledgment: {}", e))
+        })?;
+        let mut acked = self.acked.lock().unwrap();
+        if ack.resync {
+            *acked = (0, States::new());
+        } else if ack.seq == batch.seq {
+            *acked = (batch.seq, states);
+        }
+        Ok(true)
+    }
+
+    /// Syncs the states `read` returns every `interval` until the process
+    /// stops, logging failures and retrying with the next round.
+    pub async fn run<F>(self: Arc<Self>, interval: Duration, read: F)
+    where
+        F: Fn() -> States + Send + Sync + 'static,
+    {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.sync(read()).await {
+                log::warn!("Federation sync failed: {}", e);
+            }
+        }
+    }
+}
+
+/**
+ * SatelliteStatus
+ * What the hub knows about a satellite.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SatelliteStatus {
+    pub id: String,
+    pub seq: u64,
+    pub devices: usize,
+    pub metrics: SyncMetrics,
+    /// How far the satellite's clock runs ahead of the hub's.
+    pub clock: ClockOffset,
+}
+
+#[derive(Default)]
+struct Satellite {
+    /// Acknowledged sequence numbers and the states they left, newest last.
+    snapshots: VecDeque<(u64, States)>,
+    metrics: SyncMetrics,
+    clock: ClockOffset,
+}
+
+impl Satellite {
+    fn current(&self) -> Option<&(u64, States)> {
+        self.snapshots.back()
+    }
+}
+
+/**
+ * FederationHub
+ * The hub end of sync links. Keeps the states of every satellite and
+ * publishes their changes as `state_changed` events of devices named
+ * `<satellite>/<device>`.
+ */
+pub struct FederationHub {
+    satellites: RwLock<HashMap<String, Satellite>>,
+    events: Arc<EventBus>,
+}
+
+impl FederationHub {
+    pub fn new(events: Arc<EventBus>) -> Self {
+        FederationHub {
+            satellites: RwLock::new(HashMap::new()),
+            events,
+        }
+    }
+
+    /// Decodes and applies a batch as received over the wire.
+    pub fn receive(&self, bytes: &[u8]) -> Result<SyncAck, BlinkieError> {
+        let (batch, uncompressed) = SyncBatch::decode(bytes)?;
+        Ok(self.apply(&batch, bytes.len(), uncompressed))
+    }
+
+    /// Applies a batch on top of the snapshot it is relative to. Batches
+    /// relative to a snapshot the hub no longer has are answered with a
+    /// request for a full one.
+    pub fn apply(&self, batch: &SyncBatch, bytes: usize, uncompressed: usize) -> SyncAck {
+        let mut satellites = self.satellites.write().unwrap();
+        let satellite = satellites.entry(batch.satellite_id.clone()).or_default();
+        satellite
+            .metrics
+            .record(bytes, uncompressed, key_count(&batch.changes));
+        if let Some(sent) = batch.sent {
+            let was_skewed = satellite.clock.is_skewed();
+            satellite.clock.record(sent, Utc::now());
+            if satellite.clock.is_skewed() && !was_skewed {
+                log::warn!(
+                    "Clock of satellite '{}' is off by {:.0}s",
+                    batch.satellite_id,
+                    satellite.clock.offset_secs
+                );
+            }
+        }
+        let mut states = match batch.base {
+            0 => States::new(),
+            base => match satellite.snapshots.iter().find(|(seq, _)| *seq == base) {
+                Some((_, states)) => states.clone(),
+                None => {
+                    return SyncAck {
+                        seq: batch.seq,
+                        resync: true,
+                    }
+                }
+            },
+        };
+        apply(&mut states, &batch.changes);
+
+        let previous = satellite
+            .current()
+            .map(|(_, states)| states.clone())
+            .unwrap_or_default();
+        for (device_id, keys) in diff(&previous, &states) {
+            for (key, value) in keys {
+                let _ = self.events.publish(EventKind::StateChanged {
+                    device_id: format!("{}/{}", batch.satellite_id, device_id),
+                    old_value: previous
+                        .get(&device_id)
+                        .and_then(|state| state.get(&key))
+                        .map(StateValue::to_string),
+                    key,
+                    new_value: value.map(|value| value.to_string()).unwrap_or_default(),
+                    reported_at: None,
+                });
+            }
+        }
+
+        if batch.base == 0 {
+            satellite.snapshots.clear();
+        }
+        satellite.snapshots.push_back((batch.seq, states));
+        while satellite.snapshots.len() > KEEP_SNAPSHOTS {
+            satellite.snapshots.pop_front();
+        }
+        SyncAck {
+            seq: batch.seq,
+            resync: false,
+        }
+    }
+
+    /// Current states of a satellite's devices.
+    pub fn states(&self, satellite_id: &str) -> Option<States> {
+        let satellites = self.satellites.read().unwrap();
+        let satellite = satellites.get(satellite_id)?;
+        Some(
+            satellite
+                .current()
+                .map(|(_, states)| states.clone())
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn satellites(&self) -> Vec<SatelliteStatus> {
+        let satellites = self.satellites.read().unwrap();
+        let mut statuses: Vec<SatelliteStatus> = satellites
+            .iter()
+            .map(|(id, satellite)| SatelliteStatus {
+                id: id.clone(),
+                seq: satellite.current().map_or(0, |(seq, _)| *seq),
+                devices: satellite.current().map_or(0, |(_, states)| states.len()),
+                metrics: satellite.metrics.clone(),
+                clock: satellite.clock.clone(),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.id.cmp(&b.id));
+        statuses
+    }
+}
@@ -0,0 +1,214 @@
+use super::event::{Event, EventBus, EventKind};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Clock offsets beyond this many seconds count as skewed.
+pub const MAX_CLOCK_SKEW_SECS: f64 = 30.0;
+/// Samples an offset is estimated from.
+const OFFSET_SAMPLES: usize = 16;
+/// Samples needed before a clock can count as skewed.
+const MIN_SKEW_SAMPLES: u64 = 3;
+
+/**
+ * StateStamp
+ * When a state value was received by the hub and, if the device reports
+ * it, when the device measured it.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateStamp {
+    pub received: DateTime<Utc>,
+    #[serde(default)]
+    pub reported: Option<DateTime<Utc>>,
+}
+
+/**
+ * StateAge
+ * A state value's stamp and how old it is.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateAge {
+    #[serde(flatten)]
+    pub stamp: StateStamp,
+    pub measured: DateTime<Utc>,
+    pub age_secs: i64,
+}
+
+/**
+ * ClockOffset
+ * How far a clock runs ahead of the hub's, estimated from the timestamps
+ * it reports. Delays, e.g. of readings buffered while offline, only make a
+ * clock look behind, so the estimate is the largest recent offset.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClockOffset {
+    pub samples: u64,
+    pub last_secs: f64,
+    pub offset_secs: f64,
+    #[serde(skip)]
+    recent: VecDeque<f64>,
+}
+
+impl ClockOffset {
+    pub fn record(&mut self, reported: DateTime<Utc>, received: DateTime<Utc>) {
+        let secs = (reported - received).num_milliseconds() as f64 / 1000.0;
+        self.recent.push_back(secs);
+        if self.recent.len() > OFFSET_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.last_secs = secs;
+        self.offset_secs = self.recent.iter().copied().fold(f64::MIN, f64::max);
+        self.samples += 1;
+    }
+
+    pub fn is_skewed(&self) -> bool {
+        self.samples >= MIN_SKEW_SAMPLES && self.offset_secs.abs() > MAX_CLOCK_SKEW_SECS
+    }
+}
+
+/**
+ * StateClock
+ * Follows state changes to know how old every state value is, and how far
+ * off the clocks of devices reporting their own timestamps are. Values of
+ * devices with skewed clocks are dated by when the hub received them.
+ */
+#[derive(Default)]
+pub struct StateClock {
+    /// Stamps of the latest values, by device id and key.
+    stamps: RwLock<HashMap<String, HashMap<String, StateStamp>>>,
+    /// Clock offsets, by device id.
+    offsets: RwLock<HashMap<String, ClockOffset>>,
+}
+
+impl StateClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, event: &Event) {
+        let EventKind::StateChanged {
+            device_id,
+            key,
+            reported_at,
+            ..
+        } = &event.kind
+        else {
+            return;
+        };
+        if let Some(reported) = reported_at {
+            let mut offsets = self.offsets.write().unwrap();
+            let offset = offsets.entry(device_id.clone()).or_default();
+            let was_skewed = offset.is_skewed();
+            offset.record(*reported, event.timestamp);
+            if offset.is_skewed() && !was_skewed {
+                log::warn!(
+                    "Clock of device '{}' is off by {:.0}s, dating its states by receive time",
+                    device_id,
+                    offset.offset_secs
+                );
+            }
+        }
+        self.stamps
+            .write()
+            .unwrap()
+            .entry(device_id.clone())
+            .or_default()
+            .insert(
+                key.clone(),
+                StateStamp {
+                    received: event.timestamp,
+                    reported: *reported_at,
+                },
+            );
+    }
+
+    pub fn stamp(&self, device_id: &str, key: &str) -> Option<StateStamp> {
+        self.stamps
+            .read()
+            .unwrap()
+            .get(device_id)?
+            .get(key)
+            .cloned()
+    }
+
+    /// Ages of the values of a device at `now`, by key.
+    pub fn ages(&self, device_id: &str, now: DateTime<Utc>) -> HashMap<String, StateAge> {
+        let stamps = self
+            .stamps
+            .read()
+            .unwrap()
+            .get(device_id)
+            .cloned()
+            .unwrap_or_default();
+        stamps
+            .into_iter()
+            .filter_map(|(key, stamp)| {
+                let measured = self.measured_at(device_id, &key)?;
+                let age = StateAge {
+                    stamp,
+                    measured,
+                    age_secs: (now - measured).num_seconds().max(0),
+                };
+                Some((key, age))
+            })
+            .collect()
+    }
+
+    /// When the value was measured: the device's timestamp unless its
+    /// clock is skewed, else when the hub received it.
+    pub fn measured_at(&self, device_id: &str, key: &str) -> Option<DateTime<Utc>> {
+        let stamp = self.stamp(device_id, key)?;
+        let skewed = self
+            .offsets
+            .read()
+            .unwrap()
+            .get(device_id)
+            .is_some_and(ClockOffset::is_skewed);
+        Some(match stamp.reported {
+            Some(reported) if !skewed => reported,
+            _ => stamp.received,
+        })
+    }
+
+    /// Age of the value at `now`, or `None` if no change of it was seen.
+    /// Values dated in the future count as brand new.
+    pub fn age(&self, device_id: &str, key: &str, now: DateTime<Utc>) -> Option<Duration> {
+        let measured = self.measured_at(device_id, key)?;
+        Some((now - measured).max(Duration::zero()))
+    }
+
+    /// Clock offsets of devices that reported timestamps, by device id.
+    pub fn offsets(&self) -> HashMap<String, ClockOffset> {
+        self.offsets.read().unwrap().clone()
+    }
+
+    /// Follows the bus until it closes.
+    pub async fn run(self: Arc<Self>, events: Arc<EventBus>) {
+        let mut receiver = events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// Parses a timestamp as devices report it: RFC 3339, or seconds or
+/// milliseconds since the Unix epoch.
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let number: f64 = value.parse().ok()?;
+    // Seconds since the epoch stay below 1e11 until the year 5138
+    let millis = match number {
+        n if n.abs() < 1e11 => n * 1000.0,
+        n => n,
+    };
+    DateTime::from_timestamp_millis(millis as i64)
+}
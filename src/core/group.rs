@@ -0,0 +1,134 @@
+use super::device::CommandResponse;
+use super::error::BlinkieError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/**
+ * DeviceGroup
+ * Devices commanded together, e.g. all lights of the living room. Commands
+ * go to the members one after another in the listed order, or all at once
+ * if `parallel` is set.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceGroup {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<String>,
+    /// Send to all members at once. Synchronous devices still take turns
+    /// on the device list, so this mostly helps async ones.
+    #[serde(default)]
+    pub parallel: bool,
+}
+
+impl DeviceGroup {
+    /// Checks that the group has members, all of them known and listed once.
+    pub fn validate(&self, device_ids: &[String]) -> Result<(), BlinkieError> {
+        if self.members.is_empty() {
+            return Err(format!("Group '{}' has no members", self.id).into());
+        }
+        let mut seen = HashSet::new();
+        for member in &self.members {
+            if !device_ids.contains(member) {
+                return Err(format!("Unknown device '{}'", member).into());
+            }
+            if !seen.insert(member) {
+                return Err(format!("Device '{}' is listed twice", member).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * MemberResult
+ * How a command or scene went on one device of a group or scene.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberResult {
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "CommandResponse::is_empty")]
+    pub response: CommandResponse,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl MemberResult {
+    pub fn new(device_id: &str, result: Result<CommandResponse, BlinkieError>) -> Self {
+        let (response, error) = match result {
+            Ok(response) => (response, None),
+            Err(e) => (CommandResponse::default(), Some(e.to_string())),
+        };
+        MemberResult {
+            device_id: device_id.to_string(),
+            response,
+            error,
+        }
+    }
+}
+
+/**
+ * GroupStore
+ * Holds device groups, written back to `groups.json` on every change.
+ */
+pub struct GroupStore {
+    path: PathBuf,
+    groups: RwLock<BTreeMap<String, DeviceGroup>>,
+}
+
+impl GroupStore {
+    /// Opens the store, loading previously saved groups if the file exists.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let groups = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(GroupStore {
+            path,
+            groups: RwLock::new(groups),
+        })
+    }
+
+    pub fn list(&self) -> Vec<DeviceGroup> {
+        self.groups.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<DeviceGroup> {
+        self.groups.read().unwrap().get(id).cloned()
+    }
+
+    /// Validates a group and adds or replaces it.
+    pub fn save(&self, group: DeviceGroup, device_ids: &[String]) -> Result<(), BlinkieError> {
+        group.validate(device_ids)?;
+        let mut groups = self.groups.write().unwrap();
+        groups.insert(group.id.clone(), group);
+        self.persist(&groups)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<DeviceGroup>, BlinkieError> {
+        let mut groups = self.groups.write().unwrap();
+        let removed = groups.remove(id);
+        if removed.is_some() {
+            self.persist(&groups)?;
+        }
+        Ok(removed)
+    }
+
+    fn persist(&self, groups: &BTreeMap<String, DeviceGroup>) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(groups).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize groups: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+}
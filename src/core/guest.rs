@@ -0,0 +1,668 @@
+use super::device::CommandSender;
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+/// Event published when a guest code is entered on a device.
+pub const CODE_USED_EVENT: &str = "guest_code_used";
+/// Event published when a guest code was set on its devices.
+pub const CODE_ACTIVATED_EVENT: &str = "guest_code_activated";
+/// Event published when an expired or revoked code was cleared from its
+/// devices.
+pub const CODE_EXPIRED_EVENT: &str = "guest_code_expired";
+
+/// Seconds between checks for codes to set or clear.
+const TICK_SECS: u64 = 30;
+/// Shortest code locks and alarm panels reliably accept.
+const MIN_CODE_LENGTH: usize = 4;
+
+fn default_code_length() -> usize {
+    6
+}
+
+fn default_keep_days() -> i64 {
+    30
+}
+
+fn default_set_command() -> String {
+    "set_code".to_string()
+}
+
+fn default_clear_command() -> String {
+    "clear_code".to_string()
+}
+
+fn default_first_slot() -> u32 {
+    10
+}
+
+fn default_slots() -> u32 {
+    20
+}
+
+fn default_usage_key() -> String {
+    "last_code_slot".to_string()
+}
+
+/**
+ * CodeTarget
+ * A lock or alarm panel guest codes can be set on. Codes go into user
+ * slots of the device with `set_command`, getting the parameters `slot`,
+ * `code`, `name`, `valid_from` and `valid_until`, and are taken out with
+ * `clear_command`, getting `slot`. The device reports the slot of the code
+ * last entered as `usage_key`.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CodeTarget {
+    #[serde(default = "default_set_command")]
+    pub set_command: String,
+    #[serde(default = "default_clear_command")]
+    pub clear_command: String,
+    /// First user slot for guests, leaving those before it to residents.
+    #[serde(default = "default_first_slot")]
+    pub first_slot: u32,
+    /// Number of slots for guests, from `first_slot` on.
+    #[serde(default = "default_slots")]
+    pub slots: u32,
+    #[serde(default = "default_usage_key")]
+    pub usage_key: String,
+}
+
+impl Default for CodeTarget {
+    fn default() -> Self {
+        CodeTarget {
+            set_command: default_set_command(),
+            clear_command: default_clear_command(),
+            first_slot: default_first_slot(),
+            slots: default_slots(),
+            usage_key: default_usage_key(),
+        }
+    }
+}
+
+/**
+ * GuestConfig
+ * Devices guest codes can be set on and how codes are made.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GuestConfig {
+    /// Digits of generated codes.
+    #[serde(default = "default_code_length")]
+    pub code_length: usize,
+    /// Days codes are listed after they expired, with their use.
+    #[serde(default = "default_keep_days")]
+    pub keep_days: i64,
+    /// Locks and alarm panels, by device id.
+    #[serde(default)]
+    pub devices: BTreeMap<String, CodeTarget>,
+}
+
+impl Default for GuestConfig {
+    fn default() -> Self {
+        GuestConfig {
+            code_length: default_code_length(),
+            keep_days: default_keep_days(),
+            devices: BTreeMap::new(),
+        }
+    }
+}
+
+impl GuestConfig {
+    pub fn validate(&self, device_ids: &[String]) -> Result<(), BlinkieError> {
+        if !(MIN_CODE_LENGTH..=12).contains(&self.code_length) {
+            return Err(format!(
+                "Codes need {} to 12 digits, not {}",
+                MIN_CODE_LENGTH, self.code_length
+            )
+            .into());
+        }
+        if self.keep_days < 0 {
+            return Err("keep_days can't be negative".into());
+        }
+        for (id, target) in &self.devices {
+            if !device_ids.contains(id) {
+                return Err(format!("Unknown device '{}'", id).into());
+            }
+            if target.slots == 0 {
+                return Err(format!("Device '{}' has no slots for guests", id).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * CodeRequest
+ * A guest code asked for, e.g. by a booking system for a stay. Without a
+ * code, one is generated.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CodeRequest {
+    /// Name of the guest, shown on devices that keep names.
+    pub name: String,
+    /// Locks and alarm panels the code opens, all configured ones if empty.
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub valid_from: DateTime<Utc>,
+    pub valid_until: DateTime<Utc>,
+    /// Id of the booking in the system asking for the code.
+    #[serde(default)]
+    pub reference: Option<String>,
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+/**
+ * CodeState
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeState {
+    /// Not valid yet.
+    Scheduled,
+    Active,
+    /// Past its end or revoked; cleared from devices once they took it.
+    Expired,
+}
+
+/**
+ * GuestCode
+ * A time-limited code for a guest, set on its devices while it is valid
+ * and cleared from them when it expires or is revoked.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GuestCode {
+    pub id: String,
+    pub name: String,
+    pub code: String,
+    pub devices: Vec<String>,
+    pub valid_from: DateTime<Utc>,
+    pub valid_until: DateTime<Utc>,
+    #[serde(default)]
+    pub reference: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// User slot the code has on each device.
+    #[serde(default)]
+    pub slots: BTreeMap<String, u32>,
+    /// Devices the code is set on right now.
+    #[serde(default)]
+    pub set_on: Vec<String>,
+    #[serde(default)]
+    pub uses: u32,
+    #[serde(default)]
+    pub last_used: Option<DateTime<Utc>>,
+    /// Why setting or clearing the code last failed, by device id.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, String>,
+}
+
+impl GuestCode {
+    pub fn state(&self, now: DateTime<Utc>) -> CodeState {
+        if self.revoked_at.is_some() || now >= self.valid_until {
+            CodeState::Expired
+        } else if now < self.valid_from {
+            CodeState::Scheduled
+        } else {
+            CodeState::Active
+        }
+    }
+}
+
+/**
+ * GuestCodeStatus
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GuestCodeStatus {
+    #[serde(flatten)]
+    pub code: GuestCode,
+    pub state: CodeState,
+}
+
+/**
+ * GuestCodes
+ * Time-limited codes for guests, set on locks and alarm panels while they
+ * are valid and cleared when they expire, with every use published as a
+ * `guest_code_used` event. The setup is kept at `guest-access.json`, the
+ * codes at `guest-codes.json`.
+ */
+pub struct GuestCodes {
+    config_path: PathBuf,
+    codes_path: PathBuf,
+    config: RwLock<GuestConfig>,
+    codes: RwLock<BTreeMap<String, GuestCode>>,
+    events: Arc<EventBus>,
+    created: AtomicU64,
+    wake: Notify,
+}
+
+impl GuestCodes {
+    pub fn open<P: Into<PathBuf>>(
+        config_path: P,
+        codes_path: P,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let config_path = config_path.into();
+        let codes_path = codes_path.into();
+        Ok(GuestCodes {
+            config: RwLock::new(read_json(&config_path)?.unwrap_or_default()),
+            codes: RwLock::new(read_json(&codes_path)?.unwrap_or_default()),
+            config_path,
+            codes_path,
+            events,
+            created: AtomicU64::new(0),
+            wake: Notify::new(),
+        })
+    }
+
+    pub fn config(&self) -> GuestConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Stores the setup. Codes on devices that were dropped stay listed,
+    /// but are no longer set or cleared there.
+    pub fn set_config(&self, config: GuestConfig) -> Result<(), BlinkieError> {
+        write_json(&self.config_path, &config, "guest access setup")?;
+        *self.config.write().unwrap() = config;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub fn list(&self, now: DateTime<Utc>) -> Vec<GuestCodeStatus> {
+        let codes = self.codes.read().unwrap();
+        codes.values().map(|code| status(code, now)).collect()
+    }
+
+    pub fn get(&self, id: &str, now: DateTime<Utc>) -> Option<GuestCodeStatus> {
+        let codes = self.codes.read().unwrap();
+        codes.get(id).map(|code| status(code, now))
+    }
+
+    /// Adds a code for a guest and has it set on its devices once it is
+    /// valid. Generated codes differ from all codes not expired yet; given
+    /// ones have to.
+    pub fn create(
+        &self,
+        request: CodeRequest,
+        now: DateTime<Utc>,
+    ) -> Result<GuestCodeStatus, BlinkieError> {
+        let config = self.config();
+        if request.name.trim().is_empty() {
+            return Err("A guest code needs the guest's name".into());
+        }
+        if request.valid_until <= request.valid_from {
+            return Err("A guest code has to end after it starts".into());
+        }
+        if request.valid_until <= now {
+            return Err("The guest code would already have expired".into());
+        }
+        let devices = if request.devices.is_empty() {
+            config.devices.keys().cloned().collect()
+        } else {
+            request.devices.clone()
+        };
+        if devices.is_empty() {
+            return Err("No lock or alarm panel is set up for guest codes".into());
+        }
+        if let Some(device) = devices.iter().find(|d| !config.devices.contains_key(*d)) {
+            return Err(format!("Device '{}' is not set up for guest codes", device).into());
+        }
+
+        let mut codes = self.codes.write().unwrap();
+        let live: Vec<&GuestCode> = codes
+            .values()
+            .filter(|code| code.state(now) != CodeState::Expired || !code.set_on.is_empty())
+            .collect();
+        let code = match request.code {
+            Some(code) => {
+                if code.len() < MIN_CODE_LENGTH || !code.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(format!(
+                        "A guest code has to be at least {} digits",
+                        MIN_CODE_LENGTH
+                    )
+                    .into());
+                }
+                if live.iter().any(|other| other.code == code) {
+                    return Err("The code is taken by another guest".into());
+                }
+                code
+            }
+            None => loop {
+                let code = self.generate(config.code_length);
+                if !live.iter().any(|other| other.code == code) {
+                    break code;
+                }
+            },
+        };
+        let mut slots = BTreeMap::new();
+        for device in &devices {
+            let target = &config.devices[device];
+            let taken: Vec<u32> = live
+                .iter()
+                .filter_map(|other| other.slots.get(device).copied())
+                .collect();
+            let slot = (target.first_slot..target.first_slot + target.slots)
+                .find(|slot| !taken.contains(slot))
+                .ok_or_else(|| format!("All guest slots of '{}' are taken", device))?;
+            slots.insert(device.clone(), slot);
+        }
+
+        let id = format!(
+            "{}-{}",
+            now.timestamp(),
+            self.created.fetch_add(1, Ordering::Relaxed)
+        );
+        let guest_code = GuestCode {
+            id: id.clone(),
+            name: request.name,
+            code,
+            devices,
+            valid_from: request.valid_from,
+            valid_until: request.valid_until,
+            reference: request.reference,
+            created_at: now,
+            revoked_at: None,
+            slots,
+            set_on: Vec::new(),
+            uses: 0,
+            last_used: None,
+            errors: BTreeMap::new(),
+        };
+        codes.insert(id, guest_code.clone());
+        self.persist(&codes)?;
+        self.wake.notify_one();
+        Ok(status(&guest_code, now))
+    }
+
+    /// Revokes a code; it is cleared from its devices right away.
+    pub fn revoke(
+        &self,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<GuestCodeStatus>, BlinkieError> {
+        let mut codes = self.codes.write().unwrap();
+        let Some(code) = codes.get_mut(id) else {
+            return Ok(None);
+        };
+        code.revoked_at.get_or_insert(now);
+        let revoked = status(code, now);
+        self.persist(&codes)?;
+        self.wake.notify_one();
+        Ok(Some(revoked))
+    }
+
+    /// Revokes the codes of the guest named or referenced as `person` and
+    /// drops their name, reference and uses; the codes themselves go once
+    /// cleared from their devices and past `keep_days`.
+    pub fn forget(&self, person: &str, now: DateTime<Utc>) -> Result<(), BlinkieError> {
+        let mut codes = self.codes.write().unwrap();
+        let mut forgotten = false;
+        for code in codes
+            .values_mut()
+            .filter(|code| code.name == person || code.reference.as_deref() == Some(person))
+        {
+            code.revoked_at.get_or_insert(now);
+            code.name = code.id.clone();
+            code.reference = None;
+            code.uses = 0;
+            code.last_used = None;
+            forgotten = true;
+        }
+        if forgotten {
+            self.persist(&codes)?;
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    /// A random code of `length` digits.
+    fn generate(&self, length: usize) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(RandomState::new().hash_one(length).to_le_bytes());
+        hasher.update(self.created.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(
+            Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+                .to_le_bytes(),
+        );
+        hasher
+            .finalize()
+            .iter()
+            .take(length)
+            .map(|byte| char::from(b'0' + byte % 10))
+            .collect()
+    }
+
+    /// Sets codes that became valid on their devices and clears those that
+    /// expired, dropping expired codes after `keep_days`. Devices that
+    /// failed are tried again on the next round. Blocks while sending
+    /// commands, so call it from blocking threads only.
+    pub fn sync(&self, now: DateTime<Utc>, sender: &CommandSender) {
+        let config = self.config();
+        let mut work = Vec::new();
+        for code in self.codes.read().unwrap().values() {
+            let wanted = code.state(now) == CodeState::Active;
+            for device in &code.devices {
+                let (Some(target), Some(slot)) =
+                    (config.devices.get(device), code.slots.get(device))
+                else {
+                    continue;
+                };
+                let set = code.set_on.contains(device);
+                if wanted != set {
+                    work.push((code.clone(), device.clone(), target.clone(), *slot, wanted));
+                }
+            }
+        }
+
+        let mut outcomes = Vec::new();
+        for (code, device, target, slot, set) in work {
+            let result = if set {
+                let parameters = HashMap::from([
+                    ("slot".to_string(), slot.to_string()),
+                    ("code".to_string(), code.code.clone()),
+                    ("name".to_string(), code.name.clone()),
+                    ("valid_from".to_string(), code.valid_from.to_rfc3339()),
+                    ("valid_until".to_string(), code.valid_until.to_rfc3339()),
+                ]);
+                sender(&device, &target.set_command, parameters)
+            } else {
+                let parameters = HashMap::from([("slot".to_string(), slot.to_string())]);
+                sender(&device, &target.clear_command, parameters)
+            };
+            if let Err(e) = &result {
+                log::warn!(
+                    "Failed to {} guest code '{}' on '{}': {}",
+                    if set { "set" } else { "clear" },
+                    code.id,
+                    device,
+                    e
+                );
+            }
+            outcomes.push((code.id, device, set, result));
+        }
+
+        let mut codes = self.codes.write().unwrap();
+        let mut changed = !outcomes.is_empty();
+        for (id, device, set, result) in outcomes {
+            let Some(code) = codes.get_mut(&id) else {
+                continue;
+            };
+            match result {
+                Ok(()) => {
+                    code.errors.remove(&device);
+                    if set {
+                        code.set_on.push(device);
+                        if code.set_on.len() == 1 {
+                            self.publish(CODE_ACTIVATED_EVENT, code, None);
+                        }
+                    } else {
+                        code.set_on.retain(|on| *on != device);
+                        if code.set_on.is_empty() {
+                            self.publish(CODE_EXPIRED_EVENT, code, None);
+                        }
+                    }
+                }
+                Err(e) => {
+                    code.errors.insert(device, e.to_string());
+                }
+            }
+        }
+        // Errors of devices that are in line with their code by now are old
+        for code in codes.values_mut() {
+            let wanted = code.state(now) == CodeState::Active;
+            let errors = code.errors.len();
+            let set_on = &code.set_on;
+            code.errors
+                .retain(|device, _| set_on.contains(device) != wanted);
+            changed |= code.errors.len() != errors;
+        }
+        let keep = Duration::days(config.keep_days);
+        let before = codes.len();
+        codes.retain(|_, code| {
+            let ended = code.revoked_at.map_or(code.valid_until, |revoked_at| {
+                revoked_at.min(code.valid_until)
+            });
+            !code.set_on.is_empty() || ended + keep > now
+        });
+        changed |= codes.len() != before;
+        if changed {
+            if let Err(e) = self.persist(&codes) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    /// Counts a use of the guest code whose slot a configured device
+    /// reports in `event`.
+    pub fn record(&self, event: &Event, now: DateTime<Utc>) {
+        let EventKind::StateChanged {
+            device_id,
+            key,
+            new_value,
+            ..
+        } = &event.kind
+        else {
+            return;
+        };
+        let is_usage = self
+            .config
+            .read()
+            .unwrap()
+            .devices
+            .get(device_id)
+            .is_some_and(|target| target.usage_key == *key);
+        if !is_usage {
+            return;
+        }
+        let Ok(slot) = new_value.trim().parse::<u32>() else {
+            return;
+        };
+        let mut codes = self.codes.write().unwrap();
+        let Some(code) = codes.values_mut().find(|code| {
+            code.set_on.contains(device_id) && code.slots.get(device_id) == Some(&slot)
+        }) else {
+            return;
+        };
+        code.uses += 1;
+        code.last_used = Some(event.timestamp);
+        let outside = code.state(now) != CodeState::Active;
+        self.publish(CODE_USED_EVENT, code, Some((device_id, outside)));
+        if let Err(e) = self.persist(&codes) {
+            log::warn!("{}", e);
+        }
+    }
+
+    /// Publishes an event about `code`; for uses with the device and
+    /// whether the code was entered outside its validity, e.g. while the
+    /// device still had it after a failed clear.
+    fn publish(&self, name: &str, code: &GuestCode, used: Option<(&String, bool)>) {
+        let mut data = HashMap::from([
+            ("code_id".to_string(), code.id.clone()),
+            ("name".to_string(), code.name.clone()),
+        ]);
+        if let Some(reference) = &code.reference {
+            data.insert("reference".to_string(), reference.clone());
+        }
+        if let Some((device_id, outside)) = used {
+            data.insert("device_id".to_string(), device_id.clone());
+            data.insert("uses".to_string(), code.uses.to_string());
+            data.insert("outside_validity".to_string(), outside.to_string());
+        }
+        let published = self.events.publish(EventKind::Custom {
+            name: name.to_string(),
+            data,
+        });
+        if let Err(e) = published {
+            log::warn!("Failed to publish {}: {}", name, e);
+        }
+    }
+
+    /// Keeps devices in line with the codes every half minute, right away
+    /// when codes change, and counts uses as devices report them.
+    pub async fn run(self: Arc<Self>, sender: CommandSender) {
+        let mut receiver = self.events.subscribe();
+        loop {
+            let codes = self.clone();
+            let sender = sender.clone();
+            let _ = tokio::task::spawn_blocking(move || codes.sync(Utc::now(), &sender)).await;
+            let sleep = tokio::time::sleep(std::time::Duration::from_secs(TICK_SECS));
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    received = receiver.recv() => match received {
+                        Ok(event) => self.record(&event, Utc::now()),
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return,
+                    },
+                    _ = &mut sleep => break,
+                    _ = self.wake.notified() => break,
+                }
+            }
+        }
+    }
+
+    fn persist(&self, codes: &BTreeMap<String, GuestCode>) -> Result<(), BlinkieError> {
+        write_json(&self.codes_path, codes, "guest codes")
+    }
+}
+
+fn status(code: &GuestCode, now: DateTime<Utc>) -> GuestCodeStatus {
+    GuestCodeStatus {
+        code: code.clone(),
+        state: code.state(now),
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &PathBuf) -> Result<Option<T>, BlinkieError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map(Some).map_err(|e| {
+        BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+    })
+}
+
+fn write_json<T: Serialize>(path: &PathBuf, value: &T, what: &str) -> Result<(), BlinkieError> {
+    let contents = serde_json::to_string_pretty(value)
+        .map_err(|e| BlinkieError::serialization(format!("Failed to serialize {}: {}", what, e)))?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+}
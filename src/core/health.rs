@@ -0,0 +1,232 @@
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::value::StateMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Name of the custom event published when a device answers again after
+/// being offline, carrying `device_id`.
+pub const ONLINE_EVENT: &str = "device_online";
+/// Name of the custom event published when a device stops answering,
+/// carrying `device_id` and `error`.
+pub const OFFLINE_EVENT: &str = "device_offline";
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_failures() -> u32 {
+    2
+}
+
+/**
+ * HealthConfig
+ * How often devices are checked, and how many checks or commands in a row
+ * must fail before a device counts as offline.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+}
+
+impl HealthConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 {
+            return Err("interval_secs must be at least 1".to_string());
+        }
+        if self.failures == 0 {
+            return Err("failures must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            interval_secs: default_interval_secs(),
+            failures: default_failures(),
+        }
+    }
+}
+
+/**
+ * Availability
+ * Whether a device answers: `unknown` until it was first checked.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Availability {
+    #[default]
+    Unknown,
+    Online,
+    Offline,
+}
+
+/**
+ * DeviceHealth
+ * Availability of a device, since when it holds, when the device was last
+ * checked, and the failures in a row with the last error.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceHealth {
+    pub availability: Availability,
+    pub since: Option<DateTime<Utc>>,
+    pub checked_at: Option<DateTime<Utc>>,
+    pub failures: u32,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Whether a device reports being unavailable in its `available` state key,
+/// as bridges tracking availability do. The default device health check.
+pub fn reported(device_id: &str, state: &StateMap) -> Result<(), BlinkieError> {
+    match state.get("available").map(|value| value.to_string()) {
+        Some(available) if available == "false" => Err(BlinkieError::connection(format!(
+            "Device '{}' reports being unavailable",
+            device_id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * HealthMonitor
+ * Tracks the availability of every device from periodic health checks and
+ * the commands sent to it, and reports devices going offline and coming
+ * back on the bus. Only connection failures and timeouts count, so a
+ * device refusing a command it doesn't know stays online.
+ */
+pub struct HealthMonitor {
+    config: Mutex<HealthConfig>,
+    devices: Mutex<BTreeMap<String, DeviceHealth>>,
+    events: Arc<EventBus>,
+}
+
+impl HealthMonitor {
+    pub fn new(events: Arc<EventBus>) -> Self {
+        HealthMonitor {
+            config: Mutex::new(HealthConfig::default()),
+            devices: Mutex::new(BTreeMap::new()),
+            events,
+        }
+    }
+
+    pub fn config(&self) -> HealthConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: HealthConfig) -> Result<(), BlinkieError> {
+        config.validate()?;
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn status(&self) -> BTreeMap<String, DeviceHealth> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    pub fn device(&self, device_id: &str) -> Option<DeviceHealth> {
+        self.devices.lock().unwrap().get(device_id).cloned()
+    }
+
+    pub fn forget(&self, device_id: &str) {
+        self.devices.lock().unwrap().remove(device_id);
+    }
+
+    /// Records the result of a health check of `device_id`.
+    pub fn record(&self, device_id: &str, result: &Result<(), BlinkieError>) {
+        let failures = self.config.lock().unwrap().failures;
+        let mut devices = self.devices.lock().unwrap();
+        let health = devices.entry(device_id.to_string()).or_default();
+        let now = Utc::now();
+        health.checked_at = Some(now);
+        let availability = match result {
+            Ok(()) => {
+                health.failures = 0;
+                health.error = None;
+                Availability::Online
+            }
+            Err(e) => {
+                health.failures += 1;
+                health.error = Some(e.to_string());
+                if health.failures < failures && health.availability != Availability::Unknown {
+                    return;
+                }
+                Availability::Offline
+            }
+        };
+        if health.availability == availability {
+            return;
+        }
+        let was = health.availability;
+        health.availability = availability;
+        health.since = Some(now);
+        let mut data = HashMap::from([("device_id".to_string(), device_id.to_string())]);
+        let name = match (&health.error, availability) {
+            (Some(error), Availability::Offline) => {
+                log::warn!("Device '{}' is offline: {}", device_id, error);
+                data.insert("error".to_string(), error.clone());
+                OFFLINE_EVENT
+            }
+            // A device answering its first check didn't come back
+            _ if was == Availability::Unknown => return,
+            _ => {
+                log::info!("Device '{}' is online again", device_id);
+                ONLINE_EVENT
+            }
+        };
+        drop(devices);
+        let _ = self.events.publish(EventKind::Custom {
+            name: name.to_string(),
+            data,
+        });
+    }
+
+    /// Records how a command sent to `device_id` went: answering proves the
+    /// device online, failing to reach it counts like a failed check.
+    pub fn observe<T>(&self, device_id: &str, result: &Result<T, BlinkieError>) {
+        match result {
+            Ok(_) => self.record(device_id, &Ok(())),
+            Err(e @ (BlinkieError::Connection(_) | BlinkieError::Timeout(_))) => {
+                self.record(device_id, &Err(e.clone()))
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Checks every device with `check`, which returns the result for each
+    /// device id, and records the results.
+    pub fn check<F>(&self, check: F)
+    where
+        F: Fn() -> Vec<(String, Result<(), BlinkieError>)>,
+    {
+        for (device_id, result) in check() {
+            self.record(&device_id, &result);
+        }
+    }
+
+    /// Checks devices with `check` on the blocking pool every interval,
+    /// since checks may talk to slow devices.
+    pub async fn run<F>(self: Arc<Self>, check: F)
+    where
+        F: Fn() -> Vec<(String, Result<(), BlinkieError>)> + Send + Sync + 'static,
+    {
+        let check = Arc::new(check);
+        loop {
+            let interval = Duration::from_secs(self.config().interval_secs);
+            let (monitor, check) = (self.clone(), check.clone());
+            if let Err(e) = tokio::task::spawn_blocking(move || monitor.check(&*check)).await {
+                log::error!("Device health check failed: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
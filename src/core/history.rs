@@ -0,0 +1,312 @@
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::value::StateValue;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Samples the in-memory store keeps per device state.
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+/**
+ * Sample
+ * A state value and when the hub received it.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub value: String,
+}
+
+/**
+ * HistoryBucket
+ * The samples of one step of a downsampled range. Numeric values are
+ * summed up as min, max and mean; other values only count.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryBucket {
+    pub start: DateTime<Utc>,
+    pub count: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    /// Value of the bucket's last sample.
+    pub last: String,
+    #[serde(skip)]
+    numbers: usize,
+    #[serde(skip)]
+    sum: f64,
+}
+
+impl HistoryBucket {
+    fn new(start: DateTime<Utc>) -> Self {
+        HistoryBucket {
+            start,
+            count: 0,
+            min: None,
+            max: None,
+            mean: None,
+            last: String::new(),
+            numbers: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn add(&mut self, sample: &Sample) {
+        self.count += 1;
+        self.last = sample.value.clone();
+        let Some(value) = StateValue::parse(&sample.value).as_f64() else {
+            return;
+        };
+        self.numbers += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        self.mean = Some(self.sum / self.numbers as f64);
+    }
+}
+
+/**
+ * HistoryStore
+ * Where recorded samples are kept, by device and state key. Samples come
+ * in oldest first and are returned that way.
+ */
+pub trait HistoryStore: Send + Sync {
+    fn append(&self, device_id: &str, key: &str, sample: Sample);
+    /// The latest `n` samples.
+    fn last_n(&self, device_id: &str, key: &str, n: usize) -> Vec<Sample>;
+    /// Samples received within `from..to`.
+    fn range(
+        &self,
+        device_id: &str,
+        key: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<Sample>;
+    /// State keys of a device that have samples, sorted.
+    fn keys(&self, device_id: &str) -> Vec<String>;
+    /// Drops every sample of a device.
+    fn remove(&self, device_id: &str);
+}
+
+/**
+ * RingBufferStore
+ * Keeps the latest `capacity` samples of every device state in memory,
+ * dropping the oldest ones.
+ */
+pub struct RingBufferStore {
+    capacity: usize,
+    series: RwLock<HashMap<String, HashMap<String, VecDeque<Sample>>>>,
+}
+
+impl RingBufferStore {
+    pub fn new(capacity: usize) -> Self {
+        RingBufferStore {
+            capacity: capacity.max(1),
+            series: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl HistoryStore for RingBufferStore {
+    fn append(&self, device_id: &str, key: &str, sample: Sample) {
+        let mut series = self.series.write().unwrap();
+        let samples = series
+            .entry(device_id.to_string())
+            .or_default()
+            .entry(key.to_string())
+            .or_default();
+        if samples.len() >= self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    fn last_n(&self, device_id: &str, key: &str, n: usize) -> Vec<Sample> {
+        let series = self.series.read().unwrap();
+        let Some(samples) = series.get(device_id).and_then(|keys| keys.get(key)) else {
+            return Vec::new();
+        };
+        samples
+            .iter()
+            .skip(samples.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    fn range(
+        &self,
+        device_id: &str,
+        key: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<Sample> {
+        let series = self.series.read().unwrap();
+        let Some(samples) = series.get(device_id).and_then(|keys| keys.get(key)) else {
+            return Vec::new();
+        };
+        let start = samples.partition_point(|sample| sample.timestamp < from);
+        samples
+            .range(start..)
+            .take_while(|sample| sample.timestamp < to)
+            .cloned()
+            .collect()
+    }
+
+    fn keys(&self, device_id: &str) -> Vec<String> {
+        let series = self.series.read().unwrap();
+        let mut keys: Vec<String> = series
+            .get(device_id)
+            .map(|keys| keys.keys().cloned().collect())
+            .unwrap_or_default();
+        keys.sort();
+        keys
+    }
+
+    fn remove(&self, device_id: &str) {
+        self.series.write().unwrap().remove(device_id);
+    }
+}
+
+/**
+ * StateHistory
+ * Records every state change from the event bus into a history store, to
+ * graph sensor values and to ask what a device reported lately.
+ */
+pub struct StateHistory {
+    store: Arc<dyn HistoryStore>,
+    /// Sequence number of the last event recorded.
+    last_seq: Mutex<u64>,
+}
+
+impl Default for StateHistory {
+    fn default() -> Self {
+        Self::new(Arc::new(RingBufferStore::new(DEFAULT_CAPACITY)))
+    }
+}
+
+impl StateHistory {
+    pub fn new(store: Arc<dyn HistoryStore>) -> Self {
+        StateHistory {
+            store,
+            last_seq: Mutex::new(0),
+        }
+    }
+
+    /// Appends a state change to its series. Other events and events
+    /// already recorded are ignored.
+    pub fn record(&self, event: &Event) {
+        let mut last_seq = self.last_seq.lock().unwrap();
+        if event.seq <= *last_seq {
+            return;
+        }
+        *last_seq = event.seq;
+        if let EventKind::StateChanged {
+            device_id,
+            key,
+            new_value,
+            ..
+        } = &event.kind
+        {
+            self.store.append(
+                device_id,
+                key,
+                Sample {
+                    timestamp: event.timestamp,
+                    value: new_value.clone(),
+                },
+            );
+        }
+    }
+
+    pub fn last_n(&self, device_id: &str, key: &str, n: usize) -> Vec<Sample> {
+        self.store.last_n(device_id, key, n)
+    }
+
+    pub fn range(
+        &self,
+        device_id: &str,
+        key: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<Sample> {
+        self.store.range(device_id, key, from, to)
+    }
+
+    pub fn keys(&self, device_id: &str) -> Vec<String> {
+        self.store.keys(device_id)
+    }
+
+    pub fn forget(&self, device_id: &str) {
+        self.store.remove(device_id);
+    }
+
+    /// Samples within `from..to` summed up in buckets of `step`, oldest
+    /// first. Steps without samples have no bucket.
+    pub fn downsample(
+        &self,
+        device_id: &str,
+        key: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step: Duration,
+    ) -> Result<Vec<HistoryBucket>, BlinkieError> {
+        if step < Duration::milliseconds(1) {
+            return Err("Downsampling step must be at least a millisecond".into());
+        }
+        let mut buckets: Vec<HistoryBucket> = Vec::new();
+        for sample in self.store.range(device_id, key, from, to) {
+            let steps = (sample.timestamp - from).num_milliseconds() / step.num_milliseconds();
+            let start = from + Duration::milliseconds(steps * step.num_milliseconds());
+            match buckets.last_mut().filter(|bucket| bucket.start == start) {
+                Some(bucket) => bucket.add(&sample),
+                None => {
+                    let mut bucket = HistoryBucket::new(start);
+                    bucket.add(&sample);
+                    buckets.push(bucket);
+                }
+            }
+        }
+        Ok(buckets)
+    }
+
+    /// Whether a device reported a state, or with `equals` that value of
+    /// it, since `since`.
+    pub fn reported_since(
+        &self,
+        device_id: &str,
+        key: &str,
+        since: DateTime<Utc>,
+        equals: Option<&StateValue>,
+    ) -> bool {
+        self.store
+            .range(device_id, key, since, DateTime::<Utc>::MAX_UTC)
+            .iter()
+            .any(|sample| {
+                equals.is_none_or(|equals| StateValue::parse(&sample.value).matches(equals))
+            })
+    }
+
+    /// Catches up on journaled events and then follows the bus until it closes.
+    pub async fn run(self: Arc<Self>, events: Arc<EventBus>) {
+        let last_seq = *self.last_seq.lock().unwrap();
+        let mut receiver = match events.resume(last_seq) {
+            Ok((missed, receiver)) => {
+                for event in &missed {
+                    self.record(event);
+                }
+                receiver
+            }
+            Err(_) => events.subscribe(),
+        };
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use super::device::{Device, Type};
+use super::value::{StateMap, StateValue};
+use chrono::{Datelike, Duration, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Id of the hub's workday sensor.
+pub const SENSOR_ID: &str = "workday";
+
+/**
+ * Holiday Rules
+ * How the date of a holiday is determined in a given year.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HolidayRule {
+    /// Same date every year.
+    Fixed { month: u32, day: u32 },
+    /// Days relative to Easter Sunday, e.g. -2 for Good Friday.
+    Easter { offset: i64 },
+    /// The n-th weekday of a month; negative `n` counts from the end (-1 = last).
+    NthWeekday { month: u32, weekday: Weekday, n: i8 },
+}
+
+impl HolidayRule {
+    /// Date of the holiday in `year`, if the rule yields a valid date.
+    pub fn date_in(&self, year: i32) -> Option<NaiveDate> {
+        match self {
+            HolidayRule::Fixed { month, day } => NaiveDate::from_ymd_opt(year, *month, *day),
+            HolidayRule::Easter { offset } => Some(easter_sunday(year) + Duration::days(*offset)),
+            HolidayRule::NthWeekday { month, weekday, n } => match n {
+                1.. => NaiveDate::from_weekday_of_month_opt(year, *month, *weekday, *n as u8),
+                ..=-1 => {
+                    // Walk back from the last day of the month
+                    let first_of_next = if *month == 12 {
+                        NaiveDate::from_ymd_opt(year + 1, 1, 1)
+                    } else {
+                        NaiveDate::from_ymd_opt(year, month + 1, 1)
+                    }?;
+                    let last = first_of_next - Duration::days(1);
+                    let back = (7 + last.weekday().num_days_from_monday()
+                        - weekday.num_days_from_monday())
+                        % 7;
+                    let date = last
+                        - Duration::days(back as i64)
+                        - Duration::weeks(n.unsigned_abs() as i64 - 1);
+                    (date.month() == *month).then_some(date)
+                }
+                0 => None,
+            },
+        }
+    }
+}
+
+/// Easter Sunday in the Gregorian calendar (anonymous Gregorian algorithm).
+pub fn easter_sunday(year: i32) -> NaiveDate {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32).unwrap()
+}
+
+/**
+ * Holiday
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Holiday {
+    pub name: String,
+    pub rule: HolidayRule,
+}
+
+impl Holiday {
+    fn new(name: &str, rule: HolidayRule) -> Self {
+        Holiday {
+            name: name.to_string(),
+            rule,
+        }
+    }
+}
+
+/**
+ * Countries
+ * Countries with a bundled calendar of nationwide public holidays.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Country {
+    At,
+    Ch,
+    De,
+    Fr,
+    Gb,
+    Nl,
+    Us,
+}
+
+impl Country {
+    /// Nationwide public holidays of the country.
+    pub fn holidays(&self) -> Vec<Holiday> {
+        use HolidayRule::*;
+        let fixed = |name, month, day| Holiday::new(name, Fixed { month, day });
+        let easter = |name, offset| Holiday::new(name, Easter { offset });
+        let nth = |name, month, weekday, n| Holiday::new(name, NthWeekday { month, weekday, n });
+
+        match self {
+            Country::At => vec![
+                fixed("Neujahr", 1, 1),
+                fixed("Heilige Drei Könige", 1, 6),
+                easter("Ostermontag", 1),
+                fixed("Staatsfeiertag", 5, 1),
+                easter("Christi Himmelfahrt", 39),
+                easter("Pfingstmontag", 50),
+                easter("Fronleichnam", 60),
+                fixed("Mariä Himmelfahrt", 8, 15),
+                fixed("Nationalfeiertag", 10, 26),
+                fixed("Allerheiligen", 11, 1),
+                fixed("Mariä Empfängnis", 12, 8),
+                fixed("Christtag", 12, 25),
+                fixed("Stefanitag", 12, 26),
+            ],
+            Country::Ch => vec![
+                fixed("Neujahr", 1, 1),
+                easter("Karfreitag", -2),
+                easter("Ostermontag", 1),
+                easter("Auffahrt", 39),
+                easter("Pfingstmontag", 50),
+                fixed("Bundesfeier", 8, 1),
+                fixed("Weihnachten", 12, 25),
+                fixed("Stephanstag", 12, 26),
+            ],
+            Country::De => vec![
+                fixed("Neujahr", 1, 1),
+                easter("Karfreitag", -2),
+                easter("Ostermontag", 1),
+                fixed("Tag der Arbeit", 5, 1),
+                easter("Christi Himmelfahrt", 39),
+                easter("Pfingstmontag", 50),
+                fixed("Tag der Deutschen Einheit", 10, 3),
+                fixed("1. Weihnachtstag", 12, 25),
+                fixed("2. Weihnachtstag", 12, 26),
+            ],
+            Country::Fr => vec![
+                fixed("Jour de l'an", 1, 1),
+                easter("Lundi de Pâques", 1),
+                fixed("Fête du Travail", 5, 1),
+                fixed("Victoire 1945", 5, 8),
+                easter("Ascension", 39),
+                easter("Lundi de Pentecôte", 50),
+                fixed("Fête nationale", 7, 14),
+                fixed("Assomption", 8, 15),
+                fixed("Toussaint", 11, 1),
+                fixed("Armistice 1918", 11, 11),
+                fixed("Noël", 12, 25),
+            ],
+            Country::Gb => vec![
+                fixed("New Year's Day", 1, 1),
+                easter("Good Friday", -2),
+                easter("Easter Monday", 1),
+                nth("Early May Bank Holiday", 5, Weekday::Mon, 1),
+                nth("Spring Bank Holiday", 5, Weekday::Mon, -1),
+                nth("Summer Bank Holiday", 8, Weekday::Mon, -1),
+                fixed("Christmas Day", 12, 25),
+                fixed("Boxing Day", 12, 26),
+            ],
+            Country::Nl => vec![
+                fixed("Nieuwjaarsdag", 1, 1),
+                easter("Tweede Paasdag", 1),
+                fixed("Koningsdag", 4, 27),
+                fixed("Bevrijdingsdag", 5, 5),
+                easter("Hemelvaartsdag", 39),
+                easter("Tweede Pinksterdag", 50),
+                fixed("Eerste Kerstdag", 12, 25),
+                fixed("Tweede Kerstdag", 12, 26),
+            ],
+            Country::Us => vec![
+                fixed("New Year's Day", 1, 1),
+                nth("Martin Luther King Jr. Day", 1, Weekday::Mon, 3),
+                nth("Presidents' Day", 2, Weekday::Mon, 3),
+                nth("Memorial Day", 5, Weekday::Mon, -1),
+                fixed("Juneteenth", 6, 19),
+                fixed("Independence Day", 7, 4),
+                nth("Labor Day", 9, Weekday::Mon, 1),
+                nth("Columbus Day", 10, Weekday::Mon, 2),
+                fixed("Veterans Day", 11, 11),
+                nth("Thanksgiving Day", 11, Weekday::Thu, 4),
+                fixed("Christmas Day", 12, 25),
+            ],
+        }
+    }
+}
+
+fn default_workdays() -> Vec<Weekday> {
+    vec![
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+    ]
+}
+
+/// The hub's calendar, shared by the scheduler that keeps it and
+/// everything going by it.
+pub type SharedWorkdays = Arc<RwLock<Workdays>>;
+
+/**
+ * Workdays
+ * Decides whether a date is a working day: one of the configured weekdays
+ * and neither a public holiday of the country nor a custom holiday.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Workdays {
+    #[serde(default)]
+    pub country: Option<Country>,
+    #[serde(default = "default_workdays")]
+    pub weekdays: Vec<Weekday>,
+    /// Additional days off, e.g. regional holidays or company closures.
+    #[serde(default)]
+    pub extra_holidays: Vec<Holiday>,
+}
+
+impl Default for Workdays {
+    fn default() -> Self {
+        Workdays {
+            country: None,
+            weekdays: default_workdays(),
+            extra_holidays: Vec::new(),
+        }
+    }
+}
+
+impl Workdays {
+    /// Name of the holiday falling on `date`, if any.
+    pub fn holiday(&self, date: NaiveDate) -> Option<String> {
+        let national = self.country.map(|c| c.holidays()).unwrap_or_default();
+        national
+            .iter()
+            .chain(&self.extra_holidays)
+            .find(|holiday| holiday.rule.date_in(date.year()) == Some(date))
+            .map(|holiday| holiday.name.clone())
+    }
+
+    /// All holidays in `year`, sorted by date.
+    pub fn holidays_in(&self, year: i32) -> Vec<(NaiveDate, String)> {
+        let national = self.country.map(|c| c.holidays()).unwrap_or_default();
+        let mut holidays: Vec<(NaiveDate, String)> = national
+            .iter()
+            .chain(&self.extra_holidays)
+            .filter_map(|holiday| Some((holiday.rule.date_in(year)?, holiday.name.clone())))
+            .collect();
+        holidays.sort();
+        holidays
+    }
+
+    pub fn is_workday(&self, date: NaiveDate) -> bool {
+        self.weekdays.contains(&date.weekday()) && self.holiday(date).is_none()
+    }
+}
+
+/**
+ * WorkdaySensor
+ * Binary sensor that is "on" on working days in the configured time zone,
+ * so rules and schedules can treat public holidays like weekends.
+ */
+pub struct WorkdaySensor {
+    id: String,
+    name: String,
+    timezone: Tz,
+    workdays: SharedWorkdays,
+}
+
+impl WorkdaySensor {
+    pub fn new(id: &str, name: &str, timezone: Tz, workdays: SharedWorkdays) -> Self {
+        WorkdaySensor {
+            id: id.to_string(),
+            name: name.to_string(),
+            timezone,
+            workdays,
+        }
+    }
+}
+
+impl Device for WorkdaySensor {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        let today = Utc::now().with_timezone(&self.timezone).date_naive();
+        let workdays = self.workdays.read().unwrap();
+        let mut state = HashMap::new();
+        let value = if workdays.is_workday(today) {
+            "on"
+        } else {
+            "off"
+        };
+        state.insert("state".to_string(), StateValue::from(value));
+        if let Some(holiday) = workdays.holiday(today) {
+            state.insert("holiday".to_string(), StateValue::String(holiday));
+        }
+        state
+    }
+
+    // The state is derived from the calendar and can't be set from outside
+    fn set_state(&mut self, _state: StateMap) {}
+
+    fn send_cmd(&mut self, _command: &str, _parameters: Option<HashMap<String, String>>) {}
+
+    fn get_type(&self) -> Option<Type> {
+        Some(Type::Sensor)
+    }
+}
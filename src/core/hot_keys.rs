@@ -0,0 +1,285 @@
+use super::event::{Event, EventKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Time over which the update rate of a key is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+fn default_hot_per_minute() -> u32 {
+    30
+}
+
+fn default_sample_secs() -> u64 {
+    60
+}
+
+/**
+ * HotKey
+ * A state key always sampled, of one device or of all of them, with its
+ * own sample interval and deadband if given.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HotKey {
+    #[serde(default)]
+    pub device_id: Option<String>,
+    pub key: String,
+    #[serde(default)]
+    pub sample_secs: Option<u64>,
+    #[serde(default)]
+    pub deadband: Option<f64>,
+}
+
+/**
+ * HotKeyConfig
+ * Which state keys are hot: those listed in `keys`, and with `detect` on,
+ * those updated at least `hot_per_minute` times a minute. Changes of hot
+ * keys reach subscribers as usual but are journaled, and so kept in the
+ * history, once every `sample_secs` only, or as soon as a numeric value
+ * moves by `deadband` from the one journaled last.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HotKeyConfig {
+    #[serde(default)]
+    pub detect: bool,
+    #[serde(default = "default_hot_per_minute")]
+    pub hot_per_minute: u32,
+    #[serde(default = "default_sample_secs")]
+    pub sample_secs: u64,
+    #[serde(default)]
+    pub deadband: Option<f64>,
+    #[serde(default)]
+    pub keys: Vec<HotKey>,
+}
+
+impl HotKeyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hot_per_minute == 0 {
+            return Err("hot_per_minute must be at least 1".to_string());
+        }
+        let samples = std::iter::once(Some(self.sample_secs))
+            .chain(self.keys.iter().map(|hot| hot.sample_secs));
+        if samples.flatten().any(|secs| secs == 0) {
+            return Err("sample_secs must be at least 1".to_string());
+        }
+        let deadbands = std::iter::once(self.deadband)
+            .chain(self.keys.iter().map(|hot| hot.deadband))
+            .flatten();
+        if deadbands
+            .into_iter()
+            .any(|deadband| deadband.is_nan() || deadband <= 0.0)
+        {
+            return Err("deadband must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// How `key` of `device_id`, updated `per_minute` times a minute, is
+    /// sampled, if it is hot.
+    fn sampling(
+        &self,
+        device_id: &str,
+        key: &str,
+        per_minute: f64,
+    ) -> Option<(Duration, Option<f64>)> {
+        let listed = self.keys.iter().find(|hot| {
+            hot.key == key && hot.device_id.as_deref().is_none_or(|id| id == device_id)
+        });
+        match listed {
+            Some(hot) => Some((
+                Duration::from_secs(hot.sample_secs.unwrap_or(self.sample_secs)),
+                hot.deadband.or(self.deadband),
+            )),
+            None if self.detect && per_minute >= f64::from(self.hot_per_minute) => {
+                Some((Duration::from_secs(self.sample_secs), self.deadband))
+            }
+            None => None,
+        }
+    }
+}
+
+impl Default for HotKeyConfig {
+    fn default() -> Self {
+        HotKeyConfig {
+            detect: false,
+            hot_per_minute: default_hot_per_minute(),
+            sample_secs: default_sample_secs(),
+            deadband: None,
+            keys: Vec::new(),
+        }
+    }
+}
+
+/**
+ * HotKeyStats
+ * How often a key is updated, whether it is hot, and how many of its
+ * changes were journaled and skipped.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HotKeyStats {
+    pub device_id: String,
+    pub key: String,
+    pub per_minute: f64,
+    pub hot: bool,
+    pub journaled: u64,
+    pub skipped: u64,
+}
+
+struct Tracker {
+    window_start: Instant,
+    updates: u32,
+    per_minute: f64,
+    hot: bool,
+    journaled_at: Option<Instant>,
+    journaled_value: Option<f64>,
+    /// Latest change not journaled, journaled once its sample is due.
+    pending: Option<Event>,
+    journaled: u64,
+    skipped: u64,
+}
+
+impl Tracker {
+    fn new(now: Instant) -> Self {
+        Tracker {
+            window_start: now,
+            updates: 0,
+            per_minute: 0.0,
+            hot: false,
+            journaled_at: None,
+            journaled_value: None,
+            pending: None,
+            journaled: 0,
+            skipped: 0,
+        }
+    }
+
+    fn journal(&mut self, now: Instant, value: Option<f64>) {
+        self.journaled_at = Some(now);
+        self.journaled_value = value;
+        self.pending = None;
+        self.journaled += 1;
+    }
+}
+
+/**
+ * HotKeys
+ * The fast path of the event bus: profiles how often each state key
+ * changes and lets only samples of hot keys through to the journal.
+ */
+#[derive(Default)]
+pub struct HotKeys {
+    config: HotKeyConfig,
+    trackers: HashMap<(String, String), Tracker>,
+}
+
+impl HotKeys {
+    pub fn config(&self) -> &HotKeyConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: HotKeyConfig) {
+        self.config = config;
+    }
+
+    /// Whether `event` is to be journaled. Changes of keys that aren't hot
+    /// and other events always are.
+    pub fn admit(&mut self, event: &Event, now: Instant) -> bool {
+        let EventKind::StateChanged {
+            device_id,
+            key,
+            new_value,
+            ..
+        } = &event.kind
+        else {
+            return true;
+        };
+        if !self.config.detect && self.config.keys.is_empty() {
+            return true;
+        }
+        let tracker = self
+            .trackers
+            .entry((device_id.clone(), key.clone()))
+            .or_insert_with(|| Tracker::new(now));
+        tracker.updates += 1;
+        let elapsed = now.duration_since(tracker.window_start);
+        if elapsed >= RATE_WINDOW {
+            tracker.per_minute = f64::from(tracker.updates) * 60.0 / elapsed.as_secs_f64();
+            tracker.updates = 0;
+            tracker.window_start = now;
+        }
+        let value = new_value.trim().parse::<f64>().ok();
+        let sampling = self.config.sampling(device_id, key, tracker.per_minute);
+        tracker.hot = sampling.is_some();
+        let due = match sampling {
+            None => true,
+            Some((sample, deadband)) => {
+                tracker
+                    .journaled_at
+                    .is_none_or(|at| now.duration_since(at) >= sample)
+                    || matches!(
+                        (deadband, value, tracker.journaled_value),
+                        (Some(deadband), Some(value), Some(last)) if (value - last).abs() >= deadband
+                    )
+            }
+        };
+        if due {
+            tracker.journal(now, value);
+        } else {
+            tracker.pending = Some(event.clone());
+            tracker.skipped += 1;
+        }
+        due
+    }
+
+    /// Latest skipped changes whose sample is due, so a hot key that went
+    /// quiet still has its last value journaled.
+    pub fn due(&mut self, now: Instant) -> Vec<Event> {
+        let config = &self.config;
+        let mut due: Vec<Event> = self
+            .trackers
+            .iter_mut()
+            .filter_map(|((device_id, key), tracker)| {
+                let (sample, _) = config.sampling(device_id, key, tracker.per_minute)?;
+                if tracker
+                    .journaled_at
+                    .is_some_and(|at| now.duration_since(at) < sample)
+                {
+                    return None;
+                }
+                let event = tracker.pending.take()?;
+                let value = match &event.kind {
+                    EventKind::StateChanged { new_value, .. } => new_value.trim().parse().ok(),
+                    _ => None,
+                };
+                tracker.journal(now, value);
+                Some(event)
+            })
+            .collect();
+        due.sort_by_key(|event| event.seq);
+        due
+    }
+
+    /// Drops the rates and samples not yet journaled of a device's keys.
+    pub fn forget(&mut self, device_id: &str) {
+        self.trackers.retain(|(id, _), _| id != device_id);
+    }
+
+    pub fn stats(&self) -> Vec<HotKeyStats> {
+        let mut stats: Vec<HotKeyStats> = self
+            .trackers
+            .iter()
+            .map(|((device_id, key), tracker)| HotKeyStats {
+                device_id: device_id.clone(),
+                key: key.clone(),
+                per_minute: tracker.per_minute,
+                hot: tracker.hot,
+                journaled: tracker.journaled,
+                skipped: tracker.skipped,
+            })
+            .collect();
+        stats.sort_by(|a, b| (&a.device_id, &a.key).cmp(&(&b.device_id, &b.key)));
+        stats
+    }
+}
@@ -0,0 +1,254 @@
+use chrono::{NaiveTime, Timelike, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/**
+ * Languages
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    De,
+}
+
+/**
+ * Unit Systems
+ * Values are always stored metric and only converted for display.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+/**
+ * Clock Formats
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeFormat {
+    #[default]
+    #[serde(rename = "24h")]
+    H24,
+    #[serde(rename = "12h")]
+    H12,
+}
+
+/**
+ * Quantities
+ * Physical quantities with a metric base unit: °C, m, km/h and l.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Distance,
+    Speed,
+    Volume,
+}
+
+/**
+ * Locale Settings
+ * Display preferences of the hub, or of a single user overriding them.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocaleSettings {
+    #[serde(default)]
+    pub language: Language,
+    #[serde(default)]
+    pub units: UnitSystem,
+    #[serde(default)]
+    pub time_format: TimeFormat,
+}
+
+/**
+ * Catalog
+ * Notification and UI message templates per language. Templates reference
+ * arguments as `{name}`.
+ */
+pub struct Catalog {
+    messages: HashMap<Language, HashMap<String, String>>,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        let mut catalog = Catalog {
+            messages: HashMap::new(),
+        };
+        for (key, en, de) in [
+            (
+                "device.unavailable",
+                "{device} is unavailable",
+                "{device} ist nicht erreichbar",
+            ),
+            (
+                "device.available",
+                "{device} is available again",
+                "{device} ist wieder erreichbar",
+            ),
+            (
+                "device.state_changed",
+                "{device} changed {key} to {value}",
+                "{device} hat {key} auf {value} geändert",
+            ),
+            (
+                "rule.failed",
+                "Rule {rule} failed: {error}",
+                "Regel {rule} ist fehlgeschlagen: {error}",
+            ),
+            (
+                "notification.digest",
+                "{count} notifications",
+                "{count} Benachrichtigungen",
+            ),
+        ] {
+            catalog.insert(Language::En, key, en);
+            catalog.insert(Language::De, key, de);
+        }
+        catalog
+    }
+}
+
+impl Catalog {
+    /// Adds or replaces a message template.
+    pub fn insert(&mut self, language: Language, key: &str, template: &str) {
+        self.messages
+            .entry(language)
+            .or_default()
+            .insert(key.to_string(), template.to_string());
+    }
+
+    /// Looks up a template, falling back to English.
+    pub fn get(&self, language: Language, key: &str) -> Option<&str> {
+        self.messages
+            .get(&language)
+            .and_then(|messages| messages.get(key))
+            .or_else(|| {
+                self.messages
+                    .get(&Language::En)
+                    .and_then(|messages| messages.get(key))
+            })
+            .map(String::as_str)
+    }
+}
+
+/**
+ * Localizer
+ * Renders messages, times and measurements for one set of locale settings.
+ */
+pub struct Localizer {
+    pub settings: LocaleSettings,
+    catalog: Arc<Catalog>,
+}
+
+impl Localizer {
+    pub fn new(settings: LocaleSettings, catalog: Arc<Catalog>) -> Self {
+        Localizer { settings, catalog }
+    }
+
+    /// Renders the message `key` with `args`. Unknown keys render as the key itself,
+    /// unknown arguments are left in place.
+    pub fn translate(&self, key: &str, args: &HashMap<&str, String>) -> String {
+        let template = self.catalog.get(self.settings.language, key).unwrap_or(key);
+        // One pass over the template, so braces inside substituted values
+        // are never taken for placeholders
+        let mut message = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            message.push_str(&rest[..start]);
+            let placeholder = rest[start + 1..]
+                .find('}')
+                .map(|end| &rest[start + 1..start + 1 + end]);
+            match placeholder.and_then(|name| Some((name, args.get(name)?))) {
+                Some((name, value)) => {
+                    message.push_str(value);
+                    rest = &rest[start + name.len() + 2..];
+                }
+                None => {
+                    message.push('{');
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        message.push_str(rest);
+        message
+    }
+
+    /// Full weekday name, e.g. "Monday".
+    pub fn weekday(&self, day: Weekday) -> &'static str {
+        let index = day.num_days_from_monday() as usize;
+        match self.settings.language {
+            Language::En => [
+                "Monday",
+                "Tuesday",
+                "Wednesday",
+                "Thursday",
+                "Friday",
+                "Saturday",
+                "Sunday",
+            ][index],
+            Language::De => [
+                "Montag",
+                "Dienstag",
+                "Mittwoch",
+                "Donnerstag",
+                "Freitag",
+                "Samstag",
+                "Sonntag",
+            ][index],
+        }
+    }
+
+    /// Abbreviated weekday name, e.g. "Mon".
+    pub fn weekday_short(&self, day: Weekday) -> &'static str {
+        let index = day.num_days_from_monday() as usize;
+        match self.settings.language {
+            Language::En => ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"][index],
+            Language::De => ["Mo", "Di", "Mi", "Do", "Fr", "Sa", "So"][index],
+        }
+    }
+
+    /// Formats a wall-clock time according to the preferred clock format.
+    pub fn format_time(&self, time: NaiveTime) -> String {
+        match self.settings.time_format {
+            TimeFormat::H24 => format!("{:02}:{:02}", time.hour(), time.minute()),
+            TimeFormat::H12 => {
+                let (pm, hour) = time.hour12();
+                format!(
+                    "{}:{:02} {}",
+                    hour,
+                    time.minute(),
+                    if pm { "PM" } else { "AM" }
+                )
+            }
+        }
+    }
+
+    /// Converts a metric value into the preferred unit system and returns it with its unit.
+    pub fn convert(&self, quantity: Quantity, value: f64) -> (f64, &'static str) {
+        match (self.settings.units, quantity) {
+            (UnitSystem::Metric, Quantity::Temperature) => (value, "°C"),
+            (UnitSystem::Metric, Quantity::Distance) => (value, "m"),
+            (UnitSystem::Metric, Quantity::Speed) => (value, "km/h"),
+            (UnitSystem::Metric, Quantity::Volume) => (value, "l"),
+            (UnitSystem::Imperial, Quantity::Temperature) => (value * 9.0 / 5.0 + 32.0, "°F"),
+            (UnitSystem::Imperial, Quantity::Distance) => (value * 3.280_84, "ft"),
+            (UnitSystem::Imperial, Quantity::Speed) => (value * 0.621_371, "mph"),
+            (UnitSystem::Imperial, Quantity::Volume) => (value * 0.264_172, "gal"),
+        }
+    }
+
+    /// Formats a metric value for display, e.g. "21.5 °C" or "70.7 °F".
+    pub fn format_quantity(&self, quantity: Quantity, value: f64) -> String {
+        let (value, unit) = self.convert(quantity, value);
+        let number = format!("{:.1}", value);
+        match self.settings.language {
+            Language::En => format!("{} {}", number, unit),
+            Language::De => format!("{} {}", number.replace('.', ","), unit),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use super::device::Type;
+use super::error::BlinkieError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// Largest picture accepted for upload.
+pub const MAX_PICTURE_BYTES: usize = 1024 * 1024;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Icon for a device without a configured one, derived from its class or,
+/// failing that, from the state keys it reports.
+pub fn default_icon(device_type: Option<Type>, capabilities: &[String]) -> &'static str {
+    let has = |key: &str| capabilities.iter().any(|c| c == key);
+    match device_type {
+        Some(Type::Sensor) if has("temperature") => "thermometer",
+        Some(Type::Sensor) if has("humidity") => "water-percent",
+        Some(Type::Sensor) => "gauge",
+        Some(Type::Switch) => "toggle-switch",
+        Some(Type::Actor) => "power",
+        Some(Type::Controller) => "remote",
+        Some(Type::Cat) => "cat",
+        None if has("temperature") => "thermometer",
+        None if has("brightness") => "lightbulb",
+        None if has("state") => "power",
+        None => "device",
+    }
+}
+
+/// File extension for an accepted picture content type.
+fn extension(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/png" => Some("png"),
+        "image/jpeg" => Some("jpg"),
+        "image/webp" => Some("webp"),
+        "image/gif" => Some("gif"),
+        _ => None,
+    }
+}
+
+/**
+ * Picture
+ * An uploaded entity picture as kept on disk.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Picture {
+    pub file: String,
+    pub content_type: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    #[serde(default)]
+    icons: HashMap<String, String>,
+    #[serde(default)]
+    pictures: HashMap<String, Picture>,
+}
+
+/**
+ * IconStore
+ * Icon names set for devices and uploaded entity pictures. Pictures are kept
+ * as files in the store's directory, next to an index of both.
+ */
+pub struct IconStore {
+    dir: PathBuf,
+    index: RwLock<Index>,
+}
+
+impl IconStore {
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self, BlinkieError> {
+        let dir = dir.into();
+        let path = dir.join(INDEX_FILE);
+        let index = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            Index::default()
+        };
+        Ok(IconStore {
+            dir,
+            index: RwLock::new(index),
+        })
+    }
+
+    pub fn icon(&self, device_id: &str) -> Option<String> {
+        self.index.read().unwrap().icons.get(device_id).cloned()
+    }
+
+    /// Sets or, with `None`, clears the icon of a device.
+    pub fn set_icon(&self, device_id: &str, icon: Option<String>) -> Result<(), BlinkieError> {
+        let mut index = self.index.write().unwrap();
+        match icon {
+            Some(icon) => index.icons.insert(device_id.to_string(), icon),
+            None => index.icons.remove(device_id),
+        };
+        self.persist(&index)
+    }
+
+    pub fn has_picture(&self, device_id: &str) -> bool {
+        self.index.read().unwrap().pictures.contains_key(device_id)
+    }
+
+    /// Returns the content type and bytes of a device's picture.
+    pub fn picture(&self, device_id: &str) -> Result<Option<(String, Vec<u8>)>, BlinkieError> {
+        let Some(picture) = self.index.read().unwrap().pictures.get(device_id).cloned() else {
+            return Ok(None);
+        };
+        let path = self.dir.join(&picture.file);
+        let bytes =
+            fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Ok(Some((picture.content_type, bytes)))
+    }
+
+    /// Stores a picture for a device, replacing any previous one.
+    pub fn set_picture(
+        &self,
+        device_id: &str,
+        content_type: &str,
+        bytes: &[u8],
+    ) -> Result<(), BlinkieError> {
+        let extension = extension(content_type)
+            .ok_or_else(|| format!("Unsupported picture type '{}'", content_type))?;
+        if bytes.len() > MAX_PICTURE_BYTES {
+            return Err(format!("Picture is larger than {} bytes", MAX_PICTURE_BYTES).into());
+        }
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+
+        // Device ids may contain characters that are not safe in file names
+        let hash = Sha256::digest(device_id.as_bytes());
+        let stem: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        let file = format!("{}.{}", stem, extension);
+        let path = self.dir.join(&file);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        let mut index = self.index.write().unwrap();
+        let previous = index.pictures.insert(
+            device_id.to_string(),
+            Picture {
+                file: file.clone(),
+                content_type: content_type.to_string(),
+            },
+        );
+        if let Some(previous) = previous.filter(|previous| previous.file != file) {
+            let _ = fs::remove_file(self.dir.join(previous.file));
+        }
+        self.persist(&index)
+    }
+
+    /// Deletes a device's picture. Returns whether there was one.
+    pub fn remove_picture(&self, device_id: &str) -> Result<bool, BlinkieError> {
+        let mut index = self.index.write().unwrap();
+        let Some(picture) = index.pictures.remove(device_id) else {
+            return Ok(false);
+        };
+        let path = self.dir.join(&picture.file);
+        fs::remove_file(&path)
+            .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        self.persist(&index)?;
+        Ok(true)
+    }
+
+    fn persist(&self, index: &Index) -> Result<(), BlinkieError> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+        let path = self.dir.join(INDEX_FILE);
+        let contents = serde_json::to_string_pretty(index).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize icons: {}", e))
+        })?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+    }
+}
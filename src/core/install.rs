@@ -0,0 +1,392 @@
+use super::error::BlinkieError;
+use super::package::Package;
+use crate::automation::rule::Rule;
+use chrono::{DateTime, Utc};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the manifest at the root of a Git source.
+pub const MANIFEST_FILE: &str = "blinkie.json";
+const INSTALLED_FILE: &str = "installed.json";
+
+/**
+ * Install Kinds
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallKind {
+    #[default]
+    Package,
+    Blueprint,
+}
+
+impl InstallKind {
+    /// Directory below the config directory the files of this kind go to.
+    fn dir(&self) -> &'static str {
+        match self {
+            InstallKind::Package => "packages",
+            InstallKind::Blueprint => "blueprints",
+        }
+    }
+}
+
+/**
+ * Manifest
+ * Describes a published package or blueprint and pins its content by checksum.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    pub name: String,
+    pub version: Version,
+    #[serde(default)]
+    pub kind: InstallKind,
+    /// Path of the content, relative to the manifest.
+    pub file: String,
+    /// Hex encoded SHA-256 of the content.
+    pub sha256: String,
+}
+
+/**
+ * Source
+ * Where a package or blueprint is installed from.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    /// A Git repository with a `blinkie.json` manifest at its root,
+    /// optionally pinned to a branch or tag.
+    Git {
+        url: String,
+        reference: Option<String>,
+    },
+    /// The URL of a manifest served over HTTP(S).
+    Http { url: String },
+}
+
+impl Source {
+    /// Parses `https://host/manifest.json`, `https://host/repo.git#v1.0`,
+    /// `git+https://host/repo#main` or `git@host:repo.git`.
+    pub fn parse(source: &str) -> Result<Self, BlinkieError> {
+        let (url, reference) = match source.split_once('#') {
+            Some((url, reference)) => (url, Some(reference.to_string())),
+            None => (source, None),
+        };
+        // Sources are handed to git as arguments, which must not read as options
+        let url = url.strip_prefix("git+").unwrap_or(url);
+        if url.starts_with('-') || reference.as_deref().is_some_and(|r| r.starts_with('-')) {
+            return Err(format!("Unsupported install source '{}'", source).into());
+        }
+        if source.starts_with("git+") {
+            return Ok(Source::Git {
+                url: url.to_string(),
+                reference,
+            });
+        }
+        if url.starts_with("git@") || url.starts_with("ssh://") || url.ends_with(".git") {
+            return Ok(Source::Git {
+                url: url.to_string(),
+                reference,
+            });
+        }
+        if url.starts_with("https://") || url.starts_with("http://") {
+            if reference.is_some() {
+                return Err(format!("Unexpected '#' in manifest URL '{}'", source).into());
+            }
+            return Ok(Source::Http {
+                url: url.to_string(),
+            });
+        }
+        Err(format!("Unsupported install source '{}'", source).into())
+    }
+}
+
+/**
+ * InstalledEntry
+ * What was installed, from where, and in which version.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstalledEntry {
+    pub source: Source,
+    pub kind: InstallKind,
+    pub version: Version,
+    pub sha256: String,
+    pub installed_at: DateTime<Utc>,
+}
+
+/**
+ * Update
+ * An installed item with a newer published version.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Update {
+    pub id: String,
+    pub installed: Version,
+    pub available: Version,
+}
+
+/**
+ * Installer
+ * Fetches packages and blueprints into a config directory and keeps track
+ * of their versions in `installed.json`.
+ */
+pub struct Installer {
+    config_dir: PathBuf,
+}
+
+impl Installer {
+    pub fn new<P: Into<PathBuf>>(config_dir: P) -> Self {
+        Installer {
+            config_dir: config_dir.into(),
+        }
+    }
+
+    /// Everything installed so far, keyed by id.
+    pub fn installed(&self) -> Result<BTreeMap<String, InstalledEntry>, BlinkieError> {
+        let path = self.config_dir.join(INSTALLED_FILE);
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+        })
+    }
+
+    /// Fetches, verifies and installs from a source. Installing the same or an
+    /// older version than the one present is refused unless `force` is set.
+    pub fn install(&self, source: &Source, force: bool) -> Result<Manifest, BlinkieError> {
+        let (manifest, content) = fetch(source, true)?;
+        let content = content.unwrap_or_default();
+        verify(&manifest, &content)?;
+
+        let mut installed = self.installed()?;
+        if let Some(entry) = installed.get(&manifest.id) {
+            if entry.source != *source && !force {
+                return Err(format!(
+                    "'{}' is already installed from a different source",
+                    manifest.id
+                )
+                .into());
+            }
+            if entry.version >= manifest.version && !force {
+                return Err(format!(
+                    "'{}' {} is already installed (found {})",
+                    manifest.id, entry.version, manifest.version
+                )
+                .into());
+            }
+        }
+
+        let dir = self.config_dir.join(manifest.kind.dir());
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        write_atomic(&dir.join(format!("{}.json", manifest.id)), &content)?;
+
+        installed.insert(
+            manifest.id.clone(),
+            InstalledEntry {
+                source: source.clone(),
+                kind: manifest.kind,
+                version: manifest.version.clone(),
+                sha256: manifest.sha256.to_lowercase(),
+                installed_at: Utc::now(),
+            },
+        );
+        self.save_installed(&installed)?;
+        Ok(manifest)
+    }
+
+    /// Compares every installed item with the version currently published at its source.
+    pub fn check_updates(&self) -> Result<Vec<Update>, BlinkieError> {
+        let mut updates = Vec::new();
+        for (id, entry) in self.installed()? {
+            let (manifest, _) = fetch(&entry.source, false)?;
+            if manifest.version > entry.version {
+                updates.push(Update {
+                    id,
+                    installed: entry.version,
+                    available: manifest.version,
+                });
+            }
+        }
+        Ok(updates)
+    }
+
+    /// Installs the newest version of an installed item from its recorded source.
+    pub fn update(&self, id: &str) -> Result<Manifest, BlinkieError> {
+        let installed = self.installed()?;
+        let entry = installed
+            .get(id)
+            .ok_or_else(|| format!("'{}' is not installed", id))?;
+        self.install(&entry.source, false)
+    }
+
+    fn save_installed(
+        &self,
+        installed: &BTreeMap<String, InstalledEntry>,
+    ) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(installed).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize installed list: {}", e))
+        })?;
+        write_atomic(&self.config_dir.join(INSTALLED_FILE), contents.as_bytes())
+    }
+}
+
+/// Fetches the manifest of a source and, if asked for, the content it points to.
+fn fetch(source: &Source, content: bool) -> Result<(Manifest, Option<Vec<u8>>), BlinkieError> {
+    match source {
+        Source::Http { url } => {
+            let manifest: Manifest = serde_json::from_slice(&download(url)?).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse manifest {}: {}", url, e))
+            })?;
+            check_relative(&manifest.file)?;
+            let content = if content {
+                let base = url.rsplit_once('/').map(|(base, _)| base).unwrap_or(url);
+                Some(download(&format!("{}/{}", base, manifest.file))?)
+            } else {
+                None
+            };
+            Ok((manifest, content))
+        }
+        Source::Git { url, reference } => {
+            let checkout = clone(url, reference.as_deref())?;
+            let result = read_checkout(&checkout, content);
+            let _ = fs::remove_dir_all(&checkout);
+            result
+        }
+    }
+}
+
+fn download(url: &str) -> Result<Vec<u8>, BlinkieError> {
+    let response = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| BlinkieError::connection(format!("Failed to download {}: {}", url, e)))?;
+    response
+        .bytes()
+        .map(|bytes| bytes.to_vec())
+        .map_err(|e| BlinkieError::connection(format!("Failed to download {}: {}", url, e)))
+}
+
+/// Makes a shallow clone into a fresh temporary directory.
+fn clone(url: &str, reference: Option<&str>) -> Result<PathBuf, BlinkieError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let dir = std::env::temp_dir().join(format!("blinkie-install-{}-{}", process::id(), nanos));
+
+    let mut git = process::Command::new("git");
+    git.args(["clone", "--quiet", "--depth", "1"]);
+    if let Some(reference) = reference {
+        git.args(["--branch", reference]);
+    }
+    let output = git
+        .arg("--")
+        .arg(url)
+        .arg(&dir)
+        .output()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(format!(
+            "Failed to clone {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(dir)
+}
+
+fn read_checkout(dir: &Path, content: bool) -> Result<(Manifest, Option<Vec<u8>>), BlinkieError> {
+    let path = dir.join(MANIFEST_FILE);
+    let contents =
+        fs::read(&path).map_err(|e| format!("Failed to read {}: {}", MANIFEST_FILE, e))?;
+    let manifest: Manifest = serde_json::from_slice(&contents).map_err(|e| {
+        BlinkieError::serialization(format!("Failed to parse {}: {}", MANIFEST_FILE, e))
+    })?;
+    check_relative(&manifest.file)?;
+    let content = if content {
+        Some(
+            fs::read(dir.join(&manifest.file))
+                .map_err(|e| format!("Failed to read {}: {}", manifest.file, e))?,
+        )
+    } else {
+        None
+    };
+    Ok((manifest, content))
+}
+
+/// Rejects manifest paths that would escape the source.
+fn check_relative(file: &str) -> Result<(), BlinkieError> {
+    let escapes = Path::new(file)
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if file.is_empty() || escapes {
+        return Err(format!("Invalid file '{}' in manifest", file).into());
+    }
+    Ok(())
+}
+
+/// Checks the content against the manifest's checksum and makes sure it is
+/// something blinkie can load.
+fn verify(manifest: &Manifest, content: &[u8]) -> Result<(), BlinkieError> {
+    if manifest.id.is_empty() || manifest.id.contains(['/', '\\', '.']) {
+        return Err(format!("Invalid id '{}' in manifest", manifest.id).into());
+    }
+
+    let digest: String = Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if !digest.eq_ignore_ascii_case(&manifest.sha256) {
+        return Err(format!(
+            "Checksum mismatch for '{}': expected {}, got {}",
+            manifest.id, manifest.sha256, digest
+        )
+        .into());
+    }
+
+    match manifest.kind {
+        InstallKind::Package => {
+            let package: Package = serde_json::from_slice(content).map_err(|e| {
+                BlinkieError::serialization(format!(
+                    "Failed to parse package '{}': {}",
+                    manifest.id, e
+                ))
+            })?;
+            package.validate()?;
+            if package.id != manifest.id {
+                return Err(format!(
+                    "Package id '{}' does not match manifest id '{}'",
+                    package.id, manifest.id
+                )
+                .into());
+            }
+        }
+        InstallKind::Blueprint => {
+            serde_json::from_slice::<Vec<Rule>>(content).map_err(|e| {
+                BlinkieError::serialization(format!(
+                    "Failed to parse blueprint '{}': {}",
+                    manifest.id, e
+                ))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), BlinkieError> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+}
@@ -0,0 +1,222 @@
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Finished jobs kept around for the API before the oldest are dropped.
+const KEEP_FINISHED: usize = 100;
+
+/**
+ * Job States
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        self != JobState::Running
+    }
+}
+
+/**
+ * Job
+ * A command that keeps running after it was sent, e.g. a firmware update
+ * or a vacuum cleaning run, and reports its progress while it does.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub device_id: String,
+    pub command: String,
+    pub state: JobState,
+    pub percent: Option<u8>,
+    /// What the job is doing right now, e.g. `downloading`.
+    pub phase: Option<String>,
+    pub error: Option<String>,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+/**
+ * JobHandle
+ * Handed to a device starting a job, to report progress through and to
+ * learn that the job was cancelled.
+ */
+#[derive(Clone)]
+pub struct JobHandle {
+    id: u64,
+    device_id: String,
+    command: String,
+    jobs: Arc<JobManager>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Reports how far the job got. Either value may be left out.
+    pub fn progress(&self, percent: Option<u8>, phase: Option<&str>) {
+        self.jobs.update(self, |job| {
+            job.percent = percent.map(|percent| percent.min(100)).or(job.percent);
+            if let Some(phase) = phase {
+                job.phase = Some(phase.to_string());
+            }
+        });
+    }
+
+    pub fn complete(&self) {
+        self.jobs.update(self, |job| {
+            job.state = JobState::Completed;
+            job.percent = Some(100);
+        });
+    }
+
+    pub fn fail(&self, error: &str) {
+        self.jobs.update(self, |job| {
+            job.state = JobState::Failed;
+            job.error = Some(error.to_string());
+        });
+    }
+
+    /// Whether the job was cancelled and the device should stop working on it.
+    pub fn is_cancelled(&self) -> bool {
+        self.jobs
+            .get(self.id)
+            .is_some_and(|job| job.state == JobState::Cancelled)
+    }
+}
+
+/**
+ * JobManager
+ * Tracks running and recently finished jobs and publishes every change as
+ * a `job_updated` event.
+ */
+pub struct JobManager {
+    jobs: RwLock<HashMap<u64, Job>>,
+    next_id: AtomicU64,
+    events: Arc<EventBus>,
+}
+
+impl JobManager {
+    pub fn new(events: Arc<EventBus>) -> Arc<Self> {
+        Arc::new(JobManager {
+            jobs: RwLock::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            events,
+        })
+    }
+
+    /// A handle for a job `command` of the device may turn into. The job is
+    /// only listed once it is started or reports progress.
+    pub fn handle(self: &Arc<Self>, device_id: &str, command: &str) -> JobHandle {
+        JobHandle {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            device_id: device_id.to_string(),
+            command: command.to_string(),
+            jobs: self.clone(),
+        }
+    }
+
+    /// Lists the job of `handle` as running.
+    pub fn start(&self, handle: &JobHandle) {
+        self.update(handle, |_| {});
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.jobs.read().unwrap().get(&id).cloned()
+    }
+
+    /// Running and recently finished jobs, newest first.
+    pub fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.read().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|job| std::cmp::Reverse(job.id));
+        jobs
+    }
+
+    /// Marks a running job as cancelled. The device notices the next time
+    /// it checks its handle.
+    pub fn cancel(&self, id: u64) -> Result<Job, BlinkieError> {
+        let job = {
+            let mut jobs = self.jobs.write().unwrap();
+            let job = jobs
+                .get_mut(&id)
+                .ok_or_else(|| format!("Job {} not found", id))?;
+            if job.state.is_finished() {
+                return Err(format!("Job {} already finished", id).into());
+            }
+            job.state = JobState::Cancelled;
+            job.finished = Some(Utc::now());
+            job.clone()
+        };
+        self.publish(&job);
+        Ok(job)
+    }
+
+    /// Applies `change` to the job of `handle`, listing it if it isn't yet.
+    /// Finished jobs don't change anymore.
+    fn update(&self, handle: &JobHandle, change: impl FnOnce(&mut Job)) {
+        let job = {
+            let mut jobs = self.jobs.write().unwrap();
+            let job = jobs.entry(handle.id).or_insert_with(|| Job {
+                id: handle.id,
+                device_id: handle.device_id.clone(),
+                command: handle.command.clone(),
+                state: JobState::Running,
+                percent: None,
+                phase: None,
+                error: None,
+                started: Utc::now(),
+                finished: None,
+            });
+            if job.state.is_finished() {
+                return;
+            }
+            change(job);
+            if job.state.is_finished() {
+                job.finished = Some(Utc::now());
+            }
+            let job = job.clone();
+            prune(&mut jobs);
+            job
+        };
+        self.publish(&job);
+    }
+
+    fn publish(&self, job: &Job) {
+        let _ = self.events.publish(EventKind::JobUpdated {
+            job_id: job.id,
+            device_id: job.device_id.clone(),
+            command: job.command.clone(),
+            state: job.state,
+            percent: job.percent,
+            phase: job.phase.clone(),
+        });
+    }
+}
+
+/// Drops the oldest finished jobs beyond the ones kept.
+fn prune(jobs: &mut HashMap<u64, Job>) {
+    let mut finished: Vec<u64> = jobs
+        .values()
+        .filter(|job| job.state.is_finished())
+        .map(|job| job.id)
+        .collect();
+    if finished.len() <= KEEP_FINISHED {
+        return;
+    }
+    finished.sort();
+    for id in &finished[..finished.len() - KEEP_FINISHED] {
+        jobs.remove(id);
+    }
+}
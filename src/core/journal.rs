@@ -0,0 +1,246 @@
+use super::error::BlinkieError;
+use super::event::Event;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/**
+ * HighWater
+ * Line ending a rewritten journal whose newest events were removed, so
+ * sequence numbers carry on after them when the journal is opened again.
+ */
+#[derive(Serialize, Deserialize)]
+struct HighWater {
+    high_water: u64,
+}
+
+/**
+ * EventJournal
+ * Append-only, line-delimited JSON log of every event published on the bus.
+ * Each line holds exactly one event; sequence numbers only ever grow.
+ */
+pub struct EventJournal {
+    path: PathBuf,
+    file: File,
+    last_seq: Option<u64>,
+    /// A failed append may have left part of a line behind.
+    torn: bool,
+}
+
+impl EventJournal {
+    /// Opens (or creates) the journal at `path` and recovers the last sequence number.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to open event journal {}: {}", path.display(), e))?;
+
+        // A crash mid-write can leave a partial last line behind; terminate it so
+        // the next append starts on a fresh line instead of corrupting a good one.
+        let len = file
+            .metadata()
+            .map_err(|e| format!("Failed to stat event journal: {}", e))?
+            .len();
+        if len > 0 {
+            let mut last = [0u8; 1];
+            file.seek(SeekFrom::End(-1))
+                .and_then(|_| file.read_exact(&mut last))
+                .map_err(|e| format!("Failed to read event journal: {}", e))?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")
+                    .map_err(|e| format!("Failed to repair event journal: {}", e))?;
+            }
+        }
+
+        let mut journal = EventJournal {
+            path,
+            file,
+            last_seq: None,
+            torn: false,
+        };
+        journal.last_seq = journal.head()?;
+        Ok(journal)
+    }
+
+    /// Highest sequence number handed out: that of the newest event, or the
+    /// high-water mark left by a rewrite that removed it.
+    fn head(&self) -> Result<Option<u64>, BlinkieError> {
+        let file = File::open(&self.path).map_err(|e| {
+            format!(
+                "Failed to open event journal {}: {}",
+                self.path.display(),
+                e
+            )
+        })?;
+        let mut head = None;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| format!("Failed to read event journal: {}", e))?;
+            let seq = match serde_json::from_str::<Event>(&line) {
+                Ok(event) => event.seq,
+                Err(_) => match serde_json::from_str::<HighWater>(&line) {
+                    Ok(mark) => mark.high_water,
+                    Err(_) => continue,
+                },
+            };
+            head = head.max(Some(seq));
+        }
+        Ok(head)
+    }
+
+    /// Path of the journal file on disk.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sequence number of the newest event in the journal, if any.
+    pub fn last_seq(&self) -> Option<u64> {
+        self.last_seq
+    }
+
+    /// Appends an event. Its sequence number must be greater than the last one written.
+    pub fn append(&mut self, event: &Event) -> Result<(), BlinkieError> {
+        if let Some(last) = self.last_seq {
+            if event.seq <= last {
+                return Err(format!(
+                    "Event sequence {} is not after journal head {}",
+                    event.seq, last
+                )
+                .into());
+            }
+        }
+
+        let mut line = serde_json::to_string(event).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize event {}: {}", event.seq, e))
+        })?;
+        line.push('\n');
+        if self.torn {
+            // Ends what a failed append left behind on a line of its own
+            line.insert(0, '\n');
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .and_then(|_| self.file.flush())
+            .map_err(|e| {
+                self.torn = true;
+                format!("Failed to append event {}: {}", event.seq, e)
+            })?;
+
+        self.torn = false;
+        self.last_seq = Some(event.seq);
+        Ok(())
+    }
+
+    /// Returns every event with a sequence number of at least `seq`.
+    pub fn read_from(&self, seq: u64) -> Result<Vec<Event>, BlinkieError> {
+        self.read_matching(|event| event.seq >= seq)
+    }
+
+    /// Returns every event recorded within `[from, to]`.
+    pub fn read_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<Event>, BlinkieError> {
+        self.read_matching(|event| event.timestamp >= from && event.timestamp <= to)
+    }
+
+    /// Rewrites the journal keeping only events matching `keep`. The new file is
+    /// written next to the old one and swapped in atomically, so a crash leaves
+    /// either the complete old or the complete new journal. Returns the number
+    /// of events removed.
+    pub fn retain<F>(&mut self, keep: F) -> Result<usize, BlinkieError>
+    where
+        F: Fn(&Event) -> bool,
+    {
+        let events = self.read_all()?;
+        let total = events.len();
+        let kept: Vec<Event> = events.into_iter().filter(|event| keep(event)).collect();
+        let removed = total - kept.len();
+        if removed == 0 {
+            return Ok(0);
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut contents = String::new();
+        for event in &kept {
+            let line = serde_json::to_string(event).map_err(|e| {
+                BlinkieError::serialization(format!(
+                    "Failed to serialize event {}: {}",
+                    event.seq, e
+                ))
+            })?;
+            contents.push_str(&line);
+            contents.push('\n');
+        }
+        // Keeps sequence numbers of removed head events from being reused
+        // after a restart
+        if let Some(last_seq) = self
+            .last_seq
+            .filter(|seq| kept.last().is_none_or(|event| event.seq < *seq))
+        {
+            let mark = serde_json::to_string(&HighWater {
+                high_water: last_seq,
+            })
+            .map_err(|e| {
+                BlinkieError::serialization(format!("Failed to serialize high-water mark: {}", e))
+            })?;
+            contents.push_str(&mark);
+            contents.push('\n');
+        }
+        let mut tmp = File::create(&tmp_path)
+            .map_err(|e| format!("Failed to create {}: {}", tmp_path.display(), e))?;
+        tmp.write_all(contents.as_bytes())
+            .and_then(|_| tmp.sync_all())
+            .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| format!("Failed to replace event journal: {}", e))?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Failed to reopen event journal: {}", e))?;
+        // `last_seq` stays as it was even if the head event was removed, and
+        // the high-water mark keeps it across restarts, so the bus never
+        // hands out a sequence number twice.
+        Ok(removed)
+    }
+
+    /// Returns the complete journal.
+    pub fn read_all(&self) -> Result<Vec<Event>, BlinkieError> {
+        self.read_matching(|_| true)
+    }
+
+    fn read_matching<F>(&self, filter: F) -> Result<Vec<Event>, BlinkieError>
+    where
+        F: Fn(&Event) -> bool,
+    {
+        let file = File::open(&self.path).map_err(|e| {
+            format!(
+                "Failed to open event journal {}: {}",
+                self.path.display(),
+                e
+            )
+        })?;
+
+        let mut events = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| format!("Failed to read event journal: {}", e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Lines cut short by a crash are skipped rather than failing the whole replay
+            if let Ok(event) = serde_json::from_str::<Event>(&line) {
+                if filter(&event) {
+                    events.push(event);
+                }
+            }
+        }
+        Ok(events)
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/**
+ * CommandLatency
+ * How long commands to a device took to hand to its handler.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CommandLatency {
+    pub count: u64,
+    pub last_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+/**
+ * LatencyTracker
+ * Keeps per-device command latency since startup.
+ */
+#[derive(Default)]
+pub struct LatencyTracker {
+    devices: RwLock<HashMap<String, CommandLatency>>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, device_id: &str, took: Duration) {
+        let ms = took.as_secs_f64() * 1000.0;
+        let mut devices = self.devices.write().unwrap();
+        let latency = devices.entry(device_id.to_string()).or_default();
+        latency.count += 1;
+        latency.last_ms = ms;
+        latency.mean_ms += (ms - latency.mean_ms) / latency.count as f64;
+        latency.max_ms = latency.max_ms.max(ms);
+    }
+
+    pub fn all(&self) -> HashMap<String, CommandLatency> {
+        self.devices.read().unwrap().clone()
+    }
+
+    pub fn forget(&self, device_id: &str) {
+        self.devices.write().unwrap().remove(device_id);
+    }
+}
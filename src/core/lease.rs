@@ -0,0 +1,150 @@
+use super::error::BlinkieError;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Longest a device can be claimed for at once. Holders needing more renew.
+pub const MAX_LEASE_SECS: i64 = 3600;
+
+/**
+ * Lease
+ * Exclusive control of a device, e.g. for a calibration wizard or a
+ * firmware updater. Until it expires or is released, only commands
+ * carrying the lease's token reach the device.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lease {
+    pub device_id: String,
+    /// Who holds the lease, shown to those turned away.
+    pub holder: String,
+    pub claimed: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+struct Held {
+    lease: Lease,
+    token: String,
+}
+
+/**
+ * LeaseManager
+ * Hands out and checks device leases. Expired leases count as released.
+ */
+#[derive(Default)]
+pub struct LeaseManager {
+    leases: RwLock<HashMap<String, Held>>,
+    claims: AtomicU64,
+}
+
+impl LeaseManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claims the device for `secs` seconds and returns the lease with the
+    /// token commands must carry. Passing the token of the current lease
+    /// renews it instead.
+    pub fn claim(
+        &self,
+        device_id: &str,
+        holder: &str,
+        secs: i64,
+        token: Option<&str>,
+    ) -> Result<(Lease, String), BlinkieError> {
+        if !(1..=MAX_LEASE_SECS).contains(&secs) {
+            return Err(format!(
+                "Lease duration must be between 1 and {} seconds",
+                MAX_LEASE_SECS
+            )
+            .into());
+        }
+        let now = Utc::now();
+        let mut leases = self.leases.write().unwrap();
+        if let Some(held) = leases.get_mut(device_id) {
+            if held.lease.expires > now {
+                if token != Some(held.token.as_str()) {
+                    return Err(busy(&held.lease).into());
+                }
+                held.lease.holder = holder.to_string();
+                held.lease.expires = now + Duration::seconds(secs);
+                return Ok((held.lease.clone(), held.token.clone()));
+            }
+        }
+        let held = Held {
+            lease: Lease {
+                device_id: device_id.to_string(),
+                holder: holder.to_string(),
+                claimed: now,
+                expires: now + Duration::seconds(secs),
+            },
+            token: self.token(device_id, now),
+        };
+        let claimed = (held.lease.clone(), held.token.clone());
+        leases.insert(device_id.to_string(), held);
+        Ok(claimed)
+    }
+
+    /// Ends the lease on the device early. Only its holder can.
+    pub fn release(&self, device_id: &str, token: &str) -> Result<Lease, BlinkieError> {
+        let mut leases = self.leases.write().unwrap();
+        match leases.get(device_id) {
+            Some(held) if held.lease.expires > Utc::now() && held.token == token => {
+                Ok(leases.remove(device_id).unwrap().lease)
+            }
+            Some(held) if held.lease.expires > Utc::now() => Err(format!(
+                "Lease on '{}' is held by '{}' under another token",
+                device_id, held.lease.holder
+            )
+            .into()),
+            _ => Err(format!("Device '{}' is not claimed", device_id).into()),
+        }
+    }
+
+    /// The current lease on the device, if it is claimed.
+    pub fn get(&self, device_id: &str) -> Option<Lease> {
+        self.leases
+            .read()
+            .unwrap()
+            .get(device_id)
+            .filter(|held| held.lease.expires > Utc::now())
+            .map(|held| held.lease.clone())
+    }
+
+    /// Fails with a "device busy" error when the device is claimed and
+    /// `token` isn't the lease's.
+    pub fn check(&self, device_id: &str, token: Option<&str>) -> Result<(), BlinkieError> {
+        match self.leases.read().unwrap().get(device_id) {
+            Some(held) if held.lease.expires > Utc::now() && token != Some(held.token.as_str()) => {
+                Err(busy(&held.lease).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A token nobody can guess from the device and time alone.
+    fn token(&self, device_id: &str, now: DateTime<Utc>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(RandomState::new().hash_one(device_id).to_le_bytes());
+        hasher.update(self.claims.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(now.timestamp_nanos_opt().unwrap_or_default().to_le_bytes());
+        hasher.update(device_id.as_bytes());
+        hasher.finalize()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+fn busy(lease: &Lease) -> String {
+    format!(
+        "Device '{}' is busy: claimed by '{}' until {}",
+        lease.device_id,
+        lease.holder,
+        lease.expires.to_rfc3339()
+    )
+}
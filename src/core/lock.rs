@@ -0,0 +1,388 @@
+use super::device::DeviceList;
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::retention::PurgeTarget;
+use super::value::StateMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+/// State key locks report whether they are locked in.
+pub const LOCK_STATE_KEY: &str = "lock_state";
+/// State key naming who unlocked the lock last, e.g. a keypad user.
+pub const UNLOCKED_BY_KEY: &str = "unlocked_by";
+/// Name of the custom event published when a lock jams.
+pub const JAMMED_EVENT: &str = "lock_jammed";
+
+/// Keys some locks report in place of `unlocked_by`, e.g. zigbee2mqtt's
+/// user slot of the last keypad or RFID action.
+const UNLOCKED_BY_FALLBACKS: [&str; 1] = ["action_user"];
+
+/**
+ * LockState
+ * Whether a lock is locked, unlocked or jammed, stuck between the two.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockState {
+    Locked,
+    Unlocked,
+    Jammed,
+}
+
+impl LockState {
+    /// Reads the lock state from `lock_state`, or from a `state` of
+    /// `LOCK`/`UNLOCK` as zigbee2mqtt locks report it. `None` for devices
+    /// that aren't locks.
+    pub fn from_state(state: &StateMap) -> Option<Self> {
+        if let Some(value) = state.get(LOCK_STATE_KEY) {
+            return match value.to_string().to_lowercase().as_str() {
+                "locked" | "lock" => Some(LockState::Locked),
+                "unlocked" | "unlock" => Some(LockState::Unlocked),
+                "jammed" | "not_fully_locked" => Some(LockState::Jammed),
+                _ => None,
+            };
+        }
+        match state.get("state")?.to_string().to_lowercase().as_str() {
+            "locked" | "lock" => Some(LockState::Locked),
+            "unlocked" | "unlock" => Some(LockState::Unlocked),
+            "jammed" => Some(LockState::Jammed),
+            _ => None,
+        }
+    }
+
+    /// Command bringing a lock into this state, `None` for jammed.
+    pub fn command(self) -> Option<&'static str> {
+        match self {
+            LockState::Locked => Some("lock"),
+            LockState::Unlocked => Some("unlock"),
+            LockState::Jammed => None,
+        }
+    }
+}
+
+/// Who last unlocked the lock, as the lock reports it.
+fn unlocked_by(state: &StateMap) -> Option<String> {
+    std::iter::once(UNLOCKED_BY_KEY)
+        .chain(UNLOCKED_BY_FALLBACKS)
+        .find_map(|key| state.get(key))
+        .map(|value| value.to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/**
+ * LockOperation
+ * What an audited lock operation did or asked for.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockOperation {
+    Lock,
+    Unlock,
+    Jammed,
+}
+
+impl LockOperation {
+    /// Operation a command asks for, if it is a lock command.
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command {
+            "lock" => Some(LockOperation::Lock),
+            "unlock" => Some(LockOperation::Unlock),
+            _ => None,
+        }
+    }
+
+    fn reported(state: LockState) -> Self {
+        match state {
+            LockState::Locked => LockOperation::Lock,
+            LockState::Unlocked => LockOperation::Unlock,
+            LockState::Jammed => LockOperation::Jammed,
+        }
+    }
+}
+
+/**
+ * LockOrigin
+ * Where an audited lock operation came from: a command sent through the
+ * API or by the hub, a rule, a scene setting the lock's state, or the lock
+ * itself, e.g. turned by hand or opened with a keypad code.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockOrigin {
+    Command,
+    Rule,
+    Scene,
+    Device,
+}
+
+/**
+ * LockRecord
+ * An entry of the lock audit. `actor` is the API account or rule sending a
+ * command, or who the lock says unlocked it.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LockRecord {
+    pub timestamp: DateTime<Utc>,
+    pub device_id: String,
+    pub operation: LockOperation,
+    pub origin: LockOrigin,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    /// Why the command failed, on the record following a failed command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/**
+ * LockStatus
+ * A lock with its current state and who unlocked it last.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LockStatus {
+    pub device_id: String,
+    pub name: String,
+    pub state: LockState,
+    pub unlocked_by: Option<String>,
+}
+
+/**
+ * LockAudit
+ * Append-only, line-delimited JSON log of every lock operation in
+ * `lock-audit.jsonl`. Lock commands are written down before they are sent
+ * and refused if that fails, so no lock is operated without a trace;
+ * changes the locks report are followed on the bus.
+ */
+pub struct LockAudit {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Last state reported by each lock.
+    states: Mutex<HashMap<String, LockState>>,
+    devices: DeviceList,
+    events: Arc<EventBus>,
+}
+
+impl LockAudit {
+    /// Opens (or creates) the audit log at `path`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        devices: DeviceList,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to open lock audit {}: {}", path.display(), e))?;
+        Ok(LockAudit {
+            path,
+            file: Mutex::new(file),
+            states: Mutex::new(HashMap::new()),
+            devices,
+            events,
+        })
+    }
+
+    /// Audits `command` to `device_id` before it is sent if it is a lock
+    /// command. Fails, and the command must not be sent, if the audit can't
+    /// be written.
+    pub fn authorize(
+        &self,
+        device_id: &str,
+        command: &str,
+        origin: LockOrigin,
+        actor: Option<&str>,
+    ) -> Result<(), BlinkieError> {
+        let Some(operation) = LockOperation::from_command(command) else {
+            return Ok(());
+        };
+        self.append(&LockRecord {
+            timestamp: Utc::now(),
+            device_id: device_id.to_string(),
+            operation,
+            origin,
+            actor: actor.map(str::to_string),
+            error: None,
+        })
+        .map_err(|e| format!("Refusing to {} '{}': {}", command, device_id, e).into())
+    }
+
+    /// Audits that an authorized lock command failed.
+    pub fn failed(
+        &self,
+        device_id: &str,
+        command: &str,
+        origin: LockOrigin,
+        actor: Option<&str>,
+        error: &BlinkieError,
+    ) {
+        let Some(operation) = LockOperation::from_command(command) else {
+            return;
+        };
+        let record = LockRecord {
+            timestamp: Utc::now(),
+            device_id: device_id.to_string(),
+            operation,
+            origin,
+            actor: actor.map(str::to_string),
+            error: Some(error.to_string()),
+        };
+        if let Err(e) = self.append(&record) {
+            log::error!("{}", e);
+        }
+    }
+
+    fn append(&self, record: &LockRecord) -> Result<(), BlinkieError> {
+        let mut line = serde_json::to_string(record).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize lock record: {}", e))
+        })?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.flush())
+            .map_err(|e| {
+                format!("Failed to write lock audit {}: {}", self.path.display(), e).into()
+            })
+    }
+
+    /// The newest `limit` records, of `device_id` only if given, oldest
+    /// first.
+    pub fn records(
+        &self,
+        device_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<LockRecord>, BlinkieError> {
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read lock audit {}: {}", self.path.display(), e))?;
+        let mut records: Vec<LockRecord> = contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<LockRecord>(line).ok())
+            .filter(|record| device_id.is_none_or(|id| record.device_id == id))
+            .collect();
+        let skip = records.len().saturating_sub(limit);
+        Ok(records.split_off(skip))
+    }
+
+    /// Deletes the records of a lock, or those naming a person as actor.
+    /// Returns the number of records removed.
+    pub fn purge(&self, target: &PurgeTarget) -> Result<usize, BlinkieError> {
+        let mut file = self.file.lock().unwrap();
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read lock audit {}: {}", self.path.display(), e))?;
+        let mut kept = String::new();
+        let mut removed = 0;
+        for line in contents.lines() {
+            let matches =
+                serde_json::from_str::<LockRecord>(line).is_ok_and(|record| match target {
+                    PurgeTarget::Device(id) => &record.device_id == id,
+                    PurgeTarget::Person(id) => record.actor.as_ref() == Some(id),
+                });
+            if matches {
+                removed += 1;
+            } else {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, kept)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .and_then(|_| OpenOptions::new().append(true).open(&self.path))
+            .map(|reopened| *file = reopened)
+            .map_err(|e| {
+                format!(
+                    "Failed to rewrite lock audit {}: {}",
+                    self.path.display(),
+                    e
+                )
+            })?;
+        Ok(removed)
+    }
+
+    /// Devices reporting a lock state, by id.
+    pub fn locks(&self) -> Vec<LockStatus> {
+        let devices = self.devices.read().unwrap();
+        let mut locks: Vec<LockStatus> = devices
+            .iter()
+            .filter_map(|device| {
+                let state = device.get_state();
+                Some(LockStatus {
+                    device_id: device.get_id().to_string(),
+                    name: device.get_name().to_string(),
+                    state: LockState::from_state(&state)?,
+                    unlocked_by: unlocked_by(&state),
+                })
+            })
+            .collect();
+        locks.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        locks
+    }
+
+    /// Audits a change of lock state reported in `event`, and publishes
+    /// `lock_jammed` when a lock jams.
+    pub fn record(&self, event: &Event) {
+        let EventKind::StateChanged { device_id, key, .. } = &event.kind else {
+            return;
+        };
+        if key != LOCK_STATE_KEY && key != "state" {
+            return;
+        }
+        let state = {
+            let devices = self.devices.read().unwrap();
+            match devices.iter().find(|device| device.get_id() == device_id) {
+                Some(device) => device.get_state(),
+                None => return,
+            }
+        };
+        let Some(lock_state) = LockState::from_state(&state) else {
+            return;
+        };
+        let previous = self
+            .states
+            .lock()
+            .unwrap()
+            .insert(device_id.clone(), lock_state);
+        if previous == Some(lock_state) {
+            return;
+        }
+        let record = LockRecord {
+            timestamp: event.timestamp,
+            device_id: device_id.clone(),
+            operation: LockOperation::reported(lock_state),
+            origin: LockOrigin::Device,
+            actor: unlocked_by(&state).filter(|_| lock_state == LockState::Unlocked),
+            error: None,
+        };
+        if let Err(e) = self.append(&record) {
+            log::error!("{}", e);
+        }
+        if lock_state == LockState::Jammed {
+            let _ = self.events.publish(EventKind::Custom {
+                name: JAMMED_EVENT.to_string(),
+                data: HashMap::from([("device_id".to_string(), device_id.clone())]),
+            });
+        }
+    }
+
+    /// Follows lock states on the bus until it closes.
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = self.events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use super::error::BlinkieError;
+use super::event::EventBus;
+use super::retention::RetentionPolicy;
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often the scheduler checks for due jobs.
+const TICK: Duration = Duration::from_secs(60);
+
+/// Names of the built-in maintenance jobs.
+pub const JOBS: &[&str] = &[
+    "aggregate_flush",
+    "backup",
+    "history_archive",
+    "history_compaction",
+    "history_export",
+    "log_rotation",
+    "usage_flush",
+];
+
+/**
+ * MaintenanceTask
+ * An internal housekeeping job. Tasks run on a blocking thread, so they are
+ * free to do file I/O.
+ */
+pub trait MaintenanceTask: Send + Sync {
+    fn name(&self) -> &str;
+    fn run(&self) -> Result<(), BlinkieError>;
+}
+
+/**
+ * MaintenanceWindow
+ * Local time range in which a job may start. The end may lie before the
+ * start for windows spanning midnight.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/**
+ * JobSettings
+ * How often a job runs and when it is allowed to.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobSettings {
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub window: Option<MaintenanceWindow>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl JobSettings {
+    pub fn every(interval: Duration) -> Self {
+        JobSettings {
+            interval_secs: interval.as_secs(),
+            window: None,
+            enabled: true,
+        }
+    }
+
+    pub fn within(mut self, window: MaintenanceWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+}
+
+/**
+ * JobMetrics
+ * Run counters and timings of a maintenance job.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JobMetrics {
+    pub runs: u64,
+    pub failures: u64,
+    pub running: bool,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    pub last_duration_ms: u64,
+    pub total_duration_ms: u64,
+    pub last_error: Option<String>,
+}
+
+/**
+ * MaintenanceConfig
+ * Settings of the built-in maintenance jobs by name, replacing the ones
+ * they come with, and the log file to rotate, e.g.
+ *
+ * ```toml
+ * [settings.maintenance.jobs.log_rotation]
+ * interval_secs = 86400
+ * window = { start = "02:00:00", end = "05:00:00" }
+ *
+ * [settings.maintenance.log]
+ * path = "/var/log/blinkie.log"
+ * ```
+ *
+ * The hub serves plain HTTP and holds no certificates, so there is no
+ * certificate renewal job; TLS is left to a reverse proxy in front of it.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    #[serde(default)]
+    pub jobs: BTreeMap<String, JobSettings>,
+    /// Log file to rotate, none if unset.
+    #[serde(default)]
+    pub log: Option<LogRotationConfig>,
+}
+
+impl MaintenanceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, settings) in &self.jobs {
+            if !JOBS.contains(&name.as_str()) {
+                return Err(format!(
+                    "unknown job '{}', known jobs: {}",
+                    name,
+                    JOBS.join(", ")
+                ));
+            }
+            if settings.interval_secs == 0 {
+                return Err(format!("jobs.{}: interval_secs must be at least 1", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * LogRotationConfig
+ * The log file the hub's output is written to, rotated once it grows past
+ * `max_bytes`, keeping `keep` old copies.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogRotationConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+struct Job {
+    task: Arc<dyn MaintenanceTask>,
+    /// Settings the job was added with.
+    defaults: JobSettings,
+    settings: JobSettings,
+    metrics: JobMetrics,
+}
+
+/**
+ * MaintenanceScheduler
+ * Runs internal jobs such as history compaction, backups and log rotation
+ * at their interval, inside their maintenance window in `timezone`.
+ */
+pub struct MaintenanceScheduler {
+    timezone: Tz,
+    jobs: RwLock<BTreeMap<String, Job>>,
+}
+
+impl MaintenanceScheduler {
+    pub fn new(timezone: Tz) -> Self {
+        MaintenanceScheduler {
+            timezone,
+            jobs: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn add_job(&self, task: Arc<dyn MaintenanceTask>, settings: JobSettings) {
+        let mut jobs = self.jobs.write().unwrap();
+        jobs.insert(
+            task.name().to_string(),
+            Job {
+                task,
+                defaults: settings.clone(),
+                settings,
+                metrics: JobMetrics::default(),
+            },
+        );
+    }
+
+    /// Gives the jobs named in `settings` those settings and the others
+    /// the ones they were added with.
+    pub fn apply(&self, settings: &BTreeMap<String, JobSettings>) {
+        let mut jobs = self.jobs.write().unwrap();
+        for (name, job) in jobs.iter_mut() {
+            job.settings = settings.get(name).unwrap_or(&job.defaults).clone();
+        }
+    }
+
+    /// Changes the interval, window or enabled flag of a job.
+    pub fn configure(&self, name: &str, settings: JobSettings) -> Result<(), BlinkieError> {
+        let mut jobs = self.jobs.write().unwrap();
+        let job = jobs
+            .get_mut(name)
+            .ok_or_else(|| format!("Maintenance job '{}' not found", name))?;
+        job.settings = settings;
+        Ok(())
+    }
+
+    pub fn settings(&self) -> BTreeMap<String, JobSettings> {
+        let jobs = self.jobs.read().unwrap();
+        jobs.iter()
+            .map(|(name, job)| (name.clone(), job.settings.clone()))
+            .collect()
+    }
+
+    pub fn metrics(&self) -> BTreeMap<String, JobMetrics> {
+        let jobs = self.jobs.read().unwrap();
+        jobs.iter()
+            .map(|(name, job)| (name.clone(), job.metrics.clone()))
+            .collect()
+    }
+
+    /// Checks for due jobs every minute until the task is dropped.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            for name in self.due(Utc::now()) {
+                let scheduler = self.clone();
+                tokio::spawn(async move { scheduler.run_job(&name).await });
+            }
+        }
+    }
+
+    /// Runs a job right away, ignoring its interval and window.
+    pub async fn run_job(self: &Arc<Self>, name: &str) -> Result<(), BlinkieError> {
+        let task = {
+            let mut jobs = self.jobs.write().unwrap();
+            let job = jobs
+                .get_mut(name)
+                .ok_or_else(|| format!("Maintenance job '{}' not found", name))?;
+            if job.metrics.running {
+                return Err(format!("Maintenance job '{}' is already running", name).into());
+            }
+            job.metrics.running = true;
+            job.metrics.last_started = Some(Utc::now());
+            job.task.clone()
+        };
+
+        let started = Instant::now();
+        let result = match tokio::task::spawn_blocking(move || task.run()).await {
+            Ok(result) => result,
+            Err(e) => Err(format!("Maintenance job panicked: {}", e).into()),
+        };
+        let elapsed = started.elapsed().as_millis() as u64;
+
+        if let Err(e) = &result {
+            log::warn!("Maintenance job '{}' failed: {}", name, e);
+        }
+        let mut jobs = self.jobs.write().unwrap();
+        if let Some(job) = jobs.get_mut(name) {
+            let metrics = &mut job.metrics;
+            metrics.running = false;
+            metrics.runs += 1;
+            metrics.last_finished = Some(Utc::now());
+            metrics.last_duration_ms = elapsed;
+            metrics.total_duration_ms += elapsed;
+            if let Err(e) = &result {
+                metrics.failures += 1;
+                metrics.last_error = Some(e.to_string());
+            }
+        }
+        result
+    }
+
+    /// Names of the jobs whose interval has passed and whose window is open.
+    fn due(&self, now: DateTime<Utc>) -> Vec<String> {
+        let local = now.with_timezone(&self.timezone).time();
+        let jobs = self.jobs.read().unwrap();
+        jobs.iter()
+            .filter(|(_, job)| job.settings.enabled && !job.metrics.running)
+            .filter(|(_, job)| job.settings.window.is_none_or(|w| w.contains(local)))
+            .filter(|(_, job)| {
+                job.metrics.last_started.is_none_or(|last| {
+                    (now - last).num_seconds() >= job.settings.interval_secs as i64
+                })
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+/**
+ * RetentionTask
+ * Compacts the event history by dropping events past their retention period.
+ */
+pub struct RetentionTask {
+    pub events: Arc<EventBus>,
+    pub policy: RetentionPolicy,
+}
+
+impl MaintenanceTask for RetentionTask {
+    fn name(&self) -> &str {
+        "history_compaction"
+    }
+
+    fn run(&self) -> Result<(), BlinkieError> {
+        let removed = self.events.apply_retention(&self.policy, Utc::now())?;
+        log::info!("History compaction removed {} event(s)", removed);
+        Ok(())
+    }
+}
+
+/**
+ * LogRotationTask
+ * Moves a log file aside once it grows past a size limit, keeping a fixed
+ * number of old copies as `<file>.1`, `<file>.2`, ...
+ */
+pub struct LogRotationTask {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+impl MaintenanceTask for LogRotationTask {
+    fn name(&self) -> &str {
+        "log_rotation"
+    }
+
+    fn run(&self) -> Result<(), BlinkieError> {
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(()),
+        };
+        if size < self.max_bytes {
+            return Ok(());
+        }
+
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            return fs::remove_file(&self.path)
+                .map_err(|e| format!("Failed to remove {}: {}", self.path.display(), e).into());
+        }
+        let _ = fs::remove_file(rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))
+                    .map_err(|e| format!("Failed to rotate {}: {}", self.path.display(), e))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))
+            .map_err(|e| format!("Failed to rotate {}: {}", self.path.display(), e).into())
+    }
+}
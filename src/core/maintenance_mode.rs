@@ -0,0 +1,199 @@
+use super::error::BlinkieError;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Longest a device or handler can be flagged for at once, so a forgotten
+/// flag doesn't keep automations paused for good.
+pub const MAX_MAINTENANCE_SECS: i64 = 7 * 24 * 3600;
+
+/**
+ * MaintenanceFlag
+ * Marks a device or handler as being worked on. Automations leave it
+ * alone and it isn't reported as unavailable until the flag expires or
+ * is cleared.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MaintenanceFlag {
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
+
+impl MaintenanceFlag {
+    fn active(&self, now: DateTime<Utc>) -> bool {
+        self.until > now
+    }
+}
+
+/**
+ * MaintenanceFlags
+ * Flags currently set, by device id and by handler name.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MaintenanceFlags {
+    #[serde(default)]
+    pub devices: HashMap<String, MaintenanceFlag>,
+    #[serde(default)]
+    pub handlers: HashMap<String, MaintenanceFlag>,
+}
+
+impl MaintenanceFlags {
+    fn expire(&mut self, now: DateTime<Utc>) {
+        self.devices.retain(|_, flag| flag.active(now));
+        self.handlers.retain(|_, flag| flag.active(now));
+    }
+}
+
+/**
+ * MaintenanceMode
+ * Keeps maintenance flags of devices and handlers. A device counts as in
+ * maintenance while it or the handler that created it is flagged. Flags
+ * are written back to disk so they survive restarts.
+ */
+pub struct MaintenanceMode {
+    path: PathBuf,
+    flags: RwLock<MaintenanceFlags>,
+    /// Handler that created each device, shared with the protocol registry.
+    device_handlers: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl MaintenanceMode {
+    /// Opens the flags kept at `path`, resolving device handlers through
+    /// `device_handlers`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        device_handlers: Arc<RwLock<HashMap<String, String>>>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let mut flags: MaintenanceFlags = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            MaintenanceFlags::default()
+        };
+        flags.expire(Utc::now());
+        Ok(MaintenanceMode {
+            path,
+            flags: RwLock::new(flags),
+            device_handlers,
+        })
+    }
+
+    /// Flags that haven't expired yet.
+    pub fn flags(&self) -> MaintenanceFlags {
+        let mut flags = self.flags.read().unwrap().clone();
+        flags.expire(Utc::now());
+        flags
+    }
+
+    /// The flag keeping the device in maintenance, its own or its handler's.
+    pub fn device(&self, device_id: &str) -> Option<MaintenanceFlag> {
+        let now = Utc::now();
+        let flags = self.flags.read().unwrap();
+        let handler = self.device_handlers.read().unwrap().get(device_id).cloned();
+        flags
+            .devices
+            .get(device_id)
+            .or_else(|| handler.and_then(|handler| flags.handlers.get(&handler)))
+            .filter(|flag| flag.active(now))
+            .cloned()
+    }
+
+    pub fn handler(&self, name: &str) -> Option<MaintenanceFlag> {
+        self.flags
+            .read()
+            .unwrap()
+            .handlers
+            .get(name)
+            .filter(|flag| flag.active(Utc::now()))
+            .cloned()
+    }
+
+    /// Puts the device in maintenance for `secs` seconds, replacing an
+    /// earlier flag.
+    pub fn set_device(
+        &self,
+        device_id: &str,
+        secs: i64,
+        reason: Option<String>,
+    ) -> Result<MaintenanceFlag, BlinkieError> {
+        self.set(secs, reason, |flags| &mut flags.devices, device_id)
+    }
+
+    pub fn set_handler(
+        &self,
+        name: &str,
+        secs: i64,
+        reason: Option<String>,
+    ) -> Result<MaintenanceFlag, BlinkieError> {
+        self.set(secs, reason, |flags| &mut flags.handlers, name)
+    }
+
+    /// Takes the device out of maintenance before its flag expires.
+    pub fn clear_device(&self, device_id: &str) -> Result<Option<MaintenanceFlag>, BlinkieError> {
+        self.clear(|flags| &mut flags.devices, device_id)
+    }
+
+    pub fn clear_handler(&self, name: &str) -> Result<Option<MaintenanceFlag>, BlinkieError> {
+        self.clear(|flags| &mut flags.handlers, name)
+    }
+
+    fn set(
+        &self,
+        secs: i64,
+        reason: Option<String>,
+        target: impl FnOnce(&mut MaintenanceFlags) -> &mut HashMap<String, MaintenanceFlag>,
+        key: &str,
+    ) -> Result<MaintenanceFlag, BlinkieError> {
+        if !(1..=MAX_MAINTENANCE_SECS).contains(&secs) {
+            return Err(format!(
+                "Maintenance duration must be between 1 and {} seconds",
+                MAX_MAINTENANCE_SECS
+            )
+            .into());
+        }
+        let now = Utc::now();
+        let flag = MaintenanceFlag {
+            reason,
+            since: now,
+            until: now + Duration::seconds(secs),
+        };
+        let mut flags = self.flags.write().unwrap();
+        flags.expire(now);
+        target(&mut flags).insert(key.to_string(), flag.clone());
+        self.save(&flags)?;
+        Ok(flag)
+    }
+
+    fn clear(
+        &self,
+        target: impl FnOnce(&mut MaintenanceFlags) -> &mut HashMap<String, MaintenanceFlag>,
+        key: &str,
+    ) -> Result<Option<MaintenanceFlag>, BlinkieError> {
+        let mut flags = self.flags.write().unwrap();
+        flags.expire(Utc::now());
+        let removed = target(&mut flags).remove(key);
+        if removed.is_some() {
+            self.save(&flags)?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self, flags: &MaintenanceFlags) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(flags).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize maintenance flags: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+}
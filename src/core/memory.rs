@@ -0,0 +1,176 @@
+use super::value::StateMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+fn default_state_cache() -> usize {
+    1024
+}
+
+fn default_journal_backlog() -> usize {
+    10_000
+}
+
+fn default_queue_per_device() -> usize {
+    32
+}
+
+fn default_dead_letters() -> usize {
+    100
+}
+
+/**
+ * Eviction
+ * What goes first when the journal backlog is full: the oldest event, or
+ * state changes a later change of the same key makes up for, then the
+ * oldest event.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    DropOldest,
+    #[default]
+    Coalesce,
+}
+
+/**
+ * MemoryLimits
+ * Caps of what the hub keeps in memory, to stay small on small boards:
+ * the states last written to the device store, by device, events kept
+ * while the journal fails, commands waiting for each device and the
+ * commands given up on.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryLimits {
+    #[serde(default = "default_state_cache")]
+    pub state_cache: usize,
+    #[serde(default = "default_journal_backlog")]
+    pub journal_backlog: usize,
+    #[serde(default)]
+    pub backlog_eviction: Eviction,
+    #[serde(default = "default_queue_per_device")]
+    pub queue_per_device: usize,
+    #[serde(default = "default_dead_letters")]
+    pub dead_letters: usize,
+}
+
+impl MemoryLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_per_device == 0 {
+            return Err("queue_per_device must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        MemoryLimits {
+            state_cache: default_state_cache(),
+            journal_backlog: default_journal_backlog(),
+            backlog_eviction: Eviction::default(),
+            queue_per_device: default_queue_per_device(),
+            dead_letters: default_dead_letters(),
+        }
+    }
+}
+
+/**
+ * CacheMetrics
+ * How full a capped cache is and what it had to let go of: entries
+ * evicted, or refused when the cache can't evict, and entries coalesced
+ * into later ones.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CacheMetrics {
+    pub entries: usize,
+    pub limit: usize,
+    pub evicted: u64,
+    #[serde(default)]
+    pub coalesced: u64,
+}
+
+/**
+ * MemoryStatus
+ * Metrics of every capped cache. The command queue's limit is per device.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MemoryStatus {
+    pub state_cache: CacheMetrics,
+    pub journal_backlog: CacheMetrics,
+    pub command_queue: CacheMetrics,
+    pub dead_letters: CacheMetrics,
+}
+
+/**
+ * StateCache
+ * States as last written to the device store, by device, so unchanged
+ * states aren't written again. Over its limit it forgets the states
+ * written longest ago, which then are written again with the next flush.
+ */
+pub struct StateCache {
+    states: HashMap<String, (u64, StateMap)>,
+    written: u64,
+    limit: usize,
+    evicted: u64,
+}
+
+impl StateCache {
+    pub fn new(limit: usize) -> Self {
+        StateCache {
+            states: HashMap::new(),
+            written: 0,
+            limit,
+            evicted: 0,
+        }
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&StateMap> {
+        self.states.get(device_id).map(|(_, state)| state)
+    }
+
+    pub fn remove(&mut self, device_id: &str) {
+        self.states.remove(device_id);
+    }
+
+    /// Remembers `states` as written, forgetting those written longest
+    /// ago beyond the limit.
+    pub fn extend<I: IntoIterator<Item = (String, StateMap)>>(&mut self, states: I) {
+        self.written += 1;
+        for (device_id, state) in states {
+            self.states.insert(device_id, (self.written, state));
+        }
+        self.evict();
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let over = self.states.len().saturating_sub(self.limit);
+        if over == 0 {
+            return;
+        }
+        let mut oldest: Vec<(u64, String)> = self
+            .states
+            .iter()
+            .map(|(device_id, (written, _))| (*written, device_id.clone()))
+            .collect();
+        oldest.sort();
+        for (_, device_id) in oldest.into_iter().take(over) {
+            self.states.remove(&device_id);
+        }
+        self.evicted += over as u64;
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            entries: self.states.len(),
+            limit: self.limit,
+            evicted: self.evicted,
+            coalesced: 0,
+        }
+    }
+}
@@ -1,2 +1,67 @@
+pub mod access;
+pub mod aggregate;
 pub mod app;
+pub mod archive;
+pub mod async_device;
+pub mod backup;
+#[cfg(feature = "mqtt-broker")]
+pub mod broker;
+pub mod chaos;
+pub mod color;
+pub mod command_queue;
+pub mod config;
+pub mod dashboard;
+pub mod degraded;
 pub mod device;
+pub mod discovery;
+pub mod doorbell;
+pub mod drift;
+pub mod effects;
+pub mod emulation;
+pub mod energy;
+pub mod error;
+pub mod event;
+pub mod export;
+pub mod federation;
+pub mod freshness;
+pub mod group;
+pub mod guest;
+pub mod health;
+pub mod history;
+pub mod holiday;
+pub mod hot_keys;
+pub mod i18n;
+pub mod icon;
+pub mod install;
+pub mod job;
+pub mod journal;
+pub mod latency;
+pub mod lease;
+pub mod lock;
+pub mod maintenance;
+pub mod maintenance_mode;
+pub mod memory;
+pub mod notification;
+pub mod occupancy;
+pub mod pacing;
+pub mod package;
+pub mod prediction;
+pub mod price;
+pub mod pump;
+pub mod push;
+pub mod query;
+pub mod remote;
+pub mod replay;
+pub mod retention;
+pub mod scene;
+pub mod schedule;
+pub mod scheduler;
+pub mod sniffer;
+pub mod snippet;
+pub mod storage;
+pub mod tts;
+pub mod ui;
+pub mod usage;
+pub mod user;
+pub mod value;
+pub mod webhook;
@@ -0,0 +1,756 @@
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::i18n::{Catalog, LocaleSettings, Localizer};
+use super::user::UserStore;
+use super::webhook::{Webhook, WebhookStore, WEBHOOK_PATH};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Name of the custom event rules publish to send a notification.
+pub const NOTIFY_EVENT: &str = "notify";
+/// Name of the custom event the event channel delivers notifications as.
+pub const NOTIFICATION_EVENT: &str = "notification";
+/// Name of the custom event published when someone picks an action of a
+/// notification, carrying `notification_id`, `action` and `channel`.
+pub const NOTIFICATION_ACTION_EVENT: &str = "notification_action";
+/// Catalog message titling digests, given `count`.
+const DIGEST_KEY: &str = "notification.digest";
+/// Data of a `notify` event that is not an argument of its message.
+const NOTIFY_FIELDS: &[&str] = &[
+    "id",
+    "key",
+    "title",
+    "message",
+    "severity",
+    "channels",
+    "actions",
+    "image_url",
+];
+/// Seconds between checks for held notifications and due digests.
+const FLUSH_INTERVAL_SECS: u64 = 30;
+
+/**
+ * Severities
+ * How urgent a notification is, from digest material to wake-me-up.
+ */
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Critical,
+}
+
+impl Severity {
+    pub fn parse(value: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(value.to_lowercase())).ok()
+    }
+}
+
+/**
+ * NotificationAction
+ * A button of a notification, e.g. "Turn heating on anyway". Channels
+ * call its callback URL when it is picked.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NotificationAction {
+    pub id: String,
+    pub label: String,
+    /// Set by the notifier when the notification is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+}
+
+/**
+ * Notification
+ * A message for the people in the home. Without explicit channels it goes
+ * wherever the policy routes its severity. Answers to its actions arrive
+ * as `notification_action` events with its id, so rules can wait for them.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Notification {
+    /// Given by the notifier if missing.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Sent as is, unless `key` names a catalog message to send instead.
+    pub title: String,
+    /// Catalog message the title is rendered from in the language of each
+    /// channel's user, e.g. `device.unavailable`, filled in from `args`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub args: HashMap<String, String>,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub actions: Vec<NotificationAction>,
+    /// Picture shown with the notification, e.g. a camera snapshot. Push
+    /// services fetch it, so it has to be reachable from outside.
+    #[serde(default)]
+    pub image_url: Option<String>,
+}
+
+impl Notification {
+    /// Reads a notification from the data of a `notify` event. Channels
+    /// are separated by commas, as are actions, given as `id:label` or
+    /// just a label, which then is the id too. With a `key`, the title may
+    /// be left out and any other data fills in the message, e.g.
+    /// `key=device.unavailable device=Kitchen`.
+    pub fn from_event(data: &HashMap<String, String>) -> Option<Self> {
+        let list = |key: &str| -> Vec<String> {
+            data.get(key)
+                .map(|list| {
+                    list.split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let key = data.get("key").cloned();
+        Some(Notification {
+            id: data.get("id").cloned(),
+            title: data.get("title").or(key.as_ref())?.clone(),
+            args: match key {
+                Some(_) => data
+                    .iter()
+                    .filter(|(name, _)| !NOTIFY_FIELDS.contains(&name.as_str()))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+                None => HashMap::new(),
+            },
+            key,
+            message: data.get("message").cloned().unwrap_or_default(),
+            severity: data
+                .get("severity")
+                .and_then(|severity| Severity::parse(severity))
+                .unwrap_or_default(),
+            channels: list("channels"),
+            actions: list("actions")
+                .into_iter()
+                .map(|action| {
+                    let (id, label) = action.split_once(':').unwrap_or((&action, &action));
+                    NotificationAction {
+                        id: id.trim().to_string(),
+                        label: label.trim().to_string(),
+                        callback_url: None,
+                    }
+                })
+                .collect(),
+            image_url: data.get("image_url").cloned(),
+        })
+    }
+}
+
+/**
+ * QuietHours
+ * A daily period in the policy's time zone, e.g. 22:00 to 07:00, during
+ * which a channel stays silent. Periods ending before they start run over
+ * midnight.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Days the period starts on; every day if empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+impl QuietHours {
+    pub fn contains(&self, now: DateTime<Utc>, timezone: Tz) -> bool {
+        let local = now.with_timezone(&timezone);
+        let time = local.time();
+        let today = local.weekday();
+        let starts_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.start <= self.end {
+            starts_on(today) && self.start <= time && time < self.end
+        } else {
+            (starts_on(today) && time >= self.start) || (starts_on(today.pred()) && time < self.end)
+        }
+    }
+}
+
+/**
+ * ChannelPolicy
+ * Per channel settings.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChannelPolicy {
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHours>,
+}
+
+/**
+ * Route
+ * Sends notifications of at least `min_severity` to `channels`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Route {
+    pub min_severity: Severity,
+    pub channels: Vec<String>,
+}
+
+/**
+ * DigestPolicy
+ * Batches notifications of at most `max_severity` per channel and sends
+ * them together every `interval_secs`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DigestPolicy {
+    #[serde(default)]
+    pub max_severity: Option<Severity>,
+    pub interval_secs: u64,
+}
+
+impl Default for DigestPolicy {
+    fn default() -> Self {
+        DigestPolicy {
+            max_severity: Some(Severity::Low),
+            interval_secs: 3600,
+        }
+    }
+}
+
+fn default_bypass_quiet_hours() -> Severity {
+    Severity::Critical
+}
+
+/**
+ * NotificationPolicy
+ * Where notifications go and when channels may deliver them.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NotificationPolicy {
+    /// Time zone quiet hours are given in.
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    /// Every route a notification's severity reaches adds its channels.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelPolicy>,
+    /// Notifications of at least this severity ignore quiet hours.
+    #[serde(default = "default_bypass_quiet_hours")]
+    pub bypass_quiet_hours: Severity,
+    #[serde(default)]
+    pub digest: DigestPolicy,
+    /// Base URL the services behind channels reach the hub's API on, e.g.
+    /// `https://home.example.org`. Needed for notifications with actions.
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    /// Seconds the actions of a notification can be answered for.
+    #[serde(default = "default_action_timeout_secs")]
+    pub action_timeout_secs: i64,
+}
+
+fn default_timezone() -> Tz {
+    chrono_tz::UTC
+}
+
+fn default_action_timeout_secs() -> i64 {
+    24 * 3600
+}
+
+impl Default for NotificationPolicy {
+    fn default() -> Self {
+        NotificationPolicy {
+            timezone: default_timezone(),
+            routes: Vec::new(),
+            channels: HashMap::new(),
+            bypass_quiet_hours: default_bypass_quiet_hours(),
+            digest: DigestPolicy::default(),
+            callback_url: None,
+            action_timeout_secs: default_action_timeout_secs(),
+        }
+    }
+}
+
+impl NotificationPolicy {
+    /// Channels a notification goes to: its own, else those its severity
+    /// is routed to.
+    pub fn route(&self, notification: &Notification) -> Vec<String> {
+        if !notification.channels.is_empty() {
+            return notification.channels.clone();
+        }
+        let mut channels: Vec<String> = Vec::new();
+        for route in &self.routes {
+            if notification.severity < route.min_severity {
+                continue;
+            }
+            for channel in &route.channels {
+                if !channels.contains(channel) {
+                    channels.push(channel.clone());
+                }
+            }
+        }
+        channels
+    }
+
+    pub fn is_quiet(&self, channel: &str, now: DateTime<Utc>) -> bool {
+        self.channels.get(channel).is_some_and(|policy| {
+            policy
+                .quiet_hours
+                .iter()
+                .any(|quiet| quiet.contains(now, self.timezone))
+        })
+    }
+
+    /// Whether the notification waits for a digest. Questions never do.
+    fn is_digest(&self, notification: &Notification) -> bool {
+        notification.actions.is_empty()
+            && self
+                .digest
+                .max_severity
+                .is_some_and(|max| notification.severity <= max)
+    }
+}
+
+/**
+ * NotificationChannel Interface
+ * Delivers notifications to people, e.g. through a push service. Channels
+ * showing actions call an action's callback URL when it is picked.
+ * Sending may block; the notifier is called from blocking contexts.
+ */
+pub trait NotificationChannel: Send + Sync {
+    fn name(&self) -> String;
+    fn send(&self, notification: &Notification) -> Result<(), BlinkieError>;
+}
+
+/**
+ * EventChannel
+ * Delivers notifications as `notification` events on the bus, for
+ * dashboards and clients following the event stream.
+ */
+pub struct EventChannel {
+    events: Arc<EventBus>,
+}
+
+impl EventChannel {
+    pub fn new(events: Arc<EventBus>) -> Self {
+        EventChannel { events }
+    }
+}
+
+impl NotificationChannel for EventChannel {
+    fn name(&self) -> String {
+        "events".to_string()
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), BlinkieError> {
+        let severity = serde_json::to_value(notification.severity)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let mut data = HashMap::from([
+            (
+                "id".to_string(),
+                notification.id.clone().unwrap_or_default(),
+            ),
+            ("title".to_string(), notification.title.clone()),
+            ("message".to_string(), notification.message.clone()),
+            ("severity".to_string(), severity),
+        ]);
+        if !notification.actions.is_empty() {
+            let actions: Vec<String> = notification
+                .actions
+                .iter()
+                .map(|action| format!("{}:{}", action.id, action.label))
+                .collect();
+            data.insert("actions".to_string(), actions.join(","));
+        }
+        if let Some(url) = &notification.image_url {
+            data.insert("image_url".to_string(), url.clone());
+        }
+        for action in &notification.actions {
+            if let Some(url) = &action.callback_url {
+                data.insert(format!("callback.{}", action.id), url.clone());
+            }
+        }
+        self.events
+            .publish(EventKind::Custom {
+                name: NOTIFICATION_EVENT.to_string(),
+                data,
+            })
+            .map(|_| ())
+    }
+}
+
+/**
+ * Delivery
+ * What happened to a notification on one channel.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Delivery {
+    Sent,
+    /// Held until the channel's quiet hours end.
+    Held,
+    /// Queued for the channel's next digest.
+    Digest,
+    Failed {
+        error: String,
+    },
+}
+
+/**
+ * PendingNotifications
+ * Notifications a channel hasn't delivered yet.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PendingNotifications {
+    /// Held back by quiet hours.
+    pub held: Vec<Notification>,
+    pub digest: Vec<Notification>,
+    /// When the oldest notification of the digest was queued.
+    pub digest_since: Option<DateTime<Utc>>,
+}
+
+/**
+ * Notifier
+ * Routes notifications to channels following the notification policy.
+ * The policy is written back to disk; notifications waiting for quiet
+ * hours to end or for a digest are kept in memory only.
+ */
+pub struct Notifier {
+    path: PathBuf,
+    policy: RwLock<NotificationPolicy>,
+    channels: RwLock<HashMap<String, Arc<dyn NotificationChannel>>>,
+    pending: Mutex<HashMap<String, PendingNotifications>>,
+    webhooks: Option<Arc<WebhookStore>>,
+    users: Option<Arc<UserStore>>,
+    locale: RwLock<LocaleSettings>,
+    catalog: Arc<Catalog>,
+    sent: AtomicU64,
+}
+
+impl Notifier {
+    /// Opens the policy kept at `path`.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let policy = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            NotificationPolicy::default()
+        };
+        Ok(Notifier {
+            path,
+            policy: RwLock::new(policy),
+            channels: RwLock::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            webhooks: None,
+            users: None,
+            locale: RwLock::new(LocaleSettings::default()),
+            catalog: Arc::new(Catalog::default()),
+            sent: AtomicU64::new(0),
+        })
+    }
+
+    /// Lets notifications carry actions, answered through webhooks.
+    pub fn with_webhooks(mut self, webhooks: Arc<WebhookStore>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Renders notifications in the language of the user a channel is
+    /// preferred by.
+    pub fn with_users(mut self, users: Arc<UserStore>) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    /// Sets the language and units of channels no user set them for.
+    pub fn set_locale(&self, locale: LocaleSettings) {
+        *self.locale.write().unwrap() = locale;
+    }
+
+    fn localizer(&self, channel: &str) -> Localizer {
+        let locale = self
+            .users
+            .as_ref()
+            .and_then(|users| users.channel_locale(channel))
+            .unwrap_or_else(|| self.locale.read().unwrap().clone());
+        Localizer::new(locale, self.catalog.clone())
+    }
+
+    pub fn register_channel(&self, channel: Arc<dyn NotificationChannel>) {
+        self.channels
+            .write()
+            .unwrap()
+            .insert(channel.name(), channel);
+    }
+
+    /// Names of the registered channels.
+    pub fn channels(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.channels.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn policy(&self) -> NotificationPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Replaces the policy. Waiting notifications follow the new one from
+    /// the next flush on.
+    pub fn set_policy(&self, policy: NotificationPolicy) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(&policy).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize notification policy: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        *self.policy.write().unwrap() = policy;
+        Ok(())
+    }
+
+    /// Notifications waiting for delivery, by channel.
+    pub fn pending(&self) -> HashMap<String, PendingNotifications> {
+        self.pending.lock().unwrap().clone()
+    }
+
+    /// Sends, holds or batches a notification on each of its channels.
+    /// Fails if it has no channel to go to.
+    pub fn notify(
+        &self,
+        mut notification: Notification,
+        now: DateTime<Utc>,
+    ) -> Result<HashMap<String, Delivery>, BlinkieError> {
+        let policy = self.policy();
+        if notification.id.is_none() {
+            let count = self.sent.fetch_add(1, Ordering::Relaxed);
+            notification.id = Some(format!("{}-{}", now.timestamp_millis(), count));
+        }
+        let channels = policy.route(&notification);
+        if channels.is_empty() {
+            return Err(format!(
+                "No channel to send notification '{}' to",
+                notification.title
+            )
+            .into());
+        }
+        let mut deliveries = HashMap::new();
+        for channel in channels {
+            let delivery = if policy.is_digest(&notification) {
+                let mut pending = self.pending.lock().unwrap();
+                let pending = pending.entry(channel.clone()).or_default();
+                pending.digest_since.get_or_insert(now);
+                pending.digest.push(notification.clone());
+                Delivery::Digest
+            } else if notification.severity < policy.bypass_quiet_hours
+                && policy.is_quiet(&channel, now)
+            {
+                let mut pending = self.pending.lock().unwrap();
+                let pending = pending.entry(channel.clone()).or_default();
+                pending.held.push(notification.clone());
+                Delivery::Held
+            } else {
+                match self.send(&channel, &notification) {
+                    Ok(()) => Delivery::Sent,
+                    Err(error) => Delivery::Failed {
+                        error: error.to_string(),
+                    },
+                }
+            };
+            deliveries.insert(channel, delivery);
+        }
+        Ok(deliveries)
+    }
+
+    fn send(&self, channel: &str, notification: &Notification) -> Result<(), BlinkieError> {
+        let sender = self
+            .channels
+            .read()
+            .unwrap()
+            .get(channel)
+            .cloned()
+            .ok_or_else(|| format!("Unknown notification channel '{}'", channel))?;
+        if notification.actions.is_empty() && notification.key.is_none() {
+            return sender.send(notification);
+        }
+        let mut notification = notification.clone();
+        if let Some(key) = &notification.key {
+            notification.title = self.localizer(channel).translate(key, &args(&notification));
+        }
+        if !notification.actions.is_empty() {
+            self.attach_callbacks(channel, &mut notification)?;
+        }
+        sender.send(&notification)
+    }
+
+    /// Gives every action a webhook publishing its answer. Answering one
+    /// action on any channel removes the webhooks of all of them.
+    fn attach_callbacks(
+        &self,
+        channel: &str,
+        notification: &mut Notification,
+    ) -> Result<(), BlinkieError> {
+        let policy = self.policy();
+        let (Some(webhooks), Some(base)) = (&self.webhooks, &policy.callback_url) else {
+            return Err(
+                "Notification actions need a callback URL in the notification policy"
+                    .to_string()
+                    .into(),
+            );
+        };
+        let notification_id = notification.id.clone().unwrap_or_default();
+        for action in &mut notification.actions {
+            let webhook = Webhook {
+                event: NOTIFICATION_ACTION_EVENT.to_string(),
+                data: HashMap::from([
+                    ("notification_id".to_string(), notification_id.clone()),
+                    ("action".to_string(), action.id.clone()),
+                    ("channel".to_string(), channel.to_string()),
+                ]),
+                expires: None,
+                once_group: Some(format!("notification:{}", notification_id)),
+            };
+            let id = webhooks.create_for(webhook, policy.action_timeout_secs)?;
+            action.callback_url = Some(format!(
+                "{}{}{}",
+                base.trim_end_matches('/'),
+                WEBHOOK_PATH,
+                id
+            ));
+        }
+        Ok(())
+    }
+
+    /// Delivers notifications of channels whose quiet hours ended and
+    /// digests that are due. Returns the number of messages sent.
+    pub fn flush(&self, now: DateTime<Utc>) -> usize {
+        let policy = self.policy();
+        let interval = Duration::seconds(policy.digest.interval_secs as i64);
+        let mut due: Vec<(String, Notification)> = Vec::new();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            for (channel, waiting) in pending.iter_mut() {
+                if policy.is_quiet(channel, now) {
+                    continue;
+                }
+                due.extend(waiting.held.drain(..).map(|n| (channel.clone(), n)));
+                let digest_due = waiting
+                    .digest_since
+                    .is_some_and(|since| now - since >= interval);
+                if digest_due {
+                    let digest = digest(&waiting.digest, &self.localizer(channel));
+                    due.push((channel.clone(), digest));
+                    waiting.digest.clear();
+                    waiting.digest_since = None;
+                }
+            }
+            pending.retain(|_, waiting| !waiting.held.is_empty() || !waiting.digest.is_empty());
+        }
+        let mut sent = 0;
+        for (channel, notification) in due {
+            match self.send(&channel, &notification) {
+                Ok(()) => sent += 1,
+                Err(e) => log::warn!(
+                    "Failed to send notification '{}' to {}: {}",
+                    notification.title,
+                    channel,
+                    e
+                ),
+            }
+        }
+        sent
+    }
+
+    /// Sends notifications rules publish as `notify` events and flushes
+    /// waiting ones, until the bus closes.
+    pub async fn run(self: Arc<Self>, events: Arc<EventBus>) {
+        let mut receiver = events.subscribe();
+        let mut flush = tokio::time::interval(std::time::Duration::from_secs(FLUSH_INTERVAL_SECS));
+        loop {
+            tokio::select! {
+                received = receiver.recv() => match received {
+                    Ok(event) => {
+                        let EventKind::Custom { name, data } = &event.kind else {
+                            continue;
+                        };
+                        if name != NOTIFY_EVENT {
+                            continue;
+                        }
+                        let Some(notification) = Notification::from_event(data) else {
+                            log::warn!("Ignoring notify event {} without a title", event.seq);
+                            continue;
+                        };
+                        let notifier = self.clone();
+                        tokio::task::spawn_blocking(move || {
+                            if let Err(e) = notifier.notify(notification, Utc::now()) {
+                                log::warn!("{}", e);
+                            }
+                        });
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = flush.tick() => {
+                    let notifier = self.clone();
+                    tokio::task::spawn_blocking(move || notifier.flush(Utc::now()));
+                }
+            }
+        }
+    }
+}
+
+/// Arguments of a notification's catalog message.
+fn args(notification: &Notification) -> HashMap<&str, String> {
+    notification
+        .args
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.clone()))
+        .collect()
+}
+
+/// Combines notifications into a single digest message, in the language of
+/// `localizer`.
+fn digest(notifications: &[Notification], localizer: &Localizer) -> Notification {
+    let message = notifications
+        .iter()
+        .map(|notification| {
+            let title = match &notification.key {
+                Some(key) => localizer.translate(key, &args(notification)),
+                None => notification.title.clone(),
+            };
+            match notification.message.as_str() {
+                "" => format!("- {}", title),
+                message => format!("- {}: {}", title, message),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let args = HashMap::from([("count", notifications.len().to_string())]);
+    Notification {
+        id: None,
+        title: localizer.translate(DIGEST_KEY, &args),
+        key: None,
+        args: HashMap::new(),
+        message,
+        severity: Severity::Low,
+        channels: Vec::new(),
+        actions: Vec::new(),
+        image_url: None,
+    }
+}
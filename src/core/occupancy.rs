@@ -0,0 +1,608 @@
+use super::device::{Device, DeviceList, Type};
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::retention::PurgeTarget;
+use super::value::{StateMap, StateValue};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Prefix of the ids of occupancy sensors, followed by the area.
+pub const SENSOR_PREFIX: &str = "occupancy.";
+/// Seconds between updates of the sensors while evidence decays.
+const TICK_SECS: u64 = 1;
+/// Steps probabilities are reported in, so decaying evidence doesn't
+/// publish a change every tick.
+const PROBABILITY_STEP: f64 = 0.05;
+
+fn default_state_key() -> String {
+    "state".to_string()
+}
+
+fn default_power_key() -> String {
+    "power".to_string()
+}
+
+fn default_power_above() -> f64 {
+    5.0
+}
+
+fn default_threshold() -> f64 {
+    0.5
+}
+
+/**
+ * Source
+ * What a piece of occupancy evidence follows.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    /// Motion sensor, evidence while its key is on.
+    Motion {
+        device_id: String,
+        #[serde(default = "default_state_key")]
+        key: String,
+    },
+    /// Door or window contact, evidence whenever it opens or closes.
+    Contact {
+        device_id: String,
+        #[serde(default = "default_state_key")]
+        key: String,
+    },
+    /// Power meter, evidence while something draws more than `above` watts,
+    /// e.g. a TV or a desk.
+    Power {
+        device_id: String,
+        #[serde(default = "default_power_key")]
+        key: String,
+        #[serde(default = "default_power_above")]
+        above: f64,
+    },
+    /// Person, evidence while they are home.
+    Presence { person_id: String },
+}
+
+impl Source {
+    /// Key of the signal the source reads, shared by areas following the
+    /// same sensor.
+    fn signal(&self) -> String {
+        match self {
+            Source::Motion { device_id, key }
+            | Source::Contact { device_id, key }
+            | Source::Power { device_id, key, .. } => format!("{}:{}", device_id, key),
+            Source::Presence { person_id } => format!("person:{}", person_id),
+        }
+    }
+
+    /// How much the source alone says about the area being occupied.
+    fn default_weight(&self) -> f64 {
+        match self {
+            Source::Motion { .. } => 0.9,
+            Source::Contact { .. } => 0.6,
+            Source::Power { .. } => 0.7,
+            Source::Presence { .. } => 0.5,
+        }
+    }
+
+    /// Seconds evidence takes to decay once the source goes quiet.
+    fn default_decay_secs(&self) -> u64 {
+        match self {
+            Source::Motion { .. } => 300,
+            Source::Contact { .. } => 180,
+            Source::Power { .. } => 120,
+            Source::Presence { .. } => 0,
+        }
+    }
+
+    /// What `event` says about the source, if it is about it. Contacts are
+    /// never active, each change is a moment of evidence.
+    fn observe(&self, event: &Event) -> Option<Observation> {
+        match (self, &event.kind) {
+            (
+                Source::Motion { device_id, key },
+                EventKind::StateChanged {
+                    device_id: changed,
+                    key: changed_key,
+                    new_value,
+                    ..
+                },
+            ) if device_id == changed && key == changed_key => StateValue::parse(new_value)
+                .as_bool()
+                .map(Observation::from),
+            (
+                Source::Contact { device_id, key },
+                EventKind::StateChanged {
+                    device_id: changed,
+                    key: changed_key,
+                    ..
+                },
+            ) if device_id == changed && key == changed_key => Some(Observation::Pulse),
+            (
+                Source::Power {
+                    device_id,
+                    key,
+                    above,
+                },
+                EventKind::StateChanged {
+                    device_id: changed,
+                    key: changed_key,
+                    new_value,
+                    ..
+                },
+            ) if device_id == changed && key == changed_key => StateValue::parse(new_value)
+                .as_f64()
+                .map(|watts| Observation::from(watts > *above)),
+            (
+                Source::Presence { person_id },
+                EventKind::PresenceChanged {
+                    person_id: changed,
+                    state,
+                },
+            ) if person_id == changed => Some(Observation::from(state == "home")),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Observation
+ * What an event says about a source.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Observation {
+    Active,
+    Inactive,
+    /// A moment of activity, e.g. a door moving.
+    Pulse,
+}
+
+impl From<bool> for Observation {
+    fn from(active: bool) -> Self {
+        if active {
+            Observation::Active
+        } else {
+            Observation::Inactive
+        }
+    }
+}
+
+/**
+ * Evidence
+ * A source of an area with how much it weighs and how long it counts
+ * after going quiet; the source type's defaults if unset.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Evidence {
+    #[serde(flatten)]
+    pub source: Source,
+    /// Probability of the area being occupied while the source is active,
+    /// between 0 and 1.
+    #[serde(default)]
+    pub weight: Option<f64>,
+    /// Seconds the evidence decays over, linearly, after the source went
+    /// quiet.
+    #[serde(default)]
+    pub decay_secs: Option<u64>,
+}
+
+impl Evidence {
+    /// Probability the evidence gives at `now`, from its signal.
+    fn probability(&self, signal: Option<&Signal>, now: DateTime<Utc>) -> f64 {
+        let weight = self
+            .weight
+            .unwrap_or_else(|| self.source.default_weight())
+            .clamp(0.0, 1.0);
+        let Some(signal) = signal else {
+            return 0.0;
+        };
+        if signal.active {
+            return weight;
+        }
+        let decay = self
+            .decay_secs
+            .unwrap_or_else(|| self.source.default_decay_secs()) as f64;
+        let elapsed = (now - signal.changed).num_milliseconds().max(0) as f64 / 1000.0;
+        if elapsed >= decay {
+            return 0.0;
+        }
+        weight * (1.0 - elapsed / decay)
+    }
+}
+
+/**
+ * Area
+ * A room or zone whose occupancy is inferred from its evidence.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Area {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub evidence: Vec<Evidence>,
+    /// Probability from which the area counts as occupied.
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+}
+
+/**
+ * OccupancyConfig
+ * Areas whose occupancy is inferred, by id.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OccupancyConfig {
+    #[serde(default)]
+    pub areas: HashMap<String, Area>,
+}
+
+/**
+ * Signal
+ * Latest reading of a source: whether it is active and since when, or for
+ * contacts, when they last moved.
+ */
+#[derive(Clone, Debug)]
+struct Signal {
+    active: bool,
+    changed: DateTime<Utc>,
+}
+
+/**
+ * EvidenceStatus
+ * What a piece of evidence currently adds to its area.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EvidenceStatus {
+    pub source: Source,
+    pub probability: f64,
+    #[serde(default)]
+    pub active: bool,
+    #[serde(default)]
+    pub changed: Option<DateTime<Utc>>,
+}
+
+/**
+ * AreaOccupancy
+ * Inferred occupancy of an area: the probability from its fused evidence
+ * and whether that counts as occupied.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AreaOccupancy {
+    pub sensor_id: String,
+    pub probability: f64,
+    pub occupied: bool,
+    /// When the area last became occupied or vacant, while the hub ran.
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    pub evidence: Vec<EvidenceStatus>,
+}
+
+/**
+ * Tracker
+ * Signals of all sources and the occupancy last reported per area, shared
+ * by the engine and its sensors.
+ */
+#[derive(Default)]
+struct Tracker {
+    config: OccupancyConfig,
+    signals: HashMap<String, Signal>,
+    /// Probability and occupancy last published, with when the occupancy
+    /// last changed, by area.
+    reported: HashMap<String, (f64, bool, Option<DateTime<Utc>>)>,
+}
+
+impl Tracker {
+    /// Fused occupancy of `area` at `now`: the evidence combined as
+    /// independent causes, so any one source can make the area occupied and
+    /// every further one makes it likelier.
+    fn occupancy(&self, id: &str, area: &Area, now: DateTime<Utc>) -> AreaOccupancy {
+        let evidence: Vec<EvidenceStatus> = area
+            .evidence
+            .iter()
+            .map(|evidence| {
+                let signal = self.signals.get(&evidence.source.signal());
+                EvidenceStatus {
+                    source: evidence.source.clone(),
+                    probability: evidence.probability(signal, now),
+                    active: signal.is_some_and(|signal| signal.active),
+                    changed: signal.map(|signal| signal.changed),
+                }
+            })
+            .collect();
+        let vacant: f64 = evidence.iter().map(|e| 1.0 - e.probability).product();
+        let probability = quantize(1.0 - vacant);
+        AreaOccupancy {
+            sensor_id: sensor_id(id),
+            probability,
+            occupied: probability >= area.threshold,
+            since: self.reported.get(id).and_then(|(_, _, since)| *since),
+            evidence,
+        }
+    }
+}
+
+/// Rounds a probability to the steps it is reported in.
+fn quantize(probability: f64) -> f64 {
+    ((probability / PROBABILITY_STEP).round() * PROBABILITY_STEP * 100.0).round() / 100.0
+}
+
+/// Id of the sensor of the area `id`.
+pub fn sensor_id(id: &str) -> String {
+    format!("{}{}", SENSOR_PREFIX, id)
+}
+
+/**
+ * OccupancySensor
+ * Binary sensor of an area, "on" while it is occupied, with the inferred
+ * probability next to it, for lighting automations to follow.
+ */
+pub struct OccupancySensor {
+    id: String,
+    name: String,
+    area: String,
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl Device for OccupancySensor {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        let tracker = self.tracker.lock().unwrap();
+        let mut state = HashMap::new();
+        if let Some(area) = tracker.config.areas.get(&self.area) {
+            let occupancy = tracker.occupancy(&self.area, area, Utc::now());
+            let value = if occupancy.occupied { "on" } else { "off" };
+            state.insert("state".to_string(), StateValue::from(value));
+            state.insert(
+                "probability".to_string(),
+                StateValue::Float(occupancy.probability),
+            );
+        }
+        state
+    }
+
+    // The state is inferred from the evidence and can't be set from outside
+    fn set_state(&mut self, _state: StateMap) {}
+
+    fn send_cmd(&mut self, _command: &str, _parameters: Option<HashMap<String, String>>) {}
+
+    fn get_type(&self) -> Option<Type> {
+        Some(Type::Sensor)
+    }
+}
+
+/**
+ * Occupancy
+ * Infers per-area occupancy from motion, door contacts, power draw and
+ * presence, with evidence decaying once its source goes quiet. Every area
+ * gets a sensor in the device list, `occupancy.<area>`, whose changes are
+ * published like those of any other device.
+ */
+pub struct Occupancy {
+    path: PathBuf,
+    tracker: Arc<Mutex<Tracker>>,
+    devices: DeviceList,
+    events: Arc<EventBus>,
+}
+
+impl Occupancy {
+    /// Opens the areas configured at `path` and adds their sensors to
+    /// `devices`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        devices: DeviceList,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            OccupancyConfig::default()
+        };
+        let occupancy = Occupancy {
+            path,
+            tracker: Arc::new(Mutex::new(Tracker::default())),
+            devices,
+            events,
+        };
+        occupancy.apply(config);
+        Ok(occupancy)
+    }
+
+    pub fn config(&self) -> OccupancyConfig {
+        self.tracker.lock().unwrap().config.clone()
+    }
+
+    pub fn set_config(&self, config: OccupancyConfig) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize occupancy areas: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        self.apply(config);
+        Ok(())
+    }
+
+    /// Switches to `config`, replacing the sensors of the previous areas.
+    fn apply(&self, config: OccupancyConfig) {
+        let sensors: Vec<Box<dyn Device>> = config
+            .areas
+            .iter()
+            .map(|(id, area)| {
+                Box::new(OccupancySensor {
+                    id: sensor_id(id),
+                    name: area.name.clone().unwrap_or_else(|| id.clone()),
+                    area: id.clone(),
+                    tracker: self.tracker.clone(),
+                }) as Box<dyn Device>
+            })
+            .collect();
+        let mut devices = self.devices.write().unwrap();
+        let mut tracker = self.tracker.lock().unwrap();
+        devices.retain(|device| {
+            let id = device.get_id();
+            !id.strip_prefix(SENSOR_PREFIX)
+                .is_some_and(|area| tracker.config.areas.contains_key(area))
+        });
+        devices.extend(sensors);
+        tracker
+            .reported
+            .retain(|id, _| config.areas.contains_key(id));
+        tracker.config = config;
+    }
+
+    /// Occupancy of every area at `now`.
+    pub fn status(&self, now: DateTime<Utc>) -> HashMap<String, AreaOccupancy> {
+        let tracker = self.tracker.lock().unwrap();
+        tracker
+            .config
+            .areas
+            .iter()
+            .map(|(id, area)| (id.clone(), tracker.occupancy(id, area, now)))
+            .collect()
+    }
+
+    /// Updates the signals of the sources `event` is about.
+    pub fn record(&self, event: &Event) {
+        let mut tracker = self.tracker.lock().unwrap();
+        let observed: Vec<(String, Observation)> = tracker
+            .config
+            .areas
+            .values()
+            .flat_map(|area| &area.evidence)
+            .filter_map(|evidence| {
+                let observation = evidence.source.observe(event)?;
+                Some((evidence.source.signal(), observation))
+            })
+            .collect();
+        for (signal, observation) in observed {
+            let active = tracker.signals.get(&signal).map(|known| known.active);
+            // Readings that don't change whether a source is active, e.g.
+            // power draw moving below the limit, keep its evidence decaying
+            let changed = match observation {
+                Observation::Active => active != Some(true),
+                Observation::Inactive => active == Some(true),
+                Observation::Pulse => true,
+            };
+            if changed {
+                tracker.signals.insert(
+                    signal,
+                    Signal {
+                        active: observation == Observation::Active,
+                        changed: event.timestamp,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Drops what the sources about `target` last said: the signals of a
+    /// device's keys or of a person's presence.
+    pub fn forget(&self, target: &PurgeTarget) {
+        let mut tracker = self.tracker.lock().unwrap();
+        match target {
+            PurgeTarget::Device(device_id) => {
+                let prefix = format!("{}:", device_id);
+                tracker
+                    .signals
+                    .retain(|signal, _| !signal.starts_with(&prefix));
+            }
+            PurgeTarget::Person(person_id) => {
+                let signal = Source::Presence {
+                    person_id: person_id.clone(),
+                }
+                .signal();
+                tracker.signals.remove(&signal);
+            }
+        }
+    }
+
+    /// Publishes the state of sensors whose occupancy or probability
+    /// changed since they were last reported.
+    pub fn update(&self, now: DateTime<Utc>) {
+        let mut changes = Vec::new();
+        {
+            let mut tracker = self.tracker.lock().unwrap();
+            let current: Vec<(String, AreaOccupancy)> = tracker
+                .config
+                .areas
+                .iter()
+                .map(|(id, area)| (id.clone(), tracker.occupancy(id, area, now)))
+                .collect();
+            for (id, occupancy) in current {
+                let previous = tracker.reported.get(&id).copied();
+                let since = match previous {
+                    Some((_, occupied, since)) if occupied == occupancy.occupied => since,
+                    _ => Some(now),
+                };
+                if let Some((probability, occupied, _)) = previous {
+                    if occupied != occupancy.occupied {
+                        changes.push((
+                            occupancy.sensor_id.clone(),
+                            "state",
+                            Some(if occupied { "on" } else { "off" }.to_string()),
+                            if occupancy.occupied { "on" } else { "off" }.to_string(),
+                        ));
+                    }
+                    if probability != occupancy.probability {
+                        changes.push((
+                            occupancy.sensor_id.clone(),
+                            "probability",
+                            Some(probability.to_string()),
+                            occupancy.probability.to_string(),
+                        ));
+                    }
+                }
+                tracker
+                    .reported
+                    .insert(id, (occupancy.probability, occupancy.occupied, since));
+            }
+        }
+        for (device_id, key, old_value, new_value) in changes {
+            let published = self.events.publish(EventKind::StateChanged {
+                device_id,
+                key: key.to_string(),
+                old_value,
+                new_value,
+                reported_at: None,
+            });
+            if let Err(e) = published {
+                log::warn!("Failed to publish occupancy: {}", e);
+            }
+        }
+    }
+
+    /// Follows the evidence on the bus and updates the sensors as it
+    /// arrives and decays, until the bus closes.
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = self.events.subscribe();
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(TICK_SECS));
+        loop {
+            tokio::select! {
+                received = receiver.recv() => match received {
+                    Ok(event) => {
+                        self.record(&event);
+                        self.update(Utc::now());
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tick.tick() => self.update(Utc::now()),
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use super::error::BlinkieError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Farthest ahead a frame may be scheduled, so a wrong clock doesn't park
+/// frames for hours.
+const MAX_AHEAD: Duration = Duration::from_secs(10);
+
+fn default_smoothing() -> f64 {
+    0.125
+}
+
+fn default_jitter_margin() -> f64 {
+    2.0
+}
+
+/**
+ * FramePacingConfig
+ * How the display delay of every output is estimated from its frame
+ * writes: a moving average and the mean deviation of the write latency,
+ * each new write weighing in with `smoothing`. Frames are handed to an
+ * output that much early, plus `jitter_margin` times the deviation and a
+ * fixed `offsets_ms` for outputs buffering frames themselves, e.g. WLED.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FramePacingConfig {
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    #[serde(default = "default_jitter_margin")]
+    pub jitter_margin: f64,
+    #[serde(default)]
+    pub offsets_ms: BTreeMap<String, f64>,
+}
+
+impl FramePacingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.smoothing > 0.0 && self.smoothing <= 1.0) {
+            return Err("smoothing must be above 0 and at most 1".to_string());
+        }
+        if !(self.jitter_margin >= 0.0 && self.jitter_margin.is_finite()) {
+            return Err("jitter_margin must not be negative".to_string());
+        }
+        if let Some((device_id, _)) = self
+            .offsets_ms
+            .iter()
+            .find(|(_, offset)| !(**offset >= 0.0 && offset.is_finite()))
+        {
+            return Err(format!("offset of '{}' must not be negative", device_id));
+        }
+        Ok(())
+    }
+
+    fn offset_ms(&self, device_id: &str) -> f64 {
+        self.offsets_ms.get(device_id).copied().unwrap_or_default()
+    }
+}
+
+impl Default for FramePacingConfig {
+    fn default() -> Self {
+        FramePacingConfig {
+            smoothing: default_smoothing(),
+            jitter_margin: default_jitter_margin(),
+            offsets_ms: BTreeMap::new(),
+        }
+    }
+}
+
+/**
+ * OutputTiming
+ * Frames written to an output, how many were scheduled too late to show
+ * on time, its smoothed write latency and jitter, and how early frames
+ * are handed to it.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OutputTiming {
+    pub frames: u64,
+    pub late: u64,
+    pub last_ms: f64,
+    pub latency_ms: f64,
+    pub jitter_ms: f64,
+    pub offset_ms: f64,
+    pub lead_ms: f64,
+}
+
+/**
+ * FramePacer
+ * Measures how long frame writes to each output take and schedules frames
+ * meant to show at a given time, so outputs with differing delays show
+ * them together.
+ */
+#[derive(Default)]
+pub struct FramePacer {
+    config: Mutex<FramePacingConfig>,
+    outputs: Mutex<BTreeMap<String, OutputTiming>>,
+}
+
+impl FramePacer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config(&self) -> FramePacingConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: FramePacingConfig) -> Result<(), BlinkieError> {
+        config.validate()?;
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+
+    /// Records a frame write to `device_id` that took `took`.
+    pub fn record(&self, device_id: &str, took: Duration) {
+        let smoothing = self.config.lock().unwrap().smoothing;
+        let ms = took.as_secs_f64() * 1000.0;
+        let mut outputs = self.outputs.lock().unwrap();
+        let timing = outputs.entry(device_id.to_string()).or_default();
+        if timing.frames == 0 {
+            timing.latency_ms = ms;
+        } else {
+            let deviation = (ms - timing.latency_ms).abs();
+            timing.latency_ms += smoothing * (ms - timing.latency_ms);
+            timing.jitter_ms += smoothing * (deviation - timing.jitter_ms);
+        }
+        timing.last_ms = ms;
+        timing.frames += 1;
+    }
+
+    fn lead_ms(config: &FramePacingConfig, device_id: &str, timing: &OutputTiming) -> f64 {
+        timing.latency_ms + config.jitter_margin * timing.jitter_ms + config.offset_ms(device_id)
+    }
+
+    /// How long to hold a frame to `device_id` meant to show at `at`, none
+    /// if it is late already.
+    pub fn delay(&self, device_id: &str, at: DateTime<Utc>) -> Result<Duration, BlinkieError> {
+        let config = self.config.lock().unwrap().clone();
+        let mut outputs = self.outputs.lock().unwrap();
+        let timing = outputs.entry(device_id.to_string()).or_default();
+        let ahead = (at - Utc::now()).to_std().ok();
+        if ahead.is_some_and(|ahead| ahead > MAX_AHEAD) {
+            return Err(format!(
+                "Frame for '{}' is due {}, over {}s ahead",
+                device_id,
+                at.to_rfc3339(),
+                MAX_AHEAD.as_secs()
+            )
+            .into());
+        }
+        let lead = Duration::from_secs_f64(Self::lead_ms(&config, device_id, timing) / 1000.0);
+        match ahead.and_then(|ahead| ahead.checked_sub(lead)) {
+            Some(delay) => Ok(delay),
+            None => {
+                timing.late += 1;
+                Ok(Duration::ZERO)
+            }
+        }
+    }
+
+    pub fn forget(&self, device_id: &str) {
+        self.outputs.lock().unwrap().remove(device_id);
+    }
+
+    pub fn status(&self) -> BTreeMap<String, OutputTiming> {
+        let config = self.config.lock().unwrap().clone();
+        let mut outputs = self.outputs.lock().unwrap().clone();
+        for (device_id, timing) in &mut outputs {
+            timing.offset_ms = config.offset_ms(device_id);
+            timing.lead_ms = Self::lead_ms(&config, device_id, timing);
+        }
+        outputs
+    }
+}
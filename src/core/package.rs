@@ -0,0 +1,324 @@
+use super::device::{Config, DeviceFactory, DeviceList};
+use super::error::BlinkieError;
+use crate::automation::engine::RuleEngine;
+use crate::automation::rule::{Renames, Rule};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+fn default_enabled() -> bool {
+    true
+}
+
+/**
+ * Package
+ * A self-contained bundle of devices, helpers, rules and dashboard metadata
+ * that is enabled, disabled and shared as a unit. Ids inside a package are
+ * local to it; they are prefixed with the package id when installed.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub devices: Vec<Config>,
+    /// Global variables the package needs, with their initial values.
+    #[serde(default)]
+    pub helpers: HashMap<String, String>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Opaque to blinkie; stored and handed out for UIs to render.
+    #[serde(default)]
+    pub dashboards: Vec<serde_json::Value>,
+}
+
+impl Package {
+    /// Reads a package from a JSON file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read package {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents).map_err(|e| {
+            BlinkieError::serialization(format!(
+                "Failed to parse package {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Writes the package to a JSON file, e.g. to share it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), BlinkieError> {
+        let path = path.as_ref();
+        let contents = serde_json::to_string_pretty(self).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize package '{}': {}", self.id, e))
+        })?;
+        fs::write(path, contents)
+            .map_err(|e| format!("Failed to write package {}: {}", path.display(), e).into())
+    }
+
+    /// Checks the package for mistakes that would break installation.
+    pub fn validate(&self) -> Result<(), BlinkieError> {
+        if self.id.is_empty() || self.id.contains('.') {
+            return Err(format!(
+                "Invalid package id '{}': must be non-empty and contain no '.'",
+                self.id
+            )
+            .into());
+        }
+        let mut seen = Vec::new();
+        for id in self.devices.iter().map(|d| &d.id) {
+            if seen.contains(&id) {
+                return Err(
+                    format!("Duplicate device id '{}' in package '{}'", id, self.id).into(),
+                );
+            }
+            seen.push(id);
+        }
+        let mut seen = Vec::new();
+        for id in self.rules.iter().map(|r| &r.id) {
+            if seen.contains(&id) {
+                return Err(format!("Duplicate rule id '{}' in package '{}'", id, self.id).into());
+            }
+            seen.push(id);
+        }
+        Ok(())
+    }
+
+    /// Returns a copy with all package-local ids prefixed with `<package id>.`
+    /// and every internal reference updated to match. References to devices
+    /// outside the package are left alone.
+    pub fn namespaced(&self) -> Package {
+        let prefix = |id: &str| format!("{}.{}", self.id, id);
+        let renames = Renames {
+            devices: self
+                .devices
+                .iter()
+                .map(|d| (d.id.clone(), prefix(&d.id)))
+                .collect(),
+            globals: self
+                .helpers
+                .keys()
+                .map(|name| (name.clone(), prefix(name)))
+                .collect(),
+        };
+
+        let mut package = self.clone();
+        for device in &mut package.devices {
+            device.id = prefix(&device.id);
+        }
+        package.helpers = self
+            .helpers
+            .iter()
+            .map(|(name, value)| (prefix(name), value.clone()))
+            .collect();
+        for rule in &mut package.rules {
+            rule.id = prefix(&rule.id);
+            rule.apply_renames(&renames);
+        }
+        package
+    }
+}
+
+/**
+ * PackageInfo
+ * Summary of an installed package.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PackageInfo {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    pub enabled: bool,
+    pub devices: Vec<String>,
+    pub rules: Vec<String>,
+}
+
+/**
+ * PackageManager
+ * Installs packages and switches their devices and rules on and off together.
+ */
+pub struct PackageManager {
+    factory: Arc<DeviceFactory>,
+    devices: DeviceList,
+    rules: Arc<RuleEngine>,
+    packages: RwLock<BTreeMap<String, Package>>,
+}
+
+impl PackageManager {
+    pub fn new(factory: Arc<DeviceFactory>, devices: DeviceList, rules: Arc<RuleEngine>) -> Self {
+        PackageManager {
+            factory,
+            devices,
+            rules,
+            packages: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Installs a package and enables it if it is marked as enabled.
+    pub fn install(&self, package: Package) -> Result<(), BlinkieError> {
+        package.validate()?;
+        if self.packages.read().unwrap().contains_key(&package.id) {
+            return Err(format!("Package '{}' is already installed", package.id).into());
+        }
+
+        let mut package = package.namespaced();
+        let enable = package.enabled;
+        package.enabled = false;
+        let id = package.id.clone();
+        self.packages.write().unwrap().insert(id.clone(), package);
+
+        if enable {
+            if let Err(e) = self.enable(&id) {
+                self.packages.write().unwrap().remove(&id);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Installs every `*.json` package in a directory. Returns the ids of the
+    /// installed packages, or the errors of those that failed.
+    pub fn install_dir<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<String>, Vec<String>> {
+        let dir = dir.as_ref();
+        let entries = fs::read_dir(dir)
+            .map_err(|e| vec![format!("Failed to read {}: {}", dir.display(), e)])?;
+
+        let mut installed = Vec::new();
+        let mut errors = Vec::new();
+        for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+            if path.extension().is_some_and(|ext| ext == "json") {
+                match Package::load(&path).and_then(|p| {
+                    let id = p.id.clone();
+                    self.install(p).map(|_| id)
+                }) {
+                    Ok(id) => installed.push(id),
+                    Err(e) => errors.push(e.to_string()),
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(installed)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Creates the package's devices, sets up its helpers and adds its rules.
+    /// Nothing is left behind if any part fails.
+    pub fn enable(&self, id: &str) -> Result<(), BlinkieError> {
+        let package = self.get(id)?;
+        if package.enabled {
+            return Ok(());
+        }
+
+        {
+            let devices = self.devices.read().unwrap();
+            if let Some(clash) = package
+                .devices
+                .iter()
+                .find(|config| devices.iter().any(|d| d.get_id() == config.id))
+            {
+                return Err(format!("Device id '{}' is already in use", clash.id).into());
+            }
+        }
+
+        let mut created = Vec::new();
+        for config in &package.devices {
+            match self.factory.register(config) {
+                Ok(device) => created.push(device),
+                Err(e) => return Err(format!("Package '{}': {}", id, e).into()),
+            }
+        }
+        self.devices.write().unwrap().extend(created);
+
+        let variables = self.rules.variables();
+        for (name, value) in &package.helpers {
+            if variables.global(name).is_none() {
+                variables.set_global(name, value)?;
+            }
+        }
+
+        for (index, rule) in package.rules.iter().enumerate() {
+            if let Err(e) = self.rules.add_rule(rule.clone()) {
+                for added in &package.rules[..index] {
+                    self.rules.remove_rule(&added.id);
+                }
+                self.remove_devices(&package);
+                return Err(format!("Package '{}': {}", id, e).into());
+            }
+        }
+
+        self.set_enabled(id, true);
+        Ok(())
+    }
+
+    /// Removes the package's rules and devices. Helper values are kept so
+    /// they are still there when the package is enabled again.
+    pub fn disable(&self, id: &str) -> Result<(), BlinkieError> {
+        let package = self.get(id)?;
+        if !package.enabled {
+            return Ok(());
+        }
+        for rule in &package.rules {
+            self.rules.remove_rule(&rule.id);
+        }
+        self.remove_devices(&package);
+        self.set_enabled(id, false);
+        Ok(())
+    }
+
+    /// Disables and forgets a package.
+    pub fn uninstall(&self, id: &str) -> Result<Package, BlinkieError> {
+        self.disable(id)?;
+        self.packages
+            .write()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| format!("Package '{}' is not installed", id).into())
+    }
+
+    /// Returns an installed package, with namespaced ids.
+    pub fn get(&self, id: &str) -> Result<Package, BlinkieError> {
+        self.packages
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("Package '{}' is not installed", id).into())
+    }
+
+    pub fn packages(&self) -> Vec<PackageInfo> {
+        let packages = self.packages.read().unwrap();
+        packages
+            .values()
+            .map(|package| PackageInfo {
+                id: package.id.clone(),
+                name: package.name.clone(),
+                version: package.version.clone(),
+                enabled: package.enabled,
+                devices: package.devices.iter().map(|d| d.id.clone()).collect(),
+                rules: package.rules.iter().map(|r| r.id.clone()).collect(),
+            })
+            .collect()
+    }
+
+    fn remove_devices(&self, package: &Package) {
+        let mut devices = self.devices.write().unwrap();
+        devices.retain(|device| !package.devices.iter().any(|c| c.id == device.get_id()));
+    }
+
+    fn set_enabled(&self, id: &str, enabled: bool) {
+        if let Some(package) = self.packages.write().unwrap().get_mut(id) {
+            package.enabled = enabled;
+        }
+    }
+}
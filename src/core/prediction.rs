@@ -0,0 +1,591 @@
+use super::device::{Device, DeviceList, Type};
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::value::{StateMap, StateValue};
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use chrono_tz::Tz;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Prefix of the ids of prediction sensors, followed by the model.
+pub const SENSOR_PREFIX: &str = "prediction.";
+/// Seconds between checks for models due to predict.
+const TICK_SECS: u64 = 10;
+
+fn default_key() -> String {
+    "state".to_string()
+}
+
+fn default_window_secs() -> u64 {
+    3600
+}
+
+fn default_step_secs() -> u64 {
+    300
+}
+
+fn default_interval_secs() -> u64 {
+    300
+}
+
+fn default_time_features() -> bool {
+    true
+}
+
+fn default_timezone() -> Tz {
+    chrono_tz::UTC
+}
+
+/**
+ * Feature
+ * A state value sampled into feature windows, as a number: numeric values
+ * as they are, on/off as 1/0.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Feature {
+    pub device_id: String,
+    #[serde(default = "default_key")]
+    pub key: String,
+}
+
+impl Feature {
+    /// Name of the feature's column, `<device>.<key>`.
+    pub fn column(&self) -> String {
+        format!("{}.{}", self.device_id, self.key)
+    }
+}
+
+/**
+ * Backend
+ * What turns a model's feature window into predictions.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Backend {
+    /// POSTs the window as JSON to a prediction service, which answers
+    /// with the outputs.
+    Http {
+        url: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Runs a command with the window as JSON on stdin, e.g. a script
+    /// scoring an ONNX model with onnxruntime, printing the outputs as JSON.
+    Command { command: Vec<String> },
+    /// A predictor registered by code embedding the hub, by name.
+    Registered { name: String },
+    /// An outside service pulls windows and posts predictions back through
+    /// the API on its own schedule.
+    External,
+}
+
+/**
+ * Model
+ * A prediction: the features it looks at, how much history it sees and
+ * how often it runs.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Model {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub features: Vec<Feature>,
+    /// History the model sees, in seconds before the prediction.
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Seconds between the rows of the window.
+    #[serde(default = "default_step_secs")]
+    pub step_secs: u64,
+    /// Seconds between predictions.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Whether windows get `hour` and `weekday` columns, in the
+    /// configured time zone.
+    #[serde(default = "default_time_features")]
+    pub time_features: bool,
+    pub backend: Backend,
+}
+
+/**
+ * PredictionConfig
+ * Models by id, and the time zone of their time features.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PredictionConfig {
+    #[serde(default)]
+    pub models: HashMap<String, Model>,
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+}
+
+impl Default for PredictionConfig {
+    fn default() -> Self {
+        PredictionConfig {
+            models: HashMap::new(),
+            timezone: default_timezone(),
+        }
+    }
+}
+
+/**
+ * FeatureWindow
+ * History handed to a model: one row per step up to the prediction, with
+ * the value every feature had then. Values are held from their last
+ * change, looking back at most one more window; features without a value
+ * yet are null.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeatureWindow {
+    pub model: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub step_secs: u64,
+    pub columns: Vec<String>,
+    pub timestamps: Vec<DateTime<Utc>>,
+    pub rows: Vec<Vec<Option<f64>>>,
+}
+
+/**
+ * Prediction
+ * Outputs of a model's latest run, e.g. `preheat_minutes`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Prediction {
+    pub outputs: HashMap<String, f64>,
+    pub predicted_at: DateTime<Utc>,
+}
+
+/**
+ * Predictor Interface
+ * Predicts in-process, e.g. with an ONNX session, for models with a
+ * `registered` backend.
+ */
+pub trait Predictor: Send + Sync {
+    fn predict(&self, window: &FeatureWindow) -> Result<HashMap<String, f64>, BlinkieError>;
+}
+
+/// A state value as a feature: numbers as they are, on/off as 1/0.
+fn numeric(value: &str) -> Option<f64> {
+    let value = StateValue::parse(value);
+    value
+        .as_f64()
+        .or_else(|| value.as_bool().map(|on| if on { 1.0 } else { 0.0 }))
+}
+
+/// Outputs in the JSON a backend answered with.
+fn parse_outputs(body: &[u8], backend: &str) -> Result<HashMap<String, f64>, BlinkieError> {
+    serde_json::from_slice(body).map_err(|e| {
+        BlinkieError::serialization(format!("Failed to parse predictions of {}: {}", backend, e))
+    })
+}
+
+impl Backend {
+    /// Runs the window through the backend; blocks until it answered.
+    fn predict(
+        &self,
+        window: &FeatureWindow,
+        predictors: &HashMap<String, Arc<dyn Predictor>>,
+    ) -> Result<HashMap<String, f64>, BlinkieError> {
+        let body = serde_json::to_string(window).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize feature window: {}", e))
+        })?;
+        match self {
+            Backend::Http { url, token } => {
+                let client = Client::builder()
+                    .timeout(std::time::Duration::from_secs(30))
+                    .build()
+                    .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+                let mut request = client
+                    .post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body);
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                let response = request
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| {
+                        BlinkieError::connection(format!("Failed to request predictions: {}", e))
+                    })?;
+                let answer = response
+                    .bytes()
+                    .map_err(|e| format!("Failed to read predictions: {}", e))?;
+                parse_outputs(&answer, url)
+            }
+            Backend::Command { command } => {
+                let (program, args) = command.split_first().ok_or("Empty prediction command")?;
+                let mut child = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("Failed to start {}: {}", program, e))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin
+                        .write_all(body.as_bytes())
+                        .map_err(|e| format!("Failed to write to {}: {}", program, e))?;
+                }
+                let output = child
+                    .wait_with_output()
+                    .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+                if !output.status.success() {
+                    return Err(format!(
+                        "{} failed with {}: {}",
+                        program,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )
+                    .into());
+                }
+                parse_outputs(&output.stdout, program)
+            }
+            Backend::Registered { name } => predictors
+                .get(name)
+                .ok_or_else(|| format!("No predictor registered as '{}'", name))?
+                .predict(window),
+            Backend::External => Err("Predictions of this model are posted by an outside service"
+                .to_string()
+                .into()),
+        }
+    }
+}
+
+/**
+ * PredictionSensor
+ * Sensor carrying the outputs of a model's latest prediction, so rules can
+ * act on them like on any measured value.
+ */
+pub struct PredictionSensor {
+    id: String,
+    name: String,
+    model: String,
+    latest: Arc<RwLock<HashMap<String, Prediction>>>,
+}
+
+impl Device for PredictionSensor {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        let latest = self.latest.read().unwrap();
+        let Some(prediction) = latest.get(&self.model) else {
+            return HashMap::new();
+        };
+        let mut state: StateMap = prediction
+            .outputs
+            .iter()
+            .map(|(output, value)| (output.clone(), StateValue::Float(*value)))
+            .collect();
+        state.insert(
+            "predicted_at".to_string(),
+            StateValue::String(prediction.predicted_at.to_rfc3339()),
+        );
+        state
+    }
+
+    // The state comes from the model and can't be set from outside
+    fn set_state(&mut self, _state: StateMap) {}
+
+    fn send_cmd(&mut self, _command: &str, _parameters: Option<HashMap<String, String>>) {}
+
+    fn get_type(&self) -> Option<Type> {
+        Some(Type::Sensor)
+    }
+}
+
+/// Id of the sensor of the model `id`.
+pub fn sensor_id(id: &str) -> String {
+    format!("{}{}", SENSOR_PREFIX, id)
+}
+
+/**
+ * Predictions
+ * Hooks for predictive automation: exports windows of feature history for
+ * models, runs them through their backend on a schedule or takes their
+ * predictions from outside, and exposes every model's outputs as a sensor
+ * in the device list, `prediction.<model>`, publishing their changes.
+ */
+pub struct Predictions {
+    path: PathBuf,
+    config: RwLock<PredictionConfig>,
+    predictors: RwLock<HashMap<String, Arc<dyn Predictor>>>,
+    latest: Arc<RwLock<HashMap<String, Prediction>>>,
+    /// When each model is next due, by id.
+    due: Mutex<HashMap<String, DateTime<Utc>>>,
+    devices: DeviceList,
+    events: Arc<EventBus>,
+}
+
+impl Predictions {
+    /// Opens the models configured at `path` and adds their sensors to
+    /// `devices`. Windows are read from the journal of `events`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        devices: DeviceList,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            PredictionConfig::default()
+        };
+        let predictions = Predictions {
+            path,
+            config: RwLock::new(PredictionConfig::default()),
+            predictors: RwLock::new(HashMap::new()),
+            latest: Arc::new(RwLock::new(HashMap::new())),
+            due: Mutex::new(HashMap::new()),
+            devices,
+            events,
+        };
+        predictions.apply(config);
+        Ok(predictions)
+    }
+
+    pub fn config(&self) -> PredictionConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: PredictionConfig) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize prediction models: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        self.apply(config);
+        Ok(())
+    }
+
+    /// Switches to `config`, replacing the sensors of the previous models.
+    fn apply(&self, config: PredictionConfig) {
+        let sensors: Vec<Box<dyn Device>> = config
+            .models
+            .iter()
+            .map(|(id, model)| {
+                Box::new(PredictionSensor {
+                    id: sensor_id(id),
+                    name: model.name.clone().unwrap_or_else(|| id.clone()),
+                    model: id.clone(),
+                    latest: self.latest.clone(),
+                }) as Box<dyn Device>
+            })
+            .collect();
+        let mut devices = self.devices.write().unwrap();
+        let mut current = self.config.write().unwrap();
+        devices.retain(|device| {
+            !device
+                .get_id()
+                .strip_prefix(SENSOR_PREFIX)
+                .is_some_and(|model| current.models.contains_key(model))
+        });
+        devices.extend(sensors);
+        self.latest
+            .write()
+            .unwrap()
+            .retain(|id, _| config.models.contains_key(id));
+        self.due.lock().unwrap().clear();
+        *current = config;
+    }
+
+    /// Lets models with a `registered` backend named `name` predict with
+    /// `predictor`.
+    pub fn register_predictor(&self, name: &str, predictor: Arc<dyn Predictor>) {
+        self.predictors
+            .write()
+            .unwrap()
+            .insert(name.to_string(), predictor);
+    }
+
+    /// Latest prediction of every model that predicted yet.
+    pub fn latest(&self) -> HashMap<String, Prediction> {
+        self.latest.read().unwrap().clone()
+    }
+
+    /// Window of feature history the model `id` sees at `now`.
+    pub fn window(&self, id: &str, now: DateTime<Utc>) -> Result<FeatureWindow, BlinkieError> {
+        let config = self.config.read().unwrap();
+        let model = config
+            .models
+            .get(id)
+            .ok_or_else(|| format!("Unknown model '{}'", id))?;
+        let step = model.step_secs.max(1);
+        let steps = (model.window_secs / step).max(1);
+        let window = Duration::seconds((steps * step) as i64);
+        let start = now - window;
+        let timestamps: Vec<DateTime<Utc>> = (1..=steps)
+            .map(|i| start + Duration::seconds((i * step) as i64))
+            .collect();
+
+        let columns: HashMap<(&str, &str), usize> = model
+            .features
+            .iter()
+            .enumerate()
+            .map(|(i, f)| ((f.device_id.as_str(), f.key.as_str()), i))
+            .collect();
+        let mut values: Vec<Option<f64>> = vec![None; model.features.len()];
+        let mut rows = Vec::with_capacity(timestamps.len());
+        let mut sample = timestamps.iter().peekable();
+        let take_row = |values: &[Option<f64>], at: DateTime<Utc>| {
+            let mut row = values.to_vec();
+            if model.time_features {
+                let local = at.with_timezone(&config.timezone);
+                row.push(Some(local.hour() as f64 + local.minute() as f64 / 60.0));
+                row.push(Some(local.weekday().num_days_from_monday() as f64));
+            }
+            row
+        };
+        for event in self.events.replay_between(start - window, now)? {
+            let EventKind::StateChanged {
+                device_id,
+                key,
+                new_value,
+                ..
+            } = &event.kind
+            else {
+                continue;
+            };
+            while let Some(at) = sample.next_if(|at| **at < event.timestamp) {
+                rows.push(take_row(&values, *at));
+            }
+            if let Some(&column) = columns.get(&(device_id.as_str(), key.as_str())) {
+                values[column] = numeric(new_value);
+            }
+        }
+        for at in sample {
+            rows.push(take_row(&values, *at));
+        }
+
+        let mut names: Vec<String> = model.features.iter().map(Feature::column).collect();
+        if model.time_features {
+            names.push("hour".to_string());
+            names.push("weekday".to_string());
+        }
+        Ok(FeatureWindow {
+            model: id.to_string(),
+            start,
+            end: now,
+            step_secs: step,
+            columns: names,
+            timestamps,
+            rows,
+        })
+    }
+
+    /// Runs the model `id` on its current window; blocks until its backend
+    /// answered.
+    pub fn predict(&self, id: &str, now: DateTime<Utc>) -> Result<Prediction, BlinkieError> {
+        let window = self.window(id, now)?;
+        let backend = self
+            .config
+            .read()
+            .unwrap()
+            .models
+            .get(id)
+            .map(|model| model.backend.clone())
+            .ok_or_else(|| format!("Unknown model '{}'", id))?;
+        let predictors = self.predictors.read().unwrap().clone();
+        let outputs = backend
+            .predict(&window, &predictors)
+            .map_err(|e| format!("Failed to predict {}: {}", id, e))?;
+        self.accept(id, outputs, Utc::now())
+    }
+
+    /// Takes `outputs` as the latest prediction of the model `id` and
+    /// publishes the outputs that changed on its sensor.
+    pub fn accept(
+        &self,
+        id: &str,
+        outputs: HashMap<String, f64>,
+        now: DateTime<Utc>,
+    ) -> Result<Prediction, BlinkieError> {
+        if !self.config.read().unwrap().models.contains_key(id) {
+            return Err(format!("Unknown model '{}'", id).into());
+        }
+        let prediction = Prediction {
+            outputs,
+            predicted_at: now,
+        };
+        let previous = self
+            .latest
+            .write()
+            .unwrap()
+            .insert(id.to_string(), prediction.clone());
+        for (output, value) in &prediction.outputs {
+            let old_value = previous
+                .as_ref()
+                .and_then(|previous| previous.outputs.get(output));
+            if old_value == Some(value) {
+                continue;
+            }
+            let published = self.events.publish(EventKind::StateChanged {
+                device_id: sensor_id(id),
+                key: output.clone(),
+                old_value: old_value.map(f64::to_string),
+                new_value: value.to_string(),
+                reported_at: None,
+            });
+            if let Err(e) = published {
+                log::warn!("Failed to publish prediction: {}", e);
+            }
+        }
+        Ok(prediction)
+    }
+
+    /// Models whose backend runs here and that are due at `now`, marked as
+    /// run.
+    fn due(&self, now: DateTime<Utc>) -> Vec<String> {
+        let config = self.config.read().unwrap();
+        let mut due = self.due.lock().unwrap();
+        config
+            .models
+            .iter()
+            .filter(|(_, model)| !matches!(model.backend, Backend::External))
+            .filter_map(|(id, model)| {
+                let next = due.get(id).copied().unwrap_or(now);
+                if next > now {
+                    return None;
+                }
+                let interval = Duration::seconds(model.interval_secs.max(1) as i64);
+                due.insert(id.clone(), now + interval);
+                Some(id.clone())
+            })
+            .collect()
+    }
+
+    /// Runs models as they are due, until the process ends.
+    pub async fn run(self: Arc<Self>) {
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(TICK_SECS));
+        loop {
+            tick.tick().await;
+            for id in self.due(Utc::now()) {
+                let predictions = self.clone();
+                // Backends block on their service or command
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = predictions.predict(&id, Utc::now()) {
+                        log::warn!("{}", e);
+                    }
+                });
+            }
+        }
+    }
+}
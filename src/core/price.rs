@@ -0,0 +1,914 @@
+use super::device::{CommandSender, Device, DeviceList, Type};
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::holiday::{SharedWorkdays, Workdays};
+use super::schedule::ScheduleDays;
+use super::value::{StateMap, StateValue};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+
+/// Id of the sensor reporting the current price.
+pub const SENSOR_ID: &str = "price.electricity";
+
+const TIBBER_URL: &str = "https://api.tibber.com/v1-beta/gql";
+const ENTSOE_URL: &str = "https://web-api.tp.entsoe.eu/api";
+const NORDPOOL_URL: &str = "https://dataportal-api.nordpoolgroup.com/api/DayAheadPrices";
+
+/// How long prices are kept after they ended.
+const KEEP_DAYS: i64 = 2;
+
+/// Seconds between decisions on shifted runs.
+const TICK_SECS: u64 = 60;
+
+/**
+ * Price Sources
+ * Where prices come from. Spot prices of ENTSO-E and Nord Pool come per
+ * MWh and are turned into prices per kWh; Tibber's already include taxes
+ * and fees.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PriceSource {
+    Tibber {
+        token: String,
+        /// Home to take prices of, the first one if unset.
+        #[serde(default)]
+        home_id: Option<String>,
+    },
+    #[serde(rename = "entsoe")]
+    EntsoE {
+        token: String,
+        /// EIC code of the bidding zone, e.g. `10Y1001A1001A82H` for
+        /// Germany and Luxembourg.
+        area: String,
+    },
+    Nordpool {
+        /// Delivery area, e.g. `DE-LU`, `SE3` or `NO1`.
+        area: String,
+    },
+    /// A JSON list of price points, e.g. written by a script.
+    File { path: PathBuf },
+}
+
+/**
+ * PricePoint
+ * The price per kWh from `start` until `end`.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PricePoint {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub price: f64,
+}
+
+impl PricePoint {
+    fn contains(&self, at: DateTime<Utc>) -> bool {
+        self.start <= at && at < self.end
+    }
+}
+
+/**
+ * ShiftedRun
+ * A device that needs to run for `run_mins` every day between `earliest`
+ * and `deadline`, e.g. a dishwasher started after 22:00 and done by 06:00,
+ * run in the cheapest hours of that window. Without prices for the whole
+ * window, it runs as late as it can and still finish in time.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShiftedRun {
+    pub device_id: String,
+    pub run_mins: u64,
+    pub earliest: NaiveTime,
+    /// Taken to be on the next day when not after `earliest`.
+    pub deadline: NaiveTime,
+    #[serde(default)]
+    pub days: ScheduleDays,
+    /// Run in one go rather than in the cheapest hours wherever they are.
+    #[serde(default = "default_contiguous")]
+    pub contiguous: bool,
+    /// Never run while the price is above this, even if that means not
+    /// running long enough.
+    #[serde(default)]
+    pub max_price: Option<f64>,
+    #[serde(default = "default_on_command")]
+    pub on_command: String,
+    #[serde(default = "default_off_command")]
+    pub off_command: String,
+}
+
+fn default_contiguous() -> bool {
+    true
+}
+
+fn default_on_command() -> String {
+    "turn_on".to_string()
+}
+
+fn default_off_command() -> String {
+    "turn_off".to_string()
+}
+
+/**
+ * PriceConfig
+ * The price feed and the runs shifted to cheap hours, kept as
+ * `prices.json`. Prices are `(spot + surcharge) * (1 + vat_percent / 100)`.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PriceConfig {
+    #[serde(default)]
+    pub source: Option<PriceSource>,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    /// Grid fees and taxes per kWh added to spot prices.
+    #[serde(default)]
+    pub surcharge: f64,
+    #[serde(default)]
+    pub vat_percent: f64,
+    #[serde(default = "default_refresh_mins")]
+    pub refresh_mins: u64,
+    #[serde(default)]
+    pub runs: HashMap<String, ShiftedRun>,
+}
+
+impl Default for PriceConfig {
+    fn default() -> Self {
+        PriceConfig {
+            source: None,
+            currency: default_currency(),
+            surcharge: 0.0,
+            vat_percent: 0.0,
+            refresh_mins: default_refresh_mins(),
+            runs: HashMap::new(),
+        }
+    }
+}
+
+fn default_currency() -> String {
+    "EUR".to_string()
+}
+
+fn default_refresh_mins() -> u64 {
+    60
+}
+
+/**
+ * Run
+ * A stretch of time a shifted device runs.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/**
+ * Plan
+ * When a shifted run happens in its current window.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub window_start: DateTime<Utc>,
+    pub window_end: DateTime<Utc>,
+    pub runs: Vec<Run>,
+    /// What the runs cost per kW the device draws, if prices are known.
+    pub cost_per_kw: Option<f64>,
+    /// Whether prices didn't cover the window and the device runs as late
+    /// as it can instead.
+    pub fallback: bool,
+    pub planned_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Plan {
+    fn active(&self, at: DateTime<Utc>) -> bool {
+        self.runs.iter().any(|run| run.start <= at && at < run.end)
+    }
+
+    /// Whether a run started already, after which the plan stays.
+    fn started(&self, at: DateTime<Utc>) -> bool {
+        self.runs.first().is_some_and(|run| run.start <= at)
+    }
+}
+
+/**
+ * PriceStatus
+ * Known prices, when they were fetched and the plans of the shifted runs.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PriceStatus {
+    pub currency: String,
+    pub current: Option<f64>,
+    pub prices: Vec<PricePoint>,
+    pub updated: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub plans: HashMap<String, Plan>,
+}
+
+#[derive(Default)]
+struct Planner {
+    plans: HashMap<String, Plan>,
+    /// Whether runs are on, for the ones switched so far.
+    on: HashMap<String, bool>,
+    updated: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    /// Price last published for the sensor.
+    published: Option<f64>,
+}
+
+/**
+ * PriceSensor
+ * The current price, `price.electricity`, with the next one and today's
+ * lowest, highest and average, and `rank`, where the current price stands
+ * among today's starting at 1 for the cheapest.
+ */
+struct PriceSensor {
+    prices: Arc<RwLock<Vec<PricePoint>>>,
+    config: Arc<RwLock<PriceConfig>>,
+    timezone: Tz,
+}
+
+impl Device for PriceSensor {
+    fn get_id(&self) -> &str {
+        SENSOR_ID
+    }
+
+    fn get_name(&self) -> &str {
+        "Electricity price"
+    }
+
+    fn get_state(&self) -> StateMap {
+        let prices = self.prices.read().unwrap();
+        let config = self.config.read().unwrap();
+        let now = Utc::now();
+        let mut state = StateMap::new();
+        state.insert(
+            "currency".to_string(),
+            StateValue::String(config.currency.clone()),
+        );
+        let Some(current) = prices.iter().find(|point| point.contains(now)) else {
+            return state;
+        };
+        state.insert("price".to_string(), StateValue::Float(current.price));
+        if let Some(next) = prices.iter().find(|point| point.start == current.end) {
+            state.insert("next_price".to_string(), StateValue::Float(next.price));
+        }
+        let (start, end) = day_bounds(now, self.timezone);
+        let today: Vec<f64> = prices
+            .iter()
+            .filter(|point| point.start >= start && point.start < end)
+            .map(|point| point.price)
+            .collect();
+        if !today.is_empty() {
+            let min = today.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = today.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let average = today.iter().sum::<f64>() / today.len() as f64;
+            let rank = today.iter().filter(|price| **price < current.price).count() + 1;
+            state.insert("min_today".to_string(), StateValue::Float(min));
+            state.insert("max_today".to_string(), StateValue::Float(max));
+            state.insert("average_today".to_string(), StateValue::Float(average));
+            state.insert("rank".to_string(), StateValue::Int(rank as i64));
+        }
+        state
+    }
+
+    // Prices come from the feed and can't be set from outside
+    fn set_state(&mut self, _state: StateMap) {}
+
+    fn send_cmd(&mut self, _command: &str, _parameters: Option<HashMap<String, String>>) {}
+
+    fn get_type(&self) -> Option<Type> {
+        Some(Type::Sensor)
+    }
+}
+
+/// Start and end of the local day `at` falls on.
+fn day_bounds(at: DateTime<Utc>, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+    let date = at.with_timezone(&tz).date_naive();
+    (
+        local(date, NaiveTime::MIN, tz),
+        local(date + Duration::days(1), NaiveTime::MIN, tz),
+    )
+}
+
+/// `time` on `date` in `tz`, the first time after a gap if it doesn't exist.
+fn local(date: NaiveDate, time: NaiveTime, tz: Tz) -> DateTime<Utc> {
+    let mut at = date.and_time(time);
+    loop {
+        if let Some(local) = tz.from_local_datetime(&at).earliest() {
+            return local.with_timezone(&Utc);
+        }
+        at += Duration::minutes(15);
+    }
+}
+
+/**
+ * Prices
+ * Fetches electricity prices, reports the current one as a sensor and
+ * switches shifted runs on and off in the cheapest hours of their windows.
+ */
+pub struct Prices {
+    path: PathBuf,
+    timezone: Tz,
+    workdays: SharedWorkdays,
+    config: Arc<RwLock<PriceConfig>>,
+    prices: Arc<RwLock<Vec<PricePoint>>>,
+    planner: Mutex<Planner>,
+    devices: DeviceList,
+    events: Arc<EventBus>,
+    wake: Notify,
+}
+
+impl Prices {
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        timezone: Tz,
+        workdays: SharedWorkdays,
+        devices: DeviceList,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            PriceConfig::default()
+        };
+        let prices = Prices {
+            path,
+            timezone,
+            workdays,
+            config: Arc::new(RwLock::new(PriceConfig::default())),
+            prices: Arc::new(RwLock::new(Vec::new())),
+            planner: Mutex::new(Planner::default()),
+            devices,
+            events,
+            wake: Notify::new(),
+        };
+        prices.apply(config);
+        Ok(prices)
+    }
+
+    pub fn config(&self) -> PriceConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: PriceConfig) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize price setup: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        self.apply(config);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Switches to `config`, adding the sensor with a source and dropping
+    /// the plans of runs that changed. Prices are fetched again.
+    fn apply(&self, config: PriceConfig) {
+        let mut devices = self.devices.write().unwrap();
+        devices.retain(|device| device.get_id() != SENSOR_ID);
+        if config.source.is_some() {
+            devices.push(Box::new(PriceSensor {
+                prices: self.prices.clone(),
+                config: self.config.clone(),
+                timezone: self.timezone,
+            }));
+        }
+        let mut planner = self.planner.lock().unwrap();
+        let mut current = self.config.write().unwrap();
+        planner.plans.retain(|id, _| {
+            let old = current.runs.get(id).map(serde_json::to_value);
+            let new = config.runs.get(id).map(serde_json::to_value);
+            matches!((old, new), (Some(Ok(old)), Some(Ok(new))) if old == new)
+        });
+        planner.updated = None;
+        *current = config;
+    }
+
+    pub fn status(&self) -> PriceStatus {
+        let prices = self.prices.read().unwrap().clone();
+        let planner = self.planner.lock().unwrap();
+        let now = Utc::now();
+        PriceStatus {
+            currency: self.config.read().unwrap().currency.clone(),
+            current: prices
+                .iter()
+                .find(|point| point.contains(now))
+                .map(|point| point.price),
+            prices,
+            updated: planner.updated,
+            last_error: planner.last_error.clone(),
+            plans: planner.plans.clone(),
+        }
+    }
+
+    /// Fetches prices now rather than at the next refresh.
+    pub fn refresh(&self) {
+        self.planner.lock().unwrap().updated = None;
+        self.wake.notify_one();
+    }
+
+    /// Fetches prices from the source and keeps them with the ones still
+    /// of interest. Blocks, so call it from blocking threads only.
+    pub fn fetch(&self, now: DateTime<Utc>) -> Result<usize, BlinkieError> {
+        let config = self.config();
+        let Some(source) = &config.source else {
+            return Ok(0);
+        };
+        let fetched = fetch(source, &config, self.timezone, now)?;
+        let count = fetched.len();
+        let mut prices = self.prices.write().unwrap();
+        prices.retain(|point| {
+            point.end > now - Duration::days(KEEP_DAYS)
+                && !fetched.iter().any(|new| new.start == point.start)
+        });
+        prices.extend(fetched.into_iter().map(|point| PricePoint {
+            price: (point.price + config.surcharge) * (1.0 + config.vat_percent / 100.0),
+            ..point
+        }));
+        prices.sort_by_key(|point| point.start);
+        Ok(count)
+    }
+
+    /// Plans the shifted runs and switches the ones whose plan says so.
+    /// Blocks while sending commands, so call it from blocking threads only.
+    pub fn control(&self, now: DateTime<Utc>, sender: &CommandSender) {
+        let config = self.config();
+        let prices = self.prices.read().unwrap().clone();
+        self.publish_price(&prices, now);
+        let workdays = self.workdays.read().unwrap().clone();
+        let mut switches = Vec::new();
+        {
+            let mut planner = self.planner.lock().unwrap();
+            planner.plans.retain(|_, plan| plan.window_end > now);
+            for (id, run) in &config.runs {
+                let keep = planner.plans.get(id).is_some_and(|plan| plan.started(now));
+                if !keep {
+                    match window(run, now, self.timezone, &workdays) {
+                        Some((start, end)) => {
+                            let plan = plan(run, &prices, start, end, now);
+                            planner.plans.insert(id.clone(), plan);
+                        }
+                        None => {
+                            planner.plans.remove(id);
+                        }
+                    }
+                }
+                let on = planner.plans.get(id).is_some_and(|plan| plan.active(now));
+                match planner.on.get(id) {
+                    Some(was) if *was == on => {}
+                    // Runs are only switched off once switched on here
+                    None if !on => {}
+                    _ => switches.push((id.clone(), run.clone(), on)),
+                }
+            }
+            let runs = &config.runs;
+            planner.on.retain(|id, _| runs.contains_key(id));
+        }
+
+        for (id, run, on) in switches {
+            let command = if on {
+                &run.on_command
+            } else {
+                &run.off_command
+            };
+            match sender(&run.device_id, command, HashMap::new()) {
+                Ok(()) => {
+                    self.planner.lock().unwrap().on.insert(id, on);
+                }
+                Err(e) => log::warn!("Failed to switch shifted run '{}': {}", id, e),
+            }
+        }
+    }
+
+    /// Publishes the current price when it changed.
+    fn publish_price(&self, prices: &[PricePoint], now: DateTime<Utc>) {
+        let current = prices
+            .iter()
+            .find(|point| point.contains(now))
+            .map(|point| point.price);
+        let old = {
+            let mut planner = self.planner.lock().unwrap();
+            if planner.published == current {
+                return;
+            }
+            std::mem::replace(&mut planner.published, current)
+        };
+        let Some(current) = current else {
+            return;
+        };
+        let published = self.events.publish(EventKind::StateChanged {
+            device_id: SENSOR_ID.to_string(),
+            key: "price".to_string(),
+            old_value: old.map(|old| old.to_string()),
+            new_value: current.to_string(),
+            reported_at: None,
+        });
+        if let Err(e) = published {
+            log::warn!("Failed to publish price: {}", e);
+        }
+    }
+
+    /// Fetches prices every `refresh_mins` and decides on shifted runs
+    /// every minute, right away when the setup changes, until the task is
+    /// dropped.
+    pub async fn run(self: Arc<Self>, sender: CommandSender) {
+        loop {
+            let prices = self.clone();
+            let sender = sender.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                let now = Utc::now();
+                let refresh = Duration::minutes(prices.config.read().unwrap().refresh_mins as i64);
+                let due = prices
+                    .planner
+                    .lock()
+                    .unwrap()
+                    .updated
+                    .is_none_or(|updated| now - updated >= refresh);
+                if due {
+                    let result = prices.fetch(now);
+                    if let Err(e) = &result {
+                        log::warn!("Failed to fetch electricity prices: {}", e);
+                    }
+                    let mut planner = prices.planner.lock().unwrap();
+                    planner.updated = Some(now);
+                    planner.last_error = result.err().map(|e| e.to_string());
+                }
+                prices.control(now, &sender);
+            })
+            .await;
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(TICK_SECS)) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+}
+
+/// The window of `run` that is open at `now` or opens next.
+fn window(
+    run: &ShiftedRun,
+    now: DateTime<Utc>,
+    tz: Tz,
+    workdays: &Workdays,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let today = now.with_timezone(&tz).date_naive();
+    (-1..=7)
+        .map(|offset| today + Duration::days(offset))
+        .filter(|date| run.days.matches(*date, workdays))
+        .map(|date| {
+            let end_date = if run.deadline <= run.earliest {
+                date + Duration::days(1)
+            } else {
+                date
+            };
+            (
+                local(date, run.earliest, tz),
+                local(end_date, run.deadline, tz),
+            )
+        })
+        .find(|(_, end)| *end > now)
+}
+
+/// Plans `run` in the window from `start` to `end`, in its cheapest slots
+/// still ahead at `now`.
+fn plan(
+    run: &ShiftedRun,
+    prices: &[PricePoint],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Plan {
+    let from = start.max(now);
+    let length = Duration::minutes(run.run_mins as i64).min(end - from);
+    let mut plan = Plan {
+        window_start: start,
+        window_end: end,
+        runs: Vec::new(),
+        cost_per_kw: None,
+        fallback: false,
+        planned_at: now,
+        reason: None,
+    };
+    let slots: Vec<PricePoint> = prices
+        .iter()
+        .filter(|point| point.end > from && point.start < end)
+        .map(|point| PricePoint {
+            start: point.start.max(from),
+            end: point.end.min(end),
+            price: point.price,
+        })
+        .collect();
+    let covered = slots.first().is_some_and(|first| first.start <= from)
+        && slots.last().is_some_and(|last| last.end >= end)
+        && slots.windows(2).all(|pair| pair[0].end == pair[1].start);
+    if !covered {
+        plan.fallback = true;
+        plan.reason = Some("No prices for the whole window".to_string());
+        plan.runs.push(Run {
+            start: end - length,
+            end,
+        });
+        return plan;
+    }
+    let affordable = |slot: &PricePoint| run.max_price.is_none_or(|max| slot.price <= max);
+    let hours = |duration: Duration| duration.num_seconds() as f64 / 3600.0;
+
+    if run.contiguous {
+        let mut best: Option<(f64, DateTime<Utc>)> = None;
+        for first in 0..slots.len() {
+            let run_start = slots[first].start;
+            let run_end = run_start + length;
+            let mut cost = 0.0;
+            let mut fits = run_end <= end;
+            for slot in slots[first..]
+                .iter()
+                .take_while(|slot| slot.start < run_end)
+            {
+                if !affordable(slot) {
+                    fits = false;
+                    break;
+                }
+                cost += slot.price * hours(slot.end.min(run_end) - slot.start);
+            }
+            if fits && best.is_none_or(|(best, _)| cost < best) {
+                best = Some((cost, run_start));
+            }
+        }
+        match best {
+            Some((cost, run_start)) => {
+                plan.cost_per_kw = Some(cost);
+                plan.runs.push(Run {
+                    start: run_start,
+                    end: run_start + length,
+                });
+            }
+            None => plan.reason = Some("No stretch of the window is cheap enough".to_string()),
+        }
+        return plan;
+    }
+
+    let mut cheapest: Vec<&PricePoint> = slots.iter().filter(|slot| affordable(slot)).collect();
+    cheapest.sort_by(|a, b| a.price.total_cmp(&b.price).then(a.start.cmp(&b.start)));
+    let mut left = length;
+    let mut cost = 0.0;
+    let mut runs = Vec::new();
+    for slot in cheapest {
+        if left <= Duration::zero() {
+            break;
+        }
+        let taken = (slot.end - slot.start).min(left);
+        cost += slot.price * hours(taken);
+        left -= taken;
+        runs.push(Run {
+            start: slot.start,
+            end: slot.start + taken,
+        });
+    }
+    runs.sort_by_key(|run| run.start);
+    for run in runs {
+        match plan.runs.last_mut() {
+            Some(last) if last.end == run.start => last.end = run.end,
+            _ => plan.runs.push(run),
+        }
+    }
+    if left > Duration::zero() {
+        plan.reason = Some("Too few hours of the window are cheap enough".to_string());
+    }
+    plan.cost_per_kw = Some(cost);
+    plan
+}
+
+/// Spot prices per kWh from `source` around `now`, before surcharge and VAT.
+fn fetch(
+    source: &PriceSource,
+    config: &PriceConfig,
+    timezone: Tz,
+    now: DateTime<Utc>,
+) -> Result<Vec<PricePoint>, BlinkieError> {
+    let client = || {
+        Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .map_err(|e| BlinkieError::connection(format!("Failed to create client: {}", e)))
+    };
+    match source {
+        PriceSource::Tibber { token, home_id } => {
+            let query = "{viewer{homes{id currentSubscription{priceInfo{\
+                today{total startsAt} tomorrow{total startsAt}}}}}}";
+            let body = client()?
+                .post(TIBBER_URL)
+                .bearer_auth(token)
+                .header(CONTENT_TYPE, "application/json")
+                .body(json!({ "query": query }).to_string())
+                .send()
+                .and_then(|response| response.error_for_status())
+                .and_then(|response| response.text())
+                .map_err(|e| BlinkieError::connection(format!("Tibber: {}", e)))?;
+            parse_tibber(&parse_json(&body, "Tibber")?, home_id.as_deref())
+        }
+        PriceSource::EntsoE { token, area } => {
+            let format = "%Y%m%d%H00";
+            let start = now - Duration::days(1);
+            let end = now + Duration::days(2);
+            let body = client()?
+                .get(ENTSOE_URL)
+                .query(&[
+                    ("securityToken", token.as_str()),
+                    ("documentType", "A44"),
+                    ("in_Domain", area.as_str()),
+                    ("out_Domain", area.as_str()),
+                    ("periodStart", &start.format(format).to_string()),
+                    ("periodEnd", &end.format(format).to_string()),
+                ])
+                .send()
+                .and_then(|response| response.error_for_status())
+                .and_then(|response| response.text())
+                .map_err(|e| BlinkieError::connection(format!("ENTSO-E: {}", e)))?;
+            parse_entsoe(&body)
+        }
+        PriceSource::Nordpool { area } => {
+            let client = client()?;
+            let today = now.with_timezone(&timezone).date_naive();
+            let mut points = Vec::new();
+            for date in [today, today + Duration::days(1)] {
+                let response = client
+                    .get(NORDPOOL_URL)
+                    .query(&[
+                        ("date", date.to_string().as_str()),
+                        ("market", "DayAhead"),
+                        ("deliveryArea", area.as_str()),
+                        ("currency", config.currency.as_str()),
+                    ])
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| BlinkieError::connection(format!("Nord Pool: {}", e)))?;
+                // Tomorrow's prices aren't out before the early afternoon
+                if response.status() == reqwest::StatusCode::NO_CONTENT {
+                    continue;
+                }
+                let body = response
+                    .text()
+                    .map_err(|e| BlinkieError::connection(format!("Nord Pool: {}", e)))?;
+                points.extend(parse_nordpool(&parse_json(&body, "Nord Pool")?, area)?);
+            }
+            Ok(points)
+        }
+        PriceSource::File { path } => {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })
+        }
+    }
+}
+
+fn parse_json(body: &str, source: &str) -> Result<Value, BlinkieError> {
+    serde_json::from_str(body)
+        .map_err(|e| BlinkieError::serialization(format!("{}: invalid response: {}", source, e)))
+}
+
+fn parse_tibber(response: &Value, home_id: Option<&str>) -> Result<Vec<PricePoint>, BlinkieError> {
+    if let Some(message) = response
+        .pointer("/errors/0/message")
+        .and_then(Value::as_str)
+    {
+        return Err(BlinkieError::connection(format!("Tibber: {}", message)));
+    }
+    let homes = response
+        .pointer("/data/viewer/homes")
+        .and_then(Value::as_array)
+        .ok_or_else(|| BlinkieError::serialization("Tibber: no homes in response"))?;
+    let home = match home_id {
+        Some(id) => homes
+            .iter()
+            .find(|home| home.get("id").and_then(Value::as_str) == Some(id)),
+        None => homes.first(),
+    }
+    .ok_or_else(|| format!("Tibber: home '{}' not found", home_id.unwrap_or_default()))?;
+    let mut points = Vec::new();
+    for day in ["today", "tomorrow"] {
+        let entries = home
+            .pointer(&format!("/currentSubscription/priceInfo/{}", day))
+            .and_then(Value::as_array);
+        for entry in entries.into_iter().flatten() {
+            let start = entry
+                .get("startsAt")
+                .and_then(Value::as_str)
+                .and_then(|start| DateTime::parse_from_rfc3339(start).ok());
+            let price = entry.get("total").and_then(Value::as_f64);
+            if let (Some(start), Some(price)) = (start, price) {
+                let start = start.with_timezone(&Utc);
+                points.push(PricePoint {
+                    start,
+                    end: start + Duration::hours(1),
+                    price,
+                });
+            }
+        }
+    }
+    Ok(points)
+}
+
+/// Prices of an ENTSO-E day-ahead document. Points repeating the price
+/// before them may be left out, so gaps take the previous price.
+fn parse_entsoe(body: &str) -> Result<Vec<PricePoint>, BlinkieError> {
+    if body.contains("<Acknowledgement_MarketDocument") {
+        let reason = Regex::new(r"<text>([^<]*)</text>")
+            .unwrap()
+            .captures(body)
+            .map(|captures| captures[1].to_string())
+            .unwrap_or_else(|| "request rejected".to_string());
+        return Err(BlinkieError::connection(format!("ENTSO-E: {}", reason)));
+    }
+    let period = Regex::new(r"(?s)<Period>(.*?)</Period>").unwrap();
+    let interval =
+        Regex::new(r"(?s)<timeInterval>\s*<start>([^<]+)</start>\s*<end>([^<]+)</end>").unwrap();
+    let resolution = Regex::new(r"<resolution>PT(\d+)M</resolution>").unwrap();
+    let point = Regex::new(
+        r"(?s)<Point>\s*<position>(\d+)</position>\s*<price\.amount>([-\d.]+)</price\.amount>",
+    )
+    .unwrap();
+    let time = |text: &str| {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%MZ")
+            .map(|time| time.and_utc())
+            .map_err(|e| {
+                BlinkieError::serialization(format!("ENTSO-E: bad time '{}': {}", text, e))
+            })
+    };
+
+    let mut points = Vec::new();
+    for captures in period.captures_iter(body) {
+        let block = &captures[1];
+        let (Some(bounds), Some(step)) = (interval.captures(block), resolution.captures(block))
+        else {
+            continue;
+        };
+        let (start, end) = (time(&bounds[1])?, time(&bounds[2])?);
+        let step = Duration::minutes(step[1].parse::<i64>().unwrap_or(60));
+        let prices: HashMap<i64, f64> = point
+            .captures_iter(block)
+            .filter_map(|point| Some((point[1].parse().ok()?, point[2].parse().ok()?)))
+            .collect();
+        let count = (end - start).num_minutes() / step.num_minutes().max(1);
+        let mut last = None;
+        for position in 1..=count {
+            let Some(price) = prices.get(&position).copied().or(last) else {
+                continue;
+            };
+            last = Some(price);
+            let slot_start = start + step * (position as i32 - 1);
+            points.push(PricePoint {
+                start: slot_start,
+                end: slot_start + step,
+                price: price / 1000.0,
+            });
+        }
+    }
+    Ok(points)
+}
+
+fn parse_nordpool(body: &Value, area: &str) -> Result<Vec<PricePoint>, BlinkieError> {
+    let entries = body
+        .get("multiAreaEntries")
+        .and_then(Value::as_array)
+        .ok_or_else(|| BlinkieError::serialization("Nord Pool: no prices in response"))?;
+    let time = |entry: &Value, key: &str| {
+        entry
+            .get(key)
+            .and_then(Value::as_str)
+            .and_then(|text| DateTime::parse_from_rfc3339(text).ok())
+            .map(|time| time.with_timezone(&Utc))
+    };
+    Ok(entries
+        .iter()
+        .filter_map(|entry| {
+            Some(PricePoint {
+                start: time(entry, "deliveryStart")?,
+                end: time(entry, "deliveryEnd")?,
+                price: entry
+                    .pointer(&format!("/entryPerArea/{}", area))?
+                    .as_f64()?
+                    / 1000.0,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,382 @@
+use super::device::CommandSender;
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::usage::is_on;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Name of the custom event published when a pump is stopped for lack of
+/// flow, carrying `pump`, `sensor` and `reason`.
+pub const TRIPPED_EVENT: &str = "pump_tripped";
+/// State key whose value tells whether a pump is running.
+const STATE_KEY: &str = "state";
+/// Seconds between attempts to stop a pump that didn't stop.
+const STOP_RETRY_SECS: i64 = 10;
+
+fn default_flow_key() -> String {
+    "flow".to_string()
+}
+
+fn default_confirm_secs() -> u64 {
+    30
+}
+
+fn default_start_commands() -> Vec<String> {
+    vec!["turn_on".to_string()]
+}
+
+fn default_stop_command() -> String {
+    "turn_off".to_string()
+}
+
+fn default_lockout() -> bool {
+    true
+}
+
+/**
+ * PumpGuard
+ * Protection of a pump, or a sprinkler valve, by the flow or pressure
+ * sensor paired with it: once started, `key` of `sensor` must go above
+ * `min_flow` within `confirm_secs`, and stay there, or the pump is sent
+ * `stop_command`. Readings that aren't numbers count as flowing when
+ * they read on, e.g. a flow switch.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PumpGuard {
+    pub sensor: String,
+    #[serde(default = "default_flow_key")]
+    pub key: String,
+    #[serde(default)]
+    pub min_flow: f64,
+    #[serde(default = "default_confirm_secs")]
+    pub confirm_secs: u64,
+    /// Commands starting the pump.
+    #[serde(default = "default_start_commands")]
+    pub start_commands: Vec<String>,
+    #[serde(default = "default_stop_command")]
+    pub stop_command: String,
+    /// Refuses to start the pump again after it was stopped, until the
+    /// trip is reset.
+    #[serde(default = "default_lockout")]
+    pub lockout: bool,
+}
+
+impl PumpGuard {
+    fn flowing(&self, value: &str) -> bool {
+        match value.trim().parse::<f64>() {
+            Ok(flow) => flow > self.min_flow,
+            Err(_) => is_on(value.trim()),
+        }
+    }
+}
+
+/**
+ * PumpConfig
+ * Guards by the id of the pump they protect.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PumpConfig {
+    #[serde(default)]
+    pub pumps: HashMap<String, PumpGuard>,
+}
+
+impl PumpConfig {
+    fn validate(&self) -> Result<(), BlinkieError> {
+        for (pump, guard) in &self.pumps {
+            if guard.sensor.is_empty() || guard.sensor == *pump {
+                return Err(format!("Pump '{}' needs a sensor of its own", pump).into());
+            }
+            if guard.confirm_secs == 0 {
+                return Err(format!("Pump '{}' needs confirm_secs above 0", pump).into());
+            }
+            if guard.start_commands.contains(&guard.stop_command) {
+                return Err(format!(
+                    "Pump '{}' has '{}' as start and stop command",
+                    pump, guard.stop_command
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Trip
+ * When and why a pump was stopped.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trip {
+    pub at: DateTime<Utc>,
+    pub reason: String,
+}
+
+/**
+ * PumpStatus
+ * Whether a guarded pump runs, whether its sensor confirms flow and, while
+ * it doesn't, when the pump will be stopped.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PumpStatus {
+    pub running: bool,
+    pub flowing: bool,
+    pub stop_at: Option<DateTime<Utc>>,
+    pub tripped: Option<Trip>,
+}
+
+/**
+ * Pumps
+ * Stops pumps whose flow sensor doesn't confirm flow in time, so they
+ * don't run dry, e.g. a well pump with an empty well or a sprinkler pump
+ * with a closed valve. Start commands of a tripped pump are refused until
+ * the trip is reset.
+ */
+pub struct Pumps {
+    path: PathBuf,
+    config: RwLock<PumpConfig>,
+    status: Mutex<HashMap<String, PumpStatus>>,
+    events: Arc<EventBus>,
+}
+
+impl Pumps {
+    /// Opens the guards configured at `path`.
+    pub fn open<P: Into<PathBuf>>(path: P, events: Arc<EventBus>) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            PumpConfig::default()
+        };
+        Ok(Pumps {
+            path,
+            config: RwLock::new(config),
+            status: Mutex::new(HashMap::new()),
+            events,
+        })
+    }
+
+    pub fn config(&self) -> PumpConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: PumpConfig) -> Result<(), BlinkieError> {
+        config.validate()?;
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize pump guards: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        self.status
+            .lock()
+            .unwrap()
+            .retain(|pump, _| config.pumps.contains_key(pump));
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// Status of every guarded pump.
+    pub fn status(&self) -> HashMap<String, PumpStatus> {
+        let status = self.status.lock().unwrap();
+        self.config
+            .read()
+            .unwrap()
+            .pumps
+            .keys()
+            .map(|pump| (pump.clone(), status.get(pump).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    /// Refuses `command` to `device_id` if it starts a pump that tripped
+    /// and stays locked out.
+    pub fn authorize(&self, device_id: &str, command: &str) -> Result<(), BlinkieError> {
+        let starts = self
+            .config
+            .read()
+            .unwrap()
+            .pumps
+            .get(device_id)
+            .is_some_and(|guard| guard.start_commands.iter().any(|c| c == command));
+        match starts {
+            true => self.authorize_start(device_id),
+            false => Ok(()),
+        }
+    }
+
+    /// Refuses to start `device_id` by any means, e.g. a scene switching it
+    /// on, if it is a pump that tripped and stays locked out.
+    pub fn authorize_start(&self, device_id: &str) -> Result<(), BlinkieError> {
+        let config = self.config.read().unwrap();
+        let Some(guard) = config.pumps.get(device_id) else {
+            return Ok(());
+        };
+        if !guard.lockout {
+            return Ok(());
+        }
+        match self
+            .status
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .and_then(|status| status.tripped.as_ref())
+        {
+            Some(trip) => Err(format!(
+                "Pump '{}' was stopped at {}: {}; reset it before starting it again",
+                device_id, trip.at, trip.reason
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Clears the trip of `pump`, allowing it to be started again.
+    pub fn reset(&self, pump: &str) -> Result<PumpStatus, BlinkieError> {
+        if !self.config.read().unwrap().pumps.contains_key(pump) {
+            return Err(format!("Unknown pump '{}'", pump).into());
+        }
+        let mut status = self.status.lock().unwrap();
+        let status = status.entry(pump.to_string()).or_default();
+        status.tripped = None;
+        Ok(status.clone())
+    }
+
+    /// Follows pumps starting and stopping and their sensors' readings in
+    /// `event`.
+    pub fn observe(&self, event: &Event, now: DateTime<Utc>) {
+        let config = self.config.read().unwrap();
+        let mut status = self.status.lock().unwrap();
+        match &event.kind {
+            EventKind::CommandSent {
+                device_id, command, ..
+            } => {
+                let Some(guard) = config.pumps.get(device_id) else {
+                    return;
+                };
+                let pump = status.entry(device_id.clone()).or_default();
+                if guard.start_commands.iter().any(|c| c == command) {
+                    start(pump, guard, now);
+                } else if *command == guard.stop_command {
+                    stop(pump);
+                }
+            }
+            EventKind::StateChanged {
+                device_id,
+                key,
+                new_value,
+                ..
+            } => {
+                if key == STATE_KEY {
+                    if let Some(guard) = config.pumps.get(device_id) {
+                        let pump = status.entry(device_id.clone()).or_default();
+                        if is_on(new_value) {
+                            start(pump, guard, now);
+                        } else {
+                            stop(pump);
+                        }
+                    }
+                }
+                for (id, guard) in &config.pumps {
+                    if guard.sensor != *device_id || guard.key != *key {
+                        continue;
+                    }
+                    let pump = status.entry(id.clone()).or_default();
+                    pump.flowing = guard.flowing(new_value);
+                    if !pump.running {
+                        continue;
+                    }
+                    if pump.flowing {
+                        pump.stop_at = None;
+                    } else if pump.stop_at.is_none() {
+                        pump.stop_at = Some(now + Duration::seconds(guard.confirm_secs as i64));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Trips the pumps whose flow is overdue at `now` and returns them with
+    /// the command stopping them. Pumps that don't stop come up again
+    /// after a while.
+    fn due(&self, now: DateTime<Utc>) -> Vec<(String, String)> {
+        let config = self.config.read().unwrap();
+        let mut status = self.status.lock().unwrap();
+        let mut due = Vec::new();
+        for (id, guard) in &config.pumps {
+            let Some(pump) = status.get_mut(id) else {
+                continue;
+            };
+            if pump.stop_at.is_none_or(|at| at > now) {
+                continue;
+            }
+            pump.stop_at = Some(now + Duration::seconds(STOP_RETRY_SECS));
+            if pump.tripped.is_none() {
+                let reason = format!(
+                    "{} of '{}' didn't confirm flow within {} s",
+                    guard.key, guard.sensor, guard.confirm_secs
+                );
+                log::warn!("Stopping pump '{}': {}", id, reason);
+                let _ = self.events.publish(EventKind::Custom {
+                    name: TRIPPED_EVENT.to_string(),
+                    data: HashMap::from([
+                        ("pump".to_string(), id.clone()),
+                        ("sensor".to_string(), guard.sensor.clone()),
+                        ("reason".to_string(), reason.clone()),
+                    ]),
+                });
+                pump.tripped = Some(Trip { at: now, reason });
+            }
+            due.push((id.clone(), guard.stop_command.clone()));
+        }
+        due
+    }
+
+    /// Follows pumps on the bus and stops the overdue ones through `sender`
+    /// until the bus closes.
+    pub async fn run(self: Arc<Self>, sender: CommandSender) {
+        let mut receiver = self.events.subscribe();
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                received = receiver.recv() => match received {
+                    Ok(event) => self.observe(&event, Utc::now()),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tick.tick() => {
+                    for (pump, command) in self.due(Utc::now()) {
+                        let sender = sender.clone();
+                        tokio::task::spawn_blocking(move || {
+                            if let Err(e) = sender(&pump, &command, HashMap::new()) {
+                                log::error!("Failed to stop pump '{}': {}", pump, e);
+                            }
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn start(pump: &mut PumpStatus, guard: &PumpGuard, now: DateTime<Utc>) {
+    if pump.running {
+        return;
+    }
+    pump.running = true;
+    pump.stop_at = (!pump.flowing).then(|| now + Duration::seconds(guard.confirm_secs as i64));
+}
+
+fn stop(pump: &mut PumpStatus) {
+    pump.running = false;
+    pump.stop_at = None;
+}
@@ -0,0 +1,218 @@
+use super::error::BlinkieError;
+use super::notification::{Notification, NotificationChannel, Severity};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Path Telegram posts bot updates to, set up with the bot's `setWebhook`.
+pub const TELEGRAM_UPDATE_PATH: &str = "/api/notifications/telegram";
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+/**
+ * ChannelConfig
+ * A push service notifications are delivered through, as configured in
+ * `notification-channels.json` by channel name. The file holds
+ * credentials, so it isn't served by the API.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChannelConfig {
+    /// An ntfy topic. Actions become HTTP actions posting to their callback.
+    Ntfy {
+        #[serde(default = "default_ntfy_server")]
+        server: String,
+        topic: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// A Telegram chat of a bot. Actions become inline buttons; their
+    /// answers arrive as bot updates on `TELEGRAM_UPDATE_PATH`.
+    Telegram { bot_token: String, chat_id: String },
+}
+
+impl ChannelConfig {
+    /// Loads the channels kept at `path`. A missing file means there are none.
+    pub fn load(path: &Path) -> Result<HashMap<String, ChannelConfig>, BlinkieError> {
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+        })
+    }
+
+    pub fn build(self, name: &str) -> Arc<dyn NotificationChannel> {
+        match self {
+            ChannelConfig::Ntfy {
+                server,
+                topic,
+                token,
+            } => Arc::new(NtfyChannel {
+                name: name.to_string(),
+                server,
+                topic,
+                token,
+            }),
+            ChannelConfig::Telegram { bot_token, chat_id } => Arc::new(TelegramChannel {
+                name: name.to_string(),
+                bot_token,
+                chat_id,
+            }),
+        }
+    }
+}
+
+/// Posts `body` as JSON to `url` and fails on anything but a 2xx answer.
+fn post_json(url: &str, token: Option<&str>, body: &Value) -> Result<(), BlinkieError> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+    let mut request = client
+        .post(url)
+        .header("content-type", "application/json")
+        .body(body.to_string());
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map(|_| ())
+        .map_err(|e| BlinkieError::connection(format!("Failed to send notification: {}", e)))
+}
+
+/**
+ * NtfyChannel
+ */
+pub struct NtfyChannel {
+    name: String,
+    server: String,
+    topic: String,
+    token: Option<String>,
+}
+
+impl NotificationChannel for NtfyChannel {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), BlinkieError> {
+        let priority = match notification.severity {
+            Severity::Low => 2,
+            Severity::Normal => 3,
+            Severity::High => 4,
+            Severity::Critical => 5,
+        };
+        let actions: Vec<Value> = notification
+            .actions
+            .iter()
+            .filter_map(|action| {
+                Some(json!({
+                    "action": "http",
+                    "label": action.label,
+                    "url": action.callback_url.as_ref()?,
+                    "method": "POST",
+                    "clear": true,
+                }))
+            })
+            .collect();
+        let mut body = json!({
+            "topic": self.topic,
+            "title": notification.title,
+            "message": notification.message,
+            "priority": priority,
+            "actions": actions,
+        });
+        if let Some(url) = &notification.image_url {
+            body["attach"] = json!(url);
+        }
+        post_json(
+            self.server.trim_end_matches('/'),
+            self.token.as_deref(),
+            &body,
+        )
+    }
+}
+
+/**
+ * TelegramChannel
+ */
+pub struct TelegramChannel {
+    name: String,
+    bot_token: String,
+    chat_id: String,
+}
+
+impl NotificationChannel for TelegramChannel {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), BlinkieError> {
+        let text = match notification.message.as_str() {
+            "" => notification.title.clone(),
+            message => format!("{}\n{}", notification.title, message),
+        };
+        // Buttons carry the id of their webhook, the last part of the callback URL
+        let buttons: Vec<Value> = notification
+            .actions
+            .iter()
+            .filter_map(|action| {
+                let url = action.callback_url.as_ref()?;
+                let webhook = url.rsplit('/').next()?;
+                Some(json!({ "text": action.label, "callback_data": webhook }))
+            })
+            .collect();
+        let mut body = json!({
+            "chat_id": self.chat_id,
+            "disable_notification": notification.severity == Severity::Low,
+        });
+        if !buttons.is_empty() {
+            body["reply_markup"] = json!({ "inline_keyboard": [buttons] });
+        }
+        // Pictures go out as photos with the text as their caption
+        let method = match &notification.image_url {
+            Some(url) => {
+                body["photo"] = json!(url);
+                body["caption"] = json!(text);
+                "sendPhoto"
+            }
+            None => {
+                body["text"] = json!(text);
+                "sendMessage"
+            }
+        };
+        post_json(
+            &format!("https://api.telegram.org/bot{}/{}", self.bot_token, method),
+            None,
+            &body,
+        )
+    }
+}
+
+/// Reads the webhook id and who answered from a Telegram bot update, if it
+/// is the press of an inline button.
+pub fn telegram_callback(update: &Value) -> Option<(String, HashMap<String, String>)> {
+    let query = update.get("callback_query")?;
+    let webhook = query.get("data")?.as_str()?.to_string();
+    let mut payload = HashMap::new();
+    if let Some(user) = query
+        .get("from")
+        .and_then(|from| from.get("username").or_else(|| from.get("first_name")))
+        .and_then(Value::as_str)
+    {
+        payload.insert("user".to_string(), user.to_string());
+    }
+    Some((webhook, payload))
+}
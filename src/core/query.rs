@@ -0,0 +1,279 @@
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Rows returned when the caller does not ask for a limit.
+pub const DEFAULT_ROWS: usize = 1000;
+/// Upper bound on rows returned by a single query.
+pub const MAX_ROWS: usize = 10_000;
+/// Queries running longer than this are interrupted.
+const TIME_LIMIT: Duration = Duration::from_secs(5);
+
+/// Timestamps are stored in the format SQLite's date functions produce, so
+/// `timestamp > datetime('now', '-7 days')` works as expected.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+const SCHEMA: &str = "
+    CREATE TABLE events (
+        seq INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        type TEXT NOT NULL,
+        device_id TEXT,
+        person_id TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE states (
+        seq INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        key TEXT NOT NULL,
+        old_value TEXT,
+        new_value TEXT NOT NULL,
+        numeric_value REAL
+    );
+    CREATE INDEX states_device ON states (device_id, key, timestamp);
+";
+
+/**
+ * QueryRequest
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueryRequest {
+    pub sql: String,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/**
+ * QueryResult
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// Set when more rows matched than were returned.
+    pub truncated: bool,
+}
+
+struct QueryDb {
+    conn: Connection,
+    last_seq: u64,
+    rewrites: u64,
+}
+
+/**
+ * HistoryQuery
+ * Read-only SQL over the event history. Journaled events are mirrored into
+ * an in-memory SQLite database with two tables:
+ *
+ * - `events(seq, timestamp, type, device_id, person_id, data)` with every event
+ * - `states(seq, timestamp, device_id, key, old_value, new_value, numeric_value)`
+ *   with every state change
+ *
+ * Only single `SELECT` statements are accepted, and queries are cut off
+ * after a row and time limit.
+ */
+pub struct HistoryQuery {
+    events: Arc<EventBus>,
+    db: Mutex<QueryDb>,
+}
+
+impl HistoryQuery {
+    pub fn new(events: Arc<EventBus>) -> Result<Self, BlinkieError> {
+        let conn = open_db()?;
+        Ok(HistoryQuery {
+            events,
+            db: Mutex::new(QueryDb {
+                conn,
+                last_seq: 0,
+                rewrites: 0,
+            }),
+        })
+    }
+
+    pub fn query(&self, request: &QueryRequest) -> Result<QueryResult, BlinkieError> {
+        let limit = request.limit.unwrap_or(DEFAULT_ROWS).min(MAX_ROWS);
+        let mut db = self.db.lock().unwrap();
+        self.refresh(&mut db)?;
+
+        let started = Instant::now();
+        db.conn
+            .progress_handler(1000, Some(move || started.elapsed() > TIME_LIMIT));
+        let result = run_query(&db.conn, &request.sql, limit);
+        db.conn.progress_handler(0, None::<fn() -> bool>);
+        result.map_err(|e| {
+            if started.elapsed() > TIME_LIMIT {
+                BlinkieError::timeout(format!(
+                    "Query took longer than {} seconds",
+                    TIME_LIMIT.as_secs()
+                ))
+            } else {
+                e
+            }
+        })
+    }
+
+    /// Copies events journaled since the last query, starting over when
+    /// events were removed from the journal in the meantime.
+    fn refresh(&self, db: &mut QueryDb) -> Result<(), BlinkieError> {
+        let rewrites = self.events.rewrites();
+        if rewrites != db.rewrites {
+            db.conn = open_db()?;
+            db.last_seq = 0;
+            db.rewrites = rewrites;
+        }
+
+        let events = self.events.replay(db.last_seq + 1)?;
+        let tx = db
+            .conn
+            .transaction()
+            .map_err(|e| format!("Failed to update history index: {}", e))?;
+        for event in &events {
+            insert_event(&tx, event)
+                .map_err(|e| format!("Failed to update history index: {}", e))?;
+        }
+        tx.commit()
+            .map_err(|e| format!("Failed to update history index: {}", e))?;
+        if let Some(event) = events.last() {
+            db.last_seq = event.seq;
+        }
+        Ok(())
+    }
+}
+
+fn open_db() -> Result<Connection, BlinkieError> {
+    let conn =
+        Connection::open_in_memory().map_err(|e| format!("Failed to open history index: {}", e))?;
+    conn.execute_batch(SCHEMA)
+        .map_err(|e| format!("Failed to create history index: {}", e))?;
+    Ok(conn)
+}
+
+fn insert_event(conn: &Connection, event: &Event) -> rusqlite::Result<()> {
+    let timestamp = event.timestamp.format(TIMESTAMP_FORMAT).to_string();
+    let data = serde_json::to_value(&event.kind).unwrap_or_default();
+    let kind = data["type"].as_str().unwrap_or_default().to_string();
+    conn.execute(
+        "INSERT INTO events (seq, timestamp, type, device_id, person_id, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            event.seq as i64,
+            timestamp,
+            kind,
+            event.kind.device_id(),
+            event.kind.person_id(),
+            data.to_string()
+        ],
+    )?;
+    if let EventKind::StateChanged {
+        device_id,
+        key,
+        old_value,
+        new_value,
+        ..
+    } = &event.kind
+    {
+        conn.execute(
+            "INSERT INTO states (seq, timestamp, device_id, key, old_value, new_value, numeric_value) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                event.seq as i64,
+                timestamp,
+                device_id,
+                key,
+                old_value,
+                new_value,
+                new_value.parse::<f64>().ok()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Lets queries read and call functions, and nothing else.
+fn authorize(context: AuthContext<'_>) -> Authorization {
+    match context.action {
+        AuthAction::Select | AuthAction::Read { .. } | AuthAction::Recursive => {
+            Authorization::Allow
+        }
+        AuthAction::Function { function_name } if function_name != "load_extension" => {
+            Authorization::Allow
+        }
+        _ => Authorization::Deny,
+    }
+}
+
+fn run_query(conn: &Connection, sql: &str, limit: usize) -> Result<QueryResult, BlinkieError> {
+    if !single_statement(sql) {
+        return Err("Only a single statement is allowed".into());
+    }
+    conn.authorizer(Some(authorize));
+    let result = (|| {
+        let mut stmt = conn
+            .prepare(sql)
+            .map_err(|e| format!("Invalid query: {}", e))?;
+        if !stmt.readonly() {
+            return Err("Only SELECT queries are allowed".to_string());
+        }
+        let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+
+        let mut rows = stmt.query([]).map_err(|e| format!("Query failed: {}", e))?;
+        let mut result = QueryResult {
+            columns,
+            ..Default::default()
+        };
+        while let Some(row) = rows.next().map_err(|e| format!("Query failed: {}", e))? {
+            if result.rows.len() == limit {
+                result.truncated = true;
+                break;
+            }
+            let values = (0..result.columns.len())
+                .map(|index| row.get_ref(index).map(json_value))
+                .collect::<Result<Vec<Value>, _>>()
+                .map_err(|e| format!("Query failed: {}", e))?;
+            result.rows.push(values);
+        }
+        Ok(result)
+    })();
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    result.map_err(BlinkieError::from)
+}
+
+/// Checks that nothing but whitespace follows the first `;` outside of
+/// quotes and comments.
+fn single_statement(sql: &str) -> bool {
+    let mut chars = sql.chars().peekable();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '-') if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            (None, ';') => return chars.all(|c| c.is_whitespace() || c == ';'),
+            _ => {}
+        }
+    }
+    true
+}
+
+fn json_value(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => Value::from(f),
+        ValueRef::Text(text) => Value::from(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(_) => Value::Null,
+    }
+}
@@ -0,0 +1,323 @@
+use super::error::BlinkieError;
+use super::storage::xml_values;
+use reqwest::blocking::Client;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services of a gateway that can map ports.
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+const MAPPING_DESCRIPTION: &str = "blinkie";
+
+/**
+ * Gateway
+ * A router found through UPnP that maps ports from its external address
+ * to hosts on the local network.
+ */
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    /// Where the router's device description was found.
+    pub location: Url,
+    control_url: Url,
+    service: String,
+    /// Address of this host on the router's network.
+    pub local_ip: IpAddr,
+    client: Client,
+}
+
+/**
+ * PortMapping
+ * A TCP port forwarded by the router to this host.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub external_ip: Option<IpAddr>,
+    pub external_port: u16,
+    pub internal: SocketAddr,
+    /// Seconds until the router drops the mapping, 0 for never.
+    pub lease_secs: u32,
+}
+
+impl Gateway {
+    /// Looks for an Internet gateway on the local network, waiting up to
+    /// `timeout` for one to answer.
+    pub fn discover(timeout: Duration) -> Result<Self, BlinkieError> {
+        let socket =
+            UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Failed to open socket: {}", e))?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+            SSDP_ADDR, GATEWAY_DEVICE
+        );
+        socket
+            .send_to(search.as_bytes(), SSDP_ADDR)
+            .map_err(|e| format!("Failed to search for gateways: {}", e))?;
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; 2048];
+        let mut last_error = BlinkieError::connection("No UPnP gateway answered");
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            socket
+                .set_read_timeout(Some(left.max(Duration::from_millis(1))))
+                .map_err(|e| format!("Failed to wait for gateways: {}", e))?;
+            let Ok((len, _)) = socket.recv_from(&mut buf) else {
+                break;
+            };
+            let response = String::from_utf8_lossy(&buf[..len]);
+            let Some(location) = response.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("location")
+                    .then(|| value.trim().to_string())
+            }) else {
+                continue;
+            };
+            match Gateway::describe(&client, &location) {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Reads the device description at `location` for a service that maps
+    /// ports.
+    fn describe(client: &Client, location: &str) -> Result<Self, BlinkieError> {
+        let location =
+            Url::parse(location).map_err(|e| format!("Invalid gateway location: {}", e))?;
+        let description = client
+            .get(location.clone())
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| format!("Failed to read gateway description: {}", e))?;
+        let base = xml_values(&description, "URLBase")
+            .into_iter()
+            .next()
+            .and_then(|base| Url::parse(&base).ok())
+            .unwrap_or_else(|| location.clone());
+        // Every service lists its type before its control URL
+        let (service, control) = xml_values(&description, "serviceType")
+            .into_iter()
+            .zip(xml_values(&description, "controlURL"))
+            .find(|(service, _)| WAN_SERVICES.iter().any(|wan| service.starts_with(wan)))
+            .ok_or_else(|| format!("Gateway at {} can't map ports", location))?;
+        let control_url = base
+            .join(&control)
+            .map_err(|e| format!("Invalid gateway control URL: {}", e))?;
+
+        let host = location
+            .socket_addrs(|| Some(80))
+            .ok()
+            .and_then(|addrs| addrs.into_iter().next())
+            .ok_or_else(|| format!("Failed to resolve gateway {}", location))?;
+        let local_ip = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| {
+                socket.connect(host)?;
+                socket.local_addr()
+            })
+            .map_err(|e| format!("Failed to find local address: {}", e))?
+            .ip();
+        Ok(Gateway {
+            location,
+            control_url,
+            service,
+            local_ip,
+            client: client.clone(),
+        })
+    }
+
+    pub fn external_ip(&self) -> Result<IpAddr, BlinkieError> {
+        let response = self.call("GetExternalIPAddress", "")?;
+        xml_values(&response, "NewExternalIPAddress")
+            .into_iter()
+            .next()
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| "Gateway reported no external address".into())
+    }
+
+    /// Forwards `external_port` to `internal_port` on this host for
+    /// `lease_secs` seconds, or until removed when 0.
+    pub fn add_port_mapping(
+        &self,
+        external_port: u16,
+        internal_port: u16,
+        lease_secs: u32,
+    ) -> Result<PortMapping, BlinkieError> {
+        self.call(
+            "AddPortMapping",
+            &format!(
+                "<NewRemoteHost></NewRemoteHost>\
+                 <NewExternalPort>{}</NewExternalPort>\
+                 <NewProtocol>TCP</NewProtocol>\
+                 <NewInternalPort>{}</NewInternalPort>\
+                 <NewInternalClient>{}</NewInternalClient>\
+                 <NewEnabled>1</NewEnabled>\
+                 <NewPortMappingDescription>{}</NewPortMappingDescription>\
+                 <NewLeaseDuration>{}</NewLeaseDuration>",
+                external_port, internal_port, self.local_ip, MAPPING_DESCRIPTION, lease_secs
+            ),
+        )?;
+        Ok(PortMapping {
+            external_ip: self.external_ip().ok(),
+            external_port,
+            internal: SocketAddr::new(self.local_ip, internal_port),
+            lease_secs,
+        })
+    }
+
+    pub fn remove_port_mapping(&self, external_port: u16) -> Result<(), BlinkieError> {
+        self.call(
+            "DeletePortMapping",
+            &format!(
+                "<NewRemoteHost></NewRemoteHost>\
+                 <NewExternalPort>{}</NewExternalPort>\
+                 <NewProtocol>TCP</NewProtocol>",
+                external_port
+            ),
+        )
+        .map(|_| ())
+    }
+
+    /// Invokes a SOAP action of the gateway's WAN service.
+    fn call(&self, action: &str, arguments: &str) -> Result<String, BlinkieError> {
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body>\
+             </s:Envelope>",
+            action = action,
+            service = self.service,
+            arguments = arguments
+        );
+        let response = self
+            .client
+            .post(self.control_url.clone())
+            .header("content-type", "text/xml; charset=\"utf-8\"")
+            .header("soapaction", format!("\"{}#{}\"", self.service, action))
+            .body(body)
+            .send()
+            .map_err(|e| BlinkieError::connection(format!("Failed to reach gateway: {}", e)))?;
+        let status = response.status();
+        let text = response
+            .text()
+            .map_err(|e| format!("Failed to read gateway response: {}", e))?;
+        if !status.is_success() {
+            let reason = xml_values(&text, "errorDescription")
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| status.to_string());
+            return Err(format!("Gateway refused {}: {}", action, reason).into());
+        }
+        Ok(text)
+    }
+}
+
+/**
+ * WireGuardTunnel
+ * An outbound WireGuard tunnel from the hub to a relay with a public
+ * address, for networks where ports can't be mapped, e.g. behind carrier
+ * grade NAT. The hub keeps the tunnel open, so the relay can always reach
+ * the API on the hub's tunnel address.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WireGuardTunnel {
+    /// `host:port` the relay's WireGuard listens on.
+    pub relay_endpoint: String,
+    pub relay_public_key: String,
+    /// Tunnel address of the relay, e.g. `10.8.0.1/32`.
+    pub relay_address: String,
+    /// Tunnel address of the hub, e.g. `10.8.0.2/32`.
+    pub hub_address: String,
+}
+
+/**
+ * WireGuardConfig
+ * Generated configuration for both ends of a tunnel.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WireGuardConfig {
+    /// Complete configuration for the hub, e.g. `/etc/wireguard/blinkie.conf`.
+    pub hub: String,
+    /// `[Peer]` section to add to the relay's configuration.
+    pub relay_peer: String,
+    pub hub_public_key: String,
+}
+
+impl WireGuardTunnel {
+    /// Generates a key pair for the hub with the `wg` tool and the
+    /// configuration of both ends.
+    pub fn generate(&self) -> Result<WireGuardConfig, BlinkieError> {
+        let private_key = wg(&["genkey"], None)?;
+        let public_key = wg(&["pubkey"], Some(&private_key))?;
+        let hub = format!(
+            "[Interface]\n\
+             PrivateKey = {}\n\
+             Address = {}\n\
+             \n\
+             [Peer]\n\
+             PublicKey = {}\n\
+             Endpoint = {}\n\
+             AllowedIPs = {}\n\
+             # Keeps the tunnel open through NAT so the relay can reach the hub\n\
+             PersistentKeepalive = 25\n",
+            private_key,
+            self.hub_address,
+            self.relay_public_key,
+            self.relay_endpoint,
+            self.relay_address
+        );
+        let relay_peer = format!(
+            "[Peer]\n\
+             # blinkie hub\n\
+             PublicKey = {}\n\
+             AllowedIPs = {}\n",
+            public_key, self.hub_address
+        );
+        Ok(WireGuardConfig {
+            hub,
+            relay_peer,
+            hub_public_key: public_key,
+        })
+    }
+}
+
+/// Runs `wg` with `input` on stdin and returns what it printed.
+fn wg(args: &[&str], input: Option<&str>) -> Result<String, BlinkieError> {
+    let mut child = Command::new("wg")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run wg, is wireguard-tools installed? {}", e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| format!("Failed to write to wg: {}", e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run wg: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "wg {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,204 @@
+use super::error::BlinkieError;
+use crate::core::sniffer::{self, Direction, Frame, REDACTED};
+use crate::core::value::StateMap;
+use crate::handlers::{exec, subprocess};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/**
+ * Fixture
+ * A recorded protocol session: the frames a handler exchanged with its
+ * devices and the state the hub ended up with. Frames come from the
+ * sniffer, so secrets are already redacted and fixtures can be committed.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Fixture {
+    /// Decoder replaying the frames, see [`decoder`].
+    pub decoder: String,
+    /// Connection details decoding depends on, e.g. `state_format`, by
+    /// device id. Recording leaves them empty for the author to fill in.
+    #[serde(default)]
+    pub devices: BTreeMap<String, HashMap<String, String>>,
+    pub frames: Vec<Frame>,
+    /// State each device had when recording stopped.
+    #[serde(default)]
+    pub expected: BTreeMap<String, HashMap<String, String>>,
+}
+
+impl Fixture {
+    /// Builds a fixture from captured frames, expecting the current `states`
+    /// of the devices that appear in them. Secret values are redacted like
+    /// the frames are.
+    pub fn record(decoder: &str, frames: Vec<Frame>, states: &HashMap<String, StateMap>) -> Self {
+        let mut fixture = Fixture {
+            decoder: decoder.to_string(),
+            ..Default::default()
+        };
+        for device_id in frames.iter().filter_map(|frame| frame.device_id.as_ref()) {
+            fixture.devices.entry(device_id.clone()).or_default();
+            if let Some(state) = states.get(device_id) {
+                let state = state
+                    .iter()
+                    .map(|(key, value)| match sniffer::is_secret(key) {
+                        true => (key.clone(), REDACTED.to_string()),
+                        false => (key.clone(), value.to_string()),
+                    })
+                    .collect();
+                fixture.expected.insert(device_id.clone(), state);
+            }
+        }
+        fixture.frames = frames;
+        fixture
+    }
+
+    pub fn load(path: &Path) -> Result<Self, BlinkieError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read fixture {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents).map_err(|e| {
+            BlinkieError::serialization(format!(
+                "Failed to parse fixture {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize fixture: {}", e))
+        })?;
+        fs::write(path, contents + "\n")
+            .map_err(|e| format!("Failed to write fixture {}: {}", path.display(), e).into())
+    }
+}
+
+/**
+ * Decoder
+ * The part of a handler that turns a received frame into device state,
+ * runnable without a device or connection.
+ */
+pub trait Decoder {
+    /// State carried by `frame`, empty if it carries none.
+    fn decode(
+        &self,
+        frame: &Frame,
+        details: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, BlinkieError>;
+}
+
+/// Decoder by name: `exec`, `http`, `mqtt`, `serial` or `subprocess` for
+/// JSON-RPC handlers.
+pub fn decoder(name: &str) -> Result<Box<dyn Decoder>, BlinkieError> {
+    match name {
+        "exec" => Ok(Box::new(exec::ExecDecoder)),
+        "http" => Ok(Box::new(crate::handlers::http::HttpDecoder)),
+        #[cfg(feature = "mqtt")]
+        "mqtt" => Ok(Box::new(crate::handlers::mqtt::MqttDecoder)),
+        #[cfg(feature = "serial")]
+        "serial" => Ok(Box::new(crate::handlers::serial::SerialDecoder)),
+        "subprocess" => Ok(Box::new(subprocess::SubprocessDecoder)),
+        other => Err(format!("No decoder named '{}'", other).into()),
+    }
+}
+
+/**
+ * Mismatch
+ * A state value replaying produced that differs from the recorded one.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Mismatch {
+    pub device_id: String,
+    pub key: String,
+    pub expected: String,
+    pub actual: Option<String>,
+}
+
+/**
+ * ReplayReport
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReplayReport {
+    /// Received frames fed to the decoder.
+    pub frames: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// Frames the decoder rejected, with the reason.
+    pub errors: Vec<String>,
+}
+
+impl ReplayReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty() && self.errors.is_empty()
+    }
+}
+
+/// Feeds the received frames of `fixture` to `decoder` in order and compares
+/// the resulting state with the recorded one.
+pub fn replay(fixture: &Fixture, decoder: &dyn Decoder) -> ReplayReport {
+    let mut report = ReplayReport::default();
+    let mut states: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let no_details = HashMap::new();
+    for frame in &fixture.frames {
+        let (Direction::In, Some(device_id)) = (frame.direction, &frame.device_id) else {
+            continue;
+        };
+        report.frames += 1;
+        let details = fixture.devices.get(device_id).unwrap_or(&no_details);
+        match decoder.decode(frame, details) {
+            Ok(state) => states.entry(device_id.clone()).or_default().extend(state),
+            Err(e) => report
+                .errors
+                .push(format!("Frame {} of '{}': {}", frame.seq, device_id, e)),
+        }
+    }
+    for (device_id, expected) in &fixture.expected {
+        let mut keys: Vec<&String> = expected.keys().collect();
+        keys.sort();
+        for key in keys {
+            let actual = states.get(device_id).and_then(|state| state.get(key));
+            if actual != expected.get(key) {
+                report.mismatches.push(Mismatch {
+                    device_id: device_id.clone(),
+                    key: key.clone(),
+                    expected: expected[key].clone(),
+                    actual: actual.cloned(),
+                });
+            }
+        }
+    }
+    report
+}
+
+/// Replays the fixture at `path` with its decoder and fails with
+/// a description of every difference. Meant to be called from tests.
+pub fn verify(path: &Path) -> Result<ReplayReport, BlinkieError> {
+    let fixture = Fixture::load(path)?;
+    let report = replay(&fixture, decoder(&fixture.decoder)?.as_ref());
+    if report.passed() {
+        return Ok(report);
+    }
+    let problems: Vec<String> = report
+        .errors
+        .iter()
+        .cloned()
+        .chain(report.mismatches.iter().map(|mismatch| {
+            format!(
+                "{}.{}: expected '{}', got {}",
+                mismatch.device_id,
+                mismatch.key,
+                mismatch.expected,
+                mismatch
+                    .actual
+                    .as_ref()
+                    .map_or("nothing".to_string(), |actual| format!("'{}'", actual))
+            )
+        }))
+        .collect();
+    Err(format!(
+        "Replaying {} failed: {}",
+        path.display(),
+        problems.join("; ")
+    )
+    .into())
+}
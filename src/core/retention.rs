@@ -0,0 +1,92 @@
+use super::event::Event;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/**
+ * Data Categories
+ * Groups of recorded data that can be kept for different amounts of time.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataCategory {
+    Presence,
+    Location,
+    Sensor,
+    Command,
+    System,
+}
+
+/**
+ * RetentionPolicy
+ * How many days of history to keep, per category. Categories without an
+ * explicit entry fall back to `default_days`, e.g.
+ *
+ * ```toml
+ * [settings.retention]
+ * default_days = 365
+ * categories = { presence = 7, location = 7 }
+ * ```
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub default_days: u32,
+    #[serde(default)]
+    pub categories: HashMap<DataCategory, u32>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        // Where people are is more sensitive than what the thermostat said
+        let categories = HashMap::from([(DataCategory::Presence, 7), (DataCategory::Location, 7)]);
+        RetentionPolicy {
+            default_days: 30,
+            categories,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.default_days == 0 || self.categories.values().any(|days| *days == 0) {
+            return Err("history has to be kept for at least a day".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns how long data of the given category is kept.
+    pub fn retention_for(&self, category: DataCategory) -> Duration {
+        let days = self
+            .categories
+            .get(&category)
+            .copied()
+            .unwrap_or(self.default_days);
+        Duration::days(days as i64)
+    }
+
+    /// Checks whether an event has outlived its category's retention at `now`.
+    pub fn is_expired(&self, event: &Event, now: DateTime<Utc>) -> bool {
+        event.timestamp + self.retention_for(event.kind.category()) < now
+    }
+}
+
+/**
+ * Purge Targets
+ * Whose data a purge removes.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum PurgeTarget {
+    Device(String),
+    Person(String),
+}
+
+impl PurgeTarget {
+    /// Checks whether an event holds data about this target.
+    pub fn matches(&self, event: &Event) -> bool {
+        match self {
+            PurgeTarget::Device(id) => event.kind.device_id() == Some(id.as_str()),
+            PurgeTarget::Person(id) => event.kind.person_id() == Some(id.as_str()),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use super::device::{Capability, Type};
+use super::discovery::slug;
+use super::error::BlinkieError;
+use super::value::StateMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/**
+ * Scene
+ * States of a set of devices captured at one moment, e.g. "movie night"
+ * with the ceiling lights off and the lamps dimmed, that can be applied
+ * again. Unlike the scenes of the UI layout, which are lists of commands,
+ * these hold the states themselves.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scene {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// State to apply by device id.
+    pub states: BTreeMap<String, StateMap>,
+    #[serde(default = "Utc::now")]
+    pub captured_at: DateTime<Utc>,
+}
+
+impl Scene {
+    /// Checks that the scene sets something on known devices only.
+    pub fn validate(&self, device_ids: &[String]) -> Result<(), BlinkieError> {
+        if self.states.is_empty() {
+            return Err(format!("Scene '{}' has no devices", self.id).into());
+        }
+        match self.states.keys().find(|id| !device_ids.contains(id)) {
+            Some(unknown) => Err(format!("Unknown device '{}'", unknown).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * DeviceSelector
+ * Devices picked by id, by the groups they are members of, by the areas
+ * the UI layout puts them in, by their type, or by having all of the
+ * `capabilities`. Devices match if any of these pick them.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceSelector {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub areas: Vec<String>,
+    #[serde(default)]
+    pub types: Vec<Type>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+/**
+ * SceneStore
+ * Holds captured scenes, written back to `scenes.json` on every change.
+ */
+pub struct SceneStore {
+    path: PathBuf,
+    scenes: RwLock<BTreeMap<String, Scene>>,
+}
+
+impl SceneStore {
+    /// Opens the store, loading previously saved scenes if the file exists.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let scenes = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(SceneStore {
+            path,
+            scenes: RwLock::new(scenes),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Scene> {
+        self.scenes.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Scene> {
+        self.scenes.read().unwrap().get(id).cloned()
+    }
+
+    /// Validates a scene and adds or replaces it.
+    pub fn save(&self, scene: Scene, device_ids: &[String]) -> Result<(), BlinkieError> {
+        scene.validate(device_ids)?;
+        let mut scenes = self.scenes.write().unwrap();
+        scenes.insert(scene.id.clone(), scene);
+        self.persist(&scenes)
+    }
+
+    /// Validates a scene and adds it under an id made from its name, never
+    /// replacing another scene. Returns the scene with that id.
+    pub fn add(&self, mut scene: Scene, device_ids: &[String]) -> Result<Scene, BlinkieError> {
+        let mut scenes = self.scenes.write().unwrap();
+        let base = match slug(&scene.name) {
+            base if base.is_empty() => "scene".to_string(),
+            base => base,
+        };
+        scene.id = base.clone();
+        let mut n = 1;
+        while scenes.contains_key(&scene.id) {
+            n += 1;
+            scene.id = format!("{}-{}", base, n);
+        }
+        scene.validate(device_ids)?;
+        scenes.insert(scene.id.clone(), scene.clone());
+        self.persist(&scenes)?;
+        Ok(scene)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<Scene>, BlinkieError> {
+        let mut scenes = self.scenes.write().unwrap();
+        let removed = scenes.remove(id);
+        if removed.is_some() {
+            self.persist(&scenes)?;
+        }
+        Ok(removed)
+    }
+
+    fn persist(&self, scenes: &BTreeMap<String, Scene>) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(scenes).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize scenes: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+}
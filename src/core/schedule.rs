@@ -0,0 +1,289 @@
+use super::holiday::Workdays;
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+/// How far ahead to look for the next matching day before giving up.
+const SEARCH_DAYS: i64 = 400;
+
+/**
+ * Schedule Days
+ * Which days a schedule fires on.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", content = "days", rename_all = "snake_case")]
+pub enum ScheduleDays {
+    #[default]
+    Every,
+    Weekdays(Vec<Weekday>),
+    /// Working days according to the hub's holiday calendar.
+    Workdays,
+    /// Weekends and public holidays.
+    NonWorkdays,
+}
+
+impl ScheduleDays {
+    pub fn matches(&self, date: NaiveDate, workdays: &Workdays) -> bool {
+        match self {
+            ScheduleDays::Every => true,
+            ScheduleDays::Weekdays(days) => days.contains(&date.weekday()),
+            ScheduleDays::Workdays => workdays.is_workday(date),
+            ScheduleDays::NonWorkdays => !workdays.is_workday(date),
+        }
+    }
+}
+
+/**
+ * Schedule Context
+ * Hub-wide settings schedules are evaluated against.
+ */
+#[derive(Clone, Debug)]
+pub struct ScheduleContext {
+    pub timezone: Tz,
+    pub workdays: Workdays,
+}
+
+impl ScheduleContext {
+    pub fn new(timezone: Tz, workdays: Workdays) -> Self {
+        ScheduleContext { timezone, workdays }
+    }
+}
+
+/**
+ * Gap Policies
+ * What to do when the scheduled local time does not exist because the
+ * clocks jump forward (e.g. 02:30 on the spring DST change).
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapPolicy {
+    /// Don't run on that day.
+    Skip,
+    /// Run at the first local time after the gap.
+    #[default]
+    Shift,
+}
+
+/**
+ * Overlap Policies
+ * What to do when the scheduled local time happens twice because the clocks
+ * fall back (e.g. 02:30 on the autumn DST change).
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// Run only at the first occurrence.
+    #[default]
+    First,
+    /// Run only at the second occurrence.
+    Last,
+    /// Run at both occurrences.
+    Repeat,
+}
+
+/**
+ * Schedule
+ * A wall-clock time of day in a time zone. Schedules without their own
+ * `timezone` use the zone configured for the whole hub.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    pub at: NaiveTime,
+    #[serde(default)]
+    pub days: ScheduleDays,
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+    #[serde(default)]
+    pub on_gap: GapPolicy,
+    #[serde(default)]
+    pub on_overlap: OverlapPolicy,
+}
+
+impl Schedule {
+    pub fn new(at: NaiveTime) -> Self {
+        Schedule {
+            at,
+            days: ScheduleDays::Every,
+            timezone: None,
+            on_gap: GapPolicy::default(),
+            on_overlap: OverlapPolicy::default(),
+        }
+    }
+
+    /// Time zone this schedule is evaluated in.
+    pub fn zone(&self, context: &ScheduleContext) -> Tz {
+        self.timezone.unwrap_or(context.timezone)
+    }
+
+    /// Returns the first time strictly after `after` at which the schedule fires.
+    pub fn next_after(
+        &self,
+        after: DateTime<Utc>,
+        context: &ScheduleContext,
+    ) -> Option<DateTime<Utc>> {
+        let tz = self.zone(context);
+        // Start a day early: an occurrence on the previous local date can still
+        // lie in the future when the zone is behind UTC.
+        let start = after.with_timezone(&tz).date_naive() - Duration::days(1);
+
+        (0..SEARCH_DAYS)
+            .map(|offset| start + Duration::days(offset))
+            .filter(|date| self.days.matches(*date, &context.workdays))
+            .flat_map(|date| self.occurrences_on(date, tz))
+            .find(|time| *time > after)
+    }
+
+    /// Returns the instants at which the schedule fires on a local date,
+    /// applying the DST policies. Usually one, none for a skipped gap, two
+    /// for a repeated overlap.
+    pub fn occurrences_on(&self, date: NaiveDate, tz: Tz) -> Vec<DateTime<Utc>> {
+        let local = date.and_time(self.at);
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(time) => vec![time.with_timezone(&Utc)],
+            LocalResult::Ambiguous(first, last) => match self.on_overlap {
+                OverlapPolicy::First => vec![first.with_timezone(&Utc)],
+                OverlapPolicy::Last => vec![last.with_timezone(&Utc)],
+                OverlapPolicy::Repeat => vec![first.with_timezone(&Utc), last.with_timezone(&Utc)],
+            },
+            LocalResult::None => match self.on_gap {
+                GapPolicy::Skip => Vec::new(),
+                GapPolicy::Shift => {
+                    // Walk forward to the first wall-clock minute that exists again
+                    (1..=24 * 60)
+                        .map(|minutes| local + Duration::minutes(minutes))
+                        .find_map(|shifted| tz.from_local_datetime(&shifted).earliest())
+                        .map(|time| vec![time.with_timezone(&Utc)])
+                        .unwrap_or_default()
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::{America, Europe};
+
+    fn at(hour: u32, minute: u32) -> Schedule {
+        Schedule::new(NaiveTime::from_hms_opt(hour, minute, 0).unwrap())
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    /// Clocks in Berlin jump from 02:00 to 03:00.
+    fn spring() -> NaiveDate {
+        date(2026, 3, 29)
+    }
+
+    /// Clocks in Berlin fall back from 03:00 to 02:00.
+    fn autumn() -> NaiveDate {
+        date(2026, 10, 25)
+    }
+
+    #[test]
+    fn spring_gap_is_skipped() {
+        let schedule = Schedule {
+            on_gap: GapPolicy::Skip,
+            ..at(2, 30)
+        };
+        assert!(schedule.occurrences_on(spring(), Europe::Berlin).is_empty());
+        let context = ScheduleContext::new(Europe::Berlin, Workdays::default());
+        assert_eq!(
+            schedule.next_after(utc(2026, 3, 28, 12, 0), &context),
+            Some(utc(2026, 3, 30, 0, 30))
+        );
+    }
+
+    #[test]
+    fn spring_gap_is_shifted_past_the_jump() {
+        let schedule = Schedule {
+            on_gap: GapPolicy::Shift,
+            ..at(2, 30)
+        };
+        assert_eq!(
+            schedule.occurrences_on(spring(), Europe::Berlin),
+            vec![utc(2026, 3, 29, 1, 0)]
+        );
+        let context = ScheduleContext::new(Europe::Berlin, Workdays::default());
+        assert_eq!(
+            schedule.next_after(utc(2026, 3, 28, 12, 0), &context),
+            Some(utc(2026, 3, 29, 1, 0))
+        );
+    }
+
+    #[test]
+    fn autumn_overlap_runs_first_occurrence() {
+        let schedule = Schedule {
+            on_overlap: OverlapPolicy::First,
+            ..at(2, 30)
+        };
+        assert_eq!(
+            schedule.occurrences_on(autumn(), Europe::Berlin),
+            vec![utc(2026, 10, 25, 0, 30)]
+        );
+    }
+
+    #[test]
+    fn autumn_overlap_runs_last_occurrence() {
+        let schedule = Schedule {
+            on_overlap: OverlapPolicy::Last,
+            ..at(2, 30)
+        };
+        assert_eq!(
+            schedule.occurrences_on(autumn(), Europe::Berlin),
+            vec![utc(2026, 10, 25, 1, 30)]
+        );
+    }
+
+    #[test]
+    fn autumn_overlap_repeats() {
+        let schedule = Schedule {
+            on_overlap: OverlapPolicy::Repeat,
+            ..at(2, 30)
+        };
+        assert_eq!(
+            schedule.occurrences_on(autumn(), Europe::Berlin),
+            vec![utc(2026, 10, 25, 0, 30), utc(2026, 10, 25, 1, 30)]
+        );
+        let context = ScheduleContext::new(Europe::Berlin, Workdays::default());
+        let first = schedule
+            .next_after(utc(2026, 10, 24, 12, 0), &context)
+            .unwrap();
+        assert_eq!(first, utc(2026, 10, 25, 0, 30));
+        assert_eq!(
+            schedule.next_after(first, &context),
+            Some(utc(2026, 10, 25, 1, 30))
+        );
+    }
+
+    #[test]
+    fn schedule_timezone_overrides_hub_timezone() {
+        let context = ScheduleContext::new(Europe::Berlin, Workdays::default());
+        let hub = at(8, 0);
+        let own = Schedule {
+            timezone: Some(America::New_York),
+            ..at(8, 0)
+        };
+        assert_eq!(hub.zone(&context), Europe::Berlin);
+        assert_eq!(own.zone(&context), America::New_York);
+        let after = utc(2026, 1, 15, 0, 0);
+        assert_eq!(
+            hub.next_after(after, &context),
+            Some(utc(2026, 1, 15, 7, 0))
+        );
+        assert_eq!(
+            own.next_after(after, &context),
+            Some(utc(2026, 1, 15, 13, 0))
+        );
+    }
+}
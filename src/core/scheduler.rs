@@ -0,0 +1,733 @@
+use super::async_device::{AsyncExecutor, AsyncRegistry};
+use super::device::{Action, DeviceList, Executor};
+use super::error::BlinkieError;
+use super::holiday::{SharedWorkdays, Workdays};
+use super::lease::LeaseManager;
+use super::schedule::{Schedule, ScheduleContext, ScheduleDays};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// How many days ahead to look for the next match of a cron expression,
+/// enough for one on the 29th of February.
+const CRON_SEARCH_DAYS: i64 = 366 * 5;
+
+/// How many days ahead to look for the next sunrise or sunset, enough to
+/// get through a polar night.
+const SUN_SEARCH_DAYS: i64 = 200;
+
+/// Longest the run loop sleeps without a job due, so clock changes are
+/// noticed.
+const MAX_SLEEP_SECS: i64 = 60;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/**
+ * Cron
+ * A parsed five-field cron expression: minute, hour, day of month, month
+ * and day of week, with lists, ranges, steps and month and weekday names,
+ * e.g. `0,30 6-22 * * mon-fri`. Also takes `@hourly`, `@daily`,
+ * `@weekly`, `@monthly` and `@yearly`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days: BTreeSet<u32>,
+    months: BTreeSet<u32>,
+    weekdays: BTreeSet<u32>,
+    /// Whether day of month and day of week are left open. When both are
+    /// restricted a day matching either one fires, as in cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, BlinkieError> {
+        let expanded = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "Invalid cron expression '{}': expected 5 fields, got {}",
+                expression,
+                fields.len()
+            )
+            .into());
+        };
+        let invalid = |e: String| format!("Invalid cron expression '{}': {}", expression, e);
+        Ok(Cron {
+            minutes: field(minute, 0, 59, &[])
+                .map_err(invalid)?
+                .into_iter()
+                .collect(),
+            hours: field(hour, 0, 23, &[])
+                .map_err(invalid)?
+                .into_iter()
+                .collect(),
+            days: field(day, 1, 31, &[]).map_err(invalid)?,
+            months: field(month, 1, 12, &MONTHS).map_err(invalid)?,
+            weekdays: field(weekday, 0, 7, &WEEKDAYS)
+                .map_err(invalid)?
+                .into_iter()
+                .map(|day| day % 7)
+                .collect(),
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !self.months.contains(&date.month()) {
+            return false;
+        }
+        let day = self.days.contains(&date.day());
+        let weekday = self
+            .weekdays
+            .contains(&date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// Returns the first time strictly after `after` the expression matches
+    /// in `tz`. Local times skipped by a DST change don't fire, ones
+    /// happening twice fire once.
+    pub fn next_after(&self, after: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let start = after.with_timezone(&tz).date_naive();
+        for offset in 0..CRON_SEARCH_DAYS {
+            let date = start + Duration::days(offset);
+            if !self.matches_date(date) {
+                continue;
+            }
+            for &hour in &self.hours {
+                for &minute in &self.minutes {
+                    let Some(local) = date.and_hms_opt(hour, minute, 0) else {
+                        continue;
+                    };
+                    let Some(at) = tz.from_local_datetime(&local).earliest() else {
+                        continue;
+                    };
+                    let at = at.with_timezone(&Utc);
+                    if at > after {
+                        return Some(at);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Values of one cron field between `min` and `max`, with `names` standing
+/// for the values from `min` up.
+fn field(text: &str, min: u32, max: u32, names: &[&str]) -> Result<BTreeSet<u32>, String> {
+    let value = |part: &str| -> Result<u32, String> {
+        let lower = part.to_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(index) => min + index as u32,
+            None => part
+                .parse::<u32>()
+                .map_err(|_| format!("'{}' is not a number", part))?,
+        };
+        if value < min || value > max {
+            return Err(format!("{} is out of range {}-{}", value, min, max));
+        }
+        Ok(value)
+    };
+    let mut values = BTreeSet::new();
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step '{}'", step))?;
+                (range, Some(step))
+            }
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            None => {
+                let first = value(range)?;
+                (first, if step.is_some() { max } else { first })
+            }
+        };
+        if first > last {
+            return Err(format!("empty range '{}'", range));
+        }
+        values.extend((first..=last).step_by(step.unwrap_or(1) as usize));
+    }
+    Ok(values)
+}
+
+/**
+ * Location
+ * Where the hub is, for sunrise and sunset.
+ */
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/**
+ * Sun Events
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+/// Time of `event` on `date` at `location`, or `None` on days the sun
+/// doesn't rise or set there. Follows the sunrise equation with the usual
+/// correction for refraction and the sun's disc, good to about a minute.
+pub fn sun_time(date: NaiveDate, location: Location, event: SunEvent) -> Option<DateTime<Utc>> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let days = (date - epoch).num_days() as f64;
+    let noon = days - location.longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = 2451545.0 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * longitude).sin();
+    let declination = (longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour) {
+        return None;
+    }
+    let hour = cos_hour.acos() * 180.0 / PI / 360.0;
+    let julian = match event {
+        SunEvent::Sunrise => transit - hour,
+        SunEvent::Sunset => transit + hour,
+    };
+    let millis = ((julian - 2440587.5) * 86_400_000.0).round() as i64;
+    DateTime::from_timestamp_millis(millis)
+}
+
+/**
+ * Timing
+ * When a scheduled job runs.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Timing {
+    /// Whenever a cron expression matches, in `timezone` or the
+    /// scheduler's.
+    Cron {
+        expression: String,
+        #[serde(default)]
+        timezone: Option<Tz>,
+    },
+    /// Every `every_secs` seconds, counted from the Unix epoch so e.g.
+    /// every 15 minutes runs on the quarter hour.
+    Interval { every_secs: u64 },
+    /// At a local time of day.
+    At(Schedule),
+    /// At sunrise or sunset, shifted by `offset_secs`, e.g. -1800 for half
+    /// an hour before. Needs the scheduler's location.
+    Sun {
+        event: SunEvent,
+        #[serde(default)]
+        offset_secs: i64,
+        #[serde(default)]
+        days: ScheduleDays,
+    },
+}
+
+impl Timing {
+    /// Problems keeping the timing from ever firing.
+    pub fn validate(&self, location: Option<Location>) -> Result<(), BlinkieError> {
+        match self {
+            Timing::Cron { expression, .. } => Cron::parse(expression).map(|_| ()),
+            Timing::Interval { every_secs: 0 } => Err("Interval must be at least 1 second".into()),
+            Timing::Sun { .. } if location.is_none() => {
+                Err("Sunrise and sunset schedules need the scheduler's location".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the first time strictly after `after` the timing fires.
+    pub fn next_after(
+        &self,
+        after: DateTime<Utc>,
+        context: &ScheduleContext,
+        location: Option<Location>,
+    ) -> Option<DateTime<Utc>> {
+        match self {
+            Timing::Cron {
+                expression,
+                timezone,
+            } => Cron::parse(expression)
+                .ok()?
+                .next_after(after, timezone.unwrap_or(context.timezone)),
+            Timing::Interval { every_secs } => {
+                let every = (*every_secs).max(1) as i64;
+                let next = (after.timestamp().div_euclid(every) + 1) * every;
+                DateTime::from_timestamp(next, 0)
+            }
+            Timing::At(schedule) => schedule.next_after(after, context),
+            Timing::Sun {
+                event,
+                offset_secs,
+                days,
+            } => {
+                let location = location?;
+                // Start a day early: with an offset, an event on the
+                // previous local date can still be ahead.
+                let start = after.with_timezone(&context.timezone).date_naive() - Duration::days(1);
+                (0..SUN_SEARCH_DAYS)
+                    .map(|offset| start + Duration::days(offset))
+                    .filter(|date| days.matches(*date, &context.workdays))
+                    .filter_map(|date| sun_time(date, location, *event))
+                    .map(|at| at + Duration::seconds(*offset_secs))
+                    .find(|at| *at > after)
+            }
+        }
+    }
+}
+
+/**
+ * ScheduledAction
+ * A job of the scheduler's config: an action run on a device.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduledAction {
+    pub timing: Timing,
+    pub device_id: String,
+    pub action: Action,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/**
+ * SchedulerConfig
+ * Scheduled actions by name, with the location and the calendar of working
+ * days their timings are evaluated with, kept as `schedules.json`, e.g.
+ *
+ * ```json
+ * {
+ *   "location": { "latitude": 52.52, "longitude": 13.4 },
+ *   "workdays": { "country": "DE" },
+ *   "jobs": {
+ *     "porch-light-on": {
+ *       "timing": { "type": "sun", "event": "sunset" },
+ *       "device_id": "porch-light",
+ *       "action": "TurnOn"
+ *     }
+ *   }
+ * }
+ * ```
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SchedulerConfig {
+    #[serde(default)]
+    pub location: Option<Location>,
+    /// Weekdays and holidays of the hub, for `workdays` timings and
+    /// anything else going by working days.
+    #[serde(default)]
+    pub workdays: Workdays,
+    #[serde(default)]
+    pub jobs: HashMap<String, ScheduledAction>,
+}
+
+impl SchedulerConfig {
+    /// Fails naming the first job whose timing can never fire.
+    pub fn validate(&self) -> Result<(), BlinkieError> {
+        for (name, job) in &self.jobs {
+            job.timing
+                .validate(self.location)
+                .map_err(|e| format!("Job '{}': {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * JobStatus
+ * A scheduled job, when it runs next and how its last run went.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub timing: Timing,
+    pub device_id: String,
+    pub enabled: bool,
+    /// Whether the job was registered in code rather than configured.
+    pub registered: bool,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub runs: u64,
+}
+
+/// What a job does when it runs.
+#[derive(Clone)]
+enum Task {
+    Action(Action),
+    Executor(Arc<dyn Executor + Send + Sync>),
+}
+
+#[derive(Clone)]
+struct Job {
+    timing: Timing,
+    device_id: String,
+    task: Task,
+    enabled: bool,
+    registered: bool,
+    next_run: Option<DateTime<Utc>>,
+    last_run: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    runs: u64,
+}
+
+impl Job {
+    fn status(&self) -> JobStatus {
+        JobStatus {
+            timing: self.timing.clone(),
+            device_id: self.device_id.clone(),
+            enabled: self.enabled,
+            registered: self.registered,
+            next_run: self.next_run,
+            last_run: self.last_run,
+            last_error: self.last_error.clone(),
+            runs: self.runs,
+        }
+    }
+}
+
+/**
+ * Scheduler
+ * Runs actions, or any executor registered in code, on devices at cron,
+ * interval, time-of-day and sunrise or sunset timings in the hub's time
+ * zone. Started and stopped with the app; jobs don't catch up on runs
+ * missed while stopped.
+ */
+pub struct Scheduler {
+    path: PathBuf,
+    timezone: Tz,
+    workdays: SharedWorkdays,
+    config: RwLock<SchedulerConfig>,
+    jobs: Mutex<HashMap<String, Job>>,
+    devices: DeviceList,
+    async_devices: Arc<AsyncRegistry>,
+    leases: Arc<LeaseManager>,
+    wake: Notify,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        timezone: Tz,
+        workdays: SharedWorkdays,
+        devices: DeviceList,
+        async_devices: Arc<AsyncRegistry>,
+        leases: Arc<LeaseManager>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            SchedulerConfig::default()
+        };
+        let scheduler = Scheduler {
+            path,
+            timezone,
+            workdays,
+            config: RwLock::new(SchedulerConfig::default()),
+            jobs: Mutex::new(HashMap::new()),
+            devices,
+            async_devices,
+            leases,
+            wake: Notify::new(),
+            task: Mutex::new(None),
+        };
+        scheduler.apply(config);
+        Ok(scheduler)
+    }
+
+    pub fn config(&self) -> SchedulerConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the configured jobs, keeping the ones registered in code
+    /// unless a configured job takes their name. Fails without changing
+    /// anything if a timing can never fire.
+    pub fn set_config(&self, config: SchedulerConfig) -> Result<(), BlinkieError> {
+        config.validate()?;
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize schedules: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        self.apply(config);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// What timings are evaluated against: the hub's time zone and
+    /// calendar.
+    fn context(&self) -> ScheduleContext {
+        ScheduleContext::new(self.timezone, self.workdays.read().unwrap().clone())
+    }
+
+    /// Switches to `config`, keeping the run history of jobs that stay.
+    fn apply(&self, config: SchedulerConfig) {
+        *self.workdays.write().unwrap() = config.workdays.clone();
+        let context = self.context();
+        let now = Utc::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut previous: HashMap<String, Job> = jobs
+            .extract_if(|name, job| !job.registered || config.jobs.contains_key(name))
+            .collect();
+        for (name, scheduled) in &config.jobs {
+            let (last_run, last_error, runs) = previous
+                .remove(name)
+                .map(|job| (job.last_run, job.last_error, job.runs))
+                .unwrap_or_default();
+            jobs.insert(
+                name.clone(),
+                Job {
+                    timing: scheduled.timing.clone(),
+                    device_id: scheduled.device_id.clone(),
+                    task: Task::Action(scheduled.action.clone()),
+                    enabled: scheduled.enabled,
+                    registered: false,
+                    next_run: scheduled.timing.next_after(now, &context, config.location),
+                    last_run,
+                    last_error,
+                    runs,
+                },
+            );
+        }
+        for job in jobs.values_mut().filter(|job| job.registered) {
+            job.next_run = job.timing.next_after(now, &context, config.location);
+        }
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Runs `executor` on `device_id` at `timing` as job `name`, replacing
+    /// any job of that name until the config names one. Registered jobs
+    /// aren't saved.
+    pub fn schedule(
+        &self,
+        name: &str,
+        timing: Timing,
+        device_id: &str,
+        executor: Arc<dyn Executor + Send + Sync>,
+    ) -> Result<(), BlinkieError> {
+        let config = self.config.read().unwrap();
+        timing.validate(config.location)?;
+        let context = self.context();
+        let next_run = timing.next_after(Utc::now(), &context, config.location);
+        self.jobs.lock().unwrap().insert(
+            name.to_string(),
+            Job {
+                timing,
+                device_id: device_id.to_string(),
+                task: Task::Executor(executor),
+                enabled: true,
+                registered: true,
+                next_run,
+                last_run: None,
+                last_error: None,
+                runs: 0,
+            },
+        );
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Removes a job registered with `schedule`. Returns whether there was
+    /// one.
+    pub fn unschedule(&self, name: &str) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get(name) {
+            Some(job) if job.registered => {
+                jobs.remove(name);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Every job by name.
+    pub fn status(&self) -> HashMap<String, JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, job)| (name.clone(), job.status()))
+            .collect()
+    }
+
+    /// Runs job `name` now, without moving its next run. Returns `None`
+    /// for an unknown job.
+    pub async fn run_now(&self, name: &str) -> Option<JobStatus> {
+        let job = self.jobs.lock().unwrap().get(name).cloned()?;
+        let result = self.execute(&job).await;
+        self.record(name, Utc::now(), result, None)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.task
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+    }
+
+    /// Starts running jobs on the current runtime, unless already running.
+    pub fn start(self: &Arc<Self>) {
+        let mut task = self.task.lock().unwrap();
+        if task.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+        let now = Utc::now();
+        let config = self.config();
+        let context = self.context();
+        for job in self.jobs.lock().unwrap().values_mut() {
+            job.next_run = job.timing.next_after(now, &context, config.location);
+        }
+        *task = Some(tokio::spawn(self.clone().run()));
+    }
+
+    /// Stops running jobs. Runs already started finish.
+    pub fn stop(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+
+    async fn run(self: Arc<Self>) {
+        loop {
+            let now = Utc::now();
+            let due: Vec<(String, Job)> = self
+                .jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, job)| job.enabled && job.next_run.is_some_and(|at| at <= now))
+                .map(|(name, job)| (name.clone(), job.clone()))
+                .collect();
+            let config = self.config();
+            let context = self.context();
+            for (name, job) in due {
+                // A run that was due while the hub slept or the clock jumped
+                // happens once, then the job picks up from now.
+                let next_run = job.timing.next_after(now, &context, config.location);
+                let result = self.execute(&job).await;
+                if let Err(e) = &result {
+                    log::warn!("Scheduled job '{}' failed: {}", name, e);
+                }
+                self.record(&name, now, result, Some(next_run));
+            }
+
+            let next = self
+                .jobs
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|job| job.enabled)
+                .filter_map(|job| job.next_run)
+                .min();
+            let wait = next
+                .map(|at| at - Utc::now())
+                .unwrap_or(Duration::seconds(MAX_SLEEP_SECS))
+                .clamp(Duration::zero(), Duration::seconds(MAX_SLEEP_SECS));
+            tokio::select! {
+                _ = tokio::time::sleep(wait.to_std().unwrap_or_default()) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+
+    async fn execute(&self, job: &Job) -> Result<(), BlinkieError> {
+        self.leases.check(&job.device_id, None)?;
+        if let Some(device) = self.async_devices.device(&job.device_id) {
+            return match &job.task {
+                Task::Action(action) => action.execute_async(device.as_ref()).await,
+                Task::Executor(_) => Err(BlinkieError::unsupported_command(
+                    &job.device_id,
+                    "scheduled executor",
+                )),
+            };
+        }
+        let devices = self.devices.clone();
+        let device_id = job.device_id.clone();
+        let task = job.task.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut devices = devices.write().unwrap();
+            let device = devices
+                .iter_mut()
+                .find(|device| device.get_id() == device_id)
+                .ok_or_else(|| format!("Device '{}' not found", device_id))?;
+            match &task {
+                Task::Action(action) => action.execute(device.as_mut()),
+                Task::Executor(executor) => executor.execute(device.as_mut()),
+            }
+        })
+        .await
+        .map_err(|e| BlinkieError::Other(e.to_string()))?
+    }
+
+    /// Notes a run of job `name` at `at`, moving its next run if given.
+    fn record(
+        &self,
+        name: &str,
+        at: DateTime<Utc>,
+        result: Result<(), BlinkieError>,
+        next_run: Option<Option<DateTime<Utc>>>,
+    ) -> Option<JobStatus> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(name)?;
+        job.last_run = Some(at);
+        job.last_error = result.err().map(|e| e.to_string());
+        job.runs += 1;
+        if let Some(next_run) = next_run {
+            job.next_run = next_run;
+        }
+        Some(job.status())
+    }
+}
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex};
+
+pub const DEFAULT_CAPACITY: usize = 2000;
+/// Frames are cut to this many bytes.
+const MAX_FRAME_BYTES: usize = 4096;
+pub const REDACTED: &str = "***";
+/// Field names whose values are redacted.
+const SECRET_KEYS: &str = r"password|passwd|pass|secret|token|api_?key|authorization|auth";
+
+/// Whether values stored under `key` are redacted, e.g. `wifi_password`.
+pub fn is_secret(key: &str) -> bool {
+    static PATTERN: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(&format!(r"(?i)^\w*(?:{})$", SECRET_KEYS)).unwrap());
+    PATTERN.is_match(key)
+}
+
+/**
+ * Frame Directions
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Received from the device or service.
+    In,
+    /// Sent to the device or service.
+    Out,
+}
+
+/**
+ * Frame
+ * A raw protocol message as a handler saw it, with secrets redacted.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Frame {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub handler: String,
+    pub device_id: Option<String>,
+    pub direction: Direction,
+    pub data: String,
+}
+
+/**
+ * SnifferStatus
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnifferStatus {
+    pub enabled: bool,
+    /// When capturing stops by itself.
+    pub until: Option<DateTime<Utc>>,
+    pub frames: usize,
+    pub capacity: usize,
+}
+
+struct Capture {
+    frames: VecDeque<Frame>,
+    next_seq: u64,
+    until: Option<DateTime<Utc>>,
+}
+
+/**
+ * Sniffer
+ * Debug ring buffer handlers write raw protocol frames to: MQTT payloads,
+ * script output, JSON-RPC lines. Off unless enabled, and enabling is always
+ * time limited so a forgotten debug session doesn't keep recording.
+ * Values of password, token and similar fields are redacted before a frame
+ * is stored.
+ */
+pub struct Sniffer {
+    capture: Mutex<Capture>,
+    capacity: usize,
+    redactions: Vec<Regex>,
+}
+
+impl Default for Sniffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Sniffer {
+    pub fn new(capacity: usize) -> Self {
+        let keys = SECRET_KEYS;
+        Sniffer {
+            capture: Mutex::new(Capture {
+                frames: VecDeque::new(),
+                next_seq: 1,
+                until: None,
+            }),
+            capacity,
+            redactions: vec![
+                // Authorization: Bearer value
+                Regex::new(r"(?i)(bearer\s+)[^\s]+()").unwrap(),
+                // "password": "value" in JSON
+                Regex::new(&format!(r#"(?i)("\w*(?:{})"\s*:\s*")[^"]*(")"#, keys)).unwrap(),
+                // password=value in query strings, environments and lines
+                Regex::new(&format!(r"(?i)\b(\w*(?:{})\s*[=:]\s*)[^\s&,;]+()", keys)).unwrap(),
+            ],
+        }
+    }
+
+    /// Starts capturing for `duration`.
+    pub fn enable(&self, duration: Duration) {
+        self.capture.lock().unwrap().until = Some(Utc::now() + duration);
+    }
+
+    pub fn disable(&self) {
+        self.capture.lock().unwrap().until = None;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capture
+            .lock()
+            .unwrap()
+            .until
+            .is_some_and(|until| until > Utc::now())
+    }
+
+    pub fn status(&self) -> SnifferStatus {
+        let capture = self.capture.lock().unwrap();
+        let until = capture.until.filter(|until| *until > Utc::now());
+        SnifferStatus {
+            enabled: until.is_some(),
+            until,
+            frames: capture.frames.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// Stores a frame if capturing. Cheap when not.
+    pub fn record(
+        &self,
+        handler: &str,
+        device_id: Option<&str>,
+        direction: Direction,
+        data: &[u8],
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let data = String::from_utf8_lossy(&data[..data.len().min(MAX_FRAME_BYTES)]);
+        let data = self.redact(&data);
+        let mut capture = self.capture.lock().unwrap();
+        let seq = capture.next_seq;
+        capture.next_seq += 1;
+        capture.frames.push_back(Frame {
+            seq,
+            timestamp: Utc::now(),
+            handler: handler.to_string(),
+            device_id: device_id.map(str::to_string),
+            direction,
+            data,
+        });
+        while capture.frames.len() > self.capacity {
+            capture.frames.pop_front();
+        }
+    }
+
+    /// Frames after sequence number `after`, optionally of a single device,
+    /// oldest first and at most `limit` of the newest.
+    pub fn frames(&self, device_id: Option<&str>, after: u64, limit: usize) -> Vec<Frame> {
+        let capture = self.capture.lock().unwrap();
+        let mut frames: Vec<Frame> = capture
+            .frames
+            .iter()
+            .rev()
+            .filter(|frame| frame.seq > after)
+            .filter(|frame| device_id.is_none() || frame.device_id.as_deref() == device_id)
+            .take(limit)
+            .cloned()
+            .collect();
+        frames.reverse();
+        frames
+    }
+
+    pub fn clear(&self) {
+        self.capture.lock().unwrap().frames.clear();
+    }
+
+    /// Drops the captured frames of a device.
+    pub fn forget(&self, device_id: &str) {
+        self.capture
+            .lock()
+            .unwrap()
+            .frames
+            .retain(|frame| frame.device_id.as_deref() != Some(device_id));
+    }
+
+    /// Replaces the values of secret-looking fields.
+    pub fn redact(&self, data: &str) -> String {
+        self.redactions
+            .iter()
+            .fold(data.to_string(), |data, pattern| {
+                pattern
+                    .replace_all(&data, format!("${{1}}{}${{2}}", REDACTED))
+                    .into_owned()
+            })
+    }
+}
@@ -0,0 +1,107 @@
+use super::device::Config;
+use super::error::BlinkieError;
+use super::sniffer::{is_secret, REDACTED};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Snippet format written by this version; newer ones are refused.
+pub const SNIPPET_VERSION: u32 = 1;
+
+fn default_version() -> u32 {
+    SNIPPET_VERSION
+}
+
+/**
+ * DeviceSnippet
+ * A device's config and customizations to share with other hubs. Secret
+ * connection details are redacted and listed in `secrets`, so whoever
+ * imports the snippet supplies their own.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSnippet {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    pub device: Config,
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Connection details whose values were left out.
+    #[serde(default)]
+    pub secrets: Vec<String>,
+}
+
+impl DeviceSnippet {
+    /// Builds a snippet of `config`, redacting its secrets.
+    pub fn new(mut config: Config, icon: Option<String>) -> Self {
+        let mut secrets = Vec::new();
+        for (key, value) in config.connection_details.iter_mut() {
+            if is_secret(key) {
+                *value = REDACTED.to_string();
+                secrets.push(key.clone());
+            }
+        }
+        secrets.sort();
+        DeviceSnippet {
+            version: SNIPPET_VERSION,
+            device: config,
+            icon,
+            secrets,
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, BlinkieError> {
+        let snippet: DeviceSnippet = serde_json::from_str(contents).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse device snippet: {}", e))
+        })?;
+        if snippet.version > SNIPPET_VERSION {
+            return Err(format!(
+                "Device snippet version {} is newer than the supported {}",
+                snippet.version, SNIPPET_VERSION
+            )
+            .into());
+        }
+        Ok(snippet)
+    }
+
+    pub fn render(&self) -> Result<String, BlinkieError> {
+        serde_json::to_string_pretty(self)
+            .map(|json| format!("{}\n", json))
+            .map_err(|e| {
+                BlinkieError::serialization(format!("Failed to serialize device snippet: {}", e))
+            })
+    }
+
+    /// The device config to create, with `details` set over its connection
+    /// details and under `id` if given. Fails while a secret has no value.
+    pub fn config(
+        &self,
+        id: Option<&str>,
+        details: &HashMap<String, String>,
+    ) -> Result<Config, BlinkieError> {
+        let mut config = self.device.clone();
+        if let Some(id) = id {
+            config.id = id.to_string();
+        }
+        config.connection_details.extend(details.clone());
+        let missing: Vec<&str> = self
+            .secrets
+            .iter()
+            .filter(|key| {
+                config
+                    .connection_details
+                    .get(key.as_str())
+                    .is_none_or(|value| value == REDACTED)
+            })
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "Device '{}' needs values for its secrets: {}",
+                config.id,
+                missing.join(", ")
+            )
+            .into());
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,507 @@
+use super::device::CommandSender;
+use super::error::BlinkieError;
+use super::event::{EventBus, EventKind};
+use super::i18n::{Catalog, LocaleSettings, Localizer};
+use chrono::Utc;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::BuildHasher;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Name of the custom event rules publish to make an announcement, carrying
+/// `text` and optionally `outputs`, e.g. `hallway,kitchen`.
+pub const ANNOUNCE_EVENT: &str = "announce";
+/// Path rendered announcements are fetched from by media players, followed
+/// by the clip's id.
+pub const CLIP_PATH: &str = "/api/announcements/clips/";
+/// Rendered announcements kept for media players to fetch.
+const MAX_CLIPS: usize = 16;
+
+fn default_piper() -> String {
+    "piper".to_string()
+}
+
+fn default_player() -> Vec<String> {
+    vec!["aplay".to_string(), "-q".to_string()]
+}
+
+fn default_play_command() -> String {
+    "play_media".to_string()
+}
+
+fn default_url_parameter() -> String {
+    "url".to_string()
+}
+
+/**
+ * TtsEngine
+ * What renders text to speech.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TtsEngine {
+    /// The piper command line tool with a local voice model, rendering WAV.
+    Piper {
+        #[serde(default = "default_piper")]
+        command: String,
+        model: String,
+        #[serde(default)]
+        speaker: Option<u32>,
+    },
+    /// A speech service taking `{"text", "voice"}` as JSON and answering
+    /// with audio, typed by its `Content-Type`.
+    Http {
+        url: String,
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        voice: Option<String>,
+    },
+}
+
+impl TtsEngine {
+    /// Renders `text`; blocks until the audio is complete.
+    pub fn render(&self, text: &str) -> Result<Audio, BlinkieError> {
+        match self {
+            TtsEngine::Piper {
+                command,
+                model,
+                speaker,
+            } => {
+                let path = std::env::temp_dir().join(format!(
+                    "blinkie-tts-{}-{}.wav",
+                    std::process::id(),
+                    Utc::now().timestamp_nanos_opt().unwrap_or_default()
+                ));
+                let mut piper = Command::new(command);
+                piper
+                    .arg("--model")
+                    .arg(model)
+                    .arg("--output_file")
+                    .arg(&path);
+                if let Some(speaker) = speaker {
+                    piper.arg("--speaker").arg(speaker.to_string());
+                }
+                let mut child = piper
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("Failed to start {}: {}", command, e))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin
+                        .write_all(text.as_bytes())
+                        .map_err(|e| format!("Failed to write to {}: {}", command, e))?;
+                }
+                let output = child
+                    .wait_with_output()
+                    .map_err(|e| format!("Failed to run {}: {}", command, e))?;
+                let data = fs::read(&path);
+                let _ = fs::remove_file(&path);
+                if !output.status.success() {
+                    return Err(format!(
+                        "{} failed with {}: {}",
+                        command,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )
+                    .into());
+                }
+                Ok(Audio {
+                    content_type: "audio/wav".to_string(),
+                    data: data.map_err(|e| format!("Failed to read speech: {}", e))?,
+                })
+            }
+            TtsEngine::Http { url, token, voice } => {
+                let client = Client::builder()
+                    .timeout(Duration::from_secs(30))
+                    .build()
+                    .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+                let mut request = client
+                    .post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(json!({ "text": text, "voice": voice }).to_string());
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                let response = request
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("Failed to render speech: {}", e))?;
+                let content_type = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("audio/wav")
+                    .to_string();
+                let data = response
+                    .bytes()
+                    .map_err(|e| format!("Failed to read speech: {}", e))?;
+                Ok(Audio {
+                    content_type,
+                    data: data.to_vec(),
+                })
+            }
+        }
+    }
+}
+
+/**
+ * AudioOutput
+ * Where announcements are played, named e.g. after the room.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AudioOutput {
+    /// The hub's own speakers, through a player command the audio file is
+    /// appended to.
+    Local {
+        #[serde(default = "default_player")]
+        command: Vec<String>,
+    },
+    /// A media player device, sent `command` with the URL of the rendered
+    /// clip in `url_parameter`.
+    Device {
+        device_id: String,
+        #[serde(default = "default_play_command")]
+        command: String,
+        #[serde(default = "default_url_parameter")]
+        url_parameter: String,
+    },
+}
+
+/**
+ * TtsConfig
+ * The engine and the outputs announcements can be played on. Media players
+ * fetch clips from the hub, at `base_url`.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TtsConfig {
+    #[serde(default)]
+    pub engine: Option<TtsEngine>,
+    #[serde(default)]
+    pub outputs: HashMap<String, AudioOutput>,
+    /// Output of announcements that don't name one.
+    #[serde(default)]
+    pub default_output: Option<String>,
+    /// URL media players reach the hub at, e.g. `http://192.168.1.10:8123`.
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+/**
+ * Audio
+ */
+#[derive(Clone, Debug)]
+pub struct Audio {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Audio {
+    fn extension(&self) -> &'static str {
+        match self.content_type.as_str() {
+            "audio/mpeg" | "audio/mp3" => "mp3",
+            "audio/ogg" | "audio/opus" => "ogg",
+            "audio/flac" => "flac",
+            _ => "wav",
+        }
+    }
+}
+
+/**
+ * Announcement
+ * Text to speak on some outputs, the default one if none are named.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Announcement {
+    /// Spoken as is, unless `key` names a catalog message to speak instead.
+    #[serde(default)]
+    pub text: String,
+    /// Catalog message spoken in the hub's language, e.g.
+    /// `device.unavailable`, filled in from `args`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub args: HashMap<String, String>,
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+impl Announcement {
+    /// Reads an announcement from the data of an `announce` event. With a
+    /// `key`, the text may be left out and any other data fills in the
+    /// message.
+    pub fn from_event(data: &HashMap<String, String>) -> Option<Self> {
+        let key = data.get("key").cloned();
+        Some(Announcement {
+            text: data.get("text").or(key.as_ref())?.clone(),
+            args: match key {
+                Some(_) => data
+                    .iter()
+                    .filter(|(name, _)| !["key", "text", "outputs"].contains(&name.as_str()))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+                None => HashMap::new(),
+            },
+            key,
+            outputs: data
+                .get("outputs")
+                .map(|outputs| {
+                    outputs
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|output| !output.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/**
+ * Announcer
+ * Renders announcements to speech and plays them on their outputs. Clips
+ * for media players are kept in memory until newer ones replace them.
+ */
+pub struct Announcer {
+    path: PathBuf,
+    config: RwLock<TtsConfig>,
+    clips: Mutex<VecDeque<(String, Audio)>>,
+    /// Held while the hub's own speakers play, so announcements don't
+    /// talk over each other.
+    speaking: Mutex<()>,
+    locale: RwLock<LocaleSettings>,
+    catalog: Arc<Catalog>,
+    rendered: AtomicU64,
+}
+
+impl Announcer {
+    /// Opens the configuration kept at `path`.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            TtsConfig::default()
+        };
+        Ok(Announcer {
+            path,
+            config: RwLock::new(config),
+            clips: Mutex::new(VecDeque::new()),
+            speaking: Mutex::new(()),
+            locale: RwLock::new(LocaleSettings::default()),
+            catalog: Arc::new(Catalog::default()),
+            rendered: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> TtsConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: TtsConfig) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(&config).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize TTS config: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// Sets the language announcements are spoken in.
+    pub fn set_locale(&self, locale: LocaleSettings) {
+        *self.locale.write().unwrap() = locale;
+    }
+
+    /// A rendered clip, for media players fetching it.
+    pub fn clip(&self, id: &str) -> Option<Audio> {
+        self.clips
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(clip_id, _)| clip_id == id)
+            .map(|(_, audio)| audio.clone())
+    }
+
+    /// Renders `announcement` and plays it on each of its outputs, sending
+    /// media players their command through `player`. Blocks until local
+    /// playback ends.
+    pub fn announce(
+        &self,
+        announcement: &Announcement,
+        player: &CommandSender,
+    ) -> Result<(), BlinkieError> {
+        let config = self.config();
+        let engine = config
+            .engine
+            .as_ref()
+            .ok_or("No text-to-speech engine configured")?;
+        let names = match announcement.outputs.as_slice() {
+            [] => vec![config
+                .default_output
+                .clone()
+                .ok_or("Announcement names no output and there is no default")?],
+            names => names.to_vec(),
+        };
+        let outputs = names
+            .iter()
+            .map(|name| {
+                config
+                    .outputs
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown audio output '{}'", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let text = match &announcement.key {
+            Some(key) => {
+                let localizer =
+                    Localizer::new(self.locale.read().unwrap().clone(), self.catalog.clone());
+                let args = announcement
+                    .args
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.clone()))
+                    .collect();
+                localizer.translate(key, &args)
+            }
+            None => announcement.text.clone(),
+        };
+        if text.is_empty() {
+            return Err("Announcement has no text".into());
+        }
+        let audio = engine.render(&text)?;
+
+        let mut errors = Vec::new();
+        for (name, output) in names.iter().zip(outputs) {
+            let played = match output {
+                AudioOutput::Local { command } => self.play_local(command, &audio),
+                AudioOutput::Device {
+                    device_id,
+                    command,
+                    url_parameter,
+                } => match config.base_url.as_deref() {
+                    Some(base_url) => {
+                        let url = format!(
+                            "{}{}{}",
+                            base_url.trim_end_matches('/'),
+                            CLIP_PATH,
+                            self.keep(&audio)
+                        );
+                        player(
+                            device_id,
+                            command,
+                            HashMap::from([(url_parameter.clone(), url)]),
+                        )
+                    }
+                    None => Err("Playing on devices needs the hub's base URL".into()),
+                },
+            };
+            if let Err(e) = played {
+                errors.push(format!("{}: {}", name, e));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Failed to announce on {}", errors.join(", ")).into()),
+        }
+    }
+
+    fn play_local(&self, command: &[String], audio: &Audio) -> Result<(), BlinkieError> {
+        let (program, args) = command.split_first().ok_or("Empty player command")?;
+        let path = std::env::temp_dir().join(format!(
+            "blinkie-announcement-{}-{}.{}",
+            std::process::id(),
+            self.rendered.fetch_add(1, Ordering::Relaxed),
+            audio.extension()
+        ));
+        fs::write(&path, &audio.data)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        let _speaking = self.speaking.lock().unwrap();
+        let status = Command::new(program)
+            .args(args)
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        let _ = fs::remove_file(&path);
+        match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("{} failed with {}", program, status).into()),
+            Err(e) => Err(format!("Failed to start {}: {}", program, e).into()),
+        }
+    }
+
+    /// Keeps a clip for media players and returns its id, which nobody can
+    /// guess, as players fetch it without a token.
+    fn keep(&self, audio: &Audio) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(RandomState::new().hash_one(&audio.data).to_le_bytes());
+        hasher.update(self.rendered.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(
+            Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+                .to_le_bytes(),
+        );
+        let id: String = hasher.finalize()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let id = format!("{}.{}", id, audio.extension());
+        let mut clips = self.clips.lock().unwrap();
+        clips.push_back((id.clone(), audio.clone()));
+        while clips.len() > MAX_CLIPS {
+            clips.pop_front();
+        }
+        id
+    }
+
+    /// Makes the announcements rules publish as `announce` events.
+    pub async fn run(self: Arc<Self>, events: Arc<EventBus>, player: CommandSender) {
+        let mut receiver = events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let EventKind::Custom { name, data } = &event.kind else {
+                        continue;
+                    };
+                    if name != ANNOUNCE_EVENT {
+                        continue;
+                    }
+                    let Some(announcement) = Announcement::from_event(data) else {
+                        log::warn!("Ignoring announce event {} without text", event.seq);
+                        continue;
+                    };
+                    let (announcer, player) = (self.clone(), player.clone());
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = announcer.announce(&announcement, &player) {
+                            log::warn!("{}", e);
+                        }
+                    });
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use super::device::{Device, Type};
+use super::emulation::emulated_commands;
+use super::error::BlinkieError;
+use super::icon::{default_icon, IconStore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/**
+ * Area
+ * A room or zone devices are grouped by.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Area {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub icon: Option<String>,
+}
+
+/**
+ * DeviceLayout
+ * How a device is presented: where it lives, its icon and what it can do.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceLayout {
+    #[serde(default)]
+    pub area: Option<String>,
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Defaults to the state keys the device reports.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+/**
+ * SceneCommand
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SceneCommand {
+    pub device_id: String,
+    pub command: String,
+    #[serde(default)]
+    pub parameters: Option<HashMap<String, String>>,
+}
+
+/**
+ * Scene
+ * A named set of commands a dashboard can offer as a single button.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scene {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub icon: Option<String>,
+    pub commands: Vec<SceneCommand>,
+}
+
+/**
+ * UiLayout
+ * Presentation settings from `ui.json` that dashboards build their views from.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UiLayout {
+    #[serde(default)]
+    pub areas: Vec<Area>,
+    /// Layout by device id.
+    #[serde(default)]
+    pub devices: HashMap<String, DeviceLayout>,
+    #[serde(default)]
+    pub scenes: Vec<Scene>,
+}
+
+impl UiLayout {
+    /// Loads a layout, falling back to an empty one if the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, BlinkieError> {
+        if !path.exists() {
+            return Ok(UiLayout::default());
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+        })
+    }
+
+    /// Combines the layout with the devices currently known to the hub. Icons
+    /// set through the API take precedence over those in the layout.
+    pub fn manifest(&self, devices: &[Box<dyn Device>], icons: &IconStore) -> UiManifest {
+        let mut devices: Vec<DeviceEntry> = devices
+            .iter()
+            .map(|device| {
+                let layout = self
+                    .devices
+                    .get(device.get_id())
+                    .cloned()
+                    .unwrap_or_default();
+                let capabilities = if layout.capabilities.is_empty() {
+                    let mut keys: Vec<String> = device.get_state().into_keys().collect();
+                    keys.sort();
+                    keys
+                } else {
+                    layout.capabilities
+                };
+                let id = device.get_id();
+                let icon = icons
+                    .icon(id)
+                    .or(layout.icon)
+                    .unwrap_or_else(|| default_icon(device.get_type(), &capabilities).to_string());
+                DeviceEntry {
+                    id: id.to_string(),
+                    name: device.get_name().to_string(),
+                    device_type: device.get_type(),
+                    area: layout.area,
+                    icon,
+                    picture: icons
+                        .has_picture(id)
+                        .then(|| format!("/api/devices/{}/picture", id)),
+                    capabilities,
+                    emulated: device
+                        .commands()
+                        .map(|native| emulated_commands(&native))
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+
+        UiManifest {
+            areas: self.areas.clone(),
+            devices,
+            scenes: self.scenes.clone(),
+        }
+    }
+}
+
+/**
+ * DeviceEntry
+ * A device as listed in the UI manifest.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    pub id: String,
+    pub name: String,
+    pub device_type: Option<Type>,
+    pub area: Option<String>,
+    pub icon: String,
+    /// URL of the uploaded entity picture, if there is one.
+    pub picture: Option<String>,
+    pub capabilities: Vec<String>,
+    /// Commands blinkie carries out in software because the device lacks
+    /// them, e.g. `toggle`. They work, but slower or less smoothly than
+    /// native ones.
+    #[serde(default)]
+    pub emulated: Vec<String>,
+}
+
+/**
+ * UiManifest
+ * Everything a dashboard needs to lay out its views.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UiManifest {
+    pub areas: Vec<Area>,
+    pub devices: Vec<DeviceEntry>,
+    pub scenes: Vec<Scene>,
+}
@@ -0,0 +1,286 @@
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::maintenance::MaintenanceTask;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// State key whose value tells whether an actuator is running.
+const STATE_KEY: &str = "state";
+
+/// Whether a state value means the device is switched on.
+pub fn is_on(value: &str) -> bool {
+    ["on", "true", "1", "running"]
+        .iter()
+        .any(|on| value.eq_ignore_ascii_case(on))
+}
+
+/**
+ * Usage Metrics
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageMetric {
+    /// Hours the device was switched on.
+    OnHours,
+    /// Number of times the device was switched on.
+    Cycles,
+    /// Number of commands sent to the device.
+    Commands,
+}
+
+/**
+ * Usage Periods
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsagePeriod {
+    /// Since local midnight.
+    #[default]
+    Today,
+    /// Since tracking started.
+    Total,
+}
+
+/**
+ * Usage
+ * Usage counters of a device over some period.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub commands: u64,
+    pub cycles: u64,
+    pub on_seconds: f64,
+}
+
+impl Usage {
+    pub fn metric(&self, metric: UsageMetric) -> f64 {
+        match metric {
+            UsageMetric::OnHours => self.on_seconds / 3600.0,
+            UsageMetric::Cycles => self.cycles as f64,
+            UsageMetric::Commands => self.commands as f64,
+        }
+    }
+}
+
+/**
+ * DeviceUsage
+ * Lifetime and per-day usage of a device.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceUsage {
+    pub total: Usage,
+    pub days: BTreeMap<NaiveDate, Usage>,
+    /// Set while the device is switched on.
+    pub on_since: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct UsageData {
+    last_seq: u64,
+    devices: HashMap<String, DeviceUsage>,
+}
+
+/**
+ * UsageTracker
+ * Counts how often actuators are used, how long they run and how many
+ * switch cycles they go through, e.g. to keep an eye on relay wear.
+ */
+pub struct UsageTracker {
+    path: Option<PathBuf>,
+    timezone: Tz,
+    data: RwLock<UsageData>,
+}
+
+impl UsageTracker {
+    /// Creates a tracker that only keeps usage in memory.
+    pub fn new(timezone: Tz) -> Self {
+        UsageTracker {
+            path: None,
+            timezone,
+            data: RwLock::new(UsageData::default()),
+        }
+    }
+
+    /// Opens a persistent tracker, loading previously saved usage if the file exists.
+    pub fn open<P: Into<PathBuf>>(path: P, timezone: Tz) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let data = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            UsageData::default()
+        };
+        Ok(UsageTracker {
+            path: Some(path),
+            timezone,
+            data: RwLock::new(data),
+        })
+    }
+
+    pub fn record(&self, event: &Event) {
+        let mut data = self.data.write().unwrap();
+        if event.seq <= data.last_seq {
+            return;
+        }
+        data.last_seq = event.seq;
+
+        match &event.kind {
+            EventKind::CommandSent { device_id, .. } => {
+                let date = self.local_date(event.timestamp);
+                let usage = data.devices.entry(device_id.clone()).or_default();
+                usage.total.commands += 1;
+                usage.days.entry(date).or_default().commands += 1;
+                usage.last_used = Some(event.timestamp);
+            }
+            EventKind::StateChanged {
+                device_id,
+                key,
+                new_value,
+                ..
+            } if key == STATE_KEY => {
+                let usage = data.devices.entry(device_id.clone()).or_default();
+                match (usage.on_since, is_on(new_value)) {
+                    (None, true) => {
+                        let date = self.local_date(event.timestamp);
+                        usage.total.cycles += 1;
+                        usage.days.entry(date).or_default().cycles += 1;
+                        usage.on_since = Some(event.timestamp);
+                        usage.last_used = Some(event.timestamp);
+                    }
+                    (Some(since), false) => {
+                        self.add_on_time(usage, since, event.timestamp);
+                        usage.on_since = None;
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Usage of a device over a period, counting a run still in progress up to `now`.
+    pub fn usage(&self, device_id: &str, period: UsagePeriod, now: DateTime<Utc>) -> Usage {
+        let data = self.data.read().unwrap();
+        let Some(device) = data.devices.get(device_id) else {
+            return Usage::default();
+        };
+        let mut device = device.clone();
+        if let Some(since) = device.on_since {
+            self.add_on_time(&mut device, since, now);
+        }
+        match period {
+            UsagePeriod::Total => device.total,
+            UsagePeriod::Today => device
+                .days
+                .get(&self.local_date(now))
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Usage of all tracked devices.
+    pub fn all(&self) -> HashMap<String, DeviceUsage> {
+        self.data.read().unwrap().devices.clone()
+    }
+
+    /// Drops a device's usage and writes the rest to disk.
+    pub fn forget(&self, device_id: &str) -> Result<(), BlinkieError> {
+        self.data.write().unwrap().devices.remove(device_id);
+        self.save()
+    }
+
+    /// Catches up on journaled events and then follows the bus until it closes.
+    pub async fn run(self: Arc<Self>, events: Arc<EventBus>) {
+        let last_seq = self.data.read().unwrap().last_seq;
+        let mut receiver = match events.resume(last_seq) {
+            Ok((missed, receiver)) => {
+                for event in &missed {
+                    self.record(event);
+                }
+                receiver
+            }
+            Err(_) => events.subscribe(),
+        };
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), BlinkieError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let data = self.data.read().unwrap();
+        let contents = serde_json::to_string(&*data).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize usage: {}", e))
+        })?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+    }
+
+    fn local_date(&self, timestamp: DateTime<Utc>) -> NaiveDate {
+        timestamp.with_timezone(&self.timezone).date_naive()
+    }
+
+    /// Adds the time between `from` and `to` to the total and to each local day it covers.
+    fn add_on_time(&self, usage: &mut DeviceUsage, from: DateTime<Utc>, to: DateTime<Utc>) {
+        if to <= from {
+            return;
+        }
+        usage.total.on_seconds += (to - from).num_milliseconds() as f64 / 1000.0;
+
+        let mut start = from;
+        while start < to {
+            let date = self.local_date(start);
+            let next_midnight = self
+                .timezone
+                .from_local_datetime(&(date + Duration::days(1)).and_time(NaiveTime::MIN))
+                .earliest()
+                .map(|t| t.with_timezone(&Utc))
+                .unwrap_or(to);
+            // Guards against a midnight that does not lie ahead of `start`
+            let end = if next_midnight > start {
+                next_midnight.min(to)
+            } else {
+                to
+            };
+            usage.days.entry(date).or_default().on_seconds +=
+                (end - start).num_milliseconds() as f64 / 1000.0;
+            start = end;
+        }
+    }
+}
+
+/**
+ * UsageFlushTask
+ * Periodically persists usage counters.
+ */
+pub struct UsageFlushTask {
+    pub tracker: Arc<UsageTracker>,
+}
+
+impl MaintenanceTask for UsageFlushTask {
+    fn name(&self) -> &str {
+        "usage_flush"
+    }
+
+    fn run(&self) -> Result<(), BlinkieError> {
+        self.tracker.save()
+    }
+}
@@ -0,0 +1,372 @@
+use super::device::DeviceList;
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use super::i18n::LocaleSettings;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Presence state trackers report for a person at home.
+pub const HOME: &str = "home";
+/// State key of devices tracking a user's presence.
+pub const PRESENCE_KEY: &str = "presence";
+
+/**
+ * Preferences
+ * What a user likes, for automations to personalize what they do, e.g.
+ * heating to the preferred temperature of whoever is home.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Preferences {
+    /// Preferred room temperature, in the unit the thermostats use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Notification channels to reach the user on, in order of preference.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notification_channels: Vec<String>,
+    /// Language and units of notifications sent to the user's channels,
+    /// the hub's if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<LocaleSettings>,
+    /// Anything else automations want to know, e.g. `wake_time` or
+    /// `light_color`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom: BTreeMap<String, String>,
+}
+
+/**
+ * User
+ * A person living in the home, linked to the API accounts they sign in
+ * with and the presence trackers following them, e.g. `alice` with the
+ * token named `alice-phone` and the person id of the phone reporting
+ * presence for them.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+    /// Names of the API tokens the user signs in with.
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    /// Person ids of `presence_changed` events about the user, or ids of
+    /// devices reporting their presence as the state key `presence`, e.g.
+    /// a phone pinged on the network.
+    #[serde(default)]
+    pub trackers: Vec<String>,
+    #[serde(default)]
+    pub preferences: Preferences,
+}
+
+impl User {
+    /// Checks the user's id, that linked accounts and preferred channels
+    /// exist and that the preferred temperature is a number. `me` is taken
+    /// by the API for the user signed in.
+    pub fn validate(&self, accounts: &[String], channels: &[String]) -> Result<(), BlinkieError> {
+        if self.id.is_empty() || self.id.contains('.') || self.id == "me" {
+            return Err(format!("Invalid user id '{}'", self.id).into());
+        }
+        if let Some(account) = self.accounts.iter().find(|a| !accounts.contains(a)) {
+            return Err(format!("Unknown account '{}'", account).into());
+        }
+        if let Some(channel) = self
+            .preferences
+            .notification_channels
+            .iter()
+            .find(|channel| !channels.contains(channel))
+        {
+            return Err(format!("Unknown notification channel '{}'", channel).into());
+        }
+        if self
+            .preferences
+            .temperature
+            .is_some_and(|temperature| !temperature.is_finite())
+        {
+            return Err("The preferred temperature has to be a number".into());
+        }
+        Ok(())
+    }
+}
+
+/**
+ * UserStatus
+ * A user with whether they are home: `None` until one of their trackers
+ * reports.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserStatus {
+    #[serde(flatten)]
+    pub user: User,
+    pub home: Option<bool>,
+}
+
+/**
+ * UserStore
+ * Holds users, written back to `users.json` on every change, and follows
+ * their trackers on the bus to know who is home. Templates reach both, see
+ * `lookup`.
+ */
+pub struct UserStore {
+    path: PathBuf,
+    users: RwLock<BTreeMap<String, User>>,
+    /// Latest presence state, by person id.
+    presence: RwLock<HashMap<String, String>>,
+    devices: DeviceList,
+    events: Arc<EventBus>,
+}
+
+impl UserStore {
+    /// Opens the store, loading previously saved users if the file exists.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        devices: DeviceList,
+        events: Arc<EventBus>,
+    ) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let users = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(UserStore {
+            path,
+            users: RwLock::new(users),
+            presence: RwLock::new(HashMap::new()),
+            devices,
+            events,
+        })
+    }
+
+    pub fn list(&self) -> Vec<UserStatus> {
+        let users = self.users.read().unwrap();
+        users.values().map(|user| self.status(user)).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<UserStatus> {
+        let users = self.users.read().unwrap();
+        users.get(id).map(|user| self.status(user))
+    }
+
+    /// User signing in with the API token named `account`.
+    pub fn for_account(&self, account: &str) -> Option<UserStatus> {
+        let users = self.users.read().unwrap();
+        users
+            .values()
+            .find(|user| user.accounts.iter().any(|linked| linked == account))
+            .map(|user| self.status(user))
+    }
+
+    /// Validates a user and adds or replaces it. An account or tracker
+    /// belongs to one user only.
+    pub fn save(
+        &self,
+        user: User,
+        accounts: &[String],
+        channels: &[String],
+    ) -> Result<(), BlinkieError> {
+        user.validate(accounts, channels)?;
+        let mut users = self.users.write().unwrap();
+        for other in users.values().filter(|other| other.id != user.id) {
+            if let Some(account) = user.accounts.iter().find(|a| other.accounts.contains(a)) {
+                return Err(format!(
+                    "Account '{}' already belongs to user '{}'",
+                    account, other.id
+                )
+                .into());
+            }
+            if let Some(tracker) = user.trackers.iter().find(|t| other.trackers.contains(t)) {
+                return Err(format!(
+                    "Tracker '{}' already belongs to user '{}'",
+                    tracker, other.id
+                )
+                .into());
+            }
+        }
+        users.insert(user.id.clone(), user);
+        self.persist(&users)
+    }
+
+    /// Replaces the preferences of a user.
+    pub fn set_preferences(
+        &self,
+        id: &str,
+        preferences: Preferences,
+        accounts: &[String],
+        channels: &[String],
+    ) -> Result<UserStatus, BlinkieError> {
+        let mut user = self
+            .users
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("User '{}' not found", id))?;
+        user.preferences = preferences;
+        self.save(user, accounts, channels)?;
+        self.get(id)
+            .ok_or_else(|| format!("User '{}' not found", id).into())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<User>, BlinkieError> {
+        let mut users = self.users.write().unwrap();
+        let removed = users.remove(id);
+        if removed.is_some() {
+            self.persist(&users)?;
+        }
+        Ok(removed)
+    }
+
+    /// Drops the user with id `person_id` and the presence last reported
+    /// for that person or by the user's trackers.
+    pub fn forget(&self, person_id: &str) -> Result<(), BlinkieError> {
+        let removed = self.remove(person_id)?;
+        let mut presence = self.presence.write().unwrap();
+        presence.remove(person_id);
+        for tracker in removed.iter().flat_map(|user| &user.trackers) {
+            presence.remove(tracker);
+        }
+        Ok(())
+    }
+
+    /// Whether a user is home: if any of their trackers says so. `None`
+    /// until one of them reports.
+    fn is_home(&self, user: &User) -> Option<bool> {
+        let mut states: Vec<String> = {
+            let presence = self.presence.read().unwrap();
+            user.trackers
+                .iter()
+                .filter_map(|tracker| presence.get(tracker).cloned())
+                .collect()
+        };
+        let devices = self.devices.read().unwrap();
+        states.extend(
+            devices
+                .iter()
+                .filter(|device| user.trackers.iter().any(|t| t == device.get_id()))
+                .filter_map(|device| Some(device.get_state().get(PRESENCE_KEY)?.to_string())),
+        );
+        if states.is_empty() {
+            return None;
+        }
+        Some(states.iter().any(|state| state == HOME))
+    }
+
+    fn status(&self, user: &User) -> UserStatus {
+        UserStatus {
+            user: user.clone(),
+            home: self.is_home(user),
+        }
+    }
+
+    /// Users known to be home, by id.
+    pub fn home(&self) -> Vec<User> {
+        let users = self.users.read().unwrap();
+        users
+            .values()
+            .filter(|user| self.is_home(user) == Some(true))
+            .cloned()
+            .collect()
+    }
+
+    /// Locale of the first user preferring `channel` who set one.
+    pub fn channel_locale(&self, channel: &str) -> Option<LocaleSettings> {
+        let users = self.users.read().unwrap();
+        users
+            .values()
+            .filter(|user| {
+                user.preferences
+                    .notification_channels
+                    .iter()
+                    .any(|preferred| preferred == channel)
+            })
+            .find_map(|user| user.preferences.locale.clone())
+    }
+
+    /// Resolves the template paths about users:
+    ///
+    /// - `user.<id>.name`, `.home`, `.temperature`, `.notification_channels`
+    ///   (comma separated) or `.<custom preference>`
+    /// - `users.home`, the ids of users at home, comma separated,
+    ///   `users.home_count`, or `users.temperature`, the average preferred
+    ///   temperature of those at home who have one
+    pub fn lookup(&self, root: &str, path: &str) -> Option<String> {
+        match root {
+            "user" => {
+                let (id, field) = path.split_once('.')?;
+                let status = self.get(id)?;
+                let preferences = &status.user.preferences;
+                match field {
+                    "id" => Some(status.user.id),
+                    "name" => Some(status.user.name),
+                    "home" => status.home.map(|home| home.to_string()),
+                    "temperature" => preferences.temperature.map(|t| t.to_string()),
+                    "notification_channels" => Some(preferences.notification_channels.join(",")),
+                    custom => preferences.custom.get(custom).cloned(),
+                }
+            }
+            "users" => {
+                let home = self.home();
+                match path {
+                    "home" => Some(
+                        home.iter()
+                            .map(|user| user.id.as_str())
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    ),
+                    "home_count" => Some(home.len().to_string()),
+                    "temperature" => {
+                        let preferred: Vec<f64> = home
+                            .iter()
+                            .filter_map(|user| user.preferences.temperature)
+                            .collect();
+                        if preferred.is_empty() {
+                            return None;
+                        }
+                        let average = preferred.iter().sum::<f64>() / preferred.len() as f64;
+                        Some(((average * 10.0).round() / 10.0).to_string())
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes the presence reported in `event`.
+    pub fn record(&self, event: &Event) {
+        if let EventKind::PresenceChanged { person_id, state } = &event.kind {
+            self.presence
+                .write()
+                .unwrap()
+                .insert(person_id.clone(), state.clone());
+        }
+    }
+
+    /// Follows the presence of people on the bus until it closes.
+    pub async fn run(self: Arc<Self>) {
+        let mut receiver = self.events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn persist(&self, users: &BTreeMap<String, User>) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(users).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize users: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+}
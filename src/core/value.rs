@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Device state by key.
+pub type StateMap = HashMap<String, StateValue>;
+
+/**
+ * Color
+ * An RGB color, written as `#rrggbb`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    /// Parses `#rrggbb`, the `#` being optional.
+    pub fn parse(value: &str) -> Option<Self> {
+        let hex = value.trim().strip_prefix('#').unwrap_or(value.trim());
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some(Color {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/**
+ * StateValue
+ * A single value of a device's state. Values serialize as plain JSON
+ * booleans, numbers and strings, colors as `{"r", "g", "b"}` objects.
+ * Enum values, one of a fixed set of names, serialize as strings and read
+ * back as `String`.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "test-util", derive(arbitrary::Arbitrary))]
+#[serde(untagged)]
+pub enum StateValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Color(Color),
+    String(String),
+    #[serde(skip_deserializing)]
+    Enum(String),
+}
+
+impl StateValue {
+    /// Infers the type of a value reported as text: booleans, integers,
+    /// finite numbers and `#rrggbb` colors, else a string. Only values that
+    /// print back exactly as they were written are typed, so `21.50` or
+    /// `#FF8800` stay strings and reach devices unchanged.
+    pub fn parse(value: &str) -> Self {
+        let typed = if let Ok(on) = value.parse::<bool>() {
+            StateValue::Bool(on)
+        } else if let Ok(int) = value.parse::<i64>() {
+            StateValue::Int(int)
+        } else if let Some(float) = value.parse::<f64>().ok().filter(|f| f.is_finite()) {
+            StateValue::Float(float)
+        } else if let Some(color) = value.strip_prefix('#').and_then(Color::parse) {
+            StateValue::Color(color)
+        } else {
+            return StateValue::String(value.to_string());
+        };
+        match typed.to_string() == value {
+            true => typed,
+            false => StateValue::String(value.to_string()),
+        }
+    }
+
+    /// The value as a number, for integers, floats and numeric text.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            StateValue::Int(int) => Some(*int as f64),
+            StateValue::Float(float) => Some(*float),
+            StateValue::String(text) | StateValue::Enum(text) => {
+                text.trim().parse().ok().filter(|f: &f64| f.is_finite())
+            }
+            StateValue::Bool(_) | StateValue::Color(_) => None,
+        }
+    }
+
+    /// The value as a switch position: booleans, and `on`/`off` or
+    /// `true`/`false` in any case.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            StateValue::Bool(on) => Some(*on),
+            StateValue::String(text) | StateValue::Enum(text) => {
+                match text.trim().to_ascii_lowercase().as_str() {
+                    "on" | "true" => Some(true),
+                    "off" | "false" => Some(false),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            StateValue::String(text) | StateValue::Enum(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Whether two values mean the same: numbers compare by value, so `21`
+    /// matches `21.0`, everything else by how it prints.
+    pub fn matches(&self, other: &StateValue) -> bool {
+        match (self, other) {
+            (StateValue::Int(_) | StateValue::Float(_), _)
+            | (_, StateValue::Int(_) | StateValue::Float(_)) => {
+                match (self.as_f64(), other.as_f64()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => false,
+                }
+            }
+            _ => self.to_string() == other.to_string(),
+        }
+    }
+
+    pub fn as_color(&self) -> Option<Color> {
+        match self {
+            StateValue::Color(color) => Some(*color),
+            StateValue::String(text) => Color::parse(text),
+            _ => None,
+        }
+    }
+}
+
+/// Values print as they are written in events and templates, e.g. `21.5`,
+/// `on` or `#ff8800`.
+impl fmt::Display for StateValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateValue::Bool(on) => write!(f, "{}", on),
+            StateValue::Int(int) => write!(f, "{}", int),
+            StateValue::Float(float) => write!(f, "{}", float),
+            StateValue::Color(color) => write!(f, "{}", color),
+            StateValue::String(text) | StateValue::Enum(text) => f.write_str(text),
+        }
+    }
+}
+
+impl From<bool> for StateValue {
+    fn from(value: bool) -> Self {
+        StateValue::Bool(value)
+    }
+}
+
+impl From<i64> for StateValue {
+    fn from(value: i64) -> Self {
+        StateValue::Int(value)
+    }
+}
+
+impl From<f64> for StateValue {
+    fn from(value: f64) -> Self {
+        StateValue::Float(value)
+    }
+}
+
+impl From<Color> for StateValue {
+    fn from(value: Color) -> Self {
+        StateValue::Color(value)
+    }
+}
+
+impl From<String> for StateValue {
+    fn from(value: String) -> Self {
+        StateValue::String(value)
+    }
+}
+
+impl From<&str> for StateValue {
+    fn from(value: &str) -> Self {
+        StateValue::String(value.to_string())
+    }
+}
+
+/// Types the values of a state reported as text.
+pub fn parse_state(state: &HashMap<String, String>) -> StateMap {
+    state
+        .iter()
+        .map(|(key, value)| (key.clone(), StateValue::parse(value)))
+        .collect()
+}
+
+/// Writes the values of a state as text, e.g. to send it to a device.
+pub fn format_state(state: &StateMap) -> HashMap<String, String> {
+    state
+        .iter()
+        .map(|(key, value)| (key.clone(), value.to_string()))
+        .collect()
+}
@@ -0,0 +1,173 @@
+use super::error::BlinkieError;
+use super::event::{Event, EventBus, EventKind};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Path webhooks are received on, followed by their id.
+pub const WEBHOOK_PATH: &str = "/api/webhooks/";
+
+/**
+ * Webhook
+ * Turns requests from outside services into events. Its id is a secret
+ * and the only thing callers need, so services that can't send API
+ * tokens can call it.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    /// Name of the custom event published for each call.
+    pub event: String,
+    /// Data every event carries, taking precedence over what callers send.
+    #[serde(default)]
+    pub data: HashMap<String, String>,
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+    /// Webhooks of a group are removed together after the first call to
+    /// any of them, e.g. the answers to a question.
+    #[serde(default)]
+    pub once_group: Option<String>,
+}
+
+impl Webhook {
+    fn active(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+/**
+ * WebhookStore
+ * Keeps webhooks by id and publishes their calls on the bus. Webhooks are
+ * written back to disk so they survive restarts.
+ */
+pub struct WebhookStore {
+    path: PathBuf,
+    hooks: RwLock<HashMap<String, Webhook>>,
+    events: Arc<EventBus>,
+    created: AtomicU64,
+}
+
+impl WebhookStore {
+    /// Opens the webhooks kept at `path`, publishing their calls on `events`.
+    pub fn open<P: Into<PathBuf>>(path: P, events: Arc<EventBus>) -> Result<Self, BlinkieError> {
+        let path = path.into();
+        let mut hooks: HashMap<String, Webhook> = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents).map_err(|e| {
+                BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+            })?
+        } else {
+            HashMap::new()
+        };
+        let now = Utc::now();
+        hooks.retain(|_, hook| hook.active(now));
+        Ok(WebhookStore {
+            path,
+            hooks: RwLock::new(hooks),
+            events,
+            created: AtomicU64::new(0),
+        })
+    }
+
+    /// Webhooks that haven't expired, by id.
+    pub fn list(&self) -> HashMap<String, Webhook> {
+        let now = Utc::now();
+        let hooks = self.hooks.read().unwrap();
+        hooks
+            .iter()
+            .filter(|(_, hook)| hook.active(now))
+            .map(|(id, hook)| (id.clone(), hook.clone()))
+            .collect()
+    }
+
+    /// Adds a webhook and returns its id.
+    pub fn create(&self, webhook: Webhook) -> Result<String, BlinkieError> {
+        if webhook.event.is_empty() {
+            return Err("Webhook needs an event name".into());
+        }
+        let id = self.id(&webhook.event);
+        let mut hooks = self.hooks.write().unwrap();
+        let now = Utc::now();
+        hooks.retain(|_, hook| hook.active(now));
+        hooks.insert(id.clone(), webhook);
+        self.save(&hooks)?;
+        Ok(id)
+    }
+
+    /// Adds a webhook that expires after `secs` seconds.
+    pub fn create_for(&self, mut webhook: Webhook, secs: i64) -> Result<String, BlinkieError> {
+        webhook.expires = Some(Utc::now() + Duration::seconds(secs));
+        self.create(webhook)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<Webhook>, BlinkieError> {
+        let mut hooks = self.hooks.write().unwrap();
+        let removed = hooks.remove(id);
+        if removed.is_some() {
+            self.save(&hooks)?;
+        }
+        Ok(removed)
+    }
+
+    /// Publishes the event of the webhook `id` with what the caller sent.
+    pub fn receive(
+        &self,
+        id: &str,
+        payload: HashMap<String, String>,
+    ) -> Result<Event, BlinkieError> {
+        let webhook = {
+            let mut hooks = self.hooks.write().unwrap();
+            let webhook = hooks
+                .get(id)
+                .filter(|hook| hook.active(Utc::now()))
+                .cloned()
+                .ok_or_else(|| "Unknown or expired webhook".to_string())?;
+            if let Some(group) = &webhook.once_group {
+                hooks.retain(|_, hook| hook.once_group.as_ref() != Some(group));
+                self.save(&hooks)?;
+            }
+            webhook
+        };
+        let mut data = payload;
+        data.extend(webhook.data);
+        self.events.publish(EventKind::Custom {
+            name: webhook.event,
+            data,
+        })
+    }
+
+    /// An id nobody can guess from the event and time alone.
+    fn id(&self, event: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(RandomState::new().hash_one(event).to_le_bytes());
+        hasher.update(self.created.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(
+            Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+                .to_le_bytes(),
+        );
+        hasher.update(event.as_bytes());
+        hasher.finalize()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn save(&self, hooks: &HashMap<String, Webhook>) -> Result<(), BlinkieError> {
+        let contents = serde_json::to_string_pretty(hooks).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize webhooks: {}", e))
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+}
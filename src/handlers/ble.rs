@@ -0,0 +1,887 @@
+use crate::core::device::{CommandResponse, Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use crate::core::sniffer::{Direction, Sniffer};
+use crate::core::value::{self, format_state, StateMap};
+use btleplug::api::{
+    Central, CharPropFlags, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType,
+};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::OnceCell;
+use tokio_stream::StreamExt;
+
+pub const PROTOCOL: &str = "ble";
+
+/// Seconds between reads of characteristics that don't notify.
+const DEFAULT_POLL_SECS: u64 = 60;
+/// Time to look for a peripheral before trying again later.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+/// Time a peripheral has to connect or take a write.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Waits between connection attempts, doubling up to the maximum.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(120);
+/// Time between checks that a peripheral is still connected.
+const LINK_CHECK: Duration = Duration::from_secs(5);
+
+/// Base of 16-bit UUIDs assigned by the Bluetooth SIG.
+const BASE_UUID_SUFFIX: &str = "-0000-1000-8000-00805f9b34fb";
+
+/**
+ * Formats
+ * How a value is laid out in a characteristic. Multi-byte integers are
+ * little-endian unless they end in `be`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U16Be,
+    I16Be,
+    U32Be,
+    I32Be,
+    F32,
+    Bool,
+    Utf8,
+    Hex,
+}
+
+impl Format {
+    fn parse(text: &str) -> Option<Self> {
+        Some(match text {
+            "u8" => Format::U8,
+            "i8" => Format::I8,
+            "u16" => Format::U16,
+            "i16" => Format::I16,
+            "u32" => Format::U32,
+            "i32" => Format::I32,
+            "u16be" => Format::U16Be,
+            "i16be" => Format::I16Be,
+            "u32be" => Format::U32Be,
+            "i32be" => Format::I32Be,
+            "f32" => Format::F32,
+            "bool" => Format::Bool,
+            "utf8" => Format::Utf8,
+            "hex" => Format::Hex,
+            _ => return None,
+        })
+    }
+
+    /// Number the bytes at the start of `bytes` stand for, if it is a
+    /// numeric format and there are enough of them.
+    fn number(self, bytes: &[u8]) -> Option<f64> {
+        let array = |len: usize| bytes.get(..len);
+        Some(match self {
+            Format::U8 => *bytes.first()? as f64,
+            Format::I8 => *bytes.first()? as i8 as f64,
+            Format::U16 => u16::from_le_bytes(array(2)?.try_into().ok()?) as f64,
+            Format::I16 => i16::from_le_bytes(array(2)?.try_into().ok()?) as f64,
+            Format::U32 => u32::from_le_bytes(array(4)?.try_into().ok()?) as f64,
+            Format::I32 => i32::from_le_bytes(array(4)?.try_into().ok()?) as f64,
+            Format::U16Be => u16::from_be_bytes(array(2)?.try_into().ok()?) as f64,
+            Format::I16Be => i16::from_be_bytes(array(2)?.try_into().ok()?) as f64,
+            Format::U32Be => u32::from_be_bytes(array(4)?.try_into().ok()?) as f64,
+            Format::I32Be => i32::from_be_bytes(array(4)?.try_into().ok()?) as f64,
+            Format::F32 => f32::from_le_bytes(array(4)?.try_into().ok()?) as f64,
+            Format::Bool | Format::Utf8 | Format::Hex => return None,
+        })
+    }
+
+    /// Bytes of `text` in this format, e.g. a command parameter.
+    fn encode(self, text: &str) -> Result<Vec<u8>, String> {
+        let invalid = || format!("'{}' is not a valid {:?} value", text, self);
+        let integer = || text.trim().parse::<i64>().map_err(|_| invalid());
+        Ok(match self {
+            Format::U8 => vec![u8::try_from(integer()?).map_err(|_| invalid())?],
+            Format::I8 => vec![i8::try_from(integer()?).map_err(|_| invalid())? as u8],
+            Format::U16 => u16::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            Format::I16 => i16::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            Format::U32 => u32::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            Format::I32 => i32::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            Format::U16Be => u16::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+                .to_vec(),
+            Format::I16Be => i16::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+                .to_vec(),
+            Format::U32Be => u32::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+                .to_vec(),
+            Format::I32Be => i32::try_from(integer()?)
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+                .to_vec(),
+            Format::F32 => text
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| invalid())?
+                .to_le_bytes()
+                .to_vec(),
+            Format::Bool => match text.trim() {
+                "true" | "on" | "1" => vec![1],
+                "false" | "off" | "0" => vec![0],
+                _ => return Err(invalid()),
+            },
+            Format::Utf8 => text.as_bytes().to_vec(),
+            Format::Hex => decode_hex(text).ok_or_else(invalid)?,
+        })
+    }
+}
+
+/**
+ * StateMapping
+ * A state key read from a characteristic: `<uuid>[:<format>[@<offset>][:<scale>]]`,
+ * e.g. `ebe0ccc1-7a0a-4b0c-8a1a-6ff2997da3a6:i16@0:0.01` for a temperature
+ * in hundredths of a degree at the start of the value. `u8` at offset 0 by
+ * default; 16-bit UUIDs like `2a19` stand for the SIG's full ones.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateMapping {
+    pub uuid: String,
+    pub format: Format,
+    pub offset: usize,
+    pub scale: Option<f64>,
+}
+
+impl StateMapping {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = text.split(':');
+        let uuid = full_uuid(parts.next().unwrap_or_default())?;
+        let (format, offset) = match parts.next() {
+            Some(format) => match format.split_once('@') {
+                Some((format, offset)) => (
+                    format,
+                    offset
+                        .parse()
+                        .map_err(|_| format!("invalid offset '{}'", offset))?,
+                ),
+                None => (format, 0),
+            },
+            None => ("u8", 0),
+        };
+        let format = Format::parse(format).ok_or_else(|| format!("unknown format '{}'", format))?;
+        let scale = parts
+            .next()
+            .map(|scale| {
+                scale
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid scale '{}'", scale))
+            })
+            .transpose()?;
+        if parts.next().is_some() {
+            return Err(format!("too many parts in '{}'", text));
+        }
+        Ok(StateMapping {
+            uuid,
+            format,
+            offset,
+            scale,
+        })
+    }
+
+    /// The value of this key in `bytes`, the value of its characteristic.
+    fn decode(&self, bytes: &[u8]) -> Option<String> {
+        let bytes = bytes.get(self.offset..)?;
+        match self.format {
+            Format::Bool => Some((*bytes.first()? != 0).to_string()),
+            Format::Utf8 => Some(
+                String::from_utf8_lossy(bytes)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            ),
+            Format::Hex => Some(encode_hex(bytes)),
+            format => {
+                let number = format.number(bytes)?;
+                Some(match self.scale {
+                    Some(scale) => {
+                        // As many decimals as the scale has, so 2153 * 0.01
+                        // reads 21.53
+                        let decimals = scale
+                            .to_string()
+                            .split_once('.')
+                            .map_or(0, |(_, fraction)| fraction.len());
+                        let scaled = format!("{:.*}", decimals, number * scale);
+                        if scaled.contains('.') {
+                            scaled
+                                .trim_end_matches('0')
+                                .trim_end_matches('.')
+                                .to_string()
+                        } else {
+                            scaled
+                        }
+                    }
+                    None => number.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/**
+ * CommandWrite
+ * A command written to a characteristic: `<uuid>:<payload>`, the payload
+ * in hex with `{<parameter>[:<format>]}` standing for a parameter of the
+ * command, e.g. `ffd9:56{r}{g}{b}00f0aa` for the color of an LED strip.
+ * Parameters are `u8` by default.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandWrite {
+    pub uuid: String,
+    pub payload: String,
+}
+
+impl CommandWrite {
+    fn parse(text: &str) -> Result<Self, String> {
+        let (uuid, payload) = text
+            .split_once(':')
+            .ok_or_else(|| format!("expected <uuid>:<payload>, got '{}'", text))?;
+        let write = CommandWrite {
+            uuid: full_uuid(uuid)?,
+            payload: payload.to_string(),
+        };
+        // Fails on bad hex or formats before anything is sent
+        write.bytes(None, true)?;
+        Ok(write)
+    }
+
+    /// The payload with `parameters` filled in. With `check`, parameters
+    /// are only checked for a valid format.
+    fn bytes(
+        &self,
+        parameters: Option<&HashMap<String, String>>,
+        check: bool,
+    ) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        let mut rest = self.payload.as_str();
+        while let Some(open) = rest.find('{') {
+            bytes.extend(
+                decode_hex(&rest[..open])
+                    .ok_or_else(|| format!("invalid hex in payload '{}'", self.payload))?,
+            );
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in payload '{}'", self.payload))?;
+            let placeholder = &rest[open + 1..open + close];
+            let (name, format) = placeholder.split_once(':').unwrap_or((placeholder, "u8"));
+            let format =
+                Format::parse(format).ok_or_else(|| format!("unknown format '{}'", format))?;
+            if !check {
+                let value = parameters
+                    .and_then(|parameters| parameters.get(name))
+                    .ok_or_else(|| format!("missing parameter '{}'", name))?;
+                bytes.extend(format.encode(value)?);
+            }
+            rest = &rest[open + close + 1..];
+        }
+        bytes.extend(
+            decode_hex(rest).ok_or_else(|| format!("invalid hex in payload '{}'", self.payload))?,
+        );
+        Ok(bytes)
+    }
+}
+
+/**
+ * BleSpec
+ * Peripheral of a device and how its characteristics map to state keys
+ * and commands, read from its connection details:
+ *
+ * - `address`: the peripheral's address, e.g. `A4:C1:38:12:34:56`, or
+ * - `name`: the name it advertises, if the address changes
+ * - `adapter`: index of the Bluetooth adapter to use, 0 by default
+ * - `poll_secs`: seconds between reads of state characteristics that don't
+ *   notify, 60 by default, 0 to only take notifications
+ * - `state.<key>`: a characteristic to read `key` from, see `StateMapping`
+ * - `command.<name>`: a characteristic to write for command `name`, see
+ *   `CommandWrite`
+ */
+#[derive(Clone, Debug)]
+pub struct BleSpec {
+    pub address: Option<String>,
+    pub name: Option<String>,
+    pub adapter: usize,
+    pub poll_secs: u64,
+    pub states: HashMap<String, StateMapping>,
+    pub commands: HashMap<String, CommandWrite>,
+}
+
+impl BleSpec {
+    pub fn from_config(config: &Config) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let invalid = |key: &str, e: String| {
+            BlinkieError::from(format!("Invalid {} for device '{}': {}", key, config.id, e))
+        };
+        let address = details.get("address").map(|address| address.to_uppercase());
+        let name = details.get("name").cloned();
+        if address.is_none() && name.is_none() {
+            return Err(format!(
+                "Device '{}' needs an address or name to find its peripheral",
+                config.id
+            )
+            .into());
+        }
+        let number = |key: &str, default: u64| match details.get(key) {
+            Some(text) => text
+                .parse::<u64>()
+                .map_err(|_| invalid(key, format!("'{}' is not a number", text))),
+            None => Ok(default),
+        };
+        let mut states = HashMap::new();
+        let mut commands = HashMap::new();
+        for (key, text) in details {
+            if let Some(state) = key.strip_prefix("state.") {
+                let mapping = StateMapping::parse(text).map_err(|e| invalid(key, e))?;
+                states.insert(state.to_string(), mapping);
+            } else if let Some(command) = key.strip_prefix("command.") {
+                let write = CommandWrite::parse(text).map_err(|e| invalid(key, e))?;
+                commands.insert(command.to_string(), write);
+            }
+        }
+        Ok(BleSpec {
+            address,
+            name,
+            adapter: number("adapter", 0)? as usize,
+            poll_secs: number("poll_secs", DEFAULT_POLL_SECS)?,
+            states,
+            commands,
+        })
+    }
+
+    fn target(&self) -> String {
+        self.address
+            .clone()
+            .or_else(|| self.name.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The full lowercase form of `uuid`, expanding 16- and 32-bit ones.
+fn full_uuid(uuid: &str) -> Result<String, String> {
+    let uuid = uuid.trim().to_lowercase();
+    let hex = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_hexdigit());
+    match uuid.len() {
+        4 if hex(&uuid) => Ok(format!("0000{}{}", uuid, BASE_UUID_SUFFIX)),
+        8 if hex(&uuid) => Ok(format!("{}{}", uuid, BASE_UUID_SUFFIX)),
+        36 if uuid.split('-').map(str::len).eq([8, 4, 4, 4, 12]) && hex(&uuid.replace('-', "")) => {
+            Ok(uuid)
+        }
+        _ => Err(format!("invalid UUID '{}'", uuid)),
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/**
+ * Shared
+ * The runtime btleplug runs on, the adapters and what the handler's
+ * devices reported.
+ */
+struct Shared {
+    /// Runtime of the `ble` thread, which btleplug's tasks run on.
+    runtime: Handle,
+    adapters: OnceCell<Vec<Adapter>>,
+    /// Connected peripherals, by device id.
+    peripherals: RwLock<HashMap<String, Peripheral>>,
+    states: RwLock<HashMap<String, HashMap<String, String>>>,
+    /// Why the adapters couldn't be opened, if they couldn't.
+    error: RwLock<Option<String>>,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl Shared {
+    fn new(
+        events: Option<Arc<EventBus>>,
+        sniffer: Option<Arc<Sniffer>>,
+    ) -> Result<Self, BlinkieError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Failed to start the ble runtime: {}", e))?;
+        let handle = runtime.handle().clone();
+        thread::Builder::new()
+            .name("ble".to_string())
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))
+            .map_err(|e| format!("Failed to start the ble runtime: {}", e))?;
+        Ok(Shared {
+            runtime: handle,
+            adapters: OnceCell::new(),
+            peripherals: RwLock::new(HashMap::new()),
+            states: RwLock::new(HashMap::new()),
+            error: RwLock::new(None),
+            events,
+            sniffer,
+        })
+    }
+
+    fn sniff(&self, device_id: &str, direction: Direction, uuid: &str, bytes: &[u8]) {
+        if let Some(sniffer) = &self.sniffer {
+            let frame = format!("{} {}", uuid, encode_hex(bytes));
+            sniffer.record(PROTOCOL, Some(device_id), direction, frame.as_bytes());
+        }
+    }
+
+    fn update_state(&self, device_id: &str, update: HashMap<String, String>) {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(device_id.to_string()).or_default();
+        for (key, new_value) in update {
+            let old_value = state.insert(key.clone(), new_value.clone());
+            if old_value.as_ref() == Some(&new_value) {
+                continue;
+            }
+            if let Some(events) = &self.events {
+                let _ = events.publish(EventKind::StateChanged {
+                    device_id: device_id.to_string(),
+                    key,
+                    old_value,
+                    new_value,
+                    reported_at: None,
+                });
+            }
+        }
+    }
+
+    /// Updates the keys mapped to characteristic `uuid` from its value.
+    fn take_value(&self, device_id: &str, spec: &BleSpec, uuid: &str, bytes: &[u8]) {
+        self.sniff(device_id, Direction::In, uuid, bytes);
+        let update: HashMap<String, String> = spec
+            .states
+            .iter()
+            .filter(|(_, mapping)| mapping.uuid == uuid)
+            .filter_map(|(key, mapping)| Some((key.clone(), mapping.decode(bytes)?)))
+            .collect();
+        self.update_state(device_id, update);
+    }
+
+    async fn adapter(&self, index: usize) -> Result<Adapter, BlinkieError> {
+        let adapters = self
+            .adapters
+            .get_or_try_init(|| async {
+                let manager = Manager::new().await.map_err(|e| {
+                    BlinkieError::connection(format!("Failed to open Bluetooth: {}", e))
+                })?;
+                let adapters = manager.adapters().await.map_err(|e| {
+                    BlinkieError::connection(format!("Failed to list Bluetooth adapters: {}", e))
+                })?;
+                // Scanning runs all the time, so peripherals that come in
+                // range are found
+                for adapter in &adapters {
+                    if let Err(e) = adapter.start_scan(ScanFilter::default()).await {
+                        log::warn!("Failed to start Bluetooth scan: {}", e);
+                    }
+                }
+                Ok::<_, BlinkieError>(adapters)
+            })
+            .await;
+        match adapters {
+            Ok(adapters) => {
+                *self.error.write().unwrap() = None;
+                adapters.get(index).cloned().ok_or_else(|| {
+                    BlinkieError::connection(format!("No Bluetooth adapter {}", index))
+                })
+            }
+            Err(e) => {
+                *self.error.write().unwrap() = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Finds the peripheral of `spec` among those the adapter has seen,
+    /// waiting for it to show up.
+    async fn find(&self, spec: &BleSpec) -> Result<Peripheral, BlinkieError> {
+        let adapter = self.adapter(spec.adapter).await?;
+        let deadline = tokio::time::Instant::now() + SCAN_TIMEOUT;
+        loop {
+            let peripherals = adapter
+                .peripherals()
+                .await
+                .map_err(|e| BlinkieError::connection(e.to_string()))?;
+            for peripheral in peripherals {
+                let Ok(Some(properties)) = peripheral.properties().await else {
+                    continue;
+                };
+                let matches = match (&spec.address, &spec.name) {
+                    (Some(address), _) => properties.address.to_string() == *address,
+                    (None, Some(name)) => properties.local_name.as_ref() == Some(name),
+                    (None, None) => false,
+                };
+                if matches {
+                    return Ok(peripheral);
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(BlinkieError::timeout(format!(
+                    "Peripheral '{}' not found",
+                    spec.target()
+                )));
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Connects to the peripheral of device `device_id` and keeps its state
+    /// up to date until the connection drops.
+    async fn session(&self, device_id: &str, spec: &BleSpec) -> Result<(), BlinkieError> {
+        let peripheral = self.find(spec).await?;
+        let io = |e: btleplug::Error| BlinkieError::connection(e.to_string());
+        if !peripheral.is_connected().await.map_err(io)? {
+            tokio::time::timeout(IO_TIMEOUT, peripheral.connect())
+                .await
+                .map_err(|_| BlinkieError::timeout("Timed out connecting"))?
+                .map_err(io)?;
+        }
+        peripheral.discover_services().await.map_err(io)?;
+        let characteristics: Vec<Characteristic> = peripheral
+            .characteristics()
+            .into_iter()
+            .filter(|characteristic| {
+                let uuid = characteristic.uuid.to_string();
+                spec.states.values().any(|mapping| mapping.uuid == uuid)
+            })
+            .collect();
+        let mut polled = Vec::new();
+        for characteristic in &characteristics {
+            let properties = characteristic.properties;
+            if properties.intersects(CharPropFlags::NOTIFY | CharPropFlags::INDICATE) {
+                peripheral.subscribe(characteristic).await.map_err(io)?;
+            }
+            if properties.contains(CharPropFlags::READ) {
+                polled.push(characteristic.clone());
+            }
+        }
+        self.peripherals
+            .write()
+            .unwrap()
+            .insert(device_id.to_string(), peripheral.clone());
+        let mut connected = HashMap::from([("connected".to_string(), "true".to_string())]);
+        if let Ok(Some(properties)) = peripheral.properties().await {
+            if let Some(rssi) = properties.rssi {
+                connected.insert("rssi".to_string(), rssi.to_string());
+            }
+        }
+        self.update_state(device_id, connected);
+
+        let mut notifications = peripheral.notifications().await.map_err(io)?;
+        let poll = Duration::from_secs(spec.poll_secs);
+        let mut next_poll = tokio::time::Instant::now();
+        let mut check = tokio::time::interval(LINK_CHECK);
+        loop {
+            if !poll.is_zero() && tokio::time::Instant::now() >= next_poll {
+                for characteristic in &polled {
+                    let bytes = peripheral.read(characteristic).await.map_err(io)?;
+                    self.take_value(device_id, spec, &characteristic.uuid.to_string(), &bytes);
+                }
+                next_poll = tokio::time::Instant::now() + poll;
+            }
+            tokio::select! {
+                notification = notifications.next() => {
+                    let Some(notification) = notification else {
+                        break;
+                    };
+                    let uuid = notification.uuid.to_string();
+                    self.take_value(device_id, spec, &uuid, &notification.value);
+                }
+                _ = check.tick() => {
+                    if !peripheral.is_connected().await.unwrap_or(false) {
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep_until(next_poll), if !poll.is_zero() => {}
+            }
+        }
+        Err(BlinkieError::connection("Disconnected"))
+    }
+
+    /// Keeps device `device_id` connected, reconnecting with a growing
+    /// wait while its peripheral is away.
+    async fn maintain(self: Arc<Self>, device_id: String, spec: BleSpec) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = tokio::time::Instant::now();
+            if let Err(e) = self.session(&device_id, &spec).await {
+                log::debug!("BLE device '{}': {}", device_id, e);
+            }
+            self.peripherals.write().unwrap().remove(&device_id);
+            self.update_state(
+                &device_id,
+                HashMap::from([("connected".to_string(), "false".to_string())]),
+            );
+            // A connection that lasted a while starts over with a short wait
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = MIN_BACKOFF;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Writes `bytes` to characteristic `uuid` of the connected peripheral
+    /// of `device_id`.
+    fn write(&self, device_id: &str, uuid: &str, bytes: &[u8]) -> Result<(), BlinkieError> {
+        let peripheral = self
+            .peripherals
+            .read()
+            .unwrap()
+            .get(device_id)
+            .cloned()
+            .ok_or_else(|| BlinkieError::connection(format!("'{}' is not connected", device_id)))?;
+        let characteristic = peripheral
+            .characteristics()
+            .into_iter()
+            .find(|characteristic| characteristic.uuid.to_string() == uuid)
+            .ok_or_else(|| format!("Device '{}' has no characteristic {}", device_id, uuid))?;
+        let write_type = if characteristic.properties.contains(CharPropFlags::WRITE) {
+            WriteType::WithResponse
+        } else {
+            WriteType::WithoutResponse
+        };
+        self.sniff(device_id, Direction::Out, uuid, bytes);
+        // Waits on a channel rather than blocking on the runtime, which
+        // works from async code as well
+        let (done, result) = mpsc::channel();
+        let bytes = bytes.to_vec();
+        self.runtime.spawn(async move {
+            let _ = done.send(peripheral.write(&characteristic, &bytes, write_type).await);
+        });
+        result
+            .recv_timeout(IO_TIMEOUT)
+            .map_err(|_| BlinkieError::timeout(format!("Timed out writing to '{}'", device_id)))?
+            .map_err(|e| BlinkieError::connection(e.to_string()))
+    }
+}
+
+/**
+ * BleDevice
+ * A Bluetooth Low Energy peripheral, e.g. an LED strip or a thermometer.
+ * Its state has the keys mapped to characteristics, whether it is
+ * `connected` and its signal strength, `rssi`. Besides its configured
+ * commands, `write` writes the hex `value` to any `characteristic`.
+ */
+pub struct BleDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    spec: BleSpec,
+    shared: Arc<Shared>,
+}
+
+impl Device for BleDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.shared
+            .states
+            .read()
+            .unwrap()
+            .get(&self.id)
+            .map(value::parse_state)
+            .unwrap_or_default()
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.shared.update_state(&self.id, format_state(&state));
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.command(command, parameters) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        let (uuid, bytes) = match self.spec.commands.get(command) {
+            Some(write) => (write.uuid.clone(), write.bytes(parameters.as_ref(), false)?),
+            None if command == "write" => {
+                let parameter = |name: &str| {
+                    parameters
+                        .as_ref()
+                        .and_then(|parameters| parameters.get(name))
+                        .ok_or_else(|| format!("Command 'write' needs parameter '{}'", name))
+                };
+                let uuid = full_uuid(parameter("characteristic")?)?;
+                let value = parameter("value")?;
+                let bytes = decode_hex(value).ok_or_else(|| format!("Invalid hex '{}'", value))?;
+                (uuid, bytes)
+            }
+            None => return Err(BlinkieError::unsupported_command(&self.id, command)),
+        };
+        self.shared.write(&self.id, &uuid, &bytes)?;
+        Ok(CommandResponse::default())
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        let mut commands: Vec<String> = self.spec.commands.keys().cloned().collect();
+        commands.sort();
+        commands.push("write".to_string());
+        Some(commands)
+    }
+}
+
+/**
+ * BleHandler
+ * The built-in `ble` handler: connects to Bluetooth Low Energy peripherals
+ * through the system's Bluetooth stack, see `BleSpec`. Devices connect in
+ * the background once the handler is initialized, and reconnect when
+ * their peripheral comes back in range.
+ */
+#[derive(Default)]
+pub struct BleHandler {
+    shared: Option<Arc<Shared>>,
+    /// Devices created before the handler was initialized.
+    pending: Mutex<Vec<(String, BleSpec)>>,
+    initialized: bool,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl BleHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes state changes of all peripherals on `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Records values read from and written to peripherals on `sniffer`
+    /// while it captures.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        self.sniffer = Some(sniffer);
+        self
+    }
+
+    /// Runtime and state of the handler, started on first use.
+    fn shared(&mut self) -> Result<Arc<Shared>, BlinkieError> {
+        if let Some(shared) = &self.shared {
+            return Ok(shared.clone());
+        }
+        let shared = Arc::new(Shared::new(self.events.clone(), self.sniffer.clone())?);
+        self.shared = Some(shared.clone());
+        Ok(shared)
+    }
+}
+
+impl ProtocolHandler for BleHandler {
+    fn name(&self) -> String {
+        PROTOCOL.to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = BleSpec::from_config(config)?;
+        let shared = self.shared()?;
+        if self.initialized {
+            shared
+                .runtime
+                .spawn(shared.clone().maintain(config.id.clone(), spec.clone()));
+        } else {
+            self.pending
+                .lock()
+                .unwrap()
+                .push((config.id.clone(), spec.clone()));
+        }
+        Ok(Box::new(BleDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            spec,
+            shared,
+        }))
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        BleSpec::from_config(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        device.command(cmd, params).map(|_| ())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        self.initialized = true;
+        let pending: Vec<_> = self.pending.lock().unwrap().drain(..).collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let shared = self.shared()?;
+        for (device_id, spec) in pending {
+            shared
+                .runtime
+                .spawn(shared.clone().maintain(device_id, spec));
+        }
+        Ok(())
+    }
+
+    fn health(&self) -> Result<(), BlinkieError> {
+        let error = self
+            .shared
+            .as_ref()
+            .and_then(|shared| shared.error.read().unwrap().clone());
+        match error {
+            Some(e) => Err(BlinkieError::connection(e)),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use crate::core::device::{Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use crate::core::value::{format_state, parse_state, StateMap};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedValue, Value};
+
+pub const PROTOCOL: &str = "desktop";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// Turns a property value into a state value.
+type Convert = fn(&Value) -> Option<String>;
+
+/**
+ * Desktop Sources
+ * System services on the system bus a sensor can follow, picked with the
+ * `source` connection detail.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Charge and charging state of the UPower display device.
+    Battery,
+    /// Whether the laptop lid is closed, from UPower.
+    Lid,
+    /// Connection state and connectivity from NetworkManager.
+    Network,
+    /// Whether logind is suspending the machine.
+    Sleep,
+}
+
+impl Source {
+    pub fn parse(source: &str) -> Result<Self, BlinkieError> {
+        match source {
+            "battery" => Ok(Source::Battery),
+            "lid" => Ok(Source::Lid),
+            "network" => Ok(Source::Network),
+            "sleep" => Ok(Source::Sleep),
+            other => Err(format!(
+                "Unknown desktop source '{}', expected battery, lid, network or sleep",
+                other
+            )
+            .into()),
+        }
+    }
+
+    /// Source in the `source` connection detail of `config`.
+    pub fn of(config: &Config) -> Result<Self, BlinkieError> {
+        config
+            .connection_details
+            .get("source")
+            .ok_or_else(|| format!("Desktop sensor '{}' has no source", config.id).into())
+            .and_then(|source| Self::parse(source))
+    }
+
+    /// Service, object path and interface the source reads.
+    fn object(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Source::Battery => (
+                "org.freedesktop.UPower",
+                "/org/freedesktop/UPower/devices/DisplayDevice",
+                "org.freedesktop.UPower.Device",
+            ),
+            Source::Lid => (
+                "org.freedesktop.UPower",
+                "/org/freedesktop/UPower",
+                "org.freedesktop.UPower",
+            ),
+            Source::Network => (
+                "org.freedesktop.NetworkManager",
+                "/org/freedesktop/NetworkManager",
+                "org.freedesktop.NetworkManager",
+            ),
+            Source::Sleep => (
+                "org.freedesktop.login1",
+                "/org/freedesktop/login1",
+                "org.freedesktop.login1.Manager",
+            ),
+        }
+    }
+
+    /// Properties followed, with the state key each one is stored under.
+    fn properties(self) -> &'static [(&'static str, &'static str, Convert)] {
+        match self {
+            Source::Battery => &[
+                ("Percentage", "battery", percentage),
+                ("State", "state", battery_state),
+            ],
+            Source::Lid => &[("LidIsClosed", "state", lid_state)],
+            Source::Network => &[
+                ("State", "state", network_state),
+                ("Connectivity", "connectivity", connectivity),
+            ],
+            Source::Sleep => &[],
+        }
+    }
+}
+
+fn percentage(value: &Value) -> Option<String> {
+    match value {
+        Value::F64(percent) => Some(format!("{:.0}", percent)),
+        _ => None,
+    }
+}
+
+fn battery_state(value: &Value) -> Option<String> {
+    let Value::U32(state) = value else {
+        return None;
+    };
+    let name = match state {
+        1 => "charging",
+        2 => "discharging",
+        3 => "empty",
+        4 => "fully_charged",
+        5 => "pending_charge",
+        6 => "pending_discharge",
+        _ => "unknown",
+    };
+    Some(name.to_string())
+}
+
+fn lid_state(value: &Value) -> Option<String> {
+    match value {
+        Value::Bool(true) => Some("closed".to_string()),
+        Value::Bool(false) => Some("open".to_string()),
+        _ => None,
+    }
+}
+
+fn network_state(value: &Value) -> Option<String> {
+    let Value::U32(state) = value else {
+        return None;
+    };
+    let name = match state {
+        10 => "asleep",
+        20 => "disconnected",
+        30 => "disconnecting",
+        40 => "connecting",
+        50 => "connected_local",
+        60 => "connected_site",
+        70 => "connected_global",
+        _ => "unknown",
+    };
+    Some(name.to_string())
+}
+
+fn connectivity(value: &Value) -> Option<String> {
+    let Value::U32(connectivity) = value else {
+        return None;
+    };
+    let name = match connectivity {
+        1 => "none",
+        2 => "portal",
+        3 => "limited",
+        4 => "full",
+        _ => "unknown",
+    };
+    Some(name.to_string())
+}
+
+#[derive(Default)]
+struct Watched {
+    state: HashMap<String, String>,
+    error: Option<String>,
+}
+
+/// Stores `state` for the device, publishing the values that changed.
+fn apply(
+    device_id: &str,
+    watched: &Mutex<Watched>,
+    events: Option<&EventBus>,
+    state: HashMap<String, String>,
+) {
+    let mut watched = watched.lock().unwrap();
+    watched.error = None;
+    for (key, new_value) in state {
+        let old_value = watched.state.insert(key.clone(), new_value.clone());
+        if old_value.as_ref() == Some(&new_value) {
+            continue;
+        }
+        if let Some(events) = events {
+            let _ = events.publish(EventKind::StateChanged {
+                device_id: device_id.to_string(),
+                key,
+                old_value,
+                new_value,
+                reported_at: None,
+            });
+        }
+    }
+}
+
+/// Follows `source` until the connection drops.
+fn follow(
+    source: Source,
+    device_id: &str,
+    watched: &Mutex<Watched>,
+    events: Option<&EventBus>,
+) -> Result<(), BlinkieError> {
+    let (service, path, interface) = source.object();
+    let connection = Connection::system()
+        .map_err(|e| BlinkieError::connection(format!("Failed to connect to D-Bus: {}", e)))?;
+
+    if source == Source::Sleep {
+        let proxy = Proxy::new(&connection, service, path, interface)
+            .map_err(|e| BlinkieError::connection(format!("Failed to reach {}: {}", service, e)))?;
+        apply(
+            device_id,
+            watched,
+            events,
+            HashMap::from([("state".to_string(), "awake".to_string())]),
+        );
+        let signals = proxy
+            .receive_signal("PrepareForSleep")
+            .map_err(|e| format!("Failed to follow {}: {}", service, e))?;
+        for signal in signals {
+            let Ok(sleeping) = signal.body().deserialize::<bool>() else {
+                continue;
+            };
+            let state = if sleeping { "sleeping" } else { "awake" };
+            apply(
+                device_id,
+                watched,
+                events,
+                HashMap::from([("state".to_string(), state.to_string())]),
+            );
+        }
+        return Ok(());
+    }
+
+    let convert = |properties: &HashMap<String, OwnedValue>| -> HashMap<String, String> {
+        source
+            .properties()
+            .iter()
+            .filter_map(|(property, key, convert)| {
+                let value = properties.get(*property)?;
+                convert(value).map(|value| (key.to_string(), value))
+            })
+            .collect()
+    };
+    let proxy = Proxy::new(&connection, service, path, PROPERTIES_INTERFACE)
+        .map_err(|e| BlinkieError::connection(format!("Failed to reach {}: {}", service, e)))?;
+    // Subscribe before reading so no change falls in between
+    let changes = proxy
+        .receive_signal("PropertiesChanged")
+        .map_err(|e| format!("Failed to follow {}: {}", service, e))?;
+    let properties: HashMap<String, OwnedValue> = proxy
+        .call("GetAll", &(interface,))
+        .map_err(|e| format!("Failed to read {}: {}", service, e))?;
+    apply(device_id, watched, events, convert(&properties));
+
+    for change in changes {
+        let Ok((changed_interface, properties, _)) =
+            change
+                .body()
+                .deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>()
+        else {
+            continue;
+        };
+        if changed_interface == interface {
+            apply(device_id, watched, events, convert(&properties));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * DesktopSensor
+ * A read-only sensor following a desktop service on the system bus.
+ */
+pub struct DesktopSensor {
+    id: String,
+    name: String,
+    watched: Arc<Mutex<Watched>>,
+}
+
+impl Device for DesktopSensor {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        parse_state(&self.watched.lock().unwrap().state)
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.watched
+            .lock()
+            .unwrap()
+            .state
+            .extend(format_state(&state));
+    }
+
+    fn send_cmd(&mut self, command: &str, _parameters: Option<HashMap<String, String>>) {
+        log::warn!("Sensor '{}' does not accept command '{}'", self.id, command);
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(Type::Sensor)
+    }
+}
+
+/**
+ * DesktopHandler
+ * Exposes UPower, NetworkManager and logind as sensors, so automations can
+ * react to the lid closing, the battery running low or the network going
+ * away. Each sensor follows its service on a thread of its own and
+ * reconnects when the service restarts.
+ */
+#[derive(Default)]
+pub struct DesktopHandler {
+    events: Option<Arc<EventBus>>,
+    devices: RwLock<HashMap<String, Arc<Mutex<Watched>>>>,
+}
+
+impl DesktopHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes sensor changes on `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+}
+
+impl ProtocolHandler for DesktopHandler {
+    fn name(&self) -> String {
+        "desktop".to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let source = Source::of(config)?;
+        let watched = Arc::new(Mutex::new(Watched::default()));
+        self.devices
+            .write()
+            .unwrap()
+            .insert(config.id.clone(), watched.clone());
+
+        let device_id = config.id.clone();
+        let events = self.events.clone();
+        let following = watched.clone();
+        thread::Builder::new()
+            .name(format!("desktop-{}", config.id))
+            .spawn(move || loop {
+                let error = match follow(source, &device_id, &following, events.as_deref()) {
+                    Ok(()) => format!("{:?} source went away", source),
+                    Err(e) => e.to_string(),
+                };
+                log::warn!("Desktop sensor '{}': {}", device_id, error);
+                following.lock().unwrap().error = Some(error);
+                thread::sleep(RECONNECT_DELAY);
+            })
+            .map_err(|e| format!("Failed to start desktop sensor: {}", e))?;
+
+        Ok(Box::new(DesktopSensor {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            watched,
+        }))
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        Source::of(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        _params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        Err(format!(
+            "Sensor '{}' does not accept command '{}'",
+            device.get_id(),
+            cmd
+        )
+        .into())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        Ok(())
+    }
+
+    fn health(&self) -> Result<(), BlinkieError> {
+        let devices = self.devices.read().unwrap();
+        let mut failing: Vec<String> = devices
+            .iter()
+            .filter_map(|(id, watched)| {
+                let error = watched.lock().unwrap().error.clone()?;
+                Some(format!("{}: {}", id, error))
+            })
+            .collect();
+        if failing.is_empty() {
+            return Ok(());
+        }
+        failing.sort();
+        Err(failing.join("; ").into())
+    }
+}
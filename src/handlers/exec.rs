@@ -0,0 +1,691 @@
+use crate::core::device::{CommandResponse, Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::job::JobHandle;
+use crate::core::replay::Decoder;
+use crate::core::sniffer::{Direction, Frame, Sniffer};
+use crate::core::value::{format_state, parse_state, StateMap};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL: &str = "exec";
+
+const DEFAULT_SHELL: &str = "sh";
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_POLL_SECS: u64 = 5;
+/// Prefix of the connection details holding the script for a command.
+const COMMAND_PREFIX: &str = "command.";
+/// Prefix of the connection details holding the script for a job.
+const JOB_PREFIX: &str = "job.";
+
+/**
+ * State Formats
+ * How the output of the state command is turned into device state.
+ */
+#[derive(Clone, Debug)]
+pub enum StateFormat {
+    /// The trimmed output becomes the `state` key.
+    Raw,
+    /// The output is a JSON object whose top-level fields become keys.
+    Json,
+    /// Named capture groups of the pattern become keys.
+    Regex(Regex),
+}
+
+impl StateFormat {
+    /// Reads the format from the `state_format` and `state_pattern`
+    /// connection details of a device.
+    pub fn from_details(
+        details: &HashMap<String, String>,
+        device_id: &str,
+    ) -> Result<Self, BlinkieError> {
+        let format = match details.get("state_format").map(String::as_str) {
+            None | Some("raw") => StateFormat::Raw,
+            Some("json") => StateFormat::Json,
+            Some("regex") => {
+                let pattern = details.get("state_pattern").ok_or_else(|| {
+                    format!(
+                        "Device '{}' uses the regex format without a state_pattern",
+                        device_id
+                    )
+                })?;
+                StateFormat::Regex(Regex::new(pattern).map_err(|e| {
+                    BlinkieError::serialization(format!(
+                        "Invalid state_pattern for '{}': {}",
+                        device_id, e
+                    ))
+                })?)
+            }
+            Some(other) => {
+                return Err(format!(
+                    "Unknown state_format '{}' for device '{}'",
+                    other, device_id
+                )
+                .into())
+            }
+        };
+        Ok(format)
+    }
+
+    /// Turns the output of a state command into state.
+    pub fn parse(&self, output: &str) -> Result<HashMap<String, String>, BlinkieError> {
+        match self {
+            StateFormat::Raw => Ok(HashMap::from([(
+                "state".to_string(),
+                output.trim().to_string(),
+            )])),
+            StateFormat::Json => {
+                let value: Value = serde_json::from_str(output)
+                    .map_err(|e| format!("State command printed invalid JSON: {}", e))?;
+                let Value::Object(fields) = value else {
+                    return Err("State command must print a JSON object".into());
+                };
+                Ok(fields
+                    .into_iter()
+                    .map(|(key, value)| match value {
+                        Value::String(s) => (key, s),
+                        other => (key, other.to_string()),
+                    })
+                    .collect())
+            }
+            StateFormat::Regex(pattern) => {
+                let captures = pattern.captures(output).ok_or_else(|| {
+                    "State command output did not match state_pattern".to_string()
+                })?;
+                Ok(pattern
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| {
+                        captures
+                            .name(name)
+                            .map(|m| (name.to_string(), m.as_str().to_string()))
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/**
+ * ExecSpec
+ * Scripts backing a device, read from its connection details:
+ *
+ * - `state_command`: prints the device state
+ * - `state_format`: `raw` (default), `json` or `regex`
+ * - `state_pattern`: regex with named groups, for the `regex` format
+ * - `command.<name>`: runs for command `<name>`, with its parameters in
+ *   `BLINKIE_PARAM_<NAME>` environment variables
+ * - `job.<name>`: like `command.<name>`, but runs in the background as a
+ *   job without a timeout. Lines `progress <percent> [<phase>]` and
+ *   `phase <phase>` it prints are reported as progress.
+ * - `shell`, `timeout_secs` and `poll_secs`
+ */
+#[derive(Clone, Debug)]
+pub struct ExecSpec {
+    pub shell: String,
+    pub state_command: Option<String>,
+    pub state_format: StateFormat,
+    pub commands: HashMap<String, String>,
+    pub jobs: HashMap<String, String>,
+    pub timeout: Duration,
+    /// How long a read state is reused before the state command runs again.
+    pub poll: Duration,
+}
+
+impl ExecSpec {
+    pub fn from_config(config: &Config) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let secs = |key: &str, default: u64| -> Result<Duration, BlinkieError> {
+            match details.get(key) {
+                Some(value) => value.parse().map(Duration::from_secs).map_err(|_| {
+                    format!("Invalid {} '{}' for device '{}'", key, value, config.id).into()
+                }),
+                None => Ok(Duration::from_secs(default)),
+            }
+        };
+        let state_format = StateFormat::from_details(details, &config.id)?;
+        let scripts = |prefix: &str| -> HashMap<String, String> {
+            details
+                .iter()
+                .filter_map(|(key, script)| {
+                    key.strip_prefix(prefix)
+                        .map(|name| (name.to_string(), script.clone()))
+                })
+                .collect()
+        };
+        let commands = scripts(COMMAND_PREFIX);
+        let jobs = scripts(JOB_PREFIX);
+        let state_command = details.get("state_command").cloned();
+        if state_command.is_none() && commands.is_empty() && jobs.is_empty() {
+            return Err(format!(
+                "Device '{}' has neither a state_command nor any command.<name> or job.<name>",
+                config.id
+            )
+            .into());
+        }
+
+        Ok(ExecSpec {
+            shell: details
+                .get("shell")
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_SHELL.to_string()),
+            state_command,
+            state_format,
+            commands,
+            jobs,
+            timeout: secs("timeout_secs", DEFAULT_TIMEOUT_SECS)?,
+            poll: secs("poll_secs", DEFAULT_POLL_SECS)?,
+        })
+    }
+
+    /// Runs the state command and parses its output.
+    pub fn read_state(&self, device_id: &str) -> Result<HashMap<String, String>, BlinkieError> {
+        match self.read_output(device_id)? {
+            Some(output) => self.parse_state(&output),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Runs the state command, if there is one, and returns what it printed.
+    pub fn read_output(&self, device_id: &str) -> Result<Option<String>, BlinkieError> {
+        let Some(script) = &self.state_command else {
+            return Ok(None);
+        };
+        let env = HashMap::from([("BLINKIE_DEVICE_ID".to_string(), device_id.to_string())]);
+        run_shell(&self.shell, script, &env, self.timeout).map(Some)
+    }
+
+    /// Turns the output of the state command into state.
+    pub fn parse_state(&self, output: &str) -> Result<HashMap<String, String>, BlinkieError> {
+        self.state_format.parse(output)
+    }
+
+    /// Runs the script configured for `command` and returns what it printed.
+    pub fn run_command(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<String, BlinkieError> {
+        let script = self
+            .commands
+            .get(command)
+            .ok_or_else(|| BlinkieError::unsupported_command(device_id, command))?;
+        run_shell(
+            &self.shell,
+            script,
+            &command_env(device_id, command, parameters),
+            self.timeout,
+        )
+    }
+
+    /// Starts the job script configured for `command` on a thread of its
+    /// own, reporting through `job`.
+    pub fn start_job(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+        job: JobHandle,
+    ) -> Result<(), BlinkieError> {
+        let script = self
+            .jobs
+            .get(command)
+            .ok_or_else(|| format!("Device '{}' has no job '{}'", device_id, command))?
+            .clone();
+        let mut child = Command::new(&self.shell)
+            .arg("-c")
+            .arg(&script)
+            .envs(command_env(device_id, command, parameters))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run '{}': {}", script, e))?;
+        let stdout = child.stdout.take();
+        let mut stderr = child.stderr.take();
+        thread::Builder::new()
+            .name(format!("job-{}", job.id()))
+            .spawn(move || {
+                let reporter = job.clone();
+                let progress = thread::spawn(move || {
+                    for line in stdout
+                        .map(BufReader::new)
+                        .into_iter()
+                        .flat_map(|stdout| stdout.lines().map_while(Result::ok))
+                    {
+                        report_progress(&reporter, &line);
+                    }
+                });
+                let errors = thread::spawn(move || {
+                    let mut output = String::new();
+                    if let Some(stderr) = stderr.as_mut() {
+                        let _ = stderr.read_to_string(&mut output);
+                    }
+                    output
+                });
+                let status = loop {
+                    if job.is_cancelled() {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return;
+                    }
+                    match child.try_wait() {
+                        Ok(Some(status)) => break status,
+                        Ok(None) => thread::sleep(Duration::from_millis(100)),
+                        Err(e) => return job.fail(&format!("Failed to wait for job: {}", e)),
+                    }
+                };
+                let _ = progress.join();
+                let errors = errors.join().unwrap_or_default();
+                if status.success() {
+                    job.complete();
+                } else {
+                    job.fail(&format!("Exited with {}: {}", status, errors.trim()));
+                }
+            })
+            .map(|_| ())
+            .map_err(|e| format!("Failed to start job: {}", e).into())
+    }
+}
+
+/// Turns what a command script printed into its response: the fields of a
+/// JSON object, or the trimmed text as `output`.
+pub(crate) fn parse_response(output: &str) -> CommandResponse {
+    if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(output) {
+        return CommandResponse::new(
+            fields
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(s) => (key, s),
+                    other => (key, other.to_string()),
+                })
+                .collect(),
+        );
+    }
+    match output.trim() {
+        "" => CommandResponse::default(),
+        text => CommandResponse::new(HashMap::from([("output".to_string(), text.to_string())])),
+    }
+}
+
+/// Environment a command or job script runs with.
+fn command_env(
+    device_id: &str,
+    command: &str,
+    parameters: Option<&HashMap<String, String>>,
+) -> HashMap<String, String> {
+    let mut env = HashMap::from([
+        ("BLINKIE_DEVICE_ID".to_string(), device_id.to_string()),
+        ("BLINKIE_COMMAND".to_string(), command.to_string()),
+    ]);
+    for (key, value) in parameters.into_iter().flatten() {
+        env.insert(env_name(key), value.clone());
+    }
+    env
+}
+
+/// Reports a `progress <percent> [<phase>]` or `phase <phase>` line of a
+/// job script. Other lines are ignored.
+fn report_progress(job: &JobHandle, line: &str) {
+    let line = line.trim();
+    if let Some(rest) = line.strip_prefix("progress ") {
+        let (percent, phase) = rest.split_once(' ').unwrap_or((rest, ""));
+        let Ok(percent) = percent.trim_end_matches('%').parse::<f64>() else {
+            return;
+        };
+        let phase = phase.trim();
+        job.progress(
+            Some(percent.clamp(0.0, 100.0) as u8),
+            (!phase.is_empty()).then_some(phase),
+        );
+    } else if let Some(phase) = line.strip_prefix("phase ") {
+        job.progress(None, Some(phase.trim()));
+    }
+}
+
+/// Environment variable a command parameter is passed in, e.g.
+/// `brightness` becomes `BLINKIE_PARAM_BRIGHTNESS`.
+fn env_name(parameter: &str) -> String {
+    let name: String = parameter
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("BLINKIE_PARAM_{}", name)
+}
+
+/// Runs `script` with `shell -c`, killing it when it takes longer than
+/// `timeout`. Returns its standard output.
+pub fn run_shell(
+    shell: &str,
+    script: &str,
+    env: &HashMap<String, String>,
+    timeout: Duration,
+) -> Result<String, BlinkieError> {
+    let mut child = Command::new(shell)
+        .arg("-c")
+        .arg(script)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run '{}': {}", script, e))?;
+
+    // Read output on other threads so a chatty script can't block on a full pipe
+    let read = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut output = String::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_string(&mut output);
+            }
+            output
+        })
+    };
+    let stdout = read(
+        child
+            .stdout
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    );
+    let stderr = read(
+        child
+            .stderr
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    );
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() > timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(BlinkieError::timeout(format!(
+                    "'{}' timed out after {} seconds",
+                    script,
+                    timeout.as_secs()
+                )));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => return Err(format!("Failed to wait for '{}': {}", script, e).into()),
+        }
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(format!("'{}' failed with {}: {}", script, status, stderr.trim()).into());
+    }
+    Ok(stdout)
+}
+
+#[derive(Default)]
+struct Cached {
+    state: HashMap<String, String>,
+    read_at: Option<Instant>,
+    error: Option<String>,
+}
+
+/**
+ * ExecDevice
+ * A device backed by shell scripts. Its state is read by running the state
+ * command at most once per poll interval.
+ */
+pub struct ExecDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    spec: ExecSpec,
+    cache: Arc<Mutex<Cached>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl ExecDevice {
+    fn refresh(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .read_at
+            .is_some_and(|read_at| read_at.elapsed() < self.spec.poll)
+        {
+            return;
+        }
+        let state = self.spec.read_output(&self.id).and_then(|output| {
+            let Some(output) = output else {
+                return Ok(HashMap::new());
+            };
+            if let Some(sniffer) = &self.sniffer {
+                sniffer.record(PROTOCOL, Some(&self.id), Direction::In, output.as_bytes());
+            }
+            self.spec.parse_state(&output)
+        });
+        match state {
+            Ok(state) => {
+                cache.state = state;
+                cache.error = None;
+            }
+            Err(e) => {
+                log::warn!("Failed to read state of '{}': {}", self.id, e);
+                cache.error = Some(e.to_string());
+            }
+        }
+        cache.read_at = Some(Instant::now());
+    }
+}
+
+impl Device for ExecDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.refresh();
+        parse_state(&self.cache.lock().unwrap().state)
+    }
+
+    // State comes from the state command; values set from outside are kept
+    // until it runs again
+    fn set_state(&mut self, state: StateMap) {
+        self.cache
+            .lock()
+            .unwrap()
+            .state
+            .extend(format_state(&state));
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.command(command, parameters) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        if let (Some(sniffer), Some(script)) = (&self.sniffer, self.spec.commands.get(command)) {
+            let mut frame = script.clone();
+            for (key, value) in parameters.iter().flatten() {
+                frame.push_str(&format!("\n{}={}", env_name(key), value));
+            }
+            sniffer.record(PROTOCOL, Some(&self.id), Direction::Out, frame.as_bytes());
+        }
+        let output = self
+            .spec
+            .run_command(&self.id, command, parameters.as_ref());
+        // Read the state again on next access to pick up the effect
+        self.cache.lock().unwrap().read_at = None;
+        output.map(|output| parse_response(&output))
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+
+    fn start_job(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+        job: JobHandle,
+    ) -> Result<bool, BlinkieError> {
+        if !self.spec.jobs.contains_key(command) {
+            return Ok(false);
+        }
+        self.spec
+            .start_job(&self.id, command, parameters.as_ref(), job)?;
+        // Read the state again on next access to pick up the effect
+        self.cache.lock().unwrap().read_at = None;
+        Ok(true)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        let mut commands: Vec<String> = self
+            .spec
+            .commands
+            .keys()
+            .chain(self.spec.jobs.keys())
+            .cloned()
+            .collect();
+        commands.sort();
+        Some(commands)
+    }
+}
+
+/**
+ * ExecDecoder
+ * Replays recorded state command output through the device's
+ * `state_format`.
+ */
+pub struct ExecDecoder;
+
+impl Decoder for ExecDecoder {
+    fn decode(
+        &self,
+        frame: &Frame,
+        details: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, BlinkieError> {
+        let device_id = frame.device_id.as_deref().unwrap_or_default();
+        StateFormat::from_details(details, device_id)?.parse(&frame.data)
+    }
+}
+
+/**
+ * ExecHandler
+ * Turns anything scriptable into a device: state is read by running a
+ * command and parsing its output, and every device command runs a script.
+ */
+#[derive(Default)]
+pub struct ExecHandler {
+    /// Read cache of every device created, for health reporting.
+    devices: RwLock<HashMap<String, Arc<Mutex<Cached>>>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl ExecHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records scripts run and their output on `sniffer` while it captures.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        self.sniffer = Some(sniffer);
+        self
+    }
+}
+
+impl ProtocolHandler for ExecHandler {
+    fn name(&self) -> String {
+        "exec".to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = ExecSpec::from_config(config)?;
+        let cache = Arc::new(Mutex::new(Cached::default()));
+        self.devices
+            .write()
+            .unwrap()
+            .insert(config.id.clone(), cache.clone());
+        Ok(Box::new(ExecDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            spec,
+            cache,
+            sniffer: self.sniffer.clone(),
+        }))
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        ExecSpec::from_config(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        device.send_cmd(cmd, params);
+        Ok(())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        Ok(())
+    }
+
+    /// Reads the state of `device`, running its state command once the
+    /// poll interval passed, and reports the state command failing.
+    fn check_health(&self, device: &dyn Device) -> Result<(), BlinkieError> {
+        device.get_state();
+        let devices = self.devices.read().unwrap();
+        match devices
+            .get(device.get_id())
+            .and_then(|cache| cache.lock().unwrap().error.clone())
+        {
+            Some(error) => Err(BlinkieError::connection(error)),
+            None => Ok(()),
+        }
+    }
+
+    fn health(&self) -> Result<(), BlinkieError> {
+        let devices = self.devices.read().unwrap();
+        let mut failing: Vec<&String> = devices
+            .iter()
+            .filter(|(_, cache)| cache.lock().unwrap().error.is_some())
+            .map(|(id, _)| id)
+            .collect();
+        if failing.is_empty() {
+            return Ok(());
+        }
+        failing.sort();
+        Err(format!(
+            "State command failing for {}",
+            failing
+                .iter()
+                .map(|id| id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .into())
+    }
+}
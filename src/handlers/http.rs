@@ -0,0 +1,507 @@
+use super::exec::{parse_response, StateFormat};
+use crate::core::device::{CommandResponse, Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::replay::Decoder;
+use crate::core::sniffer::{Direction, Frame, Sniffer};
+use crate::core::value::{format_state, parse_state, StateMap};
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Method;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL: &str = "http";
+
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_POLL_SECS: u64 = 5;
+/// Prefix of the connection details holding the request for a command.
+const COMMAND_PREFIX: &str = "command.";
+/// Prefix of the connection details holding the body for a command.
+const BODY_PREFIX: &str = "body.";
+/// Prefix of the connection details holding a header sent with every request.
+const HEADER_PREFIX: &str = "header.";
+
+/**
+ * HttpRequest
+ * A request template: `[METHOD ]<url>` and an optional body. Both may
+ * contain `{{ name }}` placeholders for `device_id`, `command` and the
+ * command's parameters; values are percent-encoded in the URL.
+ */
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub body: Option<String>,
+}
+
+impl HttpRequest {
+    /// Parses `[METHOD ]<url>`, GET when no method is given.
+    pub fn parse(request: &str, body: Option<String>) -> Result<Self, BlinkieError> {
+        let request = request.trim();
+        let (method, url) = match request.split_once(' ') {
+            Some((method, url)) if method.chars().all(|c| c.is_ascii_alphabetic()) => (
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("Invalid HTTP method '{}'", method))?,
+                url.trim(),
+            ),
+            _ => (Method::GET, request),
+        };
+        Ok(HttpRequest {
+            method,
+            url: url.to_string(),
+            body,
+        })
+    }
+}
+
+/// Replaces every `{{ name }}` in `template` with its value, passing each
+/// value through `encode`. Unknown names render as an empty string.
+fn render(template: &str, values: &HashMap<String, String>, encode: fn(&str) -> String) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        output.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        output.push_str(
+            &values
+                .get(name)
+                .map(|value| encode(value))
+                .unwrap_or_default(),
+        );
+        rest = &rest[start + end + 2..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Percent-encodes everything but unreserved characters.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/**
+ * HttpSpec
+ * Requests backing a device, read from its connection details:
+ *
+ * - `base_url`: prefixed to request URLs that aren't absolute
+ * - `state_url`: `[METHOD ]<url>` answering the device state
+ * - `state_format`: `raw` (default), `json` or `regex`, as for `exec`
+ * - `state_pattern`: regex with named groups, for the `regex` format
+ * - `command.<name>`: `[METHOD ]<url>` sent for command `<name>`
+ * - `body.<name>`: body of the request for command `<name>`
+ * - `header.<name>`: header sent with every request, e.g.
+ *   `header.Authorization`
+ * - `username` and `password`: basic authentication
+ * - `timeout_secs` and `poll_secs`
+ */
+#[derive(Clone, Debug)]
+pub struct HttpSpec {
+    pub base_url: Option<String>,
+    pub state: Option<HttpRequest>,
+    pub state_format: StateFormat,
+    pub commands: HashMap<String, HttpRequest>,
+    pub headers: HeaderMap,
+    pub basic_auth: Option<(String, Option<String>)>,
+    pub timeout: Duration,
+    /// How long a read state is reused before it is requested again.
+    pub poll: Duration,
+}
+
+impl HttpSpec {
+    pub fn from_config(config: &Config) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let secs = |key: &str, default: u64| -> Result<Duration, BlinkieError> {
+            match details.get(key) {
+                Some(value) => value.parse().map(Duration::from_secs).map_err(|_| {
+                    format!("Invalid {} '{}' for device '{}'", key, value, config.id).into()
+                }),
+                None => Ok(Duration::from_secs(default)),
+            }
+        };
+        let invalid =
+            |e: BlinkieError| format!("Invalid request for device '{}': {}", config.id, e);
+        let state = details
+            .get("state_url")
+            .map(|request| HttpRequest::parse(request, None))
+            .transpose()
+            .map_err(invalid)?;
+        let mut commands = HashMap::new();
+        let mut headers = HeaderMap::new();
+        for (key, value) in details {
+            if let Some(command) = key.strip_prefix(COMMAND_PREFIX) {
+                let body = details.get(&format!("{}{}", BODY_PREFIX, command)).cloned();
+                commands.insert(
+                    command.to_string(),
+                    HttpRequest::parse(value, body).map_err(invalid)?,
+                );
+            } else if let Some(name) = key.strip_prefix(HEADER_PREFIX) {
+                let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                    format!("Invalid header name '{}' for device '{}'", name, config.id)
+                })?;
+                let value = HeaderValue::from_str(value).map_err(|_| {
+                    format!(
+                        "Invalid value of header '{}' for device '{}'",
+                        name, config.id
+                    )
+                })?;
+                headers.insert(name, value);
+            }
+        }
+        if state.is_none() && commands.is_empty() {
+            return Err(format!(
+                "Device '{}' has neither a state_url nor any command.<name>",
+                config.id
+            )
+            .into());
+        }
+
+        Ok(HttpSpec {
+            base_url: details
+                .get("base_url")
+                .map(|url| url.trim_end_matches('/').to_string()),
+            state,
+            state_format: StateFormat::from_details(details, &config.id)?,
+            commands,
+            headers,
+            basic_auth: details
+                .get("username")
+                .map(|username| (username.clone(), details.get("password").cloned())),
+            timeout: secs("timeout_secs", DEFAULT_TIMEOUT_SECS)?,
+            poll: secs("poll_secs", DEFAULT_POLL_SECS)?,
+        })
+    }
+
+    /// URL of a request with its placeholders filled in.
+    fn url(&self, request: &HttpRequest, values: &HashMap<String, String>) -> String {
+        let url = render(&request.url, values, percent_encode);
+        match &self.base_url {
+            Some(base) if !url.contains("://") => {
+                format!("{}/{}", base, url.trim_start_matches('/'))
+            }
+            _ => url,
+        }
+    }
+
+    /// Sends a request and returns the response body. Responses other than
+    /// 2xx are errors.
+    ///
+    /// The request runs on a thread of its own with a client of its own, as
+    /// blocking clients must not be created or dropped on the async runtime
+    /// devices may be called from.
+    pub fn send(
+        &self,
+        request: &HttpRequest,
+        values: &HashMap<String, String>,
+    ) -> Result<String, BlinkieError> {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| self.send_blocking(request, values))
+                .join()
+                .unwrap_or_else(|_| Err("HTTP request panicked".into()))
+        })
+    }
+
+    fn send_blocking(
+        &self,
+        request: &HttpRequest,
+        values: &HashMap<String, String>,
+    ) -> Result<String, BlinkieError> {
+        let client = Client::builder()
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        let url = self.url(request, values);
+        let mut builder = client
+            .request(request.method.clone(), &url)
+            .headers(self.headers.clone())
+            .timeout(self.timeout);
+        if let Some((username, password)) = &self.basic_auth {
+            builder = builder.basic_auth(username, password.as_ref());
+        }
+        if let Some(body) = &request.body {
+            builder = builder.body(render(body, values, str::to_string));
+        }
+        let response = builder.send().map_err(|e| {
+            let message = format!("{} {} failed: {}", request.method, url, e);
+            if e.is_timeout() {
+                BlinkieError::Timeout(message)
+            } else {
+                BlinkieError::Connection(message)
+            }
+        })?;
+        let status = response.status();
+        let text = response
+            .text()
+            .map_err(|e| format!("Failed to read response of {}: {}", url, e))?;
+        if !status.is_success() {
+            return Err(format!(
+                "{} {} answered {}: {}",
+                request.method,
+                url,
+                status,
+                text.trim()
+            )
+            .into());
+        }
+        Ok(text)
+    }
+
+    /// Requests the state, if there is a state URL, and returns the body.
+    pub fn read_output(&self, device_id: &str) -> Result<Option<String>, BlinkieError> {
+        let Some(request) = &self.state else {
+            return Ok(None);
+        };
+        let values = HashMap::from([("device_id".to_string(), device_id.to_string())]);
+        self.send(request, &values).map(Some)
+    }
+
+    /// Sends the request configured for `command` and returns the body.
+    pub fn run_command(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<String, BlinkieError> {
+        let request = self
+            .commands
+            .get(command)
+            .ok_or_else(|| BlinkieError::unsupported_command(device_id, command))?;
+        let mut values = parameters.cloned().unwrap_or_default();
+        values.insert("device_id".to_string(), device_id.to_string());
+        values.insert("command".to_string(), command.to_string());
+        self.send(request, &values)
+    }
+}
+
+#[derive(Default)]
+struct Cached {
+    state: HashMap<String, String>,
+    read_at: Option<Instant>,
+    error: Option<String>,
+}
+
+/**
+ * HttpDevice
+ * A device behind a REST API. Its state is requested at most once per
+ * poll interval.
+ */
+pub struct HttpDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    spec: HttpSpec,
+    cache: Arc<Mutex<Cached>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl HttpDevice {
+    fn refresh(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .read_at
+            .is_some_and(|read_at| read_at.elapsed() < self.spec.poll)
+        {
+            return;
+        }
+        let state = self.spec.read_output(&self.id).and_then(|output| {
+            let Some(output) = output else {
+                return Ok(HashMap::new());
+            };
+            if let Some(sniffer) = &self.sniffer {
+                sniffer.record(PROTOCOL, Some(&self.id), Direction::In, output.as_bytes());
+            }
+            self.spec.state_format.parse(&output)
+        });
+        match state {
+            Ok(state) => {
+                cache.state = state;
+                cache.error = None;
+            }
+            Err(e) => {
+                log::warn!("Failed to read state of '{}': {}", self.id, e);
+                cache.error = Some(e.to_string());
+            }
+        }
+        cache.read_at = Some(Instant::now());
+    }
+}
+
+impl Device for HttpDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.refresh();
+        parse_state(&self.cache.lock().unwrap().state)
+    }
+
+    // State comes from the state URL; values set from outside are kept
+    // until it is requested again
+    fn set_state(&mut self, state: StateMap) {
+        self.cache
+            .lock()
+            .unwrap()
+            .state
+            .extend(format_state(&state));
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.command(command, parameters) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        if let (Some(sniffer), Some(request)) = (&self.sniffer, self.spec.commands.get(command)) {
+            let mut frame = format!("{} {}", request.method, request.url);
+            for (key, value) in parameters.iter().flatten() {
+                frame.push_str(&format!("\n{}={}", key, value));
+            }
+            sniffer.record(PROTOCOL, Some(&self.id), Direction::Out, frame.as_bytes());
+        }
+        let output = self
+            .spec
+            .run_command(&self.id, command, parameters.as_ref());
+        // Request the state again on next access to pick up the effect
+        self.cache.lock().unwrap().read_at = None;
+        output.map(|output| parse_response(&output))
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        let mut commands: Vec<String> = self.spec.commands.keys().cloned().collect();
+        commands.sort();
+        Some(commands)
+    }
+}
+
+/**
+ * HttpDecoder
+ * Replays recorded state responses through the device's `state_format`.
+ */
+pub struct HttpDecoder;
+
+impl Decoder for HttpDecoder {
+    fn decode(
+        &self,
+        frame: &Frame,
+        details: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, BlinkieError> {
+        let device_id = frame.device_id.as_deref().unwrap_or_default();
+        StateFormat::from_details(details, device_id)?.parse(&frame.data)
+    }
+}
+
+/**
+ * HttpHandler
+ * Drives devices with simple REST APIs, such as Wi-Fi bulbs and relays,
+ * from request templates in their connection details instead of code.
+ */
+#[derive(Default)]
+pub struct HttpHandler {
+    /// Read cache of every device created, for health reporting.
+    devices: RwLock<HashMap<String, Arc<Mutex<Cached>>>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl HttpHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records requests and state responses on `sniffer` while it captures.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        self.sniffer = Some(sniffer);
+        self
+    }
+}
+
+impl ProtocolHandler for HttpHandler {
+    fn name(&self) -> String {
+        PROTOCOL.to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = HttpSpec::from_config(config)?;
+        let cache = Arc::new(Mutex::new(Cached::default()));
+        self.devices
+            .write()
+            .unwrap()
+            .insert(config.id.clone(), cache.clone());
+        Ok(Box::new(HttpDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            spec,
+            cache,
+            sniffer: self.sniffer.clone(),
+        }))
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        HttpSpec::from_config(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        device.command(cmd, params).map(|_| ())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        Ok(())
+    }
+
+    fn health(&self) -> Result<(), BlinkieError> {
+        let devices = self.devices.read().unwrap();
+        let mut failing: Vec<&String> = devices
+            .iter()
+            .filter(|(_, cache)| cache.lock().unwrap().error.is_some())
+            .map(|(id, _)| id)
+            .collect();
+        if failing.is_empty() {
+            return Ok(());
+        }
+        failing.sort();
+        Err(format!(
+            "State request failing for {}",
+            failing
+                .iter()
+                .map(|id| id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .into())
+    }
+}
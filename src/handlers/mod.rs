@@ -0,0 +1,17 @@
+#[cfg(feature = "ble")]
+pub mod ble;
+#[cfg(feature = "dbus")]
+pub mod desktop;
+pub mod exec;
+pub mod http;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "ocpp")]
+pub mod ocpp;
+pub mod plugin;
+#[cfg(feature = "serial")]
+pub mod serial;
+pub mod simulation;
+pub mod subprocess;
+#[cfg(feature = "mqtt")]
+pub mod zigbee2mqtt;
@@ -0,0 +1,901 @@
+use crate::core::device::{Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use crate::core::freshness::parse_timestamp;
+use crate::core::replay::Decoder;
+use crate::core::sniffer::{Direction, Frame, Sniffer};
+use crate::core::value::{self, format_state, StateMap};
+use chrono::{DateTime, Utc};
+use rumqttc::v5::mqttbytes::v5::{Packet, PublishProperties};
+use rumqttc::v5::mqttbytes::{matches, QoS};
+use rumqttc::v5::{Client, Connection, Event, MqttOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+pub const PROTOCOL: &str = "mqtt";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const REQUEST_CAPACITY: usize = 64;
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_keep_alive_secs() -> u64 {
+    30
+}
+
+fn default_session_expiry_secs() -> u32 {
+    3600
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_topic_alias_max() -> u16 {
+    16
+}
+
+/**
+ * MqttConfig
+ * Broker connection of an MQTT handler. With `clean_start` off the broker
+ * keeps the session, subscriptions and undelivered QoS 1/2 messages for
+ * `session_expiry_secs` across disconnects, and messages in flight are sent
+ * again after reconnecting, so flaky Wi-Fi doesn't lose commands or state.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MqttConfig {
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Must be stable for the broker to find the persistent session again.
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    #[serde(default)]
+    pub clean_start: bool,
+    #[serde(default = "default_session_expiry_secs")]
+    pub session_expiry_secs: u32,
+    /// QoS of devices that don't set their own.
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    /// Topic aliases accepted from the broker; outgoing aliases are also
+    /// capped by what the broker accepts.
+    #[serde(default = "default_topic_alias_max")]
+    pub topic_alias_max: u16,
+    /// Subscribe as part of this shared subscription group, so several hubs
+    /// split the state messages between them.
+    #[serde(default)]
+    pub share_group: Option<String>,
+    #[serde(default)]
+    pub priority: u8,
+}
+
+impl MqttConfig {
+    /// Reads the broker connection from a device's connection details:
+    /// `host`, and optionally `port`, `username`, `password` and
+    /// `client_id`. Without a client id the broker keeps no session.
+    pub fn from_details(
+        name: &str,
+        details: &HashMap<String, String>,
+    ) -> Result<Self, BlinkieError> {
+        let host = details
+            .get("host")
+            .cloned()
+            .ok_or_else(|| "Missing MQTT broker host".to_string())?;
+        let port = match details.get("port") {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("Invalid MQTT broker port '{}'", port))?,
+            None => default_port(),
+        };
+        Ok(MqttConfig {
+            name: name.to_string(),
+            host,
+            port,
+            client_id: details
+                .get("client_id")
+                .cloned()
+                .unwrap_or_else(|| format!("blinkie-{}-{}", name, std::process::id())),
+            username: details.get("username").cloned(),
+            password: details.get("password").cloned(),
+            keep_alive_secs: default_keep_alive_secs(),
+            clean_start: !details.contains_key("client_id"),
+            session_expiry_secs: default_session_expiry_secs(),
+            qos: default_qos(),
+            topic_alias_max: default_topic_alias_max(),
+            share_group: None,
+            priority: 0,
+        })
+    }
+
+    /// Identifies the broker connection, so devices on the same broker
+    /// share it.
+    pub(crate) fn broker_key(&self) -> String {
+        format!(
+            "{}@{}:{}",
+            self.username.as_deref().unwrap_or_default(),
+            self.host,
+            self.port
+        )
+    }
+}
+
+pub(crate) fn qos(level: u8) -> Result<QoS, BlinkieError> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        other => Err(format!("Invalid QoS {}, expected 0, 1 or 2", other).into()),
+    }
+}
+
+/**
+ * MqttSpec
+ * Topics of a device, read from its connection details:
+ *
+ * - `topic`: base topic of devices following the common `<topic>/state`
+ *   and `<topic>/set` layout, used when the topics below aren't given
+ * - `state_topic`: messages update the state; JSON objects set one key per
+ *   field, anything else sets `state`. May contain `+` and `#`.
+ * - `state_topic.<key>`: messages set `<key>` to their payload, for devices
+ *   reporting every value on a topic of its own
+ * - `command_topic`: commands are published here as
+ *   `{"command": ..., "parameters": {...}}`
+ * - `command_topic.<command>`: the command is published here instead, with
+ *   its `value` parameter as the payload, e.g. `ON` to `cmnd/lamp/POWER`
+ * - `timestamp_key`: state key holding when the device measured the state,
+ *   RFC 3339 or seconds since the epoch, `timestamp` by default
+ * - `qos` and `retain` of the device's messages
+ */
+#[derive(Clone, Debug)]
+pub struct MqttSpec {
+    pub state_topic: Option<String>,
+    /// Topics setting a single key, by key.
+    pub key_topics: HashMap<String, String>,
+    pub command_topic: Option<String>,
+    /// Topics of single commands, by command.
+    pub command_topics: HashMap<String, String>,
+    pub timestamp_key: String,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl MqttSpec {
+    pub fn from_config(config: &Config, default_qos: QoS) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let base = details
+            .get("topic")
+            .map(|topic| topic.trim_end_matches('/'));
+        let prefixed = |prefix: &str| -> HashMap<String, String> {
+            details
+                .iter()
+                .filter_map(|(name, topic)| {
+                    let key = name.strip_prefix(prefix)?.strip_prefix('.')?;
+                    Some((key.to_string(), topic.clone()))
+                })
+                .collect()
+        };
+        let spec = MqttSpec {
+            state_topic: details
+                .get("state_topic")
+                .cloned()
+                .or_else(|| base.map(|base| format!("{}/state", base))),
+            key_topics: prefixed("state_topic"),
+            command_topic: details
+                .get("command_topic")
+                .cloned()
+                .or_else(|| base.map(|base| format!("{}/set", base))),
+            command_topics: prefixed("command_topic"),
+            timestamp_key: details
+                .get("timestamp_key")
+                .cloned()
+                .unwrap_or_else(|| "timestamp".to_string()),
+            qos: match details.get("qos") {
+                Some(level) => level
+                    .parse()
+                    .map_err(|_| {
+                        BlinkieError::serialization(format!(
+                            "Invalid qos '{}' for device '{}'",
+                            level, config.id
+                        ))
+                    })
+                    .and_then(qos)?,
+                None => default_qos,
+            },
+            retain: details.get("retain").is_some_and(|retain| retain == "true"),
+        };
+        if spec.state_topic.is_none()
+            && spec.key_topics.is_empty()
+            && spec.command_topic.is_none()
+            && spec.command_topics.is_empty()
+        {
+            return Err(format!(
+                "Device '{}' has neither a state_topic nor a command_topic",
+                config.id
+            )
+            .into());
+        }
+        Ok(spec)
+    }
+
+    /// Topic filters the device's state is read from.
+    fn state_filters(&self) -> impl Iterator<Item = &String> {
+        self.state_topic.iter().chain(self.key_topics.values())
+    }
+
+    /// State update carried by a message on `topic`, if it is one of the
+    /// device's state topics.
+    fn state_update(&self, topic: &str, payload: &[u8]) -> Option<HashMap<String, String>> {
+        let mut update = HashMap::new();
+        if self
+            .state_topic
+            .as_deref()
+            .is_some_and(|filter| matches(topic, filter))
+        {
+            update.extend(parse_state(payload));
+        }
+        for (key, filter) in &self.key_topics {
+            if matches(topic, filter) {
+                update.insert(
+                    key.clone(),
+                    String::from_utf8_lossy(payload).trim().to_string(),
+                );
+            }
+        }
+        (!update.is_empty()).then_some(update)
+    }
+}
+
+/// Parses a state message into state keys.
+fn parse_state(payload: &[u8]) -> HashMap<String, String> {
+    match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(fields)) => fields
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(s) => (key, s),
+                other => (key, other.to_string()),
+            })
+            .collect(),
+        _ => HashMap::from([(
+            "state".to_string(),
+            String::from_utf8_lossy(payload).trim().to_string(),
+        )]),
+    }
+}
+
+/**
+ * MqttDecoder
+ * Replays recorded messages, `<topic> <payload>`, as state messages.
+ */
+pub struct MqttDecoder;
+
+impl Decoder for MqttDecoder {
+    fn decode(
+        &self,
+        frame: &Frame,
+        _details: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, BlinkieError> {
+        let (_topic, payload) = frame
+            .data
+            .split_once(' ')
+            .ok_or_else(|| "Frame has no topic".to_string())?;
+        Ok(parse_state(payload.as_bytes()))
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    connected: bool,
+    /// Set once the handler disconnects for good.
+    closed: bool,
+    error: Option<String>,
+    /// Topic aliases the broker accepts on this connection.
+    alias_max: u16,
+    aliases: HashMap<String, u16>,
+}
+
+/// Called with the topic and payload of messages on a topic filter.
+pub(crate) type Listener = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+pub(crate) struct Shared {
+    pub(crate) config: MqttConfig,
+    client: Client,
+    specs: RwLock<HashMap<String, MqttSpec>>,
+    /// Topic filters other handlers listen to, e.g. a bridge's own topics.
+    listeners: RwLock<Vec<(String, Listener)>>,
+    states: RwLock<HashMap<String, HashMap<String, String>>>,
+    session: Mutex<Session>,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl Shared {
+    fn connect(
+        config: MqttConfig,
+        events: Option<Arc<EventBus>>,
+        sniffer: Option<Arc<Sniffer>>,
+    ) -> Result<(Self, Connection), BlinkieError> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options
+            .set_keep_alive(Duration::from_secs(config.keep_alive_secs))
+            .set_clean_start(config.clean_start)
+            .set_session_expiry_interval(Some(config.session_expiry_secs))
+            .set_topic_alias_max(Some(config.topic_alias_max));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        qos(config.qos)?;
+        let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+        Ok((
+            Shared {
+                config,
+                client,
+                specs: RwLock::new(HashMap::new()),
+                listeners: RwLock::new(Vec::new()),
+                states: RwLock::new(HashMap::new()),
+                session: Mutex::new(Session::default()),
+                events,
+                sniffer,
+            },
+            connection,
+        ))
+    }
+
+    /// Drives the connection on a thread of its own.
+    fn spawn(self: &Arc<Self>, connection: Connection) -> Result<(), BlinkieError> {
+        let shared = self.clone();
+        thread::Builder::new()
+            .name(format!("mqtt-{}", self.config.host))
+            .spawn(move || shared.run(connection))
+            .map(|_| ())
+            .map_err(|e| format!("Failed to start MQTT connection: {}", e).into())
+    }
+
+    fn create_device(self: &Arc<Self>, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = MqttSpec::from_config(config, qos(self.config.qos)?)?;
+        self.register(&config.id, spec)?;
+        Ok(Box::new(MqttDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            shared: self.clone(),
+        }))
+    }
+
+    /// Follows the state topics of `spec` for device `device_id`.
+    pub(crate) fn register(&self, device_id: &str, spec: MqttSpec) -> Result<(), BlinkieError> {
+        self.subscribe(&spec)?;
+        self.specs
+            .write()
+            .unwrap()
+            .insert(device_id.to_string(), spec);
+        Ok(())
+    }
+
+    /// Calls `listener` with every message on `filter`.
+    pub(crate) fn listen(&self, filter: &str, listener: Listener) -> Result<(), BlinkieError> {
+        self.subscribe_filter(filter, qos(self.config.qos)?)?;
+        self.listeners
+            .write()
+            .unwrap()
+            .push((filter.to_string(), listener));
+        Ok(())
+    }
+
+    pub(crate) fn state(&self, device_id: &str) -> StateMap {
+        self.states
+            .read()
+            .unwrap()
+            .get(device_id)
+            .map(value::parse_state)
+            .unwrap_or_default()
+    }
+
+    /// Disconnects from the broker without reconnecting.
+    fn close(&self) {
+        self.session.lock().unwrap().closed = true;
+        if let Err(e) = self.client.disconnect() {
+            log::warn!("Failed to disconnect from {}: {}", self.config.host, e);
+        }
+    }
+
+    fn health(&self) -> Result<(), BlinkieError> {
+        let session = self.session.lock().unwrap();
+        match (&session.error, session.connected) {
+            (Some(error), _) => Err(BlinkieError::connection(error.clone())),
+            (None, false) => Err(format!("Not connected to {}", self.config.host).into()),
+            (None, true) => Ok(()),
+        }
+    }
+
+    fn subscribe(&self, spec: &MqttSpec) -> Result<(), BlinkieError> {
+        for topic in spec.state_filters() {
+            self.subscribe_filter(topic, spec.qos)?;
+        }
+        Ok(())
+    }
+
+    fn subscribe_filter(&self, topic: &str, qos: QoS) -> Result<(), BlinkieError> {
+        let filter = match &self.config.share_group {
+            Some(group) => format!("$share/{}/{}", group, topic),
+            None => topic.to_string(),
+        };
+        self.client.subscribe(filter, qos).map_err(|e| {
+            BlinkieError::connection(format!("Failed to subscribe to {}: {}", topic, e))
+        })
+    }
+
+    /// Publishes `payload`, replacing the topic by an alias once the broker
+    /// has seen it on this connection. Only QoS 0 messages use aliases, as
+    /// aliases don't survive a reconnect and QoS 1/2 messages may be resent
+    /// on a new connection.
+    fn publish(&self, topic: &str, spec: &MqttSpec, payload: Vec<u8>) -> Result<(), BlinkieError> {
+        let (topic_name, alias) = {
+            let mut session = self.session.lock().unwrap();
+            let next = session.aliases.len() as u16 + 1;
+            match session.aliases.get(topic) {
+                _ if spec.qos != QoS::AtMostOnce || !session.connected => (topic.to_string(), None),
+                Some(alias) => (String::new(), Some(*alias)),
+                None if next <= session.alias_max => {
+                    session.aliases.insert(topic.to_string(), next);
+                    (topic.to_string(), Some(next))
+                }
+                None => (topic.to_string(), None),
+            }
+        };
+        let properties = PublishProperties {
+            topic_alias: alias,
+            ..Default::default()
+        };
+        self.client
+            .publish_with_properties(topic_name, spec.qos, spec.retain, payload, properties)
+            .map_err(|e| BlinkieError::connection(format!("Failed to publish to {}: {}", topic, e)))
+    }
+
+    fn sniff(&self, device_id: Option<&str>, direction: Direction, topic: &str, payload: &[u8]) {
+        if let Some(sniffer) = &self.sniffer {
+            let mut frame = format!("{} ", topic).into_bytes();
+            frame.extend_from_slice(payload);
+            sniffer.record(&self.config.name, device_id, direction, &frame);
+        }
+    }
+
+    /// Publishes `payload` to `topic` as device `device_id`, with its QoS
+    /// and retain flag.
+    pub(crate) fn publish_as(
+        &self,
+        device_id: &str,
+        topic: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), BlinkieError> {
+        let spec = self
+            .specs
+            .read()
+            .unwrap()
+            .get(device_id)
+            .cloned()
+            .ok_or_else(|| format!("Device '{}' is not an MQTT device", device_id))?;
+        self.sniff(Some(device_id), Direction::Out, topic, &payload);
+        self.publish(topic, &spec, payload)
+    }
+
+    /// Publishes a command to the device's command topic.
+    fn send_command(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        let spec = self
+            .specs
+            .read()
+            .unwrap()
+            .get(device_id)
+            .cloned()
+            .ok_or_else(|| format!("Device '{}' is not an MQTT device", device_id))?;
+        let (topic, payload) = match spec.command_topics.get(command) {
+            Some(topic) => (
+                topic,
+                parameters
+                    .and_then(|parameters| parameters.get("value"))
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            None => (
+                spec.command_topic
+                    .as_ref()
+                    .ok_or_else(|| BlinkieError::unsupported_command(device_id, command))?,
+                json!({ "command": command, "parameters": parameters }).to_string(),
+            ),
+        };
+        self.sniff(Some(device_id), Direction::Out, topic, payload.as_bytes());
+        self.publish(topic, &spec, payload.into_bytes())
+    }
+
+    pub(crate) fn update_state(
+        &self,
+        device_id: &str,
+        update: HashMap<String, String>,
+        reported_at: Option<DateTime<Utc>>,
+    ) {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(device_id.to_string()).or_default();
+        for (key, new_value) in update {
+            let old_value = state.insert(key.clone(), new_value.clone());
+            if old_value.as_ref() == Some(&new_value) {
+                continue;
+            }
+            if let Some(events) = &self.events {
+                let _ = events.publish(EventKind::StateChanged {
+                    device_id: device_id.to_string(),
+                    key,
+                    old_value,
+                    new_value,
+                    reported_at,
+                });
+            }
+        }
+    }
+
+    /// Drives the connection, reconnecting after errors.
+    fn run(&self, mut connection: Connection) {
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+                    {
+                        let mut session = self.session.lock().unwrap();
+                        session.connected = true;
+                        session.error = None;
+                        session.aliases.clear();
+                        session.alias_max = ack
+                            .properties
+                            .as_ref()
+                            .and_then(|properties| properties.topic_alias_max)
+                            .unwrap_or(0);
+                    }
+                    // A resumed session still has its subscriptions
+                    if !ack.session_present {
+                        let specs: Vec<MqttSpec> =
+                            self.specs.read().unwrap().values().cloned().collect();
+                        for spec in &specs {
+                            if let Err(e) = self.subscribe(spec) {
+                                log::warn!("{}", e);
+                            }
+                        }
+                        let filters: Vec<String> = self
+                            .listeners
+                            .read()
+                            .unwrap()
+                            .iter()
+                            .map(|(filter, _)| filter.clone())
+                            .collect();
+                        for filter in filters {
+                            let subscribed = qos(self.config.qos)
+                                .and_then(|qos| self.subscribe_filter(&filter, qos));
+                            if let Err(e) = subscribed {
+                                log::warn!("{}", e);
+                            }
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let topic = String::from_utf8_lossy(&publish.topic).to_string();
+                    let updates: Vec<_> = self
+                        .specs
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .filter_map(|(id, spec)| {
+                            let update = spec.state_update(&topic, &publish.payload)?;
+                            let reported_at = update
+                                .get(&spec.timestamp_key)
+                                .and_then(|value| parse_timestamp(value));
+                            Some((id.clone(), update, reported_at))
+                        })
+                        .collect();
+                    let listeners: Vec<Listener> = self
+                        .listeners
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .filter(|(filter, _)| matches(&topic, filter))
+                        .map(|(_, listener)| listener.clone())
+                        .collect();
+                    if updates.is_empty() {
+                        self.sniff(None, Direction::In, &topic, &publish.payload);
+                    }
+                    for listener in listeners {
+                        listener(&topic, &publish.payload);
+                    }
+                    for (device_id, update, reported_at) in updates {
+                        self.sniff(Some(&device_id), Direction::In, &topic, &publish.payload);
+                        self.update_state(&device_id, update, reported_at);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) if self.session.lock().unwrap().closed => break,
+                Err(e) => {
+                    let error = format!("Connection to {} failed: {}", self.config.host, e);
+                    log::warn!("{}", error);
+                    {
+                        let mut session = self.session.lock().unwrap();
+                        session.connected = false;
+                        session.error = Some(error);
+                    }
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * MqttDevice
+ * A device reporting on and taking commands from MQTT topics.
+ */
+pub struct MqttDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    shared: Arc<Shared>,
+}
+
+impl Device for MqttDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.shared.state(&self.id)
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.shared
+            .update_state(&self.id, format_state(&state), None);
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self
+            .shared
+            .send_command(&self.id, command, parameters.as_ref())
+        {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+}
+
+/**
+ * MqttHandler
+ * Speaks MQTT v5 to a broker, which may be the embedded one. Commands are
+ * queued while disconnected and delivered with the device's QoS once the
+ * connection is back.
+ */
+pub struct MqttHandler {
+    shared: Arc<Shared>,
+    /// Handed to the connection thread on `initialize`.
+    connection: Mutex<Option<Connection>>,
+}
+
+impl MqttHandler {
+    pub fn new(config: MqttConfig) -> Result<Self, BlinkieError> {
+        let (shared, connection) = Shared::connect(config, None, None)?;
+        Ok(MqttHandler {
+            shared: Arc::new(shared),
+            connection: Mutex::new(Some(connection)),
+        })
+    }
+
+    /// Publishes state changes on `events`. Must be called before `initialize`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        if let Some(shared) = Arc::get_mut(&mut self.shared) {
+            shared.events = Some(events);
+        }
+        self
+    }
+
+    /// Records published and received messages on `sniffer` while it
+    /// captures. Must be called before `initialize`.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        if let Some(shared) = Arc::get_mut(&mut self.shared) {
+            shared.sniffer = Some(sniffer);
+        }
+        self
+    }
+}
+
+impl ProtocolHandler for MqttHandler {
+    fn name(&self) -> String {
+        self.shared.config.name.clone()
+    }
+
+    fn priority(&self) -> u8 {
+        self.shared.config.priority
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        self.shared.create_device(config)
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        MqttSpec::from_config(config, qos(self.shared.config.qos)?).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        self.shared
+            .send_command(device.get_id(), cmd, params.as_ref())
+    }
+
+    /// Connects to the broker on a thread of its own.
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        let Some(connection) = self.connection.lock().unwrap().take() else {
+            return Ok(());
+        };
+        self.shared.spawn(connection)
+    }
+
+    fn health(&self) -> Result<(), BlinkieError> {
+        self.shared.health()
+    }
+}
+
+/**
+ * MqttBrokers
+ * The built-in `mqtt` handler. Unlike `MqttHandler` it has no broker of
+ * its own: every device names its broker in its connection details, see
+ * `MqttConfig::from_details`, and devices on the same broker share a
+ * connection. Connections opened before `initialize` start with it.
+ */
+pub struct MqttBrokers {
+    /// Handler name, for the sniffer and default client ids.
+    name: String,
+    /// Broker connections, by `MqttConfig::broker_key`.
+    brokers: HashMap<String, Arc<Shared>>,
+    pending: Mutex<Vec<(Arc<Shared>, Connection)>>,
+    initialized: bool,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl Default for MqttBrokers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MqttBrokers {
+    pub fn new() -> Self {
+        Self::named(PROTOCOL)
+    }
+
+    /// Broker connections of a handler speaking a protocol on top of MQTT.
+    pub(crate) fn named(name: &str) -> Self {
+        MqttBrokers {
+            name: name.to_string(),
+            brokers: HashMap::new(),
+            pending: Mutex::new(Vec::new()),
+            initialized: false,
+            events: None,
+            sniffer: None,
+        }
+    }
+
+    /// Publishes state changes of all devices on `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Records published and received messages on `sniffer` while it captures.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        self.sniffer = Some(sniffer);
+        self
+    }
+
+    /// Connection to the broker in `details`, opened on first use.
+    pub(crate) fn broker(
+        &mut self,
+        details: &HashMap<String, String>,
+    ) -> Result<Arc<Shared>, BlinkieError> {
+        let config = MqttConfig::from_details(&self.name, details)?;
+        let key = config.broker_key();
+        if let Some(shared) = self.brokers.get(&key) {
+            return Ok(shared.clone());
+        }
+        let (shared, connection) =
+            Shared::connect(config, self.events.clone(), self.sniffer.clone())?;
+        let shared = Arc::new(shared);
+        if self.initialized {
+            shared.spawn(connection)?;
+        } else {
+            self.pending
+                .lock()
+                .unwrap()
+                .push((shared.clone(), connection));
+        }
+        self.brokers.insert(key, shared.clone());
+        Ok(shared)
+    }
+}
+
+impl ProtocolHandler for MqttBrokers {
+    fn name(&self) -> String {
+        PROTOCOL.to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        self.broker(&config.connection_details)
+            .map_err(|e| format!("Failed to create device '{}': {}", config.id, e))?
+            .create_device(config)
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        let broker = MqttConfig::from_details(PROTOCOL, &config.connection_details)
+            .map_err(|e| format!("Invalid device '{}': {}", config.id, e))?;
+        MqttSpec::from_config(config, qos(broker.qos)?).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        let shared = self
+            .brokers
+            .values()
+            .find(|shared| shared.specs.read().unwrap().contains_key(device.get_id()))
+            .ok_or_else(|| format!("Device '{}' is not an MQTT device", device.get_id()))?;
+        shared.send_command(device.get_id(), cmd, params.as_ref())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        self.initialized = true;
+        for (shared, connection) in self.pending.lock().unwrap().drain(..) {
+            shared.spawn(connection)?;
+        }
+        Ok(())
+    }
+
+    /// Reports the first broker that can't be reached.
+    fn health(&self) -> Result<(), BlinkieError> {
+        self.brokers.values().try_for_each(|shared| shared.health())
+    }
+
+    fn shutdown(&mut self) {
+        for shared in self.brokers.values() {
+            shared.close();
+        }
+    }
+}
@@ -0,0 +1,875 @@
+use crate::core::device::{CommandResponse, Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use crate::core::sniffer::{Direction, Sniffer};
+use crate::core::value::{self, format_state, StateMap};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{self, HeaderValue};
+use tungstenite::{Error, Message};
+
+pub const PROTOCOL: &str = "ocpp";
+/// WebSocket subprotocol of OCPP 1.6 over JSON.
+const SUBPROTOCOL: &str = "ocpp1.6";
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:9000";
+const DEFAULT_ID_TAG: &str = "blinkie";
+/// Seconds between heartbeats asked of charge points.
+const HEARTBEAT_SECS: u64 = 300;
+/// Time a charge point has to answer a call.
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time connections wait for a message before sending queued calls.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// OCPP-J message types
+const CALL: u64 = 2;
+const CALL_RESULT: u64 = 3;
+const CALL_ERROR: u64 = 4;
+
+/**
+ * OcppSpec
+ * Charge point of a device, read from its connection details:
+ *
+ * - `charge_point_id`: identity the wallbox connects with, the last
+ *   segment of the URL it is configured with, e.g.
+ *   `ws://hub:9000/ocpp/<charge_point_id>`; the device id by default
+ * - `listen`: address the central system listens on, `0.0.0.0:9000` by
+ *   default. Charge points on the same address share it.
+ * - `connector`: connector to charge on, 1 by default
+ * - `id_tag`: id tag remote starts authorize with, `blinkie` by default
+ */
+#[derive(Clone, Debug)]
+pub struct OcppSpec {
+    pub charge_point_id: String,
+    pub listen: SocketAddr,
+    pub connector: u32,
+    pub id_tag: String,
+}
+
+impl OcppSpec {
+    pub fn from_config(config: &Config) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let listen = details
+            .get("listen")
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_LISTEN);
+        let connector = match details.get("connector") {
+            Some(connector) => connector.parse().map_err(|_| {
+                format!(
+                    "Invalid connector '{}' for device '{}'",
+                    connector, config.id
+                )
+            })?,
+            None => 1,
+        };
+        Ok(OcppSpec {
+            charge_point_id: details
+                .get("charge_point_id")
+                .cloned()
+                .unwrap_or_else(|| config.id.clone()),
+            listen: listen.parse().map_err(|_| {
+                format!(
+                    "Invalid listen address '{}' for device '{}'",
+                    listen, config.id
+                )
+            })?,
+            connector,
+            id_tag: details
+                .get("id_tag")
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_ID_TAG.to_string()),
+        })
+    }
+}
+
+/**
+ * Station
+ * A charge point known to a central system and the device it belongs to.
+ */
+#[derive(Clone, Debug)]
+struct Station {
+    device_id: String,
+    spec: OcppSpec,
+}
+
+/**
+ * Session
+ * Connection of a charge point: where to queue calls to it, and which
+ * connection it is, so a reconnect isn't undone by the old one closing.
+ */
+struct Session {
+    outbox: mpsc::Sender<String>,
+    generation: u64,
+}
+
+/**
+ * Shared
+ * A central system listening for charge points on one address.
+ */
+struct Shared {
+    listen: SocketAddr,
+    /// Stations by charge point id.
+    stations: RwLock<HashMap<String, Station>>,
+    /// State of every device, by device id.
+    states: RwLock<HashMap<String, HashMap<String, String>>>,
+    /// Connected charge points, by charge point id.
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Calls waiting for their answer, by message id.
+    pending: Mutex<HashMap<String, mpsc::Sender<Result<Value, BlinkieError>>>>,
+    next_message: AtomicU64,
+    next_transaction: AtomicI64,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl Shared {
+    fn new(
+        listen: SocketAddr,
+        events: Option<Arc<EventBus>>,
+        sniffer: Option<Arc<Sniffer>>,
+    ) -> Self {
+        Shared {
+            listen,
+            stations: RwLock::new(HashMap::new()),
+            states: RwLock::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_message: AtomicU64::new(1),
+            // Transaction ids stay unique across restarts of the hub
+            next_transaction: AtomicI64::new(Utc::now().timestamp() % 1_000_000_000),
+            events,
+            sniffer,
+        }
+    }
+
+    /// Accepts charge points on a thread of its own.
+    fn spawn(self: &Arc<Self>, listener: TcpListener) -> Result<(), BlinkieError> {
+        let shared = self.clone();
+        thread::Builder::new()
+            .name(format!("ocpp-{}", self.listen))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let shared = shared.clone();
+                            thread::spawn(move || shared.serve(stream));
+                        }
+                        Err(e) => log::warn!("Failed to accept charge point: {}", e),
+                    }
+                }
+            })
+            .map(|_| ())
+            .map_err(|e| format!("Failed to start OCPP central system: {}", e).into())
+    }
+
+    fn create_device(self: &Arc<Self>, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = OcppSpec::from_config(config)?;
+        let mut stations = self.stations.write().unwrap();
+        if let Some(station) = stations.get(&spec.charge_point_id) {
+            return Err(format!(
+                "Charge point '{}' already belongs to device '{}'",
+                spec.charge_point_id, station.device_id
+            )
+            .into());
+        }
+        stations.insert(
+            spec.charge_point_id.clone(),
+            Station {
+                device_id: config.id.clone(),
+                spec,
+            },
+        );
+        self.update_state(
+            &config.id,
+            HashMap::from([("connected".to_string(), "false".to_string())]),
+        );
+        Ok(Box::new(OcppDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            shared: self.clone(),
+        }))
+    }
+
+    fn station_of(&self, device_id: &str) -> Result<Station, BlinkieError> {
+        self.stations
+            .read()
+            .unwrap()
+            .values()
+            .find(|station| station.device_id == device_id)
+            .cloned()
+            .ok_or_else(|| format!("Device '{}' is not an OCPP device", device_id).into())
+    }
+
+    fn sniff(&self, device_id: &str, direction: Direction, text: &str) {
+        if let Some(sniffer) = &self.sniffer {
+            sniffer.record(PROTOCOL, Some(device_id), direction, text.as_bytes());
+        }
+    }
+
+    fn update_state(&self, device_id: &str, update: HashMap<String, String>) {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(device_id.to_string()).or_default();
+        for (key, new_value) in update {
+            let old_value = state.insert(key.clone(), new_value.clone());
+            if old_value.as_ref() == Some(&new_value) {
+                continue;
+            }
+            if let Some(events) = &self.events {
+                let _ = events.publish(EventKind::StateChanged {
+                    device_id: device_id.to_string(),
+                    key,
+                    old_value,
+                    new_value,
+                    reported_at: None,
+                });
+            }
+        }
+    }
+
+    fn state(&self, device_id: &str, key: &str) -> Option<String> {
+        self.states
+            .read()
+            .unwrap()
+            .get(device_id)
+            .and_then(|state| state.get(key))
+            .cloned()
+    }
+
+    /// Talks to a charge point until it disconnects. Only charge points of
+    /// configured devices are accepted, and they answer with OCPP 1.6 if
+    /// they ask for it.
+    // The handshake callback has to fail with tungstenite's error response
+    #[allow(clippy::result_large_err)]
+    fn serve(self: Arc<Self>, stream: TcpStream) {
+        let mut charge_point = None;
+        let handshake =
+            tungstenite::accept_hdr(stream, |request: &Request, mut response: Response| {
+                let id = request
+                    .uri()
+                    .path()
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                if !self.stations.read().unwrap().contains_key(&id) {
+                    let mut error =
+                        ErrorResponse::new(Some(format!("Unknown charge point '{}'", id)));
+                    *error.status_mut() = http::StatusCode::NOT_FOUND;
+                    return Err(error);
+                }
+                let offered = request
+                    .headers()
+                    .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value.split(',').any(|p| p.trim() == SUBPROTOCOL));
+                if offered {
+                    response.headers_mut().insert(
+                        http::header::SEC_WEBSOCKET_PROTOCOL,
+                        HeaderValue::from_static(SUBPROTOCOL),
+                    );
+                }
+                charge_point = Some(id);
+                Ok(response)
+            });
+        let mut socket = match handshake {
+            Ok(socket) => socket,
+            Err(e) => return log::warn!("Charge point handshake failed: {}", e),
+        };
+        let Some(charge_point) = charge_point else {
+            return;
+        };
+        let Some(device_id) = self
+            .stations
+            .read()
+            .unwrap()
+            .get(&charge_point)
+            .map(|station| station.device_id.clone())
+        else {
+            return;
+        };
+        if let Err(e) = socket.get_ref().set_read_timeout(Some(POLL_INTERVAL)) {
+            return log::warn!("Failed to set up connection of '{}': {}", charge_point, e);
+        }
+
+        let (outbox, inbox) = mpsc::channel();
+        let generation = self.next_message.fetch_add(1, Ordering::Relaxed);
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(charge_point.clone(), Session { outbox, generation });
+        log::info!("Charge point '{}' connected", charge_point);
+        self.update_state(
+            &device_id,
+            HashMap::from([("connected".to_string(), "true".to_string())]),
+        );
+
+        loop {
+            match socket.read() {
+                Ok(Message::Text(text)) => {
+                    self.sniff(&device_id, Direction::In, &text);
+                    if let Some(reply) = self.receive(&device_id, &text) {
+                        self.sniff(&device_id, Direction::Out, &reply);
+                        if socket.send(Message::Text(reply)).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Ok(Message::Close(_)) => break,
+                Ok(_) => {}
+                Err(Error::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => {
+                    log::warn!(
+                        "Connection of charge point '{}' failed: {}",
+                        charge_point,
+                        e
+                    );
+                    break;
+                }
+            }
+            let mut failed = false;
+            while let Ok(call) = inbox.try_recv() {
+                self.sniff(&device_id, Direction::Out, &call);
+                if socket.send(Message::Text(call)).is_err() {
+                    failed = true;
+                    break;
+                }
+            }
+            if failed {
+                break;
+            }
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(&charge_point)
+            .is_some_and(|session| session.generation == generation)
+        {
+            sessions.remove(&charge_point);
+            drop(sessions);
+            log::info!("Charge point '{}' disconnected", charge_point);
+            self.update_state(
+                &device_id,
+                HashMap::from([("connected".to_string(), "false".to_string())]),
+            );
+        }
+    }
+
+    /// Handles a message of a charge point, returning the reply to calls.
+    fn receive(&self, device_id: &str, text: &str) -> Option<String> {
+        let Ok(Value::Array(frame)) = serde_json::from_str::<Value>(text) else {
+            log::warn!("Ignoring malformed OCPP message from '{}'", device_id);
+            return None;
+        };
+        let id = frame.get(1).and_then(Value::as_str).unwrap_or_default();
+        match frame.first().and_then(Value::as_u64) {
+            Some(CALL) => {
+                let action = frame.get(2).and_then(Value::as_str).unwrap_or_default();
+                let payload = frame.get(3).cloned().unwrap_or(Value::Null);
+                let reply = match self.answer(device_id, action, &payload) {
+                    Ok(result) => json!([CALL_RESULT, id, result]),
+                    Err((code, description)) => json!([CALL_ERROR, id, code, description, {}]),
+                };
+                Some(reply.to_string())
+            }
+            Some(CALL_RESULT) => {
+                if let Some(waiting) = self.pending.lock().unwrap().remove(id) {
+                    let _ = waiting.send(Ok(frame.get(2).cloned().unwrap_or(Value::Null)));
+                }
+                None
+            }
+            Some(CALL_ERROR) => {
+                if let Some(waiting) = self.pending.lock().unwrap().remove(id) {
+                    let code = frame.get(2).and_then(Value::as_str).unwrap_or_default();
+                    let description = frame.get(3).and_then(Value::as_str).unwrap_or_default();
+                    let _ = waiting.send(Err(format!(
+                        "Charge point of '{}' failed the call: {} {}",
+                        device_id, code, description
+                    )
+                    .into()));
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Answers a call of a charge point, or fails it with an OCPP error
+    /// code and description.
+    fn answer(
+        &self,
+        device_id: &str,
+        action: &str,
+        payload: &Value,
+    ) -> Result<Value, (&'static str, String)> {
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let accepted = json!({ "idTagInfo": { "status": "Accepted" } });
+        let text = |key: &str| payload.get(key).and_then(Value::as_str).map(str::to_string);
+        let mut update = HashMap::new();
+        let result = match action {
+            "BootNotification" => {
+                for (key, field) in [
+                    ("vendor", "chargePointVendor"),
+                    ("model", "chargePointModel"),
+                    ("firmware", "firmwareVersion"),
+                ] {
+                    if let Some(value) = text(field) {
+                        update.insert(key.to_string(), value);
+                    }
+                }
+                json!({ "status": "Accepted", "currentTime": now, "interval": HEARTBEAT_SECS })
+            }
+            "Heartbeat" => json!({ "currentTime": now }),
+            "Authorize" => accepted,
+            "StatusNotification" => {
+                let station = self.station_of(device_id).ok();
+                let connector = payload.get("connectorId").and_then(Value::as_u64);
+                // Connector 0 is the charge point as a whole
+                if connector.is_some_and(|connector| {
+                    connector != 0
+                        && station.is_none_or(|station| connector != station.spec.connector as u64)
+                }) {
+                    return Ok(json!({}));
+                }
+                if let Some(status) = text("status") {
+                    if connector != Some(0) {
+                        let charging = if status == "Charging" { "on" } else { "off" };
+                        update.insert("state".to_string(), charging.to_string());
+                    }
+                    update.insert("status".to_string(), status);
+                }
+                if let Some(error_code) = text("errorCode") {
+                    update.insert("error_code".to_string(), error_code);
+                }
+                json!({})
+            }
+            "StartTransaction" => {
+                let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+                let meter_start = payload
+                    .get("meterStart")
+                    .and_then(Value::as_f64)
+                    .unwrap_or_default();
+                update.insert("transaction_id".to_string(), transaction.to_string());
+                update.insert("meter_start_wh".to_string(), meter_start.to_string());
+                update.insert("energy_wh".to_string(), meter_start.to_string());
+                update.insert("session_energy_wh".to_string(), "0".to_string());
+                let mut result = accepted;
+                result["transactionId"] = json!(transaction);
+                result
+            }
+            "StopTransaction" => {
+                if let Some(meter_stop) = payload.get("meterStop").and_then(Value::as_f64) {
+                    update.insert("energy_wh".to_string(), meter_stop.to_string());
+                    if let Some(start) = self
+                        .state(device_id, "meter_start_wh")
+                        .and_then(|start| start.parse::<f64>().ok())
+                    {
+                        update.insert(
+                            "session_energy_wh".to_string(),
+                            (meter_stop - start).max(0.0).to_string(),
+                        );
+                    }
+                }
+                update.insert("transaction_id".to_string(), String::new());
+                update.insert("power".to_string(), "0".to_string());
+                accepted
+            }
+            "MeterValues" => {
+                update.extend(self.meter_values(device_id, payload));
+                json!({})
+            }
+            "DataTransfer" => json!({ "status": "UnknownVendorId" }),
+            other => {
+                return Err((
+                    "NotImplemented",
+                    format!("Action '{}' is not supported", other),
+                ))
+            }
+        };
+        self.update_state(device_id, update);
+        Ok(result)
+    }
+
+    /// State keys of the sampled values of a `MeterValues` call: energy
+    /// and session energy in Wh, power in W, current in A and the EV's
+    /// state of charge. Per-phase power and energy are left out in favour
+    /// of the totals; current is the highest of the phases.
+    fn meter_values(&self, device_id: &str, payload: &Value) -> HashMap<String, String> {
+        let mut update = HashMap::new();
+        let mut current: Option<f64> = None;
+        let sampled = payload
+            .get("meterValue")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|meter_value| meter_value.get("sampledValue")?.as_array())
+            .flatten();
+        for sample in sampled {
+            let Some(value) = sample
+                .get("value")
+                .and_then(Value::as_str)
+                .and_then(|value| value.parse::<f64>().ok())
+            else {
+                continue;
+            };
+            let unit = sample
+                .get("unit")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let phased = sample.get("phase").is_some();
+            let scaled = if unit.starts_with('k') {
+                value * 1000.0
+            } else {
+                value
+            };
+            match sample
+                .get("measurand")
+                .and_then(Value::as_str)
+                .unwrap_or("Energy.Active.Import.Register")
+            {
+                "Energy.Active.Import.Register" if !phased => {
+                    update.insert("energy_wh".to_string(), scaled.to_string());
+                    if let Some(start) = self
+                        .state(device_id, "meter_start_wh")
+                        .and_then(|start| start.parse::<f64>().ok())
+                    {
+                        update.insert(
+                            "session_energy_wh".to_string(),
+                            (scaled - start).max(0.0).to_string(),
+                        );
+                    }
+                }
+                "Power.Active.Import" if !phased => {
+                    update.insert("power".to_string(), scaled.to_string());
+                }
+                "Current.Import" => {
+                    current = Some(current.map_or(value, |current| current.max(value)));
+                }
+                "SoC" => {
+                    update.insert("soc".to_string(), value.to_string());
+                }
+                _ => {}
+            }
+        }
+        if let Some(current) = current {
+            update.insert("current".to_string(), current.to_string());
+        }
+        update
+    }
+
+    /// Calls `action` on a charge point and waits for its answer.
+    fn call(
+        &self,
+        charge_point: &str,
+        action: &str,
+        payload: Value,
+    ) -> Result<Value, BlinkieError> {
+        let id = self
+            .next_message
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string();
+        let (answer, answered) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id.clone(), answer);
+        let queued = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(charge_point)
+            .map(|session| {
+                session
+                    .outbox
+                    .send(json!([CALL, id, action, payload]).to_string())
+                    .is_ok()
+            });
+        if queued != Some(true) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(BlinkieError::connection(format!(
+                "Charge point '{}' is not connected",
+                charge_point
+            )));
+        }
+        answered.recv_timeout(CALL_TIMEOUT).unwrap_or_else(|_| {
+            self.pending.lock().unwrap().remove(&id);
+            Err(BlinkieError::timeout(format!(
+                "Charge point '{}' did not answer {} within {} seconds",
+                charge_point,
+                action,
+                CALL_TIMEOUT.as_secs()
+            )))
+        })
+    }
+
+    /// Sends a command to the charge point of a device:
+    ///
+    /// - `start_charge` or `turn_on`: starts a charging session remotely
+    /// - `stop_charge` or `turn_off`: stops the running session, if any
+    /// - `set_current`: limits the charging current to the `current`
+    ///   parameter, in A, with a default charging profile
+    fn send_command(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        let station = self.station_of(device_id)?;
+        let spec = &station.spec;
+        let parameter = |name: &str| parameters.and_then(|parameters| parameters.get(name));
+        let mut limit = None;
+        let (action, payload) = match command {
+            "start_charge" | "turn_on" => (
+                "RemoteStartTransaction",
+                json!({ "connectorId": spec.connector, "idTag": spec.id_tag }),
+            ),
+            "stop_charge" | "turn_off" => {
+                let Some(transaction) = self
+                    .state(device_id, "transaction_id")
+                    .and_then(|id| id.parse::<i64>().ok())
+                else {
+                    // Nothing to stop
+                    return Ok(CommandResponse::new(HashMap::from([(
+                        "status".to_string(),
+                        "Idle".to_string(),
+                    )])));
+                };
+                (
+                    "RemoteStopTransaction",
+                    json!({ "transactionId": transaction }),
+                )
+            }
+            "set_current" => {
+                let current = parameter("current")
+                    .ok_or_else(|| "set_current needs a 'current' parameter".to_string())?;
+                let amps = current
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|amps| amps.is_finite() && *amps >= 0.0)
+                    .ok_or_else(|| format!("Invalid current '{}'", current))?;
+                limit = Some(amps);
+                (
+                    "SetChargingProfile",
+                    json!({
+                        "connectorId": spec.connector,
+                        "csChargingProfiles": {
+                            "chargingProfileId": 1,
+                            "stackLevel": 0,
+                            "chargingProfilePurpose": "TxDefaultProfile",
+                            "chargingProfileKind": "Relative",
+                            "chargingSchedule": {
+                                "chargingRateUnit": "A",
+                                "chargingSchedulePeriod": [{ "startPeriod": 0, "limit": amps }]
+                            }
+                        }
+                    }),
+                )
+            }
+            _ => return Err(BlinkieError::unsupported_command(device_id, command)),
+        };
+        let result = self.call(&spec.charge_point_id, action, payload)?;
+        let status = result
+            .get("status")
+            .and_then(Value::as_str)
+            .unwrap_or("Accepted")
+            .to_string();
+        if status != "Accepted" {
+            return Err(format!(
+                "Charge point '{}' answered {} with {}",
+                spec.charge_point_id, action, status
+            )
+            .into());
+        }
+        if let Some(amps) = limit {
+            self.update_state(
+                device_id,
+                HashMap::from([("current_limit".to_string(), amps.to_string())]),
+            );
+        }
+        Ok(CommandResponse::new(HashMap::from([(
+            "status".to_string(),
+            status,
+        )])))
+    }
+}
+
+/**
+ * OcppDevice
+ * A wallbox speaking OCPP 1.6J. Its state has whether it is `connected`,
+ * its connector `status` and `error_code`, `state` "on" while charging,
+ * the meter's `energy_wh`, the `session_energy_wh` of the running or last
+ * session, `power`, `current`, the `current_limit` last set and the EV's
+ * `soc` if the wallbox reports it.
+ */
+pub struct OcppDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    shared: Arc<Shared>,
+}
+
+impl Device for OcppDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.shared
+            .states
+            .read()
+            .unwrap()
+            .get(&self.id)
+            .map(value::parse_state)
+            .unwrap_or_default()
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.shared.update_state(&self.id, format_state(&state));
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.command(command, parameters) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        self.shared
+            .send_command(&self.id, command, parameters.as_ref())
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        Some(
+            [
+                "start_charge",
+                "stop_charge",
+                "set_current",
+                "turn_on",
+                "turn_off",
+            ]
+            .map(String::from)
+            .to_vec(),
+        )
+    }
+}
+
+/**
+ * OcppHandler
+ * The built-in `ocpp` handler: an OCPP 1.6J central system wallboxes
+ * connect to over WebSocket, see `OcppSpec`. Every listen address gets a
+ * central system of its own, started once the handler is initialized.
+ */
+#[derive(Default)]
+pub struct OcppHandler {
+    /// Central systems, by listen address.
+    systems: HashMap<SocketAddr, Arc<Shared>>,
+    pending: Mutex<Vec<(Arc<Shared>, TcpListener)>>,
+    initialized: bool,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl OcppHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes state changes of all wallboxes on `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Records messages to and from wallboxes on `sniffer` while it captures.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        self.sniffer = Some(sniffer);
+        self
+    }
+
+    /// Central system listening on `listen`, started on first use.
+    fn system(&mut self, listen: SocketAddr) -> Result<Arc<Shared>, BlinkieError> {
+        if let Some(shared) = self.systems.get(&listen) {
+            return Ok(shared.clone());
+        }
+        let listener = TcpListener::bind(listen).map_err(|e| {
+            BlinkieError::connection(format!("Failed to listen on {}: {}", listen, e))
+        })?;
+        let shared = Arc::new(Shared::new(
+            listen,
+            self.events.clone(),
+            self.sniffer.clone(),
+        ));
+        if self.initialized {
+            shared.spawn(listener)?;
+        } else {
+            self.pending
+                .lock()
+                .unwrap()
+                .push((shared.clone(), listener));
+        }
+        self.systems.insert(listen, shared.clone());
+        Ok(shared)
+    }
+}
+
+impl ProtocolHandler for OcppHandler {
+    fn name(&self) -> String {
+        PROTOCOL.to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = OcppSpec::from_config(config)?;
+        self.system(spec.listen)?.create_device(config)
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        OcppSpec::from_config(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        let shared = self
+            .systems
+            .values()
+            .find(|shared| shared.station_of(device.get_id()).is_ok())
+            .ok_or_else(|| format!("Device '{}' is not an OCPP device", device.get_id()))?;
+        shared
+            .send_command(device.get_id(), cmd, params.as_ref())
+            .map(|_| ())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        self.initialized = true;
+        for (shared, listener) in self.pending.lock().unwrap().drain(..) {
+            shared.spawn(listener)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use super::subprocess::{SubprocessConfig, SubprocessHandler};
+use crate::core::device::{ProtocolHandler, ProtocolRegistry};
+use crate::core::error::BlinkieError;
+use crate::core::event::EventBus;
+use crate::core::sniffer::Sniffer;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Directory below the data directory holding a directory per plugin.
+pub const PLUGINS_DIR: &str = "plugins";
+/// Manifest of a plugin, in its directory.
+pub const MANIFEST_FILE: &str = "plugin.json";
+
+/**
+ * Plugin
+ * A protocol handler shipped on its own, so third parties can add
+ * protocols without changing blinkie: a directory below `plugins` holding
+ * the handler program and a `plugin.json` manifest describing it like a
+ * `SubprocessConfig`, e.g.
+ *
+ * ```json
+ * {"name": "acme", "program": "acme-handler", "protocols": ["acme"]}
+ * ```
+ *
+ * A relative `program` found in the plugin directory runs from there. The
+ * program speaks the subprocess handler protocol, see
+ * `SubprocessHandler`.
+ */
+#[derive(Clone, Debug)]
+pub struct Plugin {
+    pub dir: PathBuf,
+    pub config: SubprocessConfig,
+}
+
+impl Plugin {
+    /// Reads the plugin in `dir`.
+    pub fn load(dir: &Path) -> Result<Self, BlinkieError> {
+        let path = dir.join(MANIFEST_FILE);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut config: SubprocessConfig = serde_json::from_str(&contents).map_err(|e| {
+            BlinkieError::serialization(format!("Failed to parse {}: {}", path.display(), e))
+        })?;
+        if config.name.is_empty() {
+            return Err(format!("{}: empty handler name", path.display()).into());
+        }
+        if config.protocols.is_empty() {
+            return Err(format!("{}: no protocols", path.display()).into());
+        }
+        let program = dir.join(&config.program);
+        if Path::new(&config.program).is_relative() && program.is_file() {
+            config.program = program.to_string_lossy().into_owned();
+        }
+        Ok(Plugin {
+            dir: dir.to_path_buf(),
+            config,
+        })
+    }
+}
+
+/**
+ * PluginStatus
+ * A plugin found in the plugins directory, and why it isn't loaded, if it
+ * isn't.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PluginStatus {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    pub loaded: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl PluginStatus {
+    pub fn new(dir: &Path, plugin: Option<&Plugin>, error: Option<&BlinkieError>) -> Self {
+        PluginStatus {
+            dir: dir.to_path_buf(),
+            name: plugin.map(|plugin| plugin.config.name.clone()),
+            protocols: plugin
+                .map(|plugin| plugin.config.protocols.clone())
+                .unwrap_or_default(),
+            loaded: error.is_none(),
+            error: error.map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Directories of the plugins below `data_dir`, sorted, or none if it has
+/// no plugins directory.
+pub fn plugin_dirs(data_dir: &Path) -> Result<Vec<PathBuf>, BlinkieError> {
+    let root = data_dir.join(PLUGINS_DIR);
+    if !root.is_dir() {
+        return Ok(Vec::new());
+    }
+    let entries =
+        fs::read_dir(&root).map_err(|e| format!("Failed to read {}: {}", root.display(), e))?;
+    let mut dirs: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.join(MANIFEST_FILE).is_file())
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Registers the plugins below `data_dir` with `registry` and starts them.
+/// A plugin that fails to load, or is named like a registered handler or
+/// one of `reserved`, is skipped, so a broken plugin doesn't keep the hub
+/// from starting.
+pub fn load_plugins(
+    data_dir: &Path,
+    registry: &ProtocolRegistry,
+    reserved: &[&str],
+    events: Arc<EventBus>,
+    sniffer: Arc<Sniffer>,
+) -> Result<Vec<PluginStatus>, BlinkieError> {
+    let mut statuses = Vec::new();
+    for dir in plugin_dirs(data_dir)? {
+        let plugin = Plugin::load(&dir);
+        let result = plugin.as_ref().map_err(Clone::clone).and_then(|plugin| {
+            let name = &plugin.config.name;
+            let registered = registry
+                .all_handlers()
+                .iter()
+                .any(|handler| handler.read().unwrap().name() == *name);
+            if registered || reserved.contains(&name.as_str()) {
+                return Err(format!("Handler '{}' is already registered", name).into());
+            }
+            let mut handler = SubprocessHandler::new(plugin.config.clone())
+                .with_events(events.clone())
+                .with_sniffer(sniffer.clone());
+            handler.initialize()?;
+            registry.register(Arc::new(RwLock::new(handler)))
+        });
+        if let Err(e) = &result {
+            log::warn!("Failed to load plugin {}: {}", dir.display(), e);
+        }
+        statuses.push(PluginStatus::new(
+            &dir,
+            plugin.as_ref().ok(),
+            result.as_ref().err(),
+        ));
+    }
+    Ok(statuses)
+}
@@ -0,0 +1,786 @@
+use crate::core::device::{CommandResponse, Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use crate::core::replay::Decoder;
+use crate::core::sniffer::{Direction, Frame, Sniffer};
+use crate::core::value::{self, format_state, StateMap};
+use serde_json::Value;
+use serialport::{DataBits, Parity, SerialPort, StopBits};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL: &str = "serial";
+
+const DEFAULT_BAUD_RATE: u32 = 9600;
+const DEFAULT_LENGTH_BYTES: usize = 2;
+/// Time reads wait for data before the port thread checks for polls.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const MIN_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+/// Longest frame accepted; longer ones mean the framing got out of step.
+const MAX_FRAME_BYTES: usize = 64 * 1024;
+
+/**
+ * Framing
+ * How messages are delimited on the wire:
+ *
+ * - `line`: text terminated by a newline, e.g. Arduino `Serial.println`
+ * - `length`: a big-endian length of `length_bytes` bytes, then the message
+ * - `raw`: whatever a read returns, for devices answering in single bursts
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    Line,
+    Length { bytes: usize },
+    Raw,
+}
+
+impl Framing {
+    pub fn from_details(details: &HashMap<String, String>) -> Result<Self, BlinkieError> {
+        match details.get("framing").map(String::as_str) {
+            None | Some("line") => Ok(Framing::Line),
+            Some("length") => {
+                let bytes = match details.get("length_bytes") {
+                    Some(bytes) => bytes
+                        .parse()
+                        .ok()
+                        .filter(|bytes| [1, 2, 4].contains(bytes))
+                        .ok_or_else(|| {
+                            format!("Invalid length_bytes '{}', expected 1, 2 or 4", bytes)
+                        })?,
+                    None => DEFAULT_LENGTH_BYTES,
+                };
+                Ok(Framing::Length { bytes })
+            }
+            Some("raw") => Ok(Framing::Raw),
+            Some(other) => {
+                Err(format!("Invalid framing '{}', expected line, length or raw", other).into())
+            }
+        }
+    }
+
+    /// Whether messages are text rather than bytes written as hex.
+    fn is_text(self) -> bool {
+        self == Framing::Line
+    }
+}
+
+/**
+ * Deframer
+ * Splits the bytes read from a port into messages.
+ */
+pub struct Deframer {
+    framing: Framing,
+    buffer: Vec<u8>,
+}
+
+impl Deframer {
+    pub fn new(framing: Framing) -> Self {
+        Deframer {
+            framing,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Takes `data` read from the port and returns the messages completed
+    /// by it.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        match self.framing {
+            Framing::Raw => frames.push(data.to_vec()),
+            Framing::Line => {
+                self.buffer.extend_from_slice(data);
+                while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                    let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+                    line.pop();
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                    if !line.is_empty() {
+                        frames.push(line);
+                    }
+                }
+                if self.buffer.len() > MAX_FRAME_BYTES {
+                    self.buffer.clear();
+                }
+            }
+            Framing::Length { bytes } => {
+                self.buffer.extend_from_slice(data);
+                while self.buffer.len() >= bytes {
+                    let length = self.buffer[..bytes]
+                        .iter()
+                        .fold(0usize, |length, byte| length << 8 | *byte as usize);
+                    if length > MAX_FRAME_BYTES {
+                        self.buffer.clear();
+                        break;
+                    }
+                    if self.buffer.len() < bytes + length {
+                        break;
+                    }
+                    frames.push(self.buffer[bytes..bytes + length].to_vec());
+                    self.buffer.drain(..bytes + length);
+                }
+            }
+        }
+        frames
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses a line a device printed into state keys: a JSON object sets one
+/// key per field, `key=value` pairs separated by spaces, commas or
+/// semicolons set those keys, anything else sets `state`.
+fn parse_line(line: &str) -> HashMap<String, String> {
+    let line = line.trim();
+    if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(line) {
+        return fields
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(s) => (key, s),
+                other => (key, other.to_string()),
+            })
+            .collect();
+    }
+    let pairs: HashMap<String, String> = line
+        .split([' ', ',', ';'])
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (!key.is_empty()).then(|| (key.to_string(), value.to_string()))
+        })
+        .collect();
+    if pairs.is_empty() {
+        HashMap::from([("state".to_string(), line.to_string())])
+    } else {
+        pairs
+    }
+}
+
+/// State carried by a message.
+pub fn parse_frame(framing: Framing, frame: &[u8]) -> HashMap<String, String> {
+    if framing.is_text() {
+        parse_line(&String::from_utf8_lossy(frame))
+    } else {
+        HashMap::from([("data".to_string(), encode_hex(frame))])
+    }
+}
+
+/**
+ * SerialSpec
+ * Serial port of a device, read from its connection details:
+ *
+ * - `port`: e.g. `/dev/ttyUSB0` or `COM3`
+ * - `baud_rate`, 9600 by default, and `data_bits` (5 to 8), `parity`
+ *   (`none`, `odd` or `even`) and `stop_bits` (1 or 2), 8N1 by default
+ * - `framing`: `line` (default), `length` or `raw`, see `Framing`, and
+ *   `length_bytes` of the `length` framing
+ * - `line_ending` written after lines: `lf` (default) or `crlf`
+ * - `command.<name>`: message sent for command `<name>`, with `{param}`
+ *   replaced by the command's parameters. Hex for the binary framings,
+ *   where `{param}` takes the parameter as a single byte, e.g. `02{level}`.
+ * - `poll_command` and `poll_secs`: message sent every `poll_secs` seconds
+ *   for devices that only answer when asked
+ *
+ * Lines set state as in `parse_line`; binary messages set `data` to their
+ * bytes in hex.
+ */
+#[derive(Clone, Debug)]
+pub struct SerialSpec {
+    pub port: String,
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub framing: Framing,
+    pub line_ending: &'static str,
+    /// Message templates, by command.
+    pub commands: HashMap<String, String>,
+    pub poll_command: Option<String>,
+    pub poll_secs: u64,
+}
+
+impl SerialSpec {
+    pub fn from_config(config: &Config) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let invalid = |key: &str, value: &str| -> BlinkieError {
+            format!("Invalid {} '{}' for device '{}'", key, value, config.id).into()
+        };
+        let port = details
+            .get("port")
+            .filter(|port| !port.is_empty())
+            .cloned()
+            .ok_or_else(|| format!("Device '{}' has no serial port", config.id))?;
+        let baud_rate = match details.get("baud_rate") {
+            Some(rate) => rate
+                .parse()
+                .ok()
+                .filter(|rate| *rate > 0)
+                .ok_or_else(|| invalid("baud_rate", rate))?,
+            None => DEFAULT_BAUD_RATE,
+        };
+        let data_bits = match details.get("data_bits").map(String::as_str) {
+            None | Some("8") => DataBits::Eight,
+            Some("7") => DataBits::Seven,
+            Some("6") => DataBits::Six,
+            Some("5") => DataBits::Five,
+            Some(other) => return Err(invalid("data_bits", other)),
+        };
+        let parity = match details.get("parity").map(String::as_str) {
+            None | Some("none") => Parity::None,
+            Some("odd") => Parity::Odd,
+            Some("even") => Parity::Even,
+            Some(other) => return Err(invalid("parity", other)),
+        };
+        let stop_bits = match details.get("stop_bits").map(String::as_str) {
+            None | Some("1") => StopBits::One,
+            Some("2") => StopBits::Two,
+            Some(other) => return Err(invalid("stop_bits", other)),
+        };
+        let line_ending = match details.get("line_ending").map(String::as_str) {
+            None | Some("lf") => "\n",
+            Some("crlf") => "\r\n",
+            Some(other) => return Err(invalid("line_ending", other)),
+        };
+        let framing = Framing::from_details(details)?;
+        let commands: HashMap<String, String> = details
+            .iter()
+            .filter_map(|(key, template)| {
+                Some((key.strip_prefix("command.")?.to_string(), template.clone()))
+            })
+            .collect();
+        let poll_command = details.get("poll_command").cloned();
+        let spec = SerialSpec {
+            port,
+            baud_rate,
+            data_bits,
+            parity,
+            stop_bits,
+            framing,
+            line_ending,
+            commands,
+            poll_command,
+            poll_secs: match details.get("poll_secs") {
+                Some(secs) => secs
+                    .parse()
+                    .ok()
+                    .filter(|secs| *secs > 0)
+                    .ok_or_else(|| invalid("poll_secs", secs))?,
+                None => 0,
+            },
+        };
+        // Catch templates that can never be sent when the device is added
+        if !framing.is_text() {
+            for (name, template) in &spec.commands {
+                let sample = fill(template, |_| Some("00".to_string()));
+                if decode_hex(&sample).is_none() {
+                    return Err(invalid(&format!("command.{}", name), template));
+                }
+            }
+            if let Some(poll) = &spec.poll_command {
+                if decode_hex(poll).is_none() {
+                    return Err(invalid("poll_command", poll));
+                }
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Message for `command`: its template filled in with the parameters,
+    /// or the `data` parameter of the built-in `send` command.
+    fn message(
+        &self,
+        device_id: &str,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<Vec<u8>, BlinkieError> {
+        let parameter = |name: &str| parameters.and_then(|parameters| parameters.get(name));
+        let text = match self.commands.get(command) {
+            Some(template) if self.framing.is_text() => {
+                let filled = fill(template, |name| parameter(name).cloned());
+                missing_parameter(command, &filled)?;
+                filled
+            }
+            Some(template) => {
+                let mut invalid = None;
+                let filled = fill(template, |name| {
+                    let value = parameter(name)?;
+                    let byte = match value.strip_prefix("0x") {
+                        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+                        None => value.parse::<u8>().ok(),
+                    };
+                    if byte.is_none() {
+                        invalid = Some(value.to_string());
+                    }
+                    byte.map(|byte| format!("{:02x}", byte))
+                });
+                if let Some(value) = invalid {
+                    return Err(format!("'{}' is not a byte, 0 to 255", value).into());
+                }
+                missing_parameter(command, &filled)?;
+                filled
+            }
+            None if command == "send" => parameter("data")
+                .ok_or_else(|| "Command 'send' needs parameter 'data'".to_string())?
+                .clone(),
+            None => return Err(BlinkieError::unsupported_command(device_id, command)),
+        };
+        if self.framing.is_text() {
+            return Ok(text.into_bytes());
+        }
+        decode_hex(&text).ok_or_else(|| format!("Invalid hex '{}'", text).into())
+    }
+
+    /// Puts `message` on the wire in the device's framing.
+    fn frame(&self, message: &[u8]) -> Result<Vec<u8>, BlinkieError> {
+        match self.framing {
+            Framing::Line => {
+                let mut framed = message.to_vec();
+                framed.extend_from_slice(self.line_ending.as_bytes());
+                Ok(framed)
+            }
+            Framing::Length { bytes } => {
+                fits_length(message, bytes)?;
+                let length = (message.len() as u64).to_be_bytes();
+                let mut framed = length[length.len() - bytes..].to_vec();
+                framed.extend_from_slice(message);
+                Ok(framed)
+            }
+            Framing::Raw => Ok(message.to_vec()),
+        }
+    }
+}
+
+/// Fails for messages too long for a length of `bytes` bytes.
+fn fits_length(message: &[u8], bytes: usize) -> Result<(), BlinkieError> {
+    if message.len() >= 1usize << (8 * bytes).min(usize::BITS as usize - 1) {
+        return Err(format!(
+            "Message of {} bytes is too long for a {} byte length",
+            message.len(),
+            bytes
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Replaces every `{name}` in `template` by `value(name)`, leaving the
+/// placeholders it has no value for as they are.
+fn fill(template: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        filled.push_str(&rest[..start]);
+        match value(&rest[start + 1..end]) {
+            Some(value) => filled.push_str(&value),
+            None => filled.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+fn missing_parameter(command: &str, filled: &str) -> Result<(), BlinkieError> {
+    let Some(start) = filled.find('{') else {
+        return Ok(());
+    };
+    let name = filled[start + 1..].split('}').next().unwrap_or_default();
+    Err(format!("Command '{}' needs parameter '{}'", command, name).into())
+}
+
+/**
+ * Port
+ * A device's serial port, kept open on a thread of its own, and what the
+ * device reported.
+ */
+struct Port {
+    device_id: String,
+    spec: SerialSpec,
+    /// Write half of the open port, `None` while it is closed.
+    writer: Mutex<Option<Box<dyn SerialPort>>>,
+    state: RwLock<HashMap<String, String>>,
+    error: RwLock<Option<String>>,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl Port {
+    fn spawn(self: &Arc<Self>) -> Result<(), BlinkieError> {
+        let port = self.clone();
+        thread::Builder::new()
+            .name(format!("serial-{}", self.device_id))
+            .spawn(move || port.run())
+            .map(|_| ())
+            .map_err(|e| format!("Failed to start serial port thread: {}", e).into())
+    }
+
+    /// Keeps the port open, reading messages, and reopens it with a growing
+    /// delay when it fails, e.g. after the board was unplugged.
+    fn run(&self) {
+        let mut retry = MIN_RETRY;
+        loop {
+            let result = self.open().and_then(|port| {
+                retry = MIN_RETRY;
+                self.read(port)
+            });
+            if let Err(e) = result {
+                let error = format!("Serial port {}: {}", self.spec.port, e);
+                log::warn!("{}", error);
+                *self.error.write().unwrap() = Some(error);
+            }
+            *self.writer.lock().unwrap() = None;
+            self.update_state(HashMap::from([(
+                "connected".to_string(),
+                "false".to_string(),
+            )]));
+            thread::sleep(retry);
+            retry = (retry * 2).min(MAX_RETRY);
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn SerialPort>, BlinkieError> {
+        let spec = &self.spec;
+        let port = serialport::new(&spec.port, spec.baud_rate)
+            .data_bits(spec.data_bits)
+            .parity(spec.parity)
+            .stop_bits(spec.stop_bits)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(|e| BlinkieError::connection(e.to_string()))?;
+        let writer = port
+            .try_clone()
+            .map_err(|e| BlinkieError::connection(e.to_string()))?;
+        *self.writer.lock().unwrap() = Some(writer);
+        *self.error.write().unwrap() = None;
+        self.update_state(HashMap::from([(
+            "connected".to_string(),
+            "true".to_string(),
+        )]));
+        Ok(port)
+    }
+
+    /// Reads messages until the port fails, sending the poll command when
+    /// it is due.
+    fn read(&self, mut port: Box<dyn SerialPort>) -> Result<(), BlinkieError> {
+        let mut deframer = Deframer::new(self.spec.framing);
+        let mut buffer = [0u8; 1024];
+        let mut last_poll: Option<Instant> = None;
+        loop {
+            if let Some(poll) = &self.spec.poll_command {
+                let interval = Duration::from_secs(self.spec.poll_secs.max(1));
+                if last_poll.is_none_or(|at| at.elapsed() >= interval) {
+                    last_poll = Some(Instant::now());
+                    let message = if self.spec.framing.is_text() {
+                        Ok(poll.clone().into_bytes())
+                    } else {
+                        decode_hex(poll).ok_or_else(|| format!("Invalid hex '{}'", poll).into())
+                    };
+                    if let Err(e) = message.and_then(|message| self.write(&message)) {
+                        log::warn!("Polling '{}' failed: {}", self.device_id, e);
+                    }
+                }
+            }
+            match port.read(&mut buffer) {
+                Ok(0) => {}
+                Ok(read) => {
+                    for frame in deframer.push(&buffer[..read]) {
+                        self.take(&frame);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => {
+                }
+                Err(e) => return Err(BlinkieError::connection(e.to_string())),
+            }
+        }
+    }
+
+    fn take(&self, frame: &[u8]) {
+        self.sniff(Direction::In, frame);
+        self.update_state(parse_frame(self.spec.framing, frame));
+    }
+
+    /// Writes `message` in the device's framing.
+    fn write(&self, message: &[u8]) -> Result<(), BlinkieError> {
+        let framed = self.spec.frame(message)?;
+        self.write_parts(message, &[&framed])
+    }
+
+    /// Writes an output frame in the device's framing, without copying it
+    /// into a framed message first.
+    fn write_frame(&self, frame: &[u8]) -> Result<(), BlinkieError> {
+        let length = (frame.len() as u64).to_be_bytes();
+        let (header, trailer): (&[u8], &[u8]) = match self.spec.framing {
+            Framing::Line => (&[], self.spec.line_ending.as_bytes()),
+            Framing::Length { bytes } => {
+                fits_length(frame, bytes)?;
+                (&length[length.len() - bytes..], &[])
+            }
+            Framing::Raw => (&[], &[]),
+        };
+        self.write_parts(frame, &[header, frame, trailer])
+    }
+
+    /// Writes `parts` making up `message` on the wire, one after another.
+    fn write_parts(&self, message: &[u8], parts: &[&[u8]]) -> Result<(), BlinkieError> {
+        let mut writer = self.writer.lock().unwrap();
+        let port = writer.as_mut().ok_or_else(|| {
+            BlinkieError::connection(format!("Serial port {} is not open", self.spec.port))
+        })?;
+        self.sniff(Direction::Out, message);
+        parts
+            .iter()
+            .try_for_each(|part| port.write_all(part))
+            .and_then(|_| port.flush())
+            .map_err(|e| {
+                BlinkieError::connection(format!(
+                    "Failed to write to serial port {}: {}",
+                    self.spec.port, e
+                ))
+            })
+    }
+
+    fn sniff(&self, direction: Direction, message: &[u8]) {
+        if let Some(sniffer) = &self.sniffer {
+            let data = if self.spec.framing.is_text() {
+                message.to_vec()
+            } else {
+                encode_hex(message).into_bytes()
+            };
+            sniffer.record(PROTOCOL, Some(&self.device_id), direction, &data);
+        }
+    }
+
+    fn update_state(&self, update: HashMap<String, String>) {
+        let mut state = self.state.write().unwrap();
+        for (key, new_value) in update {
+            let old_value = state.insert(key.clone(), new_value.clone());
+            if old_value.as_ref() == Some(&new_value) {
+                continue;
+            }
+            if let Some(events) = &self.events {
+                let _ = events.publish(EventKind::StateChanged {
+                    device_id: self.device_id.clone(),
+                    key,
+                    old_value,
+                    new_value,
+                    reported_at: None,
+                });
+            }
+        }
+    }
+}
+
+/**
+ * SerialDecoder
+ * Replays recorded messages, lines or hex as the sniffer keeps them,
+ * through the device's framing.
+ */
+pub struct SerialDecoder;
+
+impl Decoder for SerialDecoder {
+    fn decode(
+        &self,
+        frame: &Frame,
+        details: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, BlinkieError> {
+        Ok(match Framing::from_details(details)? {
+            Framing::Line => parse_line(&frame.data),
+            _ => HashMap::from([("data".to_string(), frame.data.clone())]),
+        })
+    }
+}
+
+/**
+ * SerialDevice
+ * A board on a serial port, e.g. an Arduino driving LEDs. Its state holds
+ * what it reported plus `connected`.
+ */
+pub struct SerialDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    port: Arc<Port>,
+}
+
+impl Device for SerialDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        value::parse_state(&self.port.state.read().unwrap())
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.port.update_state(format_state(&state));
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.command(command, parameters) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        let message = self
+            .port
+            .spec
+            .message(&self.id, command, parameters.as_ref())?;
+        self.port.write(&message)?;
+        Ok(CommandResponse::default())
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), BlinkieError> {
+        self.port.write_frame(frame)
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        let mut commands: Vec<String> = self.port.spec.commands.keys().cloned().collect();
+        commands.sort();
+        commands.push("send".to_string());
+        Some(commands)
+    }
+}
+
+/**
+ * SerialHandler
+ * The built-in `serial` handler: talks to devices on serial ports and USB
+ * serial adapters, see `SerialSpec`. Every device keeps its port open on a
+ * thread of its own once the handler is initialized, and reopens it when
+ * it goes away.
+ */
+#[derive(Default)]
+pub struct SerialHandler {
+    ports: HashMap<String, Arc<Port>>,
+    initialized: bool,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+}
+
+impl SerialHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes state changes of all devices on `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Records messages read from and written to ports on `sniffer` while
+    /// it captures.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        self.sniffer = Some(sniffer);
+        self
+    }
+}
+
+impl ProtocolHandler for SerialHandler {
+    fn name(&self) -> String {
+        PROTOCOL.to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = SerialSpec::from_config(config)?;
+        if let Some((other, _)) = self
+            .ports
+            .iter()
+            .find(|(id, port)| **id != config.id && port.spec.port == spec.port)
+        {
+            return Err(format!(
+                "Serial port {} is already used by device '{}'",
+                spec.port, other
+            )
+            .into());
+        }
+        let port = Arc::new(Port {
+            device_id: config.id.clone(),
+            spec,
+            writer: Mutex::new(None),
+            state: RwLock::new(HashMap::new()),
+            error: RwLock::new(None),
+            events: self.events.clone(),
+            sniffer: self.sniffer.clone(),
+        });
+        if self.initialized {
+            port.spawn()?;
+        }
+        self.ports.insert(config.id.clone(), port.clone());
+        Ok(Box::new(SerialDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            port,
+        }))
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        SerialSpec::from_config(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        device.command(cmd, params).map(|_| ())
+    }
+
+    /// Opens the ports of the devices created so far.
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        if self.initialized {
+            return Ok(());
+        }
+        self.initialized = true;
+        self.ports.values().try_for_each(|port| port.spawn())
+    }
+
+    /// Reports the first port that can't be opened or failed.
+    fn health(&self) -> Result<(), BlinkieError> {
+        match self
+            .ports
+            .values()
+            .find_map(|port| port.error.read().unwrap().clone())
+        {
+            Some(e) => Err(BlinkieError::connection(e)),
+            None => Ok(()),
+        }
+    }
+}
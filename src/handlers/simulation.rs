@@ -0,0 +1,465 @@
+use crate::core::chaos::{next_f64, next_u64};
+use crate::core::device::{Command, CommandResponse, Config, Device, ProtocolHandler, Type};
+use crate::core::error::BlinkieError;
+use crate::core::value::{format_state, parse_state, StateMap};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL: &str = "simulation";
+
+const DEFAULT_UPDATE_SECS: u64 = 5;
+const DEFAULT_SCRIPT_SECS: u64 = 10;
+/// Prefix of the connection details holding the initial state.
+const STATE_PREFIX: &str = "state.";
+/// Prefix of the connection details holding the range of a sensor value.
+const SENSOR_PREFIX: &str = "sensor.";
+/// Prefix of the connection details holding the values a key steps through.
+const SCRIPT_PREFIX: &str = "script.";
+
+/**
+ * Range
+ * Values from `min` to `max`, written `min..max` or as a single value, and
+ * shown with as many decimals as the bounds have.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+    pub decimals: usize,
+}
+
+impl Range {
+    fn parse(value: &str) -> Option<Self> {
+        let (min, max) = value.split_once("..").unwrap_or((value, value));
+        let (min, max) = (min.trim(), max.trim());
+        let decimals = |bound: &str| bound.split_once('.').map_or(0, |(_, d)| d.len());
+        let range = Range {
+            min: min.parse().ok()?,
+            max: max.parse().ok()?,
+            decimals: decimals(min).max(decimals(max)),
+        };
+        (range.min.is_finite() && range.max.is_finite() && range.min <= range.max).then_some(range)
+    }
+
+    fn sample(&self, rng: &mut u64) -> f64 {
+        self.min + next_f64(rng) * (self.max - self.min)
+    }
+
+    fn format(&self, value: f64) -> String {
+        format!("{:.*}", self.decimals, value)
+    }
+}
+
+/**
+ * SimulationSpec
+ * Behavior of a simulated device, read from its connection details:
+ *
+ * - `state.<key>`: initial value of `<key>`
+ * - `sensor.<key>`: `min..max`, a random value drawn every `update_secs`
+ * - `script.<key>`: comma separated values `<key>` steps through, one every
+ *   `script_secs`, starting over after the last
+ * - `commands`: comma separated commands the device takes, all by default
+ * - `latency_ms`: `min..max` or a fixed delay of every command and frame
+ * - `failure_rate`: share of commands and health checks failing, 0 to 1
+ * - `seed`: makes random values and failures repeatable
+ *
+ * Commands blinkie knows change the state as a real device would, e.g.
+ * `turn_on` sets `state` to `on`; other commands set their parameters.
+ */
+#[derive(Clone, Debug)]
+pub struct SimulationSpec {
+    pub state: HashMap<String, String>,
+    pub sensors: BTreeMap<String, Range>,
+    pub scripts: BTreeMap<String, Vec<String>>,
+    pub commands: Option<Vec<String>>,
+    pub update: Duration,
+    pub script_step: Duration,
+    pub latency: Option<Range>,
+    pub failure_rate: f64,
+    pub seed: Option<u64>,
+}
+
+impl SimulationSpec {
+    pub fn from_config(config: &Config) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let invalid = |key: &str, value: &str| -> BlinkieError {
+            format!("Invalid {} '{}' for device '{}'", key, value, config.id).into()
+        };
+        let secs = |key: &str, default: u64| -> Result<Duration, BlinkieError> {
+            match details.get(key) {
+                Some(value) => value
+                    .parse()
+                    .ok()
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| invalid(key, value)),
+                None => Ok(Duration::from_secs(default)),
+            }
+        };
+        let prefixed = |prefix: &'static str| {
+            details
+                .iter()
+                .filter_map(move |(key, value)| key.strip_prefix(prefix).map(|key| (key, value)))
+        };
+        let mut sensors = BTreeMap::new();
+        for (key, value) in prefixed(SENSOR_PREFIX) {
+            let range = Range::parse(value).ok_or_else(|| invalid(SENSOR_PREFIX, value))?;
+            sensors.insert(key.to_string(), range);
+        }
+        let list = |value: &str| -> Vec<String> {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        };
+        let mut scripts = BTreeMap::new();
+        for (key, value) in prefixed(SCRIPT_PREFIX) {
+            let values = list(value);
+            if values.is_empty() {
+                return Err(invalid(SCRIPT_PREFIX, value));
+            }
+            scripts.insert(key.to_string(), values);
+        }
+        let latency = match details.get("latency_ms") {
+            Some(value) => Some(
+                Range::parse(value)
+                    .filter(|range| range.min >= 0.0)
+                    .ok_or_else(|| invalid("latency_ms", value))?,
+            ),
+            None => None,
+        };
+        let failure_rate = match details.get("failure_rate") {
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .ok_or_else(|| invalid("failure_rate", value))?,
+            None => 0.0,
+        };
+        let seed = match details.get("seed") {
+            Some(value) => Some(value.parse().map_err(|_| invalid("seed", value))?),
+            None => None,
+        };
+
+        Ok(SimulationSpec {
+            state: prefixed(STATE_PREFIX)
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+            sensors,
+            scripts,
+            commands: details.get("commands").map(|commands| list(commands)),
+            update: secs("update_secs", DEFAULT_UPDATE_SECS)?,
+            script_step: secs("script_secs", DEFAULT_SCRIPT_SECS)?,
+            latency,
+            failure_rate,
+            seed,
+        })
+    }
+}
+
+/// Runtime state of a simulated device, shared with its handler for
+/// health checks.
+struct Simulated {
+    state: HashMap<String, String>,
+    failure_rate: f64,
+    rng: u64,
+    started: Instant,
+    sampled_at: Option<Instant>,
+}
+
+impl Simulated {
+    fn fails(&mut self) -> bool {
+        self.failure_rate > 0.0 && next_f64(&mut self.rng) < self.failure_rate
+    }
+}
+
+/// Changes `state` as a device carrying out `command` would.
+fn apply(state: &mut HashMap<String, String>, command: &Command) {
+    let mut set = |key: &str, value: String| {
+        state.insert(key.to_string(), value);
+    };
+    match command {
+        Command::TurnOn => set("state", "on".to_string()),
+        Command::TurnOff => set("state", "off".to_string()),
+        Command::Toggle => {
+            let on = state.get("state").is_some_and(|value| value == "on");
+            state.insert(
+                "state".to_string(),
+                if on { "off" } else { "on" }.to_string(),
+            );
+        }
+        Command::SetBrightness(brightness) => set("brightness", brightness.to_string()),
+        Command::SetColorTemperature(mired) => set("color_temp", mired.to_string()),
+        Command::SetColor(color) => set("color", color.to_string()),
+        Command::SetPosition(position) => set("position", position.to_string()),
+        Command::Open => set("position", "100".to_string()),
+        Command::Close => set("position", "0".to_string()),
+        Command::Lock => set("lock", "locked".to_string()),
+        Command::Unlock => set("lock", "unlocked".to_string()),
+        Command::Custom { parameters, .. } => state.extend(parameters.clone().unwrap_or_default()),
+    }
+}
+
+/**
+ * SimulationDevice
+ * A virtual device for tests and demos, behaving as its `SimulationSpec`
+ * describes.
+ */
+pub struct SimulationDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    spec: SimulationSpec,
+    sim: Arc<Mutex<Simulated>>,
+}
+
+impl SimulationDevice {
+    /// Waits for the simulated latency and fails `what` at the failure rate.
+    fn transmit(&self, what: &str) -> Result<(), BlinkieError> {
+        let (delay, failed) = {
+            let mut sim = self.sim.lock().unwrap();
+            let delay = self
+                .spec
+                .latency
+                .map(|latency| Duration::from_secs_f64(latency.sample(&mut sim.rng) / 1000.0));
+            (delay, sim.fails())
+        };
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        if failed {
+            return Err(BlinkieError::timeout(format!(
+                "Simulated failure of '{}' for '{}'",
+                what, self.id
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Device for SimulationDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        let mut sim = self.sim.lock().unwrap();
+        let now = Instant::now();
+        if sim
+            .sampled_at
+            .is_none_or(|at| now.duration_since(at) >= self.spec.update)
+        {
+            for (key, range) in &self.spec.sensors {
+                let value = range.format(range.sample(&mut sim.rng));
+                sim.state.insert(key.clone(), value);
+            }
+            sim.sampled_at = Some(now);
+        }
+        let step = (now.duration_since(sim.started).as_secs_f64()
+            / self.spec.script_step.as_secs_f64()) as usize;
+        for (key, values) in &self.spec.scripts {
+            sim.state
+                .insert(key.clone(), values[step % values.len()].clone());
+        }
+        parse_state(&sim.state)
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.sim.lock().unwrap().state.extend(format_state(&state));
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.command(command, parameters) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        if let Some(commands) = &self.spec.commands {
+            if !commands.iter().any(|c| c == command) {
+                return Err(format!("Device '{}' has no command '{}'", self.id, command).into());
+            }
+        }
+        let command = Command::parse(command, parameters)?;
+        self.transmit(command.name())?;
+        apply(&mut self.sim.lock().unwrap().state, &command);
+        Ok(CommandResponse::default())
+    }
+
+    /// Takes frames, with the latency and failure rate of commands.
+    fn write_frame(&mut self, _frame: &[u8]) -> Result<(), BlinkieError> {
+        self.transmit("frame")
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        self.spec.commands.clone()
+    }
+}
+
+/**
+ * SimulationHandler
+ * Creates virtual devices with random sensor values, scripted state,
+ * artificial latency and failures, so tests and demos have devices to
+ * register without any hardware.
+ */
+#[derive(Default)]
+pub struct SimulationHandler {
+    devices: RwLock<HashMap<String, Arc<Mutex<Simulated>>>>,
+}
+
+impl SimulationHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ProtocolHandler for SimulationHandler {
+    fn name(&self) -> String {
+        "simulation".to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = SimulationSpec::from_config(config)?;
+        // Seeded devices differ from each other but repeat across runs
+        let mut rng = match spec.seed {
+            Some(seed) => config.id.bytes().fold(seed, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+            }),
+            None => RandomState::new().hash_one(&config.id),
+        };
+        next_u64(&mut rng);
+        let sim = Arc::new(Mutex::new(Simulated {
+            state: spec.state.clone(),
+            failure_rate: spec.failure_rate,
+            rng,
+            started: Instant::now(),
+            sampled_at: None,
+        }));
+        self.devices
+            .write()
+            .unwrap()
+            .insert(config.id.clone(), sim.clone());
+        Ok(Box::new(SimulationDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            spec,
+            sim,
+        }))
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        SimulationSpec::from_config(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        device.send_cmd(cmd, params);
+        Ok(())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        Ok(())
+    }
+
+    /// Fails with the failure rate of `device`, as its commands do.
+    fn check_health(&self, device: &dyn Device) -> Result<(), BlinkieError> {
+        let devices = self.devices.read().unwrap();
+        let Some(sim) = devices.get(device.get_id()) else {
+            return Ok(());
+        };
+        if sim.lock().unwrap().fails() {
+            return Err(BlinkieError::connection(format!(
+                "Simulated outage of '{}'",
+                device.get_id()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::testing::contract::{self, Contract, Parameters};
+
+    fn config(id: &str, device_type: Type, details: &[(&str, &str)]) -> Config {
+        Config {
+            id: id.to_string(),
+            name: id.replace('_', " "),
+            device_type,
+            connection_details: details
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            supported_protocols: vec![PROTOCOL.to_string()],
+            preferred_handler: None,
+        }
+    }
+
+    impl Contract for SimulationHandler {
+        fn handler() -> Self {
+            SimulationHandler::new()
+        }
+
+        fn devices() -> Vec<Config> {
+            vec![
+                config("hall_light", Type::Switch, &[("state.state", "off")]),
+                config(
+                    "living_room_thermometer",
+                    Type::Sensor,
+                    &[("sensor.temperature", "18.0..24.0"), ("seed", "7")],
+                ),
+                config(
+                    "garden_pump",
+                    Type::Actor,
+                    &[("commands", "turn_on,turn_off"), ("latency_ms", "0..2")],
+                ),
+            ]
+        }
+
+        fn commands() -> Vec<(String, Parameters)> {
+            vec![
+                ("turn_on".to_string(), None),
+                ("turn_off".to_string(), None),
+            ]
+        }
+
+        fn invalid_devices() -> Vec<Config> {
+            vec![
+                config("warm", Type::Sensor, &[("sensor.temperature", "warm")]),
+                config("flaky", Type::Switch, &[("failure_rate", "2")]),
+            ]
+        }
+    }
+
+    #[test]
+    fn satisfies_the_handler_contract() {
+        contract::run::<SimulationHandler>();
+    }
+}
@@ -0,0 +1,594 @@
+use crate::core::device::{
+    ApiVersion, CommandResponse, Config, Device, ProtocolHandler, Type, HANDLER_API_VERSION,
+};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use crate::core::replay::Decoder;
+use crate::core::sniffer::{Direction, Frame, Sniffer};
+use crate::core::value::{format_state, parse_state, StateMap};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A process that ran at least this long starts over with the shortest backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/**
+ * SubprocessConfig
+ * An external handler program and the protocols it serves.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubprocessConfig {
+    pub name: String,
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub protocols: Vec<String>,
+    #[serde(default)]
+    pub priority: u8,
+    /// Handler API version the program implements. Defaults to the version
+    /// of this blinkie; the program may also report it from `initialize`.
+    #[serde(default)]
+    pub api_version: Option<ApiVersion>,
+}
+
+#[derive(Serialize)]
+struct RpcRequest<'a> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'a str,
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    #[serde(default)]
+    code: i64,
+    message: String,
+}
+
+/// Anything the subprocess writes: a response when it has an id, a
+/// notification when it has a method.
+#[derive(Deserialize)]
+struct RpcMessage {
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    result: Value,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct StateUpdate {
+    device_id: String,
+    state: HashMap<String, String>,
+    /// When the device measured the state, if it knows.
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+/**
+ * SubprocessDecoder
+ * Replays recorded JSON-RPC lines, picking the state out of
+ * `state_update` notifications.
+ */
+pub struct SubprocessDecoder;
+
+impl Decoder for SubprocessDecoder {
+    fn decode(
+        &self,
+        frame: &Frame,
+        _details: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, BlinkieError> {
+        let message: RpcMessage = serde_json::from_str(&frame.data)
+            .map_err(|e| format!("Invalid JSON-RPC message: {}", e))?;
+        if message.method.as_deref() != Some("state_update") {
+            return Ok(HashMap::new());
+        }
+        let update: StateUpdate = serde_json::from_value(message.params)
+            .map_err(|e| BlinkieError::serialization(format!("Invalid state_update: {}", e)))?;
+        Ok(update.state)
+    }
+}
+
+type Reply = mpsc::Sender<Result<Value, BlinkieError>>;
+
+struct Shared {
+    config: SubprocessConfig,
+    stdin: Mutex<Option<ChildStdin>>,
+    child: Mutex<Option<Child>>,
+    pending: Mutex<HashMap<u64, Reply>>,
+    next_id: AtomicU64,
+    /// Configs of created devices, sent again after a restart.
+    devices: RwLock<HashMap<String, Config>>,
+    states: RwLock<HashMap<String, HashMap<String, String>>>,
+    error: Mutex<Option<String>>,
+    events: Option<Arc<EventBus>>,
+    sniffer: Option<Arc<Sniffer>>,
+    /// Handler API version of the program, as configured or reported.
+    api_version: Mutex<ApiVersion>,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    /// Calls a method in the subprocess and waits for its result.
+    fn call(&self, method: &str, params: Value) -> Result<Value, BlinkieError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, result) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, reply);
+
+        let line = serde_json::to_string(&RpcRequest {
+            jsonrpc: "2.0",
+            id,
+            method,
+            params,
+        })
+        .map_err(|e| {
+            BlinkieError::serialization(format!("Failed to serialize {} request: {}", method, e))
+        })?;
+        self.sniff(Direction::Out, &line);
+        let written = match self.stdin.lock().unwrap().as_mut() {
+            Some(stdin) => writeln!(stdin, "{}", line)
+                .and_then(|_| stdin.flush())
+                .map_err(|e| {
+                    BlinkieError::connection(format!(
+                        "Failed to write to '{}': {}",
+                        self.config.name, e
+                    ))
+                }),
+            None => Err(BlinkieError::connection(format!(
+                "Handler '{}' is not running",
+                self.config.name
+            ))),
+        };
+        if let Err(e) = written {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        match result.recv_timeout(RPC_TIMEOUT) {
+            Ok(result) => result,
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(BlinkieError::timeout(format!(
+                    "Handler '{}' did not answer {} in time",
+                    self.config.name, method
+                )))
+            }
+        }
+    }
+
+    fn sniff(&self, direction: Direction, line: &str) {
+        let Some(sniffer) = &self.sniffer else {
+            return;
+        };
+        let device_id = serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(|message| {
+                let params = &message["params"];
+                params["device_id"]
+                    .as_str()
+                    .or_else(|| params["config"]["id"].as_str())
+                    .map(str::to_string)
+            });
+        sniffer.record(
+            &self.config.name,
+            device_id.as_deref(),
+            direction,
+            line.as_bytes(),
+        );
+    }
+
+    fn handle(&self, message: RpcMessage) {
+        if let Some(id) = message.id {
+            if let Some(reply) = self.pending.lock().unwrap().remove(&id) {
+                let result = match message.error {
+                    Some(error) => Err(BlinkieError::from(format!(
+                        "{} (code {})",
+                        error.message, error.code
+                    ))),
+                    None => Ok(message.result),
+                };
+                let _ = reply.send(result);
+            }
+            return;
+        }
+        match message.method.as_deref() {
+            Some("state_update") => match serde_json::from_value::<StateUpdate>(message.params) {
+                Ok(update) => self.update_state(update),
+                Err(e) => log::warn!("Invalid state_update from '{}': {}", self.config.name, e),
+            },
+            Some("log") => log::info!("[{}] {}", self.config.name, message.params),
+            Some(other) => log::warn!(
+                "Unknown notification '{}' from '{}'",
+                other,
+                self.config.name
+            ),
+            None => {}
+        }
+    }
+
+    /// Merges reported state into the device's state and publishes the changes.
+    fn update_state(&self, update: StateUpdate) {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(update.device_id.clone()).or_default();
+        for (key, new_value) in update.state {
+            let old_value = state.insert(key.clone(), new_value.clone());
+            if old_value.as_ref() == Some(&new_value) {
+                continue;
+            }
+            if let Some(events) = &self.events {
+                let _ = events.publish(EventKind::StateChanged {
+                    device_id: update.device_id.clone(),
+                    key,
+                    old_value,
+                    new_value,
+                    reported_at: update.timestamp,
+                });
+            }
+        }
+    }
+
+    /// Starts the program, restarting it with increasing delays whenever it exits.
+    fn supervise(self: Arc<Self>) {
+        let mut backoff = MIN_BACKOFF;
+        while !self.stop.load(Ordering::Relaxed) {
+            let started = Instant::now();
+            match self.spawn() {
+                Ok(stdout) => {
+                    *self.error.lock().unwrap() = None;
+                    let shared = self.clone();
+                    thread::spawn(move || shared.setup());
+                    for line in BufReader::new(stdout).lines() {
+                        let Ok(line) = line else {
+                            break;
+                        };
+                        self.sniff(Direction::In, &line);
+                        match serde_json::from_str::<RpcMessage>(&line) {
+                            Ok(message) => self.handle(message),
+                            Err(e) => {
+                                log::warn!("Invalid message from '{}': {}", self.config.name, e)
+                            }
+                        }
+                    }
+                    let status = self
+                        .child
+                        .lock()
+                        .unwrap()
+                        .take()
+                        .and_then(|mut child| child.wait().ok());
+                    *self.stdin.lock().unwrap() = None;
+                    let error = match status {
+                        Some(status) => {
+                            format!("Handler '{}' exited with {}", self.config.name, status)
+                        }
+                        None => format!("Handler '{}' exited", self.config.name),
+                    };
+                    self.fail_pending(&error);
+                    log::warn!("{}", error);
+                    *self.error.lock().unwrap() = Some(error);
+                }
+                Err(e) => {
+                    log::warn!("{}", e);
+                    *self.error.lock().unwrap() = Some(e.to_string());
+                }
+            }
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            if started.elapsed() > STABLE_AFTER {
+                backoff = MIN_BACKOFF;
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    fn spawn(&self) -> Result<std::process::ChildStdout, BlinkieError> {
+        let mut child = Command::new(&self.config.program)
+            .args(&self.config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start '{}': {}", self.config.program, e))?;
+        let stdout = child.stdout.take().ok_or("Missing stdout")?;
+        if let Some(stderr) = child.stderr.take() {
+            let name = self.config.name.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    log::info!("[{}] {}", name, line);
+                }
+            });
+        }
+        *self.stdin.lock().unwrap() = child.stdin.take();
+        *self.child.lock().unwrap() = Some(child);
+        Ok(stdout)
+    }
+
+    /// Initializes a freshly started program and creates all known devices in it.
+    fn setup(&self) {
+        let negotiated = self
+            .call("initialize", json!({ "api_version": HANDLER_API_VERSION }))
+            .and_then(|result| match result.get("api_version") {
+                Some(version) => serde_json::from_value::<ApiVersion>(version.clone())
+                    .map_err(|e| {
+                        format!(
+                            "Handler '{}' sent an invalid api_version: {}",
+                            self.config.name, e
+                        )
+                    })?
+                    .negotiate(HANDLER_API_VERSION)
+                    .map_err(|e| {
+                        format!("Handler '{}' is incompatible: {}", self.config.name, e).into()
+                    }),
+                None => Ok(*self.api_version.lock().unwrap()),
+            });
+        match negotiated {
+            Ok(version) => *self.api_version.lock().unwrap() = version,
+            Err(e) => {
+                log::warn!("{}", e);
+                *self.error.lock().unwrap() = Some(e.to_string());
+                return;
+            }
+        }
+        let configs: Vec<Config> = self.devices.read().unwrap().values().cloned().collect();
+        for config in configs {
+            if let Err(e) = self.call("create_device", json!({ "config": config })) {
+                log::warn!("Failed to recreate '{}': {}", config.id, e);
+            }
+        }
+    }
+
+    fn fail_pending(&self, error: &str) {
+        for (_, reply) in self.pending.lock().unwrap().drain() {
+            let _ = reply.send(Err(BlinkieError::connection(error)));
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.stdin.lock().unwrap().is_some()
+    }
+}
+
+/**
+ * SubprocessDevice
+ * A device living in an external handler. Its state is whatever the
+ * handler last reported.
+ */
+pub struct SubprocessDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    shared: Arc<Shared>,
+}
+
+impl Device for SubprocessDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.shared
+            .states
+            .read()
+            .unwrap()
+            .get(&self.id)
+            .map(parse_state)
+            .unwrap_or_default()
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.shared.update_state(StateUpdate {
+            device_id: self.id.clone(),
+            state: format_state(&state),
+            timestamp: None,
+        });
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.command(command, parameters) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        let params = json!({
+            "device_id": self.id,
+            "command": command,
+            "parameters": parameters,
+        });
+        let result = self
+            .shared
+            .call("send_cmd", params)
+            .map_err(|e| format!("Command '{}' to '{}' failed: {}", command, self.id, e))?;
+        let Value::Object(fields) = result else {
+            return Ok(CommandResponse::default());
+        };
+        Ok(CommandResponse::new(
+            fields
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(s) => (key, s),
+                    other => (key, other.to_string()),
+                })
+                .collect(),
+        ))
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+}
+
+/**
+ * SubprocessHandler
+ * Runs a handler written in any language as a child process, talking
+ * line-delimited JSON-RPC 2.0 over its stdin and stdout:
+ *
+ * - blinkie calls `initialize {api_version}`, `create_device {config}` and
+ *   `send_cmd {device_id, command, parameters}`
+ * - the program sends `state_update {device_id, state, timestamp?}` and
+ *   `log` notifications; `timestamp` is when the device measured the state,
+ *   in RFC 3339
+ * - `initialize` may answer `{api_version}`; a program speaking an API
+ *   version this blinkie doesn't implement gets no devices
+ * - `send_cmd` may answer an object, which becomes the command's response
+ *
+ * The program is restarted with backoff when it exits, and its devices are
+ * created again.
+ */
+pub struct SubprocessHandler {
+    shared: Arc<Shared>,
+}
+
+impl SubprocessHandler {
+    pub fn new(config: SubprocessConfig) -> Self {
+        SubprocessHandler {
+            shared: Arc::new(Shared {
+                api_version: Mutex::new(config.api_version.unwrap_or(HANDLER_API_VERSION)),
+                config,
+                stdin: Mutex::new(None),
+                child: Mutex::new(None),
+                pending: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(1),
+                devices: RwLock::new(HashMap::new()),
+                states: RwLock::new(HashMap::new()),
+                error: Mutex::new(None),
+                events: None,
+                sniffer: None,
+                stop: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Publishes reported state changes on `events`. Must be called before `initialize`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        if let Some(shared) = Arc::get_mut(&mut self.shared) {
+            shared.events = Some(events);
+        }
+        self
+    }
+
+    /// Records every JSON-RPC line on `sniffer` while it captures. Must be
+    /// called before `initialize`.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        if let Some(shared) = Arc::get_mut(&mut self.shared) {
+            shared.sniffer = Some(sniffer);
+        }
+        self
+    }
+
+    /// Stops supervising and kills the program.
+    pub fn shutdown(&self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(child) = self.shared.child.lock().unwrap().as_mut() {
+            let _ = child.kill();
+        }
+    }
+}
+
+impl Drop for SubprocessHandler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl ProtocolHandler for SubprocessHandler {
+    fn name(&self) -> String {
+        self.shared.config.name.clone()
+    }
+
+    fn api_version(&self) -> ApiVersion {
+        *self.shared.api_version.lock().unwrap()
+    }
+
+    fn priority(&self) -> u8 {
+        self.shared.config.priority
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        self.shared.config.protocols.clone()
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        // Devices created while the program is down are created once it is back
+        if self.shared.running() {
+            self.shared
+                .call("create_device", json!({ "config": config }))?;
+        }
+        self.shared
+            .devices
+            .write()
+            .unwrap()
+            .insert(config.id.clone(), config.clone());
+        Ok(Box::new(SubprocessDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            shared: self.shared.clone(),
+        }))
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        self.shared
+            .call(
+                "send_cmd",
+                json!({
+                    "device_id": device.get_id(),
+                    "command": cmd,
+                    "parameters": params,
+                }),
+            )
+            .map(|_| ())
+    }
+
+    /// Starts the program under supervision.
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        let shared = self.shared.clone();
+        thread::Builder::new()
+            .name(format!("handler-{}", self.shared.config.name))
+            .spawn(move || shared.supervise())
+            .map(|_| ())
+            .map_err(|e| format!("Failed to start supervisor: {}", e).into())
+    }
+
+    fn shutdown(&mut self) {
+        SubprocessHandler::shutdown(self);
+    }
+
+    fn health(&self) -> Result<(), BlinkieError> {
+        match self.shared.error.lock().unwrap().clone() {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+}
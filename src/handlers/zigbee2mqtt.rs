@@ -0,0 +1,731 @@
+use super::mqtt::{qos, Listener, MqttBrokers, MqttConfig, MqttSpec, Shared};
+use crate::core::color::{self, ColorLight, ColorMode, LightColor};
+use crate::core::device::{
+    Capability, Command, CommandResponse, Config, Device, ProtocolHandler, Type,
+};
+use crate::core::discovery::{slug, Discoverer};
+use crate::core::error::BlinkieError;
+use crate::core::event::{EventBus, EventKind};
+use crate::core::sniffer::Sniffer;
+use crate::core::value::{format_state, StateMap};
+use rumqttc::v5::mqttbytes::v5::Packet;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{Client, Event, MqttOptions};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL: &str = "zigbee2mqtt";
+/// Name of the custom events bridge events are published as.
+pub const EVENT: &str = "zigbee2mqtt";
+
+const DEFAULT_BASE_TOPIC: &str = "zigbee2mqtt";
+/// State key devices report when zigbee2mqtt last heard from them.
+const LAST_SEEN_KEY: &str = "last_seen";
+/// Bit of an expose's `access` set when it can be written.
+const ACCESS_SET: u64 = 2;
+
+/**
+ * Zigbee2MqttSpec
+ * Device behind a zigbee2mqtt bridge, read from its connection details:
+ * the broker as in `MqttConfig::from_details`, `friendly_name` of the
+ * device in zigbee2mqtt, and `base_topic` of the bridge, `zigbee2mqtt` by
+ * default. `Zigbee2MqttDiscoverer` generates these from the bridge.
+ */
+#[derive(Clone, Debug)]
+pub struct Zigbee2MqttSpec {
+    pub base_topic: String,
+    pub friendly_name: String,
+    /// Properties the bridge exposes for the device, from the comma
+    /// separated `exposes` detail discovery fills in.
+    pub exposes: Option<Vec<String>>,
+}
+
+impl Zigbee2MqttSpec {
+    pub fn from_config(config: &Config) -> Result<Self, BlinkieError> {
+        let details = &config.connection_details;
+        let friendly_name = details
+            .get("friendly_name")
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .ok_or_else(|| format!("Device '{}' has no friendly_name", config.id))?;
+        let exposes = details.get("exposes").map(|exposes| {
+            exposes
+                .split(',')
+                .map(str::trim)
+                .filter(|property| !property.is_empty())
+                .map(String::from)
+                .collect()
+        });
+        Ok(Zigbee2MqttSpec {
+            base_topic: base_topic(details),
+            friendly_name,
+            exposes,
+        })
+    }
+
+    /// Topic the device publishes its state on.
+    fn topic(&self) -> String {
+        format!("{}/{}", self.base_topic, self.friendly_name)
+    }
+
+    fn mqtt_spec(&self, qos: QoS) -> MqttSpec {
+        MqttSpec {
+            state_topic: Some(self.topic()),
+            key_topics: HashMap::new(),
+            command_topic: Some(format!("{}/set", self.topic())),
+            command_topics: HashMap::new(),
+            timestamp_key: LAST_SEEN_KEY.to_string(),
+            qos,
+            retain: false,
+        }
+    }
+}
+
+fn base_topic(details: &HashMap<String, String>) -> String {
+    details
+        .get("base_topic")
+        .map(|topic| topic.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| DEFAULT_BASE_TOPIC.to_string())
+}
+
+/// Reads a parameter as JSON where it is, e.g. numbers and booleans, and
+/// as a string otherwise.
+fn json_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+fn json_object(parameters: Option<&HashMap<String, String>>) -> Map<String, Value> {
+    parameters
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| (key.clone(), json_value(value)))
+        .collect()
+}
+
+/// Translates a command into the topic suffix and payload zigbee2mqtt
+/// expects:
+///
+/// - `turn_on`, `turn_off` and `toggle` set `state`, with any parameters,
+///   e.g. `brightness`, alongside
+/// - `lock` and `unlock` lock and unlock door locks
+/// - `set` sets every parameter, e.g. `color_temp=350`
+/// - `transition` fades to the `brightness` parameter over `duration`
+///   seconds
+/// - `refresh` asks the device for its state, or the keys named in the
+///   parameters
+/// - any other command sets the key of its name to the `value` parameter,
+///   or to an object of its parameters, e.g. `brightness` with `value=128`
+fn payload(
+    device_id: &str,
+    command: &str,
+    parameters: Option<&HashMap<String, String>>,
+) -> Result<(&'static str, Value), BlinkieError> {
+    let mut object = json_object(parameters);
+    let state = match command {
+        "turn_on" => Some("ON"),
+        "turn_off" => Some("OFF"),
+        "toggle" => Some("TOGGLE"),
+        "lock" => Some("LOCK"),
+        "unlock" => Some("UNLOCK"),
+        _ => None,
+    };
+    if let Some(state) = state {
+        object.insert("state".to_string(), Value::String(state.to_string()));
+        return Ok(("set", Value::Object(object)));
+    }
+    match command {
+        "set" if object.is_empty() => Err("set needs at least one parameter".into()),
+        "set" => Ok(("set", Value::Object(object))),
+        "transition" => {
+            let brightness = object
+                .remove("brightness")
+                .ok_or("transition needs a brightness")?;
+            let mut payload = Map::from_iter([("brightness".to_string(), brightness)]);
+            if let Some(duration) = object.remove("duration") {
+                payload.insert("transition".to_string(), duration);
+            }
+            Ok(("set", Value::Object(payload)))
+        }
+        "refresh" => {
+            let mut keys: Map<String, Value> = object
+                .into_iter()
+                .map(|(key, _)| (key, Value::String(String::new())))
+                .collect();
+            if keys.is_empty() {
+                keys.insert("state".to_string(), Value::String(String::new()));
+            }
+            Ok(("get", Value::Object(keys)))
+        }
+        _ if object.is_empty() => Err(BlinkieError::unsupported_command(device_id, command)),
+        _ => {
+            let value = match object.remove("value") {
+                Some(value) if object.is_empty() => value,
+                Some(value) => {
+                    object.insert("value".to_string(), value);
+                    Value::Object(object)
+                }
+                None => Value::Object(object),
+            };
+            Ok((
+                "set",
+                Value::Object(Map::from_iter([(command.to_string(), value)])),
+            ))
+        }
+    }
+}
+
+/// Translates a typed command into what zigbee2mqtt expects on the `set`
+/// topic, e.g. `{"brightness": 128}`, if it differs from the command sent
+/// by name. Colors go through `ColorLight`.
+fn typed_payload(command: &Command) -> Option<Value> {
+    let (key, value) = match command {
+        Command::SetBrightness(brightness) => ("brightness", Value::from(*brightness)),
+        Command::SetPosition(position) => ("position", Value::from(*position)),
+        Command::Open => ("state", Value::String("OPEN".to_string())),
+        Command::Close => ("state", Value::String("CLOSE".to_string())),
+        _ => return None,
+    };
+    Some(Value::Object(Map::from_iter([(key.to_string(), value)])))
+}
+
+/// Reads an availability message, `online` or `offline`, plain or as
+/// `{"state": ...}` depending on the zigbee2mqtt version.
+fn availability(payload: &[u8]) -> Option<bool> {
+    let state = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(fields)) => fields.get("state")?.as_str()?.to_string(),
+        _ => String::from_utf8_lossy(payload).trim().to_string(),
+    };
+    match state.as_str() {
+        "online" => Some(true),
+        "offline" => Some(false),
+        _ => None,
+    }
+}
+
+/// Device type of a zigbee2mqtt device: `Switch` for plain switches,
+/// `Actor` if anything it exposes can be set, `Sensor` otherwise.
+fn device_type(exposes: &[Value]) -> Type {
+    fn settable(expose: &Value) -> bool {
+        expose
+            .get("access")
+            .and_then(Value::as_u64)
+            .is_some_and(|access| access & ACCESS_SET != 0)
+            || expose
+                .get("features")
+                .and_then(Value::as_array)
+                .is_some_and(|features| features.iter().any(settable))
+    }
+    if exposes
+        .iter()
+        .any(|expose| expose.get("type").and_then(Value::as_str) == Some("switch"))
+    {
+        Type::Switch
+    } else if exposes.iter().any(settable) {
+        Type::Actor
+    } else {
+        Type::Sensor
+    }
+}
+
+/// Properties of `exposes` and of their features, e.g. `brightness` of a
+/// light.
+fn exposed_properties(exposes: &[Value], properties: &mut Vec<String>) {
+    for expose in exposes {
+        if let Some(property) = expose
+            .get("property")
+            .or_else(|| expose.get("name"))
+            .and_then(Value::as_str)
+        {
+            if !properties.iter().any(|known| known == property) {
+                properties.push(property.to_string());
+            }
+        }
+        if let Some(features) = expose.get("features").and_then(Value::as_array) {
+            exposed_properties(features, properties);
+        }
+    }
+}
+
+/// Generates a device config per device in a `bridge/devices` message,
+/// leaving out the coordinator. `details` holds the broker and base topic
+/// the configs connect through.
+pub fn bridge_configs(
+    payload: &[u8],
+    details: &HashMap<String, String>,
+) -> Result<Vec<Config>, BlinkieError> {
+    let devices: Vec<Value> = serde_json::from_slice(payload).map_err(|e| {
+        BlinkieError::serialization(format!("Invalid zigbee2mqtt device list: {}", e))
+    })?;
+    let mut configs = Vec::new();
+    for device in &devices {
+        let field = |name: &str| device.get(name).and_then(Value::as_str);
+        if field("type") == Some("Coordinator") {
+            continue;
+        }
+        let Some(friendly_name) = field("friendly_name") else {
+            continue;
+        };
+        let definition = device.get("definition").filter(|d| d.is_object());
+        let mut connection_details: HashMap<String, String> = details
+            .iter()
+            .filter(|(key, _)| ["host", "port", "username", "base_topic"].contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        connection_details.insert("friendly_name".to_string(), friendly_name.to_string());
+        if let Some(ieee_address) = field("ieee_address") {
+            connection_details.insert("ieee_address".to_string(), ieee_address.to_string());
+        }
+        for key in ["model", "vendor"] {
+            if let Some(value) = definition.and_then(|d| d.get(key)).and_then(Value::as_str) {
+                connection_details.insert(key.to_string(), value.to_string());
+            }
+        }
+        let exposes = definition
+            .and_then(|d| d.get("exposes"))
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let mut properties = Vec::new();
+        exposed_properties(exposes, &mut properties);
+        if !properties.is_empty() {
+            connection_details.insert("exposes".to_string(), properties.join(","));
+        }
+        configs.push(Config {
+            id: slug(friendly_name),
+            name: friendly_name.to_string(),
+            device_type: device_type(exposes),
+            connection_details,
+            supported_protocols: vec![PROTOCOL.to_string()],
+            preferred_handler: None,
+        });
+    }
+    Ok(configs)
+}
+
+/**
+ * Zigbee2MqttDevice
+ * A Zigbee device bridged by zigbee2mqtt. Its state is the JSON the bridge
+ * publishes for it, plus `available` when the bridge tracks availability.
+ */
+pub struct Zigbee2MqttDevice {
+    id: String,
+    name: String,
+    device_type: Type,
+    spec: Zigbee2MqttSpec,
+    shared: Arc<Shared>,
+}
+
+impl Zigbee2MqttDevice {
+    fn send_command(
+        &self,
+        command: &str,
+        parameters: Option<&HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        let (suffix, payload) = payload(&self.id, command, parameters)?;
+        let topic = format!("{}/{}", self.spec.topic(), suffix);
+        self.shared
+            .publish_as(&self.id, &topic, payload.to_string().into_bytes())
+    }
+
+    fn publish_set(&self, payload: Value) -> Result<(), BlinkieError> {
+        let topic = format!("{}/set", self.spec.topic());
+        self.shared
+            .publish_as(&self.id, &topic, payload.to_string().into_bytes())
+    }
+}
+
+impl ColorLight for Zigbee2MqttDevice {
+    /// The color modes the bridge exposes for the device, or hex colors and
+    /// color temperatures if it didn't say.
+    fn color_modes(&self) -> Vec<ColorMode> {
+        let Some(exposes) = &self.spec.exposes else {
+            return vec![ColorMode::Rgb, ColorMode::ColorTemperature];
+        };
+        let exposed = |property: &str| exposes.iter().any(|known| known == property);
+        let mut modes = Vec::new();
+        if exposed("x") {
+            modes.push(ColorMode::Xy);
+        }
+        if exposed("hue") {
+            modes.push(ColorMode::Hsv);
+        }
+        if exposed("color") && modes.is_empty() {
+            modes.push(ColorMode::Rgb);
+        }
+        if exposed("color_temp") {
+            modes.push(ColorMode::ColorTemperature);
+        }
+        modes
+    }
+
+    fn apply_color(&mut self, color: LightColor) -> Result<(), BlinkieError> {
+        let (key, value) = match color {
+            LightColor::Rgb(color) => ("color", json!({ "hex": color.to_string() })),
+            LightColor::Hsv(color) => (
+                "color",
+                json!({ "hue": color.hue.round(), "saturation": (color.saturation * 100.0).round() }),
+            ),
+            LightColor::Xy(color) => ("color", json!({ "x": color.x, "y": color.y })),
+            LightColor::ColorTemperature(mired) => ("color_temp", Value::from(mired.0)),
+        };
+        self.publish_set(Value::Object(Map::from_iter([(key.to_string(), value)])))
+    }
+}
+
+impl Device for Zigbee2MqttDevice {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_state(&self) -> StateMap {
+        self.shared.state(&self.id)
+    }
+
+    fn set_state(&mut self, state: StateMap) {
+        self.shared
+            .update_state(&self.id, format_state(&state), None);
+    }
+
+    fn send_cmd(&mut self, command: &str, parameters: Option<HashMap<String, String>>) {
+        if let Err(e) = self.send_command(command, parameters.as_ref()) {
+            log::warn!("{}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        parameters: Option<HashMap<String, String>>,
+    ) -> Result<CommandResponse, BlinkieError> {
+        self.send_command(command, parameters.as_ref())?;
+        Ok(CommandResponse::default())
+    }
+
+    fn color_light(&mut self) -> Option<&mut dyn ColorLight> {
+        Some(self)
+    }
+
+    fn send(&mut self, command: &Command) -> Result<CommandResponse, BlinkieError> {
+        if let Some(color) = LightColor::from_command(command) {
+            color::set_color(self, color)?;
+        } else {
+            match typed_payload(command) {
+                Some(payload) => self.publish_set(payload)?,
+                None => self.send_command(command.name(), command.parameters().as_ref())?,
+            }
+        }
+        Ok(CommandResponse::default())
+    }
+
+    fn get_type(&self) -> Option<Type> {
+        Some(self.device_type)
+    }
+
+    fn commands(&self) -> Option<Vec<String>> {
+        Some(
+            [
+                "turn_on",
+                "turn_off",
+                "toggle",
+                "set",
+                "transition",
+                "refresh",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        )
+    }
+
+    /// What the bridge exposes for the device, falling back to the keys of
+    /// its state; the commands are the same for every device.
+    fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities: Vec<Capability> = match &self.spec.exposes {
+            Some(exposes) => exposes
+                .iter()
+                .filter_map(|property| Capability::from_key(property))
+                .collect(),
+            None => return Capability::infer(&self.get_state(), None),
+        };
+        capabilities.sort();
+        capabilities.dedup();
+        capabilities
+    }
+}
+
+/**
+ * Zigbee2MqttHandler
+ * The built-in `zigbee2mqtt` handler: talks to Zigbee devices through a
+ * zigbee2mqtt bridge on an MQTT broker, see `Zigbee2MqttSpec`. State comes
+ * from `<base_topic>/<friendly_name>`, commands go to its `/set` and `/get`
+ * topics. Devices joining, announcing or leaving are published as custom
+ * `zigbee2mqtt` events, and the handler is unhealthy while a bridge is
+ * offline.
+ */
+pub struct Zigbee2MqttHandler {
+    brokers: MqttBrokers,
+    /// Bridge state, `online` or `offline`, by broker key and base topic.
+    bridges: Arc<RwLock<HashMap<String, String>>>,
+    devices: HashMap<String, (Arc<Shared>, Zigbee2MqttSpec)>,
+    events: Option<Arc<EventBus>>,
+}
+
+impl Default for Zigbee2MqttHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Zigbee2MqttHandler {
+    pub fn new() -> Self {
+        Zigbee2MqttHandler {
+            brokers: MqttBrokers::named(PROTOCOL),
+            bridges: Arc::new(RwLock::new(HashMap::new())),
+            devices: HashMap::new(),
+            events: None,
+        }
+    }
+
+    /// Publishes state changes and bridge events on `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.brokers = self.brokers.with_events(events.clone());
+        self.events = Some(events);
+        self
+    }
+
+    /// Records published and received messages on `sniffer` while it captures.
+    pub fn with_sniffer(mut self, sniffer: Arc<Sniffer>) -> Self {
+        self.brokers = self.brokers.with_sniffer(sniffer);
+        self
+    }
+
+    /// Follows the bridge at `base_topic` on `shared`, once per bridge.
+    fn follow_bridge(&self, shared: &Arc<Shared>, base_topic: &str) -> Result<(), BlinkieError> {
+        let key = format!("{}/{}", shared.config.broker_key(), base_topic);
+        if self.bridges.read().unwrap().contains_key(&key) {
+            return Ok(());
+        }
+        self.bridges
+            .write()
+            .unwrap()
+            .insert(key.clone(), String::new());
+
+        let bridges = self.bridges.clone();
+        let state: Listener = Arc::new(move |_topic, payload| {
+            let state = match availability(payload) {
+                Some(true) => "online",
+                Some(false) => "offline",
+                None => return,
+            };
+            bridges
+                .write()
+                .unwrap()
+                .insert(key.clone(), state.to_string());
+        });
+        shared.listen(&format!("{}/bridge/state", base_topic), state)?;
+
+        let events = self.events.clone();
+        let base = base_topic.to_string();
+        let event: Listener = Arc::new(move |_topic, payload| {
+            let Some(events) = &events else {
+                return;
+            };
+            let Ok(message) = serde_json::from_slice::<Value>(payload) else {
+                return;
+            };
+            let Some(kind) = message.get("type").and_then(Value::as_str) else {
+                return;
+            };
+            let mut data = HashMap::from([
+                ("type".to_string(), kind.to_string()),
+                ("base_topic".to_string(), base.clone()),
+            ]);
+            if let Some(fields) = message.get("data").and_then(Value::as_object) {
+                for (key, value) in fields {
+                    if let Some(value) = value.as_str() {
+                        data.insert(key.clone(), value.to_string());
+                    }
+                }
+            }
+            let _ = events.publish(EventKind::Custom {
+                name: EVENT.to_string(),
+                data,
+            });
+        });
+        shared.listen(&format!("{}/bridge/event", base_topic), event)
+    }
+}
+
+impl ProtocolHandler for Zigbee2MqttHandler {
+    fn name(&self) -> String {
+        PROTOCOL.to_string()
+    }
+
+    fn supported_protocols(&self) -> Vec<String> {
+        vec![PROTOCOL.to_string()]
+    }
+
+    fn create_device(&mut self, config: &Config) -> Result<Box<dyn Device>, BlinkieError> {
+        let spec = Zigbee2MqttSpec::from_config(config)?;
+        let shared = self
+            .brokers
+            .broker(&config.connection_details)
+            .map_err(|e| format!("Failed to create device '{}': {}", config.id, e))?;
+        shared.register(&config.id, spec.mqtt_spec(qos(shared.config.qos)?))?;
+        self.follow_bridge(&shared, &spec.base_topic)?;
+
+        let device_id = config.id.clone();
+        let weak = Arc::downgrade(&shared);
+        let available: Listener = Arc::new(move |_topic, payload| {
+            let (Some(shared), Some(available)) = (weak.upgrade(), availability(payload)) else {
+                return;
+            };
+            let update = HashMap::from([("available".to_string(), available.to_string())]);
+            shared.update_state(&device_id, update, None);
+        });
+        shared.listen(&format!("{}/availability", spec.topic()), available)?;
+
+        self.devices
+            .insert(config.id.clone(), (shared.clone(), spec.clone()));
+        Ok(Box::new(Zigbee2MqttDevice {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            device_type: config.device_type,
+            spec,
+            shared,
+        }))
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), BlinkieError> {
+        MqttConfig::from_details(PROTOCOL, &config.connection_details)
+            .map_err(|e| format!("Invalid device '{}': {}", config.id, e))?;
+        Zigbee2MqttSpec::from_config(config).map(|_| ())
+    }
+
+    fn send_cmd(
+        &mut self,
+        device: &mut dyn Device,
+        cmd: &str,
+        params: Option<HashMap<String, String>>,
+    ) -> Result<(), BlinkieError> {
+        let id = device.get_id();
+        let (shared, spec) = self
+            .devices
+            .get(id)
+            .ok_or_else(|| format!("Device '{}' is not a zigbee2mqtt device", id))?;
+        let (suffix, payload) = payload(id, cmd, params.as_ref())?;
+        let topic = format!("{}/{}", spec.topic(), suffix);
+        shared.publish_as(id, &topic, payload.to_string().into_bytes())
+    }
+
+    fn initialize(&mut self) -> Result<(), BlinkieError> {
+        self.brokers.initialize()
+    }
+
+    fn shutdown(&mut self) {
+        self.brokers.shutdown();
+    }
+
+    /// Reports unreachable brokers first, then bridges reporting offline.
+    fn health(&self) -> Result<(), BlinkieError> {
+        self.brokers.health()?;
+        let bridges = self.bridges.read().unwrap();
+        match bridges.iter().find(|(_, state)| *state == "offline") {
+            Some((bridge, _)) => Err(BlinkieError::connection(format!(
+                "Bridge {} is offline",
+                bridge
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * Zigbee2MqttDiscoverer
+ * Reads the device list a zigbee2mqtt bridge keeps retained on
+ * `<base_topic>/bridge/devices` and turns it into device configs, see
+ * `bridge_configs`.
+ */
+pub struct Zigbee2MqttDiscoverer {
+    details: HashMap<String, String>,
+}
+
+impl Zigbee2MqttDiscoverer {
+    /// Discovers through the broker at `host`, on the MQTT port if `port`
+    /// is `None`.
+    pub fn new(host: &str, port: Option<u16>) -> Self {
+        let mut details = HashMap::from([("host".to_string(), host.to_string())]);
+        if let Some(port) = port {
+            details.insert("port".to_string(), port.to_string());
+        }
+        Zigbee2MqttDiscoverer { details }
+    }
+
+    pub fn with_base_topic(mut self, base_topic: &str) -> Self {
+        self.details
+            .insert("base_topic".to_string(), base_topic.to_string());
+        self
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.details
+            .insert("username".to_string(), username.to_string());
+        self.details
+            .insert("password".to_string(), password.to_string());
+        self
+    }
+}
+
+impl Discoverer for Zigbee2MqttDiscoverer {
+    fn name(&self) -> String {
+        PROTOCOL.to_string()
+    }
+
+    fn discover(&self, timeout: Duration) -> Result<Vec<Config>, BlinkieError> {
+        let config = MqttConfig::from_details("discover", &self.details)?;
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, 10);
+        let topic = format!("{}/bridge/devices", base_topic(&self.details));
+        client
+            .subscribe(&topic, QoS::AtMostOnce)
+            .map_err(|e| BlinkieError::connection(format!("Failed to subscribe: {}", e)))?;
+
+        let deadline = Instant::now() + timeout;
+        let result = loop {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                break Err(BlinkieError::timeout(format!(
+                    "No device list on {} within {} seconds",
+                    topic,
+                    timeout.as_secs()
+                )));
+            };
+            match connection.recv_timeout(left) {
+                Ok(Ok(Event::Incoming(Packet::Publish(publish))))
+                    if publish.topic.as_ref() == topic.as_bytes() =>
+                {
+                    break bridge_configs(&publish.payload, &self.details);
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    break Err(BlinkieError::connection(format!(
+                        "Connection to {} failed: {}",
+                        config.host, e
+                    )))
+                }
+                Err(_) => continue,
+            }
+        };
+        let _ = client.disconnect();
+        result
+    }
+}
@@ -0,0 +1,7 @@
+pub mod api;
+pub mod automation;
+pub mod client;
+pub mod core;
+pub mod handlers;
+#[cfg(feature = "test-util")]
+pub mod testing;
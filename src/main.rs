@@ -1,5 +1,1224 @@
-mod core;
+use blinkie::api::dbus;
+use blinkie::api::endpoints::{
+    self, CommandBody, DeviceSummary, HandlerStatus, ImportRequest, PurgeResult, SnifferRequest,
+};
+use blinkie::automation::rule::StalePolicy;
+use blinkie::client::console::{format_state, Console};
+use blinkie::client::http::ApiClient;
+use blinkie::client::top::Top;
+use blinkie::core::app::App;
+use blinkie::core::app::CommandResult;
+use blinkie::core::backup::BackupManager;
+use blinkie::core::device::Type;
+use blinkie::core::discovery::{
+    discover_all, Discoverer, MdnsDiscoverer, ServiceMapping, SsdpDiscoverer,
+};
+use blinkie::core::event::EventBus;
+use blinkie::core::federation::Uplink;
+use blinkie::core::install::{Installer, Source};
+use blinkie::core::journal::EventJournal;
+use blinkie::core::remote::{Gateway, WireGuardTunnel};
+use blinkie::core::replay::{self, Fixture};
+use blinkie::core::retention::PurgeTarget;
+use blinkie::core::sniffer::{Direction, Frame, SnifferStatus};
+use blinkie::core::snippet::DeviceSnippet;
+use blinkie::core::storage::StorageConfig;
+use blinkie::core::value::StateMap;
+use chrono::Utc;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
+use clap_complete::env::{Bash, CompleteEnv, Elvish, EnvCompleter, Fish, Powershell, Zsh};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-fn main() {
-    println!("Hello, world!");
+#[derive(Parser)]
+#[command(name = "blinkie", version, about = "Blinkie home automation hub")]
+struct Cli {
+    /// How results are printed
+    #[arg(long, global = true, value_enum, default_value_t)]
+    output: OutputFormat,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/**
+ * Output Formats
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Table,
+    /// A single JSON document per command, for scripts
+    Json,
+}
+
+impl OutputFormat {
+    /// Prints `value` as JSON, or hands it to `table` to print for humans.
+    fn print<T: Serialize>(self, value: &T, table: impl FnOnce(&T)) -> Result<(), String> {
+        match self {
+            OutputFormat::Table => table(value),
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(value)
+                    .map_err(|e| format!("Failed to serialize output: {}", e))?
+            ),
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Buses
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Bus {
+    Session,
+    System,
+}
+
+/**
+ * Shells
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Shell {
+    Bash,
+    Elvish,
+    Fish,
+    Powershell,
+    Zsh,
+}
+
+/// Daemon asked for device ids while completing; override with `BLINKIE_URL`.
+const DEFAULT_URL: &str = "http://127.0.0.1:8123";
+
+/// Completes device ids known to the running daemon, if it can be reached.
+fn complete_device_id(current: &OsStr) -> Vec<CompletionCandidate> {
+    let url = std::env::var("BLINKIE_URL").unwrap_or_else(|_| DEFAULT_URL.to_string());
+    let Ok(states) = ApiClient::new(&url)
+        .and_then(|client| client.get::<HashMap<String, StateMap>>("/api/states"))
+    else {
+        return Vec::new();
+    };
+    let current = current.to_string_lossy();
+    let mut ids: Vec<String> = states
+        .into_keys()
+        .filter(|id| id.starts_with(current.as_ref()))
+        .collect();
+    ids.sort();
+    ids.into_iter().map(CompletionCandidate::new).collect()
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the hub and its HTTP API
+    Serve {
+        /// Directory holding the event journal, variables and statistics
+        #[arg(long, default_value = "data")]
+        data: PathBuf,
+        /// TOML or YAML file describing the data directory, handlers,
+        /// devices and settings, instead of `--data`
+        #[arg(long, conflicts_with = "data")]
+        config: Option<PathBuf>,
+        /// Address to serve the HTTP API on
+        #[arg(long, default_value = "127.0.0.1:8123")]
+        listen: SocketAddr,
+        /// Directory with a web dashboard to serve alongside the API
+        #[arg(long)]
+        ui: Option<PathBuf>,
+        /// Only allow viewing states and streaming events without a full-scope
+        /// API token, e.g. for public dashboards
+        #[arg(long)]
+        read_only: bool,
+        /// Seconds after which state conditions stop trusting a value,
+        /// unless they set a limit of their own
+        #[arg(long)]
+        max_state_age: Option<u64>,
+        /// Let state conditions pass on values that did not change since
+        /// the hub started, whose age is unknown
+        #[arg(long, requires = "max_state_age")]
+        allow_unknown_age: bool,
+        /// Seconds between writes of changed device states to the device
+        /// store; 30 unless the config file sets it
+        #[arg(long)]
+        flush_interval: Option<u64>,
+        /// Export devices on this D-Bus bus (needs the `dbus` feature)
+        #[arg(long, value_enum)]
+        dbus: Option<Bus>,
+        /// Run an embedded MQTT broker on this address (needs the `mqtt-broker` feature)
+        #[arg(long)]
+        mqtt_listen: Option<SocketAddr>,
+        /// Also accept MQTT 5 clients on this address
+        #[arg(long, requires = "mqtt_listen")]
+        mqtt_listen_v5: Option<SocketAddr>,
+        /// User allowed on the embedded broker, as name:password; repeatable
+        #[arg(long, requires = "mqtt_listen")]
+        mqtt_user: Vec<String>,
+        /// Run as a satellite, syncing device states to the hub whose HTTP
+        /// API is at this URL. A token for it is read from
+        /// `BLINKIE_UPSTREAM_TOKEN`.
+        #[arg(long, requires = "satellite_id")]
+        upstream: Option<String>,
+        /// Name of this satellite on the hub
+        #[arg(long, requires = "upstream")]
+        satellite_id: Option<String>,
+        /// Seconds between syncs to the hub
+        #[arg(long, default_value_t = 10)]
+        sync_interval: u64,
+    },
+    /// Archive storage and config directories to a backup target
+    Backup {
+        /// Directory, WebDAV URL or s3://bucket/prefix to store the backup in
+        #[arg(long)]
+        to: String,
+        /// Number of backups to keep on the target
+        #[arg(long, default_value_t = 7)]
+        keep: usize,
+        /// Directories and files to back up
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Restore a backup created with `blinkie backup`
+    Restore {
+        /// Directory, WebDAV URL or s3://bucket/prefix holding the backups
+        #[arg(long)]
+        from: String,
+        /// Backup to restore; defaults to the newest one
+        #[arg(long)]
+        name: Option<String>,
+        /// Directory to restore into
+        #[arg(long, default_value = ".")]
+        into: PathBuf,
+        /// List the available backups instead of restoring one
+        #[arg(long)]
+        list: bool,
+    },
+    /// Install a package or blueprint from a Git repository or a manifest URL
+    Install {
+        /// Git URL (optionally `#branch-or-tag`) or HTTPS URL of a manifest
+        source: String,
+        /// Config directory to install into
+        #[arg(long, default_value = "config")]
+        config: PathBuf,
+        /// Reinstall even if the same or a newer version is present
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check installed packages and blueprints for newer versions
+    Update {
+        /// Config directory the items were installed into
+        #[arg(long, default_value = "config")]
+        config: PathBuf,
+        /// Only report available updates instead of installing them
+        #[arg(long)]
+        check: bool,
+    },
+    /// Permanently delete all recorded data about a device or person
+    Purge {
+        #[command(flatten)]
+        target: ApiTarget,
+        /// Only purge this event journal file, e.g. of a hub that is not
+        /// running, instead of every store of the hub
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        /// Device whose history should be deleted
+        #[arg(
+            long,
+            required_unless_present = "person",
+            conflicts_with = "person",
+            add = ArgValueCompleter::new(complete_device_id)
+        )]
+        device: Option<String>,
+        /// Person whose presence and location history should be deleted
+        #[arg(long)]
+        person: Option<String>,
+    },
+    /// Control a running daemon from an interactive shell
+    Console {
+        /// Base URL of the daemon's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8123")]
+        url: String,
+    },
+    /// List the devices of a hub
+    Devices {
+        #[command(flatten)]
+        target: ApiTarget,
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
+    /// Read the state of a device or send it a command
+    Device {
+        #[command(flatten)]
+        target: ApiTarget,
+        #[command(subcommand)]
+        command: DeviceCommand,
+    },
+    /// List the protocol handlers of a hub
+    Handlers {
+        #[command(flatten)]
+        target: ApiTarget,
+        #[command(subcommand)]
+        command: HandlersCommand,
+    },
+    /// Print a shell completion script, including completion of device ids
+    /// fetched from the daemon at `BLINKIE_URL`
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Monitor a running daemon in the terminal
+    Top {
+        /// Base URL of the daemon's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8123")]
+        url: String,
+        /// Milliseconds between refreshes
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+    },
+    /// Capture raw protocol frames on a running daemon and print them as
+    /// they arrive, until interrupted or, when recording, for `duration`
+    Sniff {
+        /// Base URL of the daemon's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8123")]
+        url: String,
+        /// Only print frames of this device
+        #[arg(long, add = ArgValueCompleter::new(complete_device_id))]
+        device: Option<String>,
+        /// Seconds until the daemon stops capturing by itself
+        #[arg(long, default_value_t = 600)]
+        duration: i64,
+        /// Write the session to this fixture file for `blinkie replay`
+        #[arg(long, requires = "decoder")]
+        record: Option<PathBuf>,
+        /// Decoder the fixture is replayed with: exec, mqtt or subprocess
+        #[arg(long)]
+        decoder: Option<String>,
+    },
+    /// Replay recorded fixtures through their handler's decoder and check
+    /// the resulting state against the recorded one
+    Replay {
+        #[arg(required = true)]
+        fixtures: Vec<PathBuf>,
+    },
+    /// Look for devices on the local network through mDNS and SSDP, or
+    /// behind a zigbee2mqtt bridge, and print a candidate device config for
+    /// each
+    Discover {
+        /// Seconds to wait for devices to answer
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+        /// Also look for this mDNS service, as type=protocol, e.g.
+        /// _hue._tcp.local=http; repeatable
+        #[arg(long)]
+        mdns_service: Vec<String>,
+        /// Also list the devices of a zigbee2mqtt bridge on the MQTT broker
+        /// at host[:port] (needs the `mqtt` feature)
+        #[arg(long)]
+        zigbee2mqtt: Option<String>,
+        /// Base topic of the zigbee2mqtt bridge
+        #[arg(long, default_value = "zigbee2mqtt", requires = "zigbee2mqtt")]
+        zigbee2mqtt_base_topic: String,
+    },
+    /// Ask the router to forward a port to the HTTP API through UPnP, for
+    /// remote access without manual router setup. Protect the API with
+    /// tokens before exposing it.
+    Upnp {
+        /// Local port the HTTP API listens on
+        #[arg(long, default_value_t = 8123)]
+        port: u16,
+        /// Port to open on the router; defaults to the local port
+        #[arg(long)]
+        external_port: Option<u16>,
+        /// Seconds until the router drops the mapping, 0 for never
+        #[arg(long, default_value_t = 0)]
+        lease: u32,
+        /// Remove the mapping instead of adding it
+        #[arg(long)]
+        remove: bool,
+        /// Seconds to wait for the router to answer discovery
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// Generate WireGuard configuration for an outbound tunnel from the hub
+    /// to a relay with a public address, for networks where ports can't
+    /// be mapped
+    Wireguard {
+        /// host:port the relay's WireGuard listens on
+        #[arg(long)]
+        relay_endpoint: String,
+        /// Public key of the relay
+        #[arg(long)]
+        relay_public_key: String,
+        /// Tunnel address of the relay
+        #[arg(long, default_value = "10.8.0.1/32")]
+        relay_address: String,
+        /// Tunnel address of the hub
+        #[arg(long, default_value = "10.8.0.2/32")]
+        hub_address: String,
+    },
+}
+
+fn main() -> ExitCode {
+    // Answers completion requests from the scripts printed by `completions`
+    CompleteEnv::with_factory(Cli::command).complete();
+
+    let cli = Cli::parse();
+    let output = cli.output;
+    let result = match cli.command {
+        Command::Serve {
+            data,
+            config,
+            listen,
+            ui,
+            read_only,
+            max_state_age,
+            allow_unknown_age,
+            flush_interval,
+            dbus,
+            mqtt_listen,
+            mqtt_listen_v5,
+            mqtt_user,
+            upstream,
+            satellite_id,
+            sync_interval,
+        } => {
+            let options = ServeOptions {
+                config,
+                ui,
+                read_only,
+                stale: StalePolicy {
+                    max_age_secs: max_state_age,
+                    allow_unknown: allow_unknown_age,
+                },
+                flush_interval: flush_interval.map(|secs| Duration::from_secs(secs.max(1))),
+                bus: dbus,
+                broker: mqtt_listen.map(|listen| (listen, mqtt_listen_v5, mqtt_user)),
+                uplink: upstream
+                    .zip(satellite_id)
+                    .map(|(url, id)| (url, id, Duration::from_secs(sync_interval.max(1)))),
+            };
+            serve(output, data, listen, options)
+        }
+        Command::Backup { to, keep, paths } => backup(output, &to, keep, paths),
+        Command::Restore {
+            from,
+            name,
+            into,
+            list,
+        } => restore(output, &from, name, into, list),
+        Command::Install {
+            source,
+            config,
+            force,
+        } => install(output, config, &source, force),
+        Command::Update { config, check } => update(output, config, check),
+        Command::Purge {
+            target,
+            journal,
+            device,
+            person,
+        } => purge(output, target, journal, device, person),
+        Command::Console { url } => console(&url),
+        Command::Devices { target, command } => match command {
+            DevicesCommand::List => with_api(target, |client| list_devices(output, client)),
+        },
+        Command::Device { target, command } => match command {
+            DeviceCommand::State { device, key } => with_api(target, |client| {
+                device_state(output, client, &device, key.as_deref())
+            }),
+            DeviceCommand::Cmd {
+                device,
+                command,
+                params,
+            } => with_api(target, |client| {
+                send_command(output, client, &device, &command, &params)
+            }),
+            DeviceCommand::Export { device, out } => {
+                with_api(target, |client| export_device(client, &device, out))
+            }
+            DeviceCommand::Import { file, id, details } => with_api(target, |client| {
+                import_device(output, client, &file, id, &details)
+            }),
+        },
+        Command::Handlers { target, command } => match command {
+            HandlersCommand::List => with_api(target, |client| list_handlers(output, client)),
+        },
+        Command::Completions { shell } => completions(shell),
+        Command::Top { url, interval } => top(&url, interval),
+        Command::Sniff {
+            url,
+            device,
+            duration,
+            record,
+            decoder,
+        } => {
+            let record = record.zip(decoder);
+            sniff(output, &url, device, duration, record)
+        }
+        Command::Replay { fixtures } => replay(output, &fixtures),
+        Command::Discover {
+            timeout,
+            mdns_service,
+            zigbee2mqtt,
+            zigbee2mqtt_base_topic,
+        } => discover(
+            output,
+            Duration::from_secs(timeout),
+            mdns_service,
+            zigbee2mqtt.map(|broker| (broker, zigbee2mqtt_base_topic)),
+        ),
+        Command::Upnp {
+            port,
+            external_port,
+            lease,
+            remove,
+            timeout,
+        } => upnp(
+            output,
+            port,
+            external_port.unwrap_or(port),
+            lease,
+            remove,
+            Duration::from_secs(timeout),
+        ),
+        Command::Wireguard {
+            relay_endpoint,
+            relay_public_key,
+            relay_address,
+            hub_address,
+        } => wireguard(
+            output,
+            WireGuardTunnel {
+                relay_endpoint,
+                relay_public_key,
+                relay_address,
+                hub_address,
+            },
+        ),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            match output {
+                OutputFormat::Table => eprintln!("Error: {}", e),
+                OutputFormat::Json => eprintln!("{}", json!({ "error": e })),
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/**
+ * ApiTarget
+ * The hub a one-off command talks to: a running daemon, or the hub of a
+ * config file opened in-process.
+ */
+#[derive(Args)]
+struct ApiTarget {
+    /// Base URL of the daemon's HTTP API
+    #[arg(long, global = true, default_value = DEFAULT_URL)]
+    url: String,
+    /// Open the hub of this config file in-process instead of talking to a
+    /// daemon; not while a daemon runs on the same data directory
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum DevicesCommand {
+    /// List devices with their type and state
+    List,
+}
+
+#[derive(Subcommand)]
+enum DeviceCommand {
+    /// Print the state of a device, or of one of its keys
+    State {
+        #[arg(add = ArgValueCompleter::new(complete_device_id))]
+        device: String,
+        key: Option<String>,
+    },
+    /// Send a command to a device
+    Cmd {
+        #[arg(add = ArgValueCompleter::new(complete_device_id))]
+        device: String,
+        command: String,
+        /// Parameter of the command as key=value; repeatable
+        #[arg(long = "param", short)]
+        params: Vec<String>,
+    },
+    /// Print a device's config and icon as a snippet to share, with its
+    /// secrets left out
+    Export {
+        #[arg(add = ArgValueCompleter::new(complete_device_id))]
+        device: String,
+        /// Write the snippet to this file instead
+        #[arg(long, short)]
+        out: Option<PathBuf>,
+    },
+    /// Register the device of a snippet written by `device export`
+    Import {
+        /// Snippet file, `-` for stdin
+        file: PathBuf,
+        /// Register the device under this id instead of its own
+        #[arg(long)]
+        id: Option<String>,
+        /// Connection detail as key=value, required for the snippet's
+        /// secrets; repeatable
+        #[arg(long = "set", short)]
+        details: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum HandlersCommand {
+    /// List protocol handlers and their health
+    List,
+}
+
+/**
+ * ServeOptions
+ * Optional parts of a served hub.
+ */
+struct ServeOptions {
+    config: Option<PathBuf>,
+    ui: Option<PathBuf>,
+    read_only: bool,
+    stale: StalePolicy,
+    flush_interval: Option<Duration>,
+    bus: Option<Bus>,
+    broker: Option<BrokerArgs>,
+    uplink: Option<UplinkArgs>,
+}
+
+fn serve(
+    output: OutputFormat,
+    data: PathBuf,
+    listen: SocketAddr,
+    options: ServeOptions,
+) -> Result<(), String> {
+    let ServeOptions {
+        config,
+        ui,
+        read_only,
+        stale,
+        flush_interval,
+        bus,
+        broker,
+        uplink,
+    } = options;
+    let mut app = match config {
+        Some(config) => App::from_config(config)?,
+        None => App::open(data)?,
+    };
+    // Flags add to what the config file set
+    app.access.read_only |= read_only;
+    if stale != StalePolicy::default() {
+        app.rules.set_stale_policy(stale);
+    }
+    if let Some(interval) = flush_interval {
+        app.flush_interval = interval;
+    }
+    if let Some(broker) = broker {
+        start_broker(&mut app, broker)?;
+    }
+    let mut sync_interval = None;
+    if let Some((url, satellite_id, interval)) = uplink {
+        let token = std::env::var("BLINKIE_UPSTREAM_TOKEN").ok();
+        app.uplink = Some(Arc::new(Uplink::new(&satellite_id, &url, token)?));
+        sync_interval = Some(interval);
+    }
+    let app = Arc::new(app);
+    let runtime =
+        tokio::runtime::Runtime::new().map_err(|e| format!("Failed to start runtime: {}", e))?;
+    runtime.block_on(async {
+        tokio::spawn(app.rules.clone().run());
+        tokio::spawn(app.reloader.clone().run());
+        tokio::spawn(app.aggregates.clone().run(app.events.clone()));
+        tokio::spawn(app.state_history.clone().run(app.events.clone()));
+        tokio::spawn(app.usage.clone().run(app.events.clone()));
+        tokio::spawn(app.notifications.clone().run(app.events.clone()));
+        tokio::spawn(
+            app.announcer
+                .clone()
+                .run(app.events.clone(), app.command_sender()),
+        );
+        tokio::spawn(app.doorbells.clone().run(app.command_sender()));
+        tokio::spawn(app.occupancy.clone().run());
+        tokio::spawn(app.users.clone().run());
+        tokio::spawn(app.predictions.clone().run());
+        tokio::spawn(app.energy.clone().run(app.command_sender()));
+        tokio::spawn(app.prices.clone().run(app.command_sender()));
+        tokio::spawn(app.guests.clone().run(app.command_sender()));
+        tokio::spawn(app.locks.clone().run());
+        tokio::spawn(app.pumps.clone().run(app.command_sender()));
+        tokio::spawn(app.maintenance_jobs.clone().run());
+        tokio::spawn(app.clone().persist_states());
+        app.start();
+        {
+            let app = app.clone();
+            tokio::spawn(app.drift.clone().run(move || app.fingerprints()));
+        }
+        {
+            let app = app.clone();
+            tokio::spawn(app.health.clone().run(move || app.check_health()));
+        }
+        if let Some((uplink, interval)) = app.uplink.clone().zip(sync_interval) {
+            let app = app.clone();
+            tokio::spawn(uplink.run(interval, move || app.device_states()));
+        }
+        if let Some(bus) = bus {
+            let bus = match bus {
+                Bus::Session => dbus::Bus::Session,
+                Bus::System => dbus::Bus::System,
+            };
+            let app = app.clone();
+            tokio::spawn(async move {
+                if let Err(e) = dbus::serve(app, bus).await {
+                    log::error!("{}", e);
+                }
+            });
+        }
+        output.print(&json!({ "listening": listen }), |_| {
+            println!("Listening on {}", listen)
+        })?;
+        let served = endpoints::serve(app.clone(), listen, ui).await;
+        let stopping = app.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || stopping.shutdown()).await {
+            log::error!("Failed to shut down: {}", e);
+        }
+        served
+    })
+}
+
+/// Listen address, MQTT 5 listen address and name:password users of the embedded broker.
+type BrokerArgs = (SocketAddr, Option<SocketAddr>, Vec<String>);
+/// Hub URL, satellite id and sync interval of a satellite.
+type UplinkArgs = (String, String, Duration);
+
+#[cfg(feature = "mqtt-broker")]
+fn start_broker(app: &mut App, (listen, listen_v5, users): BrokerArgs) -> Result<(), String> {
+    use blinkie::core::broker::{BrokerConfig, EmbeddedBroker};
+
+    let mut config = BrokerConfig::new(listen);
+    config.listen_v5 = listen_v5;
+    for user in users {
+        let (name, password) = user
+            .split_once(':')
+            .ok_or_else(|| format!("Expected name:password, got '{}'", user))?;
+        config.users.insert(name.to_string(), password.to_string());
+    }
+    app.broker = Some(Arc::new(EmbeddedBroker::start(config)?));
+    Ok(())
+}
+
+#[cfg(not(feature = "mqtt-broker"))]
+fn start_broker(_app: &mut App, _broker: BrokerArgs) -> Result<(), String> {
+    Err(
+        "The embedded MQTT broker requires blinkie to be built with the `mqtt-broker` feature"
+            .to_string(),
+    )
+}
+
+fn backup(output: OutputFormat, to: &str, keep: usize, paths: Vec<PathBuf>) -> Result<(), String> {
+    let manager = BackupManager::new(paths, StorageConfig::parse(to)?.build(), keep);
+    let name = manager.create(Utc::now())?;
+    output.print(&json!({ "created": name }), |_| {
+        println!("Created {}", name)
+    })
+}
+
+fn discover(
+    output: OutputFormat,
+    timeout: Duration,
+    services: Vec<String>,
+    zigbee2mqtt: Option<(String, String)>,
+) -> Result<(), String> {
+    let mut mdns = MdnsDiscoverer::new();
+    for service in services {
+        let (service, protocol) = service
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid mDNS service '{}', expected type=protocol", service))?;
+        mdns = mdns.with_service(ServiceMapping::new(service, protocol, Type::Actor));
+    }
+    let mut discoverers: Vec<Box<dyn Discoverer>> =
+        vec![Box::new(mdns), Box::new(SsdpDiscoverer::new())];
+    if let Some(bridge) = zigbee2mqtt {
+        discoverers.push(zigbee2mqtt_discoverer(bridge)?);
+    }
+    let candidates = discover_all(&discoverers, timeout);
+    output.print(&candidates, |candidates| {
+        if candidates.is_empty() {
+            println!("No devices found");
+        }
+        for config in candidates {
+            let address = match (
+                config.connection_details.get("host"),
+                config.connection_details.get("port"),
+            ) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                _ => String::new(),
+            };
+            println!(
+                "{:<32} {:<32} {:<8} {}",
+                config.id,
+                config.name,
+                config.supported_protocols.join(","),
+                address
+            );
+        }
+    })
+}
+
+#[cfg(feature = "mqtt")]
+fn zigbee2mqtt_discoverer(
+    (broker, base_topic): (String, String),
+) -> Result<Box<dyn Discoverer>, String> {
+    use blinkie::handlers::zigbee2mqtt::Zigbee2MqttDiscoverer;
+
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            Some(
+                port.parse()
+                    .map_err(|_| format!("Invalid MQTT broker port '{}'", port))?,
+            ),
+        ),
+        None => (broker.as_str(), None),
+    };
+    Ok(Box::new(
+        Zigbee2MqttDiscoverer::new(host, port).with_base_topic(&base_topic),
+    ))
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn zigbee2mqtt_discoverer(_bridge: (String, String)) -> Result<Box<dyn Discoverer>, String> {
+    Err("zigbee2mqtt discovery requires blinkie to be built with the `mqtt` feature".to_string())
+}
+
+fn upnp(
+    output: OutputFormat,
+    port: u16,
+    external_port: u16,
+    lease: u32,
+    remove: bool,
+    timeout: Duration,
+) -> Result<(), String> {
+    let gateway = Gateway::discover(timeout)?;
+    if remove {
+        gateway.remove_port_mapping(external_port)?;
+        return output.print(&json!({ "removed": external_port }), |_| {
+            println!("Removed mapping of port {}", external_port)
+        });
+    }
+    let mapping = gateway.add_port_mapping(external_port, port, lease)?;
+    output.print(&mapping, |mapping| {
+        let external = mapping
+            .external_ip
+            .map_or("<unknown>".to_string(), |ip| ip.to_string());
+        println!(
+            "Forwarding {}:{} to {}",
+            external, mapping.external_port, mapping.internal
+        );
+        if mapping.lease_secs > 0 {
+            println!("The router drops it after {} seconds", mapping.lease_secs);
+        }
+    })
+}
+
+fn wireguard(output: OutputFormat, tunnel: WireGuardTunnel) -> Result<(), String> {
+    let config = tunnel.generate()?;
+    output.print(&config, |config| {
+        println!("# Hub configuration, e.g. /etc/wireguard/blinkie.conf");
+        println!("{}", config.hub);
+        println!("# Add to the relay's configuration");
+        print!("{}", config.relay_peer);
+    })
+}
+
+fn restore(
+    output: OutputFormat,
+    from: &str,
+    name: Option<String>,
+    into: PathBuf,
+    list: bool,
+) -> Result<(), String> {
+    let manager = BackupManager::new(Vec::new(), StorageConfig::parse(from)?.build(), usize::MAX);
+    if list {
+        return output.print(&manager.list()?, |names| {
+            for name in names {
+                println!("{}", name);
+            }
+        });
+    }
+    let name = manager.restore(name.as_deref(), &into)?;
+    output.print(&json!({ "restored": name, "into": into }), |_| {
+        println!("Restored {} into {}", name, into.display())
+    })
+}
+
+fn install(output: OutputFormat, config: PathBuf, source: &str, force: bool) -> Result<(), String> {
+    let manifest = Installer::new(config).install(&Source::parse(source)?, force)?;
+    output.print(&manifest, |manifest| {
+        println!("Installed {} {}", manifest.id, manifest.version)
+    })
+}
+
+fn update(output: OutputFormat, config: PathBuf, check: bool) -> Result<(), String> {
+    let installer = Installer::new(config);
+    let updates = installer.check_updates()?;
+    if check {
+        return output.print(&updates, |updates| {
+            if updates.is_empty() {
+                println!("Everything is up to date");
+            }
+            for update in updates {
+                println!(
+                    "{:<24} {:<12} -> {}",
+                    update.id, update.installed, update.available
+                );
+            }
+        });
+    }
+    let updated = updates
+        .iter()
+        .map(|update| installer.update(&update.id))
+        .collect::<Result<Vec<_>, _>>()?;
+    output.print(&updated, |updated| {
+        if updated.is_empty() {
+            println!("Everything is up to date");
+        }
+        for manifest in updated {
+            println!("Updated {} to {}", manifest.id, manifest.version);
+        }
+    })
+}
+
+fn purge(
+    output: OutputFormat,
+    hub: ApiTarget,
+    journal: Option<PathBuf>,
+    device: Option<String>,
+    person: Option<String>,
+) -> Result<(), String> {
+    let target = match (device, person) {
+        (Some(id), _) => PurgeTarget::Device(id),
+        (None, Some(id)) => PurgeTarget::Person(id),
+        (None, None) => return Err("Either --device or --person is required".to_string()),
+    };
+
+    let print = |result: PurgeResult| {
+        output.print(&result, |result| {
+            println!("Removed {} event(s)", result.removed)
+        })
+    };
+    match journal {
+        Some(journal) => {
+            let bus = EventBus::with_journal(EventJournal::open(journal)?);
+            print(PurgeResult {
+                removed: bus.purge(&target)?,
+            })
+        }
+        None => with_api(hub, |client| {
+            print(client.post("/api/history/purge", &target)?)
+        }),
+    }
+}
+
+fn console(url: &str) -> Result<(), String> {
+    Console::new(ApiClient::new(url)?).run()
+}
+
+/// Runs `f` against the daemon at `target.url`, or against the hub of
+/// `target.config` served in-process on a loopback port while `f` runs.
+fn with_api(
+    target: ApiTarget,
+    f: impl FnOnce(&ApiClient) -> Result<(), String>,
+) -> Result<(), String> {
+    let Some(config) = target.config else {
+        return f(&ApiClient::new(&target.url)?);
+    };
+    let app = Arc::new(App::from_config(config)?);
+    let runtime =
+        tokio::runtime::Runtime::new().map_err(|e| format!("Failed to start runtime: {}", e))?;
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .and_then(|listener| Ok((listener.local_addr()?, listener)))
+        .map_err(|e| format!("Failed to serve the hub in-process: {}", e));
+    let (addr, listener) = listener?;
+    let router = endpoints::router(app.clone());
+    runtime.spawn(async move { axum::serve(listener, router).await });
+    let result = f(&ApiClient::new(&format!("http://{}", addr))?);
+    // Keeps what commands changed for the next start of the hub
+    app.shutdown();
+    result
+}
+
+fn list_devices(output: OutputFormat, client: &ApiClient) -> Result<(), String> {
+    let devices: Vec<DeviceSummary> = client.get("/api/devices")?;
+    output.print(&devices, |devices| {
+        for device in devices {
+            println!(
+                "{:<24} {:<10} {}",
+                device.id,
+                device
+                    .device_type
+                    .map_or("-".to_string(), |device_type| format!("{:?}", device_type)),
+                format_state(&device.state)
+            );
+        }
+    })
+}
+
+fn device_state(
+    output: OutputFormat,
+    client: &ApiClient,
+    device_id: &str,
+    key: Option<&str>,
+) -> Result<(), String> {
+    let device: DeviceSummary = client.get(&format!("/api/devices/{}", device_id))?;
+    match key {
+        Some(key) => {
+            let value = device
+                .state
+                .get(key)
+                .ok_or_else(|| format!("Device '{}' has no state '{}'", device_id, key))?;
+            output.print(value, |value| println!("{}", value))
+        }
+        None => output.print(&device.state, |state| println!("{}", format_state(state))),
+    }
+}
+
+fn send_command(
+    output: OutputFormat,
+    client: &ApiClient,
+    device_id: &str,
+    command: &str,
+    params: &[String],
+) -> Result<(), String> {
+    let parameters = key_values(params)?;
+    let body = CommandBody {
+        command: command.to_string(),
+        parameters: (!parameters.is_empty()).then_some(parameters),
+        lease: None,
+    };
+    let result: CommandResult =
+        client.post(&format!("/api/devices/{}/commands", device_id), &body)?;
+    output.print(&result, |result| {
+        match result.job {
+            Some(job) => println!("Sent (event #{}, job #{})", result.event.seq, job),
+            None => println!("Sent (event #{})", result.event.seq),
+        }
+        let mut response: Vec<_> = result.response.data.iter().collect();
+        response.sort();
+        for (key, value) in response {
+            println!("  {} = {}", key, value);
+        }
+    })
+}
+
+fn key_values(pairs: &[String]) -> Result<HashMap<String, String>, String> {
+    pairs
+        .iter()
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .ok_or_else(|| format!("Expected key=value, got '{}'", pair))
+        })
+        .collect()
+}
+
+fn export_device(client: &ApiClient, device_id: &str, out: Option<PathBuf>) -> Result<(), String> {
+    let snippet: DeviceSnippet = client.get(&format!("/api/devices/{}/snippet", device_id))?;
+    let contents = snippet.render()?;
+    let Some(path) = out else {
+        print!("{}", contents);
+        return Ok(());
+    };
+    std::fs::write(&path, contents)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    if !snippet.secrets.is_empty() {
+        eprintln!(
+            "Left out secrets {}; whoever imports the snippet sets them",
+            snippet.secrets.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn import_device(
+    output: OutputFormat,
+    client: &ApiClient,
+    file: &Path,
+    id: Option<String>,
+    details: &[String],
+) -> Result<(), String> {
+    let contents = match file.to_str() {
+        Some("-") => std::io::read_to_string(std::io::stdin()),
+        _ => std::fs::read_to_string(file),
+    }
+    .map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+    let body = ImportRequest {
+        snippet: DeviceSnippet::parse(&contents)?,
+        id,
+        connection_details: key_values(details)?,
+    };
+    let snippet: DeviceSnippet = client.post("/api/devices/import", &body)?;
+    output.print(&snippet, |snippet| {
+        println!(
+            "Imported device '{}' ({:?})",
+            snippet.device.id, snippet.device.device_type
+        )
+    })
+}
+
+fn list_handlers(output: OutputFormat, client: &ApiClient) -> Result<(), String> {
+    let handlers: Vec<HandlerStatus> = client.get("/api/handlers")?;
+    output.print(&handlers, |handlers| {
+        for handler in handlers {
+            println!(
+                "{:<24} {:<24} {}",
+                handler.name,
+                handler.protocols.join(","),
+                match (&handler.maintenance, &handler.error) {
+                    (Some(flag), _) => format!("maintenance until {}", flag.until),
+                    (None, Some(error)) => error.clone(),
+                    (None, None) => "ok".to_string(),
+                }
+            );
+        }
+    })
+}
+
+fn top(url: &str, interval: u64) -> Result<(), String> {
+    Top::new(
+        ApiClient::new(url)?,
+        Duration::from_millis(interval.max(100)),
+    )
+    .run()
+}
+
+fn sniff(
+    output: OutputFormat,
+    url: &str,
+    device: Option<String>,
+    duration: i64,
+    record: Option<(PathBuf, String)>,
+) -> Result<(), String> {
+    if let Some((_, decoder)) = &record {
+        replay::decoder(decoder)?;
+    }
+    let client = ApiClient::new(url)?;
+    let stop = Instant::now() + Duration::from_secs(duration.max(0) as u64);
+    let mut recorded = Vec::new();
+    let _: SnifferStatus = client.put(
+        "/api/sniffer",
+        &SnifferRequest {
+            enabled: true,
+            duration_secs: duration,
+        },
+    )?;
+    let mut after = 0;
+    loop {
+        let mut query = vec![("after", after.to_string()), ("limit", "1000".to_string())];
+        if let Some(device) = &device {
+            query.push(("device_id", device.clone()));
+        }
+        let frames: Vec<Frame> = client.get_query("/api/sniffer/frames", &query)?;
+        for frame in &frames {
+            match output {
+                OutputFormat::Table => println!(
+                    "{} {} {} {} {}",
+                    frame.timestamp.format("%H:%M:%S%.3f"),
+                    frame.handler,
+                    frame.device_id.as_deref().unwrap_or("-"),
+                    match frame.direction {
+                        Direction::In => "<",
+                        Direction::Out => ">",
+                    },
+                    frame.data
+                ),
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string(frame)
+                        .map_err(|e| format!("Failed to serialize output: {}", e))?
+                ),
+            }
+            after = frame.seq;
+        }
+        if let Some((path, decoder)) = &record {
+            recorded.extend(frames);
+            if Instant::now() >= stop {
+                let states: HashMap<String, StateMap> = client.get("/api/states")?;
+                let fixture = Fixture::record(decoder, recorded, &states);
+                fixture.save(path)?;
+                if output == OutputFormat::Table {
+                    eprintln!(
+                        "Recorded {} frames to {}",
+                        fixture.frames.len(),
+                        path.display()
+                    );
+                }
+                return Ok(());
+            }
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+fn replay(output: OutputFormat, fixtures: &[PathBuf]) -> Result<(), String> {
+    let mut failed = 0;
+    for path in fixtures {
+        let result = replay::verify(path);
+        match (output, &result) {
+            (OutputFormat::Table, Ok(report)) => {
+                println!("ok     {} ({} frames)", path.display(), report.frames)
+            }
+            (OutputFormat::Table, Err(e)) => println!("FAILED {}", e),
+            (OutputFormat::Json, _) => println!(
+                "{}",
+                json!({
+                    "fixture": path,
+                    "passed": result.is_ok(),
+                    "error": result.as_ref().err().map(ToString::to_string),
+                })
+            ),
+        }
+        if result.is_err() {
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} of {} fixtures failed", failed, fixtures.len())),
+    }
+}
+
+fn completions(shell: Shell) -> Result<(), String> {
+    let completer: &dyn EnvCompleter = match shell {
+        Shell::Bash => &Bash,
+        Shell::Elvish => &Elvish,
+        Shell::Fish => &Fish,
+        Shell::Powershell => &Powershell,
+        Shell::Zsh => &Zsh,
+    };
+    let exe = std::env::current_exe()
+        .map_err(|e| format!("Failed to locate the blinkie binary: {}", e))?;
+    completer
+        .write_registration(
+            "COMPLETE",
+            "blinkie",
+            "blinkie",
+            &exe.to_string_lossy(),
+            &mut std::io::stdout(),
+        )
+        .map_err(|e| format!("Failed to write completion script: {}", e))
 }
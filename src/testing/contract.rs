@@ -0,0 +1,282 @@
+//! Conformance suite for protocol handlers. A handler certifies against it
+//! by implementing [`Contract`] and calling [`run`] from a test:
+//!
+//! - naming: a name and at least one protocol, none listed twice
+//! - an API version this blinkie implements
+//! - initialization succeeds
+//! - every config of [`Contract::devices`] creates a device with the
+//!   config's id and name, whose state can be read
+//! - every command of [`Contract::commands`] succeeds on every device
+//! - configs of [`Contract::invalid_devices`] and an unknown command fail
+//!   with an error instead of panicking
+//! - the handler, shared the way the registry shares it, serves commands,
+//!   state reads and health checks from several threads without panicking
+//!   or deadlocking
+
+use crate::core::device::{Config, Device, HandlerRef, ProtocolHandler, HANDLER_API_VERSION};
+use crate::core::error::BlinkieError;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Threads hammering the handler in the concurrency check.
+const THREADS: usize = 8;
+/// Rounds each of them runs.
+const ROUNDS: usize = 20;
+/// How long the concurrency check may take before it counts as a deadlock.
+const DEADLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const UNKNOWN_COMMAND: &str = "blinkie_contract_unknown_command";
+
+/// Parameters of a command, as passed to `send_cmd`.
+pub type Parameters = Option<HashMap<String, String>>;
+
+/**
+ * Contract
+ * What the suite needs to know to exercise a handler: how to build one and
+ * which devices and commands it must handle.
+ */
+pub trait Contract: ProtocolHandler + Sized + 'static {
+    /// A fresh, uninitialized handler.
+    fn handler() -> Self;
+
+    /// Configs of devices the handler must accept, at least one.
+    fn devices() -> Vec<Config>;
+
+    /// Commands every device of [`Contract::devices`] must accept.
+    fn commands() -> Vec<(String, Parameters)> {
+        Vec::new()
+    }
+
+    /// Configs the handler must reject, e.g. with missing connection details.
+    fn invalid_devices() -> Vec<Config> {
+        Vec::new()
+    }
+}
+
+/// Runs the suite against `H` and panics listing every violation.
+pub fn run<H: Contract>() {
+    let violations = check::<H>();
+    if !violations.is_empty() {
+        panic!(
+            "{} violates the handler contract:\n- {}",
+            std::any::type_name::<H>(),
+            violations.join("\n- ")
+        );
+    }
+}
+
+/// Runs the suite against `H` and returns the violations found.
+pub fn check<H: Contract>() -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut handler = H::handler();
+
+    match guard(|| (handler.name(), handler.supported_protocols())) {
+        Ok((name, protocols)) => {
+            if name.is_empty() {
+                violations.push("name() is empty".to_string());
+            }
+            if protocols.is_empty() {
+                violations.push("supported_protocols() is empty".to_string());
+            }
+            let unique: HashSet<&String> = protocols.iter().collect();
+            if unique.len() != protocols.len() {
+                violations.push(format!(
+                    "supported_protocols() lists a protocol twice: {:?}",
+                    protocols
+                ));
+            }
+        }
+        Err(panic) => violations.push(format!("name() or supported_protocols() {}", panic)),
+    }
+
+    match guard(|| handler.api_version()) {
+        Ok(version) => {
+            if let Err(e) = version.negotiate(HANDLER_API_VERSION) {
+                violations.push(format!("api_version(): {}", e));
+            }
+        }
+        Err(panic) => violations.push(format!("api_version() {}", panic)),
+    }
+
+    match guard(|| handler.initialize()) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            violations.push(format!("initialize() failed: {}", e));
+            return violations;
+        }
+        Err(panic) => {
+            violations.push(format!("initialize() {}", panic));
+            return violations;
+        }
+    }
+
+    let configs = H::devices();
+    if configs.is_empty() {
+        violations.push("Contract::devices() returned no devices".to_string());
+    }
+    let mut devices: Vec<Box<dyn Device>> = Vec::new();
+    for config in &configs {
+        match guard(|| handler.create_device(config)) {
+            Ok(Ok(device)) => {
+                if device.get_id() != config.id {
+                    violations.push(format!(
+                        "device created for '{}' has id '{}'",
+                        config.id,
+                        device.get_id()
+                    ));
+                }
+                if device.get_name() != config.name {
+                    violations.push(format!(
+                        "device created for '{}' is named '{}' instead of '{}'",
+                        config.id,
+                        device.get_name(),
+                        config.name
+                    ));
+                }
+                if let Err(panic) = guard(|| device.get_state()) {
+                    violations.push(format!("get_state() of '{}' {}", config.id, panic));
+                }
+                devices.push(device);
+            }
+            Ok(Err(e)) => violations.push(format!("create_device('{}') failed: {}", config.id, e)),
+            Err(panic) => violations.push(format!("create_device('{}') {}", config.id, panic)),
+        }
+    }
+
+    for config in H::invalid_devices() {
+        match guard(|| handler.create_device(&config)) {
+            Ok(Ok(_)) => violations.push(format!(
+                "create_device('{}') accepted an invalid config",
+                config.id
+            )),
+            Ok(Err(_)) => {}
+            Err(panic) => violations.push(format!(
+                "create_device('{}') {} on an invalid config",
+                config.id, panic
+            )),
+        }
+    }
+
+    let commands = H::commands();
+    for device in devices.iter_mut() {
+        for (command, parameters) in &commands {
+            match guard(|| handler.send_cmd(device.as_mut(), command, parameters.clone())) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => violations.push(format!(
+                    "send_cmd('{}', '{}') failed: {}",
+                    device.get_id(),
+                    command,
+                    e
+                )),
+                Err(panic) => violations.push(format!(
+                    "send_cmd('{}', '{}') {}",
+                    device.get_id(),
+                    command,
+                    panic
+                )),
+            }
+        }
+        if let Err(panic) = guard(|| handler.send_cmd(device.as_mut(), UNKNOWN_COMMAND, None)) {
+            violations.push(format!(
+                "send_cmd('{}') {} on an unknown command",
+                device.get_id(),
+                panic
+            ));
+        }
+    }
+
+    if let Err(panic) = guard(|| handler.health()) {
+        violations.push(format!("health() {}", panic));
+    }
+
+    violations.extend(check_concurrency(handler, devices, commands));
+    violations
+}
+
+/// Shares the handler and devices like the hub does and uses them from
+/// several threads at once.
+fn check_concurrency<H: Contract>(
+    handler: H,
+    devices: Vec<Box<dyn Device>>,
+    commands: Vec<(String, Parameters)>,
+) -> Vec<String> {
+    let handler: HandlerRef = Arc::new(RwLock::new(handler));
+    let devices: Arc<Vec<Mutex<Box<dyn Device>>>> =
+        Arc::new(devices.into_iter().map(Mutex::new).collect());
+    let commands = Arc::new(commands);
+    let violations = Arc::new(Mutex::new(Vec::new()));
+    let (done_tx, done_rx) = mpsc::channel();
+
+    for worker in 0..THREADS {
+        let handler = handler.clone();
+        let devices = devices.clone();
+        let commands = commands.clone();
+        let violations = violations.clone();
+        let done = done_tx.clone();
+        thread::spawn(move || {
+            let result = guard(|| {
+                for round in 0..ROUNDS {
+                    let _ = handler.read().unwrap().health();
+                    if devices.is_empty() {
+                        continue;
+                    }
+                    let device = &devices[(worker + round) % devices.len()];
+                    let mut device = device.lock().unwrap();
+                    let _ = device.get_state();
+                    if let Some((command, parameters)) =
+                        commands.get((worker + round) % commands.len().max(1))
+                    {
+                        let result = handler.write().unwrap().send_cmd(
+                            device.as_mut(),
+                            command,
+                            parameters.clone(),
+                        );
+                        if let Err(e) = result {
+                            violations.lock().unwrap().push(format!(
+                                "send_cmd('{}', '{}') failed under concurrent use: {}",
+                                device.get_id(),
+                                command,
+                                e
+                            ));
+                        }
+                    }
+                }
+            });
+            if let Err(panic) = result {
+                violations
+                    .lock()
+                    .unwrap()
+                    .push(format!("concurrent use {}", panic));
+            }
+            let _ = done.send(());
+        });
+    }
+    drop(done_tx);
+
+    for _ in 0..THREADS {
+        if done_rx.recv_timeout(DEADLOCK_TIMEOUT).is_err() {
+            violations.lock().unwrap().push(format!(
+                "concurrent use did not finish within {:?}, likely a deadlock",
+                DEADLOCK_TIMEOUT
+            ));
+            break;
+        }
+    }
+    let mut violations = violations.lock().unwrap().clone();
+    violations.dedup();
+    violations
+}
+
+/// Runs `f`, turning a panic into a description of it.
+fn guard<T>(f: impl FnOnce() -> T) -> Result<T, BlinkieError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        format!("panicked: {}", message).into()
+    })
+}
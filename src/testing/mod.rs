@@ -0,0 +1,178 @@
+//! Proptest strategies for the state model, for handler authors testing
+//! their conversions. Enabled with the `test-util` feature, which also
+//! derives `arbitrary::Arbitrary` for the same types for fuzzing.
+//!
+//! Unlike the derived `Arbitrary` implementations, the strategies produce
+//! values a real hub would see: plausible ids, state keys and values.
+//! [`contract`] holds the conformance suite for protocol handlers.
+
+pub mod contract;
+
+use crate::core::device::{Action, Config, Type};
+use crate::core::event::{Event, EventKind};
+use crate::core::job::JobState;
+use crate::core::value::parse_state;
+use chrono::{DateTime, Utc};
+use proptest::collection::{hash_map, vec};
+use proptest::option;
+use proptest::prelude::*;
+use std::collections::HashMap;
+
+/// Device, person and handler ids, e.g. `kitchen_light_2`.
+pub fn id() -> impl Strategy<Value = String> {
+    "[a-z][a-z0-9_]{0,23}"
+}
+
+/// State and parameter keys, e.g. `brightness`.
+pub fn state_key() -> impl Strategy<Value = String> {
+    "[a-z][a-z_]{0,15}"
+}
+
+/// State values as devices report them: switch states, integers, decimals,
+/// booleans and arbitrary text.
+pub fn state_value() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("on".to_string()),
+        Just("off".to_string()),
+        any::<bool>().prop_map(|b| b.to_string()),
+        any::<i32>().prop_map(|n| n.to_string()),
+        (-1000.0..1000.0f64).prop_map(|x| format!("{:.2}", x)),
+        ".{0,32}",
+    ]
+}
+
+/// A device state or command parameters of up to `max` keys.
+pub fn state(max: usize) -> impl Strategy<Value = HashMap<String, String>> {
+    hash_map(state_key(), state_value(), 0..=max)
+}
+
+pub fn device_type() -> impl Strategy<Value = Type> {
+    prop_oneof![
+        Just(Type::Sensor),
+        Just(Type::Actor),
+        Just(Type::Switch),
+        Just(Type::Controller),
+        Just(Type::Cat),
+    ]
+}
+
+/// Configs of devices speaking `protocol`.
+pub fn config(protocol: &str) -> impl Strategy<Value = Config> {
+    let protocol = protocol.to_string();
+    (
+        id(),
+        ".{1,32}",
+        device_type(),
+        hash_map("[a-z_]{1,16}", ".{0,32}", 0..6),
+        option::of(id()),
+    )
+        .prop_map(
+            move |(id, name, device_type, connection_details, preferred_handler)| Config {
+                id,
+                name,
+                device_type,
+                connection_details,
+                supported_protocols: vec![protocol.clone()],
+                preferred_handler,
+            },
+        )
+}
+
+pub fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        Just(Action::TurnOn),
+        Just(Action::TurnOff),
+        state(4).prop_map(|state| Action::Set(parse_state(&state))),
+        state_key().prop_map(Action::Reset),
+    ]
+}
+
+pub fn event_kind() -> impl Strategy<Value = EventKind> {
+    prop_oneof![
+        (id(), state_key(), option::of(state_value()), state_value()).prop_map(
+            |(device_id, key, old_value, new_value)| EventKind::StateChanged {
+                device_id,
+                key,
+                old_value,
+                new_value,
+                reported_at: None,
+            }
+        ),
+        (id(), state_key(), option::of(state(3))).prop_map(|(device_id, command, parameters)| {
+            EventKind::CommandSent {
+                device_id,
+                command,
+                parameters,
+            }
+        }),
+        id().prop_map(|device_id| EventKind::DeviceRegistered { device_id }),
+        (id(), prop_oneof![Just("home"), Just("away")]).prop_map(|(person_id, state)| {
+            EventKind::PresenceChanged {
+                person_id,
+                state: state.to_string(),
+            }
+        }),
+        (id(), -90.0..90.0f64, -180.0..180.0f64).prop_map(|(person_id, latitude, longitude)| {
+            EventKind::LocationUpdated {
+                person_id,
+                latitude,
+                longitude,
+            }
+        }),
+        (
+            any::<u64>(),
+            id(),
+            state_key(),
+            prop_oneof![
+                Just(JobState::Running),
+                Just(JobState::Completed),
+                Just(JobState::Failed),
+                Just(JobState::Cancelled),
+            ],
+            option::of(0..=100u8),
+            option::of(state_value()),
+        )
+            .prop_map(|(job_id, device_id, command, state, percent, phase)| {
+                EventKind::JobUpdated {
+                    job_id,
+                    device_id,
+                    command,
+                    state,
+                    percent,
+                    phase,
+                }
+            }),
+        (id(), state(3)).prop_map(|(name, data)| EventKind::Custom { name, data }),
+    ]
+}
+
+/// Events published between 2020 and 2040.
+pub fn event() -> impl Strategy<Value = Event> {
+    (any::<u64>(), 1_577_836_800..2_208_988_800i64, event_kind()).prop_map(|(seq, secs, kind)| {
+        Event {
+            seq,
+            timestamp: DateTime::<Utc>::from_timestamp(secs, 0).unwrap(),
+            kind,
+        }
+    })
+}
+
+/// A sequence of up to `max` events with increasing sequence numbers and
+/// timestamps, as a subscriber would receive them.
+pub fn event_stream(max: usize) -> impl Strategy<Value = Vec<Event>> {
+    vec((1..60i64, event_kind()), 0..=max).prop_map(|steps| {
+        let mut timestamp = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        steps
+            .into_iter()
+            .enumerate()
+            .map(|(i, (delay, kind))| {
+                timestamp += chrono::Duration::seconds(delay);
+                Event {
+                    seq: i as u64 + 1,
+                    timestamp,
+                    kind,
+                }
+            })
+            .collect()
+    })
+}